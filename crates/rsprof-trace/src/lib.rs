@@ -22,6 +22,44 @@
 //! rsprof_trace::profiler!(cpu = 199);  // CPU at 199Hz + heap profiling
 //! ```
 //!
+//! Or sample CPU only, without installing an allocator (needs just `cpu`):
+//! ```rust,ignore
+//! rsprof_trace::profiler!(cpu_only = 199);
+//! ```
+//!
+//! Attribute allocations to a logical operation (e.g. a request) with markers:
+//! ```rust,ignore
+//! rsprof_trace::mark("handle_request");
+//! handle_request();
+//! rsprof_trace::clear_mark();
+//! ```
+//!
+//! Or tag a scope; its allocations are grouped under the tag until the guard
+//! drops, whichever path reached it:
+//! ```rust,ignore
+//! let _tag = rsprof_trace::tag("parsing");
+//! parse(input);
+//! ```
+//!
+//! Annotate the recording timeline with a checkpoint label:
+//! ```rust,ignore
+//! rsprof_trace::label("cache warm");
+//! ```
+//!
+//! Skip heap tracking (or CPU sampling) during a phase that isn't of interest:
+//! ```rust,ignore
+//! rsprof_trace::set_heap_recording(false);
+//! load_fixtures();
+//! rsprof_trace::set_heap_recording(true);
+//! ```
+//!
+//! Or pause both for a scope, e.g. a benchmark's warmup:
+//! ```rust,ignore
+//! let paused = rsprof_trace::pause();
+//! warm_up();
+//! drop(paused);
+//! ```
+//!
 //! Build with frame pointers for accurate stack traces:
 //! ```bash
 //! RUSTFLAGS="-C force-frame-pointers=yes" cargo build --release --features profiling
@@ -33,6 +71,9 @@
 #![no_std]
 
 extern crate alloc;
+// Thread-locals of the profiling hooks (no heap or destructors involved)
+#[cfg(any(feature = "heap", feature = "cpu"))]
+extern crate std;
 
 // Include profiling module when any profiling feature is enabled
 #[cfg(any(feature = "heap", feature = "cpu"))]
@@ -40,7 +81,163 @@ mod profiling;
 
 // Re-export CPU profiling functions
 #[cfg(feature = "cpu")]
-pub use profiling::{start_cpu_profiling, stop_cpu_profiling};
+pub use profiling::{set_cpu_recording, start_cpu_profiling, stop_cpu_profiling};
+
+// Re-export allocation markers
+#[cfg(feature = "heap")]
+pub use profiling::{clear_mark, mark, set_heap_recording};
+
+/// Group the current thread's allocations under `name` until the guard drops
+///
+/// A tag is a scoped [`mark`]: allocations made in the scope (and the later
+/// freeing of that memory) are credited to the tag, regardless of the stack
+/// that reached it. Dropping the guard restores the marker that was active
+/// before, so tags nest. `name` is a string or an integer.
+#[must_use = "the tag ends when the guard is dropped"]
+pub fn tag(name: impl TagName) -> TagGuard {
+    #[cfg(feature = "heap")]
+    let previous = profiling::saved_mark();
+    #[cfg(not(feature = "heap"))]
+    let previous = 0;
+    name.with_name(&mut |name| mark(name));
+    TagGuard {
+        previous,
+        _thread: core::marker::PhantomData,
+    }
+}
+
+/// Ends a [`tag`] scope when dropped
+pub struct TagGuard {
+    /// Marker active before the tag (slot + 1, 0 = none)
+    previous: u64,
+    /// The tag is per-thread, so the guard must stay on its thread
+    _thread: core::marker::PhantomData<*const ()>,
+}
+
+impl Drop for TagGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "heap")]
+        profiling::restore_mark(self.previous);
+        #[cfg(not(feature = "heap"))]
+        let _ = self.previous;
+    }
+}
+
+/// Pause heap tracking and CPU sampling until the guard drops
+///
+/// Allocations and samples in the paused window are dropped, as with
+/// [`set_heap_recording`] and [`set_cpu_recording`]; memory allocated before
+/// the pause is still credited when it is freed, so live bytes stay right.
+/// Recording is process-wide: dropping the guard restores the state from
+/// before the pause, so pauses nest.
+#[must_use = "recording resumes when the guard is dropped"]
+pub fn pause() -> ProfilingGuard {
+    #[cfg(feature = "heap")]
+    let heap = profiling::replace_heap_recording(false);
+    #[cfg(not(feature = "heap"))]
+    let heap = false;
+    #[cfg(feature = "cpu")]
+    let cpu = profiling::replace_cpu_recording(false);
+    #[cfg(not(feature = "cpu"))]
+    let cpu = false;
+    ProfilingGuard { heap, cpu }
+}
+
+/// Ends a [`pause`] when dropped
+pub struct ProfilingGuard {
+    /// Whether allocations were recorded before the pause
+    heap: bool,
+    /// Whether CPU samples were recorded before the pause
+    cpu: bool,
+}
+
+impl Drop for ProfilingGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "heap")]
+        profiling::set_heap_recording(self.heap);
+        #[cfg(not(feature = "heap"))]
+        let _ = self.heap;
+        #[cfg(feature = "cpu")]
+        profiling::set_cpu_recording(self.cpu);
+        #[cfg(not(feature = "cpu"))]
+        let _ = self.cpu;
+    }
+}
+
+/// A [`tag`] name: a string, or an integer (shown in decimal)
+pub trait TagName {
+    #[doc(hidden)]
+    fn with_name(&self, f: &mut dyn FnMut(&str));
+}
+
+impl TagName for &str {
+    fn with_name(&self, f: &mut dyn FnMut(&str)) {
+        f(self)
+    }
+}
+
+macro_rules! integer_tag_name {
+    ($($int:ty),*) => {
+        $(
+            impl TagName for $int {
+                fn with_name(&self, f: &mut dyn FnMut(&str)) {
+                    let mut name = DecimalBuf::default();
+                    let _ = core::fmt::Write::write_fmt(&mut name, format_args!("{}", self));
+                    f(name.as_str())
+                }
+            }
+        )*
+    };
+}
+
+integer_tag_name!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+/// Stack buffer an integer tag is formatted into (no allocation)
+#[derive(Default)]
+struct DecimalBuf {
+    bytes: [u8; 20],
+    len: usize,
+}
+
+impl DecimalBuf {
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+impl core::fmt::Write for DecimalBuf {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        if end > self.bytes.len() {
+            return Err(core::fmt::Error);
+        }
+        self.bytes[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+// Re-export checkpoint labels
+#[cfg(any(feature = "heap", feature = "cpu"))]
+pub use profiling::label;
+
+// Stubs when heap feature is disabled
+#[cfg(not(feature = "heap"))]
+#[inline]
+pub fn mark(_name: &str) {}
+
+#[cfg(not(feature = "heap"))]
+#[inline]
+pub fn clear_mark() {}
+
+#[cfg(not(feature = "heap"))]
+#[inline]
+pub fn set_heap_recording(_enabled: bool) {}
+
+// Stub when profiling is disabled
+#[cfg(not(any(feature = "heap", feature = "cpu")))]
+#[inline]
+pub fn label(_text: &str) {}
 
 // Stubs when CPU feature is disabled
 #[cfg(not(feature = "cpu"))]
@@ -51,25 +248,151 @@ pub fn start_cpu_profiling(_freq_hz: u32) {}
 #[inline]
 pub fn stop_cpu_profiling() {}
 
+#[cfg(not(feature = "cpu"))]
+#[inline]
+pub fn set_cpu_recording(_enabled: bool) {}
+
+/// What the profiler does with events from new call sites once its fixed-size
+/// callsite table is full.
+///
+/// Selected with the `full` option of [`profiler!`]. No policy ever blocks the
+/// application.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FullPolicy {
+    /// Count the event without its stack, under the shared "no stack"
+    /// callsite if that has a slot, and drop it otherwise (lossy: nothing is
+    /// reserved for it)
+    Overwrite = 0,
+    /// Drop the event, leaving the recorded callsites untouched
+    DropNew = 1,
+    /// Count the event without its stack, under the shared "no stack" callsite,
+    /// so byte totals stay accurate
+    CountOnly = 2,
+}
+
+impl FullPolicy {
+    /// Policy for a `ProfilingAllocator` `FULL_POLICY` parameter
+    pub const fn from_u8(value: u8) -> Self {
+        match value {
+            1 => FullPolicy::DropNew,
+            2 => FullPolicy::CountOnly,
+            _ => FullPolicy::Overwrite,
+        }
+    }
+}
+
+/// Allocation tracking slots unless `profiler!(alloc_slots = N)` says otherwise
+///
+/// Each live allocation the profiler tracks (to attribute its free) takes a
+/// 40-byte slot of shared memory, so the default table is 10MB.
+pub const DEFAULT_ALLOC_SLOTS: usize = 256 * 1024;
+
 /// A profiling allocator that wraps the system allocator.
 ///
 /// The const generic `CPU_FREQ` specifies the CPU sampling frequency in Hz.
 /// Set to 0 to disable CPU profiling.
 ///
+/// `STACK_MIN` and `STACK_MAX` bound the allocation sizes (inclusive) for
+/// which a stack trace is captured. Allocations outside the range skip the
+/// stack walk and are only counted, under a single shared callsite.
+///
+/// `FULL_POLICY` is a [`FullPolicy`] as `u8`, applied once the callsite table
+/// is full.
+///
+/// `ALLOC_SLOTS` sizes the table of live allocations tracked for free
+/// attribution; it must be a power of two (checked at compile time). Once the
+/// table is full, frees of untracked allocations aren't attributed.
+///
+/// `HEAP_SAMPLE_BYTES` records only a sample of allocations, one per that many
+/// allocated bytes on average (0 = record all). Each sampled allocation is
+/// weighted by its inverse sampling probability, so counts and bytes are
+/// unbiased estimates; small allocations get the largest weights and the
+/// noisiest estimates.
+///
+/// `HEAP_MIN_SIZE` skips allocations smaller than that many bytes entirely,
+/// along with their frees and reallocations: they are absent from counts,
+/// bytes and the size histogram.
+///
 /// When the `heap` feature is enabled, this allocator captures
 /// allocation and deallocation events along with stack traces.
 /// CPU profiling (if enabled) starts automatically on the first allocation.
 ///
 /// When profiling features are disabled, it's a zero-cost passthrough.
-pub struct ProfilingAllocator<const CPU_FREQ: u32 = 99>;
+pub struct ProfilingAllocator<
+    const CPU_FREQ: u32 = 99,
+    const STACK_MIN: usize = 0,
+    const STACK_MAX: usize = { usize::MAX },
+    const FULL_POLICY: u8 = 0,
+    const ALLOC_SLOTS: usize = DEFAULT_ALLOC_SLOTS,
+    const HEAP_SAMPLE_BYTES: usize = 0,
+    const HEAP_MIN_SIZE: usize = 0,
+>;
+
+impl<
+    const CPU_FREQ: u32,
+    const STACK_MIN: usize,
+    const STACK_MAX: usize,
+    const FULL_POLICY: u8,
+    const ALLOC_SLOTS: usize,
+    const HEAP_SAMPLE_BYTES: usize,
+    const HEAP_MIN_SIZE: usize,
+>
+    ProfilingAllocator<
+        CPU_FREQ,
+        STACK_MIN,
+        STACK_MAX,
+        FULL_POLICY,
+        ALLOC_SLOTS,
+        HEAP_SAMPLE_BYTES,
+        HEAP_MIN_SIZE,
+    >
+{
+    const VALID_ALLOC_SLOTS: () = assert!(
+        ALLOC_SLOTS.is_power_of_two(),
+        "alloc_slots must be a power of two"
+    );
 
-impl<const CPU_FREQ: u32> ProfilingAllocator<CPU_FREQ> {
     pub const fn new() -> Self {
+        let () = Self::VALID_ALLOC_SLOTS;
         Self
     }
+
+    /// Whether an allocation of `size` bytes gets a stack trace
+    #[inline(always)]
+    pub const fn captures_stack(size: usize) -> bool {
+        size >= STACK_MIN && size <= STACK_MAX
+    }
+
+    /// Whether an allocation of `size` bytes is recorded at all
+    ///
+    /// Depends only on the size, so the free of an allocation that was
+    /// skipped (same layout) is skipped too.
+    #[inline(always)]
+    pub const fn tracks(size: usize) -> bool {
+        size >= HEAP_MIN_SIZE
+    }
 }
 
-impl<const CPU_FREQ: u32> Default for ProfilingAllocator<CPU_FREQ> {
+impl<
+    const CPU_FREQ: u32,
+    const STACK_MIN: usize,
+    const STACK_MAX: usize,
+    const FULL_POLICY: u8,
+    const ALLOC_SLOTS: usize,
+    const HEAP_SAMPLE_BYTES: usize,
+    const HEAP_MIN_SIZE: usize,
+> Default
+    for ProfilingAllocator<
+        CPU_FREQ,
+        STACK_MIN,
+        STACK_MAX,
+        FULL_POLICY,
+        ALLOC_SLOTS,
+        HEAP_SAMPLE_BYTES,
+        HEAP_MIN_SIZE,
+    >
+{
     fn default() -> Self {
         Self::new()
     }
@@ -78,15 +401,58 @@ impl<const CPU_FREQ: u32> Default for ProfilingAllocator<CPU_FREQ> {
 // Legacy alias for backwards compatibility
 pub type HeapProfiler = ProfilingAllocator<99>;
 
+// Minimum alignment guaranteed by malloc (typically 8 on 32-bit, 16 on 64-bit)
+const MIN_ALIGN: usize = core::mem::size_of::<usize>() * 2;
+
+/// Allocate with proper alignment using posix_memalign when needed
+#[inline(never)]
+unsafe fn aligned_malloc(size: usize, align: usize) -> *mut u8 {
+    if align <= MIN_ALIGN {
+        // malloc provides sufficient alignment
+        unsafe { libc::malloc(size) as *mut u8 }
+    } else {
+        // Need explicit alignment
+        let mut ptr: *mut libc::c_void = core::ptr::null_mut();
+        let ret = unsafe { libc::posix_memalign(&mut ptr, align, size) };
+        if ret == 0 {
+            ptr as *mut u8
+        } else {
+            core::ptr::null_mut()
+        }
+    }
+}
+
 #[cfg(not(feature = "heap"))]
 mod disabled {
-    use super::ProfilingAllocator;
+    use super::{MIN_ALIGN, ProfilingAllocator, aligned_malloc};
     use core::alloc::{GlobalAlloc, Layout};
 
-    unsafe impl<const CPU_FREQ: u32> GlobalAlloc for ProfilingAllocator<CPU_FREQ> {
+    unsafe impl<
+        const CPU_FREQ: u32,
+        const STACK_MIN: usize,
+        const STACK_MAX: usize,
+        const FULL_POLICY: u8,
+        const ALLOC_SLOTS: usize,
+        const HEAP_SAMPLE_BYTES: usize,
+        const HEAP_MIN_SIZE: usize,
+    > GlobalAlloc
+        for ProfilingAllocator<
+            CPU_FREQ,
+            STACK_MIN,
+            STACK_MAX,
+            FULL_POLICY,
+            ALLOC_SLOTS,
+            HEAP_SAMPLE_BYTES,
+            HEAP_MIN_SIZE,
+        >
+    {
         #[inline]
         unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-            unsafe { libc::malloc(layout.size()) as *mut u8 }
+            if layout.align() <= MIN_ALIGN {
+                unsafe { libc::malloc(layout.size()) as *mut u8 }
+            } else {
+                unsafe { aligned_malloc(layout.size(), layout.align()) }
+            }
         }
 
         #[inline]
@@ -95,75 +461,147 @@ mod disabled {
         }
 
         #[inline]
-        unsafe fn realloc(&self, ptr: *mut u8, _layout: Layout, new_size: usize) -> *mut u8 {
-            unsafe { libc::realloc(ptr as *mut libc::c_void, new_size) as *mut u8 }
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            // realloc doesn't preserve alignment, so over-aligned blocks are
+            // moved by hand
+            if layout.align() > MIN_ALIGN {
+                let new_ptr = unsafe { aligned_malloc(new_size, layout.align()) };
+                if !new_ptr.is_null() {
+                    let copy_size = new_size.min(layout.size());
+                    unsafe { core::ptr::copy_nonoverlapping(ptr, new_ptr, copy_size) };
+                    unsafe { libc::free(ptr as *mut libc::c_void) };
+                }
+                new_ptr
+            } else {
+                unsafe { libc::realloc(ptr as *mut libc::c_void, new_size) as *mut u8 }
+            }
         }
 
         #[inline]
         unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
-            unsafe { libc::calloc(1, layout.size()) as *mut u8 }
+            if layout.align() <= MIN_ALIGN {
+                unsafe { libc::calloc(1, layout.size()) as *mut u8 }
+            } else {
+                // calloc doesn't support alignment, use aligned_malloc + memset
+                let ptr = unsafe { aligned_malloc(layout.size(), layout.align()) };
+                if !ptr.is_null() {
+                    unsafe { core::ptr::write_bytes(ptr, 0, layout.size()) };
+                }
+                ptr
+            }
         }
     }
 }
 
 #[cfg(feature = "heap")]
 mod enabled {
-    use super::ProfilingAllocator;
     #[cfg(feature = "cpu")]
     use super::profiling::start_cpu_profiling;
-    use super::profiling::{record_alloc, record_dealloc};
+    use super::profiling::{
+        record_alloc, record_dealloc, sample_alloc, set_alloc_slots, set_full_policy,
+    };
+    use super::{FullPolicy, MIN_ALIGN, ProfilingAllocator, aligned_malloc};
     use core::alloc::{GlobalAlloc, Layout};
     use core::sync::atomic::{AtomicBool, Ordering};
 
-    static CPU_INITIALIZED: AtomicBool = AtomicBool::new(false);
+    static STARTED: AtomicBool = AtomicBool::new(false);
 
+    /// Apply the allocator's configuration and start CPU profiling, once
     #[inline]
-    fn maybe_init_cpu<const FREQ: u32>() {
+    fn maybe_start<const FREQ: u32, const FULL_POLICY: u8, const ALLOC_SLOTS: usize>() {
+        if STARTED.load(Ordering::Relaxed) || STARTED.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // Before anything sets up shared memory, which fixes the table size
+        set_alloc_slots(ALLOC_SLOTS);
+        set_full_policy(FullPolicy::from_u8(FULL_POLICY));
         #[cfg(feature = "cpu")]
         {
-            if FREQ > 0 && !CPU_INITIALIZED.swap(true, Ordering::SeqCst) {
+            if FREQ > 0 {
                 start_cpu_profiling(FREQ);
             }
         }
     }
 
-    // Minimum alignment guaranteed by malloc (typically 8 on 32-bit, 16 on 64-bit)
-    const MIN_ALIGN: usize = core::mem::size_of::<usize>() * 2;
+    impl<
+        const CPU_FREQ: u32,
+        const STACK_MIN: usize,
+        const STACK_MAX: usize,
+        const FULL_POLICY: u8,
+        const ALLOC_SLOTS: usize,
+        const HEAP_SAMPLE_BYTES: usize,
+        const HEAP_MIN_SIZE: usize,
+    >
+        ProfilingAllocator<
+            CPU_FREQ,
+            STACK_MIN,
+            STACK_MAX,
+            FULL_POLICY,
+            ALLOC_SLOTS,
+            HEAP_SAMPLE_BYTES,
+            HEAP_MIN_SIZE,
+        >
+    {
+        /// Record a free, unless the allocation was too small to be recorded
+        #[inline(always)]
+        fn forget(ptr: *mut u8, size: usize) {
+            if Self::tracks(size) {
+                record_dealloc(ptr, size);
+            }
+        }
 
-    /// Allocate with proper alignment using posix_memalign when needed
-    #[inline(never)]
-    unsafe fn aligned_malloc(size: usize, align: usize) -> *mut u8 {
-        if align <= MIN_ALIGN {
-            // malloc provides sufficient alignment
-            unsafe { libc::malloc(size) as *mut u8 }
-        } else {
-            // Need explicit alignment
-            let mut ptr: *mut libc::c_void = core::ptr::null_mut();
-            let ret = unsafe { libc::posix_memalign(&mut ptr, align, size) };
-            if ret == 0 {
-                ptr as *mut u8
+        /// Record an allocation of at least `HEAP_MIN_SIZE` bytes, or only a
+        /// weighted sample of them with `HEAP_SAMPLE_BYTES` set
+        #[inline(always)]
+        fn record(ptr: *mut u8, size: usize) {
+            if !Self::tracks(size) {
+                return;
+            }
+            let weight = if HEAP_SAMPLE_BYTES == 0 {
+                1
             } else {
-                core::ptr::null_mut()
+                sample_alloc(size, HEAP_SAMPLE_BYTES)
+            };
+            if weight > 0 {
+                record_alloc(ptr, size, Self::captures_stack(size), weight);
             }
         }
     }
 
-    unsafe impl<const CPU_FREQ: u32> GlobalAlloc for ProfilingAllocator<CPU_FREQ> {
+    unsafe impl<
+        const CPU_FREQ: u32,
+        const STACK_MIN: usize,
+        const STACK_MAX: usize,
+        const FULL_POLICY: u8,
+        const ALLOC_SLOTS: usize,
+        const HEAP_SAMPLE_BYTES: usize,
+        const HEAP_MIN_SIZE: usize,
+    > GlobalAlloc
+        for ProfilingAllocator<
+            CPU_FREQ,
+            STACK_MIN,
+            STACK_MAX,
+            FULL_POLICY,
+            ALLOC_SLOTS,
+            HEAP_SAMPLE_BYTES,
+            HEAP_MIN_SIZE,
+        >
+    {
         // IMPORTANT: These must NOT be inlined!
         // If inlined into libstd (which has no frame pointers), stack capture breaks.
         #[inline(never)]
         unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-            maybe_init_cpu::<CPU_FREQ>();
+            maybe_start::<CPU_FREQ, FULL_POLICY, ALLOC_SLOTS>();
             let ptr = unsafe { aligned_malloc(layout.size(), layout.align()) };
             if !ptr.is_null() {
-                record_alloc(ptr, layout.size());
+                Self::record(ptr, layout.size());
             }
             ptr
         }
 
         #[inline(never)]
         unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-            record_dealloc(ptr, layout.size());
+            Self::forget(ptr, layout.size());
             unsafe { libc::free(ptr as *mut libc::c_void) }
         }
 
@@ -180,17 +618,17 @@ mod enabled {
                         layout.size()
                     };
                     unsafe { core::ptr::copy_nonoverlapping(ptr, new_ptr, copy_size) };
-                    record_dealloc(ptr, layout.size());
+                    Self::forget(ptr, layout.size());
                     unsafe { libc::free(ptr as *mut libc::c_void) };
-                    record_alloc(new_ptr, new_size);
+                    Self::record(new_ptr, new_size);
                 }
                 new_ptr
             } else {
-                record_dealloc(ptr, layout.size());
+                Self::forget(ptr, layout.size());
                 let new_ptr =
                     unsafe { libc::realloc(ptr as *mut libc::c_void, new_size) as *mut u8 };
                 if !new_ptr.is_null() {
-                    record_alloc(new_ptr, new_size);
+                    Self::record(new_ptr, new_size);
                 }
                 new_ptr
             }
@@ -198,11 +636,11 @@ mod enabled {
 
         #[inline(never)]
         unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
-            maybe_init_cpu::<CPU_FREQ>();
+            maybe_start::<CPU_FREQ, FULL_POLICY, ALLOC_SLOTS>();
             if layout.align() <= MIN_ALIGN {
                 let ptr = unsafe { libc::calloc(1, layout.size()) as *mut u8 };
                 if !ptr.is_null() {
-                    record_alloc(ptr, layout.size());
+                    Self::record(ptr, layout.size());
                 }
                 ptr
             } else {
@@ -210,7 +648,7 @@ mod enabled {
                 let ptr = unsafe { aligned_malloc(layout.size(), layout.align()) };
                 if !ptr.is_null() {
                     unsafe { core::ptr::write_bytes(ptr, 0, layout.size()) };
-                    record_alloc(ptr, layout.size());
+                    Self::record(ptr, layout.size());
                 }
                 ptr
             }
@@ -224,6 +662,11 @@ mod enabled {
 /// CPU profiling starts automatically on the first allocation.
 /// When the `profiling` feature is disabled, it expands to a zero-cost no-op.
 ///
+/// `cpu_only` (optionally `= freq`) samples CPU without tracking the heap: no
+/// global allocator is registered, and sampling starts from a constructor
+/// before `main`. It only needs the `cpu` feature; [`stop_cpu_profiling`]
+/// turns it off.
+///
 /// # Examples
 ///
 /// ```rust,ignore
@@ -232,6 +675,30 @@ mod enabled {
 ///
 /// // Custom CPU frequency
 /// rsprof_trace::profiler!(cpu = 199);
+///
+/// // Only capture stacks for allocations of 1MB or more
+/// rsprof_trace::profiler!(stack_min = 1024 * 1024);
+///
+/// // Options can be combined, in any order
+/// rsprof_trace::profiler!(cpu = 199, stack_min = 4096, stack_max = 65536);
+///
+/// // Once the callsite table is full, drop events from new call sites
+/// // (`overwrite` is the default, `count_only` keeps totals without stacks)
+/// rsprof_trace::profiler!(full = drop_new);
+///
+/// // Track up to 16K live allocations for free attribution (640KB of shared
+/// // memory instead of the default 10MB; must be a power of two)
+/// rsprof_trace::profiler!(alloc_slots = 16384);
+///
+/// // Record one allocation per 512KB allocated, on average, weighted so
+/// // totals stay right (cheaper for allocation-heavy services)
+/// rsprof_trace::profiler!(heap_sample_bytes = 512 * 1024);
+///
+/// // Ignore allocations under 1KB (iterator and formatting scratch space)
+/// rsprof_trace::profiler!(heap_min_size = 1024);
+///
+/// // CPU only, at 199Hz: no allocation tracking at all
+/// rsprof_trace::profiler!(cpu_only = 199);
 /// ```
 ///
 /// # Build
@@ -243,20 +710,127 @@ mod enabled {
 #[macro_export]
 #[cfg(feature = "heap")]
 macro_rules! profiler {
-    () => {
-        $crate::profiler!(cpu = 99);
+    (cpu_only $(= $freq:expr)?) => {
+        $crate::__cpu_only_profiler!($($freq)?);
+    };
+
+    // Option parsing: [cpu, stack_min, stack_max, full, alloc_slots, heap_sample_bytes,
+    // heap_min_size]
+    (@config [$cpu:expr, $min:expr, $max:expr, $full:expr, $slots:expr, $sample:expr, $min_size:expr] cpu = $v:expr $(, $($rest:tt)*)?) => {
+        $crate::profiler!(@config [$v, $min, $max, $full, $slots, $sample, $min_size] $($($rest)*)?);
+    };
+    (@config [$cpu:expr, $min:expr, $max:expr, $full:expr, $slots:expr, $sample:expr, $min_size:expr] stack_min = $v:expr $(, $($rest:tt)*)?) => {
+        $crate::profiler!(@config [$cpu, $v, $max, $full, $slots, $sample, $min_size] $($($rest)*)?);
+    };
+    (@config [$cpu:expr, $min:expr, $max:expr, $full:expr, $slots:expr, $sample:expr, $min_size:expr] stack_max = $v:expr $(, $($rest:tt)*)?) => {
+        $crate::profiler!(@config [$cpu, $min, $v, $full, $slots, $sample, $min_size] $($($rest)*)?);
+    };
+    (@config [$cpu:expr, $min:expr, $max:expr, $full:expr, $slots:expr, $sample:expr, $min_size:expr] full = overwrite $(, $($rest:tt)*)?) => {
+        $crate::profiler!(@config [$cpu, $min, $max, $crate::FullPolicy::Overwrite, $slots, $sample, $min_size] $($($rest)*)?);
     };
-    (cpu = $freq:expr) => {
+    (@config [$cpu:expr, $min:expr, $max:expr, $full:expr, $slots:expr, $sample:expr, $min_size:expr] full = drop_new $(, $($rest:tt)*)?) => {
+        $crate::profiler!(@config [$cpu, $min, $max, $crate::FullPolicy::DropNew, $slots, $sample, $min_size] $($($rest)*)?);
+    };
+    (@config [$cpu:expr, $min:expr, $max:expr, $full:expr, $slots:expr, $sample:expr, $min_size:expr] full = count_only $(, $($rest:tt)*)?) => {
+        $crate::profiler!(@config [$cpu, $min, $max, $crate::FullPolicy::CountOnly, $slots, $sample, $min_size] $($($rest)*)?);
+    };
+    (@config [$cpu:expr, $min:expr, $max:expr, $full:expr, $slots:expr, $sample:expr, $min_size:expr] alloc_slots = $v:expr $(, $($rest:tt)*)?) => {
+        $crate::profiler!(@config [$cpu, $min, $max, $full, $v, $sample, $min_size] $($($rest)*)?);
+    };
+    (@config [$cpu:expr, $min:expr, $max:expr, $full:expr, $slots:expr, $sample:expr, $min_size:expr] heap_sample_bytes = $v:expr $(, $($rest:tt)*)?) => {
+        $crate::profiler!(@config [$cpu, $min, $max, $full, $slots, $v, $min_size] $($($rest)*)?);
+    };
+    (@config [$cpu:expr, $min:expr, $max:expr, $full:expr, $slots:expr, $sample:expr, $min_size:expr] heap_min_size = $v:expr $(, $($rest:tt)*)?) => {
+        $crate::profiler!(@config [$cpu, $min, $max, $full, $slots, $sample, $v] $($($rest)*)?);
+    };
+    (@config [$cpu:expr, $min:expr, $max:expr, $full:expr, $slots:expr, $sample:expr, $min_size:expr]) => {
         #[global_allocator]
-        static __RSPROF_ALLOC: $crate::ProfilingAllocator<$freq> =
-            $crate::ProfilingAllocator::<$freq>::new();
+        static __RSPROF_ALLOC: $crate::ProfilingAllocator<
+            { $cpu },
+            { $min },
+            { $max },
+            { $full as u8 },
+            { $slots },
+            { $sample },
+            { $min_size },
+        > = $crate::ProfilingAllocator::<{ $cpu }, { $min }, { $max }, { $full as u8 }, { $slots }, { $sample }, { $min_size }>::new();
+    };
+
+    // Defaults: CPU at 99Hz, stacks for every allocation size, lossy when full,
+    // every allocation of any size recorded
+    ($($opts:tt)*) => {
+        $crate::profiler!(@config [99, 0, usize::MAX, $crate::FullPolicy::Overwrite, $crate::DEFAULT_ALLOC_SLOTS, 0, 0] $($opts)*);
     };
 }
 
-/// No-op when heap feature is disabled (CPU-only not supported with this macro)
+/// No-op when heap feature is disabled, except for `cpu_only`
 #[macro_export]
 #[cfg(not(feature = "heap"))]
 macro_rules! profiler {
-    () => {};
-    (cpu = $freq:expr) => {};
+    (cpu_only $(= $freq:expr)?) => {
+        $crate::__cpu_only_profiler!($($freq)?);
+    };
+    ($($opts:tt)*) => {};
+}
+
+/// Start CPU sampling from an `.init_array` constructor (`profiler!(cpu_only)`)
+///
+/// Expands to nothing without the `cpu` feature.
+#[doc(hidden)]
+#[macro_export]
+#[cfg(feature = "cpu")]
+macro_rules! __cpu_only_profiler {
+    () => {
+        $crate::__cpu_only_profiler!(99);
+    };
+    ($freq:expr) => {
+        const _: () = {
+            extern "C" fn __rsprof_start_cpu() {
+                $crate::start_cpu_profiling($freq);
+            }
+
+            #[used]
+            #[unsafe(link_section = ".init_array")]
+            static __RSPROF_CPU_INIT: extern "C" fn() = __rsprof_start_cpu;
+        };
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+#[cfg(not(feature = "cpu"))]
+macro_rules! __cpu_only_profiler {
+    ($($freq:expr)?) => {};
+}
+
+#[cfg(test)]
+mod tests {
+    use core::alloc::{GlobalAlloc, Layout};
+
+    /// Allocate, zero-allocate and grow blocks aligned past malloc's guarantee
+    pub(crate) fn check_over_aligned(allocator: &impl GlobalAlloc) {
+        for align in [32, 64, 4096] {
+            let layout = Layout::from_size_align(100, align).unwrap();
+            let aligned = |ptr: *mut u8| !ptr.is_null() && (ptr as usize).is_multiple_of(align);
+
+            let ptr = unsafe { allocator.alloc(layout) };
+            assert!(aligned(ptr), "alloc, align {align}");
+            unsafe { ptr.write_bytes(0xab, layout.size()) };
+            let grown = unsafe { allocator.realloc(ptr, layout, 5000) };
+            assert!(aligned(grown), "realloc, align {align}");
+            assert_eq!(unsafe { *grown.add(99) }, 0xab);
+            unsafe { allocator.dealloc(grown, Layout::from_size_align(5000, align).unwrap()) };
+
+            let zeroed = unsafe { allocator.alloc_zeroed(layout) };
+            assert!(aligned(zeroed), "alloc_zeroed, align {align}");
+            assert!((0..layout.size()).all(|i| unsafe { *zeroed.add(i) } == 0));
+            unsafe { allocator.dealloc(zeroed, layout) };
+        }
+    }
+
+    #[cfg(not(feature = "heap"))]
+    #[test]
+    fn over_aligned_allocations_are_aligned() {
+        check_over_aligned(&crate::ProfilingAllocator::<0>::new());
+    }
 }
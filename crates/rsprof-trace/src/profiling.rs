@@ -1,6 +1,8 @@
 //! Profiling implementation - aggregated callsite stats for CPU and heap.
 
-use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use crate::{DEFAULT_ALLOC_SLOTS, FullPolicy};
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 
 /// Maximum stack depth to capture
 const MAX_STACK_DEPTH: usize = 64;
@@ -8,20 +10,52 @@ const MAX_STACK_DEPTH: usize = 64;
 /// Number of callsite stats slots
 const CALLSITE_CAPACITY: usize = 8192;
 
-/// Number of allocation tracking slots
-const ALLOC_TABLE_CAPACITY: usize = 256 * 1024;
+/// Allocation size histogram buckets per callsite (powers of two; the last
+/// bucket also takes everything larger)
+const SIZE_BUCKETS: usize = 32;
+
+/// Number of marker slots (distinct marker names)
+const MARKER_CAPACITY: usize = 256;
+
+/// Maximum marker name length in bytes (including NUL terminator)
+const MARKER_NAME_LEN: usize = 64;
+
+/// Maximum checkpoint label length in bytes (including NUL terminator)
+const LABEL_LEN: usize = 64;
+
+/// Maximum executable path length in bytes (including NUL terminator)
+const EXE_PATH_LEN: usize = 256;
+
+/// Number of (alloc site, free site) pair slots
+const TRANSFER_CAPACITY: usize = 4096;
+
+/// Number of (callsite, thread) CPU sample count slots
+const THREAD_SAMPLE_CAPACITY: usize = 4096;
+
+/// Number of thread IDs rsprof can list in the thread filter
+const THREAD_FILTER_CAPACITY: usize = 256;
+
+/// `thread_filter_mode`: only threads listed in `thread_filter` are recorded
+const THREAD_FILTER_ONLY: u32 = 1;
+
+/// `thread_filter_mode`: threads listed in `thread_filter` are not recorded
+const THREAD_FILTER_EXCEPT: u32 = 2;
 
 /// Tombstone marker for deleted entries (allows continued probing)
 const TOMBSTONE: u64 = u64::MAX;
 
+/// Reserved callsite hash for allocations recorded without a stack
+/// (sizes outside the `ProfilingAllocator` stack capture range)
+const UNSTACKED_HASH: u64 = u64::MAX - 1;
+
 /// Shared memory path
 const SHM_PATH: &[u8] = b"/rsprof-trace\0";
 
 /// Magic number for validation
-const MAGIC: u64 = 0x5253_5052_4F46_5333; // "RSPROFS3" (stats v3)
+const MAGIC: u64 = 0x5253_5052_4F46_5341; // "RSPROFSA" (stats v17)
 
 /// Version number
-const VERSION: u32 = 3;
+const VERSION: u32 = 17;
 
 /// Aggregated stats per callsite
 #[repr(C)]
@@ -38,6 +72,14 @@ pub struct CallsiteStats {
     pub free_bytes: AtomicU64,
     /// CPU sample count
     pub cpu_samples: AtomicU64,
+    /// Bitwise NOT of the smallest allocation size (0 = none yet), so a
+    /// zeroed slot needs no initialization before `fetch_max`
+    pub min_size_inv: AtomicU64,
+    /// Largest allocation size
+    pub max_size: AtomicU64,
+    /// Allocation counts by size: bucket `i` holds sizes in `[2^i, 2^(i+1))`
+    /// (bucket 0 also holds size 0)
+    pub size_hist: [AtomicU64; SIZE_BUCKETS],
     /// Stack depth
     pub stack_depth: AtomicU32,
     /// Reserved for alignment
@@ -55,6 +97,65 @@ pub struct AllocEntry {
     pub size: AtomicU64,
     /// Callsite hash
     pub callsite_hash: AtomicU64,
+    /// Marker slot + 1 active when allocated (0 = none)
+    pub marker: AtomicU32,
+    /// Allocations this one stands for under `heap_sample_bytes` sampling
+    /// (0 = unsampled, i.e. 1)
+    pub weight: AtomicU32,
+    /// CLOCK_MONOTONIC time of the allocation in nanoseconds
+    pub alloc_ns: AtomicU64,
+}
+
+/// Aggregated heap stats per marker (logical request/operation)
+#[repr(C)]
+pub struct MarkerStats {
+    /// Hash of the marker name (0 = unused slot)
+    pub hash: AtomicU64,
+    /// Total allocation count
+    pub alloc_count: AtomicU64,
+    /// Total allocated bytes
+    pub alloc_bytes: AtomicU64,
+    /// Total free count
+    pub free_count: AtomicU64,
+    /// Total freed bytes
+    pub free_bytes: AtomicU64,
+    /// Set (Release) once `name` is written; the slot isn't read before
+    pub ready: AtomicU32,
+    /// NUL-terminated marker name
+    pub name: [u8; MARKER_NAME_LEN],
+}
+
+/// Allocations freed at `free_hash` that were made at `alloc_hash`
+#[repr(C)]
+pub struct TransferStats {
+    /// Hash of the (alloc, free) pair (0 = unused slot)
+    pub key: AtomicU64,
+    /// Callsite that made the allocations
+    pub alloc_hash: AtomicU64,
+    /// Callsite that freed them (0 while the slot is being claimed)
+    pub free_hash: AtomicU64,
+    /// Number of allocations freed
+    pub count: AtomicU64,
+    /// Bytes freed
+    pub bytes: AtomicU64,
+}
+
+/// CPU samples one thread took at a callsite
+///
+/// Callsites are shared by every thread; this side table keeps who took the
+/// samples, for grouping by thread and by (forked) process.
+#[repr(C)]
+pub struct ThreadSampleStats {
+    /// Hash of the (callsite, thread) pair (0 = unused slot)
+    pub key: AtomicU64,
+    /// Callsite the samples are counted against
+    pub callsite_hash: AtomicU64,
+    /// Thread ID (`gettid`) that took the samples
+    pub tid: AtomicU32,
+    /// Process the thread belongs to (0 while the slot is being claimed)
+    pub pid: AtomicU32,
+    /// Number of samples
+    pub samples: AtomicU64,
 }
 
 /// Shared memory header
@@ -70,12 +171,175 @@ pub struct StatsHeader {
     pub alloc_table_capacity: u32,
     /// Process ID
     pub pid: u32,
+    /// Marker table capacity
+    pub marker_capacity: u32,
+    /// SIGPROF sampling frequency in Hz (0 = CPU profiling not started)
+    pub cpu_freq_hz: AtomicU32,
+    /// Estimated nanoseconds spent inside the profiler hooks (all threads)
+    pub overhead_ns: AtomicU64,
+    /// Checkpoint label sequence number (odd while `label` is being written)
+    pub label_seq: AtomicU64,
+    /// NUL-terminated text of the most recent checkpoint label
+    pub label: [u8; LABEL_LEN],
+    /// Nanoseconds spent in the SIGPROF handler (part of `overhead_ns`)
+    pub cpu_overhead_ns: AtomicU64,
+    /// Sampling frequency requested by rsprof (`--auto-freq`; 0 = none)
+    pub cpu_freq_request_hz: AtomicU32,
+    /// Set by rsprof to capture free-site stacks (`--transfers`; 0 = off)
+    pub free_sites: AtomicU32,
+    /// Transfer table capacity
+    pub transfer_capacity: u32,
+    /// Set by rsprof to keep CPU samples taken in forked children
+    /// (`--follow-forks`; 0 = only this process's)
+    pub follow_forks: AtomicU32,
+    /// Load address of the executable minus the file offset of its first
+    /// mapping (what rsprof would read from `/proc/<pid>/maps`)
+    pub aslr_offset: u64,
+    /// NUL-terminated path of the executable, as the process sees it
+    pub exe_path: [u8; EXE_PATH_LEN],
+    /// Events that lost detail because a table was full: allocations or
+    /// samples without a callsite of their own, and allocations whose free
+    /// can't be attributed (only ever increases)
+    pub dropped_events: AtomicU64,
+    /// Thread sample table capacity
+    pub thread_sample_capacity: u32,
+    /// Set by rsprof to record only some threads (`--threads`,
+    /// `--exclude-thread`): 0 = all, `THREAD_FILTER_ONLY` or `THREAD_FILTER_EXCEPT`
+    pub thread_filter_mode: AtomicU32,
+    /// Number of valid entries in `thread_filter`
+    pub thread_filter_len: AtomicU32,
+    /// Thread IDs the filter lists, as this process sees them
+    pub thread_filter: [AtomicU32; THREAD_FILTER_CAPACITY],
 }
 
+/// Every Nth allocator hook is timed and its cost scaled by N (prime, so
+/// regular alloc/free patterns don't always land on the same kind of call)
+const OVERHEAD_SAMPLE_PERIOD: u32 = 61;
+
 /// Global state
+static OVERHEAD_TICK: AtomicU32 = AtomicU32::new(0);
+/// Cost of reading the clock, subtracted from each timed hook
+static TIMER_COST_NS: AtomicU64 = AtomicU64::new(0);
 static INITIALIZED: AtomicBool = AtomicBool::new(false);
+/// PID of this process, updated in forked children (see `after_fork_in_child`)
+static PROCESS_ID: AtomicU32 = AtomicU32::new(0);
 static IN_SIGNAL_HANDLER: AtomicBool = AtomicBool::new(false);
 static mut SHM_BASE: *mut u8 = core::ptr::null_mut();
+/// Allocation tracking slots, a power of two; fixed once shared memory is set up
+static ALLOC_SLOTS: AtomicUsize = AtomicUsize::new(DEFAULT_ALLOC_SLOTS);
+/// `FullPolicy` applied once the callsite table has no free slot
+static FULL_POLICY: AtomicU8 = AtomicU8::new(FullPolicy::Overwrite as u8);
+/// Allocations are recorded while set (see `set_heap_recording`)
+#[cfg(feature = "heap")]
+static HEAP_RECORDING: AtomicBool = AtomicBool::new(true);
+/// SIGPROF samples are recorded while set (see `set_cpu_recording`)
+#[cfg(feature = "cpu")]
+static CPU_RECORDING: AtomicBool = AtomicBool::new(true);
+
+std::thread_local! {
+    /// Set while the thread is inside a profiler critical section. SIGPROF
+    /// samples taken then are dropped so the profiler's own bookkeeping
+    /// doesn't show up as (or skew) CPU time of the target.
+    ///
+    /// Const-initialized and without a destructor, so it is a plain TLS
+    /// slot: reading it never allocates and is safe in the signal handler.
+    static IN_PROFILER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether the calling thread is inside a profiler critical section
+#[cfg(feature = "cpu")]
+#[inline]
+fn in_profiler() -> bool {
+    IN_PROFILER.with(Cell::get)
+}
+
+/// Flags the calling thread as inside the profiler until dropped
+#[cfg(feature = "heap")]
+struct ProfilerSection {
+    /// Previous flag value, so nested sections restore correctly
+    prev: bool,
+}
+
+#[cfg(feature = "heap")]
+impl ProfilerSection {
+    #[inline]
+    fn enter() -> Self {
+        ProfilerSection {
+            prev: IN_PROFILER.with(|flag| flag.replace(true)),
+        }
+    }
+}
+
+#[cfg(feature = "heap")]
+impl Drop for ProfilerSection {
+    #[inline]
+    fn drop(&mut self) {
+        IN_PROFILER.with(|flag| flag.set(self.prev));
+    }
+}
+
+std::thread_local! {
+    /// Thread ID of the calling thread once looked up (0 = not yet); read
+    /// and set from the SIGPROF handler, so also without lazy init or drop
+    static TID: Cell<u32> = const { Cell::new(0) };
+}
+
+/// Thread ID of the calling thread, cached after the first `gettid`
+#[inline]
+fn current_tid() -> u32 {
+    TID.with(|tid| match tid.get() {
+        0 => {
+            let current = unsafe { libc::syscall(libc::SYS_gettid) } as u32;
+            tid.set(current);
+            current
+        }
+        cached => cached,
+    })
+}
+
+/// Runs in the child after `fork()`, on its only thread
+///
+/// The child inherits the shared memory mapping, so what it records lands
+/// next to the parent's stats. Its thread has a new ID (the cached one is the
+/// forking thread's), and interval timers aren't inherited, so CPU sampling
+/// is restarted at the parent's rate.
+extern "C" fn after_fork_in_child() {
+    PROCESS_ID.store(unsafe { libc::getpid() } as u32, Ordering::Relaxed);
+    TID.with(|tid| tid.set(0));
+    #[cfg(feature = "cpu")]
+    cpu_profiling::restart_after_fork();
+}
+
+/// Whether CPU samples taken in this process are wanted
+///
+/// Forked children only record them once rsprof asks (`follow_forks`).
+#[cfg(feature = "cpu")]
+#[inline]
+fn samples_this_process() -> bool {
+    let header = get_header();
+    PROCESS_ID.load(Ordering::Relaxed) == unsafe { (*header).pid }
+        || unsafe { (*header).follow_forks.load(Ordering::Relaxed) } != 0
+}
+
+/// Whether rsprof asked not to record the calling thread
+#[inline]
+fn thread_filtered_out() -> bool {
+    filter_excludes(unsafe { &*get_header() }, current_tid())
+}
+
+/// Whether the thread filter in `header` leaves out thread `tid`
+fn filter_excludes(header: &StatsHeader, tid: u32) -> bool {
+    let mode = header.thread_filter_mode.load(Ordering::Acquire);
+    if mode != THREAD_FILTER_ONLY && mode != THREAD_FILTER_EXCEPT {
+        return false;
+    }
+    let len =
+        (header.thread_filter_len.load(Ordering::Acquire) as usize).min(THREAD_FILTER_CAPACITY);
+    let listed = header.thread_filter[..len]
+        .iter()
+        .any(|listed| listed.load(Ordering::Relaxed) == tid);
+    listed != (mode == THREAD_FILTER_ONLY)
+}
 
 /// Get pointer to the header
 #[inline]
@@ -100,6 +364,38 @@ fn get_alloc_table() -> *mut AllocEntry {
     }
 }
 
+/// Index mask for the alloc table (its size is a power of two)
+#[inline]
+fn alloc_slot_mask() -> usize {
+    ALLOC_SLOTS.load(Ordering::Relaxed) - 1
+}
+
+/// Get pointer to marker stats array
+#[inline]
+fn get_markers() -> *mut MarkerStats {
+    let alloc_table_size = ALLOC_SLOTS.load(Ordering::Relaxed) * core::mem::size_of::<AllocEntry>();
+    unsafe { (get_alloc_table() as *mut u8).add(alloc_table_size) as *mut MarkerStats }
+}
+
+/// Get pointer to transfer stats array (after the markers)
+#[cfg(feature = "heap")]
+#[inline]
+fn get_transfers() -> *mut TransferStats {
+    let markers_size = MARKER_CAPACITY * core::mem::size_of::<MarkerStats>();
+    unsafe { (get_markers() as *mut u8).add(markers_size) as *mut TransferStats }
+}
+
+/// Get pointer to thread sample stats array (after the transfers)
+#[cfg(feature = "cpu")]
+#[inline]
+fn get_thread_samples() -> *mut ThreadSampleStats {
+    let markers_size = MARKER_CAPACITY * core::mem::size_of::<MarkerStats>();
+    let transfers_size = TRANSFER_CAPACITY * core::mem::size_of::<TransferStats>();
+    unsafe {
+        (get_markers() as *mut u8).add(markers_size + transfers_size) as *mut ThreadSampleStats
+    }
+}
+
 /// Check if shared memory is initialized
 #[inline]
 fn shm_ready() -> bool {
@@ -147,14 +443,25 @@ fn stack_key_cpu(stack: &[u64], depth: u32) -> u64 {
     key
 }
 
-/// Find or create a callsite entry. Returns pointer to the CallsiteStats.
+/// Find or create a callsite entry. Returns pointer to the CallsiteStats,
+/// or null when the table is full.
 #[inline]
 fn find_or_create_callsite(
     hash: u64,
     stack: &[u64; MAX_STACK_DEPTH],
     depth: u32,
 ) -> *mut CallsiteStats {
-    let callsites = get_callsites();
+    find_or_create_callsite_in(get_callsites(), hash, stack, depth)
+}
+
+/// `find_or_create_callsite` in the callsite table at `callsites`
+#[inline]
+fn find_or_create_callsite_in(
+    callsites: *mut CallsiteStats,
+    hash: u64,
+    stack: &[u64; MAX_STACK_DEPTH],
+    depth: u32,
+) -> *mut CallsiteStats {
     let mut idx = (hash as usize) % CALLSITE_CAPACITY;
 
     for _ in 0..CALLSITE_CAPACITY {
@@ -195,8 +502,77 @@ fn find_or_create_callsite(
         idx = (idx + 1) % CALLSITE_CAPACITY;
     }
 
-    // Table full - return first slot as fallback (will aggregate there)
-    callsites
+    core::ptr::null_mut()
+}
+
+/// Size the allocation tracking table (a power of two)
+///
+/// Only takes effect before shared memory is set up, i.e. before the first
+/// allocation or `start_cpu_profiling`.
+#[cfg(feature = "heap")]
+pub fn set_alloc_slots(slots: usize) {
+    if slots.is_power_of_two() && !INITIALIZED.load(Ordering::Relaxed) {
+        ALLOC_SLOTS.store(slots, Ordering::Relaxed);
+    }
+}
+
+/// Set what happens to new callsites once the callsite table is full
+#[cfg(feature = "heap")]
+pub fn set_full_policy(policy: FullPolicy) {
+    FULL_POLICY.store(policy as u8, Ordering::Relaxed);
+
+    // Claim the "no stack" callsite up front so there is still somewhere to
+    // count into once the table fills (the reader skips it while it's empty)
+    if policy == FullPolicy::CountOnly {
+        if !INITIALIZED.load(Ordering::Relaxed) {
+            init();
+        }
+        if shm_ready() {
+            find_or_create_callsite(UNSTACKED_HASH, &[0; MAX_STACK_DEPTH], 0);
+        }
+    }
+}
+
+/// Callsite that a new event is counted against, applying `FULL_POLICY` when
+/// the table has no slot left for `hash`. Returns null if the event is dropped.
+#[inline]
+fn callsite_for_event(hash: u64, stack: &[u64; MAX_STACK_DEPTH], depth: u32) -> *mut CallsiteStats {
+    let policy = FullPolicy::from_u8(FULL_POLICY.load(Ordering::Relaxed));
+    callsite_for_event_in(get_callsites(), policy, hash, stack, depth)
+}
+
+/// `callsite_for_event` in the callsite table at `callsites`, under `policy`
+#[inline]
+fn callsite_for_event_in(
+    callsites: *mut CallsiteStats,
+    policy: FullPolicy,
+    hash: u64,
+    stack: &[u64; MAX_STACK_DEPTH],
+    depth: u32,
+) -> *mut CallsiteStats {
+    let entry = find_or_create_callsite_in(callsites, hash, stack, depth);
+    if !entry.is_null() {
+        return entry;
+    }
+    count_dropped_event();
+
+    match policy {
+        FullPolicy::DropNew => core::ptr::null_mut(),
+        // Without a stack, under the "no stack" callsite: reserved by
+        // `set_full_policy` for CountOnly, so totals stay right; under
+        // Overwrite only there if it was claimed before the table filled
+        FullPolicy::Overwrite | FullPolicy::CountOnly => {
+            find_or_create_callsite_in(callsites, UNSTACKED_HASH, stack, 0)
+        }
+    }
+}
+
+/// Histogram bucket for an allocation of `size` bytes: floor(log2(size))
+#[cfg(feature = "heap")]
+#[inline]
+fn size_bucket(size: usize) -> usize {
+    let log2 = (usize::BITS - size.leading_zeros()).saturating_sub(1) as usize;
+    log2.min(SIZE_BUCKETS - 1)
 }
 
 /// Find a callsite by hash only (for dealloc attribution)
@@ -224,12 +600,57 @@ fn find_callsite(hash: u64) -> *mut CallsiteStats {
     core::ptr::null_mut()
 }
 
+/// Current CLOCK_MONOTONIC time in nanoseconds (comparable across processes)
+#[inline]
+fn monotonic_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Start timing an allocator hook (0 = this call is not sampled)
+#[inline]
+fn overhead_start() -> u64 {
+    if OVERHEAD_TICK
+        .fetch_add(1, Ordering::Relaxed)
+        .is_multiple_of(OVERHEAD_SAMPLE_PERIOD)
+    {
+        monotonic_ns()
+    } else {
+        0
+    }
+}
+
+/// Add the time since `start` (scaled by `scale`) to the overhead counter
+///
+/// Returns the time added.
+#[inline]
+fn overhead_end(start: u64, scale: u64) -> u64 {
+    if start == 0 || !shm_ready() {
+        return 0;
+    }
+    let elapsed = monotonic_ns()
+        .saturating_sub(start)
+        .saturating_sub(TIMER_COST_NS.load(Ordering::Relaxed))
+        * scale;
+    unsafe {
+        (*get_header())
+            .overhead_ns
+            .fetch_add(elapsed, Ordering::Relaxed)
+    };
+    elapsed
+}
+
 /// Track an allocation in the alloc table
 #[inline]
-fn track_alloc(ptr: u64, size: u64, callsite_hash: u64) {
+fn track_alloc(ptr: u64, size: u64, callsite_hash: u64, marker: u64, weight: u64) {
     let alloc_table = get_alloc_table();
+    let mask = alloc_slot_mask();
     // Use pointer bits for better distribution (skip low bits which are often 0)
-    let mut idx = ((ptr >> 4) as usize) % ALLOC_TABLE_CAPACITY;
+    let mut idx = ((ptr >> 4) as usize) & mask;
 
     for _ in 0..1024 {
         // Limited probing to avoid long searches
@@ -246,6 +667,11 @@ fn track_alloc(ptr: u64, size: u64, callsite_hash: u64) {
             } {
                 unsafe {
                     (*entry).size.store(size, Ordering::Relaxed);
+                    (*entry).marker.store(marker as u32, Ordering::Relaxed);
+                    (*entry)
+                        .weight
+                        .store(weight.min(u32::MAX as u64) as u32, Ordering::Relaxed);
+                    (*entry).alloc_ns.store(monotonic_ns(), Ordering::Relaxed);
                     (*entry)
                         .callsite_hash
                         .store(callsite_hash, Ordering::Release);
@@ -255,17 +681,29 @@ fn track_alloc(ptr: u64, size: u64, callsite_hash: u64) {
             // CAS failed, another thread took this slot - continue probing
         }
 
-        idx = (idx + 1) % ALLOC_TABLE_CAPACITY;
+        idx = (idx + 1) & mask;
     }
 
     // Table full or too much probing - drop this allocation's tracking
+    count_dropped_event();
+}
+
+/// Let rsprof know an event couldn't be recorded in full (see `dropped_events`)
+#[inline]
+fn count_dropped_event() {
+    unsafe {
+        (*get_header())
+            .dropped_events
+            .fetch_add(1, Ordering::Relaxed)
+    };
 }
 
-/// Untrack an allocation, returning (size, callsite_hash) if found
+/// Untrack an allocation, returning (size, callsite_hash, marker, weight) if found
 #[inline]
-fn untrack_alloc(ptr: u64) -> Option<(u64, u64)> {
+fn untrack_alloc(ptr: u64) -> Option<(u64, u64, u64, u64)> {
     let alloc_table = get_alloc_table();
-    let mut idx = ((ptr >> 4) as usize) % ALLOC_TABLE_CAPACITY;
+    let mask = alloc_slot_mask();
+    let mut idx = ((ptr >> 4) as usize) & mask;
 
     for _ in 0..1024 {
         let entry = unsafe { alloc_table.add(idx) };
@@ -274,9 +712,11 @@ fn untrack_alloc(ptr: u64) -> Option<(u64, u64)> {
         if stored_ptr == ptr {
             let size = unsafe { (*entry).size.load(Ordering::Relaxed) };
             let callsite_hash = unsafe { (*entry).callsite_hash.load(Ordering::Acquire) };
+            let marker = unsafe { (*entry).marker.load(Ordering::Relaxed) } as u64;
+            let weight = unsafe { (*entry).weight.load(Ordering::Relaxed) }.max(1) as u64;
             // Mark as tombstone (not 0!) to allow continued probing
             unsafe { (*entry).ptr.store(TOMBSTONE, Ordering::Release) };
-            return Some((size, callsite_hash));
+            return Some((size, callsite_hash, marker, weight));
         }
 
         if stored_ptr == 0 {
@@ -285,12 +725,22 @@ fn untrack_alloc(ptr: u64) -> Option<(u64, u64)> {
         }
 
         // Tombstone - continue probing
-        idx = (idx + 1) % ALLOC_TABLE_CAPACITY;
+        idx = (idx + 1) & mask;
     }
 
     None
 }
 
+/// Size of the shared memory: the header followed by each table
+fn shm_size() -> usize {
+    core::mem::size_of::<StatsHeader>()
+        + CALLSITE_CAPACITY * core::mem::size_of::<CallsiteStats>()
+        + ALLOC_SLOTS.load(Ordering::Relaxed) * core::mem::size_of::<AllocEntry>()
+        + MARKER_CAPACITY * core::mem::size_of::<MarkerStats>()
+        + TRANSFER_CAPACITY * core::mem::size_of::<TransferStats>()
+        + THREAD_SAMPLE_CAPACITY * core::mem::size_of::<ThreadSampleStats>()
+}
+
 /// Initialize the profiler - sets up shared memory
 pub fn init() {
     if INITIALIZED.swap(true, Ordering::SeqCst) {
@@ -298,11 +748,7 @@ pub fn init() {
     }
 
     unsafe {
-        // Calculate shared memory size
-        let header_size = core::mem::size_of::<StatsHeader>();
-        let callsites_size = CALLSITE_CAPACITY * core::mem::size_of::<CallsiteStats>();
-        let alloc_table_size = ALLOC_TABLE_CAPACITY * core::mem::size_of::<AllocEntry>();
-        let total_size = header_size + callsites_size + alloc_table_size;
+        let total_size = shm_size();
 
         // Remove any existing shared memory to ensure fresh start
         libc::shm_unlink(SHM_PATH.as_ptr() as *const libc::c_char);
@@ -351,40 +797,200 @@ pub fn init() {
         (*header).magic = MAGIC;
         (*header).version = VERSION;
         (*header).callsite_capacity = CALLSITE_CAPACITY as u32;
-        (*header).alloc_table_capacity = ALLOC_TABLE_CAPACITY as u32;
+        (*header).alloc_table_capacity = ALLOC_SLOTS.load(Ordering::Relaxed) as u32;
         (*header).pid = libc::getpid() as u32;
+        (*header).marker_capacity = MARKER_CAPACITY as u32;
+        (*header).transfer_capacity = TRANSFER_CAPACITY as u32;
+        (*header).thread_sample_capacity = THREAD_SAMPLE_CAPACITY as u32;
+        // Lets rsprof symbolicate when it can't read the target's /proc
+        (*header).aslr_offset = exe_aslr_offset();
+        read_exe_path(&mut (*header).exe_path);
+
+        // Forked children keep writing to the shared memory (see after_fork_in_child)
+        PROCESS_ID.store((*header).pid, Ordering::Relaxed);
+        libc::pthread_atfork(None, None, Some(after_fork_in_child));
+
+        // Key and seed for allocation sampling (see sample_alloc)
+        #[cfg(feature = "heap")]
+        {
+            let mut key: libc::pthread_key_t = 0;
+            if libc::pthread_key_create(&mut key, None) == 0 {
+                SAMPLE_KEY.store(key as u32 + 1, Ordering::Release);
+            }
+            SAMPLE_RNG.store(monotonic_ns() | 1, Ordering::Relaxed);
+            // For stack scanning when frame pointers are missing
+            init_text_range();
+        }
+
+        // Calibrate the clock read cost so overhead estimates don't include it
+        let mut timer_cost = u64::MAX;
+        for _ in 0..16 {
+            let start = monotonic_ns();
+            timer_cost = timer_cost.min(monotonic_ns().saturating_sub(start));
+        }
+        TIMER_COST_NS.store(timer_cost, Ordering::Relaxed);
 
         // Zero-initialize tables (mmap may already be zeroed, but be explicit)
         // Callsites and alloc table use 0 as "empty" marker
     }
 }
 
+/// ASLR offset of the executable, from its program headers
+///
+/// The runtime address of the program headers (`AT_PHDR`) minus their
+/// address in the file (`PT_PHDR`) is the load bias; the first `PT_LOAD`
+/// segment turns it into the start of the first mapping minus its file
+/// offset. Needs no `/proc`.
+fn exe_aslr_offset() -> u64 {
+    let (phdrs, bias) = exe_program_headers();
+    match phdrs.iter().find(|p| p.p_type == libc::PT_LOAD) {
+        Some(load) => bias.wrapping_add(load.p_vaddr).wrapping_sub(load.p_offset),
+        None => bias,
+    }
+}
+
+/// The executable's program headers and load bias (see `exe_aslr_offset`)
+fn exe_program_headers() -> (&'static [libc::Elf64_Phdr], u64) {
+    let phdrs = unsafe { libc::getauxval(libc::AT_PHDR) } as *const libc::Elf64_Phdr;
+    let count = unsafe { libc::getauxval(libc::AT_PHNUM) } as usize;
+    if phdrs.is_null() {
+        return (&[], 0);
+    }
+    let phdrs = unsafe { core::slice::from_raw_parts(phdrs, count) };
+    // No PT_PHDR: a non-PIE executable, loaded where it was linked
+    let bias = phdrs
+        .iter()
+        .find(|p| p.p_type == libc::PT_PHDR)
+        .map_or(0, |p| (phdrs.as_ptr() as u64).wrapping_sub(p.p_vaddr));
+    (phdrs, bias)
+}
+
+/// Write the executable's path, NUL-terminated, into `buf`
+///
+/// `/proc/self/exe` gives the resolved path; without procfs the path the
+/// program was started with (`AT_EXECFN`) stands in.
+fn read_exe_path(buf: &mut [u8; EXE_PATH_LEN]) {
+    let len = unsafe {
+        libc::readlink(
+            c"/proc/self/exe".as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_char,
+            EXE_PATH_LEN - 1,
+        )
+    };
+    if len > 0 {
+        buf[len as usize] = 0;
+        return;
+    }
+    let execfn = unsafe { libc::getauxval(libc::AT_EXECFN) } as *const libc::c_char;
+    if execfn.is_null() {
+        return;
+    }
+    let bytes = unsafe { core::ffi::CStr::from_ptr(execfn) }.to_bytes();
+    let len = bytes.len().min(EXE_PATH_LEN - 1);
+    buf[..len].copy_from_slice(&bytes[..len]);
+    buf[len] = 0;
+}
+
 /// Capture stack trace using frame pointers
+///
+/// Without frame pointers the walk finds nothing; the stack is then scanned
+/// for return addresses instead, which at least reaches the allocator's
+/// caller.
+#[cfg(feature = "heap")]
 #[inline(never)]
 fn capture_stack(stack: &mut [u64; MAX_STACK_DEPTH]) -> u32 {
-    capture_stack_from_fp(stack, core::ptr::null())
+    match capture_stack_from_fp(stack, core::ptr::null()) {
+        0 => scan_return_addresses(stack),
+        depth => depth,
+    }
+}
+
+/// Start and end of the executable's code, for `scan_return_addresses`
+#[cfg(feature = "heap")]
+static TEXT_START: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "heap")]
+static TEXT_END: AtomicU64 = AtomicU64::new(0);
+
+/// Stack words examined by `scan_return_addresses`: the callers' stack
+/// buffer plus a few frames of the allocator itself
+#[cfg(feature = "heap")]
+const SCAN_WORDS: usize = MAX_STACK_DEPTH + 256;
+/// Return addresses kept by `scan_return_addresses`
+#[cfg(feature = "heap")]
+const SCAN_FRAMES: usize = 16;
+
+/// Record where the executable's code is mapped
+#[cfg(feature = "heap")]
+fn init_text_range() {
+    let (phdrs, bias) = exe_program_headers();
+    let code = phdrs
+        .iter()
+        .filter(|p| p.p_type == libc::PT_LOAD && p.p_flags & libc::PF_X != 0);
+    let start = code.clone().map(|p| bias.wrapping_add(p.p_vaddr)).min();
+    let end = code.map(|p| bias.wrapping_add(p.p_vaddr + p.p_memsz)).max();
+    if let (Some(start), Some(end)) = (start, end) {
+        TEXT_START.store(start, Ordering::Relaxed);
+        TEXT_END.store(end, Ordering::Relaxed);
+    }
+}
+
+/// Collect return addresses by scanning up the stack from our own frame
+///
+/// The fallback for code built without frame pointers. A word counts as a
+/// return address if it points into the executable's code just after a call
+/// instruction. Stale words can slip in, but the first ones past the
+/// profiler's own frames are the allocator's callers, which rsprof's
+/// attribution skips to.
+#[cfg(feature = "heap")]
+#[inline(never)]
+fn scan_return_addresses(stack: &mut [u64; MAX_STACK_DEPTH]) -> u32 {
+    let start = TEXT_START.load(Ordering::Relaxed) as usize;
+    let end = TEXT_END.load(Ordering::Relaxed) as usize;
+    let sp = arch::stack_pointer();
+    if start == 0 || sp.is_null() {
+        return 0;
+    }
+    // The output buffer lives on the stack being scanned
+    let buffer = stack.as_ptr_range();
+    let mut depth = 0;
+    for i in 0..SCAN_WORDS {
+        let slot = unsafe { sp.add(i) };
+        if buffer.contains(&(slot as *const u64)) {
+            continue;
+        }
+        let word = arch::return_address(unsafe { slot.read_volatile() });
+        // Leave room to read the call instruction before it
+        if word >= start + 8 && word < end && unsafe { arch::follows_call(word) } {
+            stack[depth] = word as u64;
+            depth += 1;
+            if depth == SCAN_FRAMES {
+                break;
+            }
+        }
+    }
+    depth as u32
 }
 
 /// Capture stack trace by walking frame pointers
+#[cfg(feature = "heap")]
 #[inline(never)]
 fn capture_stack_from_fp(stack: &mut [u64; MAX_STACK_DEPTH], start_fp: *const usize) -> u32 {
-    let mut depth = 0u32;
+    // Start from our own frame unless given one
+    let fp = if start_fp.is_null() {
+        arch::frame_pointer()
+    } else {
+        start_fp
+    };
+    walk_frames(stack, 0, fp)
+}
 
+/// Append return addresses from the frame chain at `fp` to `stack[depth..]`
+///
+/// Returns the new depth. The walk stops at the first frame pointer that is
+/// misaligned, outside user space or not above the previous one, so a
+/// corrupt chain ends the stack instead of faulting.
+fn walk_frames(stack: &mut [u64; MAX_STACK_DEPTH], mut depth: u32, mut fp: *const usize) -> u32 {
     unsafe {
-        // Get starting frame pointer
-        let mut fp: *const usize = if start_fp.is_null() {
-            let current_fp: *const usize;
-            core::arch::asm!(
-                "mov {}, rbp",
-                out(reg) current_fp,
-                options(nomem, nostack, preserves_flags)
-            );
-            current_fp
-        } else {
-            start_fp
-        };
-
-        // Walk the stack using frame pointers
         while !fp.is_null() && depth < MAX_STACK_DEPTH as u32 {
             // Validate frame pointer alignment
             if (fp as usize) & 0x7 != 0 {
@@ -393,12 +999,12 @@ fn capture_stack_from_fp(stack: &mut [u64; MAX_STACK_DEPTH], start_fp: *const us
 
             // Bounds check
             let fp_val = fp as usize;
-            if !(0x1000..=0x7fff_ffff_ffff).contains(&fp_val) {
+            if !(0x1000..=arch::MAX_USER_ADDR).contains(&fp_val) {
                 break;
             }
 
-            // Read return address at [fp + 8]
-            let ret_addr = *fp.add(1);
+            // Frame record: saved frame pointer at [fp], return address at [fp + 8]
+            let ret_addr = arch::return_address(*fp.add(1));
             if ret_addr == 0 {
                 break;
             }
@@ -406,7 +1012,7 @@ fn capture_stack_from_fp(stack: &mut [u64; MAX_STACK_DEPTH], start_fp: *const us
             stack[depth as usize] = ret_addr as u64;
             depth += 1;
 
-            // Move to next frame (saved RBP is at [fp])
+            // Move to next frame
             let next_fp = *fp as *const usize;
             if next_fp <= fp {
                 break;
@@ -418,77 +1024,681 @@ fn capture_stack_from_fp(stack: &mut [u64; MAX_STACK_DEPTH], start_fp: *const us
     depth
 }
 
-// =============================================================================
-// Heap profiling (conditional on "heap" feature)
-// =============================================================================
-
-/// Record an allocation event
-#[cfg(feature = "heap")]
-#[inline(never)]
-pub fn record_alloc(ptr: *mut u8, size: usize) {
-    // Don't record allocations from within signal handler
-    if IN_SIGNAL_HANDLER.load(Ordering::Relaxed) {
-        return;
+/// Per-architecture frame pointer access
+///
+/// x86_64 and aarch64 share the frame record layout `walk_frames` relies on
+/// (rbp or x29 points at the caller's frame pointer, followed by the return
+/// address). They differ in the registers, the user address range and
+/// aarch64's pointer authentication bits on saved return addresses.
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    /// Highest user-space address (47-bit virtual addresses)
+    pub const MAX_USER_ADDR: usize = 0x7fff_ffff_ffff;
+
+    /// Frame pointer of the calling function
+    #[inline(always)]
+    pub fn frame_pointer() -> *const usize {
+        let fp: *const usize;
+        unsafe {
+            core::arch::asm!(
+                "mov {}, rbp",
+                out(reg) fp,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        fp
     }
 
-    // Ensure initialized
-    if !INITIALIZED.load(Ordering::Relaxed) {
-        init();
+    /// Return address as stored in a frame record
+    #[inline(always)]
+    pub fn return_address(raw: usize) -> usize {
+        raw
     }
 
-    if !shm_ready() {
-        return;
+    /// Stack pointer of the calling function
+    #[inline(always)]
+    pub fn stack_pointer() -> *const usize {
+        let sp: *const usize;
+        unsafe {
+            core::arch::asm!(
+                "mov {}, rsp",
+                out(reg) sp,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        sp
     }
 
-    // Capture stack and compute hash
-    let mut stack = [0u64; MAX_STACK_DEPTH];
-    let depth = capture_stack(&mut stack);
-    let hash = stack_key_heap(&stack, depth);
-
-    // Find or create callsite, update stats
-    let callsite = find_or_create_callsite(hash, &stack, depth);
-    unsafe {
-        (*callsite).alloc_count.fetch_add(1, Ordering::Relaxed);
-        (*callsite)
-            .alloc_bytes
-            .fetch_add(size as u64, Ordering::Relaxed);
+    /// Whether the code just before `addr` is a call instruction
+    ///
+    /// Matches `call rel32` (e8) and the `call r/m64` forms (ff /2) from the
+    /// register one up to a 7-byte memory operand. `addr - 7` must be
+    /// readable.
+    #[inline(always)]
+    pub unsafe fn follows_call(addr: usize) -> bool {
+        let code = addr as *const u8;
+        unsafe {
+            *code.sub(5) == 0xe8
+                || (2..=7).any(|len| *code.sub(len) == 0xff && (*code.sub(len - 1) >> 3) & 7 == 2)
+        }
     }
 
-    // Track allocation for later dealloc attribution
-    track_alloc(ptr as u64, size as u64, hash);
+    /// Instruction and frame pointer of an interrupted thread
+    #[cfg(feature = "cpu")]
+    pub unsafe fn interrupted_registers(uc: *const libc::ucontext_t) -> (u64, *const usize) {
+        const REG_RIP: usize = 16;
+        const REG_RBP: usize = 10;
+        unsafe {
+            let rip = (*uc).uc_mcontext.gregs[REG_RIP] as u64;
+            let rbp = (*uc).uc_mcontext.gregs[REG_RBP] as usize;
+            (rip, rbp as *const usize)
+        }
+    }
 }
 
-/// Record a deallocation event
-#[cfg(feature = "heap")]
-#[inline(never)]
-pub fn record_dealloc(ptr: *mut u8, _size: usize) {
-    // Don't record deallocations from within signal handler
-    if IN_SIGNAL_HANDLER.load(Ordering::Relaxed) {
-        return;
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    /// Highest user-space address (48-bit virtual addresses)
+    pub const MAX_USER_ADDR: usize = 0xffff_ffff_ffff;
+
+    /// Frame pointer (x29) of the calling function
+    #[inline(always)]
+    pub fn frame_pointer() -> *const usize {
+        let fp: *const usize;
+        unsafe {
+            core::arch::asm!(
+                "mov {}, x29",
+                out(reg) fp,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        fp
     }
 
-    // Can't dealloc if never initialized
-    if !INITIALIZED.load(Ordering::Relaxed) || !shm_ready() {
-        return;
+    /// Return address with pointer authentication bits stripped
+    ///
+    /// With PAC enabled the saved link register carries a signature in the
+    /// bits above the virtual address range; it would never symbolize.
+    #[inline(always)]
+    pub fn return_address(raw: usize) -> usize {
+        raw & MAX_USER_ADDR
     }
 
-    // Look up the allocation to get size and callsite
-    if let Some((size, callsite_hash)) = untrack_alloc(ptr as u64) {
-        // Find the callsite and update free stats
-        let callsite = find_callsite(callsite_hash);
-        if !callsite.is_null() {
-            unsafe {
-                (*callsite).free_count.fetch_add(1, Ordering::Relaxed);
-                (*callsite).free_bytes.fetch_add(size, Ordering::Relaxed);
-            }
+    /// Stack pointer of the calling function
+    #[inline(always)]
+    pub fn stack_pointer() -> *const usize {
+        let sp: *const usize;
+        unsafe {
+            core::arch::asm!(
+                "mov {}, sp",
+                out(reg) sp,
+                options(nomem, nostack, preserves_flags)
+            );
         }
+        sp
     }
-}
 
-// Stubs when heap feature is disabled
+    /// Whether the instruction before `addr` is a call (`bl` or `blr`)
+    ///
+    /// `addr - 4` must be readable.
+    #[inline(always)]
+    pub unsafe fn follows_call(addr: usize) -> bool {
+        let insn = unsafe { *(addr as *const u32).sub(1) };
+        insn & 0xfc00_0000 == 0x9400_0000 || insn & 0xffff_fc1f == 0xd63f_0000
+    }
+
+    /// Instruction and frame pointer (pc, x29) of an interrupted thread
+    #[cfg(feature = "cpu")]
+    pub unsafe fn interrupted_registers(uc: *const libc::ucontext_t) -> (u64, *const usize) {
+        unsafe {
+            let pc = (*uc).uc_mcontext.pc;
+            let fp = (*uc).uc_mcontext.regs[29] as usize;
+            (pc, fp as *const usize)
+        }
+    }
+}
+
+/// Other architectures: no stack walking, so every event is counted on the
+/// "no stack" callsite instead of recording garbage frames
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod arch {
+    pub const MAX_USER_ADDR: usize = 0x7fff_ffff_ffff;
+
+    #[inline(always)]
+    pub fn frame_pointer() -> *const usize {
+        core::ptr::null()
+    }
+
+    #[inline(always)]
+    pub fn return_address(raw: usize) -> usize {
+        raw
+    }
+
+    #[inline(always)]
+    pub fn stack_pointer() -> *const usize {
+        core::ptr::null()
+    }
+
+    #[inline(always)]
+    pub unsafe fn follows_call(_addr: usize) -> bool {
+        false
+    }
+
+    #[cfg(feature = "cpu")]
+    pub unsafe fn interrupted_registers(_uc: *const libc::ucontext_t) -> (u64, *const usize) {
+        (0, core::ptr::null())
+    }
+}
+
+// =============================================================================
+// Heap profiling (conditional on "heap" feature)
+// =============================================================================
+
+/// Record an allocation event
+///
+/// When `with_stack` is false the stack walk is skipped and the allocation is
+/// counted against a single shared callsite. `weight` is the number of
+/// allocations it stands for (1, or more when sampling; see `sample_alloc`).
+#[cfg(feature = "heap")]
+#[inline(never)]
+pub fn record_alloc(ptr: *mut u8, size: usize, with_stack: bool, weight: u64) {
+    // Don't record allocations from within signal handler, or while paused
+    if IN_SIGNAL_HANDLER.load(Ordering::Relaxed) || !HEAP_RECORDING.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let timer = overhead_start();
+
+    // Ensure initialized
+    if !INITIALIZED.load(Ordering::Relaxed) {
+        init();
+    }
+
+    // Left out by `--threads`/`--exclude-thread`; untracked, so its frees are too
+    if shm_ready() && !thread_filtered_out() {
+        let _section = ProfilerSection::enter();
+        count_alloc(ptr, size, with_stack, weight);
+    }
+
+    // The only exit once timing started, so every path is charged
+    overhead_end(timer, OVERHEAD_SAMPLE_PERIOD as u64);
+}
+
+/// Count an allocation against its callsite and marker, and track it
+#[cfg(feature = "heap")]
+#[inline(always)]
+fn count_alloc(ptr: *mut u8, size: usize, with_stack: bool, weight: u64) {
+    // Capture stack and compute hash
+    let mut stack = [0u64; MAX_STACK_DEPTH];
+    let depth = if with_stack {
+        capture_stack(&mut stack)
+    } else {
+        0
+    };
+    // No frames (stack capture off, or no unwinder for this architecture)
+    let hash = if depth > 0 {
+        stack_key_heap(&stack, depth)
+    } else {
+        UNSTACKED_HASH
+    };
+
+    // Find or create callsite, update stats
+    let callsite = callsite_for_event(hash, &stack, depth);
+    if callsite.is_null() {
+        return;
+    }
+    // The full-table policy may have redirected the event to another callsite;
+    // frees must be credited to the one that was charged
+    let hash = unsafe { (*callsite).hash.load(Ordering::Relaxed) };
+    unsafe {
+        (*callsite).alloc_count.fetch_add(weight, Ordering::Relaxed);
+        (*callsite)
+            .alloc_bytes
+            .fetch_add(size as u64 * weight, Ordering::Relaxed);
+        (*callsite)
+            .min_size_inv
+            .fetch_max(!(size as u64), Ordering::Relaxed);
+        (*callsite)
+            .max_size
+            .fetch_max(size as u64, Ordering::Relaxed);
+        (*callsite).size_hist[size_bucket(size)].fetch_add(weight, Ordering::Relaxed);
+    }
+
+    // Attribute to the thread's current marker, if any
+    let marker = current_marker();
+    if marker != 0 {
+        let stats = unsafe { get_markers().add(marker as usize - 1) };
+        unsafe {
+            (*stats).alloc_count.fetch_add(weight, Ordering::Relaxed);
+            (*stats)
+                .alloc_bytes
+                .fetch_add(size as u64 * weight, Ordering::Relaxed);
+        }
+    }
+
+    // Track allocation for later dealloc attribution
+    track_alloc(ptr as u64, size as u64, hash, marker, weight);
+}
+
+/// Pause (`false`) or resume (`true`) recording allocations
+///
+/// While paused, new allocations are neither counted nor tracked. Memory
+/// allocated before the pause is still credited when it is freed, so live
+/// bytes stay right. Only an atomic store: safe to call from anywhere,
+/// including signal handlers.
+#[cfg(feature = "heap")]
+pub fn set_heap_recording(enabled: bool) {
+    HEAP_RECORDING.store(enabled, Ordering::Relaxed);
+}
+
+/// `set_heap_recording`, returning the previous setting
+#[cfg(feature = "heap")]
+pub(crate) fn replace_heap_recording(enabled: bool) -> bool {
+    HEAP_RECORDING.swap(enabled, Ordering::Relaxed)
+}
+
+/// Record a deallocation event
+#[cfg(feature = "heap")]
+#[inline(never)]
+pub fn record_dealloc(ptr: *mut u8, _size: usize) {
+    // Don't record deallocations from within signal handler
+    if IN_SIGNAL_HANDLER.load(Ordering::Relaxed) {
+        return;
+    }
+
+    // Can't dealloc if never initialized
+    if !INITIALIZED.load(Ordering::Relaxed) || !shm_ready() {
+        return;
+    }
+
+    let _section = ProfilerSection::enter();
+    let timer = overhead_start();
+
+    // Look up the allocation to get size and callsite
+    if let Some((size, callsite_hash, marker, weight)) = untrack_alloc(ptr as u64) {
+        let bytes = size * weight;
+        // Frees are credited to the marker active when the memory was allocated
+        if marker != 0 {
+            let stats = unsafe { get_markers().add(marker as usize - 1) };
+            unsafe {
+                (*stats).free_count.fetch_add(weight, Ordering::Relaxed);
+                (*stats).free_bytes.fetch_add(bytes, Ordering::Relaxed);
+            }
+        }
+
+        // Find the callsite and update free stats
+        let callsite = find_callsite(callsite_hash);
+        if !callsite.is_null() {
+            unsafe {
+                (*callsite).free_count.fetch_add(weight, Ordering::Relaxed);
+                (*callsite).free_bytes.fetch_add(bytes, Ordering::Relaxed);
+            }
+        }
+
+        // Pair the alloc site with this free site, when rsprof asked for it
+        let free_sites = unsafe { (*get_header()).free_sites.load(Ordering::Relaxed) };
+        if free_sites != 0 && callsite_hash != UNSTACKED_HASH {
+            record_transfer(callsite_hash, bytes, weight);
+        }
+    }
+
+    overhead_end(timer, OVERHEAD_SAMPLE_PERIOD as u64);
+}
+
+/// Count `count` frees totalling `bytes`, allocated at `alloc_hash`, against
+/// the calling stack's free site
+///
+/// Free sites are stored in the callsite table like alloc sites (with no
+/// counts of their own), so rsprof can resolve both ends of the pair.
+#[cfg(feature = "heap")]
+#[inline(never)]
+fn record_transfer(alloc_hash: u64, bytes: u64, count: u64) {
+    let mut stack = [0u64; MAX_STACK_DEPTH];
+    let depth = capture_stack(&mut stack);
+    if depth == 0 {
+        return;
+    }
+    let free_hash = stack_key_heap(&stack, depth);
+    if find_or_create_callsite(free_hash, &stack, depth).is_null() {
+        return; // No room for the free site's stack
+    }
+
+    count_transfer_in(get_transfers(), alloc_hash, free_hash, bytes, count);
+}
+
+/// Add `count` frees of `bytes` to the (alloc site, free site) pair's slot in
+/// `transfers`, claiming one for a new pair
+///
+/// Returns false if the table is full and the pair was dropped.
+#[cfg(feature = "heap")]
+fn count_transfer_in(
+    transfers: *mut TransferStats,
+    alloc_hash: u64,
+    free_hash: u64,
+    bytes: u64,
+    count: u64,
+) -> bool {
+    let mut key = (alloc_hash ^ free_hash.rotate_left(29)).wrapping_mul(0x100000001b3);
+    if key == 0 {
+        key = 1;
+    }
+    let mut idx = (key as usize) % TRANSFER_CAPACITY;
+    for _ in 0..TRANSFER_CAPACITY {
+        let entry = unsafe { transfers.add(idx) };
+        let stored = unsafe { (*entry).key.load(Ordering::Acquire) };
+        let claimed = stored == 0
+            && unsafe {
+                (*entry)
+                    .key
+                    .compare_exchange(0, key, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+            };
+        if claimed {
+            unsafe {
+                (*entry).alloc_hash.store(alloc_hash, Ordering::Relaxed);
+                (*entry).free_hash.store(free_hash, Ordering::Release);
+            }
+        }
+        if claimed || unsafe { (*entry).key.load(Ordering::Acquire) } == key {
+            unsafe {
+                (*entry).count.fetch_add(count, Ordering::Relaxed);
+                (*entry).bytes.fetch_add(bytes, Ordering::Relaxed);
+            }
+            return true;
+        }
+        idx = (idx + 1) % TRANSFER_CAPACITY;
+    }
+    // Table full - drop the pair
+    false
+}
+
+// =============================================================================
+// Allocation sampling (`profiler!(heap_sample_bytes = N)`)
+// =============================================================================
+
+/// pthread key + 1 for the per-thread bytes left until the next sampled
+/// allocation (0 = not created). A null value means "draw a new gap".
+#[cfg(feature = "heap")]
+static SAMPLE_KEY: AtomicU32 = AtomicU32::new(0);
+
+/// xorshift state for sampling gaps and weight rounding (seeded in `init`;
+/// only advanced once per sampled allocation)
+#[cfg(feature = "heap")]
+static SAMPLE_RNG: AtomicU64 = AtomicU64::new(0x9E37_79B9_7F4A_7C15);
+
+/// Decide whether to record an allocation of `size` bytes when sampling one
+/// allocation per `period` allocated bytes on average
+///
+/// Each thread counts down the bytes to its next sample, a random gap with
+/// mean `period` (exponentially distributed, as in tcmalloc), so an
+/// allocation is sampled with probability `p = 1 - exp(-size / period)`.
+/// Returns 0 to skip it, or its weight: `1 / p` rounded up or down at random
+/// so that expected counts and bytes match the real ones.
+///
+/// The estimate is noisiest for small allocations: under a 512KB period each
+/// sampled 64-byte allocation stands for ~8192 of them, so a site needs many
+/// samples before its numbers settle. Allocations of `period` bytes or more
+/// are almost always sampled, at weight 1 or 2.
+#[cfg(feature = "heap")]
+pub fn sample_alloc(size: usize, period: usize) -> u64 {
+    if !INITIALIZED.load(Ordering::Relaxed) {
+        init();
+    }
+    let key = match SAMPLE_KEY.load(Ordering::Acquire) {
+        0 => return 1, // No per-thread counter: record everything
+        key => (key - 1) as libc::pthread_key_t,
+    };
+
+    let left = match unsafe { libc::pthread_getspecific(key) } as usize {
+        0 => sample_gap(period),
+        left => left,
+    };
+    if size < left {
+        unsafe { libc::pthread_setspecific(key, (left - size) as *const libc::c_void) };
+        return 0;
+    }
+    unsafe { libc::pthread_setspecific(key, sample_gap(period) as *const libc::c_void) };
+
+    let probability = 1.0 - exp_neg(size as f64 / period as f64);
+    let weight = 1.0 / probability;
+    let whole = weight as u64;
+    whole + (random_unit() < weight - whole as f64) as u64
+}
+
+/// Bytes until the next sample: exponential with mean `period`, at least 1
+#[cfg(feature = "heap")]
+fn sample_gap(period: usize) -> usize {
+    let uniform = 1.0 - random_unit(); // (0, 1]
+    ((-ln(uniform) * period as f64) as usize).max(1)
+}
+
+/// Uniform random number in [0, 1)
+#[cfg(feature = "heap")]
+fn random_unit() -> f64 {
+    let mut state = SAMPLE_RNG.load(Ordering::Relaxed);
+    loop {
+        let mut next = state;
+        next ^= next << 13;
+        next ^= next >> 7;
+        next ^= next << 17;
+        match SAMPLE_RNG.compare_exchange_weak(state, next, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return (next >> 11) as f64 / (1u64 << 53) as f64,
+            Err(current) => state = current,
+        }
+    }
+}
+
+/// Natural logarithm of a positive, normal `x` (`f64::ln` needs std)
+///
+/// Splits off the binary exponent and takes the atanh series of the
+/// mantissa; good to ~1e-6, plenty for picking sample gaps.
+#[cfg(feature = "heap")]
+fn ln(x: f64) -> f64 {
+    let bits = x.to_bits();
+    let exponent = ((bits >> 52) & 0x7ff) as i64 - 1023;
+    let mantissa = f64::from_bits((bits & ((1 << 52) - 1)) | (1023 << 52)); // [1, 2)
+    let z = (mantissa - 1.0) / (mantissa + 1.0);
+    let z2 = z * z;
+    let series = 1.0 + z2 * (1.0 / 3.0 + z2 * (1.0 / 5.0 + z2 * (1.0 / 7.0 + z2 / 9.0)));
+    exponent as f64 * core::f64::consts::LN_2 + 2.0 * z * series
+}
+
+/// `exp(-x)` for `x >= 0` (`f64::exp` needs std)
+///
+/// Halves `x` into the range where a short Taylor series is accurate, then
+/// squares the result back up.
+#[cfg(feature = "heap")]
+fn exp_neg(x: f64) -> f64 {
+    if x > 700.0 {
+        return 0.0;
+    }
+    let mut y = x;
+    let mut halvings = 0;
+    while y > 0.5 {
+        y /= 2.0;
+        halvings += 1;
+    }
+    let mut result = 1.0
+        - y * (1.0
+            - y / 2.0 * (1.0 - y / 3.0 * (1.0 - y / 4.0 * (1.0 - y / 5.0 * (1.0 - y / 6.0)))));
+    for _ in 0..halvings {
+        result *= result;
+    }
+    result
+}
+
+// =============================================================================
+// Markers (per-thread attribution of allocations to a logical operation)
+// =============================================================================
+
+#[cfg(feature = "heap")]
+std::thread_local! {
+    /// Marker active on the thread (slot + 1, 0 = none); like `IN_PROFILER`,
+    /// a plain TLS slot, so reading it from the allocator never allocates
+    static MARKER: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Marker active on the calling thread (slot + 1, 0 = none)
+#[cfg(feature = "heap")]
+#[inline]
+fn current_marker() -> u64 {
+    MARKER.with(Cell::get)
+}
+
+/// Find or create the marker slot for a name
+#[cfg(feature = "heap")]
+fn find_or_create_marker(name: &str) -> Option<usize> {
+    let bytes = name.as_bytes();
+    let mut hash = 0xcbf29ce484222325u64;
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    if hash == 0 {
+        hash = 1;
+    }
+    find_or_create_marker_in(get_markers(), hash, bytes)
+}
+
+/// `find_or_create_marker` in the marker table at `markers`, for a name
+/// hashing to `hash`
+#[cfg(feature = "heap")]
+fn find_or_create_marker_in(markers: *mut MarkerStats, hash: u64, name: &[u8]) -> Option<usize> {
+    // Names are stored truncated, so that's what two equal names share
+    let name = &name[..name.len().min(MARKER_NAME_LEN - 1)];
+    let mut idx = (hash as usize) % MARKER_CAPACITY;
+    for _ in 0..MARKER_CAPACITY {
+        let entry = unsafe { markers.add(idx) };
+        let claimed = unsafe {
+            (*entry)
+                .hash
+                .compare_exchange(0, hash, Ordering::AcqRel, Ordering::Acquire)
+        };
+        match claimed {
+            Ok(_) => {
+                // Claiming publishes the hash only: the reader waits for
+                // `ready` to see the name complete
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        name.as_ptr(),
+                        (*entry).name.as_mut_ptr(),
+                        name.len(),
+                    );
+                    (*entry).ready.store(1, Ordering::Release);
+                }
+                return Some(idx);
+            }
+            // Another name may hash the same: the slot is only this marker's
+            // if the stored name matches too
+            Err(stored) if stored == hash && unsafe { marker_name_is(entry, name) } => {
+                return Some(idx);
+            }
+            Err(_) => idx = (idx + 1) % MARKER_CAPACITY,
+        }
+    }
+
+    // Table full - allocations stay unattributed
+    None
+}
+
+/// Whether the marker in a claimed slot is named `name`
+///
+/// Waits for the name if the thread that claimed the slot is still writing it.
+#[cfg(feature = "heap")]
+unsafe fn marker_name_is(entry: *const MarkerStats, name: &[u8]) -> bool {
+    let entry = unsafe { &*entry };
+    while entry.ready.load(Ordering::Acquire) == 0 {
+        core::hint::spin_loop();
+    }
+    entry.name[..name.len()] == *name && entry.name[name.len()] == 0
+}
+
+/// Set the marker for allocations made by the current thread.
+///
+/// Every allocation until the next `mark` (or `clear_mark`) on this thread is
+/// attributed to `name`, as is the later freeing of that memory.
+#[cfg(feature = "heap")]
+pub fn mark(name: &str) {
+    if !INITIALIZED.load(Ordering::Relaxed) {
+        init();
+    }
+    if !shm_ready() {
+        return;
+    }
+    let _section = ProfilerSection::enter();
+
+    let slot = find_or_create_marker(name).map_or(0, |idx| idx + 1);
+    MARKER.with(|marker| marker.set(slot as u64));
+}
+
+/// Stop attributing the current thread's allocations to a marker
+#[cfg(feature = "heap")]
+pub fn clear_mark() {
+    MARKER.with(|marker| marker.set(0));
+}
+
+/// Marker active on the calling thread, to hand back to `restore_mark`
+#[cfg(feature = "heap")]
+pub(crate) fn saved_mark() -> u64 {
+    current_marker()
+}
+
+/// Reinstate a marker returned by `saved_mark` (the end of a `tag` scope)
+#[cfg(feature = "heap")]
+pub(crate) fn restore_mark(marker: u64) {
+    MARKER.with(|current| current.set(marker));
+}
+
+// =============================================================================
+// Checkpoint labels (annotations on the recording timeline)
+// =============================================================================
+
+/// Label the current point of the recording (e.g. "v2 deployed").
+///
+/// rsprof attaches the text to the next checkpoint and shows it on the chart
+/// timeline. Only the most recent label is kept between two reads, and text
+/// longer than 63 bytes is truncated.
+pub fn label(text: &str) {
+    if !INITIALIZED.load(Ordering::Relaxed) {
+        init();
+    }
+    if !shm_ready() {
+        return;
+    }
+
+    let header = get_header();
+    let seq = unsafe { &(*header).label_seq };
+
+    // Seqlock: make the sequence odd while writing so the reader can detect torn reads
+    let mut current = seq.load(Ordering::Acquire);
+    loop {
+        if current & 1 == 1 {
+            core::hint::spin_loop();
+            current = seq.load(Ordering::Acquire);
+            continue;
+        }
+        match seq.compare_exchange_weak(current, current + 1, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => break,
+            Err(actual) => current = actual,
+        }
+    }
+
+    let bytes = text.as_bytes();
+    let len = bytes.len().min(LABEL_LEN - 1);
+    unsafe {
+        let dst = core::ptr::addr_of_mut!((*header).label) as *mut u8;
+        core::ptr::write_bytes(dst, 0, LABEL_LEN);
+        core::ptr::copy_nonoverlapping(bytes.as_ptr(), dst, len);
+    }
+
+    seq.store(current + 2, Ordering::Release);
+}
+
+// Stubs when heap feature is disabled
 #[cfg(not(feature = "heap"))]
 #[inline]
-pub fn record_alloc(_ptr: *mut u8, _size: usize) {}
+pub fn record_alloc(_ptr: *mut u8, _size: usize, _with_stack: bool, _weight: u64) {}
 
 #[cfg(not(feature = "heap"))]
 #[inline]
@@ -505,80 +1715,157 @@ mod cpu_profiling {
     /// Default sampling frequency in Hz
     const DEFAULT_FREQ_HZ: u32 = 99;
 
+    /// Count a sample against the calling thread's entry for `callsite_hash`
+    #[inline]
+    fn record_thread_sample(callsite_hash: u64) {
+        let tid = current_tid();
+        let mut key = (callsite_hash ^ (tid as u64).rotate_left(32)).wrapping_mul(0x100000001b3);
+        if key == 0 {
+            key = 1;
+        }
+        let thread_samples = get_thread_samples();
+        let mut idx = (key as usize) % THREAD_SAMPLE_CAPACITY;
+        for _ in 0..THREAD_SAMPLE_CAPACITY {
+            let entry = unsafe { thread_samples.add(idx) };
+            let stored = unsafe { (*entry).key.load(Ordering::Acquire) };
+            let claimed = stored == 0
+                && unsafe {
+                    (*entry)
+                        .key
+                        .compare_exchange(0, key, Ordering::AcqRel, Ordering::Relaxed)
+                        .is_ok()
+                };
+            if claimed {
+                unsafe {
+                    (*entry)
+                        .callsite_hash
+                        .store(callsite_hash, Ordering::Relaxed);
+                    (*entry).tid.store(tid, Ordering::Relaxed);
+                    (*entry)
+                        .pid
+                        .store(PROCESS_ID.load(Ordering::Relaxed), Ordering::Release);
+                }
+            }
+            if claimed || unsafe { (*entry).key.load(Ordering::Acquire) } == key {
+                unsafe { (*entry).samples.fetch_add(1, Ordering::Relaxed) };
+                return;
+            }
+            idx = (idx + 1) % THREAD_SAMPLE_CAPACITY;
+        }
+        // Table full - the sample still counts at its callsite, without a thread
+        count_dropped_event();
+    }
+
     /// Signal handler for CPU sampling
-    extern "C" fn cpu_sample_handler(
+    pub(super) extern "C" fn cpu_sample_handler(
         _sig: libc::c_int,
         _info: *mut libc::siginfo_t,
         ucontext: *mut libc::c_void,
     ) {
+        // Paused with set_cpu_recording(false): the timer keeps running
+        if !CPU_RECORDING.load(Ordering::Relaxed) {
+            return;
+        }
+
         // Prevent reentrant calls
         if IN_SIGNAL_HANDLER.swap(true, Ordering::SeqCst) {
             return;
         }
 
-        if !shm_ready() {
+        // Drop samples that land in the profiler's own bookkeeping, in a
+        // forked child rsprof doesn't follow, or in a thread it filters out
+        if !shm_ready() || in_profiler() || !samples_this_process() || thread_filtered_out() {
             IN_SIGNAL_HANDLER.store(false, Ordering::SeqCst);
             return;
         }
+        let timer = monotonic_ns();
+        apply_requested_freq();
 
         // Extract the interrupted registers from the ucontext
-        let (rip, start_fp) = if !ucontext.is_null() {
-            unsafe {
-                let uc = ucontext as *const libc::ucontext_t;
-                const REG_RIP: usize = 16;
-                const REG_RBP: usize = 10;
-                let rip = (*uc).uc_mcontext.gregs[REG_RIP] as u64;
-                let rbp = (*uc).uc_mcontext.gregs[REG_RBP] as usize;
-                (rip, rbp as *const usize)
-            }
+        let (pc, start_fp) = if !ucontext.is_null() {
+            unsafe { arch::interrupted_registers(ucontext as *const libc::ucontext_t) }
         } else {
             (0, core::ptr::null())
         };
 
-        // Build stack with RIP as first frame
+        // Build stack with the interrupted instruction as first frame
         let mut stack = [0u64; MAX_STACK_DEPTH];
         let mut depth = 0u32;
 
-        if rip != 0 {
-            stack[0] = rip;
+        if pc != 0 {
+            stack[0] = pc;
             depth = 1;
         }
 
         // Walk the rest of the stack
         if !start_fp.is_null() {
-            let mut fp = start_fp;
-
-            while !fp.is_null() && (depth as usize) < MAX_STACK_DEPTH {
-                if (fp as usize) & 0x7 != 0 {
-                    break;
-                }
-                let fp_val = fp as usize;
-                if !(0x1000..=0x7fff_ffff_ffff).contains(&fp_val) {
-                    break;
-                }
+            depth = walk_frames(&mut stack, depth, start_fp);
+        }
 
-                let ret_addr = unsafe { *fp.add(1) };
-                if ret_addr == 0 {
-                    break;
-                }
+        // Compute callsite hash and update stats
+        let hash = if depth > 0 {
+            stack_key_cpu(&stack, depth)
+        } else {
+            UNSTACKED_HASH
+        };
+        let callsite = callsite_for_event(hash, &stack, depth);
+        if !callsite.is_null() {
+            unsafe {
+                (*callsite).cpu_samples.fetch_add(1, Ordering::Relaxed);
+                record_thread_sample((*callsite).hash.load(Ordering::Relaxed));
+            }
+        }
 
-                stack[depth as usize] = ret_addr as u64;
-                depth += 1;
+        let elapsed = overhead_end(timer, 1);
+        unsafe {
+            (*get_header())
+                .cpu_overhead_ns
+                .fetch_add(elapsed, Ordering::Relaxed)
+        };
+        IN_SIGNAL_HANDLER.store(false, Ordering::SeqCst);
+    }
 
-                let next_fp = unsafe { *fp as *const usize };
-                if next_fp <= fp {
-                    break;
-                }
-                fp = next_fp;
+    /// Switch to the sampling frequency rsprof asked for, if it changed
+    ///
+    /// Called from the SIGPROF handler: rsprof can't re-arm the target's
+    /// timer itself, so the next sample does it.
+    fn apply_requested_freq() {
+        let header = get_header();
+        unsafe {
+            let requested = (*header).cpu_freq_request_hz.load(Ordering::Relaxed);
+            if requested != 0 && requested != (*header).cpu_freq_hz.load(Ordering::Relaxed) {
+                set_timer(requested);
+                (*header).cpu_freq_hz.store(requested, Ordering::Relaxed);
             }
         }
+    }
 
-        // Compute callsite hash and update stats
-        let hash = stack_key_cpu(&stack, depth);
-        let callsite = find_or_create_callsite(hash, &stack, depth);
-        unsafe { (*callsite).cpu_samples.fetch_add(1, Ordering::Relaxed) };
+    /// Arm the ITIMER_PROF interval timer at `freq_hz` (0 disarms it)
+    fn set_timer(freq_hz: u32) {
+        let interval_usec = match freq_hz {
+            0 => 0,
+            freq => (1_000_000 / freq as i64).max(1),
+        };
+        let interval = libc::timeval {
+            tv_sec: 0,
+            tv_usec: interval_usec,
+        };
+        let timer = libc::itimerval {
+            it_interval: interval,
+            it_value: interval,
+        };
+        unsafe { libc::setitimer(libc::ITIMER_PROF, &timer, core::ptr::null_mut()) };
+    }
 
-        IN_SIGNAL_HANDLER.store(false, Ordering::SeqCst);
+    /// Re-arm the sampling timer in a forked child (timers aren't inherited)
+    pub(super) fn restart_after_fork() {
+        if !shm_ready() {
+            return;
+        }
+        let freq = unsafe { (*get_header()).cpu_freq_hz.load(Ordering::Relaxed) };
+        if freq != 0 {
+            set_timer(freq);
+        }
     }
 
     /// Start CPU profiling with timer-based sampling
@@ -605,49 +1892,52 @@ mod cpu_profiling {
             } else {
                 freq_hz
             };
-            let interval_usec = 1_000_000 / freq as i64;
-
-            let timer = libc::itimerval {
-                it_interval: libc::timeval {
-                    tv_sec: 0,
-                    tv_usec: interval_usec,
-                },
-                it_value: libc::timeval {
-                    tv_sec: 0,
-                    tv_usec: interval_usec,
-                },
-            };
+            set_timer(freq);
 
-            libc::setitimer(libc::ITIMER_PROF, &timer, core::ptr::null_mut());
+            // Let rsprof convert sample counts to CPU time
+            if shm_ready() {
+                (*get_header()).cpu_freq_hz.store(freq, Ordering::Relaxed);
+            }
         }
     }
 
+    /// Pause (`false`) or resume (`true`) recording CPU samples
+    ///
+    /// The sampling timer keeps running; samples taken while paused are
+    /// discarded. Only an atomic store: safe to call from anywhere, including
+    /// signal handlers.
+    pub fn set_cpu_recording(enabled: bool) {
+        CPU_RECORDING.store(enabled, Ordering::Relaxed);
+    }
+
+    /// `set_cpu_recording`, returning the previous setting
+    pub(crate) fn replace_cpu_recording(enabled: bool) -> bool {
+        CPU_RECORDING.swap(enabled, Ordering::Relaxed)
+    }
+
     /// Stop CPU profiling
     pub fn stop_cpu_profiling() {
-        unsafe {
-            // Disable timer
-            let timer = libc::itimerval {
-                it_interval: libc::timeval {
-                    tv_sec: 0,
-                    tv_usec: 0,
-                },
-                it_value: libc::timeval {
-                    tv_sec: 0,
-                    tv_usec: 0,
-                },
-            };
-            libc::setitimer(libc::ITIMER_PROF, &timer, core::ptr::null_mut());
+        // Disable timer (and keep children forked from now on from restarting it)
+        set_timer(0);
+        if shm_ready() {
+            unsafe { (*get_header()).cpu_freq_hz.store(0, Ordering::Relaxed) };
+        }
 
-            // Reset signal handler
+        unsafe {
+            // Ignore rather than restore the default, which terminates the
+            // process: a SIGPROF raised just before the timer stopped can
+            // still be pending on another thread
             let mut sa: libc::sigaction = core::mem::zeroed();
-            sa.sa_sigaction = libc::SIG_DFL;
+            sa.sa_sigaction = libc::SIG_IGN;
             libc::sigaction(libc::SIGPROF, &sa, core::ptr::null_mut());
         }
     }
 }
 
 #[cfg(feature = "cpu")]
-pub use cpu_profiling::{start_cpu_profiling, stop_cpu_profiling};
+pub(crate) use cpu_profiling::replace_cpu_recording;
+#[cfg(feature = "cpu")]
+pub use cpu_profiling::{set_cpu_recording, start_cpu_profiling, stop_cpu_profiling};
 
 // Stubs when cpu feature is disabled
 #[cfg(not(feature = "cpu"))]
@@ -655,3 +1945,709 @@ pub fn start_cpu_profiling(_freq_hz: u32) {}
 
 #[cfg(not(feature = "cpu"))]
 pub fn stop_cpu_profiling() {}
+
+#[cfg(all(test, feature = "heap", feature = "cpu"))]
+mod tests {
+    use super::*;
+    use core::alloc::{GlobalAlloc, Layout};
+    use std::sync::{PoisonError, RwLock, RwLockReadGuard};
+
+    /// Held for writing by tests that pause recording, and for reading by
+    /// tests that expect their events to be recorded
+    static RECORDING: RwLock<()> = RwLock::new(());
+
+    fn recording() -> RwLockReadGuard<'static, ()> {
+        RECORDING.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Allocation slots of the test profiler, as if set by `profiler!(alloc_slots)`
+    const TEST_ALLOC_SLOTS: usize = 4096;
+
+    /// Point the profiler at a zeroed buffer rather than the shared memory
+    /// segment, which another profiled process may be using
+    fn private_shm() {
+        static SETUP: std::sync::Once = std::sync::Once::new();
+        SETUP.call_once(|| {
+            // Not a power of two: ignored
+            set_alloc_slots(TEST_ALLOC_SLOTS - 1);
+            set_alloc_slots(TEST_ALLOC_SLOTS);
+            let buffer = alloc::vec![0u64; shm_size().div_ceil(8)].leak();
+            unsafe {
+                SHM_BASE = buffer.as_mut_ptr() as *mut u8;
+                (*get_header()).pid = libc::getpid() as u32;
+            }
+            PROCESS_ID.store(unsafe { libc::getpid() } as u32, Ordering::Relaxed);
+            let mut key: libc::pthread_key_t = 0;
+            if unsafe { libc::pthread_key_create(&mut key, None) } == 0 {
+                SAMPLE_KEY.store(key as u32 + 1, Ordering::Release);
+            }
+            INITIALIZED.store(true, Ordering::SeqCst);
+            init_text_range();
+        });
+    }
+
+    /// A callsite table with every slot taken by another site, plus the
+    /// "no stack" callsite if `unstacked`
+    fn full_callsite_table(unstacked: bool) -> *mut CallsiteStats {
+        let words = CALLSITE_CAPACITY * core::mem::size_of::<CallsiteStats>() / 8;
+        let table = alloc::vec![0u64; words].leak().as_mut_ptr() as *mut CallsiteStats;
+        for i in 0..CALLSITE_CAPACITY {
+            unsafe { (*table.add(i)).hash.store(i as u64 + 1, Ordering::Relaxed) };
+        }
+        if unstacked {
+            let slot = (UNSTACKED_HASH as usize) % CALLSITE_CAPACITY;
+            unsafe {
+                (*table.add(slot))
+                    .hash
+                    .store(UNSTACKED_HASH, Ordering::Relaxed)
+            };
+        }
+        table
+    }
+
+    /// Callsite a live allocation was counted against
+    fn callsite_of(ptr: *mut u8) -> &'static CallsiteStats {
+        let table = get_alloc_table();
+        let entry = (0..ALLOC_SLOTS.load(Ordering::Relaxed))
+            .map(|i| unsafe { &*table.add(i) })
+            .find(|entry| entry.ptr.load(Ordering::Acquire) == ptr as u64)
+            .expect("allocation is tracked");
+        let hash = entry.callsite_hash.load(Ordering::Relaxed);
+        unsafe { &*find_callsite(hash) }
+    }
+
+    #[test]
+    fn only_allocations_in_the_stack_range_get_stacks() {
+        private_shm();
+        let _recording = recording();
+        // CPU sampling off, stacks for 4KB..=64KB
+        let allocator = crate::ProfilingAllocator::<0, 4096, 65536>::new();
+        let unstacked_bytes = || {
+            let callsite = find_callsite(UNSTACKED_HASH);
+            match unsafe { callsite.as_ref() } {
+                Some(callsite) => callsite.alloc_bytes.load(Ordering::Relaxed),
+                None => 0,
+            }
+        };
+
+        for (size, stacked) in [(100, false), (4096, true), (65536, true), (1 << 20, false)] {
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            let before = unstacked_bytes();
+            let ptr = unsafe { allocator.alloc(layout) };
+            let callsite = callsite_of(ptr);
+
+            let depth = callsite.stack_depth.load(Ordering::Relaxed);
+            if stacked {
+                assert!(depth > 0, "{} bytes: no stack", size);
+                assert!(callsite.alloc_bytes.load(Ordering::Relaxed) >= size as u64);
+                assert_eq!(unstacked_bytes(), before);
+            } else {
+                // Counted without a stack, bytes and all
+                assert_eq!(callsite.hash.load(Ordering::Relaxed), UNSTACKED_HASH);
+                assert_eq!(depth, 0);
+                assert_eq!(unstacked_bytes(), before + size as u64);
+            }
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+    }
+
+    #[test]
+    fn samples_are_dropped_inside_profiler_sections() {
+        assert!(!in_profiler());
+        {
+            let _outer = ProfilerSection::enter();
+            assert!(in_profiler());
+            {
+                let _inner = ProfilerSection::enter();
+                assert!(in_profiler());
+            }
+            // Leaving a nested section keeps the outer one active
+            assert!(in_profiler());
+
+            // Other threads keep sampling meanwhile
+            let other = std::thread::spawn(in_profiler).join().unwrap();
+            assert!(!other);
+        }
+        assert!(!in_profiler());
+    }
+
+    #[test]
+    fn thread_ids_are_cached_per_thread() {
+        let tid = current_tid();
+        assert_eq!(tid, unsafe { libc::syscall(libc::SYS_gettid) } as u32);
+        assert_eq!(current_tid(), tid);
+        let other = std::thread::spawn(current_tid).join().unwrap();
+        assert_ne!(other, tid);
+        assert_ne!(other, 0);
+    }
+    #[test]
+    fn excluded_threads_record_no_allocations() {
+        private_shm();
+        let _recording = recording();
+        let allocator = || crate::ProfilingAllocator::<0, 4096, 65536>::new();
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+        let tracked = |ptr: *mut u8| {
+            let table = get_alloc_table();
+            (0..ALLOC_SLOTS.load(Ordering::Relaxed))
+                .any(|i| unsafe { &*table.add(i) }.ptr.load(Ordering::Acquire) == ptr as u64)
+        };
+
+        // Exclude a worker thread, as `--exclude-thread` does
+        let (tid_tx, tid_rx) = std::sync::mpsc::channel();
+        let (go_tx, go_rx) = std::sync::mpsc::channel::<()>();
+        let worker = std::thread::spawn(move || {
+            tid_tx.send(current_tid()).unwrap();
+            go_rx.recv().unwrap();
+            let ptr = unsafe { allocator().alloc(layout) };
+            let recorded = tracked(ptr);
+            unsafe { allocator().dealloc(ptr, layout) };
+            recorded
+        });
+        let header = unsafe { &*get_header() };
+        header.thread_filter[0].store(tid_rx.recv().unwrap(), Ordering::Relaxed);
+        header.thread_filter_len.store(1, Ordering::Release);
+        header
+            .thread_filter_mode
+            .store(THREAD_FILTER_EXCEPT, Ordering::Release);
+        go_tx.send(()).unwrap();
+        assert!(!worker.join().unwrap());
+
+        // Other threads are still recorded, stacks and all
+        let ptr = unsafe { allocator().alloc(layout) };
+        assert!(tracked(ptr));
+        assert!(callsite_of(ptr).stack_depth.load(Ordering::Relaxed) > 0);
+        unsafe { allocator().dealloc(ptr, layout) };
+
+        header.thread_filter_mode.store(0, Ordering::Release);
+    }
+
+    #[test]
+    fn overhead_is_charged_for_every_allocation_path() {
+        private_shm();
+        let _recording = recording();
+        let overhead = || unsafe { (*get_header()).overhead_ns.load(Ordering::Relaxed) };
+
+        // A timed section is charged its length, times the sampling scale
+        let before = overhead();
+        let start = monotonic_ns();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let charged = overhead_end(start, 3);
+        assert!(charged >= 3 * 5_000_000, "{charged}");
+        assert!(overhead() >= before + charged);
+
+        // Whole allocator hooks: enough calls that one is timed
+        let before = overhead();
+        let blocks: std::vec::Vec<u64> = (0..OVERHEAD_SAMPLE_PERIOD as u64 * 4).collect();
+        for block in &blocks {
+            record_alloc(block as *const u64 as *mut u8, 8, false, 1);
+        }
+        for block in &blocks {
+            record_dealloc(block as *const u64 as *mut u8, 8);
+        }
+        assert!(overhead() > before);
+    }
+
+    #[test]
+    fn frees_are_paired_with_their_allocation_site() {
+        let words = TRANSFER_CAPACITY * core::mem::size_of::<TransferStats>() / 8;
+        let transfers = alloc::vec![0u64; words].leak().as_mut_ptr() as *mut TransferStats;
+
+        // Allocated at 1: freed twice at 2 and once at 3; allocated at 4, freed at 2
+        for (alloc_hash, free_hash, bytes) in [(1, 2, 100), (1, 3, 50), (1, 2, 100), (4, 2, 8)] {
+            assert!(count_transfer_in(
+                transfers, alloc_hash, free_hash, bytes, 1
+            ));
+        }
+
+        let mut pairs: std::vec::Vec<(u64, u64, u64, u64)> = (0..TRANSFER_CAPACITY)
+            .map(|i| unsafe { &*transfers.add(i) })
+            .filter(|t| t.key.load(Ordering::Relaxed) != 0)
+            .map(|t| {
+                (
+                    t.alloc_hash.load(Ordering::Relaxed),
+                    t.free_hash.load(Ordering::Relaxed),
+                    t.count.load(Ordering::Relaxed),
+                    t.bytes.load(Ordering::Relaxed),
+                )
+            })
+            .collect();
+        pairs.sort();
+        assert_eq!(pairs, [(1, 2, 2, 200), (1, 3, 1, 50), (4, 2, 1, 8)]);
+    }
+
+    #[test]
+    fn alloc_slots_are_fixed_once_shared_memory_is_set_up() {
+        private_shm();
+        assert_eq!(ALLOC_SLOTS.load(Ordering::Relaxed), TEST_ALLOC_SLOTS);
+        set_alloc_slots(DEFAULT_ALLOC_SLOTS);
+        assert_eq!(ALLOC_SLOTS.load(Ordering::Relaxed), TEST_ALLOC_SLOTS);
+
+        // Both pointers hash to the last slot; the second wraps around to the
+        // start of the table rather than past its end
+        let last = 0x5a00_0000_0000 | ((TEST_ALLOC_SLOTS as u64 - 1) << 4);
+        let wrapped = last + ((TEST_ALLOC_SLOTS as u64) << 4);
+        track_alloc(last, 16, 7, 0, 1);
+        track_alloc(wrapped, 32, 8, 0, 1);
+        assert_eq!(untrack_alloc(wrapped), Some((32, 8, 0, 1)));
+        assert_eq!(untrack_alloc(last), Some((16, 7, 0, 1)));
+    }
+
+    #[test]
+    fn over_aligned_allocations_are_aligned() {
+        private_shm();
+        crate::tests::check_over_aligned(&crate::ProfilingAllocator::<0>::new());
+    }
+
+    #[test]
+    fn allocations_under_the_minimum_size_are_skipped_with_their_frees() {
+        private_shm();
+        let _recording = recording();
+        let allocator =
+            crate::ProfilingAllocator::<0, 0, { usize::MAX }, 0, TEST_ALLOC_SLOTS, 0, 1024>::new();
+        let layout = |size| Layout::from_size_align(size, 8).unwrap();
+        let marker =
+            || unsafe { &*get_markers().add(find_or_create_marker("min-size-test").unwrap()) };
+        let live = || {
+            let marker = marker();
+            marker.alloc_bytes.load(Ordering::Relaxed) as i64
+                - marker.free_bytes.load(Ordering::Relaxed) as i64
+        };
+
+        mark("min-size-test");
+        unsafe {
+            let small = allocator.alloc(layout(16));
+            let big = allocator.alloc(layout(2048));
+            assert_eq!(live(), 2048);
+            // Growing past the minimum starts counting, shrinking under it stops
+            let grown = allocator.realloc(small, layout(16), 1500);
+            let shrunk = allocator.realloc(big, layout(2048), 100);
+            assert_eq!(live(), 1500);
+            allocator.dealloc(shrunk, layout(100));
+            assert_eq!(live(), 1500);
+            allocator.dealloc(grown, layout(1500));
+        }
+        clear_mark();
+
+        let marker = marker();
+        assert_eq!(marker.alloc_count.load(Ordering::Relaxed), 2);
+        assert_eq!(marker.alloc_bytes.load(Ordering::Relaxed), 3548);
+        assert_eq!(marker.free_bytes.load(Ordering::Relaxed), 3548);
+    }
+
+    #[test]
+    fn allocations_between_marks_go_to_the_active_marker() {
+        private_shm();
+        let _recording = recording();
+        let marker =
+            |name: &str| unsafe { &*get_markers().add(find_or_create_marker(name).unwrap()) };
+        let blocks = [0u64; 3];
+        let block = |i: usize| &blocks[i] as *const u64 as *mut u8;
+
+        mark("marks-test-first");
+        record_alloc(block(0), 100, false, 1);
+        mark("marks-test-second");
+        record_alloc(block(1), 200, false, 1);
+        record_alloc(block(2), 300, false, 1);
+        clear_mark();
+        // Frees go to the marker the memory was allocated under
+        mark("marks-test-first");
+        record_dealloc(block(1), 200);
+        clear_mark();
+
+        let first = marker("marks-test-first");
+        assert_eq!(first.alloc_count.load(Ordering::Relaxed), 1);
+        assert_eq!(first.alloc_bytes.load(Ordering::Relaxed), 100);
+        assert_eq!(first.free_bytes.load(Ordering::Relaxed), 0);
+        let second = marker("marks-test-second");
+        assert_eq!(second.alloc_count.load(Ordering::Relaxed), 2);
+        assert_eq!(second.alloc_bytes.load(Ordering::Relaxed), 500);
+        assert_eq!(second.free_bytes.load(Ordering::Relaxed), 200);
+
+        record_dealloc(block(0), 100);
+        record_dealloc(block(2), 300);
+    }
+
+    #[test]
+    fn allocations_in_a_tagged_scope_are_grouped_under_the_tag() {
+        private_shm();
+        let _recording = recording();
+        let marker =
+            |name: &str| unsafe { &*get_markers().add(find_or_create_marker(name).unwrap()) };
+        let blocks = [0u64; 4];
+        let block = |i: usize| &blocks[i] as *const u64 as *mut u8;
+
+        mark("tags-test-outer");
+        {
+            let _parsing = crate::tag("tags-test-parsing");
+            record_alloc(block(0), 100, false, 1);
+            {
+                // Tags nest; an integer tag is named in decimal
+                let _inner = crate::tag(4253u32);
+                record_alloc(block(1), 200, false, 1);
+            }
+            record_alloc(block(2), 300, false, 1);
+        }
+        // The guard hands the thread back to the marker it interrupted
+        record_alloc(block(3), 400, false, 1);
+        clear_mark();
+
+        let parsing = marker("tags-test-parsing");
+        assert_eq!(parsing.alloc_count.load(Ordering::Relaxed), 2);
+        assert_eq!(parsing.alloc_bytes.load(Ordering::Relaxed), 400);
+        let inner = marker("4253");
+        assert_eq!(inner.alloc_count.load(Ordering::Relaxed), 1);
+        assert_eq!(inner.alloc_bytes.load(Ordering::Relaxed), 200);
+        let outer = marker("tags-test-outer");
+        assert_eq!(outer.alloc_count.load(Ordering::Relaxed), 1);
+        assert_eq!(outer.alloc_bytes.load(Ordering::Relaxed), 400);
+
+        for (i, size) in [100, 200, 300, 400].into_iter().enumerate() {
+            record_dealloc(block(i), size);
+        }
+    }
+
+    #[test]
+    fn paused_heap_recording_keeps_cpu_samples() {
+        private_shm();
+        let _paused = RECORDING.write().unwrap_or_else(PoisonError::into_inner);
+        let blocks = [0u64; 2];
+        let block = |i: usize| &blocks[i] as *const u64 as *mut u8;
+        let tracked = |ptr: *mut u8| {
+            let table = get_alloc_table();
+            (0..ALLOC_SLOTS.load(Ordering::Relaxed))
+                .any(|i| unsafe { &*table.add(i) }.ptr.load(Ordering::Acquire) == ptr as u64)
+        };
+        let cpu_samples = || {
+            let callsite = find_callsite(UNSTACKED_HASH);
+            unsafe { callsite.as_ref() }.map_or(0, |c| c.cpu_samples.load(Ordering::Relaxed))
+        };
+
+        set_heap_recording(false);
+        let before = cpu_samples();
+        record_alloc(block(0), 64, false, 1);
+        cpu_profiling::cpu_sample_handler(
+            libc::SIGPROF,
+            core::ptr::null_mut(),
+            core::ptr::null_mut(),
+        );
+        set_heap_recording(true);
+        assert!(!tracked(block(0)));
+        assert_eq!(cpu_samples(), before + 1);
+
+        // Resumed: allocations are tracked again
+        record_alloc(block(1), 64, false, 1);
+        assert!(tracked(block(1)));
+        record_dealloc(block(1), 64);
+    }
+
+    #[test]
+    fn sampled_allocation_weights_add_up_to_the_real_totals() {
+        private_shm();
+        // A thread of its own, so its byte countdown starts fresh
+        std::thread::spawn(|| {
+            let period = 4096;
+            let (mut count, mut bytes, mut samples) = (0u64, 0u64, 0u64);
+            for size in core::iter::repeat_n(64, 100_000).chain(core::iter::repeat_n(10_000, 500)) {
+                let weight = sample_alloc(size, period);
+                samples += (weight > 0) as u64;
+                count += weight;
+                bytes += weight * size as u64;
+            }
+
+            // 11.4MB allocated in 100,500 allocations, one sample per 4KB
+            let (real_count, real_bytes) = (100_500.0, 11_400_000.0);
+            assert!(samples < 4000);
+            assert!((count as f64 / real_count - 1.0).abs() < 0.1, "{}", count);
+            assert!((bytes as f64 / real_bytes - 1.0).abs() < 0.1, "{}", bytes);
+
+            // Allocations far above the period are always sampled, at weight 1
+            assert_eq!(sample_alloc(100 * period, period), 1);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn pause_guard_drops_events_until_it_is_dropped() {
+        private_shm();
+        let _paused = RECORDING.write().unwrap_or_else(PoisonError::into_inner);
+        let blocks = [0u64; 3];
+        let block = |i: usize| &blocks[i] as *const u64 as *mut u8;
+        let marker =
+            || unsafe { &*get_markers().add(find_or_create_marker("pause-test").unwrap()) };
+        let cpu_samples = || {
+            let callsite = find_callsite(UNSTACKED_HASH);
+            unsafe { callsite.as_ref() }.map_or(0, |c| c.cpu_samples.load(Ordering::Relaxed))
+        };
+        let sample = || {
+            cpu_profiling::cpu_sample_handler(
+                libc::SIGPROF,
+                core::ptr::null_mut(),
+                core::ptr::null_mut(),
+            )
+        };
+
+        mark("pause-test");
+        record_alloc(block(0), 100, false, 1);
+        let outer = crate::pause();
+        let before = cpu_samples();
+        record_alloc(block(1), 200, false, 1);
+        sample();
+        // Pauses nest: the outer one still holds after the inner one ends
+        drop(crate::pause());
+        sample();
+        assert_eq!(cpu_samples(), before);
+        // Memory from before the pause is still credited when freed
+        record_dealloc(block(0), 100);
+        drop(outer);
+
+        record_alloc(block(2), 300, false, 1);
+        sample();
+        clear_mark();
+        assert_eq!(cpu_samples(), before + 1);
+        let marker = marker();
+        assert_eq!(marker.alloc_count.load(Ordering::Relaxed), 2);
+        assert_eq!(marker.alloc_bytes.load(Ordering::Relaxed), 400);
+        assert_eq!(marker.free_bytes.load(Ordering::Relaxed), 100);
+
+        record_dealloc(block(2), 300);
+    }
+
+    #[test]
+    fn markers_with_the_same_hash_keep_their_own_slots() {
+        let words = MARKER_CAPACITY * core::mem::size_of::<MarkerStats>() / 8;
+        let markers = alloc::vec![0u64; words].leak().as_mut_ptr() as *mut MarkerStats;
+
+        let first = find_or_create_marker_in(markers, 42, b"first").unwrap();
+        let second = find_or_create_marker_in(markers, 42, b"second").unwrap();
+        assert_ne!(first, second);
+        assert_eq!(find_or_create_marker_in(markers, 42, b"first"), Some(first));
+        assert_eq!(
+            find_or_create_marker_in(markers, 42, b"second"),
+            Some(second)
+        );
+        // A prefix of a stored name is another name
+        assert_ne!(find_or_create_marker_in(markers, 42, b"sec"), Some(second));
+    }
+
+    #[test]
+    fn full_policies_decide_where_new_sites_are_counted() {
+        private_shm();
+        let stack = [0u64; MAX_STACK_DEPTH];
+        let new_site = CALLSITE_CAPACITY as u64 + 100;
+        let hash_of = |callsite: *mut CallsiteStats| {
+            unsafe { callsite.as_ref() }.map(|c| c.hash.load(Ordering::Relaxed))
+        };
+        let charged = |unstacked, policy| {
+            let table = full_callsite_table(unstacked);
+            // Sites already recorded are still counted exactly
+            assert_eq!(
+                hash_of(callsite_for_event_in(table, policy, 7, &stack, 0)),
+                Some(7)
+            );
+            hash_of(callsite_for_event_in(table, policy, new_site, &stack, 1))
+        };
+
+        // Never another site's callsite
+        assert_eq!(charged(true, FullPolicy::Overwrite), Some(UNSTACKED_HASH));
+        assert_eq!(charged(false, FullPolicy::Overwrite), None);
+        assert_eq!(charged(true, FullPolicy::DropNew), None);
+        assert_eq!(charged(true, FullPolicy::CountOnly), Some(UNSTACKED_HASH));
+
+        let dropped = unsafe { &(*get_header()).dropped_events };
+        let before = dropped.load(Ordering::Relaxed);
+        charged(true, FullPolicy::CountOnly);
+        assert!(dropped.load(Ordering::Relaxed) > before);
+    }
+
+    #[test]
+    fn only_listed_threads_are_recorded() {
+        let header: StatsHeader = unsafe { core::mem::zeroed() };
+        // No filter set: every thread is recorded
+        assert!(!filter_excludes(&header, 7));
+
+        header.thread_filter[0].store(7, Ordering::Relaxed);
+        header.thread_filter[1].store(9, Ordering::Relaxed);
+        header.thread_filter_len.store(2, Ordering::Release);
+        header
+            .thread_filter_mode
+            .store(THREAD_FILTER_ONLY, Ordering::Release);
+        assert!(!filter_excludes(&header, 7));
+        assert!(!filter_excludes(&header, 9));
+        assert!(filter_excludes(&header, 8));
+
+        // Entries past the length are stale
+        header.thread_filter_len.store(1, Ordering::Release);
+        assert!(filter_excludes(&header, 9));
+    }
+
+    /// A synthetic stack of frame records, innermost first, each holding the
+    /// caller's frame pointer and the given saved return address
+    fn frame_chain(returns: &[usize]) -> alloc::vec::Vec<usize> {
+        let mut frames = alloc::vec![0usize; returns.len() * 2];
+        let base = frames.as_ptr() as usize;
+        for (i, &ret) in returns.iter().enumerate() {
+            let caller = if i + 1 < returns.len() {
+                base + (i + 1) * 16
+            } else {
+                0
+            };
+            frames[i * 2] = caller;
+            frames[i * 2 + 1] = ret;
+        }
+        frames
+    }
+
+    fn walk(frames: &[usize]) -> alloc::vec::Vec<u64> {
+        let mut stack = [0u64; MAX_STACK_DEPTH];
+        let depth = walk_frames(&mut stack, 0, frames.as_ptr());
+        stack[..depth as usize].to_vec()
+    }
+
+    #[test]
+    fn frame_walk_follows_a_synthetic_chain() {
+        let frames = frame_chain(&[0x40_1000, 0x40_2000, 0x40_3000]);
+        assert_eq!(walk(&frames), [0x40_1000, 0x40_2000, 0x40_3000]);
+
+        // A link that points back down the stack ends the walk
+        let mut looped = frame_chain(&[0x40_1000, 0x40_2000, 0x40_3000]);
+        looped[2] = looped.as_ptr() as usize;
+        assert_eq!(walk(&looped), [0x40_1000, 0x40_2000]);
+
+        // So does a misaligned frame pointer
+        let mut misaligned = frame_chain(&[0x40_1000, 0x40_2000]);
+        misaligned[0] += 4;
+        assert_eq!(walk(&misaligned), [0x40_1000]);
+    }
+
+    /// Stand-in for a function calling into the allocator: scans the stack
+    /// and returns the return addresses found
+    #[inline(never)]
+    fn scanning_caller() -> ([u64; MAX_STACK_DEPTH], u32) {
+        let mut stack = [0u64; MAX_STACK_DEPTH];
+        let depth = scan_return_addresses(core::hint::black_box(&mut stack));
+        (core::hint::black_box(stack), depth)
+    }
+
+    #[test]
+    fn stack_scan_finds_the_immediate_caller() {
+        private_shm();
+        let (stack, depth) = scanning_caller();
+        // Without relying on frame pointers, the first frame found is the
+        // return address into the caller, just past its entry
+        let caller = scanning_caller as *const () as u64;
+        assert!(depth > 1);
+        assert!(stack[0] > caller && stack[0] < caller + 256, "{:x?}", stack);
+        // Every frame found points just past a call instruction
+        let start = TEXT_START.load(Ordering::Relaxed);
+        let end = TEXT_END.load(Ordering::Relaxed);
+        for &frame in &stack[..depth as usize] {
+            assert!(frame > start && frame < end);
+            assert!(unsafe { arch::follows_call(frame as usize) });
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn x86_64_return_addresses_follow_calls() {
+        // call rel32; call rax; then nops
+        let mut code = [0x90u8; 14];
+        code[..7].copy_from_slice(&[0xe8, 1, 2, 3, 4, 0xff, 0xd0]);
+        let at = |offset: usize| code.as_ptr() as usize + offset;
+        assert!(unsafe { arch::follows_call(at(5)) });
+        assert!(unsafe { arch::follows_call(at(7)) });
+        assert!(!unsafe { arch::follows_call(at(14)) });
+        assert_eq!(arch::return_address(0x40_1000), 0x40_1000);
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn aarch64_return_addresses_are_stripped_and_follow_calls() {
+        // Saved link registers signed by pointer authentication
+        let pac = 0x002a_0000_0000_0000;
+        let frames = frame_chain(&[pac | 0x40_1000, pac | 0x40_2000]);
+        assert_eq!(walk(&frames), [0x40_1000, 0x40_2000]);
+
+        // bl; blr x8; nop
+        let code: [u32; 3] = [0x9400_0010, 0xd63f_0100, 0xd503_201f];
+        let at = |index: usize| code.as_ptr() as usize + index * 4;
+        assert!(unsafe { arch::follows_call(at(1)) });
+        assert!(unsafe { arch::follows_call(at(2)) });
+        assert!(!unsafe { arch::follows_call(at(3)) });
+    }
+}
+
+#[cfg(all(test, feature = "cpu", not(feature = "heap")))]
+mod cpu_only_tests {
+    use super::*;
+
+    /// Point the profiler at a private zeroed mapping before the
+    /// `profiler!(cpu_only)` constructor starts sampling, so the test never
+    /// touches the shared memory segment another profiled process may use
+    extern "C" fn private_shm() {
+        unsafe {
+            let ptr = libc::mmap(
+                core::ptr::null_mut(),
+                shm_size(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            assert_ne!(ptr, libc::MAP_FAILED);
+            SHM_BASE = ptr as *mut u8;
+            (*get_header()).pid = libc::getpid() as u32;
+            PROCESS_ID.store(libc::getpid() as u32, Ordering::Relaxed);
+        }
+        INITIALIZED.store(true, Ordering::SeqCst);
+    }
+
+    // Constructors with a priority run before those without, like the
+    // profiler's own
+    #[used]
+    #[unsafe(link_section = ".init_array.00101")]
+    static PRIVATE_SHM: extern "C" fn() = private_shm;
+
+    crate::profiler!(cpu_only = 199);
+
+    fn timer_interval_us() -> i64 {
+        let mut timer: libc::itimerval = unsafe { core::mem::zeroed() };
+        unsafe { libc::getitimer(libc::ITIMER_PROF, &mut timer) };
+        timer.it_interval.tv_usec
+    }
+
+    fn sigprof_action() -> libc::sighandler_t {
+        let mut action: libc::sigaction = unsafe { core::mem::zeroed() };
+        unsafe { libc::sigaction(libc::SIGPROF, core::ptr::null(), &mut action) };
+        action.sa_sigaction
+    }
+
+    #[test]
+    fn cpu_only_profiler_samples_until_stopped() {
+        let header = unsafe { &*get_header() };
+        let samples = || -> u64 {
+            (0..CALLSITE_CAPACITY)
+                .map(|i| unsafe { &*get_callsites().add(i) })
+                .map(|callsite| callsite.cpu_samples.load(Ordering::Relaxed))
+                .sum()
+        };
+
+        // Started before main, with no allocation to piggy-back on
+        assert_eq!(timer_interval_us(), 1_000_000 / 199);
+        assert_eq!(header.cpu_freq_hz.load(Ordering::Relaxed), 199);
+        assert_ne!(sigprof_action(), libc::SIG_DFL);
+        assert_ne!(sigprof_action(), libc::SIG_IGN);
+
+        let start = std::time::Instant::now();
+        let mut spin = 0u64;
+        while samples() == 0 && start.elapsed() < std::time::Duration::from_secs(10) {
+            spin = core::hint::black_box(spin.wrapping_mul(31).wrapping_add(7));
+        }
+        assert!(samples() > 0);
+
+        crate::stop_cpu_profiling();
+        assert_eq!(timer_interval_us(), 0);
+        assert_eq!(sigprof_action(), libc::SIG_IGN);
+        assert_eq!(header.cpu_freq_hz.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,70 @@
+//! Checkpoint flush latency with many live locations
+//!
+//! Records CPU and heap samples for `LOCATIONS` sites per checkpoint and
+//! times `Storage::flush_checkpoint_at`:
+//!
+//!     cargo run --release -p rsprof --example flush_bench [locations] [checkpoints]
+
+use rsprof::storage::Storage;
+use rsprof::symbols::Location;
+use std::time::{Duration, Instant};
+
+const LOCATIONS: usize = 10_000;
+const CHECKPOINTS: usize = 20;
+
+fn main() -> rsprof::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let locations = args
+        .next()
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or(LOCATIONS);
+    let checkpoints = args
+        .next()
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or(CHECKPOINTS)
+        .max(2);
+
+    let path = std::env::temp_dir().join(format!("rsprof-flush-bench-{}.db", std::process::id()));
+    let mut storage = Storage::create(&path)?;
+
+    let sites: Vec<Location> = (0..locations)
+        .map(|i| Location {
+            file: format!("src/site_{}.rs", i / 100),
+            line: (i % 100) as u32 + 1,
+            column: 0,
+            function: format!("bench::site_{i}"),
+        })
+        .collect();
+
+    let mut flushes = Vec::with_capacity(checkpoints);
+    for checkpoint in 0..checkpoints {
+        let n = checkpoint as i64 + 1;
+        for (i, site) in sites.iter().enumerate() {
+            storage.record_cpu_sample_count(0x1000 + i as u64, site, n as u64);
+            storage.record_heap_sample(site, 64 * n, 32 * n, 32 * n, n as u64, n as u64);
+        }
+
+        let start = Instant::now();
+        storage.flush_checkpoint_at(checkpoint as i64 * 100)?;
+        flushes.push(start.elapsed());
+    }
+    drop(storage);
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+    }
+
+    // The first flush also inserts every location's address
+    let first = flushes.remove(0);
+    flushes.sort();
+    let total: Duration = flushes.iter().sum();
+    println!(
+        "{} locations x {} checkpoints: first {:.2?}, then mean {:.2?}, median {:.2?}, max {:.2?}",
+        locations,
+        checkpoints,
+        first,
+        total / flushes.len() as u32,
+        flushes[flushes.len() / 2],
+        flushes[flushes.len() - 1],
+    );
+    Ok(())
+}
@@ -1,3 +1,4 @@
+use crate::symbols::Attribution;
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 use std::time::Duration;
@@ -18,6 +19,16 @@ pub struct Cli {
     #[arg(long, short = 'P', global = true, conflicts_with = "pid")]
     pub process: Option<String>,
 
+    /// With --process, wait for a matching process to start (at most TIMEOUT, e.g. 30s)
+    /// and attach as soon as it does
+    #[arg(long, value_name = "TIMEOUT", num_args = 0..=1, requires = "process", conflicts_with = "pid", value_parser = parse_duration)]
+    pub wait: Option<Option<Duration>>,
+
+    /// Read --pid and --process in the PID namespace of this process, e.g. a container's
+    /// (`docker inspect -f '{{.State.Pid}}' NAME`)
+    #[arg(long, global = true, value_name = "HOST_PID")]
+    pub pid_ns: Option<u32>,
+
     /// Output database path
     #[arg(long, short = 'o', global = true)]
     pub output: Option<PathBuf>,
@@ -34,22 +45,129 @@ pub struct Cli {
     #[arg(long, default_value = "99")]
     pub cpu_freq: u64,
 
+    /// Tune the CPU sampling frequency while recording to keep overhead under this % of target CPU time
+    #[arg(long, value_name = "PERCENT", num_args = 0..=1, default_missing_value = "2", conflicts_with = "cpu_freq")]
+    pub auto_freq: Option<f64>,
+
+    /// Hardware counters to read at each CPU sample (perf sampler only), e.g. cycles,instructions
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "COUNTER,...")]
+    pub counters: Vec<crate::cpu::PerfCounter>,
+
     /// Disable TUI, record only
     #[arg(long, short = 'q')]
     pub quiet: bool,
 
-    /// Include internal/profiler frames in recording
+    /// Most locations the live TUI keeps per view; the least recently sampled are
+    /// dropped from the view (not from the profile) past this
+    #[arg(long, value_name = "N", default_value = "5000", value_parser = clap::value_parser!(u64).range(1..))]
+    pub live_max_locations: u64,
+
+    /// Include internal/profiler frames in recording (implies --attribute leaf)
     #[arg(long)]
     pub include_internal: bool,
 
+    /// Credit each sample to the frame that was executing (even inside std or the allocator)
+    /// or to the nearest user frame
+    #[arg(long, value_enum, default_value = "user")]
+    pub attribute: Attribution,
+
+    /// Only sample threads whose name matches this pattern (substring match on comm)
+    ///
+    /// Re-matched as threads start or get renamed. rsprof-trace targets also record only
+    /// these threads' allocations
+    #[arg(long, value_name = "PATTERN")]
+    pub threads: Option<String>,
+
+    /// Don't sample this thread: a TID (the main thread's is the PID) or a name substring.
+    /// Repeatable; applied after --threads
+    #[arg(long, value_name = "TID|NAME")]
+    pub exclude_thread: Vec<String>,
+
+    /// Also sample processes the target forks without exec (e.g. prefork workers), running
+    /// or forked later. Samples keep their PID: see `rsprof top processes`
+    #[arg(long, conflicts_with_all = ["threads", "exclude_thread"])]
+    pub follow_forks: bool,
+
+    /// Skip loading debug symbols; store raw addresses and resolve them at view time
+    #[arg(long, conflicts_with = "append")]
+    pub no_symbols: bool,
+
+    /// Store heap stacks as raw addresses and resolve them at view time (needs -q)
+    ///
+    /// Keeps per-checkpoint stack resolution off the recorder at high
+    /// allocation rates; CPU samples are still resolved while recording.
+    #[arg(long, requires = "quiet", conflicts_with_all = ["append", "no_symbols"])]
+    pub defer_heap_symbols: bool,
+
+    /// Only show heap sites holding allocations still live after this age (e.g. 30s), with
+    /// those as their live bytes; allocation totals stay cumulative
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub min_age: Option<Duration>,
+
+    /// Record where heap memory is freed, to pair alloc and free sites (rsprof-trace only;
+    /// costs a stack walk per free). See `rsprof top transfers`
+    #[arg(long)]
+    pub transfers: bool,
+
+    /// Also record where threads block and for how long, from sched:sched_switch (needs -q;
+    /// only threads running when recording starts). See `rsprof top off-cpu`
+    #[arg(long, requires = "quiet")]
+    pub off_cpu: bool,
+
+    /// Warn once if profiler overhead exceeds this % of the target's CPU time
+    #[arg(long, value_name = "PERCENT", num_args = 0..=1, default_missing_value = "5")]
+    pub warn_on_overhead: Option<f64>,
+
     /// Append to the most recent profile for this process instead of creating a new one
     #[arg(long, short = 'a')]
     pub append: bool,
+
+    /// Overwrite the --output file if it already exists (replaced once recording ends)
+    #[arg(long, conflicts_with = "append")]
+    pub force: bool,
+
+    /// Recording format: SQLite database, or an append-only event log for `rsprof ingest`
+    #[arg(long, value_enum, default_value = "db")]
+    pub format: RecordFormat,
+
+    /// Publish a JSON snapshot per checkpoint to clients of this Unix socket
+    #[arg(long, value_name = "SOCKET")]
+    pub serve: Option<PathBuf>,
+
+    /// TUI table column widths, e.g. `function=40,trend=0` (columns: function,
+    /// location, trend; 0 hides one). Unset columns adapt to the terminal width
+    #[arg(long, global = true, value_name = "SPEC", value_parser = parse_columns)]
+    pub columns: Option<ColumnWidths>,
+
+    #[command(flatten)]
+    pub names: NameOptions,
+}
+
+/// Function name simplifications that can be turned off independently
+#[derive(clap::Args, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NameOptions {
+    /// Show generic parameters in full instead of collapsing long ones to <_>
+    #[arg(long, global = true)]
+    pub keep_generics: bool,
+
+    /// Keep the ::h<hash> suffix of function names
+    #[arg(long, global = true)]
+    pub keep_hash: bool,
+}
+
+/// Fixed widths for the TUI table's variable columns (`--columns`)
+///
+/// `None` leaves a column to the adaptive layout; `Some(0)` hides it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ColumnWidths {
+    pub function: Option<u16>,
+    pub location: Option<u16>,
+    pub trend: Option<u16>,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Command {
-    /// View top CPU or heap consumers from a recorded profile
+    /// View top CPU, heap, marker or combined cost consumers from a recorded profile
     Top {
         /// What to display
         #[arg(value_enum)]
@@ -78,28 +196,238 @@ pub enum Command {
         #[arg(long)]
         json: bool,
 
+        /// Output as JSON Lines: one object per entry, with the same fields as --json
+        #[arg(long, conflicts_with_all = ["json", "csv"])]
+        jsonl: bool,
+
         /// Output as CSV
         #[arg(long)]
         csv: bool,
 
-        /// Filter by file or function name
+        /// Only show rows whose function or file contains this (a regex with --regex)
         #[arg(long, short = 'f')]
         filter: Option<String>,
+
+        /// Hide rows whose function or file contains this (a regex with --regex)
+        #[arg(long, short = 'x', value_name = "PATTERN")]
+        exclude: Option<String>,
+
+        /// Read --filter and --exclude as regular expressions
+        #[arg(long)]
+        regex: bool,
+
+        /// Weight of CPU share in the cost score
+        #[arg(long, default_value = "1.0")]
+        cpu_weight: f64,
+
+        /// Weight of allocation share in the cost score
+        #[arg(long, default_value = "1.0")]
+        heap_weight: f64,
+
+        /// What CPU percentages are relative to
+        #[arg(long, value_enum, default_value = "total")]
+        percent_of: PercentBase,
+    },
+
+    /// Print the hottest source files with per-line sample counts in the gutter
+    Annotate {
+        /// Profile database file
+        file: PathBuf,
+
+        /// Directory to find the recorded source paths under (default: current directory)
+        #[arg(long, value_name = "DIR")]
+        source_root: Option<PathBuf>,
+
+        /// Annotate with live heap bytes instead of CPU samples
+        #[arg(long)]
+        heap: bool,
+
+        /// Number of source files to annotate
+        #[arg(long, short = 'n', default_value = "3")]
+        top: usize,
+
+        /// Lines of context around each hot line
+        #[arg(long, short = 'C', default_value = "3")]
+        context: usize,
+    },
+
+    /// Compare CPU share per function between two profiles
+    Diff {
+        /// Profile to compare against (before)
+        base: PathBuf,
+
+        /// Profile to compare (after)
+        new: PathBuf,
+
+        /// What to compare
+        #[arg(long, value_enum, default_value = "cpu")]
+        metric: DiffMetric,
+
+        /// Compare source lines (file, line, function) instead of whole functions
+        #[arg(long)]
+        lines: bool,
+
+        /// Number of functions to display
+        #[arg(long, short = 'n', default_value = "20")]
+        top: usize,
+
+        /// File of `old_name = new_name` lines, for functions renamed between the profiles
+        #[arg(long, value_name = "FILE")]
+        rename_map: Option<PathBuf>,
+
+        /// Suggest renames: functions that vanished next to new ones in the same file
+        #[arg(long)]
+        suggest_renames: bool,
+
+        /// Output as JSON
+        #[arg(long, conflicts_with = "suggest_renames")]
+        json: bool,
+    },
+
+    /// Fail (exit 1) if functions regressed against a golden profile, for CI
+    Assert {
+        /// Profile to check
+        profile: PathBuf,
+
+        /// Known-good profile to compare against
+        #[arg(long, value_name = "FILE")]
+        golden: PathBuf,
+
+        /// Largest allowed growth of a function's CPU share or allocated bytes (e.g. 10%)
+        #[arg(long, value_name = "PERCENT", default_value = "10%", value_parser = parse_percent)]
+        max_regression: f64,
+
+        /// Ignore functions under this share of CPU samples / allocated bytes in both profiles
+        #[arg(long, value_name = "PERCENT", default_value = "1%", value_parser = parse_percent)]
+        min_share: f64,
+
+        /// File of `old_name = new_name` lines, for functions renamed since the golden profile
+        #[arg(long, value_name = "FILE")]
+        rename_map: Option<PathBuf>,
+    },
+
+    /// Show what held memory when total live heap bytes peaked
+    Peak {
+        /// Profile database file
+        file: PathBuf,
+
+        /// Number of entries to display
+        #[arg(long, short = 'n', default_value = "20")]
+        top: usize,
+
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Show the sites holding the most live memory at the end, and whether each is settling
+    Retained {
+        /// Profile database file
+        file: PathBuf,
+
+        /// Number of sites to display
+        #[arg(long, short = 'n', default_value = "20")]
+        top: usize,
+
+        /// Checkpoints to classify each site's trend over (stable/growing/shrinking)
+        #[arg(long, short = 'k', default_value = "10", value_parser = clap::value_parser!(u64).range(2..))]
+        window: u64,
+
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Find heap sites whose live bytes grow steadily (likely leaks)
+    Leaks {
+        /// Profile database file
+        file: PathBuf,
+
+        /// Number of sites to display
+        #[arg(long, short = 'n', default_value = "20")]
+        top: usize,
+
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Export a profile as a single shareable file (flame graph + top tables)
+    Report {
+        /// Profile database file
+        file: PathBuf,
+
+        /// Report format
+        #[arg(long, value_enum, default_value = "html")]
+        format: ReportFormat,
+
+        /// Output file (default: stdout)
+        #[arg(long, short = 'o')]
+        output: Option<PathBuf>,
+
+        /// Number of entries in each table
+        #[arg(long, short = 'n', default_value = "20")]
+        top: usize,
     },
 
-    /// Execute raw SQL query on a profile database
+    /// Convert a profile for other tools (`go tool pprof`, flamegraph.pl, speedscope)
+    Export {
+        /// Profile database file
+        file: PathBuf,
+
+        /// Output format
+        #[arg(long, value_enum, default_value = "pprof")]
+        format: ExportFormat,
+
+        /// Value of each folded stack (pprof and speedscope carry all of them)
+        #[arg(long, value_enum, default_value = "cpu")]
+        metric: ExportMetric,
+
+        /// Output file (default: stdout, unless it is a terminal)
+        #[arg(long, short = 'o')]
+        output: Option<PathBuf>,
+    },
+
+    /// Execute raw SQL (or a canned --preset query) on a profile database
     Query {
         /// Profile database file
         file: PathBuf,
 
         /// SQL query to execute
-        sql: String,
+        #[arg(required_unless_present = "preset", conflicts_with = "preset")]
+        sql: Option<String>,
+
+        /// Run a canned query instead of raw SQL
+        #[arg(long, value_enum)]
+        preset: Option<QueryPreset>,
+
+        /// Number of rows for --preset queries
+        #[arg(long, short = 'n', default_value = "20")]
+        top: usize,
+
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Print the profile database schema (DDL), or compare a file's schema to it
+    Schema {
+        /// Profile database to inspect (default: print the current schema)
+        file: Option<PathBuf>,
     },
 
     /// Interactive TUI viewer for a recorded profile
     View {
         /// Profile database file (defaults to most recent)
         file: Option<PathBuf>,
+
+        /// Flip through every profile in a directory ([ and ] for prev/next)
+        #[arg(long, value_name = "DIR", conflicts_with = "file")]
+        playlist: Option<PathBuf>,
+
+        /// With --playlist, advance to the next profile after this long
+        #[arg(long, value_parser = parse_duration, requires = "playlist")]
+        dwell: Option<Duration>,
     },
 
     /// List saved profile databases
@@ -109,6 +437,41 @@ pub enum Command {
         dir: Option<PathBuf>,
     },
 
+    /// List a process's threads with their names and states (to pick a --threads filter)
+    Threads {
+        /// PID or process name (pgrep-style matching; see --pid-ns)
+        target: String,
+    },
+
+    /// Convert an event log (recorded with --format eventlog) into a profile database
+    Ingest {
+        /// Event log file (use -o to set the output database path)
+        file: PathBuf,
+    },
+
+    /// Resolve the raw stacks of a --no-symbols or --defer-heap-symbols recording in place
+    ///
+    /// Optional: `view`, `top` and the other commands resolve them in memory on every open
+    Symbolicate {
+        /// Profile database file
+        file: PathBuf,
+
+        /// Load address of the binary, overriding the recorded one (0 for non-PIE binaries)
+        #[arg(long, value_name = "ADDR", value_parser = parse_address)]
+        aslr_offset: Option<u64>,
+    },
+
+    /// Convert a perf.data file (from `perf record`, ideally with -g) into a profile database
+    ///
+    /// Imports the CPU samples of one process: -p PID, or the one with the most samples
+    ImportPerf {
+        /// perf.data file
+        file: PathBuf,
+
+        /// Output database path (defaults to the input path with a .db extension)
+        out: Option<PathBuf>,
+    },
+
     /// Generate shell completions
     Completions {
         /// Shell to generate completions for
@@ -120,6 +483,135 @@ pub enum Command {
 pub enum TopMetric {
     Cpu,
     Heap,
+    /// Heap usage grouped by marker or tag (rsprof_trace::mark, rsprof_trace::tag)
+    #[value(alias = "tags")]
+    Markers,
+    /// Combined CPU + allocation cost (see --cpu-weight/--heap-weight)
+    Cost,
+    /// p50/p90/p99 time between checkpoints with CPU samples per location (stalls)
+    Gaps,
+    /// Allocation size distribution per site: min/median/mode/max
+    Sizes,
+    /// CPU spent inside malloc/realloc/free on behalf of each allocation site
+    AllocCpu,
+    /// Memory allocated at one site and freed at another (record with --transfers)
+    Transfers,
+    /// CPU samples per process, parent and forked children (record with --follow-forks)
+    Processes,
+    /// Live heap bytes per full call stack, allocation site first
+    Stacks,
+    /// Time threads spent blocked (off-CPU) per location (record with --off-cpu)
+    OffCpu,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// Self-contained HTML page with an interactive flame graph; no network needed
+    Html,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Gzipped profile.proto: CPU samples/time, plus inuse_space and alloc_space per heap stack
+    Pprof,
+    /// Collapsed stacks (`frame;frame;frame value`) for flamegraph.pl or inferno
+    Folded,
+    /// speedscope JSON: CPU over time (per process with --follow-forks), heap per call stack
+    Speedscope,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum ExportMetric {
+    /// CPU samples per function
+    Cpu,
+    /// Live heap bytes at the end of the run, per call stack
+    Heap,
+    /// Heap bytes allocated over the run, per call stack
+    Alloc,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum DiffMetric {
+    /// Share of CPU samples
+    Cpu,
+    /// Live heap bytes at the end of the recording
+    Heap,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum QueryPreset {
+    /// CPU samples and share per location
+    TopCpu,
+    /// Live and allocated bytes per location
+    TopHeap,
+    /// Locations whose memory is still live, with growth since first seen
+    Leaks,
+    /// Locations with the most allocation calls
+    Churn,
+    /// Distinct sampled addresses per location, as offsets into the binary
+    Addresses,
+}
+
+/// Denominator for CPU percentages
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum PercentBase {
+    /// All samples in the recording
+    #[default]
+    Total,
+    /// Samples in the window shown: --since/--until, or the visible chart range in the TUI
+    Window,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordFormat {
+    /// SQLite profile database, queryable while recording
+    Db,
+    /// Append-only event log of raw samples; lowest overhead (symbols are only
+    /// loaded by `rsprof ingest`, which converts it)
+    Eventlog,
+}
+
+/// Parse a percentage: `10%` or `10`
+fn parse_percent(s: &str) -> Result<f64, String> {
+    match s.trim().trim_end_matches('%').trim().parse::<f64>() {
+        Ok(percent) if percent >= 0.0 && percent.is_finite() => Ok(percent),
+        _ => Err(format!("Invalid percentage '{}'. Examples: 10%, 2.5", s)),
+    }
+}
+
+fn parse_address(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse::<u64>(),
+    }
+    .map_err(|_| format!("Invalid address '{}'. Examples: 0x55d4a0000000, 0", s))
+}
+
+fn parse_columns(s: &str) -> Result<ColumnWidths, String> {
+    let mut columns = ColumnWidths::default();
+    for spec in s.split(',').map(str::trim).filter(|spec| !spec.is_empty()) {
+        let (name, width) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid column '{}'. Expected NAME=WIDTH", spec))?;
+        let width = width
+            .trim()
+            .parse::<u16>()
+            .map_err(|_| format!("Invalid width '{}' for column '{}'", width, name))?;
+        let slot = match name.trim().to_ascii_lowercase().as_str() {
+            "function" => &mut columns.function,
+            "location" => &mut columns.location,
+            "trend" => &mut columns.trend,
+            other => {
+                return Err(format!(
+                    "Unknown column '{}'. Columns: function, location, trend",
+                    other
+                ));
+            }
+        };
+        *slot = Some(width);
+    }
+    Ok(columns)
 }
 
 fn parse_duration(s: &str) -> Result<Duration, String> {
@@ -146,6 +638,15 @@ impl Cli {
             return Err("Either --pid or --process is required for recording".to_string());
         }
 
+        if let Some(percent) = self.auto_freq
+            && !(percent > 0.0 && percent <= 100.0)
+        {
+            return Err(format!(
+                "--auto-freq budget must be between 0 and 100 percent, got {}",
+                percent
+            ));
+        }
+
         // Validate CPU frequency
         if self.cpu_freq == 0 || self.cpu_freq > 10000 {
             return Err(format!(
@@ -154,6 +655,130 @@ impl Cli {
             ));
         }
 
+        if self.counters.len() > crate::cpu::MAX_COUNTERS {
+            return Err(format!(
+                "At most {} --counters can be recorded together, got {}",
+                crate::cpu::MAX_COUNTERS,
+                self.counters.len()
+            ));
+        }
+
+        if self.format == RecordFormat::Eventlog {
+            if !self.quiet {
+                return Err("--format eventlog requires --quiet".to_string());
+            }
+            if self.append {
+                return Err("--format eventlog cannot be used with --append".to_string());
+            }
+        }
+
+        if self.serve.is_some() {
+            if !self.quiet {
+                return Err("--serve requires --quiet".to_string());
+            }
+            if self.format == RecordFormat::Eventlog {
+                return Err("--serve cannot be used with --format eventlog".to_string());
+            }
+        }
+
         Ok(())
     }
+
+    /// Attribution policy, with --include-internal forcing leaf attribution
+    pub fn attribution(&self) -> Attribution {
+        if self.include_internal {
+            Attribution::Leaf
+        } else {
+            self.attribute
+        }
+    }
+
+    /// Recording settings as shell-quoted flags, stored with the profile so
+    /// `R` in `rsprof view` can repeat the recording
+    ///
+    /// Leaves out the target and output (-p/-P, -o, --append, --format,
+    /// --serve) and flags left at their defaults.
+    pub fn record_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        match self.wait {
+            Some(Some(timeout)) => {
+                args.extend(["--wait".to_string(), format_duration_arg(timeout)])
+            }
+            Some(None) => args.push("--wait".to_string()),
+            None => {}
+        }
+        if self.interval != Duration::from_secs(1) {
+            args.extend(["-i".to_string(), format_duration_arg(self.interval)]);
+        }
+        if let Some(duration) = self.duration {
+            args.extend(["-d".to_string(), format_duration_arg(duration)]);
+        }
+        if let Some(percent) = self.auto_freq {
+            args.extend(["--auto-freq".to_string(), percent.to_string()]);
+        } else if self.cpu_freq != 99 {
+            args.extend(["--cpu-freq".to_string(), self.cpu_freq.to_string()]);
+        }
+        if !self.counters.is_empty() {
+            let names: Vec<_> = self.counters.iter().map(|c| c.name()).collect();
+            args.extend(["--counters".to_string(), names.join(",")]);
+        }
+        if self.quiet {
+            args.push("-q".to_string());
+        }
+        if self.include_internal {
+            args.push("--include-internal".to_string());
+        } else if self.attribute != Attribution::default() {
+            args.extend(["--attribute".to_string(), self.attribute.name().to_string()]);
+        }
+        if let Some(pattern) = &self.threads {
+            args.extend(["--threads".to_string(), shell_quote(pattern)]);
+        }
+        for spec in &self.exclude_thread {
+            args.extend(["--exclude-thread".to_string(), shell_quote(spec)]);
+        }
+        if self.follow_forks {
+            args.push("--follow-forks".to_string());
+        }
+        if self.no_symbols {
+            args.push("--no-symbols".to_string());
+        }
+        if self.defer_heap_symbols {
+            args.push("--defer-heap-symbols".to_string());
+        }
+        if let Some(min_age) = self.min_age {
+            args.extend(["--min-age".to_string(), format_duration_arg(min_age)]);
+        }
+        if self.transfers {
+            args.push("--transfers".to_string());
+        }
+        if self.off_cpu {
+            args.push("--off-cpu".to_string());
+        }
+        if let Some(percent) = self.warn_on_overhead {
+            args.extend(["--warn-on-overhead".to_string(), percent.to_string()]);
+        }
+        args
+    }
+}
+
+/// Format a duration so `parse_duration` reads it back unchanged
+fn format_duration_arg(duration: Duration) -> String {
+    if duration.subsec_nanos() == 0 {
+        format!("{}s", duration.as_secs())
+    } else {
+        format!("{}ms", duration.as_millis())
+    }
+}
+
+/// Quote an argument for a POSIX shell unless it only has safe characters
+pub fn shell_quote(arg: &str) -> String {
+    let safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=,@%+".contains(c));
+    if safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
 }
@@ -0,0 +1,266 @@
+use crate::error::Result;
+use crate::storage::{query_top_cpu, query_top_heap_live, repair_clock_skew};
+use crate::symbols::deferred;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// Per-line weights (samples or live bytes) for one source file
+struct FileHotness {
+    file: String,
+    total: u64,
+    lines: BTreeMap<u32, u64>,
+}
+
+/// Run the annotate command
+///
+/// Prints the hottest source files of the profile with each line's share of
+/// CPU samples (or live heap bytes with `heap`) in the left gutter. Only hot
+/// lines and `context` lines around them are shown.
+pub fn run(
+    file: &Path,
+    source_root: Option<&Path>,
+    heap: bool,
+    files: usize,
+    context: usize,
+) -> Result<()> {
+    // A --no-symbols recording is resolved in memory; the file stays as recorded
+    let mut conn = deferred::open_resolved(file)?;
+    // Keep the timeline ordered if the clock stepped back while recording
+    repair_clock_skew(&mut conn)?;
+
+    let by_file = if heap {
+        hotness_by_file(
+            query_top_heap_live(&conn, i64::MAX as usize)?
+                .into_iter()
+                .map(|e| (e.file, e.line, e.live_bytes.max(0) as u64)),
+        )
+    } else {
+        hotness_by_file(
+            query_top_cpu(&conn, i64::MAX as usize, 0.0)?
+                .into_iter()
+                .map(|e| (e.file, e.line, e.total_samples)),
+        )
+    };
+
+    if by_file.is_empty() {
+        eprintln!(
+            "No {} with line information found in {}",
+            if heap {
+                "live heap data"
+            } else {
+                "CPU samples"
+            },
+            file.display()
+        );
+        return Ok(());
+    }
+
+    let grand_total: u64 = by_file.iter().map(|f| f.total).sum();
+    let source_root = source_root.unwrap_or(Path::new("."));
+
+    println!("# {}", file.display());
+    for hot in by_file.iter().take(files) {
+        println!();
+        println!(
+            "# {}: {} ({:.1}% of {})",
+            hot.file,
+            if heap {
+                format_weight(hot.total, heap)
+            } else {
+                format!("{} samples", hot.total)
+            },
+            hot.total as f64 * 100.0 / grand_total as f64,
+            if heap { "live heap" } else { "CPU samples" }
+        );
+
+        let Some(path) = find_source(source_root, &hot.file) else {
+            println!("# (source not found under {})", source_root.display());
+            continue;
+        };
+        let source = match std::fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(e) => {
+                println!("# (can't read {}: {})", path.display(), e);
+                continue;
+            }
+        };
+        for line in annotate(&source, hot, grand_total, heap, context) {
+            println!("{}", line);
+        }
+    }
+
+    Ok(())
+}
+
+/// Sum weights by file and line, hottest file first
+///
+/// Locations without line information (unknown frames, "no stack") are skipped.
+fn hotness_by_file(entries: impl Iterator<Item = (String, u32, u64)>) -> Vec<FileHotness> {
+    let mut files: HashMap<String, BTreeMap<u32, u64>> = HashMap::new();
+    for (file, line, weight) in entries {
+        if line == 0 || weight == 0 {
+            continue;
+        }
+        *files.entry(file).or_default().entry(line).or_insert(0) += weight;
+    }
+
+    let mut result: Vec<FileHotness> = files
+        .into_iter()
+        .map(|(file, lines)| FileHotness {
+            total: lines.values().sum(),
+            file,
+            lines,
+        })
+        .collect();
+    result.sort_by(|a, b| b.total.cmp(&a.total).then_with(|| a.file.cmp(&b.file)));
+    result
+}
+
+/// Locate a recorded source path on disk
+///
+/// Relative paths are looked up under `root`. Absolute paths are used as-is
+/// when they exist, otherwise the longest suffix of the path that exists
+/// under `root` wins (so sources built elsewhere can still be found).
+pub(crate) fn find_source(root: &Path, file: &str) -> Option<PathBuf> {
+    let path = Path::new(file);
+    if path.is_relative() {
+        let candidate = root.join(path);
+        return candidate.is_file().then_some(candidate);
+    }
+    if path.is_file() {
+        return Some(path.to_path_buf());
+    }
+
+    let components: Vec<_> = path.components().skip(1).collect();
+    (0..components.len()).find_map(|skip| {
+        let candidate: PathBuf = root.join(components[skip..].iter().collect::<PathBuf>());
+        candidate.is_file().then_some(candidate)
+    })
+}
+
+/// Lines of `source` annotated with their weight: hot lines and their context
+fn annotate(
+    source: &str,
+    hot: &FileHotness,
+    grand_total: u64,
+    heap: bool,
+    context: usize,
+) -> Vec<String> {
+    let lines: Vec<&str> = source.lines().collect();
+    let mut out = Vec::new();
+
+    // Hot lines plus their context, as 1-based line numbers
+    let mut shown = vec![false; lines.len() + 1];
+    for &line in hot.lines.keys() {
+        let line = line as usize;
+        let first = line.saturating_sub(context).max(1);
+        let last = (line + context).min(lines.len());
+        for flag in shown.iter_mut().take(last + 1).skip(first) {
+            *flag = true;
+        }
+    }
+
+    out.push(String::new());
+    out.push(format!(
+        "{:>8} {:>6}  {:>5}  SOURCE",
+        weight_header(heap),
+        "%",
+        "LINE"
+    ));
+    out.push("-".repeat(80));
+
+    let mut skipped = false;
+    for (idx, text) in lines.iter().enumerate() {
+        let line_no = idx + 1;
+        if !shown[line_no] {
+            skipped = true;
+            continue;
+        }
+        if skipped {
+            out.push(format!("{:>8} {:>6}  {:>5}", "", "", "..."));
+            skipped = false;
+        }
+
+        out.push(match hot.lines.get(&(line_no as u32)) {
+            Some(&weight) => format!(
+                "{:>8} {:>5.1}%  {:>5}  {}",
+                format_weight(weight, heap),
+                weight as f64 * 100.0 / grand_total as f64,
+                line_no,
+                text
+            ),
+            None => format!("{:>8} {:>6}  {:>5}  {}", "", "", line_no, text),
+        });
+    }
+
+    // Samples attributed past the end of the file (source changed since the build?)
+    let stale = hot.lines.range(lines.len() as u32 + 1..).count();
+    if stale > 0 {
+        out.push(format!(
+            "# {} hot line(s) past the end of the file; the source may not match the profiled build",
+            stale
+        ));
+    }
+    out
+}
+
+fn weight_header(heap: bool) -> &'static str {
+    if heap { "LIVE" } else { "SAMPLES" }
+}
+
+fn format_weight(weight: u64, heap: bool) -> String {
+    if !heap {
+        return weight.to_string();
+    }
+    let bytes = weight as f64;
+    if bytes >= 1_073_741_824.0 {
+        format!("{:.1}GB", bytes / 1_073_741_824.0)
+    } else if bytes >= 1_048_576.0 {
+        format!("{:.1}MB", bytes / 1_048_576.0)
+    } else if bytes >= 1024.0 {
+        format!("{:.1}KB", bytes / 1024.0)
+    } else {
+        format!("{}B", weight)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hot_lines_are_annotated_with_their_counts() {
+        // Two functions inlined on one line of lib.rs add up; main.rs is cooler
+        let entries = [
+            ("src/lib.rs", 3, 30),
+            ("src/lib.rs", 3, 10),
+            ("src/lib.rs", 6, 20),
+            ("src/lib.rs", 0, 500),
+            ("src/main.rs", 1, 40),
+        ];
+        let by_file = hotness_by_file(
+            entries
+                .iter()
+                .map(|&(file, line, samples)| (file.to_string(), line, samples)),
+        );
+        let summary: Vec<(&str, u64)> =
+            by_file.iter().map(|f| (f.file.as_str(), f.total)).collect();
+        assert_eq!(summary, [("src/lib.rs", 60), ("src/main.rs", 40)]);
+
+        let source = "fn a() {}\n\nfn hot() {}\n\n\nfn warm() {}\n\n\n\nfn cold() {}\n";
+        // Line 1 is out of context; nothing after line 7 is shown
+        let lines = annotate(source, &by_file[0], 100, false, 1);
+        assert_eq!(
+            lines[3..],
+            [
+                format!("{:>8} {:>6}  {:>5}", "", "", "..."),
+                format!("{:>8} {:>6}  {:>5}  {}", "", "", 2, ""),
+                format!("{:>8} {:>5.1}%  {:>5}  {}", 40, 40.0, 3, "fn hot() {}"),
+                format!("{:>8} {:>6}  {:>5}  {}", "", "", 4, ""),
+                format!("{:>8} {:>6}  {:>5}  {}", "", "", 5, ""),
+                format!("{:>8} {:>5.1}%  {:>5}  {}", 20, 20.0, 6, "fn warm() {}"),
+                format!("{:>8} {:>6}  {:>5}  {}", "", "", 7, ""),
+            ][..]
+        );
+    }
+}
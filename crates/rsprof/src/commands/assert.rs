@@ -0,0 +1,314 @@
+use super::diff::{RenameMap, load_sites};
+use super::top::{format_bytes, format_function};
+use crate::cli::{DiffMetric, NameOptions};
+use crate::error::{Error, Result};
+use crate::storage::repair_clock_skew;
+use crate::symbols::{deferred, strip_hash_suffix};
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+/// Run the assert command
+///
+/// Compares CPU share and allocated bytes per function (matched like
+/// `rsprof diff`) against a golden profile, and fails if any function grew
+/// by more than `max_regression` percent of its golden value. Functions under
+/// `min_share` percent of the total in both profiles are noise and skipped;
+/// a function above it that the golden profile lacks counts as a regression,
+/// one that disappeared does not.
+pub fn run(
+    profile: &Path,
+    golden: &Path,
+    max_regression: f64,
+    min_share: f64,
+    rename_map: Option<&Path>,
+    names: NameOptions,
+) -> Result<()> {
+    let renames = match rename_map {
+        Some(path) => RenameMap::load(path)?,
+        None => RenameMap::default(),
+    };
+
+    let metrics = [
+        (Metric::Cpu, load_cpu(golden)?, load_cpu(profile)?),
+        (Metric::Alloc, load_alloc(golden)?, load_alloc(profile)?),
+    ];
+
+    println!("# golden: {}", golden.display());
+    println!("# new:    {}", profile.display());
+    println!(
+        "# max regression {:.1}%, functions under {:.1}% of the total skipped",
+        max_regression, min_share
+    );
+
+    let mut failed = 0;
+    for (metric, golden_values, new_values) in metrics {
+        println!();
+        if golden_values.is_empty() {
+            println!("{}: no data in the golden profile, skipped", metric.name());
+            continue;
+        }
+        let golden_values = golden_values.renamed(&renames);
+        let (compared, regressions) =
+            compare(&golden_values, &new_values, max_regression, min_share);
+        if regressions.is_empty() {
+            println!("{}: {} function(s) compared, ok", metric.name(), compared);
+            continue;
+        }
+        println!(
+            "{}: {} function(s) compared, {} regressed",
+            metric.name(),
+            compared,
+            regressions.len()
+        );
+        println!("{:>10}  {:>10}  {:>8}  FUNCTION", "GOLDEN", "NEW", "CHANGE");
+        for r in &regressions {
+            let change = match r.change() {
+                Some(change) => format!("{:+.1}%", change),
+                None => "new".to_string(),
+            };
+            println!(
+                "{:>10}  {:>10}  {:>8}  {}",
+                metric.format(r.golden),
+                metric.format(r.new),
+                change,
+                format_function(&r.function, names)
+            );
+        }
+        failed += regressions.len();
+    }
+
+    println!();
+    if failed > 0 {
+        println!("FAIL");
+        return Err(Error::Regression(failed));
+    }
+    println!("PASS");
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum Metric {
+    Cpu,
+    Alloc,
+}
+
+impl Metric {
+    fn name(self) -> &'static str {
+        match self {
+            Metric::Cpu => "cpu",
+            Metric::Alloc => "alloc",
+        }
+    }
+
+    fn format(self, value: f64) -> String {
+        match self {
+            Metric::Cpu => format!("{:.1}%", value),
+            Metric::Alloc => format_bytes(value as i64),
+        }
+    }
+}
+
+/// Value per function (hash suffix removed) and the profile's total
+#[derive(Default)]
+struct FunctionValues {
+    total: f64,
+    values: HashMap<String, f64>,
+}
+
+impl FunctionValues {
+    fn is_empty(&self) -> bool {
+        self.total <= 0.0
+    }
+
+    /// Percent of the profile's total
+    fn share(&self, value: f64) -> f64 {
+        if self.total > 0.0 {
+            value / self.total * 100.0
+        } else {
+            0.0
+        }
+    }
+
+    /// Golden values under the names they have in the new profile
+    fn renamed(self, renames: &RenameMap) -> FunctionValues {
+        let mut values = HashMap::new();
+        for (name, value) in self.values {
+            let name = renames.get(&name).map_or(name.clone(), str::to_string);
+            *values.entry(name).or_insert(0.0) += value;
+        }
+        FunctionValues {
+            total: self.total,
+            values,
+        }
+    }
+}
+
+struct Regression {
+    function: String,
+    golden: f64,
+    new: f64,
+}
+
+impl Regression {
+    /// Growth in percent of the golden value, or `None` for a new function
+    fn change(&self) -> Option<f64> {
+        (self.golden > 0.0).then(|| (self.new - self.golden) / self.golden * 100.0)
+    }
+}
+
+/// Number of functions compared and those that grew past the threshold,
+/// worst first (new functions before all others)
+fn compare(
+    golden: &FunctionValues,
+    new: &FunctionValues,
+    max_regression: f64,
+    min_share: f64,
+) -> (usize, Vec<Regression>) {
+    let functions: BTreeSet<&String> = golden.values.keys().chain(new.values.keys()).collect();
+    let mut compared = 0;
+    let mut regressions = Vec::new();
+    for function in functions {
+        let golden_value = golden.values.get(function).copied().unwrap_or(0.0);
+        let new_value = new.values.get(function).copied().unwrap_or(0.0);
+        if golden.share(golden_value) < min_share && new.share(new_value) < min_share {
+            continue;
+        }
+        compared += 1;
+        let regression = Regression {
+            function: function.clone(),
+            golden: golden_value,
+            new: new_value,
+        };
+        if regression
+            .change()
+            .is_none_or(|change| change > max_regression)
+        {
+            regressions.push(regression);
+        }
+    }
+    regressions.sort_by(|a, b| {
+        let key = |r: &Regression| r.change().unwrap_or(f64::INFINITY);
+        key(b).total_cmp(&key(a))
+    });
+    (compared, regressions)
+}
+
+/// CPU share per function, as `rsprof diff` compares it
+fn load_cpu(file: &Path) -> Result<FunctionValues> {
+    let (total, functions) = load_sites(file, DiffMetric::Cpu, false)?;
+    Ok(FunctionValues {
+        total: if total > 0 { 100.0 } else { 0.0 },
+        values: functions
+            .into_iter()
+            .map(|(key, site)| (key.function, site.value))
+            .collect(),
+    })
+}
+
+/// Bytes allocated over the run per function (cumulative per location, so
+/// the largest value is the latest)
+fn load_alloc(file: &Path) -> Result<FunctionValues> {
+    // A --no-symbols recording is resolved in memory; the file stays as recorded
+    let mut conn = deferred::open_resolved(file)?;
+    // Keep the timeline ordered if the clock stepped back while recording
+    repair_clock_skew(&mut conn)?;
+
+    let mut stmt = conn.prepare(
+        "SELECT l.function, MAX(hs.alloc_bytes) FROM heap_samples hs
+         JOIN locations l ON l.id = hs.location_id
+         GROUP BY hs.location_id",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+    })?;
+    let mut functions = FunctionValues::default();
+    for row in rows {
+        let (function, bytes) = row?;
+        functions.total += bytes as f64;
+        *functions
+            .values
+            .entry(strip_hash_suffix(&function).to_string())
+            .or_insert(0.0) += bytes as f64;
+    }
+    Ok(functions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use crate::symbols::Location;
+    use std::path::PathBuf;
+
+    fn values(functions: &[(&str, f64)]) -> FunctionValues {
+        FunctionValues {
+            total: functions.iter().map(|(_, value)| value).sum(),
+            values: functions
+                .iter()
+                .map(|&(function, value)| (function.to_string(), value))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn compare_flags_growth_past_the_threshold() {
+        let golden = values(&[("parse", 50.0), ("render", 40.0), ("gone", 10.0)]);
+        let new = values(&[("parse", 54.0), ("render", 30.0), ("fresh", 16.0)]);
+
+        let (compared, regressions) = compare(&golden, &new, 10.0, 1.0);
+        assert_eq!(compared, 4);
+        // parse grew 8%, within tolerance; the function that disappeared is fine
+        let regressed: Vec<&str> = regressions.iter().map(|r| r.function.as_str()).collect();
+        assert_eq!(regressed, ["fresh"]);
+
+        let (_, regressions) = compare(&golden, &new, 5.0, 1.0);
+        let regressed: Vec<&str> = regressions.iter().map(|r| r.function.as_str()).collect();
+        assert_eq!(regressed, ["fresh", "parse"]);
+
+        // Under min_share in both profiles: noise, not compared
+        let (compared, regressions) = compare(&golden, &new, 10.0, 20.0);
+        assert_eq!(compared, 2);
+        assert!(regressions.is_empty());
+    }
+
+    /// Write a one-checkpoint profile with the given CPU samples per function
+    fn cpu_profile(name: &str, samples: &[(&str, u64)]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rsprof-{}-{}.db", name, std::process::id()));
+        let mut storage = Storage::create(&path).unwrap();
+        for &(function, count) in samples {
+            let location = Location {
+                file: format!("src/{}.rs", function),
+                line: 1,
+                column: 0,
+                function: function.to_string(),
+            };
+            storage.record_cpu_sample_count(0, &location, count);
+        }
+        storage.flush_checkpoint_at(1000).unwrap();
+        path
+    }
+
+    fn remove_profile(path: &Path) {
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", path.display(), suffix)).ok();
+        }
+    }
+
+    #[test]
+    fn regression_fails_the_assertion() {
+        let golden = cpu_profile("assert-golden", &[("parse", 500), ("render", 500)]);
+        let within = cpu_profile("assert-within", &[("parse", 520), ("render", 480)]);
+        let regressed = cpu_profile("assert-regressed", &[("parse", 700), ("render", 300)]);
+
+        let assert =
+            |profile: &Path| run(profile, &golden, 10.0, 1.0, None, NameOptions::default());
+        let within_result = assert(&within);
+        let regressed_result = assert(&regressed);
+        for path in [&golden, &within, &regressed] {
+            remove_profile(path);
+        }
+
+        assert!(within_result.is_ok());
+        assert!(matches!(regressed_result, Err(Error::Regression(1))));
+    }
+}
@@ -0,0 +1,562 @@
+use super::query::json_string;
+use super::top::{format_bytes, format_function, format_location};
+use crate::cli::{DiffMetric, NameOptions};
+use crate::error::{Error, Result};
+use crate::storage::{query_top_cpu, query_top_heap_live, repair_clock_skew};
+use crate::symbols::{deferred, split_symbol_hash};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Furthest apart (in lines of the same file) a removed and an added
+/// function can be to be suggested as a rename
+const SUGGEST_MAX_LINES: u32 = 30;
+
+/// Cells on each side of a diff bar's axis
+const BAR_HALF_WIDTH: usize = 6;
+
+/// Run the diff command
+///
+/// Compares CPU share or live heap bytes per function (or per source line
+/// with `lines`) between two profiles. Functions are matched by name
+/// (without the `::h<hash>` suffix, which changes with every build); a rename
+/// map lets functions renamed in between still line up.
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    base: &Path,
+    new: &Path,
+    metric: DiffMetric,
+    lines: bool,
+    limit: usize,
+    rename_map: Option<&Path>,
+    suggest_renames: bool,
+    json: bool,
+    names: NameOptions,
+) -> Result<()> {
+    let renames = match rename_map {
+        Some(path) => RenameMap::load(path)?,
+        None => RenameMap::default(),
+    };
+    let (base_total, base_sites) = load_sites(base, metric, lines)?;
+    let (new_total, new_sites) = load_sites(new, metric, lines)?;
+
+    let (base_by_key, renamed) = apply_renames(base_sites, &renames);
+    let rows = diff_rows(&base_by_key, &new_sites);
+    let shown = &rows[..rows.len().min(limit)];
+
+    if json {
+        print_json(base, new, metric, base_total, new_total, shown);
+        return Ok(());
+    }
+
+    println!(
+        "# base: {} ({})",
+        base.display(),
+        metric.format_total(base_total)
+    );
+    println!(
+        "# new:  {} ({})",
+        new.display(),
+        metric.format_total(new_total)
+    );
+    if let Some(path) = rename_map {
+        println!("# {} function(s) renamed by {}", renamed, path.display());
+    }
+    println!();
+    println!(
+        "{:>7}  {:>7}  {:>7}  {:^width$}  FUNCTION",
+        "BASE",
+        "NEW",
+        "DELTA",
+        "CHANGE",
+        width = BAR_HALF_WIDTH * 2 + 1
+    );
+    println!("{}", "-".repeat(90));
+    let max_delta = shown.iter().map(|r| r.delta().abs()).fold(0.0, f64::max);
+    for row in shown {
+        let mut function = format_function(&row.function, names);
+        if let Some(old) = &row.renamed_from {
+            function = format!("{} -> {}", format_function(old, names), function);
+        }
+        if lines {
+            function = format!("{}  {}", function, format_location(&row.file, row.line));
+        }
+        println!(
+            "{:>7}  {:>7}  {:>7}  {}  {}",
+            metric.format(row.base_value),
+            metric.format(row.new_value),
+            metric.format_delta(row.delta()),
+            diff_bar(row.delta(), max_delta),
+            function
+        );
+    }
+
+    if suggest_renames {
+        let removed: Vec<(&String, &Site)> = base_by_key
+            .iter()
+            .filter(|(key, _)| !new_sites.contains_key(*key))
+            .map(|(key, (_, site))| (&key.function, site))
+            .collect();
+        let added: Vec<(&String, &Site)> = new_sites
+            .iter()
+            .filter(|(key, _)| !base_by_key.contains_key(*key))
+            .map(|(key, site)| (&key.function, site))
+            .collect();
+        print_suggestions(&suggest(&removed, &added));
+    }
+
+    Ok(())
+}
+
+impl DiffMetric {
+    fn name(self) -> &'static str {
+        match self {
+            DiffMetric::Cpu => "cpu",
+            DiffMetric::Heap => "heap",
+        }
+    }
+
+    fn format_total(self, total: u64) -> String {
+        match self {
+            DiffMetric::Cpu => format!("{} samples", total),
+            DiffMetric::Heap => format!("{} live", format_bytes(total as i64)),
+        }
+    }
+
+    fn format(self, value: Option<f64>) -> String {
+        match self {
+            DiffMetric::Cpu => format_percent(value),
+            DiffMetric::Heap => value.map_or_else(|| "-".to_string(), |b| format_bytes(b as i64)),
+        }
+    }
+
+    fn format_delta(self, delta: f64) -> String {
+        match self {
+            DiffMetric::Cpu => format!("{:+.1}%", delta),
+            DiffMetric::Heap if delta > 0.0 => format!("+{}", format_bytes(delta as i64)),
+            DiffMetric::Heap => format_bytes(delta as i64),
+        }
+    }
+}
+
+/// What a function (or source line with --lines) is matched on
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub(crate) struct SiteKey {
+    pub(crate) function: String,
+    /// File and line, when comparing source lines
+    line: Option<(String, u32)>,
+}
+
+/// CPU share or live bytes of one function, summed over its lines
+#[derive(Default)]
+pub(crate) struct Site {
+    file: String,
+    /// First sampled line, a stand-in for where the function starts
+    line: u32,
+    pub(crate) value: f64,
+}
+
+impl Site {
+    fn merge(&mut self, other: &Site) {
+        if self.file.is_empty() || (other.file == self.file && other.line < self.line) {
+            self.file = other.file.clone();
+            self.line = other.line;
+        }
+        self.value += other.value;
+    }
+}
+
+struct DiffRow {
+    function: String,
+    file: String,
+    line: u32,
+    renamed_from: Option<String>,
+    base_value: Option<f64>,
+    new_value: Option<f64>,
+}
+
+impl DiffRow {
+    fn delta(&self) -> f64 {
+        self.new_value.unwrap_or(0.0) - self.base_value.unwrap_or(0.0)
+    }
+}
+
+/// Rewrite base profile sites to the names they have in the new profile
+///
+/// Returns the sites (each with its name in the base profile) and how many
+/// were renamed.
+fn apply_renames(
+    base_sites: HashMap<SiteKey, Site>,
+    renames: &RenameMap,
+) -> (HashMap<SiteKey, (String, Site)>, usize) {
+    let mut renamed = 0;
+    let mut base_by_key: HashMap<SiteKey, (String, Site)> = HashMap::new();
+    for (key, site) in base_sites {
+        let name = key.function.clone();
+        let key = match renames.get(&name) {
+            Some(to) => {
+                renamed += 1;
+                SiteKey {
+                    function: to.to_string(),
+                    ..key
+                }
+            }
+            None => key,
+        };
+        let slot = base_by_key
+            .entry(key)
+            .or_insert_with(|| (name, Site::default()));
+        slot.1.merge(&site);
+    }
+    (base_by_key, renamed)
+}
+
+/// One row per site in either profile, largest change first
+fn diff_rows(
+    base_by_key: &HashMap<SiteKey, (String, Site)>,
+    new_sites: &HashMap<SiteKey, Site>,
+) -> Vec<DiffRow> {
+    let mut rows: Vec<DiffRow> = Vec::new();
+    for (key, (old_name, site)) in base_by_key {
+        let new_site = new_sites.get(key);
+        rows.push(DiffRow {
+            function: key.function.clone(),
+            file: site.file.clone(),
+            line: site.line,
+            renamed_from: (*old_name != key.function).then(|| old_name.clone()),
+            base_value: Some(site.value),
+            new_value: new_site.map(|s| s.value),
+        });
+    }
+    for (key, site) in new_sites {
+        if !base_by_key.contains_key(key) {
+            rows.push(DiffRow {
+                function: key.function.clone(),
+                file: site.file.clone(),
+                line: site.line,
+                renamed_from: None,
+                base_value: None,
+                new_value: Some(site.value),
+            });
+        }
+    }
+    rows.sort_by(|a, b| {
+        b.delta()
+            .abs()
+            .total_cmp(&a.delta().abs())
+            .then_with(|| a.function.cmp(&b.function))
+            .then_with(|| (&a.file, a.line).cmp(&(&b.file, b.line)))
+    });
+    rows
+}
+
+fn print_json(
+    base: &Path,
+    new: &Path,
+    metric: DiffMetric,
+    base_total: u64,
+    new_total: u64,
+    rows: &[DiffRow],
+) {
+    let value = |v: Option<f64>| match (metric, v) {
+        (_, None) => "null".to_string(),
+        (DiffMetric::Cpu, Some(percent)) => format!("{:.1}", percent),
+        (DiffMetric::Heap, Some(bytes)) => format!("{}", bytes as i64),
+    };
+
+    println!("{{");
+    println!("  \"base\": {},", json_string(&base.display().to_string()));
+    println!("  \"new\": {},", json_string(&new.display().to_string()));
+    println!("  \"metric\": \"{}\",", metric.name());
+    println!("  \"base_total\": {},", base_total);
+    println!("  \"new_total\": {},", new_total);
+    println!("  \"entries\": [");
+    for (i, row) in rows.iter().enumerate() {
+        let comma = if i < rows.len() - 1 { "," } else { "" };
+        let status = match (row.base_value, row.new_value) {
+            (None, _) => "new",
+            (_, None) => "removed",
+            _ if row.delta() == 0.0 => "unchanged",
+            _ => "changed",
+        };
+        println!(
+            "    {{ \"base\": {}, \"new\": {}, \"delta\": {}, \"status\": \"{}\", \"file\": {}, \"line\": {}, \"function\": {}, \"renamed_from\": {} }}{}",
+            value(row.base_value),
+            value(row.new_value),
+            value(Some(row.delta())),
+            status,
+            json_string(&row.file),
+            row.line,
+            json_string(&row.function),
+            row.renamed_from
+                .as_deref()
+                .map_or_else(|| "null".to_string(), json_string),
+            comma
+        );
+    }
+    println!("  ]");
+    println!("}}");
+}
+
+/// Function renames between two profiles (`--rename-map`)
+///
+/// One `old_name = new_name` per line; blank lines and `#` comments are
+/// skipped. Names are compared without their `::h<hash>` suffix.
+#[derive(Default)]
+pub struct RenameMap {
+    renames: HashMap<String, String>,
+}
+
+impl RenameMap {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Self::parse(&text).map_err(|(line, message)| {
+            Error::InvalidArgument(format!("{}:{}: {}", path.display(), line, message))
+        })
+    }
+
+    /// Parse a rename map, or return the 1-based line number of the first bad line
+    pub fn parse(text: &str) -> std::result::Result<Self, (usize, String)> {
+        let mut renames = HashMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((old, new)) = line.split_once('=') else {
+                return Err((i + 1, "expected `old_name = new_name`".to_string()));
+            };
+            let (old, new) = (strip_hash(old.trim()), strip_hash(new.trim()));
+            if old.is_empty() || new.is_empty() {
+                return Err((i + 1, "expected `old_name = new_name`".to_string()));
+            }
+            renames.insert(old.to_string(), new.to_string());
+        }
+        Ok(RenameMap { renames })
+    }
+
+    /// New name of a function from the base profile, if it was renamed
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.renames.get(name).map(String::as_str)
+    }
+}
+
+/// Load CPU share or live bytes per function (hash suffix removed), or per
+/// source line with `lines`, and the profile's total (samples or live bytes)
+pub(crate) fn load_sites(
+    file: &Path,
+    metric: DiffMetric,
+    lines: bool,
+) -> Result<(u64, HashMap<SiteKey, Site>)> {
+    // A --no-symbols recording is resolved in memory; the file stays as recorded
+    let mut conn = deferred::open_resolved(file)?;
+    // Keep the timeline ordered if the clock stepped back while recording
+    repair_clock_skew(&mut conn)?;
+
+    let (total, entries): (u64, Vec<(String, String, u32, f64)>) = match metric {
+        DiffMetric::Cpu => {
+            let entries = query_top_cpu(&conn, i64::MAX as usize, 0.0)?;
+            let total = entries.iter().map(|e| e.total_samples).sum();
+            let entries = entries
+                .into_iter()
+                .map(|e| (e.function, e.file, e.line, e.total_percent))
+                .collect();
+            (total, entries)
+        }
+        DiffMetric::Heap => {
+            let entries = query_top_heap_live(&conn, i64::MAX as usize)?;
+            let total = entries.iter().map(|e| e.live_bytes.max(0) as u64).sum();
+            let entries = entries
+                .into_iter()
+                .map(|e| (e.function, e.file, e.line, e.live_bytes as f64))
+                .collect();
+            (total, entries)
+        }
+    };
+    let mut sites: HashMap<SiteKey, Site> = HashMap::new();
+    for (function, file, line, value) in entries {
+        let key = SiteKey {
+            function: strip_hash(&function).to_string(),
+            line: lines.then(|| (file.clone(), line)),
+        };
+        sites
+            .entry(key)
+            .or_default()
+            .merge(&Site { file, line, value });
+    }
+    Ok((total, sites))
+}
+
+fn strip_hash(name: &str) -> &str {
+    split_symbol_hash(name).0
+}
+
+/// Rename candidate: a function that disappeared next to one that appeared
+struct Suggestion<'a> {
+    old: &'a str,
+    new: &'a str,
+    file: &'a str,
+    old_line: u32,
+    new_line: u32,
+}
+
+/// Pair functions only in the base with functions only in the new profile
+/// that sit in the same file at nearby lines, closest pairs first
+fn suggest<'a>(
+    removed: &[(&'a String, &'a Site)],
+    added: &[(&'a String, &'a Site)],
+) -> Vec<Suggestion<'a>> {
+    let mut candidates: Vec<(u32, usize, usize)> = Vec::new();
+    for (i, (old_name, old)) in removed.iter().enumerate() {
+        for (j, (new_name, new)) in added.iter().enumerate() {
+            // With --lines, a function that moved is not a rename
+            if old_name == new_name {
+                continue;
+            }
+            let distance = old.line.abs_diff(new.line);
+            if old.file == new.file && !old.file.is_empty() && distance <= SUGGEST_MAX_LINES {
+                candidates.push((distance, i, j));
+            }
+        }
+    }
+    candidates.sort();
+
+    let mut used_old = HashSet::new();
+    let mut used_new = HashSet::new();
+    let mut suggestions = Vec::new();
+    for (_, i, j) in candidates {
+        if used_old.contains(&i) || used_new.contains(&j) {
+            continue;
+        }
+        used_old.insert(i);
+        used_new.insert(j);
+        let ((old, old_cpu), (new, new_cpu)) = (removed[i], added[j]);
+        suggestions.push(Suggestion {
+            old,
+            new,
+            file: &old_cpu.file,
+            old_line: old_cpu.line,
+            new_line: new_cpu.line,
+        });
+    }
+    suggestions.sort_by(|a, b| (a.file, a.old_line).cmp(&(b.file, b.old_line)));
+    suggestions
+}
+
+fn print_suggestions(suggestions: &[Suggestion]) {
+    println!();
+    if suggestions.is_empty() {
+        println!(
+            "# No likely renames (same file, within {} lines)",
+            SUGGEST_MAX_LINES
+        );
+        return;
+    }
+    println!("# Likely renames (same file, nearby lines); save as a --rename-map file:");
+    for s in suggestions {
+        println!(
+            "{} = {}  # {} -> line {}",
+            s.old,
+            s.new,
+            format_location(s.file, s.old_line),
+            s.new_line
+        );
+    }
+}
+
+/// Bar for a change in CPU share, scaled to the largest change shown
+///
+/// Regressions grow `+` to the right of the axis, improvements `-` to the
+/// left, so direction and size read at a glance in any terminal.
+fn diff_bar(delta: f64, max_delta: f64) -> String {
+    let cells = if max_delta > 0.0 {
+        ((delta.abs() / max_delta * BAR_HALF_WIDTH as f64).round() as usize)
+            .clamp(1, BAR_HALF_WIDTH)
+    } else {
+        0
+    };
+    // Changes that round to 0.0% get no bar
+    let cells = if delta.abs() < 0.05 { 0 } else { cells };
+    let (left, right) = if delta < 0.0 {
+        ("-".repeat(cells), String::new())
+    } else {
+        (String::new(), "+".repeat(cells))
+    };
+    format!("{:>half$}|{:<half$}", left, right, half = BAR_HALF_WIDTH)
+}
+
+fn format_percent(percent: Option<f64>) -> String {
+    percent.map_or_else(|| "-".to_string(), |p| format!("{:.1}%", p))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sites(entries: &[(&str, &str, u32, f64)]) -> HashMap<SiteKey, Site> {
+        let mut sites: HashMap<SiteKey, Site> = HashMap::new();
+        for &(function, file, line, value) in entries {
+            let key = SiteKey {
+                function: function.to_string(),
+                line: None,
+            };
+            sites.entry(key).or_default().merge(&Site {
+                file: file.to_string(),
+                line,
+                value,
+            });
+        }
+        sites
+    }
+
+    #[test]
+    fn rename_map_aligns_a_renamed_function_into_one_row() {
+        let base = || {
+            sites(&[
+                ("app::parse_request", "src/http.rs", 10, 60.0),
+                ("app::respond", "src/http.rs", 80, 40.0),
+            ])
+        };
+        let new = sites(&[
+            ("app::parse_headers", "src/http.rs", 12, 45.0),
+            ("app::respond", "src/http.rs", 80, 55.0),
+        ]);
+
+        // Without a map the old and new names are separate rows
+        let (base_by_key, renamed) = apply_renames(base(), &RenameMap::default());
+        assert_eq!(renamed, 0);
+        assert_eq!(diff_rows(&base_by_key, &new).len(), 3);
+
+        let renames =
+            RenameMap::parse("# refactor\napp::parse_request = app::parse_headers\n").unwrap();
+        let (base_by_key, renamed) = apply_renames(base(), &renames);
+        assert_eq!(renamed, 1);
+        let rows = diff_rows(&base_by_key, &new);
+        assert_eq!(rows.len(), 2);
+        let row = rows
+            .iter()
+            .find(|r| r.function == "app::parse_headers")
+            .unwrap();
+        assert_eq!(row.renamed_from.as_deref(), Some("app::parse_request"));
+        assert_eq!(row.base_value, Some(60.0));
+        assert_eq!(row.new_value, Some(45.0));
+        assert_eq!(row.delta(), -15.0);
+
+        assert_eq!(RenameMap::parse("a = b\nno separator").err().unwrap().0, 2);
+    }
+
+    #[test]
+    fn larger_changes_get_longer_bars() {
+        let bar = |delta| diff_bar(delta, 12.0);
+        let count = |bar: &str, c| bar.chars().filter(|&x| x == c).count();
+
+        // Regressions grow right of the axis, improvements left
+        assert_eq!(bar(12.0), "      |++++++");
+        assert_eq!(bar(2.0), "      |+     ");
+        assert_eq!(bar(-6.0), "   ---|      ");
+        assert!(count(&bar(12.0), '+') > count(&bar(4.0), '+'));
+        assert!(count(&bar(4.0), '+') > count(&bar(0.5), '+'));
+        // Too small to show as a change at all
+        assert_eq!(bar(0.01), "      |      ");
+        assert_eq!(diff_bar(0.0, 0.0), "      |      ");
+    }
+}
@@ -0,0 +1,560 @@
+use super::query::json_string;
+use crate::cli::{ExportFormat, ExportMetric};
+use crate::error::{Error, Result};
+use crate::storage::{
+    get_meta, has_table, query_cpu_freq_hz, query_heap_stacks, repair_clock_skew,
+};
+use crate::symbols::{deferred, split_symbol_hash, strip_hash_suffix};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use rusqlite::Connection;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{IsTerminal, Write};
+use std::path::Path;
+
+/// Run the export command
+///
+/// Writes the profile to `output`, or stdout (unless it is a terminal and
+/// the format is binary).
+pub fn run(
+    file: &Path,
+    format: ExportFormat,
+    metric: ExportMetric,
+    output: Option<&Path>,
+) -> Result<()> {
+    if format == ExportFormat::Pprof && output.is_none() && std::io::stdout().is_terminal() {
+        return Err(Error::InvalidArgument(
+            "refusing to write a binary profile to the terminal; use -o or a pipe".to_string(),
+        ));
+    }
+
+    // A --no-symbols recording is resolved in memory; the file stays as recorded
+    let mut conn = deferred::open_resolved(file)?;
+    // Keep the timeline ordered if the clock stepped back while recording
+    repair_clock_skew(&mut conn)?;
+
+    let data = match format {
+        ExportFormat::Pprof => {
+            let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+            gz.write_all(&encode_pprof(&conn)?)?;
+            gz.finish()?
+        }
+        ExportFormat::Speedscope => encode_speedscope(&conn, file)?.into_bytes(),
+        ExportFormat::Folded => match metric {
+            ExportMetric::Cpu => fold_cpu(&conn)?,
+            ExportMetric::Heap => fold_heap(&conn, HeapValue::Live)?,
+            ExportMetric::Alloc => fold_heap(&conn, HeapValue::Allocated)?,
+        }
+        .into_bytes(),
+    };
+
+    match output {
+        Some(path) => {
+            std::fs::write(path, data)?;
+            eprintln!("Wrote {}", path.display());
+        }
+        None => std::io::stdout().write_all(&data)?,
+    }
+    Ok(())
+}
+
+/// Sample types, in the order of every sample's values
+const SAMPLE_TYPES: [(&str, &str); 4] = [
+    ("samples", "count"),
+    ("cpu", "nanoseconds"),
+    ("inuse_space", "bytes"),
+    ("alloc_space", "bytes"),
+];
+
+/// Build an uncompressed `profile.proto` message
+///
+/// CPU samples are stored per location, so each becomes a one-frame sample;
+/// heap samples come from `heap_samples`.
+fn encode_pprof(conn: &Connection) -> Result<Vec<u8>> {
+    let mut strings = StringTable::default();
+    for (ty, unit) in SAMPLE_TYPES {
+        strings.index(ty);
+        strings.index(unit);
+    }
+
+    // Every location becomes a pprof location with one line, and each
+    // distinct (function, file) a pprof function
+    let locations = query_locations(conn)?;
+
+    let mut profile = Vec::new();
+    for (ty, unit) in SAMPLE_TYPES {
+        let mut value_type = Vec::new();
+        put_varint_field(&mut value_type, 1, strings.index(ty));
+        put_varint_field(&mut value_type, 2, strings.index(unit));
+        put_bytes_field(&mut profile, 1, &value_type);
+    }
+
+    // CPU: samples per location, and their time at the sampling frequency
+    let period_ns = query_cpu_freq_hz(conn).map_or(0, |freq| (1e9 / freq) as i64);
+    let cpu: Vec<(i64, i64)> = {
+        let mut stmt =
+            conn.prepare("SELECT location_id, SUM(count) FROM cpu_samples GROUP BY location_id")?;
+        stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?
+    };
+    let has_cpu = !cpu.is_empty();
+    for (location_id, count) in cpu {
+        put_sample(
+            &mut profile,
+            &[location_id],
+            [count, count * period_ns, 0, 0],
+        );
+    }
+
+    // Heap: live bytes at the end of the run and bytes allocated over it
+    for (frames, live, alloc) in heap_samples(conn, &locations)? {
+        put_sample(&mut profile, &frames, [0, 0, live, alloc]);
+    }
+
+    // One mapping for the executable; rsprof already symbolized every
+    // location, so pprof needn't look at the binary again
+    let exe_path = get_meta(conn, "exe_path")?.unwrap_or_default();
+    let mut mapping = Vec::new();
+    put_varint_field(&mut mapping, 1, 1);
+    put_varint_field(&mut mapping, 5, strings.index(&exe_path));
+    if let Some(build_id) = get_meta(conn, "build_id")? {
+        put_varint_field(&mut mapping, 6, strings.index(&build_id));
+    }
+    put_varint_field(&mut mapping, 7, 1);
+    put_varint_field(&mut mapping, 8, 1);
+    put_varint_field(&mut mapping, 9, 1);
+    put_bytes_field(&mut profile, 3, &mapping);
+
+    let mut function_ids: HashMap<(&str, &str), u64> = HashMap::new();
+    let mut functions = Vec::new();
+    for (id, file, line, function) in &locations {
+        let next_id = function_ids.len() as u64 + 1;
+        let function_id = *function_ids
+            .entry((function.as_str(), file.as_str()))
+            .or_insert_with(|| {
+                let mut message = Vec::new();
+                put_varint_field(&mut message, 1, next_id);
+                put_varint_field(
+                    &mut message,
+                    2,
+                    strings.index(split_symbol_hash(function).0),
+                );
+                put_varint_field(&mut message, 3, strings.index(function));
+                put_varint_field(&mut message, 4, strings.index(file));
+                functions.push(message);
+                next_id
+            });
+
+        let mut line_message = Vec::new();
+        put_varint_field(&mut line_message, 1, function_id);
+        put_varint_field(&mut line_message, 2, *line as u64);
+        let mut location = Vec::new();
+        put_varint_field(&mut location, 1, *id as u64);
+        put_varint_field(&mut location, 2, 1);
+        put_bytes_field(&mut location, 4, &line_message);
+        put_bytes_field(&mut profile, 4, &location);
+    }
+    for function in functions {
+        put_bytes_field(&mut profile, 5, &function);
+    }
+
+    let start_ns = get_meta(conn, "start_time")?
+        .and_then(|v| chrono::DateTime::parse_from_rfc3339(&v).ok())
+        .and_then(|t| t.timestamp_nanos_opt());
+    if let Some(start_ns) = start_ns {
+        put_varint_field(&mut profile, 9, start_ns as u64);
+    }
+    let duration_ms: i64 = conn.query_row(
+        "SELECT COALESCE(MAX(timestamp_ms), 0) FROM checkpoints",
+        [],
+        |row| row.get(0),
+    )?;
+    put_varint_field(&mut profile, 10, (duration_ms * 1_000_000) as u64);
+
+    // Period: one CPU sample's worth of time
+    let mut period_type = Vec::new();
+    put_varint_field(&mut period_type, 1, strings.index("cpu"));
+    put_varint_field(&mut period_type, 2, strings.index("nanoseconds"));
+    put_bytes_field(&mut profile, 11, &period_type);
+    put_varint_field(&mut profile, 12, period_ns as u64);
+
+    // Open on CPU time, or on live memory for heap-only profiles
+    let default_type = if has_cpu { "cpu" } else { "inuse_space" };
+    put_varint_field(&mut profile, 14, strings.index(default_type));
+
+    // Last: every string above has been interned
+    for s in &strings.strings {
+        put_bytes_field(&mut profile, 6, s.as_bytes());
+    }
+
+    Ok(profile)
+}
+
+/// Build a speedscope file: "sampled" profiles over one shared frame table
+///
+/// CPU samples are kept per process (`--follow-forks`) rather than per
+/// thread, so there is one CPU profile per process, or one for the whole
+/// recording. Its samples run in checkpoint order, each interval padded
+/// with an empty (idle) sample up to its checkpoint's timestamp, so the time
+/// axis follows the recording. Heap profiles weigh call stacks by live and
+/// by allocated bytes.
+fn encode_speedscope(conn: &Connection, file: &Path) -> Result<String> {
+    let locations = query_locations(conn)?;
+    let frame_index: HashMap<i64, usize> = locations
+        .iter()
+        .enumerate()
+        .map(|(index, (id, ..))| (*id, index))
+        .collect();
+    let frames: Vec<String> = locations
+        .iter()
+        .map(|(_, file, line, function)| {
+            format!(
+                "{{\"name\":{},\"file\":{},\"line\":{}}}",
+                json_string(strip_hash_suffix(function)),
+                json_string(file),
+                line
+            )
+        })
+        .collect();
+
+    let mut profiles = Vec::new();
+
+    // CPU: per process if the recording followed forks
+    let period_ns = query_cpu_freq_hz(conn).map(|freq| 1e9 / freq);
+    let checkpoints: Vec<(i64, i64)> = {
+        let mut stmt =
+            conn.prepare("SELECT id, timestamp_ms FROM checkpoints ORDER BY timestamp_ms")?;
+        stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?
+    };
+    let has_processes = has_table(conn, "cpu_process_samples")?
+        && conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM cpu_process_samples)",
+            [],
+            |row| row.get(0),
+        )?;
+    let sql = if has_processes {
+        "SELECT process_id, checkpoint_id, location_id, count FROM cpu_process_samples"
+    } else {
+        "SELECT 0, checkpoint_id, location_id, count FROM cpu_samples"
+    };
+    let mut cpu: BTreeMap<u32, HashMap<i64, Vec<(i64, i64)>>> = BTreeMap::new();
+    {
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?;
+        for row in rows {
+            let (process_id, checkpoint_id, location_id, count) = row?;
+            cpu.entry(process_id)
+                .or_default()
+                .entry(checkpoint_id)
+                .or_default()
+                .push((location_id, count));
+        }
+    }
+    for (process_id, mut by_checkpoint) in cpu {
+        let mut samples = Vec::new();
+        let mut weights = Vec::new();
+        let mut previous_ms = 0;
+        for &(checkpoint_id, timestamp_ms) in &checkpoints {
+            let mut interval_ns = 0.0;
+            if let Some(mut counts) = by_checkpoint.remove(&checkpoint_id) {
+                counts.sort_unstable();
+                for (location_id, count) in counts {
+                    let Some(frame) = frame_index.get(&location_id) else {
+                        continue;
+                    };
+                    let weight = count as f64 * period_ns.unwrap_or(1.0);
+                    samples.push(format!("[{frame}]"));
+                    weights.push(weight);
+                    interval_ns += weight;
+                }
+            }
+            // Without a sampling frequency there is no time to pad with
+            let idle_ns = (timestamp_ms - previous_ms) as f64 * 1e6 - interval_ns;
+            if period_ns.is_some() && idle_ns > 0.0 {
+                samples.push("[]".to_string());
+                weights.push(idle_ns);
+            }
+            previous_ms = timestamp_ms;
+        }
+        let name = if has_processes {
+            format!("CPU: PID {process_id}")
+        } else {
+            "CPU".to_string()
+        };
+        let unit = if period_ns.is_some() {
+            "nanoseconds"
+        } else {
+            "none"
+        };
+        profiles.push(sampled_profile(&name, unit, &samples, &weights));
+    }
+
+    // Heap: call stacks by live bytes at the end and by bytes allocated
+    let heap = heap_samples(conn, &locations)?;
+    for (name, live) in [("Heap: live bytes", true), ("Heap: allocated bytes", false)] {
+        let mut samples = Vec::new();
+        let mut weights = Vec::new();
+        for (stack, live_bytes, alloc_bytes) in &heap {
+            let bytes = if live { *live_bytes } else { *alloc_bytes };
+            if bytes <= 0 {
+                continue;
+            }
+            let indexes: Option<Vec<String>> = stack
+                .iter()
+                .rev()
+                .map(|id| frame_index.get(id).map(usize::to_string))
+                .collect();
+            if let Some(indexes) = indexes {
+                samples.push(format!("[{}]", indexes.join(",")));
+                weights.push(bytes as f64);
+            }
+        }
+        if !samples.is_empty() {
+            profiles.push(sampled_profile(name, "bytes", &samples, &weights));
+        }
+    }
+
+    let name = file.file_name().map_or_else(
+        || file.display().to_string(),
+        |n| n.to_string_lossy().to_string(),
+    );
+    Ok(format!(
+        "{{\"$schema\":\"https://www.speedscope.app/file-format-schema.json\",\
+         \"name\":{},\"exporter\":{},\"activeProfileIndex\":0,\
+         \"shared\":{{\"frames\":[{}]}},\"profiles\":[{}]}}\n",
+        json_string(&name),
+        json_string(&format!("rsprof {}", env!("CARGO_PKG_VERSION"))),
+        frames.join(","),
+        profiles.join(",")
+    ))
+}
+
+/// One speedscope "sampled" profile; stacks are frame indexes, root first
+fn sampled_profile(name: &str, unit: &str, samples: &[String], weights: &[f64]) -> String {
+    let end: f64 = weights.iter().sum();
+    let weights: Vec<String> = weights.iter().map(|w| format!("{}", w.round())).collect();
+    format!(
+        "{{\"type\":\"sampled\",\"name\":{},\"unit\":\"{}\",\"startValue\":0,\
+         \"endValue\":{},\"samples\":[{}],\"weights\":[{}]}}",
+        json_string(name),
+        unit,
+        end.round(),
+        samples.join(","),
+        weights.join(",")
+    )
+}
+
+/// Heap samples as (location ids leaf first, live bytes, allocated bytes)
+///
+/// Live bytes are taken at the last checkpoint, allocated bytes over the run
+/// (cumulative, so the largest value is the latest). Full call stacks are
+/// used where the profile has them (schema v5); the rest of each location's
+/// heap (allocations recorded without a stack, or older profiles) becomes a
+/// one-frame sample.
+fn heap_samples(
+    conn: &Connection,
+    locations: &[(i64, String, i64, String)],
+) -> Result<Vec<(Vec<i64>, i64, i64)>> {
+    let location_ids: HashMap<(&str, i64, &str), i64> = locations
+        .iter()
+        .map(|(id, file, line, function)| ((file.as_str(), *line, function.as_str()), *id))
+        .collect();
+
+    let last_checkpoint: i64 = conn
+        .query_row(
+            "SELECT id FROM checkpoints ORDER BY timestamp_ms DESC LIMIT 1",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap_or(0);
+    let mut heap: HashMap<i64, (i64, i64)> = {
+        let mut stmt = conn.prepare(
+            "SELECT location_id,
+                    COALESCE(MAX(CASE WHEN checkpoint_id = ?1 THEN live_bytes END), 0),
+                    MAX(alloc_bytes)
+             FROM heap_samples GROUP BY location_id",
+        )?;
+        stmt.query_map([last_checkpoint], |row| {
+            Ok((row.get(0)?, (row.get(1)?, row.get(2)?)))
+        })?
+        .collect::<rusqlite::Result<_>>()?
+    };
+
+    let mut samples = Vec::new();
+    for stack in query_heap_stacks(conn, i64::MAX as usize)? {
+        let frames: Option<Vec<i64>> = stack
+            .frames
+            .iter()
+            .map(|frame| {
+                location_ids
+                    .get(&(
+                        frame.file.as_str(),
+                        frame.line as i64,
+                        frame.function.as_str(),
+                    ))
+                    .copied()
+            })
+            .collect();
+        let Some(frames) = frames else {
+            continue;
+        };
+        // Whatever the stacks don't cover stays on the location itself
+        if let Some((live, alloc)) = heap.get_mut(&frames[0]) {
+            *live -= stack.live_bytes;
+            *alloc -= stack.total_alloc_bytes;
+        }
+        samples.push((frames, stack.live_bytes, stack.total_alloc_bytes));
+    }
+    for (location_id, (live, alloc)) in heap {
+        if live > 0 || alloc > 0 {
+            samples.push((vec![location_id], live.max(0), alloc.max(0)));
+        }
+    }
+    Ok(samples)
+}
+
+/// Which heap value `fold_heap` weighs stacks by
+#[derive(Clone, Copy)]
+enum HeapValue {
+    Live,
+    Allocated,
+}
+
+/// Collapsed stacks of CPU samples (`frame;frame count`)
+///
+/// CPU samples are stored per location, so every line is a single frame.
+fn fold_cpu(conn: &Connection) -> Result<String> {
+    let mut stmt = conn.prepare(
+        "SELECT l.function, SUM(c.count) FROM cpu_samples c
+         JOIN locations l ON l.id = c.location_id
+         GROUP BY c.location_id",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+    })?;
+    let mut folded = BTreeMap::new();
+    for row in rows {
+        let (function, count) = row?;
+        *folded.entry(fold_frame(&function)).or_insert(0) += count;
+    }
+    Ok(collapse(folded))
+}
+
+/// Collapsed heap call stacks, root first, weighed by live or allocated bytes
+fn fold_heap(conn: &Connection, value: HeapValue) -> Result<String> {
+    let locations = query_locations(conn)?;
+    let functions: HashMap<i64, &str> = locations
+        .iter()
+        .map(|(id, _, _, function)| (*id, function.as_str()))
+        .collect();
+
+    let mut folded = BTreeMap::new();
+    for (frames, live, alloc) in heap_samples(conn, &locations)? {
+        let bytes = match value {
+            HeapValue::Live => live,
+            HeapValue::Allocated => alloc,
+        };
+        let stack = frames
+            .iter()
+            .rev()
+            .map(|id| fold_frame(functions.get(id).copied().unwrap_or("[unknown]")))
+            .collect::<Vec<_>>()
+            .join(";");
+        *folded.entry(stack).or_insert(0) += bytes;
+    }
+    Ok(collapse(folded))
+}
+
+/// Function name as a collapsed-stack frame: no hash suffix, and no `;`
+/// (array types like `[u8; 4]`) since that separates frames
+fn fold_frame(function: &str) -> String {
+    strip_hash_suffix(function).replace(';', ",")
+}
+
+/// One `stack value` line per stack with a positive value
+fn collapse(folded: BTreeMap<String, i64>) -> String {
+    folded
+        .into_iter()
+        .filter(|(_, value)| *value > 0)
+        .map(|(stack, value)| format!("{stack} {value}\n"))
+        .collect()
+}
+
+fn query_locations(conn: &Connection) -> Result<Vec<(i64, String, i64, String)>> {
+    let mut stmt = conn.prepare("SELECT id, file, line, function FROM locations")?;
+    let locations = stmt
+        .query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(locations)
+}
+
+/// pprof string table: index 0 must be the empty string
+struct StringTable {
+    strings: Vec<String>,
+    indexes: HashMap<String, u64>,
+}
+
+impl Default for StringTable {
+    fn default() -> Self {
+        StringTable {
+            strings: vec![String::new()],
+            indexes: HashMap::from([(String::new(), 0)]),
+        }
+    }
+}
+
+impl StringTable {
+    fn index(&mut self, s: &str) -> u64 {
+        if let Some(&index) = self.indexes.get(s) {
+            return index;
+        }
+        let index = self.strings.len() as u64;
+        self.strings.push(s.to_string());
+        self.indexes.insert(s.to_string(), index);
+        index
+    }
+}
+
+/// Sample with its stack (leaf first) and one value per sample type
+fn put_sample(profile: &mut Vec<u8>, location_ids: &[i64], values: [i64; 4]) {
+    let mut sample = Vec::new();
+    let mut packed = Vec::new();
+    for &id in location_ids {
+        put_varint(&mut packed, id as u64);
+    }
+    put_bytes_field(&mut sample, 1, &packed);
+    packed.clear();
+    for value in values {
+        put_varint(&mut packed, value as u64);
+    }
+    put_bytes_field(&mut sample, 2, &packed);
+    put_bytes_field(profile, 2, &sample);
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Varint field (wire type 0); zero is the default and left out
+fn put_varint_field(buf: &mut Vec<u8>, field: u32, value: u64) {
+    if value != 0 {
+        put_varint(buf, (field as u64) << 3);
+        put_varint(buf, value);
+    }
+}
+
+/// Length-delimited field (wire type 2): strings, messages and packed values
+fn put_bytes_field(buf: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    put_varint(buf, ((field as u64) << 3) | 2);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
@@ -0,0 +1,477 @@
+use crate::cpu::{PerfData, PerfDataSample, PerfMmap};
+use crate::error::{Error, Result};
+use crate::storage::Storage;
+use crate::symbols::attribution::is_internal_location;
+use crate::symbols::library::SymbolTable;
+use crate::symbols::{Attribution, Location, SymbolResolver, read_build_id};
+use object::{Object, ObjectSegment};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::time::Duration;
+
+/// Convert a `perf record` perf.data file into a profile database
+///
+/// Imports the CPU samples of one process: `pid`, or the one with the most
+/// samples. Each sample is attributed like a recorded one, walking its
+/// callchain (`perf record -g`) for the first user frame.
+pub fn run(
+    file: &Path,
+    output: Option<&Path>,
+    pid: Option<u32>,
+    interval: Duration,
+    attribution: Attribution,
+) -> Result<()> {
+    let db_path = match output {
+        Some(path) => path.to_path_buf(),
+        None => file.with_extension("db"),
+    };
+    if db_path.exists() {
+        return Err(Error::InvalidArgument(format!(
+            "{} already exists; choose another output path",
+            db_path.display()
+        )));
+    }
+
+    let perf = PerfData::open(file)?;
+    if perf.events > 1 {
+        eprintln!(
+            "Warning: {} records {} events; importing samples of the first only",
+            file.display(),
+            perf.events
+        );
+    }
+
+    let pid = match pid {
+        Some(pid) => pid,
+        None => busiest_pid(&perf.samples)
+            .ok_or_else(|| Error::InvalidArgument(format!("{} has no samples", file.display())))?,
+    };
+    let samples: Vec<&PerfDataSample> = perf.samples.iter().filter(|s| s.pid == pid).collect();
+    if samples.is_empty() {
+        return Err(Error::InvalidArgument(format!(
+            "{} has no samples for PID {}",
+            file.display(),
+            pid
+        )));
+    }
+
+    eprintln!(
+        "Importing {} (PID {}) -> {}",
+        file.display(),
+        pid,
+        db_path.display()
+    );
+
+    let mut modules = Modules::new(&perf, pid);
+    let mut storage = Storage::create(&db_path)?;
+    storage.set_meta("pid", &pid.to_string())?;
+    if let Some(name) = perf.comms.get(&pid) {
+        storage.set_meta("process_name", name)?;
+    }
+    if let Some(exe) = modules.exe_path() {
+        storage.set_meta("exe_path", &exe)?;
+    }
+    if let Some(freq) = perf.sample_freq {
+        storage.set_meta("cpu_freq_hz", &freq.to_string())?;
+    }
+    storage.set_meta("attribution", attribution.name())?;
+    storage.set_meta("imported_from", &file.display().to_string())?;
+
+    // Bucket samples into checkpoints by their perf timestamps
+    let start_ns = samples.iter().map(|s| s.time_ns).min().unwrap_or(0);
+    let interval_ns = interval.as_nanos().max(1) as u64;
+    let mut bucket = 0;
+    let mut checkpoints = 0;
+    let mut ordered = samples;
+    ordered.sort_by_key(|s| s.time_ns);
+    for sample in &ordered {
+        let sample_bucket = (sample.time_ns - start_ns) / interval_ns;
+        if sample_bucket != bucket {
+            storage.flush_checkpoint_at(((bucket + 1) * interval_ns / 1_000_000) as i64)?;
+            checkpoints += 1;
+            bucket = sample_bucket;
+        }
+        let location = modules.attribute(sample, attribution);
+        storage.record_cpu_sample_count(sample.ip, &location, 1);
+        storage.record_cpu_thread(sample.tid);
+    }
+    storage.flush_checkpoint_at(((bucket + 1) * interval_ns / 1_000_000) as i64)?;
+    checkpoints += 1;
+
+    eprintln!(
+        "Wrote {} checkpoints, {} samples ({} modules symbolicated{})",
+        checkpoints,
+        ordered.len(),
+        modules.symbolicated(),
+        if perf.lost > 0 {
+            format!(", {} samples lost while recording", perf.lost)
+        } else {
+            String::new()
+        }
+    );
+
+    Ok(())
+}
+
+/// PID with the most samples, ignoring the idle task
+fn busiest_pid(samples: &[PerfDataSample]) -> Option<u32> {
+    let mut counts: HashMap<u32, usize> = HashMap::new();
+    for sample in samples.iter().filter(|s| s.pid != 0) {
+        *counts.entry(sample.pid).or_insert(0) += 1;
+    }
+    counts
+        .into_iter()
+        .max_by_key(|&(pid, count)| (count, std::cmp::Reverse(pid)))
+        .map(|(pid, _)| pid)
+}
+
+/// A file mapped into the imported process
+struct Module {
+    start: u64,
+    end: u64,
+    filename: String,
+    /// Runtime address minus file virtual address
+    bias: u64,
+    symbols: Symbols,
+}
+
+/// How a module's addresses are symbolicated
+enum Symbols {
+    /// Not loaded yet
+    Pending,
+    /// Debug info: source lines
+    Dwarf(Box<SymbolResolver>),
+    /// Symbol table only: function names
+    Table(SymbolTable),
+    /// File missing or not the one perf recorded
+    None,
+}
+
+/// The imported process's mappings, symbolicated on first use
+struct Modules<'a> {
+    perf: &'a PerfData,
+    modules: Vec<Module>,
+}
+
+impl<'a> Modules<'a> {
+    fn new(perf: &'a PerfData, pid: u32) -> Self {
+        let mut modules: Vec<Module> = perf
+            .mmaps
+            .iter()
+            .filter(|m| m.pid == pid && !m.filename.starts_with('['))
+            .map(|m| Module {
+                start: m.start,
+                end: m.start + m.len,
+                filename: m.filename.clone(),
+                bias: load_bias(m).unwrap_or(m.start.wrapping_sub(m.pgoff)),
+                symbols: Symbols::Pending,
+            })
+            .collect();
+        modules.sort_by_key(|m| m.start);
+        Modules { perf, modules }
+    }
+
+    /// The executable: the first mapped file that isn't a shared library
+    fn exe_path(&self) -> Option<String> {
+        self.perf
+            .mmaps
+            .iter()
+            .filter(|m| self.modules.iter().any(|module| module.start == m.start))
+            .find(|m| !m.filename.contains(".so"))
+            .map(|m| m.filename.clone())
+    }
+
+    /// Number of modules with debug info
+    fn symbolicated(&self) -> usize {
+        self.modules
+            .iter()
+            .filter(|m| matches!(m.symbols, Symbols::Dwarf(_)))
+            .count()
+    }
+
+    /// Credit a sample to a frame of its callchain
+    fn attribute(&mut self, sample: &PerfDataSample, attribution: Attribution) -> Location {
+        if sample.callchain.is_empty() {
+            return self.resolve(sample.ip, sample.kernel);
+        }
+        // Return addresses point after their call; look up the call itself
+        let frames = sample
+            .callchain
+            .iter()
+            .enumerate()
+            .map(|(i, &addr)| if i == 0 { addr } else { addr.saturating_sub(1) });
+
+        let mut leaf = None;
+        for (i, addr) in frames.enumerate() {
+            let location = self.resolve(addr, sample.kernel && i == 0);
+            let found = match attribution {
+                Attribution::Leaf => location.function != "[unknown]",
+                Attribution::User => !is_internal_location(&location),
+            };
+            if found {
+                return location;
+            }
+            leaf.get_or_insert(location);
+        }
+        leaf.unwrap_or_else(Location::unknown)
+    }
+
+    fn resolve(&mut self, addr: u64, kernel: bool) -> Location {
+        let Some(module) = self
+            .modules
+            .iter_mut()
+            .find(|m| addr >= m.start && addr < m.end)
+        else {
+            let name = if kernel || addr >= 1 << 63 {
+                "[kernel]"
+            } else {
+                "[unknown]"
+            };
+            return Location {
+                file: name.to_string(),
+                line: 0,
+                column: 0,
+                function: name.to_string(),
+            };
+        };
+
+        if let Symbols::Pending = module.symbols {
+            module.symbols = load_symbols(&module.filename, module.bias, self.perf);
+        }
+        let library = format!("[{}]", file_name(&module.filename));
+        match &module.symbols {
+            Symbols::Dwarf(resolver) => resolver.resolve(addr),
+            Symbols::Table(symbols) => {
+                let function = symbols
+                    .lookup(addr.wrapping_sub(module.bias))
+                    .map_or_else(|| library.clone(), str::to_string);
+                Location {
+                    file: library,
+                    line: 0,
+                    column: 0,
+                    function,
+                }
+            }
+            Symbols::Pending | Symbols::None => Location {
+                file: library.clone(),
+                line: 0,
+                column: 0,
+                function: library,
+            },
+        }
+    }
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Load debug info or the symbol table of a mapped file
+///
+/// A file whose build-id differs from the one perf recorded has been rebuilt
+/// since; its addresses would resolve to the wrong code.
+fn load_symbols(filename: &str, bias: u64, perf: &PerfData) -> Symbols {
+    let path = Path::new(filename);
+    if let Some(recorded) = perf.build_ids.get(filename) {
+        let trimmed = recorded.trim_end_matches("00");
+        match read_build_id(path) {
+            Some(current) if current.trim_end_matches("00") == trimmed => {}
+            Some(_) => {
+                eprintln!(
+                    "Warning: {} changed since it was profiled (build-id mismatch); not symbolicating it",
+                    filename
+                );
+                return Symbols::None;
+            }
+            None if !path.exists() => return Symbols::None,
+            None => {}
+        }
+    }
+
+    match SymbolResolver::for_binary(path, path, Path::new("/"), bias) {
+        Ok(resolver) => Symbols::Dwarf(Box::new(resolver)),
+        Err(_) => SymbolTable::load(path).map_or(Symbols::None, Symbols::Table),
+    }
+}
+
+/// Runtime address minus ELF virtual address for a mapping
+///
+/// The mapping starts at file offset `pgoff`, inside the segment it loads.
+fn load_bias(mmap: &PerfMmap) -> Option<u64> {
+    let file = File::open(&mmap.filename).ok()?;
+    let data = unsafe { memmap2::Mmap::map(&file) }.ok()?;
+    let object = object::File::parse(&*data).ok()?;
+    // Segments can share a page; the mapping belongs to the later one
+    let segment = object
+        .segments()
+        .filter(|s| {
+            let (offset, size) = s.file_range();
+            mmap.pgoff >= offset & !0xfff && mmap.pgoff < offset + size
+        })
+        .max_by_key(|s| s.file_range().0)?;
+    let (offset, _) = segment.file_range();
+    Some(
+        mmap.start
+            .wrapping_add(offset)
+            .wrapping_sub(segment.address())
+            .wrapping_sub(mmap.pgoff),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::query_top_cpu;
+    use rusqlite::Connection;
+
+    /// Size of `struct perf_file_header`
+    const FILE_HEADER_SIZE: u64 = 104;
+    /// `perf_event_attr.freq`
+    const ATTR_FREQ: u64 = 1 << 10;
+
+    #[inline(never)]
+    fn hot_function() -> u32 {
+        std::hint::black_box(17)
+    }
+
+    #[inline(never)]
+    fn cold_function() -> u32 {
+        std::hint::black_box(19)
+    }
+
+    /// A perf.data record: header, then `body` padded to 8 bytes
+    fn record(kind: u32, body: &[u8]) -> Vec<u8> {
+        let size = 8 + body.len().div_ceil(8) * 8;
+        let mut record = Vec::with_capacity(size);
+        record.extend(kind.to_le_bytes());
+        record.extend(2u16.to_le_bytes()); // PERF_RECORD_MISC_USER
+        record.extend((size as u16).to_le_bytes());
+        record.extend(body);
+        record.resize(size, 0);
+        record
+    }
+
+    fn comm(pid: u32, name: &str) -> Vec<u8> {
+        let mut body = [pid.to_le_bytes(), pid.to_le_bytes()].concat();
+        body.extend(name.as_bytes());
+        body.push(0);
+        record(3, &body)
+    }
+
+    fn mmap(pid: u32, start: u64, len: u64, filename: &str) -> Vec<u8> {
+        let mut body = [pid.to_le_bytes(), pid.to_le_bytes()].concat();
+        for field in [start, len, 0] {
+            body.extend(field.to_le_bytes());
+        }
+        body.extend(filename.as_bytes());
+        body.push(0);
+        record(1, &body)
+    }
+
+    /// A sample with IP, TID, TIME and a user callchain
+    fn sample(pid: u32, time_ns: u64, ip: u64) -> Vec<u8> {
+        let mut body = ip.to_le_bytes().to_vec();
+        body.extend([pid.to_le_bytes(), pid.to_le_bytes()].concat());
+        body.extend(time_ns.to_le_bytes());
+        // PERF_CONTEXT_USER, then the sampled frame
+        for field in [2, -512i64 as u64, ip] {
+            body.extend(field.to_le_bytes());
+        }
+        record(9, &body)
+    }
+
+    /// perf.data with one event sampled at `freq` Hz
+    fn perf_data(freq: u64, records: &[Vec<u8>]) -> Vec<u8> {
+        let mut attr = vec![0u8; 112];
+        attr[4..8].copy_from_slice(&112u32.to_le_bytes());
+        attr[16..24].copy_from_slice(&freq.to_le_bytes());
+        // PERF_SAMPLE_IP | TID | TIME | CALLCHAIN
+        attr[24..32].copy_from_slice(&0x27u64.to_le_bytes());
+        attr[40..48].copy_from_slice(&ATTR_FREQ.to_le_bytes());
+        // No sample ids
+        attr.extend([0u8; 16]);
+
+        let data: Vec<u8> = records.concat();
+        let attrs_offset = FILE_HEADER_SIZE;
+        let data_offset = attrs_offset + attr.len() as u64;
+        let mut file = b"PERFILE2".to_vec();
+        for field in [
+            FILE_HEADER_SIZE,
+            attr.len() as u64,
+            attrs_offset,
+            attr.len() as u64,
+            data_offset,
+            data.len() as u64,
+        ] {
+            file.extend(field.to_le_bytes());
+        }
+        // No event types, no feature sections
+        file.resize(FILE_HEADER_SIZE as usize, 0);
+        file.extend(attr);
+        file.extend(data);
+        file
+    }
+
+    #[test]
+    fn imported_fixture_keeps_sample_counts_and_top_symbols() {
+        let exe = std::env::current_exe().unwrap();
+        let base = crate::process::MemoryMaps::for_pid(std::process::id())
+            .unwrap()
+            .aslr_offset(&exe)
+            .unwrap();
+        let len = std::fs::metadata(&exe).unwrap().len();
+        let hot = hot_function as *const () as u64;
+        let cold = cold_function as *const () as u64;
+
+        // PID 4242 runs the test binary; PID 77 is a bystander with fewer samples
+        let mut records = vec![
+            comm(4242, "fixture"),
+            mmap(4242, base, len, &exe.to_string_lossy()),
+        ];
+        for (i, ip) in [hot, cold, hot, hot, cold].into_iter().enumerate() {
+            records.push(sample(4242, i as u64 * 100_000_000, ip));
+        }
+        records.push(sample(77, 0, 0x1234));
+
+        let dir = std::env::temp_dir();
+        let id = std::process::id();
+        let file = dir.join(format!("rsprof-import-{}.perf.data", id));
+        let db = dir.join(format!("rsprof-import-{}.db", id));
+        std::fs::write(&file, perf_data(997, &records)).unwrap();
+
+        run(
+            &file,
+            Some(&db),
+            None,
+            Duration::from_secs(1),
+            Attribution::Leaf,
+        )
+        .unwrap();
+
+        let conn = Connection::open(&db).unwrap();
+        let meta = |key: &str| -> String {
+            conn.query_row("SELECT value FROM meta WHERE key = ?", [key], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(meta("pid"), "4242");
+        assert_eq!(meta("process_name"), "fixture");
+        assert_eq!(meta("cpu_freq_hz"), "997");
+
+        let top = query_top_cpu(&conn, 10, 0.0).unwrap();
+        // Symbolicated from the test binary's own debug info
+        assert_eq!(top.len(), 2);
+        assert!(top[0].function.contains("::hot_function"));
+        assert_eq!(top[0].total_samples, 3);
+        assert!(top[1].function.contains("::cold_function"));
+        assert_eq!(top[1].total_samples, 2);
+        drop(conn);
+
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", db.display(), suffix));
+        }
+        let _ = std::fs::remove_file(&file);
+    }
+}
@@ -0,0 +1,44 @@
+use crate::error::{Error, Result};
+use crate::storage::eventlog;
+use crate::symbols::deferred;
+use std::path::Path;
+
+/// Convert an event log into a profile database
+///
+/// The log holds raw sampled addresses; they are resolved against the
+/// recorded binary here. If that fails (e.g. the binary is gone) the
+/// profile keeps them, like a `--no-symbols` recording.
+pub fn run(file: &Path, output: Option<&Path>) -> Result<()> {
+    let db_path = match output {
+        Some(path) => path.to_path_buf(),
+        None => file.with_extension("db"),
+    };
+    if db_path.exists() {
+        return Err(Error::InvalidArgument(format!(
+            "{} already exists; choose another path with -o",
+            db_path.display()
+        )));
+    }
+
+    eprintln!("Ingesting {} -> {}", file.display(), db_path.display());
+    let summary = eventlog::ingest(file, &db_path)?;
+    eprintln!(
+        "Wrote {} checkpoints, {} locations ({} records)",
+        summary.checkpoints, summary.locations, summary.records
+    );
+
+    let mut conn = rusqlite::Connection::open(&db_path)?;
+    if deferred::is_deferred(&conn)? {
+        eprintln!("Resolving symbols...");
+        match deferred::symbolicate_if_deferred(&mut conn, None) {
+            Ok(resolved) => eprintln!("Resolved {} stacks", resolved),
+            Err(e) => eprintln!(
+                "Warning: {}; symbols stay unresolved (retry with `rsprof symbolicate {}`)",
+                e,
+                db_path.display()
+            ),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use super::top::{format_bytes, format_function, format_location};
+use crate::cli::NameOptions;
+use crate::error::Result;
+use crate::storage::{HeapSeries, query_heap_series, repair_clock_skew};
+use crate::symbols::deferred;
+use std::path::Path;
+
+/// Checkpoints a site needs before its growth counts as sustained
+const MIN_CHECKPOINTS: usize = 3;
+
+/// A site whose live bytes only ever grew
+struct Leak<'a> {
+    series: &'a HeapSeries,
+    /// Least-squares slope of live bytes over time
+    bytes_per_sec: f64,
+    /// Live bytes at the last checkpoint minus the first
+    growth: i64,
+}
+
+impl<'a> Leak<'a> {
+    /// Fit the site's live bytes against checkpoint time
+    ///
+    /// A site qualifies when it has data at `MIN_CHECKPOINTS` or more
+    /// checkpoints, its live bytes never go down from one to the next, and
+    /// the fitted slope is positive.
+    fn fit(series: &'a HeapSeries) -> Option<Self> {
+        let points = &series.points;
+        if points.len() < MIN_CHECKPOINTS || points.windows(2).any(|w| w[1].1 < w[0].1) {
+            return None;
+        }
+        let growth = points[points.len() - 1].1 - points[0].1;
+        if growth <= 0 {
+            return None;
+        }
+
+        // Seconds since the site's first checkpoint, to keep the sums small
+        let start = points[0].0;
+        let n = points.len() as f64;
+        let mean_x = points
+            .iter()
+            .map(|&(t, _)| (t - start) as f64 / 1000.0)
+            .sum::<f64>()
+            / n;
+        let mean_y = points.iter().map(|&(_, v)| v as f64).sum::<f64>() / n;
+        let (mut num, mut den) = (0.0, 0.0);
+        for &(t, v) in points {
+            let dx = (t - start) as f64 / 1000.0 - mean_x;
+            num += dx * (v as f64 - mean_y);
+            den += dx * dx;
+        }
+        if den <= 0.0 || num <= 0.0 {
+            return None;
+        }
+
+        Some(Leak {
+            series,
+            bytes_per_sec: num / den,
+            growth,
+        })
+    }
+
+    fn live_bytes(&self) -> i64 {
+        self.series.points.last().map_or(0, |&(_, v)| v)
+    }
+
+    /// Seconds between the site's first and last checkpoint
+    fn span_secs(&self) -> f64 {
+        let points = &self.series.points;
+        (points[points.len() - 1].0 - points[0].0) as f64 / 1000.0
+    }
+}
+
+/// Run the leaks command
+///
+/// Lists the heap sites whose live bytes grew steadily and never went down
+/// over the recording, fastest growing first.
+pub fn run(file: &Path, limit: usize, json: bool, names: NameOptions) -> Result<()> {
+    // A --no-symbols recording is resolved in memory; the file stays as recorded
+    let mut conn = deferred::open_resolved(file)?;
+    // Keep the timeline ordered if the clock stepped back while recording
+    repair_clock_skew(&mut conn)?;
+
+    let series = query_heap_series(&conn)?;
+    if series.is_empty() {
+        eprintln!("No heap data found in {}", file.display());
+        return Ok(());
+    }
+
+    let mut leaks: Vec<Leak> = series.iter().filter_map(Leak::fit).collect();
+    leaks.sort_by(|a, b| {
+        b.bytes_per_sec
+            .total_cmp(&a.bytes_per_sec)
+            .then_with(|| b.growth.cmp(&a.growth))
+    });
+    leaks.truncate(limit);
+
+    if json {
+        print_json(file, &leaks);
+    } else if leaks.is_empty() {
+        println!("# {}", file.display());
+        println!("No site's live bytes grew steadily over the recording");
+    } else {
+        print_table(file, &leaks, names);
+    }
+    Ok(())
+}
+
+fn print_table(file: &Path, leaks: &[Leak], names: NameOptions) {
+    println!("# {}", file.display());
+    println!(
+        "# Sites whose live bytes never decreased over {}+ checkpoints, by growth rate",
+        MIN_CHECKPOINTS
+    );
+    println!();
+
+    println!(
+        "{:>12}  {:>10}  {:>10}  {:>8}  {:<30}  FUNCTION",
+        "RATE", "GROWTH", "LIVE", "OVER", "LOCATION"
+    );
+    println!("{}", "-".repeat(100));
+
+    for leak in leaks {
+        println!(
+            "{:>12}  {:>10}  {:>10}  {:>7.1}s  {:<30}  {}",
+            format!("{}/s", format_bytes(leak.bytes_per_sec.round() as i64)),
+            format!("+{}", format_bytes(leak.growth)),
+            format_bytes(leak.live_bytes()),
+            leak.span_secs(),
+            format_location(&leak.series.file, leak.series.line),
+            format_function(&leak.series.function, names)
+        );
+    }
+}
+
+fn print_json(file: &Path, leaks: &[Leak]) {
+    println!("{{");
+    println!("  \"file\": \"{}\",", file.display());
+    println!("  \"min_checkpoints\": {},", MIN_CHECKPOINTS);
+    println!("  \"entries\": [");
+
+    for (i, leak) in leaks.iter().enumerate() {
+        let comma = if i < leaks.len() - 1 { "," } else { "" };
+        println!(
+            "    {{ \"bytes_per_sec\": {:.1}, \"growth_bytes\": {}, \"live_bytes\": {}, \"checkpoints\": {}, \"span_secs\": {:.3}, \"file\": \"{}\", \"line\": {}, \"function\": \"{}\" }}{}",
+            leak.bytes_per_sec,
+            leak.growth,
+            leak.live_bytes(),
+            leak.series.points.len(),
+            leak.span_secs(),
+            leak.series.file.replace('\\', "\\\\").replace('"', "\\\""),
+            leak.series.line,
+            leak.series
+                .function
+                .replace('\\', "\\\\")
+                .replace('"', "\\\""),
+            comma
+        );
+    }
+
+    println!("  ]");
+    println!("}}");
+}
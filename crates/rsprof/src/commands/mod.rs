@@ -1,4 +1,17 @@
+pub mod annotate;
+pub mod assert;
+pub mod diff;
+pub mod export;
+pub mod import_perf;
+pub mod ingest;
+pub mod leaks;
 pub mod list;
+pub mod peak;
 pub mod query;
+pub mod report;
+pub mod retained;
+pub mod schema;
+pub mod symbolicate;
+pub mod threads;
 pub mod top;
 pub mod view;
@@ -0,0 +1,120 @@
+use super::top::{format_bytes, format_function, format_location};
+use crate::cli::NameOptions;
+use crate::error::Result;
+use crate::storage::{
+    HeapEntry, HeapPeak, query_heap_at_checkpoint, query_heap_peak, repair_clock_skew,
+};
+use crate::symbols::deferred;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Run the peak command
+///
+/// Shows what held memory at the checkpoint where total live heap bytes
+/// were highest, next to what the same sites held at the end of the run.
+pub fn run(file: &Path, limit: usize, json: bool, names: NameOptions) -> Result<()> {
+    // A --no-symbols recording is resolved in memory; the file stays as recorded
+    let mut conn = deferred::open_resolved(file)?;
+    // Keep the timeline ordered if the clock stepped back while recording
+    repair_clock_skew(&mut conn)?;
+
+    let Some(peak) = query_heap_peak(&conn)? else {
+        eprintln!("No heap data found in {}", file.display());
+        return Ok(());
+    };
+
+    let entries = query_heap_at_checkpoint(&conn, peak.checkpoint_id, limit)?;
+
+    // Same sites at the last checkpoint with heap data
+    let last_checkpoint: Option<i64> =
+        conn.query_row("SELECT MAX(checkpoint_id) FROM heap_samples", [], |row| {
+            row.get(0)
+        })?;
+    let end = match last_checkpoint {
+        Some(id) => query_heap_at_checkpoint(&conn, id, i64::MAX as usize)?,
+        None => Vec::new(),
+    };
+    let end_live: HashMap<i64, i64> = end.iter().map(|e| (e.location_id, e.live_bytes)).collect();
+    let end_total: i64 = end.iter().map(|e| e.live_bytes).sum();
+
+    if json {
+        print_json(file, &peak, end_total, &entries, &end_live);
+    } else {
+        print_table(file, &peak, end_total, &entries, &end_live, names);
+    }
+
+    Ok(())
+}
+
+fn print_table(
+    file: &Path,
+    peak: &HeapPeak,
+    end_total: i64,
+    entries: &[HeapEntry],
+    end_live: &HashMap<i64, i64>,
+    names: NameOptions,
+) {
+    let secs = peak.timestamp_ms / 1000;
+    println!("# {}", file.display());
+    println!(
+        "# Peak live heap: {} at {}m{:02}s (checkpoint {}) | At end: {}",
+        format_bytes(peak.live_bytes),
+        secs / 60,
+        secs % 60,
+        peak.checkpoint_id,
+        format_bytes(end_total)
+    );
+    println!();
+
+    println!(
+        "{:>10}  {:>6}  {:>10}  {:<30}  FUNCTION",
+        "AT PEAK", "%", "AT END", "LOCATION"
+    );
+    println!("{}", "-".repeat(90));
+
+    for entry in entries {
+        let at_end = end_live.get(&entry.location_id).copied().unwrap_or(0);
+        println!(
+            "{:>10}  {:>5.1}%  {:>10}  {:<30}  {}",
+            format_bytes(entry.live_bytes),
+            entry.live_bytes as f64 * 100.0 / peak.live_bytes.max(1) as f64,
+            format_bytes(at_end),
+            format_location(&entry.file, entry.line),
+            format_function(&entry.function, names)
+        );
+    }
+}
+
+fn print_json(
+    file: &Path,
+    peak: &HeapPeak,
+    end_total: i64,
+    entries: &[HeapEntry],
+    end_live: &HashMap<i64, i64>,
+) {
+    println!("{{");
+    println!("  \"file\": \"{}\",", file.display());
+    println!("  \"peak_checkpoint\": {},", peak.checkpoint_id);
+    println!("  \"peak_timestamp_ms\": {},", peak.timestamp_ms);
+    println!("  \"peak_live_bytes\": {},", peak.live_bytes);
+    println!("  \"end_live_bytes\": {},", end_total);
+    println!("  \"entries\": [");
+
+    for (i, entry) in entries.iter().enumerate() {
+        let comma = if i < entries.len() - 1 { "," } else { "" };
+        println!(
+            "    {{ \"live_bytes\": {}, \"end_live_bytes\": {}, \"alloc_bytes\": {}, \"alloc_count\": {}, \"file\": \"{}\", \"line\": {}, \"function\": \"{}\" }}{}",
+            entry.live_bytes,
+            end_live.get(&entry.location_id).copied().unwrap_or(0),
+            entry.total_alloc_bytes,
+            entry.alloc_count,
+            entry.file.replace('\\', "\\\\").replace('"', "\\\""),
+            entry.line,
+            entry.function.replace('\\', "\\\\").replace('"', "\\\""),
+            comma
+        );
+    }
+
+    println!("  ]");
+    println!("}}");
+}
@@ -1,28 +1,148 @@
+use crate::cli::QueryPreset;
 use crate::error::Result;
 use rusqlite::Connection;
 use std::path::Path;
 
-pub fn run(file: &Path, sql: &str) -> Result<()> {
+/// Heap stats at the last checkpoint each location was recorded in
+/// (heap_samples values are cumulative, so the latest row is the total)
+const LATEST_HEAP: &str = r#"
+    WITH latest AS (
+        SELECT location_id, MAX(checkpoint_id) AS checkpoint_id
+        FROM heap_samples
+        GROUP BY location_id
+    )
+"#;
+
+/// Expand a preset to SQL (`?1` is the row limit)
+fn preset_sql(preset: QueryPreset) -> String {
+    match preset {
+        QueryPreset::TopCpu => r#"
+            SELECT l.function, l.file, l.line,
+                   SUM(c.count) AS samples,
+                   ROUND(SUM(c.count) * 100.0 / (SELECT SUM(count) FROM cpu_samples), 2) AS cpu_pct
+            FROM cpu_samples c
+            JOIN locations l ON l.id = c.location_id
+            GROUP BY c.location_id
+            ORDER BY samples DESC
+            LIMIT ?1
+        "#
+        .to_string(),
+        QueryPreset::TopHeap => format!(
+            r#"{LATEST_HEAP}
+            SELECT l.function, l.file, l.line,
+                   h.live_bytes, h.alloc_bytes, h.free_bytes, h.alloc_count, h.free_count
+            FROM latest
+            JOIN heap_samples h USING (location_id, checkpoint_id)
+            JOIN locations l ON l.id = h.location_id
+            ORDER BY h.live_bytes DESC, h.alloc_bytes DESC
+            LIMIT ?1
+            "#
+        ),
+        QueryPreset::Leaks => format!(
+            r#"{LATEST_HEAP}
+            SELECT l.function, l.file, l.line,
+                   h.live_bytes,
+                   h.alloc_count - h.free_count AS leaked_count,
+                   h.live_bytes - (
+                       SELECT first.live_bytes FROM heap_samples first
+                       WHERE first.location_id = h.location_id
+                       ORDER BY first.checkpoint_id ASC LIMIT 1
+                   ) AS growth_bytes
+            FROM latest
+            JOIN heap_samples h USING (location_id, checkpoint_id)
+            JOIN locations l ON l.id = h.location_id
+            WHERE h.live_bytes > 0 AND h.alloc_count > h.free_count
+            ORDER BY growth_bytes DESC, h.live_bytes DESC
+            LIMIT ?1
+            "#
+        ),
+        QueryPreset::Churn => format!(
+            r#"{LATEST_HEAP}
+            SELECT l.function, l.file, l.line,
+                   h.alloc_count, h.free_count, h.alloc_bytes,
+                   h.alloc_bytes / MAX(h.alloc_count, 1) AS avg_size,
+                   h.live_bytes
+            FROM latest
+            JOIN heap_samples h USING (location_id, checkpoint_id)
+            JOIN locations l ON l.id = h.location_id
+            WHERE h.alloc_count > 0
+            ORDER BY h.alloc_count DESC
+            LIMIT ?1
+            "#
+        ),
+        QueryPreset::Addresses => r#"
+            SELECT l.function, l.file, l.line,
+                   COUNT(*) AS addresses,
+                   GROUP_CONCAT(printf('0x%x', a.addr - (
+                       SELECT COALESCE(MAX(CAST(value AS INTEGER)), 0)
+                       FROM meta WHERE key = 'aslr_offset'
+                   )), ' ') AS addrs
+            FROM (SELECT * FROM location_addresses ORDER BY addr) a
+            JOIN locations l ON l.id = a.location_id
+            GROUP BY a.location_id
+            ORDER BY addresses DESC
+            LIMIT ?1
+        "#
+        .to_string(),
+    }
+}
+
+pub fn run(file: &Path, sql: &str, json: bool) -> Result<()> {
     let conn = Connection::open(file)?;
     let mut stmt = conn.prepare(sql)?;
+    print_rows(&mut stmt, [], json)
+}
 
+/// Run a canned query
+pub fn run_preset(file: &Path, preset: QueryPreset, limit: usize, json: bool) -> Result<()> {
+    let conn = Connection::open(file)?;
+    let mut stmt = conn.prepare(&preset_sql(preset))?;
+    print_rows(&mut stmt, [limit as i64], json)
+}
+
+fn print_rows(
+    stmt: &mut rusqlite::Statement,
+    params: impl rusqlite::Params,
+    json: bool,
+) -> Result<()> {
     let column_count = stmt.column_count();
     let column_names: Vec<String> = stmt.column_names().iter().map(|s| s.to_string()).collect();
 
     // Print header
-    println!("{}", column_names.join("\t"));
+    if !json {
+        println!("{}", column_names.join("\t"));
+    }
 
-    // Execute and print rows
-    let mut rows = stmt.query([])?;
+    // Execute and print rows (JSON objects are collected to place the commas)
+    let mut rows = stmt.query(params)?;
+    let mut objects = Vec::new();
     while let Some(row) = rows.next()? {
-        let values: Vec<String> = (0..column_count)
+        let values: Vec<rusqlite::types::Value> = (0..column_count)
             .map(|i| {
                 row.get::<_, rusqlite::types::Value>(i)
-                    .map(|v| format_value(&v))
-                    .unwrap_or_else(|_| "NULL".to_string())
+                    .unwrap_or(rusqlite::types::Value::Null)
             })
             .collect();
-        println!("{}", values.join("\t"));
+
+        if json {
+            let fields: Vec<String> = column_names
+                .iter()
+                .zip(&values)
+                .map(|(name, value)| format!("{}: {}", json_string(name), json_value(value)))
+                .collect();
+            objects.push(format!("  {{ {} }}", fields.join(", ")));
+        } else {
+            let values: Vec<String> = values.iter().map(format_value).collect();
+            println!("{}", values.join("\t"));
+        }
+    }
+
+    if json {
+        println!("[");
+        if !objects.is_empty() {
+            println!("{}", objects.join(",\n"));
+        }
+        println!("]");
     }
 
     Ok(())
@@ -37,3 +157,143 @@ fn format_value(value: &rusqlite::types::Value) -> String {
         rusqlite::types::Value::Blob(b) => format!("<blob {} bytes>", b.len()),
     }
 }
+
+fn json_value(value: &rusqlite::types::Value) -> String {
+    match value {
+        rusqlite::types::Value::Null => "null".to_string(),
+        rusqlite::types::Value::Integer(i) => i.to_string(),
+        rusqlite::types::Value::Real(f) if f.is_finite() => f.to_string(),
+        rusqlite::types::Value::Real(_) => "null".to_string(),
+        rusqlite::types::Value::Text(s) => json_string(s),
+        rusqlite::types::Value::Blob(b) => json_string(&format!("<blob {} bytes>", b.len())),
+    }
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use crate::symbols::Location;
+    use rusqlite::types::Value;
+
+    fn location(function: &str) -> Location {
+        Location {
+            file: format!("src/{}.rs", function),
+            line: 1,
+            column: 0,
+            function: function.to_string(),
+        }
+    }
+
+    /// Column names and rows of a preset against `conn`
+    fn preset_rows(conn: &Connection, preset: QueryPreset) -> (Vec<String>, Vec<Vec<Value>>) {
+        let mut stmt = conn.prepare(&preset_sql(preset)).unwrap();
+        let columns: Vec<String> = stmt.column_names().iter().map(|s| s.to_string()).collect();
+        let rows = stmt
+            .query_map([10], |row| {
+                (0..columns.len()).map(|i| row.get::<_, Value>(i)).collect()
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        (columns, rows)
+    }
+
+    #[test]
+    fn presets_return_their_columns() {
+        let path = std::env::temp_dir().join(format!("rsprof-presets-{}.db", std::process::id()));
+        let mut storage = Storage::create(&path).unwrap();
+        storage.set_meta("aslr_offset", "4096").unwrap();
+        // `parse` runs and leaks; `copy` allocates the most but frees it all
+        storage.record_cpu_sample_count(0x1100, &location("parse"), 30);
+        storage.record_cpu_sample_count(0x1180, &location("parse"), 0);
+        storage.record_cpu_sample_count(0x2100, &location("copy"), 10);
+        storage.record_heap_sample(&location("parse"), 1000, 0, 1000, 10, 0);
+        storage.record_heap_sample(&location("copy"), 8000, 8000, 0, 80, 80);
+        storage.flush_checkpoint_at(100).unwrap();
+        storage.record_heap_sample(&location("parse"), 3000, 500, 2500, 30, 5);
+        storage.record_heap_sample(&location("copy"), 16000, 16000, 0, 160, 160);
+        storage.flush_checkpoint_at(200).unwrap();
+        drop(storage);
+        let conn = Connection::open(&path).unwrap();
+
+        let columns = |names: &[&str]| {
+            let mut all = vec!["function", "file", "line"];
+            all.extend(names);
+            all.into_iter().map(String::from).collect::<Vec<_>>()
+        };
+        let text = |s: &str| Value::Text(s.to_string());
+        let int = Value::Integer;
+
+        let (names, rows) = preset_rows(&conn, QueryPreset::TopCpu);
+        assert_eq!(names, columns(&["samples", "cpu_pct"]));
+        assert_eq!(rows[0][0], text("parse"));
+        assert_eq!(rows[0][3..], [int(30), Value::Real(75.0)]);
+
+        let (names, rows) = preset_rows(&conn, QueryPreset::TopHeap);
+        let heap = [
+            "live_bytes",
+            "alloc_bytes",
+            "free_bytes",
+            "alloc_count",
+            "free_count",
+        ];
+        assert_eq!(names, columns(&heap));
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0][0], text("parse"));
+        assert_eq!(
+            rows[0][3..],
+            [int(2500), int(3000), int(500), int(30), int(5)]
+        );
+
+        let (names, rows) = preset_rows(&conn, QueryPreset::Leaks);
+        assert_eq!(
+            names,
+            columns(&["live_bytes", "leaked_count", "growth_bytes"])
+        );
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0][3..], [int(2500), int(25), int(1500)]);
+
+        let (names, rows) = preset_rows(&conn, QueryPreset::Churn);
+        let churn = [
+            "alloc_count",
+            "free_count",
+            "alloc_bytes",
+            "avg_size",
+            "live_bytes",
+        ];
+        assert_eq!(names, columns(&churn));
+        assert_eq!(rows[0][0], text("copy"));
+        assert_eq!(
+            rows[0][3..],
+            [int(160), int(160), int(16000), int(100), int(0)]
+        );
+
+        let (names, rows) = preset_rows(&conn, QueryPreset::Addresses);
+        assert_eq!(names, columns(&["addresses", "addrs"]));
+        assert_eq!(rows[0][0], text("parse"));
+        assert_eq!(rows[0][3..], [int(2), text("0x100 0x180")]);
+
+        drop(conn);
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", path.display(), suffix)).ok();
+        }
+    }
+}
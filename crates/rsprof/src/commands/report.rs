@@ -0,0 +1,346 @@
+use super::query::json_string;
+use super::top::{format_bytes, format_function, format_location, format_secs};
+use crate::cli::{NameOptions, ReportFormat};
+use crate::error::Result;
+use crate::storage::{
+    CpuEntry, HeapEntry, query_cpu_freq_hz, query_heap_at_checkpoint, query_heap_peak,
+    query_top_cpu, query_top_heap_live, repair_clock_skew, samples_to_secs,
+};
+use crate::symbols::deferred;
+use rusqlite::Connection;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+/// Page skeleton with inline CSS and JS; `{{...}}` placeholders are filled in
+const HTML_TEMPLATE: &str = include_str!("report.html");
+
+/// Run the report command
+///
+/// Writes a single self-contained file (no network access needed to view
+/// it) to `output`, or stdout.
+pub fn run(
+    file: &Path,
+    format: ReportFormat,
+    output: Option<&Path>,
+    limit: usize,
+    names: NameOptions,
+) -> Result<()> {
+    // A --no-symbols recording is resolved in memory; the file stays as recorded
+    let mut conn = deferred::open_resolved(file)?;
+    // Keep the timeline ordered if the clock stepped back while recording
+    repair_clock_skew(&mut conn)?;
+
+    let report = match format {
+        ReportFormat::Html => render_html(&conn, file, limit, names)?,
+    };
+
+    match output {
+        Some(path) => {
+            std::fs::write(path, report)?;
+            eprintln!("Wrote {}", path.display());
+        }
+        None => std::io::stdout().write_all(report.as_bytes())?,
+    }
+    Ok(())
+}
+
+fn render_html(conn: &Connection, file: &Path, limit: usize, names: NameOptions) -> Result<String> {
+    let duration_ms: Option<i64> = conn
+        .query_row("SELECT MAX(timestamp_ms) FROM checkpoints", [], |row| {
+            row.get(0)
+        })
+        .ok()
+        .flatten();
+    let cpu_freq = query_cpu_freq_hz(conn);
+
+    // Every location feeds the flame graph; the tables show the top `limit`
+    let cpu = query_top_cpu(conn, i64::MAX as usize, 0.0)?;
+    let heap = query_top_heap_live(conn, limit)?;
+    let total_samples: u64 = cpu.iter().map(|e| e.total_samples).sum();
+
+    // Memory is graphed at its peak: by the end of a run most of it is freed
+    let peak = query_heap_peak(conn)?;
+    let at_peak = match &peak {
+        Some(peak) => query_heap_at_checkpoint(conn, peak.checkpoint_id, i64::MAX as usize)?,
+        None => Vec::new(),
+    };
+
+    let mut summary = format!("Samples: {}", total_samples);
+    if let Some(freq) = cpu_freq {
+        summary.push_str(&format!(
+            " at {} Hz (~{} CPU)",
+            freq,
+            format_secs(samples_to_secs(total_samples, freq))
+        ));
+    }
+    if let Some(ms) = duration_ms {
+        let secs = ms / 1000;
+        summary = format!("Duration: {}m{:02}s | {}", secs / 60, secs % 60, summary);
+    }
+    if let Some(peak) = &peak {
+        summary.push_str(&format!(
+            " | Peak live heap: {} at {:.1}s",
+            format_bytes(peak.live_bytes),
+            peak.timestamp_ms as f64 / 1000.0
+        ));
+    }
+
+    let cpu_tree = flame_tree(
+        cpu.iter().map(|e| {
+            (
+                e.function.as_str(),
+                e.file.as_str(),
+                e.line,
+                e.total_samples,
+            )
+        }),
+        names,
+    );
+    let heap_tree = flame_tree(
+        at_peak.iter().map(|e| {
+            let live = e.live_bytes.max(0) as u64;
+            (e.function.as_str(), e.file.as_str(), e.line, live)
+        }),
+        names,
+    );
+    let data = format!(
+        "{{\"cpu\": {}, \"heap\": {}}}",
+        cpu_tree.to_json("all samples"),
+        heap_tree.to_json("live heap at peak")
+    )
+    // A literal "</script>" in a function name would end the data block
+    .replace("</", "<\\/");
+
+    let title = format!("rsprof report: {}", file.display());
+    Ok(HTML_TEMPLATE
+        .replace("{{title}}", &html_escape(&title))
+        .replace("{{summary}}", &html_escape(&summary))
+        .replace("{{cpu_table}}", &cpu_table(&cpu, limit, cpu_freq, names))
+        .replace("{{heap_table}}", &heap_table(&heap, limit, names))
+        .replace("{{data}}", &data))
+}
+
+/// Flame graph node: children keyed by name, value summed over the subtree
+#[derive(Default)]
+struct FlameNode {
+    value: u64,
+    children: BTreeMap<String, FlameNode>,
+}
+
+impl FlameNode {
+    fn to_json(&self, name: &str) -> String {
+        let children = self
+            .children
+            .iter()
+            .map(|(child_name, child)| child.to_json(child_name))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "{{\"n\":{},\"v\":{},\"c\":[{}]}}",
+            json_string(name),
+            self.value,
+            children
+        )
+    }
+}
+
+/// Nest (function, file, line, value) rows by module path, then source line
+///
+/// Profiles store the attributed frame of each sample rather than whole call
+/// stacks, so the graph nests `crate::module::Type::method` segments and
+/// ends in one block per line.
+fn flame_tree<'a>(
+    rows: impl Iterator<Item = (&'a str, &'a str, u32, u64)>,
+    names: NameOptions,
+) -> FlameNode {
+    let mut root = FlameNode::default();
+    for (function, file, line, value) in rows {
+        if value == 0 {
+            continue;
+        }
+        root.value += value;
+        let function = format_function(function, names);
+        let mut node = &mut root;
+        for segment in split_path(&function) {
+            node = node.children.entry(segment.to_string()).or_default();
+            node.value += value;
+        }
+        let leaf = node
+            .children
+            .entry(format_location(file, line))
+            .or_default();
+        leaf.value += value;
+    }
+    root
+}
+
+/// Split a function path on `::` outside of generics and `<impl ...>` blocks
+fn split_path(function: &str) -> Vec<&str> {
+    let mut segments = Vec::new();
+    let mut depth = 0i32;
+    let mut start = 0;
+    let bytes = function.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'<' => depth += 1,
+            b'>' => depth -= 1,
+            b':' if depth == 0 && bytes.get(i + 1) == Some(&b':') => {
+                segments.push(&function[start..i]);
+                start = i + 2;
+                i += 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    segments.push(&function[start..]);
+    segments.retain(|s| !s.is_empty());
+    segments
+}
+
+fn cpu_table(
+    entries: &[CpuEntry],
+    limit: usize,
+    cpu_freq: Option<f64>,
+    names: NameOptions,
+) -> String {
+    if entries.is_empty() {
+        return "<p class=\"empty\">No CPU samples recorded.</p>".to_string();
+    }
+    let mut html = String::from(
+        "<table>\n<tr><th>CPU%</th><th>CPU TIME</th><th>LOCATION</th><th>FUNCTION</th></tr>\n",
+    );
+    for entry in entries.iter().take(limit) {
+        let time = cpu_freq.map_or_else(
+            || "-".to_string(),
+            |freq| format_secs(samples_to_secs(entry.total_samples, freq)),
+        );
+        html.push_str(&format!(
+            "<tr><td class=\"num\">{:.1}%</td><td class=\"num\">{}</td><td>{}</td><td>{}</td></tr>\n",
+            entry.total_percent,
+            time,
+            html_escape(&format_location(&entry.file, entry.line)),
+            html_escape(&format_function(&entry.function, names))
+        ));
+    }
+    html.push_str("</table>");
+    html
+}
+
+fn heap_table(entries: &[HeapEntry], limit: usize, names: NameOptions) -> String {
+    if entries.is_empty() {
+        return "<p class=\"empty\">No heap data recorded.</p>".to_string();
+    }
+    let mut html = String::from(
+        "<table>\n<tr><th>LIVE</th><th>ALLOCATED</th><th>LOCATION</th><th>FUNCTION</th></tr>\n",
+    );
+    for entry in entries.iter().take(limit) {
+        html.push_str(&format!(
+            "<tr><td class=\"num\">{}</td><td class=\"num\">{}</td><td>{}</td><td>{}</td></tr>\n",
+            format_bytes(entry.live_bytes),
+            format_bytes(entry.total_alloc_bytes),
+            html_escape(&format_location(&entry.file, entry.line)),
+            html_escape(&format_function(&entry.function, names))
+        ));
+    }
+    html.push_str("</table>");
+    html
+}
+
+fn html_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use crate::symbols::Location;
+
+    /// Open and close tags outside `<script>`/`<style>` bodies must nest
+    fn tags_balance(html: &str) -> bool {
+        const VOID: &[&str] = &["meta", "br", "hr", "img", "input", "link"];
+        let mut open: Vec<String> = Vec::new();
+        let mut rest = html;
+        while let Some(start) = rest.find('<') {
+            let Some(end) = rest[start..].find('>') else {
+                return false;
+            };
+            let tag = &rest[start + 1..start + end];
+            rest = &rest[start + end + 1..];
+            let name: String = tag
+                .trim_start_matches('/')
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric())
+                .collect();
+            if tag.starts_with('!') || VOID.contains(&name.as_str()) {
+                continue;
+            }
+            if tag.starts_with('/') {
+                if open.pop().as_deref() != Some(name.as_str()) {
+                    return false;
+                }
+            } else {
+                if name == "script" || name == "style" {
+                    let Some(close) = rest.find(&format!("</{}>", name)) else {
+                        return false;
+                    };
+                    rest = &rest[close..];
+                }
+                open.push(name);
+            }
+        }
+        open.is_empty()
+    }
+
+    #[test]
+    fn html_report_holds_the_flame_graph_and_top_functions() {
+        let path = std::env::temp_dir().join(format!("rsprof-report-{}.db", std::process::id()));
+        let mut storage = Storage::create(&path).unwrap();
+        let location = |function: &str, line: u32| Location {
+            file: "src/parse.rs".to_string(),
+            line,
+            column: 0,
+            function: function.to_string(),
+        };
+        storage.record_cpu_sample_count(0x1000, &location("app::parse::parse_request", 10), 30);
+        storage.record_cpu_sample_count(0x2000, &location("app::Vec<T>::push", 20), 10);
+        storage.record_heap_sample(&location("app::cache::insert", 30), 4096, 0, 4096, 1, 0);
+        storage.flush_checkpoint_at(1000).unwrap();
+        drop(storage);
+
+        let conn = Connection::open(&path).unwrap();
+        let html = render_html(&conn, &path, 10, NameOptions::default()).unwrap();
+        drop(conn);
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", path.display(), suffix)).ok();
+        }
+
+        // Flame graph data: module path blocks ending in a line block
+        let data_start = html.find("application/json\">").unwrap();
+        let data = &html[data_start..html[data_start..].find("</script>").unwrap() + data_start];
+        assert!(data.contains(r#"{"cpu": {"n":"all samples","v":40"#));
+        assert!(data.contains(r#"{"n":"parse_request","v":30,"c":[{"n":"parse.rs:10","v":30"#));
+        assert!(data.contains(r#""heap": {"n":"live heap at peak","v":4096"#));
+        assert!(data.contains(r#"{"n":"insert","v":4096"#));
+
+        // Top tables, with generics escaped
+        assert!(html.contains("<td>app::parse::parse_request</td>"));
+        assert!(html.contains("<td>app::Vec&lt;T&gt;::push</td>"));
+        assert!(html.contains("<td>app::cache::insert</td>"));
+        assert!(!html.contains("{{"));
+        assert!(tags_balance(&html));
+    }
+}
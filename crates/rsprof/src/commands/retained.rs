@@ -0,0 +1,248 @@
+use super::top::{format_bytes, format_function, format_location};
+use crate::cli::NameOptions;
+use crate::error::Result;
+use crate::storage::{
+    HeapEntry, query_heap_sparklines_for_locations, query_top_heap_live, repair_clock_skew,
+};
+use crate::symbols::deferred;
+use rusqlite::Connection;
+use std::path::Path;
+
+/// Net change over the window, as a fraction of the site's average live
+/// bytes, below which it counts as stable
+const STABLE_FRACTION: f64 = 0.1;
+
+/// Direction a site's live bytes took over the last checkpoints
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Trend {
+    Stable,
+    Growing,
+    Shrinking,
+}
+
+impl Trend {
+    fn name(self) -> &'static str {
+        match self {
+            Trend::Stable => "stable",
+            Trend::Growing => "growing",
+            Trend::Shrinking => "shrinking",
+        }
+    }
+}
+
+/// Classify a series of live byte values (oldest first)
+///
+/// Uses the least-squares slope rather than last minus first, so a single
+/// noisy checkpoint at either end doesn't decide the trend. Returns the
+/// trend and the fitted change over the whole window.
+fn classify_trend(values: &[i64]) -> (Trend, i64) {
+    let n = values.len();
+    if n < 2 {
+        return (Trend::Stable, 0);
+    }
+    let mean_x = (n - 1) as f64 / 2.0;
+    let mean_y = values.iter().sum::<i64>() as f64 / n as f64;
+    let (mut num, mut den) = (0.0, 0.0);
+    for (i, &v) in values.iter().enumerate() {
+        let dx = i as f64 - mean_x;
+        num += dx * (v as f64 - mean_y);
+        den += dx * dx;
+    }
+    let change = num / den * (n - 1) as f64;
+
+    let trend = if change.abs() < STABLE_FRACTION * mean_y.abs().max(1.0) {
+        Trend::Stable
+    } else if change > 0.0 {
+        Trend::Growing
+    } else {
+        Trend::Shrinking
+    };
+    (trend, change.round() as i64)
+}
+
+/// Run the retained command
+///
+/// Lists the sites holding the most live bytes at the last checkpoint, each
+/// with the trend of its live bytes over the last `window` checkpoints.
+pub fn run(file: &Path, limit: usize, window: usize, json: bool, names: NameOptions) -> Result<()> {
+    // A --no-symbols recording is resolved in memory; the file stays as recorded
+    let mut conn = deferred::open_resolved(file)?;
+    // Keep the timeline ordered if the clock stepped back while recording
+    repair_clock_skew(&mut conn)?;
+
+    let entries = retained_sites(&conn, limit)?;
+    if entries.is_empty() {
+        eprintln!("No live heap data found in {}", file.display());
+        return Ok(());
+    }
+    let total_live: i64 = query_top_heap_live(&conn, i64::MAX as usize)?
+        .iter()
+        .map(|e| e.live_bytes.max(0))
+        .sum();
+    let trends = site_trends(&conn, &entries, window);
+
+    if json {
+        print_json(file, window, total_live, &entries, &trends);
+    } else {
+        print_table(file, window, total_live, &entries, &trends, names);
+    }
+    Ok(())
+}
+
+/// The `limit` sites holding the most live bytes at the last checkpoint,
+/// largest first
+fn retained_sites(conn: &Connection, limit: usize) -> Result<Vec<HeapEntry>> {
+    let mut entries = query_top_heap_live(conn, limit)?;
+    entries.retain(|e| e.live_bytes > 0);
+    Ok(entries)
+}
+
+/// Trend of each site's live bytes over the last `window` checkpoints
+fn site_trends(conn: &Connection, entries: &[HeapEntry], window: usize) -> Vec<(Trend, i64)> {
+    let ids: Vec<i64> = entries.iter().map(|e| e.location_id).collect();
+    let series = query_heap_sparklines_for_locations(conn, window, &ids);
+    entries
+        .iter()
+        .map(|e| {
+            series
+                .get(&e.location_id)
+                .map_or((Trend::Stable, 0), |values| classify_trend(values))
+        })
+        .collect()
+}
+
+fn print_table(
+    file: &Path,
+    window: usize,
+    total_live: i64,
+    entries: &[HeapEntry],
+    trends: &[(Trend, i64)],
+    names: NameOptions,
+) {
+    println!("# {}", file.display());
+    println!(
+        "# Live heap at end: {} | Trend over the last {} checkpoints",
+        format_bytes(total_live),
+        window
+    );
+    println!();
+
+    println!(
+        "{:>10}  {:>6}  {:<9}  {:>10}  {:<30}  FUNCTION",
+        "LIVE", "%", "TREND", "CHANGE", "LOCATION"
+    );
+    println!("{}", "-".repeat(90));
+
+    for (entry, (trend, change)) in entries.iter().zip(trends) {
+        let sign = if *change > 0 { "+" } else { "" };
+        println!(
+            "{:>10}  {:>5.1}%  {:<9}  {:>10}  {:<30}  {}",
+            format_bytes(entry.live_bytes),
+            entry.live_bytes as f64 * 100.0 / total_live.max(1) as f64,
+            trend.name(),
+            format!("{}{}", sign, format_bytes(*change)),
+            format_location(&entry.file, entry.line),
+            format_function(&entry.function, names)
+        );
+    }
+}
+
+fn print_json(
+    file: &Path,
+    window: usize,
+    total_live: i64,
+    entries: &[HeapEntry],
+    trends: &[(Trend, i64)],
+) {
+    println!("{{");
+    println!("  \"file\": \"{}\",", file.display());
+    println!("  \"window_checkpoints\": {},", window);
+    println!("  \"live_bytes\": {},", total_live);
+    println!("  \"entries\": [");
+
+    for (i, (entry, (trend, change))) in entries.iter().zip(trends).enumerate() {
+        let comma = if i < entries.len() - 1 { "," } else { "" };
+        println!(
+            "    {{ \"live_bytes\": {}, \"trend\": \"{}\", \"change_bytes\": {}, \"file\": \"{}\", \"line\": {}, \"function\": \"{}\" }}{}",
+            entry.live_bytes,
+            trend.name(),
+            change,
+            entry.file.replace('\\', "\\\\").replace('"', "\\\""),
+            entry.line,
+            entry.function.replace('\\', "\\\\").replace('"', "\\\""),
+            comma
+        );
+    }
+
+    println!("  ]");
+    println!("}}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use crate::symbols::Location;
+
+    fn location(function: &str) -> Location {
+        Location {
+            file: format!("src/{}.rs", function),
+            line: 1,
+            column: 0,
+            function: function.to_string(),
+        }
+    }
+
+    #[test]
+    fn sites_are_ranked_by_live_bytes_with_their_trend() {
+        let path = std::env::temp_dir().join(format!("rsprof-retained-{}.db", std::process::id()));
+        let mut storage = Storage::create(&path).unwrap();
+        // Live bytes of each site at four checkpoints
+        let sites: [(&str, [i64; 4]); 4] = [
+            ("cache", [1000, 2000, 3000, 4000]),
+            ("buffers", [8000, 8100, 7900, 8000]),
+            ("scratch", [5000, 4000, 3000, 2000]),
+            ("startup", [600, 0, 0, 0]),
+        ];
+        for checkpoint in 0..4 {
+            for (function, live) in &sites {
+                let live = live[checkpoint];
+                storage.record_heap_sample(&location(function), live, 0, live, 1, 0);
+            }
+            storage
+                .flush_checkpoint_at(1000 * (checkpoint as i64 + 1))
+                .unwrap();
+        }
+        drop(storage);
+
+        let conn = Connection::open(&path).unwrap();
+        let entries = retained_sites(&conn, 10).unwrap();
+        let trends = site_trends(&conn, &entries, 4);
+        let ranked: Vec<(&str, i64, Trend)> = entries
+            .iter()
+            .zip(&trends)
+            .map(|(e, (trend, _))| (e.function.as_str(), e.live_bytes, *trend))
+            .collect();
+        // Freed sites hold nothing, so they aren't listed
+        assert_eq!(
+            ranked,
+            [
+                ("buffers", 8000, Trend::Stable),
+                ("cache", 4000, Trend::Growing),
+                ("scratch", 2000, Trend::Shrinking),
+            ]
+        );
+        assert_eq!(trends[1].1, 3000);
+        assert_eq!(trends[2].1, -3000);
+
+        // --top keeps the largest
+        let entries = retained_sites(&conn, 1).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].function, "buffers");
+
+        drop(conn);
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", path.display(), suffix)).ok();
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use crate::error::Result;
+use crate::storage::{SCHEMA_VERSION, dump_ddl, expected_ddl, get_meta, schema_differences};
+use rusqlite::{Connection, OpenFlags};
+use std::path::Path;
+
+/// Run the schema command
+///
+/// Without a file, prints the DDL of a freshly created profile (the tables
+/// available to `rsprof query`). With a file, prints that database's DDL,
+/// its schema version and how it differs from the current schema. The file
+/// is opened read-only, so nothing is migrated.
+pub fn run(file: Option<&Path>) -> Result<()> {
+    let Some(file) = file else {
+        println!("-- rsprof profile schema v{}", SCHEMA_VERSION);
+        print_ddl(&expected_ddl()?);
+        return Ok(());
+    };
+
+    let conn = Connection::open_with_flags(file, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let version = get_meta(&conn, "version").unwrap_or(None);
+    println!("-- {}", file.display());
+    match version {
+        Some(v) => println!("-- schema v{} (current: v{})", v, SCHEMA_VERSION),
+        None => println!("-- schema version unknown (current: v{})", SCHEMA_VERSION),
+    }
+    print_ddl(&dump_ddl(&conn)?);
+
+    let diffs = schema_differences(&conn)?;
+    println!();
+    if diffs.is_empty() {
+        println!("-- Matches the current schema");
+    } else {
+        println!("-- Differences from the current schema:");
+        for diff in &diffs {
+            println!("--   {}", diff);
+        }
+        // Appending runs upgrade_tables, which creates tables and indexes newer than the file
+        if diffs.iter().any(|d| d.starts_with("missing ")) {
+            println!("-- Recording into it with --append creates the missing tables and indexes");
+        }
+    }
+    Ok(())
+}
+
+fn print_ddl(statements: &[String]) {
+    for sql in statements {
+        println!();
+        println!("{};", sql);
+    }
+}
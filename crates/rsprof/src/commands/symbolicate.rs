@@ -0,0 +1,22 @@
+use crate::error::Result;
+use crate::symbols::deferred;
+use std::path::Path;
+
+/// Resolve the raw stacks of a deferred-symbols recording in place
+///
+/// Reading commands resolve them in memory on every open; this saves that work.
+///
+/// `aslr_offset` replaces the load address stored when recording.
+pub fn run(file: &Path, aslr_offset: Option<u64>) -> Result<()> {
+    let mut conn = rusqlite::Connection::open(file)?;
+    if deferred::is_deferred(&conn)? {
+        eprintln!("Resolving symbols for {}...", file.display());
+    }
+    let resolved = deferred::symbolicate_if_deferred(&mut conn, aslr_offset)?;
+    if resolved == 0 {
+        eprintln!("{} has no unresolved stacks", file.display());
+    } else {
+        eprintln!("Resolved {} stacks in {}", resolved, file.display());
+    }
+    Ok(())
+}
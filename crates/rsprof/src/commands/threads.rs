@@ -0,0 +1,40 @@
+use crate::error::Result;
+use crate::process::{ProcessInfo, find_process_by_name, host_pid, procfs_hidden};
+
+/// Run the threads command, with PIDs and names read in the PID namespace of
+/// `pid_ns` if given
+pub fn run(target: &str, pid_ns: Option<u32>) -> Result<()> {
+    // A number that names a live process is a PID; anything else is a name pattern
+    let pid = match (target.parse::<u32>(), pid_ns) {
+        (Ok(pid), Some(holder)) => host_pid(pid, holder)?,
+        (Ok(pid), None)
+            if std::path::Path::new(&format!("/proc/{}", pid)).exists() || procfs_hidden(pid) =>
+        {
+            pid
+        }
+        _ => find_process_by_name(target, pid_ns)?,
+    };
+    let proc_info = ProcessInfo::new(pid)?;
+    let threads = proc_info.threads()?;
+
+    println!(
+        "# {} (PID {}) - {} thread(s)",
+        proc_info.name(),
+        pid,
+        threads.len()
+    );
+    println!();
+    println!("{:>8}  {:<12}  NAME", "TID", "STATE");
+    println!("{}", "-".repeat(40));
+
+    for thread in threads {
+        println!(
+            "{:>8}  {:<12}  {}",
+            thread.tid,
+            thread.state_name(),
+            thread.name
+        );
+    }
+
+    Ok(())
+}
@@ -1,7 +1,19 @@
-use crate::cli::TopMetric;
-use crate::error::Result;
-use crate::storage::{HeapEntry, query_top_cpu, query_top_heap_live};
+use crate::cli::{NameOptions, PercentBase, TopMetric};
+use crate::error::{Error, Result};
+use crate::heap::SizeDistribution;
+use crate::process::MemoryRollup;
+use crate::storage::{
+    AllocatorCpuEntry, CostEntry, GapEntry, HeapEntry, MarkerEntry, OffCpuEntry, ProcessEntry,
+    SizeEntry, StackEntry, TransferEntry, get_meta, has_table, query_cpu_freq_hz,
+    query_cpu_sample_count, query_heap_stacks, query_heap_transfers, query_sample_gaps,
+    query_top_allocator_cpu, query_top_cost, query_top_cpu, query_top_cpu_range,
+    query_top_heap_live, query_top_markers, query_top_off_cpu, query_top_processes,
+    query_top_sizes, repair_clock_skew, samples_to_secs,
+};
+use crate::symbols::{deferred, split_symbol_hash};
+use regex::Regex;
 use rusqlite::Connection;
+use std::io::Write;
 use std::path::Path;
 use std::time::Duration;
 
@@ -11,13 +23,21 @@ pub fn run(
     metric: TopMetric,
     limit: usize,
     threshold: f64,
-    _since: Option<Duration>,
-    _until: Option<Duration>,
+    since: Option<Duration>,
+    until: Option<Duration>,
     json: bool,
+    jsonl: bool,
     csv: bool,
-    _filter: Option<String>,
+    filter: &RowFilter,
+    cpu_weight: f64,
+    heap_weight: f64,
+    percent_of: PercentBase,
+    names: NameOptions,
 ) -> Result<()> {
-    let conn = Connection::open(file)?;
+    // A --no-symbols recording is resolved in memory; the file stays as recorded
+    let mut conn = deferred::open_resolved(file)?;
+    // Keep the timeline ordered if the clock stepped back while recording
+    repair_clock_skew(&mut conn)?;
 
     // Get metadata
     let duration_ms: Option<i64> = conn
@@ -34,20 +54,64 @@ pub fn run(
         )
         .unwrap_or(0);
 
+    // Filtered rows can come from anywhere in the ranking: query them all
+    // (LIMIT -1 is no limit) and keep `limit` of the matches
+    let query_limit = if filter.is_empty() { limit } else { usize::MAX };
+
     match metric {
         TopMetric::Cpu => {
-            let entries = query_top_cpu(&conn, limit, threshold)?;
+            // --since/--until pick the samples counted, --percent-of what they're a share of
+            let window = time_window(duration_ms, since, until);
+            let (entries, window) = if window.is_none() && percent_of == PercentBase::Total {
+                (query_top_cpu(&conn, query_limit, threshold)?, None)
+            } else {
+                let range = window.unwrap_or((i64::MIN, i64::MAX));
+                let window_samples = query_cpu_sample_count(&conn, range.0, range.1)?;
+                let base = match percent_of {
+                    PercentBase::Total => total_samples as u64,
+                    PercentBase::Window => window_samples,
+                };
+                let entries = query_top_cpu_range(&conn, query_limit, threshold, range, base)?;
+                (
+                    entries,
+                    Some(CpuWindow {
+                        range,
+                        samples: window_samples,
+                        percent_of,
+                    }),
+                )
+            };
+            let mut entries = entries;
+            filter.apply(&mut entries, limit, |e| vec![&e.function, &e.file]);
+            let cpu_freq = query_cpu_freq_hz(&conn);
 
-            if json {
-                print_cpu_json(file, duration_ms, total_samples, &entries);
+            if json || jsonl {
+                print_cpu_json(
+                    file,
+                    duration_ms,
+                    total_samples,
+                    cpu_freq,
+                    window,
+                    &entries,
+                    jsonl,
+                );
             } else if csv {
-                print_cpu_csv(&entries);
+                print_cpu_csv(cpu_freq, &entries);
             } else {
-                print_cpu_table(file, duration_ms, total_samples, &entries);
+                print_cpu_table(
+                    file,
+                    duration_ms,
+                    total_samples,
+                    cpu_freq,
+                    window,
+                    &entries,
+                    names,
+                );
             }
         }
         TopMetric::Heap => {
-            let entries = query_top_heap_live(&conn, limit)?;
+            let mut entries = query_top_heap_live(&conn, query_limit)?;
+            filter.apply(&mut entries, limit, |e| vec![&e.function, &e.file]);
 
             if entries.is_empty() {
                 eprintln!("No heap data found. Heap profiling requires:");
@@ -56,12 +120,205 @@ pub fn run(
                 return Ok(());
             }
 
-            if json {
-                print_heap_json(file, duration_ms, &entries);
+            let rollup = query_memory_rollup(&conn)?;
+
+            if json || jsonl {
+                print_heap_json(file, duration_ms, rollup, &entries, jsonl);
             } else if csv {
                 print_heap_csv(&entries);
             } else {
-                print_heap_table(file, duration_ms, &entries);
+                print_heap_table(file, duration_ms, rollup, &entries, names);
+                let accounting_error = get_meta(&conn, "heap_accounting_error_bytes")?
+                    .and_then(|v| v.parse::<i64>().ok())
+                    .unwrap_or(0);
+                if accounting_error > 0 {
+                    println!(
+                        "\n# {} freed without a recorded allocation (live bytes clamped to 0)",
+                        format_bytes(accounting_error)
+                    );
+                }
+            }
+        }
+        TopMetric::Markers => {
+            let mut entries = query_top_markers(&conn, query_limit).unwrap_or_default();
+            filter.apply(&mut entries, limit, |e| vec![&e.marker]);
+
+            if entries.is_empty() {
+                eprintln!(
+                    "No marker data found. Group allocations with rsprof_trace::tag(\"name\") or rsprof_trace::mark(\"name\")."
+                );
+                return Ok(());
+            }
+
+            if json || jsonl {
+                print_markers_json(file, duration_ms, &entries, jsonl);
+            } else if csv {
+                print_markers_csv(&entries);
+            } else {
+                print_markers_table(file, duration_ms, &entries);
+            }
+        }
+        TopMetric::Cost => {
+            let mut entries = query_top_cost(&conn, query_limit, cpu_weight, heap_weight)?;
+            entries.retain(|e| e.score >= threshold);
+            filter.apply(&mut entries, limit, |e| vec![&e.function, &e.file]);
+
+            if json || jsonl {
+                print_cost_json(file, duration_ms, cpu_weight, heap_weight, &entries, jsonl);
+            } else if csv {
+                print_cost_csv(&entries);
+            } else {
+                print_cost_table(file, duration_ms, cpu_weight, heap_weight, &entries, names);
+            }
+        }
+        TopMetric::Gaps => {
+            let mut entries = query_sample_gaps(&conn, query_limit, threshold)?;
+            filter.apply(&mut entries, limit, |e| vec![&e.function, &e.file]);
+
+            if json || jsonl {
+                print_gaps_json(file, duration_ms, &entries, jsonl);
+            } else if csv {
+                print_gaps_csv(&entries);
+            } else {
+                print_gaps_table(file, duration_ms, total_samples, &entries, names);
+            }
+        }
+        TopMetric::Sizes => {
+            // Profiles from older versions have no size distributions
+            let mut entries = query_top_sizes(&conn, query_limit).unwrap_or_default();
+            filter.apply(&mut entries, limit, |e| vec![&e.function, &e.file]);
+
+            if entries.is_empty() {
+                eprintln!(
+                    "No allocation size data found. Record a target built with rsprof-trace's 'heap' feature."
+                );
+                return Ok(());
+            }
+
+            if json || jsonl {
+                print_sizes_json(file, duration_ms, &entries, jsonl);
+            } else if csv {
+                print_sizes_csv(&entries);
+            } else {
+                print_sizes_table(file, duration_ms, &entries, names);
+            }
+        }
+        TopMetric::AllocCpu => {
+            // Profiles from older versions don't split out allocator samples
+            let mut entries = query_top_allocator_cpu(&conn, query_limit).unwrap_or_default();
+            entries.retain(|e| e.allocator_pct >= threshold);
+            filter.apply(&mut entries, limit, |e| vec![&e.function, &e.file]);
+
+            if entries.is_empty() {
+                eprintln!(
+                    "No allocator CPU samples found. Record a target built with rsprof-trace's 'cpu' feature (perf sampling only sees the leaf frame)."
+                );
+                return Ok(());
+            }
+
+            let cpu_freq = query_cpu_freq_hz(&conn);
+            if json || jsonl {
+                print_alloc_cpu_json(file, duration_ms, cpu_freq, &entries, jsonl);
+            } else if csv {
+                print_alloc_cpu_csv(cpu_freq, &entries);
+            } else {
+                print_alloc_cpu_table(file, duration_ms, total_samples, cpu_freq, &entries, names);
+            }
+        }
+        TopMetric::Transfers => {
+            // Profiles from older versions (or without --transfers) have no pairs
+            let mut entries = query_heap_transfers(&conn, query_limit).unwrap_or_default();
+            filter.apply(&mut entries, limit, |e| {
+                vec![
+                    &e.alloc_function,
+                    &e.alloc_file,
+                    &e.free_function,
+                    &e.free_file,
+                ]
+            });
+
+            if entries.is_empty() {
+                eprintln!(
+                    "No alloc/free site pairs found. Record with --transfers (rsprof-trace 'heap' feature)."
+                );
+                return Ok(());
+            }
+
+            if json || jsonl {
+                print_transfers_json(file, duration_ms, &entries, jsonl);
+            } else if csv {
+                print_transfers_csv(&entries);
+            } else {
+                print_transfers_table(file, duration_ms, &entries, names);
+            }
+        }
+        TopMetric::Processes => {
+            // Profiles from older versions (or without --follow-forks) have no processes
+            let mut entries = query_top_processes(&conn, query_limit).unwrap_or_default();
+            entries.retain(|e| e.percent >= threshold);
+            filter.apply(&mut entries, limit, |e| vec![&e.function, &e.file]);
+
+            if entries.is_empty() {
+                eprintln!("No per-process CPU samples found. Record with --follow-forks.");
+                return Ok(());
+            }
+
+            let cpu_freq = query_cpu_freq_hz(&conn);
+            if json || jsonl {
+                print_processes_json(file, duration_ms, cpu_freq, &entries, jsonl);
+            } else if csv {
+                print_processes_csv(cpu_freq, &entries);
+            } else {
+                print_processes_table(file, duration_ms, total_samples, cpu_freq, &entries, names);
+            }
+        }
+        TopMetric::Stacks => {
+            if !has_table(&conn, "frames")? {
+                let version = get_meta(&conn, "version")?.unwrap_or_else(|| "?".to_string());
+                eprintln!(
+                    "{} predates heap call stacks (schema v{}, stacks need v5); record it again to see them.",
+                    file.display(),
+                    version
+                );
+                return Ok(());
+            }
+
+            let mut entries = query_heap_stacks(&conn, query_limit)?;
+            filter.apply(&mut entries, limit, |e| {
+                e.frames
+                    .iter()
+                    .flat_map(|frame| [frame.function.as_str(), frame.file.as_str()])
+                    .collect()
+            });
+            if entries.is_empty() {
+                eprintln!("No heap call stacks found. Heap stacks are recorded by rsprof-trace.");
+                return Ok(());
+            }
+
+            if json || jsonl {
+                print_stacks_json(file, duration_ms, &entries, jsonl);
+            } else if csv {
+                print_stacks_csv(&entries);
+            } else {
+                print_stacks_table(file, duration_ms, &entries, names);
+            }
+        }
+        TopMetric::OffCpu => {
+            let mut entries = query_top_off_cpu(&conn, query_limit)?;
+            entries.retain(|e| e.percent >= threshold);
+            filter.apply(&mut entries, limit, |e| vec![&e.function, &e.file]);
+
+            if entries.is_empty() {
+                eprintln!("No off-CPU time found. Record with --off-cpu.");
+                return Ok(());
+            }
+
+            if json || jsonl {
+                print_off_cpu_json(file, duration_ms, &entries, jsonl);
+            } else if csv {
+                print_off_cpu_csv(&entries);
+            } else {
+                print_off_cpu_table(file, duration_ms, &entries, names);
             }
         }
     }
@@ -69,11 +326,114 @@ pub fn run(
     Ok(())
 }
 
+/// Rows `top` shows, by the functions and files in them (--filter, --exclude)
+///
+/// Patterns are substrings, or regular expressions with --regex.
+#[derive(Default)]
+pub struct RowFilter {
+    include: Option<Regex>,
+    exclude: Option<Regex>,
+}
+
+impl RowFilter {
+    pub fn new(filter: Option<&str>, exclude: Option<&str>, regex: bool) -> Result<Self> {
+        let compile = |option: &str, pattern: Option<&str>| -> Result<Option<Regex>> {
+            let Some(pattern) = pattern else {
+                return Ok(None);
+            };
+            let source = if regex {
+                pattern.to_string()
+            } else {
+                regex::escape(pattern)
+            };
+            Regex::new(&source).map(Some).map_err(|e| {
+                Error::InvalidArgument(format!("invalid {} pattern '{}': {}", option, pattern, e))
+            })
+        };
+        Ok(RowFilter {
+            include: compile("--filter", filter)?,
+            exclude: compile("--exclude", exclude)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_none() && self.exclude.is_none()
+    }
+
+    /// Keep the rows where one of `names` matches --filter and none matches
+    /// --exclude, then the first `limit` of them
+    fn apply<T>(&self, entries: &mut Vec<T>, limit: usize, names: impl Fn(&T) -> Vec<&str>) {
+        if self.is_empty() {
+            return;
+        }
+        entries.retain(|entry| {
+            let names = names(entry);
+            self.include
+                .as_ref()
+                .is_none_or(|include| names.iter().any(|name| include.is_match(name)))
+                && self
+                    .exclude
+                    .as_ref()
+                    .is_none_or(|exclude| !names.iter().any(|name| exclude.is_match(name)))
+        });
+        entries.truncate(limit);
+    }
+}
+
+/// `--jsonl`: each entry as a line of its own, flushed as it is written so
+/// a pipeline sees it straight away. Stops quietly once the reader is gone
+fn print_jsonl(lines: impl Iterator<Item = String>) {
+    let mut out = std::io::stdout().lock();
+    for line in lines {
+        if writeln!(out, "{}", line).and_then(|_| out.flush()).is_err() {
+            return;
+        }
+    }
+}
+
+/// Part of the recording `top cpu` counted samples in
+#[derive(Clone, Copy)]
+struct CpuWindow {
+    /// Checkpoint time range, ms since start (end inclusive)
+    range: (i64, i64),
+    /// CPU samples within the range
+    samples: u64,
+    percent_of: PercentBase,
+}
+
+/// Window bound in seconds, or `open` for an unbounded end
+fn format_bound(ms: i64, open: &str) -> String {
+    if ms == i64::MIN || ms == i64::MAX {
+        open.to_string()
+    } else {
+        format!("{:.1}s", ms as f64 / 1000.0)
+    }
+}
+
+/// Time range selected by --since (last N) and --until (first N), in ms
+fn time_window(
+    duration_ms: Option<i64>,
+    since: Option<Duration>,
+    until: Option<Duration>,
+) -> Option<(i64, i64)> {
+    if since.is_none() && until.is_none() {
+        return None;
+    }
+    let end_ms = until.map_or(i64::MAX, |d| d.as_millis() as i64);
+    let start_ms = since.map_or(i64::MIN, |d| {
+        (duration_ms.unwrap_or(0) - d.as_millis() as i64).max(0)
+    });
+    Some((start_ms, end_ms))
+}
+
 fn print_cpu_table(
     file: &Path,
     duration_ms: Option<i64>,
     total_samples: i64,
+    cpu_freq: Option<f64>,
+    window: Option<CpuWindow>,
     entries: &[crate::storage::CpuEntry],
+    names: NameOptions,
 ) {
     // Header comment
     println!("# {}", file.display());
@@ -81,23 +441,53 @@ fn print_cpu_table(
         let secs = ms / 1000;
         let mins = secs / 60;
         let remaining_secs = secs % 60;
+        match cpu_freq {
+            Some(freq) => println!(
+                "# Duration: {}m{:02}s | Samples: {} at {} Hz (~{} CPU)",
+                mins,
+                remaining_secs,
+                total_samples,
+                freq,
+                format_secs(samples_to_secs(total_samples as u64, freq))
+            ),
+            None => println!(
+                "# Duration: {}m{:02}s | Samples: {}",
+                mins, remaining_secs, total_samples
+            ),
+        }
+    }
+    if let Some(window) = window {
+        let (start_ms, end_ms) = window.range;
         println!(
-            "# Duration: {}m{:02}s | Samples: {}",
-            mins, remaining_secs, total_samples
+            "# Window: {}-{} | Samples: {} | CPU% of {}",
+            format_bound(start_ms, "start"),
+            format_bound(end_ms, "end"),
+            window.samples,
+            match window.percent_of {
+                PercentBase::Total => "all samples",
+                PercentBase::Window => "samples in window",
+            }
         );
     }
     println!();
 
     // Simple aligned output - LLM-friendly
-    println!("{:>6}  {:<30}  FUNCTION", "CPU%", "LOCATION");
-    println!("{}", "-".repeat(80));
+    println!(
+        "{:>6}  {:>8}  {:<30}  FUNCTION",
+        "CPU%", "CPU TIME", "LOCATION"
+    );
+    println!("{}", "-".repeat(90));
 
     for entry in entries {
         let location = format_location(&entry.file, entry.line);
-        let function = format_function(&entry.function);
+        let function = format_function(&entry.function, names);
+        let time = cpu_freq.map_or_else(
+            || "-".to_string(),
+            |freq| format_secs(samples_to_secs(entry.total_samples, freq)),
+        );
         println!(
-            "{:>5.1}%  {:<30}  {}",
-            entry.total_percent, location, function
+            "{:>5.1}%  {:>8}  {:<30}  {}",
+            entry.total_percent, time, location, function
         );
     }
 }
@@ -106,44 +496,110 @@ fn print_cpu_json(
     file: &Path,
     duration_ms: Option<i64>,
     total_samples: i64,
+    cpu_freq: Option<f64>,
+    window: Option<CpuWindow>,
     entries: &[crate::storage::CpuEntry],
+    jsonl: bool,
 ) {
+    if jsonl {
+        print_jsonl(entries.iter().map(|entry| cpu_json(entry, cpu_freq)));
+        return;
+    }
+    let cpu_secs = |samples: u64| {
+        cpu_freq.map_or_else(
+            || "null".to_string(),
+            |freq| format!("{:.3}", samples_to_secs(samples, freq)),
+        )
+    };
+
     println!("{{");
     println!("  \"file\": \"{}\",", file.display());
     if let Some(ms) = duration_ms {
         println!("  \"duration_ms\": {},", ms);
     }
+    if let Some(freq) = cpu_freq {
+        println!("  \"cpu_freq_hz\": {},", freq);
+    }
     println!("  \"total_samples\": {},", total_samples);
+    println!("  \"total_cpu_secs\": {},", cpu_secs(total_samples as u64));
+    if let Some(window) = window {
+        let (start_ms, end_ms) = window.range;
+        let json_bound = |ms: i64, open: &str| {
+            if ms == i64::MIN || ms == i64::MAX {
+                open.to_string()
+            } else {
+                ms.to_string()
+            }
+        };
+        println!(
+            "  \"window_ms\": [{}, {}],",
+            json_bound(start_ms, "null"),
+            json_bound(end_ms, "null")
+        );
+        println!("  \"window_samples\": {},", window.samples);
+    }
+    println!(
+        "  \"percent_of\": \"{}\",",
+        match window.map(|w| w.percent_of) {
+            Some(PercentBase::Window) => "window",
+            _ => "total",
+        }
+    );
     println!("  \"entries\": [");
 
     for (i, entry) in entries.iter().enumerate() {
         let comma = if i < entries.len() - 1 { "," } else { "" };
-        println!(
-            "    {{ \"cpu_pct\": {:.1}, \"file\": \"{}\", \"line\": {}, \"function\": \"{}\" }}{}",
-            entry.total_percent,
-            entry.file.replace('\\', "\\\\").replace('"', "\\\""),
-            entry.line,
-            entry.function.replace('\\', "\\\\").replace('"', "\\\""),
-            comma
-        );
+        println!("    {}{}", cpu_json(entry, cpu_freq), comma);
     }
 
     println!("  ]");
     println!("}}");
 }
 
-fn print_cpu_csv(entries: &[crate::storage::CpuEntry]) {
-    println!("cpu_pct,file,line,function");
+fn cpu_json(entry: &crate::storage::CpuEntry, cpu_freq: Option<f64>) -> String {
+    let cpu_secs = |samples: u64| {
+        cpu_freq.map_or_else(
+            || "null".to_string(),
+            |freq| format!("{:.3}", samples_to_secs(samples, freq)),
+        )
+    };
+    format!(
+        "{{ \"cpu_pct\": {:.1}, \"cpu_secs\": {}, \"file\": \"{}\", \"line\": {}, \"function\": \"{}\" }}",
+        entry.total_percent,
+        cpu_secs(entry.total_samples),
+        entry.file.replace('\\', "\\\\").replace('"', "\\\""),
+        entry.line,
+        entry.function.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+fn print_cpu_csv(cpu_freq: Option<f64>, entries: &[crate::storage::CpuEntry]) {
+    println!("cpu_pct,cpu_secs,file,line,function");
     for entry in entries {
+        let cpu_secs = cpu_freq
+            .map(|freq| format!("{:.3}", samples_to_secs(entry.total_samples, freq)))
+            .unwrap_or_default();
         println!(
-            "{:.1},{},{},\"{}\"",
-            entry.total_percent, entry.file, entry.line, entry.function
+            "{:.1},{},{},{},\"{}\"",
+            entry.total_percent, cpu_secs, entry.file, entry.line, entry.function
         );
     }
 }
 
+/// Format estimated CPU time: 850ms, 12.3s, 4m05s
+pub(crate) fn format_secs(secs: f64) -> String {
+    if secs < 1.0 {
+        format!("{:.0}ms", secs * 1000.0)
+    } else if secs < 60.0 {
+        format!("{:.1}s", secs)
+    } else {
+        let secs = secs.round() as u64;
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    }
+}
+
 /// Format a file path for display - keep the most relevant parts
-fn format_location(file: &str, line: u32) -> String {
+pub(crate) fn format_location(file: &str, line: u32) -> String {
     let simplified = simplify_path(file);
     if line > 0 {
         format!("{}:{}", simplified, line)
@@ -193,7 +649,28 @@ fn simplify_path(path: &str) -> String {
     path.rsplit('/').next().unwrap_or(path).to_string()
 }
 
-fn print_heap_table(file: &Path, duration_ms: Option<i64>, entries: &[HeapEntry]) {
+/// Resident memory at the last checkpoint, if it was read while recording
+fn query_memory_rollup(conn: &Connection) -> Result<Option<MemoryRollup>> {
+    let read = |key: &str| -> Result<Option<u64>> {
+        Ok(get_meta(conn, key)?.and_then(|v| v.parse().ok()))
+    };
+    let Some(rss_bytes) = read("rss_bytes")? else {
+        return Ok(None);
+    };
+    Ok(Some(MemoryRollup {
+        rss_bytes,
+        anon_bytes: read("anon_bytes")?.unwrap_or(0),
+        anon_huge_bytes: read("anon_huge_bytes")?.unwrap_or(0),
+    }))
+}
+
+fn print_heap_table(
+    file: &Path,
+    duration_ms: Option<i64>,
+    rollup: Option<MemoryRollup>,
+    entries: &[HeapEntry],
+    names: NameOptions,
+) {
     // Header comment
     println!("# {}", file.display());
     if let Some(ms) = duration_ms {
@@ -211,6 +688,22 @@ fn print_heap_table(file: &Path, duration_ms: Option<i64>, entries: &[HeapEntry]
             format_bytes(total_bytes)
         );
     }
+    if let Some(rollup) = rollup {
+        let mut line = format!(
+            "# RSS: {} | Anonymous: {}",
+            format_bytes(rollup.rss_bytes as i64),
+            format_bytes(rollup.anon_bytes as i64)
+        );
+        // With transparent huge pages, small allocations can pin whole 2MB pages
+        if rollup.anon_huge_bytes > 0 {
+            line.push_str(&format!(
+                " | Hugepage-backed: {} ({:.0}% of anonymous)",
+                format_bytes(rollup.anon_huge_bytes as i64),
+                rollup.anon_huge_bytes as f64 * 100.0 / rollup.anon_bytes.max(1) as f64
+            ));
+        }
+        println!("{}", line);
+    }
     println!();
 
     // Heaptrack-style output: SIZE  CALLS  LOCATION  FUNCTION
@@ -222,7 +715,7 @@ fn print_heap_table(file: &Path, duration_ms: Option<i64>, entries: &[HeapEntry]
 
     for entry in entries {
         let location = format_location(&entry.file, entry.line);
-        let function = format_function(&entry.function);
+        let function = format_function(&entry.function, names);
         let size = format_bytes(entry.total_alloc_bytes);
         let calls = format!("{} calls", format_count(entry.alloc_count));
         println!(
@@ -232,34 +725,53 @@ fn print_heap_table(file: &Path, duration_ms: Option<i64>, entries: &[HeapEntry]
     }
 }
 
-fn print_heap_json(file: &Path, duration_ms: Option<i64>, entries: &[HeapEntry]) {
+fn print_heap_json(
+    file: &Path,
+    duration_ms: Option<i64>,
+    rollup: Option<MemoryRollup>,
+    entries: &[HeapEntry],
+    jsonl: bool,
+) {
+    if jsonl {
+        print_jsonl(entries.iter().map(heap_json));
+        return;
+    }
+
     println!("{{");
     println!("  \"file\": \"{}\",", file.display());
     if let Some(ms) = duration_ms {
         println!("  \"duration_ms\": {},", ms);
     }
+    if let Some(rollup) = rollup {
+        for (key, value) in rollup.meta() {
+            println!("  \"{}\": {},", key, value);
+        }
+    }
     println!("  \"entries\": [");
 
     for (i, entry) in entries.iter().enumerate() {
         let comma = if i < entries.len() - 1 { "," } else { "" };
-        println!(
-            "    {{ \"alloc_bytes\": {}, \"alloc_count\": {}, \"free_bytes\": {}, \"free_count\": {}, \"live_bytes\": {}, \"file\": \"{}\", \"line\": {}, \"function\": \"{}\" }}{}",
-            entry.total_alloc_bytes,
-            entry.alloc_count,
-            entry.total_free_bytes,
-            entry.free_count,
-            entry.live_bytes,
-            entry.file.replace('\\', "\\\\").replace('"', "\\\""),
-            entry.line,
-            entry.function.replace('\\', "\\\\").replace('"', "\\\""),
-            comma
-        );
+        println!("    {}{}", heap_json(entry), comma);
     }
 
     println!("  ]");
     println!("}}");
 }
 
+fn heap_json(entry: &HeapEntry) -> String {
+    format!(
+        "{{ \"alloc_bytes\": {}, \"alloc_count\": {}, \"free_bytes\": {}, \"free_count\": {}, \"live_bytes\": {}, \"file\": \"{}\", \"line\": {}, \"function\": \"{}\" }}",
+        entry.total_alloc_bytes,
+        entry.alloc_count,
+        entry.total_free_bytes,
+        entry.free_count,
+        entry.live_bytes,
+        entry.file.replace('\\', "\\\\").replace('"', "\\\""),
+        entry.line,
+        entry.function.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
 fn print_heap_csv(entries: &[HeapEntry]) {
     println!("alloc_bytes,alloc_count,free_bytes,free_count,live_bytes,file,line,function");
     for entry in entries {
@@ -277,109 +789,1070 @@ fn print_heap_csv(entries: &[HeapEntry]) {
     }
 }
 
-/// Format bytes as human-readable with decimals (heaptrack style)
-fn format_bytes(bytes: i64) -> String {
-    let abs = bytes.unsigned_abs() as f64;
-    let sign = if bytes < 0 { "-" } else { "" };
-    if abs >= 1024.0 * 1024.0 * 1024.0 {
-        format!("{}{:.2}G", sign, abs / (1024.0 * 1024.0 * 1024.0))
-    } else if abs >= 1024.0 * 1024.0 {
-        format!("{}{:.2}M", sign, abs / (1024.0 * 1024.0))
-    } else if abs >= 1024.0 {
-        format!("{}{:.1}K", sign, abs / 1024.0)
-    } else {
-        format!("{}{}B", sign, bytes.unsigned_abs())
+fn print_markers_table(file: &Path, duration_ms: Option<i64>, entries: &[MarkerEntry]) {
+    // Header comment
+    println!("# {}", file.display());
+    if let Some(ms) = duration_ms {
+        let secs = ms / 1000;
+        println!("# Duration: {}m{:02}s", secs / 60, secs % 60);
     }
-}
+    println!();
 
-/// Format a number with commas for readability
-fn format_count(n: u64) -> String {
-    let s = n.to_string();
-    let mut result = String::new();
-    for (i, c) in s.chars().rev().enumerate() {
-        if i > 0 && i % 3 == 0 {
-            result.push(',');
-        }
-        result.push(c);
+    println!("{:>10}  {:>10}  {:>12}  MARKER", "SIZE", "LIVE", "CALLS");
+    println!("{}", "-".repeat(80));
+
+    for entry in entries {
+        let calls = format!("{} calls", format_count(entry.alloc_count));
+        println!(
+            "{:>10}  {:>10}  {:>12}  {}",
+            format_bytes(entry.total_alloc_bytes),
+            format_bytes(entry.live_bytes),
+            calls,
+            entry.marker
+        );
     }
-    result.chars().rev().collect()
 }
 
-/// Format a function name - remove hash suffix and simplify
-fn format_function(func: &str) -> String {
-    let mut result = func.to_string();
-
-    // Remove the hash suffix (e.g., "::h1234567890abcdef")
-    if let Some(idx) = result.rfind("::h") {
-        let suffix = &result[idx + 3..];
-        if suffix.len() == 16 && suffix.chars().all(|c| c.is_ascii_hexdigit()) {
-            result = result[..idx].to_string();
-        }
+fn print_markers_json(file: &Path, duration_ms: Option<i64>, entries: &[MarkerEntry], jsonl: bool) {
+    if jsonl {
+        print_jsonl(entries.iter().map(markers_json));
+        return;
     }
 
-    // Simplify trait impls: <Type as Trait>::method -> Type::method
-    // Pattern: <path::to::Type as path::to::Trait>::method
-    if result.starts_with('<')
-        && let Some(as_pos) = result.find(" as ")
-        && let Some(gt_pos) = result.find(">::")
-    {
-        // Extract the implementing type (between < and " as ")
-        let impl_type = &result[1..as_pos];
-        // Extract the method (after >::)
-        let method = &result[gt_pos + 3..];
-        // Simplify the type path - take last 2 components
-        let type_short = simplify_type_path(impl_type);
-        result = format!("{}::{}", type_short, method);
+    println!("{{");
+    println!("  \"file\": \"{}\",", file.display());
+    if let Some(ms) = duration_ms {
+        println!("  \"duration_ms\": {},", ms);
     }
+    println!("  \"entries\": [");
 
-    // Simplify common prefixes
-    let prefixes_to_shorten = [
-        ("core::slice::sort::", "sort::"),
-        ("core::ptr::", "ptr::"),
-        ("core::fmt::", "fmt::"),
-        ("core::iter::", "iter::"),
-        ("core::hash::", "hash::"),
-        ("core::str::", "str::"),
-        ("core::num::", "num::"),
-        ("alloc::vec::", "Vec::"),
-        ("alloc::string::", "String::"),
-        ("alloc::alloc::", "alloc::"),
-        ("hashbrown::raw::", "hashbrown::"),
-        ("std::collections::hash_map::", "HashMap::"),
-    ];
-
-    for (prefix, replacement) in prefixes_to_shorten {
-        if result.starts_with(prefix) {
-            result = format!("{}{}", replacement, &result[prefix.len()..]);
-            break;
-        }
+    for (i, entry) in entries.iter().enumerate() {
+        let comma = if i < entries.len() - 1 { "," } else { "" };
+        println!("    {}{}", markers_json(entry), comma);
     }
 
-    // Remove <...> generic parameters for readability
-    while let (Some(start), Some(end)) = (result.find('<'), result.rfind('>')) {
-        if start < end {
-            // Check if it's simple enough to keep
-            let generic = &result[start..=end];
-            if generic.len() > 20 || generic.contains("::") {
-                result = format!("{}<_>{}", &result[..start], &result[end + 1..]);
-            } else {
-                break;
-            }
-        } else {
-            break;
-        }
-    }
+    println!("  ]");
+    println!("}}");
+}
 
-    result
+fn markers_json(entry: &MarkerEntry) -> String {
+    format!(
+        "{{ \"marker\": \"{}\", \"alloc_bytes\": {}, \"alloc_count\": {}, \"free_bytes\": {}, \"free_count\": {}, \"live_bytes\": {} }}",
+        entry.marker.replace('\\', "\\\\").replace('"', "\\\""),
+        entry.total_alloc_bytes,
+        entry.alloc_count,
+        entry.total_free_bytes,
+        entry.free_count,
+        entry.live_bytes
+    )
 }
 
-/// Simplify a type path to module::Type format
-fn simplify_type_path(path: &str) -> String {
-    let parts: Vec<&str> = path.split("::").collect();
-    if parts.len() >= 2 {
+fn print_markers_csv(entries: &[MarkerEntry]) {
+    println!("marker,alloc_bytes,alloc_count,free_bytes,free_count,live_bytes");
+    for entry in entries {
+        println!(
+            "\"{}\",{},{},{},{},{}",
+            entry.marker,
+            entry.total_alloc_bytes,
+            entry.alloc_count,
+            entry.total_free_bytes,
+            entry.free_count,
+            entry.live_bytes
+        );
+    }
+}
+
+fn print_sizes_table(
+    file: &Path,
+    duration_ms: Option<i64>,
+    entries: &[SizeEntry],
+    names: NameOptions,
+) {
+    // Header comment
+    println!("# {}", file.display());
+    if let Some(ms) = duration_ms {
+        let secs = ms / 1000;
+        println!("# Duration: {}m{:02}s", secs / 60, secs % 60);
+    }
+    println!("# Sizes are bucketed by power of two; ~ marks an estimate within a bucket");
+    println!();
+
+    println!(
+        "{:>12}  {:>8}  {:>9}  {:>15}  {:>8}  {:<30}  FUNCTION",
+        "ALLOCS", "MIN", "MEDIAN", "MODE", "MAX", "LOCATION"
+    );
+    println!("{}", "-".repeat(100));
+
+    for entry in entries {
+        let sizes = &entry.sizes;
+        println!(
+            "{:>12}  {:>8}  {:>9}  {:>15}  {:>8}  {:<30}  {}",
+            format_count(sizes.count()),
+            format_bytes(sizes.min as i64),
+            format_median(sizes),
+            format_mode(sizes),
+            format_bytes(sizes.max as i64),
+            format_location(&entry.file, entry.line),
+            format_function(&entry.function, names)
+        );
+    }
+}
+
+fn print_sizes_json(file: &Path, duration_ms: Option<i64>, entries: &[SizeEntry], jsonl: bool) {
+    if jsonl {
+        print_jsonl(entries.iter().map(sizes_json));
+        return;
+    }
+
+    println!("{{");
+    println!("  \"file\": \"{}\",", file.display());
+    if let Some(ms) = duration_ms {
+        println!("  \"duration_ms\": {},", ms);
+    }
+    println!("  \"entries\": [");
+
+    for (i, entry) in entries.iter().enumerate() {
+        let comma = if i < entries.len() - 1 { "," } else { "" };
+        println!("    {}{}", sizes_json(entry), comma);
+    }
+
+    println!("  ]");
+    println!("}}");
+}
+
+fn sizes_json(entry: &SizeEntry) -> String {
+    let sizes = &entry.sizes;
+    let (mode_low, mode_high) = sizes.mode().unwrap_or_default();
+    let buckets = sizes
+        .buckets
+        .iter()
+        .enumerate()
+        .filter(|&(_, &n)| n > 0)
+        .map(|(bucket, n)| {
+            let (low, high) = sizes.bucket_range(bucket);
+            format!(
+                "{{ \"low\": {}, \"high\": {}, \"count\": {} }}",
+                low, high, n
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        "{{ \"alloc_count\": {}, \"min\": {}, \"median\": {}, \"mode_low\": {}, \"mode_high\": {}, \"max\": {}, \"buckets\": [{}], \"file\": \"{}\", \"line\": {}, \"function\": \"{}\" }}",
+        sizes.count(),
+        sizes.min,
+        sizes.median().unwrap_or(0),
+        mode_low,
+        mode_high,
+        sizes.max,
+        buckets,
+        entry.file.replace('\\', "\\\\").replace('"', "\\\""),
+        entry.line,
+        entry.function.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+fn print_sizes_csv(entries: &[SizeEntry]) {
+    println!("alloc_count,min,median,mode_low,mode_high,max,file,line,function");
+    for entry in entries {
+        let sizes = &entry.sizes;
+        let (mode_low, mode_high) = sizes.mode().unwrap_or_default();
+        println!(
+            "{},{},{},{},{},{},{},{},\"{}\"",
+            sizes.count(),
+            sizes.min,
+            sizes.median().unwrap_or(0),
+            mode_low,
+            mode_high,
+            sizes.max,
+            entry.file,
+            entry.line,
+            entry.function
+        );
+    }
+}
+
+fn print_alloc_cpu_table(
+    file: &Path,
+    duration_ms: Option<i64>,
+    total_samples: i64,
+    cpu_freq: Option<f64>,
+    entries: &[AllocatorCpuEntry],
+    names: NameOptions,
+) {
+    // Header comment
+    println!("# {}", file.display());
+    if let Some(ms) = duration_ms {
+        let secs = ms / 1000;
+        println!(
+            "# Duration: {}m{:02}s | Samples: {}",
+            secs / 60,
+            secs % 60,
+            total_samples
+        );
+    }
+    println!("# ALLOC CPU%: share of all samples spent in the allocator for the site");
+    println!();
+
+    println!(
+        "{:>10}  {:>10}  {:>9}  {:>12}  {:>9}  {:<30}  FUNCTION",
+        "ALLOC CPU%", "ALLOC TIME", "PER ALLOC", "ALLOCS", "ALLOCATED", "LOCATION"
+    );
+    println!("{}", "-".repeat(110));
+
+    for entry in entries {
+        let time = cpu_freq.map_or_else(
+            || "-".to_string(),
+            |freq| format_secs(samples_to_secs(entry.allocator_samples, freq)),
+        );
+        let per_alloc = match (cpu_freq, entry.alloc_count) {
+            (Some(freq), count) if count > 0 => {
+                format_per_alloc(samples_to_secs(entry.allocator_samples, freq) / count as f64)
+            }
+            _ => "-".to_string(),
+        };
+        println!(
+            "{:>9.1}%  {:>10}  {:>9}  {:>12}  {:>9}  {:<30}  {}",
+            entry.allocator_pct,
+            time,
+            per_alloc,
+            format_count(entry.alloc_count),
+            format_bytes(entry.alloc_bytes),
+            format_location(&entry.file, entry.line),
+            format_function(&entry.function, names)
+        );
+    }
+}
+
+fn print_alloc_cpu_json(
+    file: &Path,
+    duration_ms: Option<i64>,
+    cpu_freq: Option<f64>,
+    entries: &[AllocatorCpuEntry],
+    jsonl: bool,
+) {
+    if jsonl {
+        print_jsonl(entries.iter().map(|entry| alloc_cpu_json(entry, cpu_freq)));
+        return;
+    }
+
+    println!("{{");
+    println!("  \"file\": \"{}\",", file.display());
+    if let Some(ms) = duration_ms {
+        println!("  \"duration_ms\": {},", ms);
+    }
+    if let Some(freq) = cpu_freq {
+        println!("  \"cpu_freq_hz\": {},", freq);
+    }
+    println!("  \"entries\": [");
+
+    for (i, entry) in entries.iter().enumerate() {
+        let comma = if i < entries.len() - 1 { "," } else { "" };
+        println!("    {}{}", alloc_cpu_json(entry, cpu_freq), comma);
+    }
+
+    println!("  ]");
+    println!("}}");
+}
+
+fn alloc_cpu_json(entry: &AllocatorCpuEntry, cpu_freq: Option<f64>) -> String {
+    let cpu_secs = |samples: u64| {
+        cpu_freq.map_or_else(
+            || "null".to_string(),
+            |freq| format!("{:.3}", samples_to_secs(samples, freq)),
+        )
+    };
+    format!(
+        "{{ \"alloc_cpu_pct\": {:.1}, \"alloc_cpu_secs\": {}, \"allocator_samples\": {}, \"cpu_samples\": {}, \"alloc_count\": {}, \"alloc_bytes\": {}, \"file\": \"{}\", \"line\": {}, \"function\": \"{}\" }}",
+        entry.allocator_pct,
+        cpu_secs(entry.allocator_samples),
+        entry.allocator_samples,
+        entry.cpu_samples,
+        entry.alloc_count,
+        entry.alloc_bytes,
+        entry.file.replace('\\', "\\\\").replace('"', "\\\""),
+        entry.line,
+        entry.function.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+fn print_alloc_cpu_csv(cpu_freq: Option<f64>, entries: &[AllocatorCpuEntry]) {
+    println!(
+        "alloc_cpu_pct,alloc_cpu_secs,allocator_samples,alloc_count,alloc_bytes,file,line,function"
+    );
+    for entry in entries {
+        let cpu_secs = cpu_freq
+            .map(|freq| format!("{:.3}", samples_to_secs(entry.allocator_samples, freq)))
+            .unwrap_or_default();
+        println!(
+            "{:.1},{},{},{},{},{},{},\"{}\"",
+            entry.allocator_pct,
+            cpu_secs,
+            entry.allocator_samples,
+            entry.alloc_count,
+            entry.alloc_bytes,
+            entry.file,
+            entry.line,
+            entry.function
+        );
+    }
+}
+
+fn print_transfers_table(
+    file: &Path,
+    duration_ms: Option<i64>,
+    entries: &[TransferEntry],
+    names: NameOptions,
+) {
+    // Header comment
+    println!("# {}", file.display());
+    if let Some(ms) = duration_ms {
+        let secs = ms / 1000;
+        println!("# Duration: {}m{:02}s", secs / 60, secs % 60);
+    }
+    println!("# Memory allocated at one location and freed at another; * = crosses modules");
+    println!();
+
+    println!(
+        "{:>9}  {:>12}     {:<30}  {:<30}  FUNCTIONS",
+        "FREED", "FREES", "ALLOCATED AT", "FREED AT"
+    );
+    println!("{}", "-".repeat(110));
+
+    for entry in entries {
+        println!(
+            "{:>9}  {:>12}  {}  {:<30}  {:<30}  {} -> {}",
+            format_bytes(entry.bytes),
+            format_count(entry.count),
+            if entry.crosses_module() { "*" } else { " " },
+            format_location(&entry.alloc_file, entry.alloc_line),
+            format_location(&entry.free_file, entry.free_line),
+            format_function(&entry.alloc_function, names),
+            format_function(&entry.free_function, names)
+        );
+    }
+}
+
+fn print_transfers_json(
+    file: &Path,
+    duration_ms: Option<i64>,
+    entries: &[TransferEntry],
+    jsonl: bool,
+) {
+    if jsonl {
+        print_jsonl(entries.iter().map(transfers_json));
+        return;
+    }
+
+    println!("{{");
+    println!("  \"file\": \"{}\",", file.display());
+    if let Some(ms) = duration_ms {
+        println!("  \"duration_ms\": {},", ms);
+    }
+    println!("  \"entries\": [");
+
+    for (i, entry) in entries.iter().enumerate() {
+        let comma = if i < entries.len() - 1 { "," } else { "" };
+        println!("    {}{}", transfers_json(entry), comma);
+    }
+
+    println!("  ]");
+    println!("}}");
+}
+
+fn transfers_json(entry: &TransferEntry) -> String {
+    format!(
+        "{{ \"bytes\": {}, \"count\": {}, \"crosses_module\": {}, \"alloc\": {{ \"file\": \"{}\", \"line\": {}, \"function\": \"{}\" }}, \"free\": {{ \"file\": \"{}\", \"line\": {}, \"function\": \"{}\" }} }}",
+        entry.bytes,
+        entry.count,
+        entry.crosses_module(),
+        entry.alloc_file.replace('\\', "\\\\").replace('"', "\\\""),
+        entry.alloc_line,
+        entry
+            .alloc_function
+            .replace('\\', "\\\\")
+            .replace('"', "\\\""),
+        entry.free_file.replace('\\', "\\\\").replace('"', "\\\""),
+        entry.free_line,
+        entry
+            .free_function
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+    )
+}
+
+fn print_transfers_csv(entries: &[TransferEntry]) {
+    println!(
+        "bytes,count,crosses_module,alloc_file,alloc_line,alloc_function,free_file,free_line,free_function"
+    );
+    for entry in entries {
+        println!(
+            "{},{},{},{},{},\"{}\",{},{},\"{}\"",
+            entry.bytes,
+            entry.count,
+            entry.crosses_module(),
+            entry.alloc_file,
+            entry.alloc_line,
+            entry.alloc_function,
+            entry.free_file,
+            entry.free_line,
+            entry.free_function
+        );
+    }
+}
+
+fn print_stacks_table(
+    file: &Path,
+    duration_ms: Option<i64>,
+    entries: &[StackEntry],
+    names: NameOptions,
+) {
+    // Header comment
+    println!("# {}", file.display());
+    if let Some(ms) = duration_ms {
+        let secs = ms / 1000;
+        println!("# Duration: {}m{:02}s", secs / 60, secs % 60);
+    }
+    println!("# Heap per call stack: allocation site first, then its callers");
+    println!();
+
+    println!(
+        "{:>10}  {:>10}  {:>12}  {:<30}  FUNCTION",
+        "LIVE", "SIZE", "CALLS", "LOCATION"
+    );
+    println!("{}", "-".repeat(100));
+
+    for entry in entries {
+        for (depth, frame) in entry.frames.iter().enumerate() {
+            let location = format_location(&frame.file, frame.line);
+            let function = format_function(&frame.function, names);
+            if depth == 0 {
+                println!(
+                    "{:>10}  {:>10}  {:>12}  {:<30}  {}",
+                    format_bytes(entry.live_bytes),
+                    format_bytes(entry.total_alloc_bytes),
+                    format!("{} calls", format_count(entry.alloc_count)),
+                    location,
+                    function
+                );
+            } else {
+                println!("{:>38}{:<30}  {}", "", location, function);
+            }
+        }
+    }
+}
+
+fn print_stacks_json(file: &Path, duration_ms: Option<i64>, entries: &[StackEntry], jsonl: bool) {
+    if jsonl {
+        print_jsonl(entries.iter().map(stacks_json));
+        return;
+    }
+
+    println!("{{");
+    println!("  \"file\": \"{}\",", file.display());
+    if let Some(ms) = duration_ms {
+        println!("  \"duration_ms\": {},", ms);
+    }
+    println!("  \"entries\": [");
+
+    for (i, entry) in entries.iter().enumerate() {
+        let comma = if i < entries.len() - 1 { "," } else { "" };
+        println!("    {}{}", stacks_json(entry), comma);
+    }
+
+    println!("  ]");
+    println!("}}");
+}
+
+fn stacks_json(entry: &StackEntry) -> String {
+    let frames = entry
+        .frames
+        .iter()
+        .map(|frame| {
+            format!(
+                "{{ \"file\": \"{}\", \"line\": {}, \"function\": \"{}\" }}",
+                frame.file.replace('\\', "\\\\").replace('"', "\\\""),
+                frame.line,
+                frame.function.replace('\\', "\\\\").replace('"', "\\\"")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        "{{ \"live_bytes\": {}, \"alloc_bytes\": {}, \"alloc_count\": {}, \"frames\": [{}] }}",
+        entry.live_bytes, entry.total_alloc_bytes, entry.alloc_count, frames
+    )
+}
+
+/// One row per frame; `stack` numbers the stacks in rank order
+fn print_stacks_csv(entries: &[StackEntry]) {
+    println!("stack,live_bytes,alloc_bytes,alloc_count,depth,file,line,function");
+    for (i, entry) in entries.iter().enumerate() {
+        for (depth, frame) in entry.frames.iter().enumerate() {
+            println!(
+                "{},{},{},{},{},{},{},\"{}\"",
+                i + 1,
+                entry.live_bytes,
+                entry.total_alloc_bytes,
+                entry.alloc_count,
+                depth,
+                frame.file,
+                frame.line,
+                frame.function
+            );
+        }
+    }
+}
+
+fn print_processes_table(
+    file: &Path,
+    duration_ms: Option<i64>,
+    total_samples: i64,
+    cpu_freq: Option<f64>,
+    entries: &[ProcessEntry],
+    names: NameOptions,
+) {
+    // Header comment
+    println!("# {}", file.display());
+    if let Some(ms) = duration_ms {
+        let secs = ms / 1000;
+        println!("# Duration: {}m{:02}s", secs / 60, secs % 60);
+    }
+    println!("# Total samples: {}", total_samples);
+    println!("# CPU samples per process; HOTTEST is each process's top location");
+    println!();
+
+    println!(
+        "{:>8}  {:>6}  {:>9}  {:>8}  {:<30}  FUNCTION",
+        "PID", "CPU%", "SAMPLES", "TIME", "HOTTEST"
+    );
+    println!("{}", "-".repeat(100));
+
+    for entry in entries {
+        let time = cpu_freq
+            .map(|freq| format_secs(samples_to_secs(entry.samples, freq)))
+            .unwrap_or_else(|| "-".to_string());
+        println!(
+            "{:>8}  {:>5.1}%  {:>9}  {:>8}  {:<30}  {}",
+            entry.process_id,
+            entry.percent,
+            entry.samples,
+            time,
+            format_location(&entry.file, entry.line),
+            format_function(&entry.function, names)
+        );
+    }
+}
+
+fn print_processes_json(
+    file: &Path,
+    duration_ms: Option<i64>,
+    cpu_freq: Option<f64>,
+    entries: &[ProcessEntry],
+    jsonl: bool,
+) {
+    if jsonl {
+        print_jsonl(entries.iter().map(|entry| processes_json(entry, cpu_freq)));
+        return;
+    }
+
+    println!("{{");
+    println!("  \"file\": \"{}\",", file.display());
+    if let Some(ms) = duration_ms {
+        println!("  \"duration_ms\": {},", ms);
+    }
+    println!("  \"entries\": [");
+
+    for (i, entry) in entries.iter().enumerate() {
+        let comma = if i < entries.len() - 1 { "," } else { "" };
+        println!("    {}{}", processes_json(entry, cpu_freq), comma);
+    }
+
+    println!("  ]");
+    println!("}}");
+}
+
+fn processes_json(entry: &ProcessEntry, cpu_freq: Option<f64>) -> String {
+    let secs = cpu_freq
+        .map(|freq| format!("{:.6}", samples_to_secs(entry.samples, freq)))
+        .unwrap_or_else(|| "null".to_string());
+    format!(
+        "{{ \"process_id\": {}, \"samples\": {}, \"percent\": {:.2}, \"cpu_secs\": {}, \"hottest\": {{ \"file\": \"{}\", \"line\": {}, \"function\": \"{}\" }} }}",
+        entry.process_id,
+        entry.samples,
+        entry.percent,
+        secs,
+        entry.file.replace('\\', "\\\\").replace('"', "\\\""),
+        entry.line,
+        entry.function.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+fn print_processes_csv(cpu_freq: Option<f64>, entries: &[ProcessEntry]) {
+    println!("process_id,samples,percent,cpu_secs,file,line,function");
+    for entry in entries {
+        let secs = cpu_freq
+            .map(|freq| format!("{:.6}", samples_to_secs(entry.samples, freq)))
+            .unwrap_or_default();
+        println!(
+            "{},{},{:.2},{},{},{},\"{}\"",
+            entry.process_id,
+            entry.samples,
+            entry.percent,
+            secs,
+            entry.file,
+            entry.line,
+            entry.function
+        );
+    }
+}
+
+fn print_off_cpu_table(
+    file: &Path,
+    duration_ms: Option<i64>,
+    entries: &[OffCpuEntry],
+    names: NameOptions,
+) {
+    // Header comment
+    println!("# {}", file.display());
+    if let Some(ms) = duration_ms {
+        let secs = ms / 1000;
+        println!("# Duration: {}m{:02}s", secs / 60, secs % 60);
+    }
+    println!("# Time threads spent blocked (off-CPU), summed over threads");
+    println!();
+
+    println!(
+        "{:>6}  {:>9}  {:>9}  {:>9}  {:<30}  FUNCTION",
+        "OFF%", "BLOCKED", "SWITCHES", "AVG", "LOCATION"
+    );
+    println!("{}", "-".repeat(100));
+
+    for entry in entries {
+        let blocked = entry.blocked_ns as f64 / 1e9;
+        let avg = if entry.switches > 0 {
+            format_per_alloc(blocked / entry.switches as f64)
+        } else {
+            "-".to_string()
+        };
+        println!(
+            "{:>5.1}%  {:>9}  {:>9}  {:>9}  {:<30}  {}",
+            entry.percent,
+            format_secs(blocked),
+            format_count(entry.switches),
+            avg,
+            format_location(&entry.file, entry.line),
+            format_function(&entry.function, names)
+        );
+    }
+}
+
+fn print_off_cpu_json(file: &Path, duration_ms: Option<i64>, entries: &[OffCpuEntry], jsonl: bool) {
+    if jsonl {
+        print_jsonl(entries.iter().map(off_cpu_json));
+        return;
+    }
+
+    println!("{{");
+    println!("  \"file\": \"{}\",", file.display());
+    if let Some(ms) = duration_ms {
+        println!("  \"duration_ms\": {},", ms);
+    }
+    println!("  \"entries\": [");
+
+    for (i, entry) in entries.iter().enumerate() {
+        let comma = if i < entries.len() - 1 { "," } else { "" };
+        println!("    {}{}", off_cpu_json(entry), comma);
+    }
+
+    println!("  ]");
+    println!("}}");
+}
+
+fn off_cpu_json(entry: &OffCpuEntry) -> String {
+    format!(
+        "{{ \"off_cpu_pct\": {:.2}, \"blocked_ns\": {}, \"switches\": {}, \"file\": \"{}\", \"line\": {}, \"function\": \"{}\" }}",
+        entry.percent,
+        entry.blocked_ns,
+        entry.switches,
+        entry.file.replace('\\', "\\\\").replace('"', "\\\""),
+        entry.line,
+        entry.function.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+fn print_off_cpu_csv(entries: &[OffCpuEntry]) {
+    println!("off_cpu_pct,blocked_ns,switches,file,line,function");
+    for entry in entries {
+        println!(
+            "{:.2},{},{},{},{},\"{}\"",
+            entry.percent, entry.blocked_ns, entry.switches, entry.file, entry.line, entry.function
+        );
+    }
+}
+
+/// Format a short time, such as the average allocator time of one call: 85ns, 1.2us, 3.4ms
+fn format_per_alloc(secs: f64) -> String {
+    if secs < 1e-6 {
+        format!("{:.0}ns", secs * 1e9)
+    } else if secs < 1e-3 {
+        format!("{:.1}us", secs * 1e6)
+    } else {
+        format!("{:.1}ms", secs * 1e3)
+    }
+}
+
+/// Median size, prefixed with ~ unless every allocation had the same size
+fn format_median(sizes: &SizeDistribution) -> String {
+    let median = format_bytes(sizes.median().unwrap_or(0) as i64);
+    if sizes.min == sizes.max {
+        median
+    } else {
+        format!("~{}", median)
+    }
+}
+
+/// Most common size bucket as a range (a single size when it is exact)
+fn format_mode(sizes: &SizeDistribution) -> String {
+    match sizes.mode() {
+        Some((low, high)) if low == high => format_bytes(low as i64),
+        Some((low, high)) => format!("{}-{}", format_bytes(low as i64), format_bytes(high as i64)),
+        None => "-".to_string(),
+    }
+}
+
+fn print_cost_table(
+    file: &Path,
+    duration_ms: Option<i64>,
+    cpu_weight: f64,
+    heap_weight: f64,
+    entries: &[CostEntry],
+    names: NameOptions,
+) {
+    // Header comment
+    println!("# {}", file.display());
+    if let Some(ms) = duration_ms {
+        let secs = ms / 1000;
+        println!(
+            "# Duration: {}m{:02}s | Score: {} x CPU% + {} x ALLOC%",
+            secs / 60,
+            secs % 60,
+            cpu_weight,
+            heap_weight
+        );
+    }
+    println!();
+
+    println!(
+        "{:>7}  {:>6}  {:>6}  {:<30}  FUNCTION",
+        "SCORE", "CPU%", "ALLOC%", "LOCATION"
+    );
+    println!("{}", "-".repeat(80));
+
+    for entry in entries {
+        let location = format_location(&entry.file, entry.line);
+        let function = format_function(&entry.function, names);
+        println!(
+            "{:>7.1}  {:>5.1}%  {:>5.1}%  {:<30}  {}",
+            entry.score, entry.cpu_pct, entry.heap_pct, location, function
+        );
+    }
+}
+
+fn print_cost_json(
+    file: &Path,
+    duration_ms: Option<i64>,
+    cpu_weight: f64,
+    heap_weight: f64,
+    entries: &[CostEntry],
+    jsonl: bool,
+) {
+    if jsonl {
+        print_jsonl(entries.iter().map(cost_json));
+        return;
+    }
+
+    println!("{{");
+    println!("  \"file\": \"{}\",", file.display());
+    if let Some(ms) = duration_ms {
+        println!("  \"duration_ms\": {},", ms);
+    }
+    println!("  \"cpu_weight\": {},", cpu_weight);
+    println!("  \"heap_weight\": {},", heap_weight);
+    println!("  \"entries\": [");
+
+    for (i, entry) in entries.iter().enumerate() {
+        let comma = if i < entries.len() - 1 { "," } else { "" };
+        println!("    {}{}", cost_json(entry), comma);
+    }
+
+    println!("  ]");
+    println!("}}");
+}
+
+fn cost_json(entry: &CostEntry) -> String {
+    format!(
+        "{{ \"score\": {:.1}, \"cpu_pct\": {:.1}, \"alloc_pct\": {:.1}, \"alloc_bytes\": {}, \"file\": \"{}\", \"line\": {}, \"function\": \"{}\" }}",
+        entry.score,
+        entry.cpu_pct,
+        entry.heap_pct,
+        entry.alloc_bytes,
+        entry.file.replace('\\', "\\\\").replace('"', "\\\""),
+        entry.line,
+        entry.function.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+fn print_gaps_table(
+    file: &Path,
+    duration_ms: Option<i64>,
+    total_samples: i64,
+    entries: &[GapEntry],
+    names: NameOptions,
+) {
+    // Header comment
+    println!("# {}", file.display());
+    if let Some(ms) = duration_ms {
+        let secs = ms / 1000;
+        println!(
+            "# Duration: {}m{:02}s | Samples: {} | Gaps: time in checkpoints without samples",
+            secs / 60,
+            secs % 60,
+            total_samples
+        );
+    }
+    println!();
+
+    println!(
+        "{:>8}  {:>8}  {:>8}  {:>6}  {:<30}  FUNCTION",
+        "P50", "P90", "P99", "CPU%", "LOCATION"
+    );
+    println!("{}", "-".repeat(80));
+
+    for entry in entries {
+        let location = format_location(&entry.file, entry.line);
+        let function = format_function(&entry.function, names);
+        println!(
+            "{:>8}  {:>8}  {:>8}  {:>5.1}%  {:<30}  {}",
+            format_ms(entry.p50_ms),
+            format_ms(entry.p90_ms),
+            format_ms(entry.p99_ms),
+            entry.cpu_pct,
+            location,
+            function
+        );
+    }
+}
+
+fn print_gaps_json(file: &Path, duration_ms: Option<i64>, entries: &[GapEntry], jsonl: bool) {
+    if jsonl {
+        print_jsonl(entries.iter().map(gaps_json));
+        return;
+    }
+
+    println!("{{");
+    println!("  \"file\": \"{}\",", file.display());
+    if let Some(ms) = duration_ms {
+        println!("  \"duration_ms\": {},", ms);
+    }
+    println!("  \"entries\": [");
+
+    for (i, entry) in entries.iter().enumerate() {
+        let comma = if i < entries.len() - 1 { "," } else { "" };
+        println!("    {}{}", gaps_json(entry), comma);
+    }
+
+    println!("  ]");
+    println!("}}");
+}
+
+fn gaps_json(entry: &GapEntry) -> String {
+    format!(
+        "{{ \"p50_ms\": {:.1}, \"p90_ms\": {:.1}, \"p99_ms\": {:.1}, \"samples\": {}, \"cpu_pct\": {:.1}, \"file\": \"{}\", \"line\": {}, \"function\": \"{}\" }}",
+        entry.p50_ms,
+        entry.p90_ms,
+        entry.p99_ms,
+        entry.samples,
+        entry.cpu_pct,
+        entry.file.replace('\\', "\\\\").replace('"', "\\\""),
+        entry.line,
+        entry.function.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+fn print_gaps_csv(entries: &[GapEntry]) {
+    println!("p50_ms,p90_ms,p99_ms,samples,cpu_pct,file,line,function");
+    for entry in entries {
+        println!(
+            "{:.1},{:.1},{:.1},{},{:.1},{},{},\"{}\"",
+            entry.p50_ms,
+            entry.p90_ms,
+            entry.p99_ms,
+            entry.samples,
+            entry.cpu_pct,
+            entry.file,
+            entry.line,
+            entry.function
+        );
+    }
+}
+
+/// Format a gap for display: milliseconds below 10s, seconds above
+fn format_ms(ms: f64) -> String {
+    if ms >= 10_000.0 {
+        format!("{:.1}s", ms / 1000.0)
+    } else {
+        format!("{:.1}ms", ms)
+    }
+}
+
+fn print_cost_csv(entries: &[CostEntry]) {
+    println!("score,cpu_pct,alloc_pct,alloc_bytes,file,line,function");
+    for entry in entries {
+        println!(
+            "{:.1},{:.1},{:.1},{},{},{},\"{}\"",
+            entry.score,
+            entry.cpu_pct,
+            entry.heap_pct,
+            entry.alloc_bytes,
+            entry.file,
+            entry.line,
+            entry.function
+        );
+    }
+}
+
+/// Format bytes as human-readable with decimals (heaptrack style)
+pub(crate) fn format_bytes(bytes: i64) -> String {
+    let abs = bytes.unsigned_abs() as f64;
+    let sign = if bytes < 0 { "-" } else { "" };
+    if abs >= 1024.0 * 1024.0 * 1024.0 {
+        format!("{}{:.2}G", sign, abs / (1024.0 * 1024.0 * 1024.0))
+    } else if abs >= 1024.0 * 1024.0 {
+        format!("{}{:.2}M", sign, abs / (1024.0 * 1024.0))
+    } else if abs >= 1024.0 {
+        format!("{}{:.1}K", sign, abs / 1024.0)
+    } else {
+        format!("{}{}B", sign, bytes.unsigned_abs())
+    }
+}
+
+/// Format a number with commas for readability
+fn format_count(n: u64) -> String {
+    let s = n.to_string();
+    let mut result = String::new();
+    for (i, c) in s.chars().rev().enumerate() {
+        if i > 0 && i % 3 == 0 {
+            result.push(',');
+        }
+        result.push(c);
+    }
+    result.chars().rev().collect()
+}
+
+/// Format a function name - remove hash suffix and simplify
+///
+/// `names` turns off hash removal and generic collapsing independently.
+pub(crate) fn format_function(func: &str, names: NameOptions) -> String {
+    // Split off the hash suffix (e.g., "::h1234567890abcdef")
+    let (name, hash) = split_symbol_hash(func);
+    let mut result = name.to_string();
+
+    // Simplify trait impls: <Type as Trait>::method -> Type::method
+    // Pattern: <path::to::Type as path::to::Trait>::method
+    if result.starts_with('<')
+        && let Some(as_pos) = result.find(" as ")
+        && let Some(gt_pos) = result.find(">::")
+    {
+        // Extract the implementing type (between < and " as ")
+        let impl_type = &result[1..as_pos];
+        // Extract the method (after >::)
+        let method = &result[gt_pos + 3..];
+        // Simplify the type path - take last 2 components
+        let type_short = simplify_type_path(impl_type);
+        result = format!("{}::{}", type_short, method);
+    }
+
+    // Simplify common prefixes
+    let prefixes_to_shorten = [
+        ("core::slice::sort::", "sort::"),
+        ("core::ptr::", "ptr::"),
+        ("core::fmt::", "fmt::"),
+        ("core::iter::", "iter::"),
+        ("core::hash::", "hash::"),
+        ("core::str::", "str::"),
+        ("core::num::", "num::"),
+        ("alloc::vec::", "Vec::"),
+        ("alloc::string::", "String::"),
+        ("alloc::alloc::", "alloc::"),
+        ("hashbrown::raw::", "hashbrown::"),
+        ("std::collections::hash_map::", "HashMap::"),
+    ];
+
+    for (prefix, replacement) in prefixes_to_shorten {
+        if result.starts_with(prefix) {
+            result = format!("{}{}", replacement, &result[prefix.len()..]);
+            break;
+        }
+    }
+
+    // Remove <...> generic parameters for readability
+    while !names.keep_generics
+        && let (Some(start), Some(end)) = (result.find('<'), result.rfind('>'))
+    {
+        if start < end {
+            // Check if it's simple enough to keep
+            let generic = &result[start..=end];
+            if generic.len() > 20 || generic.contains("::") {
+                result = format!("{}<_>{}", &result[..start], &result[end + 1..]);
+            } else {
+                break;
+            }
+        } else {
+            break;
+        }
+    }
+
+    if names.keep_hash {
+        result.push_str(hash);
+    }
+    result
+}
+
+/// Simplify a type path to module::Type format
+fn simplify_type_path(path: &str) -> String {
+    let parts: Vec<&str> = path.split("::").collect();
+    if parts.len() >= 2 {
         // Return last 2 components: module::Type
         format!("{}::{}", parts[parts.len() - 2], parts[parts.len() - 1])
     } else {
         path.to_string()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_options_toggle_generics_and_hash_independently() {
+        let symbol = "alloc::vec::Vec<myapp::model::Record>::push::h0123456789abcdef";
+        let format = |keep_generics, keep_hash| {
+            format_function(
+                symbol,
+                NameOptions {
+                    keep_generics,
+                    keep_hash,
+                },
+            )
+        };
+
+        assert_eq!(format(false, false), "Vec::Vec<_>::push");
+        assert_eq!(format(true, false), "Vec::Vec<myapp::model::Record>::push");
+        assert_eq!(format(false, true), "Vec::Vec<_>::push::h0123456789abcdef");
+        assert_eq!(
+            format(true, true),
+            "Vec::Vec<myapp::model::Record>::push::h0123456789abcdef"
+        );
+    }
+}
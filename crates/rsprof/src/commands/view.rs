@@ -1,10 +1,146 @@
-use crate::error::Result;
+use crate::cli::{ColumnWidths, NameOptions, shell_quote};
+use crate::error::{Error, Result};
+use crate::storage::{get_meta, repair_clock_skew};
+use crate::symbols::deferred;
 use crate::tui::App;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 /// Run the view command - opens a profile in the unified TUI
-pub fn run(file: &Path) -> Result<()> {
+pub fn run(file: &Path, names: NameOptions, columns: ColumnWidths) -> Result<()> {
+    let mut conn = rusqlite::Connection::open(file)?;
+    // A --no-symbols recording is resolved in memory when the app loads it
+    if deferred::is_deferred(&conn)? {
+        eprintln!("Resolving symbols for {}...", file.display());
+    }
+    // Keep the timeline ordered if the clock stepped back while recording
+    repair_clock_skew(&mut conn)?;
+    let rerecord = rerecord_command(&conn)?;
+    drop(conn);
+
     let mut app = App::from_file(file)?;
+    app.name_options = names;
+    app.column_widths = columns;
+    app.rerecord_command = rerecord;
     app.run()?;
+
+    if app.rerecord_requested()
+        && let Some(command) = &app.rerecord_command
+    {
+        println!("Record {} again with the same settings:", file.display());
+        println!("  {}", command);
+    }
     Ok(())
 }
+
+/// Command that records a profile's target again with the same settings
+///
+/// The target is found by process name (its PID has likely changed); the
+/// flags come from the `record_args` meta. Profiles recorded before it was
+/// stored only give back the CPU frequency.
+pub fn rerecord_command(conn: &rusqlite::Connection) -> Result<Option<String>> {
+    let Some(name) = get_meta(conn, "process_name")? else {
+        return Ok(None);
+    };
+    let mut parts = vec!["rsprof".to_string(), "-P".to_string(), shell_quote(&name)];
+    match get_meta(conn, "record_args")? {
+        Some(args) if !args.is_empty() => parts.push(args),
+        Some(_) => {}
+        None => {
+            if let Some(freq) = get_meta(conn, "cpu_freq_hz")?
+                && freq != "99"
+                && freq.parse::<u64>().is_ok()
+            {
+                parts.extend(["--cpu-freq".to_string(), freq]);
+            }
+        }
+    }
+    Ok(Some(parts.join(" ")))
+}
+
+/// Run the view command over every profile in a directory, in file name order
+pub fn run_playlist(
+    dir: &Path,
+    dwell: Option<Duration>,
+    names: NameOptions,
+    columns: ColumnWidths,
+) -> Result<()> {
+    let files = playlist_files(dir)?;
+    for file in &files {
+        let mut conn = rusqlite::Connection::open(file)?;
+        // Keep the timeline ordered if the clock stepped back while recording
+        repair_clock_skew(&mut conn)?;
+    }
+
+    crate::tui::run_playlist(&files, dwell, names, columns)
+}
+
+/// The profiles (`*.db`) in a directory, in file name order
+pub(crate) fn playlist_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "db"))
+        .collect();
+    files.sort();
+    if files.is_empty() {
+        return Err(Error::InvalidArgument(format!(
+            "No profiles (*.db) in {}",
+            dir.display()
+        )));
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Cli;
+    use crate::storage::set_meta;
+    use clap::Parser;
+
+    fn profile_meta(meta: &[(&str, &str)]) -> rusqlite::Connection {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE meta (key TEXT PRIMARY KEY, value TEXT NOT NULL)")
+            .unwrap();
+        for (key, value) in meta {
+            set_meta(&conn, key, value).unwrap();
+        }
+        conn
+    }
+
+    #[test]
+    fn rerecord_command_repeats_the_stored_settings() {
+        let cli = Cli::try_parse_from([
+            "rsprof",
+            "-P",
+            "server",
+            "-o",
+            "before.db",
+            "-i",
+            "250ms",
+            "--cpu-freq",
+            "499",
+            "--threads",
+            "worker *",
+            "--transfers",
+        ])
+        .unwrap();
+        let conn = profile_meta(&[
+            ("process_name", "my server"),
+            ("record_args", &cli.record_args().join(" ")),
+        ]);
+        assert_eq!(
+            rerecord_command(&conn).unwrap().as_deref(),
+            Some("rsprof -P 'my server' -i 250ms --cpu-freq 499 --threads 'worker *' --transfers")
+        );
+
+        // Older profiles only give back a non-default CPU frequency
+        let conn = profile_meta(&[("process_name", "server"), ("cpu_freq_hz", "499")]);
+        assert_eq!(
+            rerecord_command(&conn).unwrap().as_deref(),
+            Some("rsprof -P server --cpu-freq 499")
+        );
+        assert_eq!(rerecord_command(&profile_meta(&[])).unwrap(), None);
+    }
+}
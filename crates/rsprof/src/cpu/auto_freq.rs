@@ -0,0 +1,198 @@
+use super::CpuSampler;
+use crate::error::Result;
+use crate::heap::ShmHeapSampler;
+use std::time::{Duration, Instant};
+
+/// Frequency `--auto-freq` starts at before it has measured anything
+pub const AUTO_FREQ_START_HZ: u64 = 49;
+/// Lowest frequency `--auto-freq` backs off to
+pub const AUTO_FREQ_MIN_HZ: u64 = 10;
+/// Highest frequency `--auto-freq` ramps up to
+pub const AUTO_FREQ_MAX_HZ: u64 = 999;
+
+/// Target CPU time needed before an overhead reading is trusted
+const MIN_OBSERVED_CPU: Duration = Duration::from_millis(50);
+
+/// Changes smaller than this fraction of the current frequency are skipped
+const MIN_CHANGE: f64 = 0.1;
+
+/// Adjusts the CPU sampling frequency to keep profiler overhead under a budget
+///
+/// Overhead is measured per checkpoint against the target's CPU time: with
+/// rsprof-trace it is the time the target spent in its SIGPROF handler, with
+/// perf_event it is the time spent here draining and recording samples. Lost
+/// samples (a full perf ring buffer) always count as over budget.
+pub struct AutoFreq {
+    pid: u32,
+    budget_percent: f64,
+    freq_hz: u64,
+    started: Instant,
+    start_target_cpu: Option<Duration>,
+    last_target_cpu: Option<Duration>,
+    last_cpu_overhead: Duration,
+    processing: Duration,
+    lost: u64,
+    changes: Vec<(u64, u64)>,
+    samples: u64,
+}
+
+impl AutoFreq {
+    pub fn new(pid: u32, budget_percent: f64) -> Self {
+        let target_cpu = crate::process::cpu_time(pid);
+        AutoFreq {
+            pid,
+            budget_percent,
+            freq_hz: AUTO_FREQ_START_HZ,
+            started: Instant::now(),
+            start_target_cpu: target_cpu,
+            last_target_cpu: target_cpu,
+            last_cpu_overhead: Duration::ZERO,
+            processing: Duration::ZERO,
+            lost: 0,
+            changes: vec![(0, AUTO_FREQ_START_HZ)],
+            samples: 0,
+        }
+    }
+
+    /// Current sampling frequency
+    pub fn freq_hz(&self) -> u64 {
+        self.freq_hz
+    }
+
+    /// Count samples read from the sampler
+    pub fn count_samples(&mut self, samples: u64) {
+        self.samples += samples;
+    }
+
+    /// Add time spent draining and recording perf samples
+    pub fn add_processing(&mut self, elapsed: Duration) {
+        self.processing += elapsed;
+    }
+
+    /// Samples per second of target CPU time over the recording
+    ///
+    /// Used to convert sample counts taken at several rates back to time.
+    /// Measured rather than derived from the requested rates, which timers
+    /// don't always deliver (SIGPROF is bounded by the kernel tick).
+    pub fn effective_freq_hz(&self) -> f64 {
+        let cpu = self
+            .start_target_cpu
+            .zip(crate::process::cpu_time(self.pid))
+            .map_or(Duration::ZERO, |(start, now)| now.saturating_sub(start));
+        if self.samples > 0 && cpu >= MIN_OBSERVED_CPU {
+            self.samples as f64 / cpu.as_secs_f64()
+        } else {
+            self.freq_hz as f64
+        }
+    }
+
+    /// Frequency changes as `ms:hz` pairs (ms since recording started)
+    pub fn changes_meta(&self) -> String {
+        self.changes
+            .iter()
+            .map(|(ms, hz)| format!("{}:{}", ms, hz))
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+
+    /// Measure overhead since the last call and retune the active sampler
+    ///
+    /// Returns the new frequency when it changed.
+    pub fn adjust(
+        &mut self,
+        mut perf: Option<&mut CpuSampler>,
+        shm: Option<&ShmHeapSampler>,
+    ) -> Result<Option<u64>> {
+        let Some(target_cpu) = crate::process::cpu_time(self.pid) else {
+            return Ok(None);
+        };
+        let cpu_delta = self
+            .last_target_cpu
+            .map_or(Duration::ZERO, |last| target_cpu.saturating_sub(last));
+
+        let overhead = match shm {
+            Some(shm) => {
+                let total = shm.cpu_overhead();
+                let delta = total.saturating_sub(self.last_cpu_overhead);
+                self.last_cpu_overhead = total;
+                delta
+            }
+            None => std::mem::take(&mut self.processing),
+        };
+        if let Some(sampler) = perf.as_deref_mut() {
+            self.lost += sampler.take_lost();
+        }
+
+        // An idle target gives no reliable reading; wait for more CPU time
+        if cpu_delta < MIN_OBSERVED_CPU && self.lost == 0 {
+            return Ok(None);
+        }
+        self.last_target_cpu = Some(target_cpu);
+        let lost = std::mem::take(&mut self.lost);
+
+        let percent = if cpu_delta.is_zero() {
+            0.0
+        } else {
+            overhead.as_secs_f64() / cpu_delta.as_secs_f64() * 100.0
+        };
+        let Some(next) = next_freq(self.freq_hz, percent, self.budget_percent, lost > 0) else {
+            return Ok(None);
+        };
+
+        match (perf, shm) {
+            (_, Some(shm)) => {
+                shm.request_cpu_freq(next);
+            }
+            (Some(sampler), None) => sampler.set_frequency(next)?,
+            (None, None) => return Ok(None),
+        }
+        self.freq_hz = next;
+        self.changes
+            .push((self.started.elapsed().as_millis() as u64, next));
+        Ok(Some(next))
+    }
+}
+
+/// Pick the next frequency from the overhead observed at `freq`
+fn next_freq(freq: u64, percent: f64, budget: f64, lost: bool) -> Option<u64> {
+    let factor = if lost || percent > budget {
+        // Aim a little under budget so the next reading doesn't bounce back
+        if percent > 0.0 {
+            (budget * 0.8 / percent).clamp(0.25, 0.8)
+        } else {
+            0.5
+        }
+    } else if percent < budget / 2.0 {
+        if percent > 0.0 {
+            (budget * 0.8 / percent).min(2.0)
+        } else {
+            2.0
+        }
+    } else {
+        return None;
+    };
+    let next = ((freq as f64 * factor).round() as u64).clamp(AUTO_FREQ_MIN_HZ, AUTO_FREQ_MAX_HZ);
+    let change = (next as f64 - freq as f64).abs() / freq as f64;
+    (change >= MIN_CHANGE).then_some(next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn high_overhead_backs_the_frequency_off() {
+        // 4% overhead against a 2% budget: aim for 80% of the budget
+        assert_eq!(next_freq(100, 4.0, 2.0, false), Some(40));
+        // Far over budget: at most a quarter of the rate per step
+        assert_eq!(next_freq(499, 10.0, 2.0, false), Some(125));
+        // Lost samples back off even when overhead looks fine
+        assert_eq!(next_freq(100, 0.5, 2.0, true), Some(80));
+        // Never below the floor
+        assert_eq!(next_freq(AUTO_FREQ_MIN_HZ, 50.0, 2.0, false), None);
+
+        // Within budget it holds, well under it ramps up
+        assert_eq!(next_freq(100, 1.5, 2.0, false), None);
+        assert_eq!(next_freq(100, 0.1, 2.0, false), Some(200));
+    }
+}
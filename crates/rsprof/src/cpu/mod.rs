@@ -1,4 +1,11 @@
+mod auto_freq;
+mod offcpu;
 mod perf;
+mod perf_data;
 mod sampler;
 
+pub use auto_freq::AutoFreq;
+pub use offcpu::{OffCpuSample, OffCpuSampler};
+pub use perf::{CpuSample, MAX_COUNTERS, PerfCounter};
+pub use perf_data::{PerfData, PerfDataSample, PerfMmap};
 pub use sampler::CpuSampler;
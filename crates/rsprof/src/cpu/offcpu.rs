@@ -0,0 +1,303 @@
+//! Off-CPU sampling (`--off-cpu`): where threads block, and for how long
+//!
+//! Each thread gets a `sched:sched_switch` tracepoint event that samples its
+//! user call chain whenever it is switched out, plus PERF_RECORD_SWITCH
+//! records for when it is switched back in. The time in between is the
+//! blocked duration (waiting on a lock, I/O or sleep, or preempted while
+//! runnable), credited to the stack it went off-CPU at.
+
+use super::perf::{
+    PERF_CONTEXT_MAX, PERF_RECORD_LOST, PERF_RECORD_MISC_SWITCH_OUT, PERF_RECORD_SAMPLE,
+    PERF_RECORD_SWITCH, PERF_SAMPLE_CALLCHAIN, PERF_SAMPLE_IP, PERF_SAMPLE_TID, PERF_SAMPLE_TIME,
+    PERF_TYPE_TRACEPOINT, PerfEventAttr, PerfEventHeader, PerfEventMmapPage, check_perf_paranoid,
+    copy_from_ring, map_ring, perf_event_open,
+};
+use crate::error::{Error, Result};
+use std::fs;
+use std::os::unix::io::{AsRawFd, OwnedFd};
+
+/// Where tracefs exposes the tracepoint's id (newer kernels, then debugfs)
+const SCHED_SWITCH_ID_PATHS: &[&str] = &[
+    "/sys/kernel/tracing/events/sched/sched_switch/id",
+    "/sys/kernel/debug/tracing/events/sched/sched_switch/id",
+];
+
+/// Blocked time of one thread at one call stack
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OffCpuSample {
+    pub tid: u32,
+    /// User call chain at the switch, innermost frame first
+    pub stack: Vec<u64>,
+    /// Times the thread went off-CPU here (0 when continuing a block
+    /// already counted at an earlier read)
+    pub count: u64,
+    /// Time spent off-CPU since the switch, or since the previous read
+    pub blocked_ns: u64,
+}
+
+/// A thread that is off-CPU right now
+struct Blocked {
+    stack: Vec<u64>,
+    /// Blocked time is credited up to here (CLOCK_MONOTONIC ns)
+    credited_until: u64,
+    /// Whether an earlier read already counted this switch
+    counted: bool,
+}
+
+/// Switch events of one thread
+struct OffCpuEvent {
+    tid: u32,
+    fd: OwnedFd,
+    mmap: *mut u8,
+    mmap_size: usize,
+    data_size: usize,
+    blocked: Option<Blocked>,
+    /// Records the kernel dropped because the ring buffer was full
+    lost: u64,
+}
+
+// SAFETY: The mmap pointer is only used from a single thread
+unsafe impl Send for OffCpuEvent {}
+
+impl OffCpuEvent {
+    fn open(tid: u32, tracepoint: u64) -> Result<Self> {
+        let mut attr = PerfEventAttr::new();
+        attr.type_ = PERF_TYPE_TRACEPOINT;
+        attr.config = tracepoint;
+        attr.sample_type =
+            PERF_SAMPLE_IP | PERF_SAMPLE_TID | PERF_SAMPLE_TIME | PERF_SAMPLE_CALLCHAIN;
+        // Every switch, not a sampled subset
+        attr.sample_period_or_freq = 1;
+        attr.set_disabled(true);
+        // The tracepoint fires in the kernel, so kernel events can't be
+        // excluded; only the user part of the call chain is kept
+        attr.set_exclude_callchain_kernel(true);
+        attr.set_exclude_hv(true);
+        attr.set_context_switch(true);
+        attr.set_sample_id_all(true);
+        // Timestamps comparable with clock_gettime, to credit ongoing blocks
+        attr.set_use_clockid(true);
+        attr.clockid = libc::CLOCK_MONOTONIC;
+        attr.set_watermark(true);
+        attr.wakeup_events_or_watermark = 4096;
+
+        let fd =
+            perf_event_open(&attr, tid as i32, -1, None).map_err(|err| match err.raw_os_error() {
+                Some(libc::EACCES) | Some(libc::EPERM) => Error::PermissionDenied(format!(
+                    "Cannot trace context switches of TID {}. Try: sudo sysctl kernel.perf_event_paranoid=1",
+                    tid
+                )),
+                Some(libc::ESRCH) => Error::ProcessNotFound(format!("TID {}", tid)),
+                _ => Error::PerfEvent(format!("perf_event_open (sched_switch) failed: {}", err)),
+            })?;
+        let (mmap, mmap_size, data_size) = map_ring(&fd)?;
+
+        Ok(OffCpuEvent {
+            tid,
+            fd,
+            mmap,
+            mmap_size,
+            data_size,
+            blocked: None,
+            lost: 0,
+        })
+    }
+
+    /// Blocks that ended since the last read, then the part of a still
+    /// ongoing block up to `now`
+    fn read_samples(&mut self, now: u64, samples: &mut Vec<OffCpuSample>) {
+        let header = unsafe { &*(self.mmap as *const PerfEventMmapPage) };
+        let data_ptr = unsafe { self.mmap.add(header.data_offset as usize) };
+        let data = unsafe { std::slice::from_raw_parts(data_ptr, self.data_size) };
+
+        // Read barrier
+        std::sync::atomic::fence(std::sync::atomic::Ordering::Acquire);
+
+        let mut tail = header.data_tail;
+        let head = header.data_head;
+        let mut record = Vec::new();
+
+        while tail < head {
+            let offset = (tail % self.data_size as u64) as usize;
+            // Records are 8-byte aligned, so the header itself never wraps
+            let event_header = unsafe { &*(data_ptr.add(offset) as *const PerfEventHeader) };
+            copy_from_ring(data, offset, event_header.size as usize, &mut record);
+
+            match event_header.type_ {
+                PERF_RECORD_SAMPLE => {
+                    if let Some((time, stack)) = parse_switch_out(&record) {
+                        // A thread can't go off-CPU twice without coming
+                        // back; a missed switch-in only loses that block
+                        self.blocked = Some(Blocked {
+                            stack,
+                            credited_until: time,
+                            counted: false,
+                        });
+                    }
+                }
+                // Switched back in: the block ends (sample_id holds pid/tid, time)
+                PERF_RECORD_SWITCH if event_header.misc & PERF_RECORD_MISC_SWITCH_OUT == 0 => {
+                    if let Some(time) = word(&record, 1)
+                        && let Some(blocked) = self.blocked.take()
+                    {
+                        samples.push(self.sample(blocked, time));
+                    }
+                }
+                PERF_RECORD_LOST => {
+                    if let Some(lost) = word(&record, 1) {
+                        log::warn!("off_cpu_records_lost tid={} count={}", self.tid, lost);
+                        self.lost += lost;
+                    }
+                }
+                _ => {}
+            }
+
+            tail += event_header.size as u64;
+        }
+
+        // Write barrier
+        std::sync::atomic::fence(std::sync::atomic::Ordering::Release);
+
+        unsafe {
+            let header_mut = &mut *(self.mmap as *mut PerfEventMmapPage);
+            header_mut.data_tail = tail;
+        }
+
+        // Credit a long block as it goes rather than all at once when it ends
+        if let Some(blocked) = self.blocked.as_mut()
+            && now > blocked.credited_until
+        {
+            samples.push(OffCpuSample {
+                tid: self.tid,
+                stack: blocked.stack.clone(),
+                count: u64::from(!blocked.counted),
+                blocked_ns: now - blocked.credited_until,
+            });
+            blocked.credited_until = now;
+            blocked.counted = true;
+        }
+    }
+
+    fn sample(&self, blocked: Blocked, switched_in: u64) -> OffCpuSample {
+        OffCpuSample {
+            tid: self.tid,
+            count: u64::from(!blocked.counted),
+            blocked_ns: switched_in.saturating_sub(blocked.credited_until),
+            stack: blocked.stack,
+        }
+    }
+}
+
+impl Drop for OffCpuEvent {
+    fn drop(&mut self) {
+        unsafe {
+            libc::ioctl(self.fd.as_raw_fd(), 0x2401, 0); // PERF_EVENT_IOC_DISABLE
+            libc::munmap(self.mmap as *mut libc::c_void, self.mmap_size);
+        }
+    }
+}
+
+/// Off-CPU sampler for a set of threads
+///
+/// Threads the target starts after this is created aren't traced.
+pub struct OffCpuSampler {
+    events: Vec<OffCpuEvent>,
+}
+
+impl OffCpuSampler {
+    /// Trace context switches of the given threads
+    pub fn for_threads(tids: &[u32]) -> Result<Self> {
+        check_perf_paranoid()?;
+        let tracepoint = sched_switch_id()?;
+
+        let mut events = Vec::with_capacity(tids.len());
+        for &tid in tids {
+            match OffCpuEvent::open(tid, tracepoint) {
+                Ok(event) => events.push(event),
+                // The thread exited since it was listed
+                Err(Error::ProcessNotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        if events.is_empty() {
+            return Err(Error::Sampler("No threads to trace".to_string()));
+        }
+        log::info!(
+            "off_cpu_sampler_opened tids={:?} threads={}",
+            tids,
+            events.len()
+        );
+
+        Ok(OffCpuSampler { events })
+    }
+
+    /// Blocked time per thread and stack since the last call
+    pub fn read_samples(&mut self) -> Vec<OffCpuSample> {
+        let now = monotonic_ns();
+        let mut samples = Vec::new();
+        for event in &mut self.events {
+            event.read_samples(now, &mut samples);
+        }
+        samples
+    }
+
+    /// Switch records the kernel dropped (ring buffer full) since the last call
+    pub fn take_lost(&mut self) -> u64 {
+        self.events
+            .iter_mut()
+            .map(|event| std::mem::take(&mut event.lost))
+            .sum()
+    }
+}
+
+impl Drop for OffCpuSampler {
+    fn drop(&mut self) {
+        log::info!("off_cpu_sampler_closed threads={}", self.events.len());
+    }
+}
+
+/// Id of the `sched:sched_switch` tracepoint
+fn sched_switch_id() -> Result<u64> {
+    SCHED_SWITCH_ID_PATHS
+        .iter()
+        .find_map(|path| fs::read_to_string(path).ok()?.trim().parse().ok())
+        .ok_or_else(|| {
+            Error::PermissionDenied(
+                "Cannot read the sched:sched_switch tracepoint id from tracefs \
+                 (not mounted, or readable by root only)"
+                    .to_string(),
+            )
+        })
+}
+
+fn monotonic_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// `index`th u64 after the record header
+fn word(record: &[u8], index: usize) -> Option<u64> {
+    let start = std::mem::size_of::<PerfEventHeader>() + index * 8;
+    record
+        .get(start..start + 8)
+        .map(|bytes| u64::from_ne_bytes(bytes.try_into().unwrap()))
+}
+
+/// Parse a switch-out sample: ip, pid/tid, time, then the call chain
+/// (nr, ips). Returns the time and the user frames, innermost first.
+fn parse_switch_out(record: &[u8]) -> Option<(u64, Vec<u64>)> {
+    let time = word(record, 2)?;
+    let nr = word(record, 3)? as usize;
+    let stack = (0..nr)
+        .map(|i| word(record, 4 + i))
+        .collect::<Option<Vec<u64>>>()?
+        .into_iter()
+        .filter(|&ip| ip != 0 && ip < PERF_CONTEXT_MAX)
+        .collect();
+    Some((time, stack))
+}
@@ -5,12 +5,91 @@ use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
 use std::ptr;
 
 // perf_event constants (from linux/perf_event.h)
+pub const PERF_TYPE_HARDWARE: u32 = 0;
 pub const PERF_TYPE_SOFTWARE: u32 = 1;
+pub const PERF_TYPE_TRACEPOINT: u32 = 2;
 pub const PERF_COUNT_SW_CPU_CLOCK: u64 = 0;
 
 pub const PERF_SAMPLE_IP: u64 = 1 << 0;
 pub const PERF_SAMPLE_TID: u64 = 1 << 1;
 pub const PERF_SAMPLE_TIME: u64 = 1 << 2;
+pub const PERF_SAMPLE_READ: u64 = 1 << 4;
+pub const PERF_SAMPLE_CALLCHAIN: u64 = 1 << 5;
+
+pub const PERF_FORMAT_GROUP: u64 = 1 << 3;
+
+/// ioctl flag: apply ENABLE/DISABLE to the whole group
+const PERF_IOC_FLAG_GROUP: c_ulong = 1;
+
+/// ioctl: set a new sample period
+const PERF_EVENT_IOC_PERIOD: c_ulong = 0x4008_2404; // _IOW('$', 4, u64)
+
+/// Most hardware counters recorded alongside each sample
+///
+/// CPUs typically have 4-8 general-purpose counters; a larger group can't be
+/// scheduled at once and would never count.
+pub const MAX_COUNTERS: usize = 4;
+
+/// Hardware counter recorded with each CPU sample (`--counters`)
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PerfCounter {
+    /// CPU cycles
+    Cycles,
+    /// Retired instructions
+    Instructions,
+    /// Last-level cache accesses
+    CacheReferences,
+    /// Last-level cache misses
+    CacheMisses,
+    /// Retired branch instructions
+    Branches,
+    /// Mispredicted branches
+    BranchMisses,
+}
+
+impl PerfCounter {
+    /// Name as stored in the profile (same as perf's event names)
+    pub fn name(self) -> &'static str {
+        match self {
+            PerfCounter::Cycles => "cycles",
+            PerfCounter::Instructions => "instructions",
+            PerfCounter::CacheReferences => "cache-references",
+            PerfCounter::CacheMisses => "cache-misses",
+            PerfCounter::Branches => "branches",
+            PerfCounter::BranchMisses => "branch-misses",
+        }
+    }
+
+    /// PERF_COUNT_HW_* config value
+    fn config(self) -> u64 {
+        match self {
+            PerfCounter::Cycles => 0,
+            PerfCounter::Instructions => 1,
+            PerfCounter::CacheReferences => 2,
+            PerfCounter::CacheMisses => 3,
+            PerfCounter::Branches => 4,
+            PerfCounter::BranchMisses => 5,
+        }
+    }
+}
+
+/// One CPU sample: the interrupted instruction pointer, the user call stack
+/// and, per group member, how much its counter advanced since the thread's
+/// previous sample
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuSample {
+    pub ip: u64,
+    /// `ip` followed by the return addresses of its callers, walked by the
+    /// kernel along frame pointers (just `ip` where it couldn't)
+    pub stack: Vec<u64>,
+    /// Process the sample was taken in (differs from the target's in
+    /// children followed with `inherit`)
+    pub pid: u32,
+    /// Thread the sample was taken in
+    pub tid: u32,
+    /// Parallel to the sampler's counters; unused slots are zero
+    pub counters: [u64; MAX_COUNTERS],
+}
 
 /// perf_event_attr structure
 #[repr(C)]
@@ -42,7 +121,6 @@ pub struct PerfEventAttr {
 impl PerfEventAttr {
     // Flag bit positions
     const DISABLED_BIT: u64 = 1 << 0;
-    #[allow(dead_code)]
     const INHERIT_BIT: u64 = 1 << 1;
     #[allow(dead_code)]
     const EXCLUDE_USER_BIT: u64 = 1 << 4;
@@ -50,6 +128,10 @@ impl PerfEventAttr {
     const EXCLUDE_HV_BIT: u64 = 1 << 6;
     const FREQ_BIT: u64 = 1 << 10;
     const WATERMARK_BIT: u64 = 1 << 14;
+    const SAMPLE_ID_ALL_BIT: u64 = 1 << 18;
+    const EXCLUDE_CALLCHAIN_KERNEL_BIT: u64 = 1 << 21;
+    const USE_CLOCKID_BIT: u64 = 1 << 25;
+    const CONTEXT_SWITCH_BIT: u64 = 1 << 26;
 
     pub fn new() -> Self {
         PerfEventAttr {
@@ -66,6 +148,14 @@ impl PerfEventAttr {
         }
     }
 
+    pub fn set_inherit(&mut self, val: bool) {
+        if val {
+            self.flags |= Self::INHERIT_BIT;
+        } else {
+            self.flags &= !Self::INHERIT_BIT;
+        }
+    }
+
     pub fn set_exclude_kernel(&mut self, val: bool) {
         if val {
             self.flags |= Self::EXCLUDE_KERNEL_BIT;
@@ -97,6 +187,41 @@ impl PerfEventAttr {
             self.flags &= !Self::WATERMARK_BIT;
         }
     }
+
+    /// Append the sample's TID/TIME fields to non-sample records too
+    pub fn set_sample_id_all(&mut self, val: bool) {
+        if val {
+            self.flags |= Self::SAMPLE_ID_ALL_BIT;
+        } else {
+            self.flags &= !Self::SAMPLE_ID_ALL_BIT;
+        }
+    }
+
+    pub fn set_exclude_callchain_kernel(&mut self, val: bool) {
+        if val {
+            self.flags |= Self::EXCLUDE_CALLCHAIN_KERNEL_BIT;
+        } else {
+            self.flags &= !Self::EXCLUDE_CALLCHAIN_KERNEL_BIT;
+        }
+    }
+
+    /// Timestamp samples with `clockid` instead of the perf clock
+    pub fn set_use_clockid(&mut self, val: bool) {
+        if val {
+            self.flags |= Self::USE_CLOCKID_BIT;
+        } else {
+            self.flags &= !Self::USE_CLOCKID_BIT;
+        }
+    }
+
+    /// Emit PERF_RECORD_SWITCH when the monitored thread is switched in or out
+    pub fn set_context_switch(&mut self, val: bool) {
+        if val {
+            self.flags |= Self::CONTEXT_SWITCH_BIT;
+        } else {
+            self.flags &= !Self::CONTEXT_SWITCH_BIT;
+        }
+    }
 }
 
 /// perf_event_mmap_page header structure
@@ -141,12 +266,27 @@ pub struct PerfEventHeader {
 
 // Record types
 pub const PERF_RECORD_SAMPLE: u32 = 9;
-#[allow(dead_code)]
 pub const PERF_RECORD_LOST: u32 = 2;
+pub const PERF_RECORD_SWITCH: u32 = 14;
+
+/// PERF_RECORD_SWITCH misc flag: the thread was switched out (else in)
+pub const PERF_RECORD_MISC_SWITCH_OUT: u16 = 1 << 13;
+
+/// Call chain entries at or above this are context markers (PERF_CONTEXT_USER...)
+pub const PERF_CONTEXT_MAX: u64 = -4095i64 as u64;
 
 /// Wrapper for a perf_event file descriptor
+///
+/// With counters the fd leads a perf_event group: members count alongside
+/// the sampling leader and every sample carries all their values.
 pub struct PerfEvent {
     fd: OwnedFd,
+    /// Group members, in the order of the counters they were opened for
+    members: Vec<OwnedFd>,
+    /// Members' running totals at the previous sample
+    last_counts: [u64; MAX_COUNTERS],
+    /// Samples the kernel dropped because the ring buffer was full
+    lost: u64,
     mmap: *mut u8,
     mmap_size: usize,
     data_size: usize,
@@ -156,115 +296,124 @@ pub struct PerfEvent {
 unsafe impl Send for PerfEvent {}
 
 impl PerfEvent {
-    /// Open a perf_event for CPU sampling
-    pub fn open(pid: pid_t, freq: u64) -> Result<Self> {
+    /// Open a perf_event for CPU sampling, with `counters` read at each sample
+    pub fn open(pid: pid_t, freq: u64, counters: &[PerfCounter]) -> Result<Self> {
+        Self::open_on(pid, -1, freq, counters, false)
+    }
+
+    /// Open a perf_event sampling `pid` on one CPU, inherited by the threads
+    /// and processes it creates from now on
+    ///
+    /// The kernel only maps inherited events per CPU, so following a process
+    /// takes one of these for every online CPU.
+    pub fn open_inherited(pid: pid_t, cpu: u32, freq: u64) -> Result<Self> {
+        Self::open_on(pid, cpu as c_int, freq, &[], true)
+    }
+
+    fn open_on(
+        pid: pid_t,
+        cpu: c_int,
+        freq: u64,
+        counters: &[PerfCounter],
+        inherit: bool,
+    ) -> Result<Self> {
         // Check perf_event_paranoid
         check_perf_paranoid()?;
 
         let mut attr = PerfEventAttr::new();
         attr.type_ = PERF_TYPE_SOFTWARE;
         attr.config = PERF_COUNT_SW_CPU_CLOCK;
-        attr.sample_type = PERF_SAMPLE_IP | PERF_SAMPLE_TID | PERF_SAMPLE_TIME;
+        attr.sample_type =
+            PERF_SAMPLE_IP | PERF_SAMPLE_TID | PERF_SAMPLE_TIME | PERF_SAMPLE_CALLCHAIN;
+        if !counters.is_empty() {
+            attr.sample_type |= PERF_SAMPLE_READ;
+            attr.read_format = PERF_FORMAT_GROUP;
+        }
         attr.sample_period_or_freq = freq;
         attr.set_freq(true);
         attr.set_disabled(true);
+        attr.set_inherit(inherit);
         attr.set_exclude_kernel(true);
         attr.set_exclude_hv(true);
+        attr.set_exclude_callchain_kernel(true);
         attr.set_watermark(true);
         attr.wakeup_events_or_watermark = 4096; // Wake when 4KB ready
 
-        let fd = unsafe {
-            syscall(
-                SYS_perf_event_open,
-                &attr as *const PerfEventAttr,
-                pid,
-                -1 as c_int, // any CPU
-                -1 as c_int, // no group
-                0 as c_ulong,
-            )
-        };
-
-        if fd < 0 {
-            let err = std::io::Error::last_os_error();
-            return Err(match err.raw_os_error() {
+        let fd =
+            perf_event_open(&attr, pid, cpu, None).map_err(|err| match err.raw_os_error() {
                 Some(libc::EACCES) | Some(libc::EPERM) => Error::PermissionDenied(format!(
                     "Cannot attach to PID {}. Try: sudo sysctl kernel.perf_event_paranoid=1",
                     pid
                 )),
                 Some(libc::ESRCH) => Error::ProcessNotFound(format!("PID {}", pid)),
                 _ => Error::PerfEvent(format!("perf_event_open failed: {}", err)),
-            });
-        }
-
-        let fd = unsafe { OwnedFd::from_raw_fd(fd as c_int) };
-
-        // Memory map the ring buffer
-        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
-        let data_pages = 64; // 64 pages = 256KB
-        let mmap_size = (1 + data_pages) * page_size; // 1 metadata page + data pages
-        let data_size = data_pages * page_size;
-
-        let mmap = unsafe {
-            libc::mmap(
-                ptr::null_mut(),
-                mmap_size,
-                libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_SHARED,
-                fd.as_raw_fd(),
-                0,
-            )
-        };
-
-        if mmap == libc::MAP_FAILED {
-            return Err(Error::PerfEvent(format!(
-                "Failed to mmap perf buffer: {}",
-                std::io::Error::last_os_error()
-            )));
+            })?;
+
+        // Counting-only members; they start and stop with the leader
+        let mut members = Vec::with_capacity(counters.len());
+        for &counter in counters {
+            let mut attr = PerfEventAttr::new();
+            attr.type_ = PERF_TYPE_HARDWARE;
+            attr.config = counter.config();
+            attr.set_exclude_kernel(true);
+            attr.set_exclude_hv(true);
+            let member = perf_event_open(&attr, pid, cpu, Some(&fd)).map_err(|err| {
+                Error::PerfEvent(format!(
+                    "Cannot count {} (no hardware counters, e.g. in a VM?): {}",
+                    counter.name(),
+                    err
+                ))
+            })?;
+            members.push(member);
         }
 
-        // Enable the event
-        let ret = unsafe { libc::ioctl(fd.as_raw_fd(), 0x2400, 0) }; // PERF_EVENT_IOC_ENABLE
-        if ret < 0 {
-            unsafe { libc::munmap(mmap, mmap_size) };
-            return Err(Error::PerfEvent(format!(
-                "Failed to enable perf event: {}",
-                std::io::Error::last_os_error()
-            )));
-        }
+        let (mmap, mmap_size, data_size) = map_ring(&fd)?;
 
         Ok(PerfEvent {
             fd,
-            mmap: mmap as *mut u8,
+            members,
+            last_counts: [0; MAX_COUNTERS],
+            lost: 0,
+            mmap,
             mmap_size,
             data_size,
         })
     }
 
     /// Read samples from the ring buffer
-    pub fn read_samples(&mut self) -> Vec<u64> {
+    pub fn read_samples(&mut self) -> Vec<CpuSample> {
         let mut samples = Vec::new();
 
         let header = unsafe { &*(self.mmap as *const PerfEventMmapPage) };
         let data_ptr = unsafe { self.mmap.add(header.data_offset as usize) };
+        let data = unsafe { std::slice::from_raw_parts(data_ptr, self.data_size) };
 
         // Read barrier
         std::sync::atomic::fence(std::sync::atomic::Ordering::Acquire);
 
         let mut tail = header.data_tail;
         let head = header.data_head;
+        let mut record = Vec::new();
 
         while tail < head {
             let offset = (tail % self.data_size as u64) as usize;
+            // Records are 8-byte aligned, so the header itself never wraps
             let event_header = unsafe { &*(data_ptr.add(offset) as *const PerfEventHeader) };
 
             if event_header.type_ == PERF_RECORD_SAMPLE {
-                // Sample record: header followed by IP (and optionally TID, TIME)
-                // We configured PERF_SAMPLE_IP | PERF_SAMPLE_TID | PERF_SAMPLE_TIME
-                // Layout: ip, pid, tid, time
-                let ip_offset = offset + std::mem::size_of::<PerfEventHeader>();
-                let ip_ptr = data_ptr.wrapping_add(ip_offset % self.data_size);
-                let ip = unsafe { *(ip_ptr as *const u64) };
-                samples.push(ip);
+                copy_from_ring(data, offset, event_header.size as usize, &mut record);
+                if let Some(mut sample) = parse_sample(&record, self.members.len()) {
+                    self.take_deltas(&mut sample);
+                    samples.push(sample);
+                }
+            } else if event_header.type_ == PERF_RECORD_LOST {
+                // Layout: header, id, lost
+                copy_from_ring(data, offset, event_header.size as usize, &mut record);
+                if let Some(lost) = record.get(16..24) {
+                    let lost = u64::from_ne_bytes(lost.try_into().unwrap());
+                    log::warn!("perf_samples_lost count={}", lost);
+                    self.lost += lost;
+                }
             }
 
             tail += event_header.size as u64;
@@ -281,13 +430,192 @@ impl PerfEvent {
 
         samples
     }
+
+    /// Take the number of samples lost since the last call
+    pub fn take_lost(&mut self) -> u64 {
+        std::mem::take(&mut self.lost)
+    }
+
+    /// Change the sampling frequency of a running event
+    pub fn set_frequency(&self, freq: u64) -> Result<()> {
+        // cpu-clock turns a requested frequency into a fixed period (in ns)
+        // when opened, so from then on it only takes periods
+        let period: u64 = 1_000_000_000 / freq.max(1);
+        let ret = unsafe { libc::ioctl(self.fd.as_raw_fd(), PERF_EVENT_IOC_PERIOD, &period) };
+        if ret < 0 {
+            return Err(Error::PerfEvent(format!(
+                "Failed to set sampling frequency to {} Hz: {}",
+                freq,
+                std::io::Error::last_os_error()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Turn a sample's running totals into deltas since the previous sample
+    fn take_deltas(&mut self, sample: &mut CpuSample) {
+        let counts = sample.counters;
+        let members = self.members.len();
+        for (delta, last) in sample.counters[..members]
+            .iter_mut()
+            .zip(&self.last_counts[..members])
+        {
+            *delta = delta.saturating_sub(*last);
+        }
+        self.last_counts = counts;
+    }
+}
+
+/// Memory map an event's ring buffer and enable the event (and its group)
+///
+/// Returns the mapping, its size and the size of its data area.
+pub(super) fn map_ring(fd: &OwnedFd) -> Result<(*mut u8, usize, usize)> {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let data_pages = 64; // 64 pages = 256KB
+    let mmap_size = (1 + data_pages) * page_size; // 1 metadata page + data pages
+    let data_size = data_pages * page_size;
+
+    let mmap = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            mmap_size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd.as_raw_fd(),
+            0,
+        )
+    };
+
+    if mmap == libc::MAP_FAILED {
+        return Err(Error::PerfEvent(format!(
+            "Failed to mmap perf buffer: {}",
+            std::io::Error::last_os_error()
+        )));
+    }
+
+    // Enable the event
+    let ret = unsafe { libc::ioctl(fd.as_raw_fd(), 0x2400, PERF_IOC_FLAG_GROUP) }; // PERF_EVENT_IOC_ENABLE
+    if ret < 0 {
+        unsafe { libc::munmap(mmap, mmap_size) };
+        return Err(Error::PerfEvent(format!(
+            "Failed to enable perf event: {}",
+            std::io::Error::last_os_error()
+        )));
+    }
+
+    Ok((mmap as *mut u8, mmap_size, data_size))
+}
+
+/// Open one perf_event on `cpu` (-1 = any), optionally as a member of `group`'s group
+pub(super) fn perf_event_open(
+    attr: &PerfEventAttr,
+    pid: pid_t,
+    cpu: c_int,
+    group: Option<&OwnedFd>,
+) -> std::io::Result<OwnedFd> {
+    let fd = unsafe {
+        syscall(
+            SYS_perf_event_open,
+            attr as *const PerfEventAttr,
+            pid,
+            cpu,
+            group.map_or(-1, |g| g.as_raw_fd()),
+            0 as c_ulong,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as c_int) })
+}
+
+/// Online CPU numbers, from /sys/devices/system/cpu/online (e.g. "0-3,6")
+pub fn online_cpus() -> Vec<u32> {
+    let parsed = fs::read_to_string("/sys/devices/system/cpu/online")
+        .ok()
+        .and_then(|list| parse_cpu_list(list.trim()));
+    parsed.unwrap_or_else(|| {
+        let count = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) }.max(1);
+        (0..count as u32).collect()
+    })
+}
+
+/// Parse a kernel CPU list: comma-separated numbers and inclusive ranges
+fn parse_cpu_list(list: &str) -> Option<Vec<u32>> {
+    let mut cpus = Vec::new();
+    for part in list.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => cpus.extend(first.parse::<u32>().ok()?..=last.parse().ok()?),
+            None => cpus.push(part.parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
+
+/// Copy a `len`-byte record starting at `offset` out of the ring, unwrapping it
+pub(super) fn copy_from_ring(data: &[u8], offset: usize, len: usize, out: &mut Vec<u8>) {
+    out.clear();
+    let first = len.min(data.len() - offset);
+    out.extend_from_slice(&data[offset..offset + first]);
+    out.extend_from_slice(&data[..len - first]);
+}
+
+/// Parse a PERF_RECORD_SAMPLE record (header included)
+///
+/// Layout for our sample_type: ip, pid/tid, time, then with `members` > 0 the
+/// group read: nr, the leader's value and one running total per member, then
+/// the call chain: nr and the addresses. The sample's counters hold the
+/// members' running totals.
+fn parse_sample(record: &[u8], members: usize) -> Option<CpuSample> {
+    let word = |index: usize| {
+        let start = std::mem::size_of::<PerfEventHeader>() + index * 8;
+        record
+            .get(start..start + 8)
+            .map(|bytes| u64::from_ne_bytes(bytes.try_into().unwrap()))
+    };
+
+    let ip = word(0)?;
+    // Word 1 holds pid and tid as two u32s, pid first
+    let start = std::mem::size_of::<PerfEventHeader>() + 8;
+    let pid = u32::from_ne_bytes(record.get(start..start + 4)?.try_into().unwrap());
+    let tid = u32::from_ne_bytes(record.get(start + 4..start + 8)?.try_into().unwrap());
+    let mut counters = [0; MAX_COUNTERS];
+    let mut next = 3;
+    if members > 0 {
+        let nr = word(3)? as usize;
+        if nr != members + 1 {
+            return None;
+        }
+        for (i, count) in counters.iter_mut().enumerate().take(members) {
+            // word(4) is the leader's own (cpu-clock) value
+            *count = word(5 + i)?;
+        }
+        next = 4 + nr;
+    }
+
+    // The user chain starts with a PERF_CONTEXT_USER marker, then ip again
+    let nr = word(next)? as usize;
+    let mut stack = (0..nr)
+        .map(|i| word(next + 1 + i))
+        .collect::<Option<Vec<u64>>>()?;
+    stack.retain(|&addr| addr != 0 && addr < PERF_CONTEXT_MAX);
+    if stack.first() != Some(&ip) {
+        stack.insert(0, ip);
+    }
+    Some(CpuSample {
+        ip,
+        stack,
+        pid,
+        tid,
+        counters,
+    })
 }
 
 impl Drop for PerfEvent {
     fn drop(&mut self) {
         unsafe {
             // Disable the event
-            libc::ioctl(self.fd.as_raw_fd(), 0x2401, 0); // PERF_EVENT_IOC_DISABLE
+            libc::ioctl(self.fd.as_raw_fd(), 0x2401, PERF_IOC_FLAG_GROUP); // PERF_EVENT_IOC_DISABLE
             // Unmap
             libc::munmap(self.mmap as *mut libc::c_void, self.mmap_size);
         }
@@ -295,20 +623,79 @@ impl Drop for PerfEvent {
 }
 
 /// Check /proc/sys/kernel/perf_event_paranoid
-fn check_perf_paranoid() -> Result<()> {
+///
+/// Warns once per run, however many events are opened.
+pub(super) fn check_perf_paranoid() -> Result<()> {
+    static WARNED: std::sync::Once = std::sync::Once::new();
     let path = "/proc/sys/kernel/perf_event_paranoid";
     match fs::read_to_string(path) {
         Ok(content) => {
             let level: i32 = content.trim().parse().unwrap_or(2);
             if level > 1 {
-                eprintln!(
-                    "Warning: perf_event_paranoid={}, profiling may be restricted.",
-                    level
-                );
-                eprintln!("Consider: sudo sysctl kernel.perf_event_paranoid=1");
+                WARNED.call_once(|| {
+                    eprintln!(
+                        "Warning: perf_event_paranoid={}, profiling may be restricted.",
+                        level
+                    );
+                    eprintln!("Consider: sudo sysctl kernel.perf_event_paranoid=1");
+                });
             }
             Ok(())
         }
         Err(_) => Ok(()), // File might not exist on some systems
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A PERF_RECORD_SAMPLE for a leader with cycles and instructions
+    /// members, with a one-frame call chain
+    fn group_sample(ip: u64, cycles: u64, instructions: u64) -> Vec<u8> {
+        let pid_tid = u64::from_ne_bytes(
+            [42u32.to_ne_bytes(), 43u32.to_ne_bytes()]
+                .concat()
+                .try_into()
+                .unwrap(),
+        );
+        let words = [ip, pid_tid, 1_000, 3, 250_000, cycles, instructions, 1, ip];
+        let size = std::mem::size_of::<PerfEventHeader>() + words.len() * 8;
+        let mut record = Vec::with_capacity(size);
+        record.extend_from_slice(&PERF_RECORD_SAMPLE.to_ne_bytes());
+        record.extend_from_slice(&0u16.to_ne_bytes());
+        record.extend_from_slice(&(size as u16).to_ne_bytes());
+        for word in words {
+            record.extend_from_slice(&word.to_ne_bytes());
+        }
+        record
+    }
+
+    #[test]
+    fn group_samples_carry_every_counter_value() {
+        // Two 80-byte records in a 160-byte ring, the second wrapping around
+        let mut ring = vec![0u8; 160];
+        let first = group_sample(0x40_1000, 5_000, 9_000);
+        let second = group_sample(0x40_2000, 7_500, 12_000);
+        ring[40..120].copy_from_slice(&first);
+        ring[120..].copy_from_slice(&second[..40]);
+        ring[..40].copy_from_slice(&second[40..]);
+
+        let mut record = Vec::new();
+        copy_from_ring(&ring, 40, first.len(), &mut record);
+        let sample = parse_sample(&record, 2).unwrap();
+        assert_eq!(sample.ip, 0x40_1000);
+        assert_eq!((sample.pid, sample.tid), (42, 43));
+        assert_eq!(sample.counters, [5_000, 9_000, 0, 0]);
+
+        copy_from_ring(&ring, 120, second.len(), &mut record);
+        assert_eq!(record, second);
+        let sample = parse_sample(&record, 2).unwrap();
+        assert_eq!(sample.ip, 0x40_2000);
+        assert_eq!(sample.stack, [0x40_2000]);
+        assert_eq!(sample.counters, [7_500, 12_000, 0, 0]);
+
+        // A group read of the wrong size is skipped rather than misread
+        assert_eq!(parse_sample(&record, 1), None);
+    }
+}
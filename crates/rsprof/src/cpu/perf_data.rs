@@ -0,0 +1,408 @@
+//! Reader for perf.data files written by `perf record`
+//!
+//! Only what `rsprof import-perf` needs: samples with their callchains,
+//! executable mappings to symbolicate them, process names and the build-ids
+//! perf recorded for each mapped file. Little-endian file mode only (not
+//! `perf record -o -` pipe output).
+
+use super::perf::{PERF_FORMAT_GROUP, PERF_SAMPLE_TID, PERF_SAMPLE_TIME};
+use super::perf::{PERF_RECORD_LOST, PERF_RECORD_SAMPLE, PERF_SAMPLE_IP, PERF_SAMPLE_READ};
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"PERFILE2";
+/// Size of `struct perf_file_header`
+const FILE_HEADER_SIZE: u64 = 104;
+
+const PERF_SAMPLE_ADDR: u64 = 1 << 3;
+const PERF_SAMPLE_CALLCHAIN: u64 = 1 << 5;
+const PERF_SAMPLE_ID: u64 = 1 << 6;
+const PERF_SAMPLE_CPU: u64 = 1 << 7;
+const PERF_SAMPLE_PERIOD: u64 = 1 << 8;
+const PERF_SAMPLE_STREAM_ID: u64 = 1 << 9;
+const PERF_SAMPLE_IDENTIFIER: u64 = 1 << 16;
+
+const PERF_FORMAT_TOTAL_TIME_ENABLED: u64 = 1 << 0;
+const PERF_FORMAT_TOTAL_TIME_RUNNING: u64 = 1 << 1;
+const PERF_FORMAT_ID: u64 = 1 << 2;
+const PERF_FORMAT_LOST: u64 = 1 << 4;
+
+/// `perf_event_attr.freq`: sample_period holds a frequency
+const ATTR_FREQ_BIT: u64 = 1 << 10;
+
+const PERF_RECORD_MMAP: u32 = 1;
+const PERF_RECORD_COMM: u32 = 3;
+const PERF_RECORD_MMAP2: u32 = 10;
+
+/// MMAP2 carries a build-id instead of device and inode numbers
+const PERF_RECORD_MISC_MMAP_BUILD_ID: u16 = 1 << 14;
+/// Build-id records store the build-id length
+const PERF_RECORD_MISC_BUILD_ID_SIZE: u16 = 1 << 15;
+const PERF_RECORD_MISC_CPUMODE_MASK: u16 = 7;
+const PERF_RECORD_MISC_KERNEL: u16 = 1;
+const PERF_RECORD_MISC_GUEST_KERNEL: u16 = 4;
+
+/// Feature section holding build-id records
+const HEADER_BUILD_ID: usize = 2;
+
+/// Callchain entries at or above this mark a context switch (kernel/user)
+pub const PERF_CONTEXT_MAX: u64 = -4095i64 as u64;
+
+/// One sample of the first recorded event
+#[derive(Debug, Clone)]
+pub struct PerfDataSample {
+    pub pid: u32,
+    pub tid: u32,
+    /// perf clock time in ns (0 without PERF_SAMPLE_TIME)
+    pub time_ns: u64,
+    pub ip: u64,
+    /// Sampled in kernel mode
+    pub kernel: bool,
+    /// Instruction pointer first, then return addresses; context markers removed
+    pub callchain: Vec<u64>,
+}
+
+/// An executable file mapping (PERF_RECORD_MMAP/MMAP2)
+#[derive(Debug, Clone)]
+pub struct PerfMmap {
+    /// u32::MAX for kernel mappings
+    pub pid: u32,
+    pub start: u64,
+    pub len: u64,
+    pub pgoff: u64,
+    pub filename: String,
+}
+
+/// A parsed perf.data file
+#[derive(Debug, Default)]
+pub struct PerfData {
+    pub samples: Vec<PerfDataSample>,
+    pub mmaps: Vec<PerfMmap>,
+    /// Process name per PID (last COMM seen for the main thread)
+    pub comms: HashMap<u32, String>,
+    /// Build-id (hex) per mapped file, from the mappings and the build-id table
+    pub build_ids: HashMap<String, String>,
+    /// Sampling frequency, if the first event was sampled by frequency
+    pub sample_freq: Option<u64>,
+    /// Number of events recorded; only the first one's samples are read
+    pub events: usize,
+    /// Samples the kernel reported as lost
+    pub lost: u64,
+}
+
+/// The parts of `perf_event_attr` needed to decode samples
+#[derive(Debug, Clone, Copy)]
+struct Attr {
+    sample_type: u64,
+    read_format: u64,
+}
+
+impl PerfData {
+    /// Read and parse a perf.data file
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        let mmap = unsafe { memmap2::Mmap::map(&file) }?;
+        Self::parse(&mmap)
+            .map_err(|message| Error::InvalidArgument(format!("{}: {}", path.display(), message)))
+    }
+
+    /// Parse perf.data contents
+    pub fn parse(data: &[u8]) -> std::result::Result<Self, String> {
+        let bytes = Bytes(data);
+        if data.len() < 16 || &data[..8] != MAGIC {
+            if data.len() >= 8 && data[..8].iter().rev().eq(MAGIC.iter()) {
+                return Err("big-endian perf.data is not supported".to_string());
+            }
+            return Err("not a perf.data file".to_string());
+        }
+        let header_size = bytes.u64(8)?;
+        if header_size != FILE_HEADER_SIZE {
+            return Err("pipe-mode perf.data is not supported; record with -o <file>".to_string());
+        }
+        let attr_size = bytes.u64(16)?;
+        let (attrs_offset, attrs_size) = (bytes.u64(24)?, bytes.u64(32)?);
+        let (data_offset, data_size) = (bytes.u64(40)?, bytes.u64(48)?);
+
+        // Attributes, each followed by the section listing its sample ids
+        let mut attrs = Vec::new();
+        let mut attr_by_id = HashMap::new();
+        let mut sample_freq = None;
+        if attr_size == 0 {
+            return Err("no event attributes".to_string());
+        }
+        for i in 0..attrs_size / attr_size {
+            let at = (attrs_offset + i * attr_size) as usize;
+            let size = bytes.u32(at + 4)? as usize;
+            let attr = Attr {
+                sample_type: bytes.u64(at + 24)?,
+                read_format: bytes.u64(at + 32)?,
+            };
+            if i == 0 && bytes.u64(at + 40)? & ATTR_FREQ_BIT != 0 {
+                sample_freq = Some(bytes.u64(at + 16)?);
+            }
+            let (ids_offset, ids_size) = (bytes.u64(at + size)?, bytes.u64(at + size + 8)?);
+            for j in 0..ids_size / 8 {
+                attr_by_id.insert(bytes.u64((ids_offset + j * 8) as usize)?, attrs.len());
+            }
+            attrs.push(attr);
+        }
+        if attrs.is_empty() {
+            return Err("no event attributes".to_string());
+        }
+        let same_format = attrs.iter().all(|a| a.sample_type == attrs[0].sample_type);
+        if !same_format && attrs[0].sample_type & PERF_SAMPLE_IDENTIFIER == 0 {
+            return Err("events with different sample formats are not supported".to_string());
+        }
+
+        let mut perf = PerfData {
+            sample_freq,
+            events: attrs.len(),
+            ..Default::default()
+        };
+
+        let end = (data_offset + data_size) as usize;
+        let mut at = data_offset as usize;
+        while at + 8 <= end {
+            let kind = bytes.u32(at)?;
+            let misc = bytes.u16(at + 4)?;
+            let size = bytes.u16(at + 6)? as usize;
+            if size < 8 {
+                return Err(format!("corrupt record at offset {}", at));
+            }
+            let record = Bytes(bytes.slice(at, size)?);
+            match kind {
+                PERF_RECORD_SAMPLE => {
+                    // Samples of other events than the first are skipped
+                    let attr = if attrs.len() == 1 {
+                        0
+                    } else if attrs[0].sample_type & PERF_SAMPLE_IDENTIFIER != 0 {
+                        attr_by_id
+                            .get(&record.u64(8)?)
+                            .copied()
+                            .unwrap_or(usize::MAX)
+                    } else {
+                        parse_sample_id(&record, attrs[0])
+                            .and_then(|id| attr_by_id.get(&id).copied())
+                            .unwrap_or(usize::MAX)
+                    };
+                    if attr == 0 {
+                        perf.samples.push(parse_sample(&record, misc, attrs[0])?);
+                    }
+                }
+                PERF_RECORD_MMAP | PERF_RECORD_MMAP2 => {
+                    let pid = record.u32(8)?;
+                    let (start, len, pgoff) = (record.u64(16)?, record.u64(24)?, record.u64(32)?);
+                    let filename_at = if kind == PERF_RECORD_MMAP { 40 } else { 72 };
+                    let filename = record.str(filename_at, size)?;
+                    if kind == PERF_RECORD_MMAP2 && misc & PERF_RECORD_MISC_MMAP_BUILD_ID != 0 {
+                        let id_size = (record.u8(40)? as usize).min(20);
+                        let id = record.slice(44, id_size)?;
+                        perf.build_ids.insert(filename.clone(), hex(id));
+                    }
+                    perf.mmaps.push(PerfMmap {
+                        pid,
+                        start,
+                        len,
+                        pgoff,
+                        filename,
+                    });
+                }
+                PERF_RECORD_COMM => {
+                    let (pid, tid) = (record.u32(8)?, record.u32(12)?);
+                    if pid == tid {
+                        perf.comms.insert(pid, record.str(16, size)?);
+                    }
+                }
+                PERF_RECORD_LOST => perf.lost += record.u64(16)?,
+                _ => {}
+            }
+            at += size;
+        }
+
+        // Feature sections follow the data, one per set bit of adds_features
+        let mut feature_index = 0;
+        for bit in 0..256 {
+            if bytes.u64(72 + bit / 64 * 8)? & (1 << (bit % 64)) == 0 {
+                continue;
+            }
+            if bit == HEADER_BUILD_ID {
+                let section = (data_offset + data_size) as usize + feature_index * 16;
+                let (offset, size) = (bytes.u64(section)?, bytes.u64(section + 8)?);
+                parse_build_ids(&bytes, offset as usize, size as usize, &mut perf.build_ids)?;
+            }
+            feature_index += 1;
+        }
+
+        Ok(perf)
+    }
+}
+
+/// Decode a PERF_RECORD_SAMPLE up to its callchain
+fn parse_sample(
+    record: &Bytes,
+    misc: u16,
+    attr: Attr,
+) -> std::result::Result<PerfDataSample, String> {
+    let sample_type = attr.sample_type;
+    let mut at = 8;
+    let mut next = |len: usize| {
+        let field = at;
+        at += len;
+        field
+    };
+    if sample_type & PERF_SAMPLE_IDENTIFIER != 0 {
+        next(8);
+    }
+    let ip = if sample_type & PERF_SAMPLE_IP != 0 {
+        record.u64(next(8))?
+    } else {
+        0
+    };
+    let (pid, tid) = if sample_type & PERF_SAMPLE_TID != 0 {
+        let field = next(8);
+        (record.u32(field)?, record.u32(field + 4)?)
+    } else {
+        (0, 0)
+    };
+    let time_ns = if sample_type & PERF_SAMPLE_TIME != 0 {
+        record.u64(next(8))?
+    } else {
+        0
+    };
+    for flag in [
+        PERF_SAMPLE_ADDR,
+        PERF_SAMPLE_ID,
+        PERF_SAMPLE_STREAM_ID,
+        PERF_SAMPLE_CPU,
+        PERF_SAMPLE_PERIOD,
+    ] {
+        if sample_type & flag != 0 {
+            next(8);
+        }
+    }
+    if sample_type & PERF_SAMPLE_READ != 0 {
+        let format = attr.read_format;
+        let per_value = 8
+            + 8 * (format & PERF_FORMAT_ID != 0) as usize
+            + 8 * (format & PERF_FORMAT_LOST != 0) as usize;
+        let times = 8 * (format & PERF_FORMAT_TOTAL_TIME_ENABLED != 0) as usize
+            + 8 * (format & PERF_FORMAT_TOTAL_TIME_RUNNING != 0) as usize;
+        if format & PERF_FORMAT_GROUP != 0 {
+            let nr = record.u64(next(8))? as usize;
+            next(times + nr * per_value);
+        } else {
+            next(per_value + times);
+        }
+    }
+
+    let mut callchain = Vec::new();
+    if sample_type & PERF_SAMPLE_CALLCHAIN != 0 {
+        let nr = record.u64(next(8))? as usize;
+        let ips = next(nr * 8);
+        for i in 0..nr {
+            let addr = record.u64(ips + i * 8)?;
+            if addr < PERF_CONTEXT_MAX {
+                callchain.push(addr);
+            }
+        }
+    }
+    if callchain.first() != Some(&ip) && ip != 0 {
+        callchain.insert(0, ip);
+    }
+
+    let cpumode = misc & PERF_RECORD_MISC_CPUMODE_MASK;
+    Ok(PerfDataSample {
+        pid,
+        tid,
+        time_ns,
+        ip,
+        kernel: cpumode == PERF_RECORD_MISC_KERNEL || cpumode == PERF_RECORD_MISC_GUEST_KERNEL,
+        callchain,
+    })
+}
+
+/// Sample id of a record without PERF_SAMPLE_IDENTIFIER (PERF_SAMPLE_ID's slot)
+fn parse_sample_id(record: &Bytes, attr: Attr) -> Option<u64> {
+    let sample_type = attr.sample_type;
+    if sample_type & PERF_SAMPLE_ID == 0 {
+        return None;
+    }
+    let before = [
+        PERF_SAMPLE_IP,
+        PERF_SAMPLE_TID,
+        PERF_SAMPLE_TIME,
+        PERF_SAMPLE_ADDR,
+    ]
+    .iter()
+    .filter(|&&flag| sample_type & flag != 0)
+    .count();
+    record.u64(8 + before * 8).ok()
+}
+
+/// Read the HEADER_BUILD_ID feature: `build_id_event` records
+fn parse_build_ids(
+    bytes: &Bytes,
+    offset: usize,
+    size: usize,
+    build_ids: &mut HashMap<String, String>,
+) -> std::result::Result<(), String> {
+    let mut at = offset;
+    while at + 8 <= offset + size {
+        let misc = bytes.u16(at + 4)?;
+        let len = bytes.u16(at + 6)? as usize;
+        if len < 36 {
+            return Err(format!("corrupt build-id record at offset {}", at));
+        }
+        // header, pid, build_id[24], filename
+        let id_size = if misc & PERF_RECORD_MISC_BUILD_ID_SIZE != 0 {
+            (bytes.u8(at + 32)? as usize).min(20)
+        } else {
+            20
+        };
+        let id = bytes.slice(at + 12, id_size)?;
+        let filename = bytes.str(at + 36, at + len)?;
+        build_ids.entry(filename).or_insert_with(|| hex(id));
+        at += len;
+    }
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Bounds-checked little-endian reads
+struct Bytes<'a>(&'a [u8]);
+
+impl<'a> Bytes<'a> {
+    fn slice(&self, at: usize, len: usize) -> std::result::Result<&'a [u8], String> {
+        self.0
+            .get(at..at.saturating_add(len))
+            .ok_or_else(|| format!("truncated at offset {}", at))
+    }
+
+    fn u8(&self, at: usize) -> std::result::Result<u8, String> {
+        Ok(self.slice(at, 1)?[0])
+    }
+
+    fn u16(&self, at: usize) -> std::result::Result<u16, String> {
+        Ok(u16::from_le_bytes(self.slice(at, 2)?.try_into().unwrap()))
+    }
+
+    fn u32(&self, at: usize) -> std::result::Result<u32, String> {
+        Ok(u32::from_le_bytes(self.slice(at, 4)?.try_into().unwrap()))
+    }
+
+    fn u64(&self, at: usize) -> std::result::Result<u64, String> {
+        Ok(u64::from_le_bytes(self.slice(at, 8)?.try_into().unwrap()))
+    }
+
+    /// NUL-terminated string in `[at, end)`
+    fn str(&self, at: usize, end: usize) -> std::result::Result<String, String> {
+        let bytes = self.slice(at, end.saturating_sub(at))?;
+        let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+    }
+}
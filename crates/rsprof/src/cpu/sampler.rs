@@ -1,26 +1,254 @@
-use super::perf::PerfEvent;
-use crate::error::Result;
+use super::perf::{CpuSample, MAX_COUNTERS, PerfCounter, PerfEvent, online_cpus};
+use crate::error::{Error, Result};
+use crate::process::{ThreadFilter, task_ids, threads_in};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How often `/proc/<pid>/task` is re-read for threads started or exited
+/// since (see `new` and `for_threads`)
+const RESCAN_INTERVAL: Duration = Duration::from_secs(1);
 
 /// CPU sampler that reads perf_event samples
 pub struct CpuSampler {
     /// Per-thread perf events
     events: Vec<PerfEvent>,
+    /// Hardware counters read with each sample
+    counters: Vec<PerfCounter>,
+    /// Sampling frequency, for events opened on new threads
+    freq: u64,
+    /// Events are inherited by forked children (see `following_forks`)
+    follows_forks: bool,
+    /// Threads of a process, kept up to date (see `new` and `for_threads`)
+    scan: Option<ThreadScan>,
+    /// Samples lost by events of threads that have exited
+    exited_lost: u64,
+}
+
+/// Threads of the process `new` or `for_threads` samples
+struct ThreadScan {
+    pid: u32,
+    /// Thread each event samples, parallel to `events`
+    tids: Vec<u32>,
+    scanned: Instant,
+    /// Only threads it allows are sampled (all when `None`)
+    filter: Option<ThreadFilter>,
 }
 
 impl CpuSampler {
     /// Create a new CPU sampler for all threads of a process
-    pub fn new(pid: u32, freq: u64) -> Result<Self> {
-        // For now, just sample the main thread
-        // TODO: Sample all threads by enumerating /proc/[pid]/task/
-        let event = PerfEvent::open(pid as i32, freq)?;
+    ///
+    /// Each thread gets its own event. The thread list is re-read every
+    /// `RESCAN_INTERVAL` while sampling, so threads started later are
+    /// sampled too. Without /proc only the main thread is.
+    pub fn new(pid: u32, freq: u64, counters: &[PerfCounter]) -> Result<Self> {
+        Self::check_counters(counters)?;
+
+        let Ok(tids) = task_ids(pid) else {
+            let event = PerfEvent::open(pid as i32, freq, counters)?;
+            log::info!(
+                "cpu_sampler_opened pid={} freq_hz={} threads=1 counters={} rescan=false",
+                pid,
+                freq,
+                counters.len()
+            );
+            return Ok(CpuSampler {
+                events: vec![event],
+                counters: counters.to_vec(),
+                freq,
+                follows_forks: false,
+                scan: None,
+                exited_lost: 0,
+            });
+        };
+
+        let mut events = Vec::with_capacity(tids.len());
+        let mut opened = Vec::with_capacity(tids.len());
+        for tid in tids {
+            match PerfEvent::open(tid as i32, freq, counters) {
+                Ok(event) => {
+                    events.push(event);
+                    opened.push(tid);
+                }
+                // The thread exited since it was listed
+                Err(Error::ProcessNotFound(_)) if tid != pid => {}
+                Err(e) => return Err(e),
+            }
+        }
+        log::info!(
+            "cpu_sampler_opened pid={} freq_hz={} threads={} counters={} rescan=true",
+            pid,
+            freq,
+            events.len(),
+            counters.len()
+        );
 
         Ok(CpuSampler {
-            events: vec![event],
+            events,
+            counters: counters.to_vec(),
+            freq,
+            follows_forks: false,
+            scan: Some(ThreadScan {
+                pid,
+                tids: opened,
+                scanned: Instant::now(),
+                filter: None,
+            }),
+            exited_lost: 0,
         })
     }
 
+    /// Create a CPU sampler for the threads of a process a filter allows
+    ///
+    /// Only those threads get a perf fd, so other threads contribute no
+    /// samples. Like `new`, the threads are re-read every `RESCAN_INTERVAL`:
+    /// threads started (or renamed) to match are sampled from then on, and
+    /// those renamed away stop being sampled.
+    pub fn for_threads(
+        pid: u32,
+        filter: ThreadFilter,
+        freq: u64,
+        counters: &[PerfCounter],
+    ) -> Result<Self> {
+        Self::check_counters(counters)?;
+
+        let threads = threads_in(Path::new("/proc"), pid).map_err(|e| {
+            Error::ProcessNotFound(format!("Cannot read tasks for PID {}: {}", pid, e))
+        })?;
+        let mut events = Vec::new();
+        let mut opened = Vec::new();
+        for thread in filter.select(threads) {
+            match PerfEvent::open(thread.tid as i32, freq, counters) {
+                Ok(event) => {
+                    events.push(event);
+                    opened.push(thread.tid);
+                }
+                // The thread exited since it was listed
+                Err(Error::ProcessNotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        if events.is_empty() {
+            return Err(Error::Sampler("No threads to sample".to_string()));
+        }
+        log::info!(
+            "cpu_sampler_opened pid={} tids={:?} freq_hz={} threads={} counters={} rescan=true",
+            pid,
+            opened,
+            freq,
+            events.len(),
+            counters.len()
+        );
+
+        Ok(CpuSampler {
+            events,
+            counters: counters.to_vec(),
+            freq,
+            follows_forks: false,
+            scan: Some(ThreadScan {
+                pid,
+                tids: opened,
+                scanned: Instant::now(),
+                filter: Some(filter),
+            }),
+            exited_lost: 0,
+        })
+    }
+
+    /// Create a CPU sampler for processes and the children they fork
+    ///
+    /// Each of `pids` is sampled with `inherit` set, so processes (and
+    /// threads) it creates after this call are sampled as well. Samples carry
+    /// the PID they were taken in.
+    pub fn following_forks(pids: &[u32], freq: u64, counters: &[PerfCounter]) -> Result<Self> {
+        // Inherited events can't carry group reads
+        if !counters.is_empty() {
+            return Err(Error::InvalidArgument(
+                "--counters can't be recorded with --follow-forks".to_string(),
+            ));
+        }
+
+        let cpus = online_cpus();
+        let mut events = Vec::with_capacity(pids.len() * cpus.len());
+        for &pid in pids {
+            for &cpu in &cpus {
+                events.push(PerfEvent::open_inherited(pid as i32, cpu, freq)?);
+            }
+        }
+
+        if events.is_empty() {
+            return Err(Error::Sampler("No processes to sample".to_string()));
+        }
+        log::info!(
+            "cpu_sampler_opened pids={:?} freq_hz={} cpus={} inherit=true",
+            pids,
+            freq,
+            cpus.len()
+        );
+
+        Ok(CpuSampler {
+            events,
+            counters: Vec::new(),
+            freq,
+            follows_forks: true,
+            scan: None,
+            exited_lost: 0,
+        })
+    }
+
+    fn check_counters(counters: &[PerfCounter]) -> Result<()> {
+        if counters.len() > MAX_COUNTERS {
+            return Err(Error::InvalidArgument(format!(
+                "At most {} counters can be recorded together, got {}",
+                MAX_COUNTERS,
+                counters.len()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether samples come from forked children too, tagged with their PID
+    pub fn follows_forks(&self) -> bool {
+        self.follows_forks
+    }
+
+    /// Hardware counters whose values each sample carries, in order
+    pub fn counters(&self) -> &[PerfCounter] {
+        &self.counters
+    }
+
+    /// A sample's counter deltas paired with the counter names
+    pub fn named_counters(&self, sample: &CpuSample) -> Vec<(&'static str, u64)> {
+        self.counters
+            .iter()
+            .zip(sample.counters)
+            .map(|(counter, value)| (counter.name(), value))
+            .collect()
+    }
+
+    /// A sample's delta for `counter`, if it is being recorded
+    pub fn counter_value(&self, sample: &CpuSample, counter: PerfCounter) -> Option<u64> {
+        let index = self.counters.iter().position(|&c| c == counter)?;
+        Some(sample.counters[index])
+    }
+
+    /// Change the sampling frequency of every thread's event
+    pub fn set_frequency(&mut self, freq: u64) -> Result<()> {
+        for event in &self.events {
+            event.set_frequency(freq)?;
+        }
+        self.freq = freq;
+        Ok(())
+    }
+
+    /// Samples the kernel dropped (ring buffer full) since the last call
+    pub fn take_lost(&mut self) -> u64 {
+        let lost: u64 = self.events.iter_mut().map(|event| event.take_lost()).sum();
+        lost + std::mem::take(&mut self.exited_lost)
+    }
+
     /// Read all available samples from all threads
-    pub fn read_samples(&mut self) -> Result<Vec<u64>> {
+    pub fn read_samples(&mut self) -> Result<Vec<CpuSample>> {
         let mut all_samples = Vec::new();
 
         for event in &mut self.events {
@@ -28,6 +256,81 @@ impl CpuSampler {
             all_samples.extend(samples);
         }
 
+        if self
+            .scan
+            .as_ref()
+            .is_some_and(|scan| scan.scanned.elapsed() >= RESCAN_INTERVAL)
+        {
+            self.rescan(&mut all_samples);
+        }
+
         Ok(all_samples)
     }
+
+    /// Open events for threads started since the last scan and close those
+    /// of threads that exited, keeping their last samples
+    ///
+    /// With a filter, threads renamed to match count as started and those
+    /// renamed away as exited.
+    fn rescan(&mut self, samples: &mut Vec<CpuSample>) {
+        let Some(scan) = self.scan.as_mut() else {
+            return;
+        };
+        scan.scanned = Instant::now();
+        // The process itself is gone; its events just stop producing samples
+        let current = match &scan.filter {
+            Some(filter) => threads_in(Path::new("/proc"), scan.pid)
+                .map(|threads| filter.select(threads).iter().map(|t| t.tid).collect()),
+            None => task_ids(scan.pid),
+        };
+        let Ok(current) = current else {
+            return;
+        };
+
+        let mut exited = 0;
+        let mut index = 0;
+        while index < scan.tids.len() {
+            if current.contains(&scan.tids[index]) {
+                index += 1;
+                continue;
+            }
+            let mut event = self.events.swap_remove(index);
+            scan.tids.swap_remove(index);
+            samples.extend(event.read_samples());
+            self.exited_lost += event.take_lost();
+            exited += 1;
+        }
+
+        let mut started = 0;
+        for tid in current {
+            if scan.tids.contains(&tid) {
+                continue;
+            }
+            match PerfEvent::open(tid as i32, self.freq, &self.counters) {
+                Ok(event) => {
+                    self.events.push(event);
+                    scan.tids.push(tid);
+                    started += 1;
+                }
+                // Exited already, or can't be sampled: the other threads still are
+                Err(e) => log::debug!("cpu_sampler_thread_skipped tid={} error={}", tid, e),
+            }
+        }
+
+        if started > 0 || exited > 0 {
+            log::info!(
+                "cpu_sampler_threads pid={} started={} exited={} threads={}",
+                scan.pid,
+                started,
+                exited,
+                scan.tids.len()
+            );
+        }
+    }
+}
+
+impl Drop for CpuSampler {
+    fn drop(&mut self) {
+        log::info!("cpu_sampler_closed threads={}", self.events.len());
+    }
 }
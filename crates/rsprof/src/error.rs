@@ -20,6 +20,9 @@ pub enum Error {
     #[error("Sampler error: {0}")]
     Sampler(String),
 
+    #[error("Incompatible rsprof-trace shared memory: {0}")]
+    IncompatibleShm(String),
+
     #[error("Database error: {0}")]
     Database(#[from] rusqlite::Error),
 
@@ -34,6 +37,12 @@ pub enum Error {
 
     #[error("Unsupported platform: {0}")]
     UnsupportedPlatform(String),
+
+    #[error("Interrupted")]
+    Interrupted,
+
+    #[error("{0} function(s) regressed beyond the allowed threshold")]
+    Regression(usize),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -47,6 +56,7 @@ pub mod exit_code {
     pub const PERMISSION_DENIED: i32 = 4;
     pub const MISSING_DEBUG_INFO: i32 = 5;
     pub const DATABASE_ERROR: i32 = 6;
+    pub const INTERRUPTED: i32 = 130; // 128 + SIGINT, as a shell reports it
 }
 
 impl Error {
@@ -59,6 +69,7 @@ impl Error {
             Error::MissingDebugInfo { .. } => exit_code::MISSING_DEBUG_INFO,
             Error::Database(_) => exit_code::DATABASE_ERROR,
             Error::InvalidArgument(_) => exit_code::INVALID_ARGUMENTS,
+            Error::Interrupted => exit_code::INTERRUPTED,
             _ => exit_code::GENERAL_ERROR,
         }
     }
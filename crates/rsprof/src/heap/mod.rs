@@ -9,6 +9,9 @@
 
 // Shared memory sampler (always available) - reads from rsprof-trace
 mod shm_sampler;
+mod sizes;
 pub use shm_sampler::{
-    CpuSample, HeapStats as ShmHeapStats, ShmHeapSampler, TraceEvent, TraceEventType,
+    CpuSample, HeapStats as ShmHeapStats, MarkerStats, ShmHeapSampler, ThreadSamples, TraceEvent,
+    TraceEventType, TransferStats, UNSTACKED_CALLSITE, attach_without_procfs, samples_by_process,
 };
+pub use sizes::{SIZE_BUCKETS, SizeDistribution};
@@ -3,10 +3,15 @@
 //! This reader reads pre-aggregated CPU and heap stats from shared memory
 //! populated by the rsprof-trace crate. No event processing needed.
 
+use super::sizes::{SIZE_BUCKETS, SizeDistribution};
 use crate::error::{Error, Result};
+use crate::process::{ProcessInfo, ThreadFilter, ns_pids, threads_in};
 use std::collections::HashMap;
-use std::path::Path;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 /// Maximum stack depth (must match rsprof-trace)
 const MAX_STACK_DEPTH: usize = 64;
@@ -14,11 +19,41 @@ const MAX_STACK_DEPTH: usize = 64;
 /// Callsite table capacity (must match rsprof-trace)
 const CALLSITE_CAPACITY: usize = 8192;
 
+/// Callsite hash for allocations recorded without a stack (must match rsprof-trace)
+pub const UNSTACKED_CALLSITE: u64 = u64::MAX - 1;
+
+/// Tombstone marker for freed alloc table entries (must match rsprof-trace)
+const TOMBSTONE: u64 = u64::MAX;
+
 /// Shared memory path (must match rsprof-trace)
 const SHM_PATH: &str = "/rsprof-trace";
 
-/// Magic number for validation (must match rsprof-trace v3)
-const MAGIC: u64 = 0x5253_5052_4F46_5333; // "RSPROFS3"
+/// Maximum marker name length (must match rsprof-trace)
+const MARKER_NAME_LEN: usize = 64;
+
+/// Maximum checkpoint label length (must match rsprof-trace)
+const LABEL_LEN: usize = 64;
+
+/// Maximum executable path length (must match rsprof-trace)
+const EXE_PATH_LEN: usize = 256;
+
+/// Thread filter capacity (must match rsprof-trace)
+const THREAD_FILTER_CAPACITY: usize = 256;
+
+/// Thread filter mode: only listed threads are recorded (must match rsprof-trace)
+const THREAD_FILTER_ONLY: u32 = 1;
+
+/// Thread filter mode: listed threads are not recorded (must match rsprof-trace)
+const THREAD_FILTER_EXCEPT: u32 = 2;
+
+/// How often the thread filter is re-matched against the target's threads
+const THREAD_FILTER_REFRESH: Duration = Duration::from_secs(1);
+
+/// Magic number for validation (must match rsprof-trace)
+const MAGIC: u64 = 0x5253_5052_4F46_5341; // "RSPROFSA"
+
+/// Shared memory layout version (must match rsprof-trace)
+const VERSION: u32 = 17;
 
 /// Shared memory header (must match rsprof-trace)
 #[repr(C)]
@@ -28,6 +63,23 @@ struct StatsHeader {
     callsite_capacity: u32,
     alloc_table_capacity: u32,
     pid: u32,
+    marker_capacity: u32,
+    cpu_freq_hz: AtomicU32,
+    overhead_ns: AtomicU64,
+    label_seq: AtomicU64,
+    label: [u8; LABEL_LEN],
+    cpu_overhead_ns: AtomicU64,
+    cpu_freq_request_hz: AtomicU32,
+    free_sites: AtomicU32,
+    transfer_capacity: u32,
+    follow_forks: AtomicU32,
+    aslr_offset: u64,
+    exe_path: [u8; EXE_PATH_LEN],
+    dropped_events: AtomicU64,
+    thread_sample_capacity: u32,
+    thread_filter_mode: AtomicU32,
+    thread_filter_len: AtomicU32,
+    thread_filter: [AtomicU32; THREAD_FILTER_CAPACITY],
 }
 
 /// Callsite stats (must match rsprof-trace)
@@ -39,11 +91,92 @@ struct ShmCallsiteStats {
     free_count: AtomicU64,
     free_bytes: AtomicU64,
     cpu_samples: AtomicU64,
+    min_size_inv: AtomicU64,
+    max_size: AtomicU64,
+    size_hist: [AtomicU64; SIZE_BUCKETS],
     stack_depth: AtomicU32,
     _reserved: u32,
     stack: [AtomicU64; MAX_STACK_DEPTH],
 }
 
+/// Allocation tracking entry (must match rsprof-trace)
+#[repr(C)]
+struct ShmAllocEntry {
+    ptr: AtomicU64,
+    size: AtomicU64,
+    callsite_hash: AtomicU64,
+    #[allow(dead_code)]
+    marker: AtomicU32,
+    /// Allocations the entry stands for when the target samples (0 = 1)
+    weight: AtomicU32,
+    alloc_ns: AtomicU64,
+}
+
+/// Marker stats (must match rsprof-trace)
+#[repr(C)]
+struct ShmMarkerStats {
+    hash: AtomicU64,
+    alloc_count: AtomicU64,
+    alloc_bytes: AtomicU64,
+    free_count: AtomicU64,
+    free_bytes: AtomicU64,
+    ready: AtomicU32,
+    name: [u8; MARKER_NAME_LEN],
+}
+
+/// Alloc/free site pair counts (must match rsprof-trace)
+#[repr(C)]
+struct ShmTransferStats {
+    key: AtomicU64,
+    alloc_hash: AtomicU64,
+    free_hash: AtomicU64,
+    count: AtomicU64,
+    bytes: AtomicU64,
+}
+
+/// Per-thread CPU sample counts (must match rsprof-trace)
+#[repr(C)]
+struct ShmThreadSampleStats {
+    key: AtomicU64,
+    callsite_hash: AtomicU64,
+    tid: AtomicU32,
+    pid: AtomicU32,
+    samples: AtomicU64,
+}
+
+/// CPU samples a thread took at a callsite since the last read
+#[derive(Debug, Clone)]
+pub struct ThreadSamples {
+    /// Callsite hash (key into `read_cpu_stats`)
+    pub callsite: u64,
+    pub tid: u32,
+    /// Process the thread belongs to (the target, or a child it forked)
+    pub pid: u32,
+    pub count: u64,
+}
+
+/// Allocations made at one callsite and freed at another (cumulative)
+#[derive(Debug, Clone)]
+pub struct TransferStats {
+    /// Callsite hash of the allocation (key into `read_inline_stacks`)
+    pub alloc_hash: u64,
+    /// Callsite hash of the free
+    pub free_hash: u64,
+    pub count: u64,
+    pub bytes: u64,
+}
+
+/// Heap stats attributed to a marker (set with `rsprof_trace::mark`)
+#[derive(Debug, Clone)]
+pub struct MarkerStats {
+    pub name: String,
+    pub live_bytes: i64,
+    pub total_allocs: u64,
+    pub total_frees: u64,
+    pub total_alloc_bytes: u64,
+    pub total_free_bytes: u64,
+}
+
 /// Stats per callsite (public API)
 #[derive(Debug, Clone, Default)]
 pub struct HeapStats {
@@ -52,6 +185,7 @@ pub struct HeapStats {
     pub total_frees: u64,
     pub total_alloc_bytes: u64,
     pub total_free_bytes: u64,
+    pub sizes: SizeDistribution,
 }
 
 /// CPU sample data (for compatibility)
@@ -70,6 +204,7 @@ pub struct CallsiteSnapshot {
     pub free_count: u64,
     pub free_bytes: u64,
     pub cpu_samples: u64,
+    pub sizes: SizeDistribution,
     pub stack: Vec<u64>,
 }
 
@@ -86,6 +221,7 @@ pub enum TraceEventType {
 pub struct TraceEvent {
     pub timestamp: u64,
     pub event_type: TraceEventType,
+    pub tid: u32,
     pub ptr: u64,
     pub size: i64,
     pub stack: Vec<u64>,
@@ -96,11 +232,34 @@ pub struct ShmHeapSampler {
     /// Memory-mapped region
     mmap: *mut u8,
     mmap_size: usize,
+    /// Mapped read-write, so requests (e.g. CPU frequency) can reach the target
+    writable: bool,
     /// Target PID
-    #[allow(dead_code)]
     target_pid: u32,
+    /// Target PID in its own PID namespace (what it records for itself)
+    ns_pid: u32,
     /// Previous CPU sample counts per callsite (for computing deltas)
     prev_cpu_counts: HashMap<u64, u64>,
+    /// Only report allocations that have been live at least this long
+    min_age: Option<Duration>,
+    /// Profiler overhead (ns) and target CPU time when attached
+    overhead_baseline: (u64, Option<Duration>),
+    /// Warn once when overhead exceeds this percentage of target CPU time
+    overhead_warn_percent: Option<f64>,
+    /// Sequence number of the last checkpoint label read
+    label_seq: u64,
+    /// Dropped event count seen by the last `poll_events`
+    dropped_events: u64,
+    /// Keep CPU samples from forked children (see `enable_follow_forks`)
+    follow_forks: bool,
+    /// Previous sample counts per (callsite, thread) slot key
+    prev_thread_counts: HashMap<u64, u64>,
+    /// Our PID for each process the target recorded by its namespace PID
+    host_pids: HashMap<u32, u32>,
+    /// Threads the target records (see `set_thread_filter`)
+    thread_filter: Option<ThreadFilter>,
+    /// When the thread filter was last written to the target
+    thread_filter_written: Instant,
 }
 
 // Safety: The mmap pointer is only accessed through &self or &mut self
@@ -109,12 +268,23 @@ unsafe impl Sync for ShmHeapSampler {}
 
 impl ShmHeapSampler {
     /// Create a new shared memory stats reader
-    pub fn new(pid: u32, _exe_path: &Path) -> Result<Self> {
-        let shm_path = std::ffi::CString::new(SHM_PATH).unwrap();
+    ///
+    /// `ns_pid` is the PID the target knows itself by and `root` its root
+    /// directory, which differ from `pid` and `/` when it runs in a container:
+    /// its shared memory is then under its own /dev/shm.
+    pub fn new(pid: u32, ns_pid: u32, root: &Path) -> Result<Self> {
+        let shm_path =
+            crate::process::path_in_root(root, Path::new(&format!("/dev/shm{}", SHM_PATH)));
+        let shm_path = std::ffi::CString::new(shm_path.as_os_str().as_bytes()).unwrap();
 
         unsafe {
-            // Open shared memory
-            let fd = libc::shm_open(shm_path.as_ptr(), libc::O_RDONLY, 0);
+            // Open shared memory; read-write if permitted, for requests to the target
+            let mut writable = true;
+            let mut fd = libc::open(shm_path.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC);
+            if fd < 0 {
+                writable = false;
+                fd = libc::open(shm_path.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC);
+            }
 
             if fd < 0 {
                 return Err(Error::Sampler(format!(
@@ -132,10 +302,15 @@ impl ShmHeapSampler {
             let buffer_size = stat.st_size as usize;
 
             // Map into memory
+            let prot = if writable {
+                libc::PROT_READ | libc::PROT_WRITE
+            } else {
+                libc::PROT_READ
+            };
             let ptr = libc::mmap(
                 std::ptr::null_mut(),
                 buffer_size,
-                libc::PROT_READ,
+                prot,
                 libc::MAP_SHARED,
                 fd,
                 0,
@@ -150,29 +325,55 @@ impl ShmHeapSampler {
             let mmap = ptr as *mut u8;
 
             // Validate header
-            let header = &*(mmap as *const StatsHeader);
-
-            if header.magic != MAGIC {
+            if let Err(e) = validate_header(mmap, buffer_size, pid, ns_pid) {
                 libc::munmap(ptr, buffer_size);
-                return Err(Error::Sampler(format!(
-                    "Invalid shared memory magic: expected 0x{:x}, got 0x{:x}. Make sure rsprof-trace is v3.",
-                    MAGIC, header.magic
-                )));
+                return Err(e);
             }
+            Ok(Self::from_mapping(mmap, buffer_size, writable, pid, ns_pid))
+        }
+    }
 
-            if header.pid != pid {
-                eprintln!(
-                    "[WARN] Shared memory PID ({}) doesn't match target PID ({})",
-                    header.pid, pid
-                );
-            }
+    /// Reader for a mapped and validated segment, which it unmaps when dropped
+    unsafe fn from_mapping(
+        mmap: *mut u8,
+        mmap_size: usize,
+        writable: bool,
+        pid: u32,
+        ns_pid: u32,
+    ) -> Self {
+        let header = unsafe { &*(mmap as *const StatsHeader) };
 
-            Ok(ShmHeapSampler {
-                mmap,
-                mmap_size: buffer_size,
-                target_pid: pid,
-                prev_cpu_counts: HashMap::new(),
-            })
+        log::info!(
+            "shm_sampler_opened pid={} version={} size={} writable={} callsites={} alloc_slots={}",
+            header.pid,
+            header.version,
+            mmap_size,
+            writable,
+            header.callsite_capacity,
+            header.alloc_table_capacity
+        );
+        ShmHeapSampler {
+            mmap,
+            mmap_size,
+            writable,
+            target_pid: pid,
+            ns_pid,
+            prev_cpu_counts: HashMap::new(),
+            min_age: None,
+            overhead_baseline: (
+                header.overhead_ns.load(Ordering::Relaxed),
+                crate::process::cpu_time(pid),
+            ),
+            overhead_warn_percent: None,
+            // Labels set before attaching belong to no checkpoint of ours
+            label_seq: header.label_seq.load(Ordering::Acquire),
+            // Likewise, only losses from now on are ours to report
+            dropped_events: header.dropped_events.load(Ordering::Relaxed),
+            follow_forks: false,
+            prev_thread_counts: HashMap::new(),
+            host_pids: HashMap::new(),
+            thread_filter: None,
+            thread_filter_written: Instant::now(),
         }
     }
 
@@ -181,6 +382,217 @@ impl ShmHeapSampler {
         unsafe { self.mmap.add(std::mem::size_of::<StatsHeader>()) as *const ShmCallsiteStats }
     }
 
+    /// Get pointer to the alloc table (after the callsite table)
+    unsafe fn get_alloc_table(&self) -> *const ShmAllocEntry {
+        unsafe {
+            let offset = std::mem::size_of::<StatsHeader>()
+                + CALLSITE_CAPACITY * std::mem::size_of::<ShmCallsiteStats>();
+            self.mmap.add(offset) as *const ShmAllocEntry
+        }
+    }
+
+    /// Get pointer to the marker stats array (after the callsite and alloc tables)
+    unsafe fn get_markers(&self) -> *const ShmMarkerStats {
+        unsafe {
+            let header = &*(self.mmap as *const StatsHeader);
+            let offset = std::mem::size_of::<StatsHeader>()
+                + CALLSITE_CAPACITY * std::mem::size_of::<ShmCallsiteStats>()
+                + header.alloc_table_capacity as usize * std::mem::size_of::<ShmAllocEntry>();
+            self.mmap.add(offset) as *const ShmMarkerStats
+        }
+    }
+
+    /// Get pointer to the transfer stats array (after the markers)
+    unsafe fn get_transfers(&self) -> *const ShmTransferStats {
+        unsafe {
+            let header = &*(self.mmap as *const StatsHeader);
+            (self.get_markers() as *const u8)
+                .add(header.marker_capacity as usize * std::mem::size_of::<ShmMarkerStats>())
+                as *const ShmTransferStats
+        }
+    }
+
+    /// Get pointer to the thread sample stats array (after the transfers)
+    unsafe fn get_thread_samples(&self) -> *const ShmThreadSampleStats {
+        unsafe {
+            let header = &*(self.mmap as *const StatsHeader);
+            (self.get_transfers() as *const u8)
+                .add(header.transfer_capacity as usize * std::mem::size_of::<ShmTransferStats>())
+                as *const ShmThreadSampleStats
+        }
+    }
+
+    /// Ask the target to record where its allocations are freed
+    ///
+    /// Costs a stack walk per free of a stacked allocation. Returns false if
+    /// the shared memory could only be opened read-only.
+    pub fn enable_free_sites(&self) -> bool {
+        if !self.writable {
+            return false;
+        }
+        let header = unsafe { &*(self.mmap as *const StatsHeader) };
+        header.free_sites.store(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Ask the target to keep CPU samples from processes it forks (`--follow-forks`)
+    ///
+    /// Forked children share the parent's shared memory, so their heap stats
+    /// are always included; CPU samples are dropped unless this is set.
+    /// Returns false if the shared memory could only be opened read-only.
+    pub fn enable_follow_forks(&mut self) -> bool {
+        if !self.writable {
+            return false;
+        }
+        let header = unsafe { &*(self.mmap as *const StatsHeader) };
+        header.follow_forks.store(1, Ordering::Relaxed);
+        self.follow_forks = true;
+        true
+    }
+
+    /// Ask the target to record only the threads `filter` allows (`--threads`,
+    /// `--exclude-thread`), for both CPU samples and allocations
+    ///
+    /// The target matches thread IDs, so the filter is re-matched against its
+    /// threads every `THREAD_FILTER_REFRESH` (from `poll_events`) to cover
+    /// threads started or renamed since. Returns false if the shared memory
+    /// could only be opened read-only.
+    pub fn set_thread_filter(&mut self, filter: ThreadFilter) -> bool {
+        if !self.writable {
+            return false;
+        }
+        self.thread_filter = Some(filter);
+        self.write_thread_filter();
+        true
+    }
+
+    /// Write the thread IDs the filter selects right now to the target
+    fn write_thread_filter(&mut self) {
+        let Some(filter) = &self.thread_filter else {
+            return;
+        };
+        self.thread_filter_written = Instant::now();
+        // The process itself is gone; nothing is recorded any more
+        let Ok(threads) = threads_in(Path::new("/proc"), self.target_pid) else {
+            return;
+        };
+
+        // The target knows its threads by their IDs in its own namespace
+        let same_namespace = self.ns_pid == self.target_pid;
+        let mut allowed = Vec::new();
+        let mut excluded = Vec::new();
+        for thread in threads {
+            let tid = if same_namespace {
+                Some(thread.tid)
+            } else {
+                ns_pids(thread.tid).and_then(|ids| ids.last().copied())
+            };
+            match tid {
+                Some(tid) if filter.allows(&thread) => allowed.push(tid),
+                Some(tid) => excluded.push(tid),
+                // Exited since it was listed
+                None => {}
+            }
+        }
+
+        let header = unsafe { &*(self.mmap as *const StatsHeader) };
+        let Some((mode, tids)) = thread_filter_list(allowed, excluded) else {
+            log::warn!(
+                "thread_filter_too_large pid={} capacity={}",
+                self.target_pid,
+                THREAD_FILTER_CAPACITY
+            );
+            header.thread_filter_mode.store(0, Ordering::Release);
+            return;
+        };
+        for (slot, &tid) in header.thread_filter.iter().zip(&tids) {
+            slot.store(tid, Ordering::Relaxed);
+        }
+        header
+            .thread_filter_len
+            .store(tids.len() as u32, Ordering::Release);
+        header.thread_filter_mode.store(mode, Ordering::Release);
+    }
+
+    /// Whether CPU samples from forked children are kept (see `enable_follow_forks`)
+    pub fn follows_forks(&self) -> bool {
+        self.follow_forks
+    }
+
+    /// Read alloc site -> free site pairs (see `enable_free_sites`)
+    pub fn read_transfers(&self) -> Vec<TransferStats> {
+        let mut result = Vec::new();
+
+        unsafe {
+            let header = &*(self.mmap as *const StatsHeader);
+            let transfers = self.get_transfers();
+
+            for i in 0..header.transfer_capacity as usize {
+                let entry = &*transfers.add(i);
+                if entry.key.load(Ordering::Acquire) == 0 {
+                    continue; // Empty slot
+                }
+                let free_hash = entry.free_hash.load(Ordering::Acquire);
+                let count = entry.count.load(Ordering::Relaxed);
+                if free_hash == 0 || count == 0 {
+                    continue; // Still being claimed
+                }
+                result.push(TransferStats {
+                    alloc_hash: entry.alloc_hash.load(Ordering::Relaxed),
+                    free_hash,
+                    count,
+                    bytes: entry.bytes.load(Ordering::Relaxed),
+                });
+            }
+        }
+
+        result
+    }
+
+    /// Read heap stats per marker
+    pub fn read_markers(&self) -> Vec<MarkerStats> {
+        let mut result = Vec::new();
+
+        unsafe {
+            let header = &*(self.mmap as *const StatsHeader);
+            let markers = self.get_markers();
+
+            for i in 0..header.marker_capacity as usize {
+                let entry = &*markers.add(i);
+                if entry.hash.load(Ordering::Acquire) == 0 {
+                    continue; // Empty slot
+                }
+                if entry.ready.load(Ordering::Acquire) == 0 {
+                    continue; // Name still being written
+                }
+
+                let alloc_count = entry.alloc_count.load(Ordering::Relaxed);
+                if alloc_count == 0 {
+                    continue;
+                }
+
+                let len = entry
+                    .name
+                    .iter()
+                    .position(|&b| b == 0)
+                    .unwrap_or(MARKER_NAME_LEN);
+                let alloc_bytes = entry.alloc_bytes.load(Ordering::Relaxed);
+                let free_bytes = entry.free_bytes.load(Ordering::Relaxed);
+
+                result.push(MarkerStats {
+                    name: String::from_utf8_lossy(&entry.name[..len]).into_owned(),
+                    live_bytes: alloc_bytes as i64 - free_bytes as i64,
+                    total_allocs: alloc_count,
+                    total_frees: entry.free_count.load(Ordering::Relaxed),
+                    total_alloc_bytes: alloc_bytes,
+                    total_free_bytes: free_bytes,
+                });
+            }
+        }
+
+        result
+    }
+
     /// Read current snapshot of all callsites
     pub fn read_snapshot(&self) -> Vec<CallsiteSnapshot> {
         let mut result = Vec::new();
@@ -210,6 +622,13 @@ impl ShmHeapSampler {
                     free_count: entry.free_count.load(Ordering::Relaxed),
                     free_bytes: entry.free_bytes.load(Ordering::Relaxed),
                     cpu_samples: entry.cpu_samples.load(Ordering::Relaxed),
+                    sizes: SizeDistribution {
+                        min: !entry.min_size_inv.load(Ordering::Relaxed),
+                        max: entry.max_size.load(Ordering::Relaxed),
+                        buckets: std::array::from_fn(|i| {
+                            entry.size_hist[i].load(Ordering::Relaxed)
+                        }),
+                    },
                     stack,
                 });
             }
@@ -218,6 +637,56 @@ impl ShmHeapSampler {
         result
     }
 
+    /// Only report allocations still live after `min_age` from `read_stats`
+    ///
+    /// Sites without such allocations are left out entirely, and live bytes
+    /// count only those allocations, so the heap view shows only persistent
+    /// (cached or leaked) memory.
+    pub fn set_min_age(&mut self, min_age: Duration) {
+        self.min_age = Some(min_age);
+    }
+
+    /// Read heap stats for allocations live for at least `min_age`
+    ///
+    /// Built from the live allocation table rather than the callsite
+    /// counters, so it is a snapshot: frees are never reported, and the
+    /// totals count only the allocations that qualify, not every allocation
+    /// the site made (`read_stats` keeps those cumulative).
+    ///
+    /// The table is indexed by address, so allocations a loop made back to
+    /// back at one site tend to fill adjacent slots. Runs of entries with the
+    /// same callsite are summed first and added to the map once per run.
+    pub fn read_persistent_stats(&self, min_age: Duration) -> HashMap<u64, HeapStats> {
+        let cutoff = monotonic_ns().saturating_sub(min_age.as_nanos() as u64);
+
+        let (result, runs) = unsafe {
+            let header = &*(self.mmap as *const StatsHeader);
+            let alloc_table = self.get_alloc_table();
+
+            let live = (0..header.alloc_table_capacity as usize).filter_map(|i| {
+                let entry = &*alloc_table.add(i);
+                let ptr = entry.ptr.load(Ordering::Acquire);
+                if ptr == 0 || ptr == TOMBSTONE {
+                    return None;
+                }
+
+                let callsite_hash = entry.callsite_hash.load(Ordering::Acquire);
+                let alloc_ns = entry.alloc_ns.load(Ordering::Relaxed);
+                if alloc_ns == 0 || alloc_ns > cutoff {
+                    return None; // Too young (or still being written)
+                }
+
+                let size = entry.size.load(Ordering::Relaxed);
+                let weight = entry.weight.load(Ordering::Relaxed).max(1) as u64;
+                Some((callsite_hash, size, weight))
+            });
+            sum_live_runs(live)
+        };
+        log::trace!("persistent_stats callsites={} runs={}", result.len(), runs);
+
+        result
+    }
+
     /// Read current heap stats (compatible with old API)
     pub fn read_stats(&self) -> HashMap<u64, HeapStats> {
         let snapshot = self.read_snapshot();
@@ -233,21 +702,39 @@ impl ShmHeapSampler {
                         total_frees: cs.free_count,
                         total_alloc_bytes: cs.alloc_bytes,
                         total_free_bytes: cs.free_bytes,
+                        sizes: cs.sizes,
                     },
                 );
             }
         }
 
+        // --min-age: only sites holding persistent memory, with only that as
+        // live; their counters stay cumulative like everywhere else
+        if let Some(min_age) = self.min_age {
+            let persistent = self.read_persistent_stats(min_age);
+            result.retain(|hash, stats| match persistent.get(hash) {
+                Some(old) => {
+                    stats.live_bytes = old.live_bytes;
+                    true
+                }
+                None => false,
+            });
+        }
+
         result
     }
 
     /// Read inline stacks from callsites
+    ///
+    /// Heap and free-site stacks hold only return addresses, so every frame
+    /// is moved back to its call (see `return_addresses_to_calls`).
     pub fn read_inline_stacks(&self) -> HashMap<u64, Vec<u64>> {
         let snapshot = self.read_snapshot();
         let mut result = HashMap::new();
 
-        for cs in snapshot {
+        for mut cs in snapshot {
             if !cs.stack.is_empty() {
+                return_addresses_to_calls(&mut cs.stack);
                 result.insert(cs.hash, cs.stack);
             }
         }
@@ -255,6 +742,60 @@ impl ShmHeapSampler {
         result
     }
 
+    /// Read CPU samples per (callsite, thread) (returns deltas since last read)
+    ///
+    /// Read separately from `read_cpu_stats`, so a sample taken in between
+    /// shows up in one read before the other.
+    pub fn read_thread_samples(&mut self) -> Vec<ThreadSamples> {
+        let mut result = Vec::new();
+
+        unsafe {
+            let header = &*(self.mmap as *const StatsHeader);
+            let thread_samples = self.get_thread_samples();
+
+            for i in 0..header.thread_sample_capacity as usize {
+                let entry = &*thread_samples.add(i);
+                let key = entry.key.load(Ordering::Acquire);
+                if key == 0 {
+                    continue; // Empty slot
+                }
+                let pid = entry.pid.load(Ordering::Acquire);
+                if pid == 0 {
+                    continue; // Still being claimed
+                }
+                let samples = entry.samples.load(Ordering::Relaxed);
+                let prev = self.prev_thread_counts.insert(key, samples).unwrap_or(0);
+                let count = samples.saturating_sub(prev);
+                if count == 0 {
+                    continue;
+                }
+                result.push(ThreadSamples {
+                    callsite: entry.callsite_hash.load(Ordering::Relaxed),
+                    tid: entry.tid.load(Ordering::Relaxed),
+                    pid: self.host_pid(pid),
+                    count,
+                });
+            }
+        }
+
+        result
+    }
+
+    /// Our PID for a process the target recorded by its own namespace PID
+    fn host_pid(&mut self, ns_pid: u32) -> u32 {
+        if ns_pid == self.ns_pid {
+            return self.target_pid;
+        }
+        if self.ns_pid == self.target_pid {
+            return ns_pid; // Same namespace as us
+        }
+        let target_pid = self.target_pid;
+        *self
+            .host_pids
+            .entry(ns_pid)
+            .or_insert_with(|| crate::process::host_pid(ns_pid, target_pid).unwrap_or(ns_pid))
+    }
+
     /// Read CPU samples - returns snapshots with cpu_samples > 0
     /// Note: In the new model, we don't have individual samples with timestamps,
     /// just aggregated counts per callsite.
@@ -264,15 +805,21 @@ impl ShmHeapSampler {
     }
 
     /// Read CPU stats per callsite (returns deltas since last read)
+    ///
+    /// The first frame is the interrupted instruction and is kept as is; the
+    /// return addresses after it are moved back to their calls.
     pub fn read_cpu_stats(&mut self) -> HashMap<u64, (u64, Vec<u64>)> {
         let snapshot = self.read_snapshot();
         let mut result = HashMap::new();
 
-        for cs in snapshot {
+        for mut cs in snapshot {
             if cs.cpu_samples > 0 {
                 let prev = self.prev_cpu_counts.get(&cs.hash).copied().unwrap_or(0);
                 let delta = cs.cpu_samples.saturating_sub(prev);
                 if delta > 0 {
+                    if let Some(callers) = cs.stack.get_mut(1..) {
+                        return_addresses_to_calls(callers);
+                    }
                     result.insert(cs.hash, (delta, cs.stack));
                 }
                 self.prev_cpu_counts.insert(cs.hash, cs.cpu_samples);
@@ -283,10 +830,143 @@ impl ShmHeapSampler {
     }
 
     /// Poll events - for compatibility, computes deltas from snapshots
-    pub fn poll_events(&mut self, _timeout: std::time::Duration) -> Vec<TraceEvent> {
+    ///
+    /// Also returns how many events the target dropped since the last poll
+    /// because its callsite or alloc table was full.
+    pub fn poll_events(&mut self, _timeout: std::time::Duration) -> (Vec<TraceEvent>, u64) {
+        let header = unsafe { &*(self.mmap as *const StatsHeader) };
+        let dropped_events = header.dropped_events.load(Ordering::Relaxed);
+        let dropped = dropped_events.saturating_sub(self.dropped_events);
+        self.dropped_events = dropped_events;
+        if dropped > 0 {
+            log::debug!("events_dropped count={} total={}", dropped, dropped_events);
+        }
+
+        if self.thread_filter.is_some()
+            && self.thread_filter_written.elapsed() >= THREAD_FILTER_REFRESH
+        {
+            self.write_thread_filter();
+        }
+
         // The new model doesn't have individual events
         // Return empty for compatibility
-        Vec::new()
+        (Vec::new(), dropped)
+    }
+
+    /// Warn (once, via `check_overhead`) when overhead exceeds `percent` of target CPU time
+    pub fn set_overhead_warning(&mut self, percent: f64) {
+        self.overhead_warn_percent = Some(percent);
+    }
+
+    /// Estimated time the target has spent inside rsprof-trace hooks (all threads)
+    pub fn overhead(&self) -> Duration {
+        let header = unsafe { &*(self.mmap as *const StatsHeader) };
+        Duration::from_nanos(header.overhead_ns.load(Ordering::Relaxed))
+    }
+
+    /// Profiler overhead since attaching, as a percentage of the target's CPU time
+    ///
+    /// `None` until the target has used enough CPU for the estimate to mean anything.
+    pub fn overhead_percent(&self) -> Option<f64> {
+        let (base_overhead, base_cpu) = self.overhead_baseline;
+        let cpu = crate::process::cpu_time(self.target_pid)?.checked_sub(base_cpu?)?;
+        let overhead = self.overhead().as_nanos() as f64 - base_overhead as f64;
+        overhead_share(overhead, cpu)
+    }
+
+    /// Return a warning the first time overhead exceeds the configured threshold
+    pub fn check_overhead(&mut self) -> Option<String> {
+        let percent = self.overhead_percent()?;
+        self.overhead_warning(percent)
+    }
+
+    /// `check_overhead` for an overhead of `percent`
+    fn overhead_warning(&mut self, percent: f64) -> Option<String> {
+        let threshold = self.overhead_warn_percent?;
+        if percent < threshold {
+            return None;
+        }
+        self.overhead_warn_percent = None; // One-time warning
+        Some(format!(
+            "Profiler overhead is ~{:.1}% of target CPU time (limit {}%); \
+             consider a lower cpu frequency or a stack_min/stack_max size range in profiler!",
+            percent, threshold
+        ))
+    }
+
+    /// Estimated time the target has spent taking CPU samples (part of `overhead`)
+    pub fn cpu_overhead(&self) -> Duration {
+        let header = unsafe { &*(self.mmap as *const StatsHeader) };
+        Duration::from_nanos(header.cpu_overhead_ns.load(Ordering::Relaxed))
+    }
+
+    /// Ask the target to sample CPU at `freq_hz` (applied at its next sample)
+    ///
+    /// Returns false if the shared memory could only be opened read-only.
+    pub fn request_cpu_freq(&self, freq_hz: u64) -> bool {
+        if !self.writable {
+            return false;
+        }
+        let header = unsafe { &*(self.mmap as *const StatsHeader) };
+        header
+            .cpu_freq_request_hz
+            .store(freq_hz.min(u32::MAX as u64) as u32, Ordering::Relaxed);
+        true
+    }
+
+    /// Take the checkpoint label set with `rsprof_trace::label` since the last call
+    pub fn take_label(&mut self) -> Option<String> {
+        let header = self.mmap as *const StatsHeader;
+        unsafe {
+            let seq = (*header).label_seq.load(Ordering::Acquire);
+            // Unchanged, or a write is in progress (retried on the next poll)
+            if seq == self.label_seq || seq & 1 == 1 {
+                return None;
+            }
+            let label = std::ptr::read_volatile(std::ptr::addr_of!((*header).label));
+            std::sync::atomic::fence(Ordering::Acquire);
+            if (*header).label_seq.load(Ordering::Acquire) != seq {
+                return None; // Torn read
+            }
+            self.label_seq = seq;
+
+            let len = label.iter().position(|&b| b == 0).unwrap_or(LABEL_LEN);
+            let text = String::from_utf8_lossy(&label[..len]).trim().to_string();
+            (!text.is_empty()).then_some(text)
+        }
+    }
+
+    /// CPU sampling frequency the target runs at (its `profiler!(cpu = ...)`)
+    ///
+    /// `None` until CPU profiling has started, or with CPU profiling disabled.
+    pub fn cpu_freq_hz(&self) -> Option<u64> {
+        let header = unsafe { &*(self.mmap as *const StatsHeader) };
+        match header.cpu_freq_hz.load(Ordering::Relaxed) {
+            0 => None,
+            freq => Some(freq as u64),
+        }
+    }
+
+    /// Target PID as seen from here (the one this sampler was opened for)
+    pub fn target_pid(&self) -> u32 {
+        self.target_pid
+    }
+
+    /// Executable path the target reported, as seen from its own filesystem
+    pub fn exe_path(&self) -> Option<PathBuf> {
+        let header = unsafe { &*(self.mmap as *const StatsHeader) };
+        let len = header
+            .exe_path
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(EXE_PATH_LEN);
+        (len > 0).then(|| PathBuf::from(OsStr::from_bytes(&header.exe_path[..len])))
+    }
+
+    /// ASLR offset of the target's executable, computed by the target itself
+    pub fn aslr_offset(&self) -> u64 {
+        let header = unsafe { &*(self.mmap as *const StatsHeader) };
+        header.aslr_offset
     }
 
     /// Get the target PID from shared memory
@@ -298,12 +978,524 @@ impl ShmHeapSampler {
     }
 }
 
+/// Current CLOCK_MONOTONIC time in nanoseconds (same clock as rsprof-trace)
+fn monotonic_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Move return addresses back into the call instruction they follow
+///
+/// A return address is the instruction after the call, which the line
+/// table often maps to the next source line. Without this, an allocation
+/// and a CPU sample on the same line land on different locations.
+fn return_addresses_to_calls(stack: &mut [u64]) {
+    for addr in stack {
+        *addr = addr.saturating_sub(1);
+    }
+}
+
+/// Heap stats per callsite of live `(callsite, size, weight)` allocations,
+/// and the number of runs they were added in
+///
+/// Runs of allocations from the same callsite are summed first and added to
+/// the map once per run.
+fn sum_live_runs(live: impl Iterator<Item = (u64, u64, u64)>) -> (HashMap<u64, HeapStats>, usize) {
+    let mut result: HashMap<u64, HeapStats> = HashMap::new();
+    let mut runs = 0;
+    let mut run: Option<(u64, HeapStats)> = None;
+    for (callsite_hash, size, weight) in live {
+        let (_, stats) = match &mut run {
+            Some(current) if current.0 == callsite_hash => current,
+            _ => {
+                if let Some((hash, stats)) = run.take() {
+                    add_heap_stats(&mut result, hash, &stats);
+                    runs += 1;
+                }
+                run.insert((callsite_hash, HeapStats::default()))
+            }
+        };
+        stats.live_bytes += (size * weight) as i64;
+        stats.total_allocs += weight;
+        stats.total_alloc_bytes += size * weight;
+        stats.sizes.add_many(size, weight);
+    }
+    if let Some((hash, stats)) = run {
+        add_heap_stats(&mut result, hash, &stats);
+        runs += 1;
+    }
+    (result, runs)
+}
+
+/// Add a run of allocations from one callsite to its stats
+fn add_heap_stats(result: &mut HashMap<u64, HeapStats>, hash: u64, run: &HeapStats) {
+    let stats = result.entry(hash).or_default();
+    stats.live_bytes += run.live_bytes;
+    stats.total_allocs += run.total_allocs;
+    stats.total_alloc_bytes += run.total_alloc_bytes;
+    stats.sizes.merge(&run.sizes);
+}
+
+/// Sum thread samples per callsite and process, for `--follow-forks`
+pub fn samples_by_process(samples: &[ThreadSamples]) -> HashMap<u64, HashMap<u32, u64>> {
+    let mut result: HashMap<u64, HashMap<u32, u64>> = HashMap::new();
+    for samples in samples {
+        *result
+            .entry(samples.callsite)
+            .or_default()
+            .entry(samples.pid)
+            .or_insert(0) += samples.count;
+    }
+    result
+}
+
+/// The thread filter mode and list that select `allowed` threads and not
+/// `excluded` ones, listing whichever set is smaller
+///
+/// `None` if neither fits the target's list.
+fn thread_filter_list(allowed: Vec<u32>, excluded: Vec<u32>) -> Option<(u32, Vec<u32>)> {
+    let (mode, tids) = if excluded.len() < allowed.len() {
+        (THREAD_FILTER_EXCEPT, excluded)
+    } else {
+        (THREAD_FILTER_ONLY, allowed)
+    };
+    (tids.len() <= THREAD_FILTER_CAPACITY).then_some((mode, tids))
+}
+
+/// `overhead_ns` of hook time as a percentage of `cpu` time
+///
+/// `None` under a second of CPU time, too little for the estimate to mean anything.
+fn overhead_share(overhead_ns: f64, cpu: Duration) -> Option<f64> {
+    if cpu < Duration::from_secs(1) {
+        return None;
+    }
+    Some((overhead_ns.max(0.0) / cpu.as_nanos() as f64 * 100.0).min(100.0))
+}
+
+/// Describe a target whose /proc entry is hidden from us, from what its
+/// rsprof-trace shared memory under `root` reports (executable and ASLR
+/// offset)
+///
+/// Returns the process with the warnings to show about what it loses without
+/// /proc, or `err` (why /proc failed) when the target doesn't use rsprof-trace.
+pub fn attach_without_procfs(
+    pid: u32,
+    root: &Path,
+    err: Error,
+) -> Result<(ProcessInfo, Vec<String>)> {
+    let shm = match ShmHeapSampler::new(pid, pid, root) {
+        Ok(shm) => shm,
+        Err(e @ Error::IncompatibleShm(_)) => return Err(e),
+        Err(_) => {
+            return Err(Error::PermissionDenied(format!(
+                "{}; without it only targets using rsprof-trace can be profiled",
+                err.to_string().trim_start_matches("Permission denied: ")
+            )));
+        }
+    };
+    let Some(exe_path) = shm.exe_path() else {
+        return Err(err);
+    };
+    let warnings = vec![
+        format!(
+            "/proc/{} is not visible; using the executable and load address rsprof-trace reports ({})",
+            pid,
+            exe_path.display()
+        ),
+        "perf_event sampling, thread filters, --auto-freq, RSS and overhead estimates need /proc and are disabled"
+            .to_string(),
+    ];
+    Ok((
+        ProcessInfo::without_procfs(pid, exe_path, shm.aslr_offset()),
+        warnings,
+    ))
+}
+
+/// Check that a mapped segment is a live rsprof-trace buffer of `pid` with
+/// the layout this rsprof reads
+///
+/// The segment outlives its target (it is only replaced when the next target
+/// starts), so one left by an exited or different process is rejected rather
+/// than read as the target's.
+unsafe fn validate_header(
+    mmap: *const u8,
+    buffer_size: usize,
+    pid: u32,
+    ns_pid: u32,
+) -> Result<()> {
+    if buffer_size < std::mem::size_of::<StatsHeader>() {
+        return Err(Error::IncompatibleShm(format!(
+            "'{}' is too small ({} bytes) to hold its header",
+            SHM_PATH, buffer_size
+        )));
+    }
+    let header = unsafe { &*(mmap as *const StatsHeader) };
+
+    if header.magic != MAGIC {
+        return Err(Error::IncompatibleShm(format!(
+            "invalid magic 0x{:x}, expected 0x{:x} (make sure rsprof-trace is v{})",
+            header.magic, MAGIC, VERSION
+        )));
+    }
+
+    if header.version != VERSION {
+        let newer = if header.version > VERSION {
+            "the target's rsprof-trace is newer; upgrade rsprof"
+        } else {
+            "this rsprof is newer; rebuild the target against a matching rsprof-trace"
+        };
+        return Err(Error::IncompatibleShm(format!(
+            "layout is v{}, this rsprof reads v{} ({})",
+            header.version, VERSION, newer
+        )));
+    }
+
+    if header.pid != ns_pid {
+        // Another namespace's PIDs can't be looked up from here
+        let owner = if ns_pid != pid {
+            "in the target's namespace: another process, or one that exited"
+        } else if crate::process::process_exists(header.pid) {
+            "still running: profile that PID instead"
+        } else {
+            "exited: remove the stale /dev/shm/rsprof-trace"
+        };
+        return Err(Error::IncompatibleShm(format!(
+            "'{}' belongs to PID {}, not the target PID {} ({})",
+            SHM_PATH, header.pid, ns_pid, owner
+        )));
+    }
+
+    let layout_size = std::mem::size_of::<StatsHeader>()
+        + CALLSITE_CAPACITY * std::mem::size_of::<ShmCallsiteStats>()
+        + header.alloc_table_capacity as usize * std::mem::size_of::<ShmAllocEntry>()
+        + header.marker_capacity as usize * std::mem::size_of::<ShmMarkerStats>()
+        + header.transfer_capacity as usize * std::mem::size_of::<ShmTransferStats>()
+        + header.thread_sample_capacity as usize * std::mem::size_of::<ShmThreadSampleStats>();
+    if header.callsite_capacity as usize != CALLSITE_CAPACITY || buffer_size < layout_size {
+        return Err(Error::IncompatibleShm(format!(
+            "'{}' is {} bytes with {} callsites; v{} needs {} bytes with {} callsites",
+            SHM_PATH,
+            buffer_size,
+            header.callsite_capacity,
+            VERSION,
+            layout_size,
+            CALLSITE_CAPACITY
+        )));
+    }
+
+    Ok(())
+}
+
 impl Drop for ShmHeapSampler {
     fn drop(&mut self) {
         unsafe {
             if !self.mmap.is_null() {
+                // Free-site stacks cost the target a stack walk per free; stop
+                // capturing them once nobody reads them
+                // Likewise the thread filter, which was only meant for us
+                if self.writable {
+                    let header = &*(self.mmap as *const StatsHeader);
+                    header.free_sites.store(0, Ordering::Relaxed);
+                    header.thread_filter_mode.store(0, Ordering::Release);
+                }
                 libc::munmap(self.mmap as *mut libc::c_void, self.mmap_size);
+                log::info!("shm_sampler_closed pid={}", self.target_pid);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reader over an anonymous mapping laid out like a target's segment,
+    /// with `alloc_slots` allocation slots and no markers, transfers or
+    /// thread samples
+    fn test_sampler(alloc_slots: usize) -> ShmHeapSampler {
+        let size = std::mem::size_of::<StatsHeader>()
+            + CALLSITE_CAPACITY * std::mem::size_of::<ShmCallsiteStats>()
+            + alloc_slots * std::mem::size_of::<ShmAllocEntry>();
+        let pid = std::process::id();
+        unsafe {
+            let ptr = libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            assert_ne!(ptr, libc::MAP_FAILED);
+            let header = &mut *(ptr as *mut StatsHeader);
+            header.magic = MAGIC;
+            header.version = VERSION;
+            header.callsite_capacity = CALLSITE_CAPACITY as u32;
+            header.alloc_table_capacity = alloc_slots as u32;
+            header.pid = pid;
+            validate_header(ptr as *const u8, size, pid, pid).unwrap();
+            ShmHeapSampler::from_mapping(ptr as *mut u8, size, false, pid, pid)
+        }
+    }
+
+    /// Write a segment like the one a target at `exe_path` would create, as
+    /// seen under `root`
+    fn write_segment(root: &Path, exe_path: &str, aslr_offset: u64) {
+        let size = std::mem::size_of::<StatsHeader>()
+            + CALLSITE_CAPACITY * std::mem::size_of::<ShmCallsiteStats>();
+        let mut words = vec![0u64; size.div_ceil(8)];
+        let header = unsafe { &mut *(words.as_mut_ptr() as *mut StatsHeader) };
+        header.magic = MAGIC;
+        header.version = VERSION;
+        header.callsite_capacity = CALLSITE_CAPACITY as u32;
+        header.pid = std::process::id();
+        header.aslr_offset = aslr_offset;
+        header.exe_path[..exe_path.len()].copy_from_slice(exe_path.as_bytes());
+        let bytes = unsafe { std::slice::from_raw_parts(words.as_ptr() as *const u8, size) };
+        std::fs::create_dir_all(root.join("dev/shm")).unwrap();
+        std::fs::write(root.join("dev/shm").join(&SHM_PATH[1..]), bytes).unwrap();
+    }
+
+    /// Set a callsite's cumulative (alloc count, alloc bytes, free count, free bytes)
+    fn set_callsite(sampler: &ShmHeapSampler, slot: usize, hash: u64, counts: [u64; 4]) {
+        let callsite = unsafe { &*sampler.get_callsites().add(slot) };
+        callsite.hash.store(hash, Ordering::Relaxed);
+        callsite.alloc_count.store(counts[0], Ordering::Relaxed);
+        callsite.alloc_bytes.store(counts[1], Ordering::Relaxed);
+        callsite.free_count.store(counts[2], Ordering::Relaxed);
+        callsite.free_bytes.store(counts[3], Ordering::Relaxed);
+    }
+
+    /// Set a callsite's stack (innermost frame first)
+    fn set_stack(sampler: &ShmHeapSampler, slot: usize, stack: &[u64]) {
+        let callsite = unsafe { &*sampler.get_callsites().add(slot) };
+        for (frame, &addr) in callsite.stack.iter().zip(stack) {
+            frame.store(addr, Ordering::Relaxed);
+        }
+        callsite
+            .stack_depth
+            .store(stack.len() as u32, Ordering::Relaxed);
+    }
+
+    /// Track a live allocation made `age` ago
+    fn set_alloc(sampler: &ShmHeapSampler, slot: usize, hash: u64, size: u64, age: Duration) {
+        let entry = unsafe { &*sampler.get_alloc_table().add(slot) };
+        entry
+            .ptr
+            .store(0x1000 * (slot as u64 + 1), Ordering::Relaxed);
+        entry.size.store(size, Ordering::Relaxed);
+        entry.callsite_hash.store(hash, Ordering::Relaxed);
+        entry
+            .alloc_ns
+            .store(monotonic_ns() - age.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn min_age_reports_only_sites_holding_old_allocations() {
+        let mut sampler = test_sampler(8);
+        let (churn, cache) = (0x10, 0x20);
+        // Churn: 10 allocations, all freed but one just made
+        set_callsite(&sampler, 0, churn, [10, 1000, 9, 900]);
+        set_alloc(&sampler, 0, churn, 100, Duration::ZERO);
+        // Cache: 5 allocations, 2 freed, 2 kept for a minute and 1 just made
+        set_callsite(&sampler, 1, cache, [5, 500, 2, 200]);
+        set_alloc(&sampler, 1, cache, 100, Duration::from_secs(60));
+        set_alloc(&sampler, 2, cache, 100, Duration::from_secs(60));
+        set_alloc(&sampler, 3, cache, 100, Duration::ZERO);
+
+        let all = sampler.read_stats();
+        assert_eq!(all[&churn].live_bytes, 100);
+        assert_eq!(all[&cache].live_bytes, 300);
+
+        sampler.set_min_age(Duration::from_secs(10));
+        let persistent = sampler.read_stats();
+        assert!(!persistent.contains_key(&churn));
+        let cache = &persistent[&cache];
+        assert_eq!(cache.live_bytes, 200);
+        // Counters stay cumulative, as in every other recording
+        assert_eq!(
+            (
+                cache.total_allocs,
+                cache.total_alloc_bytes,
+                cache.total_frees,
+                cache.total_free_bytes
+            ),
+            (5, 500, 2, 200)
+        );
+    }
+
+    #[test]
+    fn high_overhead_warns_once() {
+        let second = Duration::from_secs(1);
+        assert_eq!(overhead_share(1e9, second / 2), None);
+        assert_eq!(overhead_share(1e9, 4 * second), Some(25.0));
+        assert_eq!(overhead_share(-1.0, 4 * second), Some(0.0));
+        assert_eq!(overhead_share(1e10, second), Some(100.0));
+
+        let mut sampler = test_sampler(64);
+        assert_eq!(sampler.overhead_warning(50.0), None);
+        sampler.set_overhead_warning(10.0);
+        assert_eq!(sampler.overhead_warning(5.0), None);
+        let warning = sampler.overhead_warning(25.0).unwrap();
+        assert!(warning.starts_with("Profiler overhead is ~25.0% of target CPU time (limit 10%)"));
+        assert_eq!(sampler.overhead_warning(30.0), None);
+    }
+
+    #[test]
+    fn same_callsite_runs_are_added_once() {
+        // 100 back-to-back allocations at callsite 1, then one at 2 and 1 again
+        let live = std::iter::repeat_n((1, 64, 1), 100)
+            .chain([(2, 1000, 1), (1, 32, 4)])
+            .collect::<Vec<_>>();
+        let (stats, runs) = sum_live_runs(live.into_iter());
+
+        assert_eq!(runs, 3);
+        let site = &stats[&1];
+        assert_eq!(site.total_allocs, 104);
+        assert_eq!(site.total_alloc_bytes, 100 * 64 + 4 * 32);
+        assert_eq!(site.live_bytes, 100 * 64 + 4 * 32);
+        assert_eq!(site.sizes.count(), 104);
+        assert_eq!(stats[&2].total_alloc_bytes, 1000);
+
+        let (stats, runs) = sum_live_runs(std::iter::repeat_n((7, 16, 1), 50));
+        assert_eq!(runs, 1);
+        assert_eq!(stats[&7].total_allocs, 50);
+        assert_eq!(stats[&7].total_alloc_bytes, 800);
+    }
+
+    #[test]
+    fn hidden_procfs_attaches_through_shared_memory_with_warnings() {
+        let pid = std::process::id();
+        let root = std::env::temp_dir().join(format!("rsprof-hidden-proc-{}", pid));
+        let hidden = || Error::PermissionDenied(format!("/proc/{} is not visible", pid));
+
+        // No segment: only rsprof-trace targets can be profiled
+        let Err(err) = attach_without_procfs(pid, &root, hidden()) else {
+            panic!("attached without a segment");
+        };
+        assert!(err.to_string().contains("only targets using rsprof-trace"));
+
+        write_segment(&root, "/srv/app/server", 0x5555_0000_0000);
+        let (proc_info, warnings) = attach_without_procfs(pid, &root, hidden()).unwrap();
+        assert!(!proc_info.has_procfs());
+        assert_eq!(proc_info.name(), "server");
+        assert_eq!(proc_info.exe_path(), Path::new("/srv/app/server"));
+        assert_eq!(proc_info.aslr_offset().unwrap(), 0x5555_0000_0000);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with(&format!("/proc/{} is not visible", pid)));
+        assert!(warnings[0].contains("/srv/app/server"));
+        assert!(warnings[1].contains("need /proc and are disabled"));
+
+        // Recording reads the same segment
+        let sampler = ShmHeapSampler::new(pid, pid, &root).unwrap();
+        set_callsite(&sampler, 0, 0x10, [4, 400, 1, 100]);
+        assert_eq!(sampler.read_stats()[&0x10].live_bytes, 300);
+
+        drop(sampler);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn polls_report_events_dropped_since_the_last_poll() {
+        let mut sampler = test_sampler(8);
+        let header = unsafe { &*(sampler.mmap as *const StatsHeader) };
+        let poll = |sampler: &mut ShmHeapSampler| sampler.poll_events(Duration::ZERO).1;
+
+        assert_eq!(poll(&mut sampler), 0);
+        header.dropped_events.store(5, Ordering::Relaxed);
+        assert_eq!(poll(&mut sampler), 5);
+        assert_eq!(poll(&mut sampler), 0);
+        header.dropped_events.store(12, Ordering::Relaxed);
+        assert_eq!(poll(&mut sampler), 7);
+    }
+
+    #[test]
+    fn samples_stay_with_the_process_that_took_them() {
+        let samples = |callsite, tid, pid, count| ThreadSamples {
+            callsite,
+            tid,
+            pid,
+            count,
+        };
+        // The target (100) and a forked child (200) run the same code
+        let by_process = samples_by_process(&[
+            samples(1, 100, 100, 3),
+            samples(1, 101, 100, 2),
+            samples(1, 200, 200, 7),
+            samples(2, 200, 200, 4),
+        ]);
+
+        assert_eq!(by_process[&1], HashMap::from([(100, 5), (200, 7)]));
+        assert_eq!(by_process[&2], HashMap::from([(200, 4)]));
+    }
+
+    #[test]
+    fn thread_filters_list_the_smaller_set() {
+        // A few matching threads out of many: only those are listed
+        let many: Vec<u32> = (100..400).collect();
+        assert_eq!(
+            thread_filter_list(vec![1, 2], many.clone()),
+            Some((THREAD_FILTER_ONLY, vec![1, 2]))
+        );
+        // A few excluded ones: everything but those
+        assert_eq!(
+            thread_filter_list(many.clone(), vec![7]),
+            Some((THREAD_FILTER_EXCEPT, vec![7]))
+        );
+        // No thread matches: an empty list records none
+        assert_eq!(
+            thread_filter_list(Vec::new(), vec![7]),
+            Some((THREAD_FILTER_ONLY, Vec::new()))
+        );
+        assert_eq!(thread_filter_list(many.clone(), many), None);
+    }
+
+    #[test]
+    fn cpu_and_heap_events_on_one_line_share_a_location() {
+        use crate::storage::Storage;
+        use crate::symbols::{Attribution, SymbolResolver};
+
+        // parse() calls the allocator from line 10; the return address
+        // after that call is the first instruction of line 11
+        let resolver = SymbolResolver::from_symbols(
+            &[(0x1000, "app::parse"), (0x2000, "app::main")],
+            &[
+                (0x1000, 0x1010, "/home/me/app/src/parse.rs", 10),
+                (0x1010, 0x1020, "/home/me/app/src/parse.rs", 11),
+                (0x2000, 0x2100, "/home/me/app/src/main.rs", 20),
+            ],
+        );
+        let mut sampler = test_sampler(8);
+        let (heap, cpu) = (0x10, 0x20);
+        set_callsite(&sampler, 0, heap, [1, 64, 0, 0]);
+        set_stack(&sampler, 0, &[0x1010, 0x2010]);
+        // A CPU sample interrupted in line 10 itself
+        set_callsite(&sampler, 1, cpu, [0, 0, 0, 0]);
+        unsafe { &*sampler.get_callsites().add(1) }
+            .cpu_samples
+            .store(5, Ordering::Relaxed);
+        set_stack(&sampler, 1, &[0x1008, 0x2010]);
+
+        let heap_stack = &sampler.read_inline_stacks()[&heap];
+        let (samples, cpu_stack) = &sampler.read_cpu_stats()[&cpu];
+        let heap_location = Attribution::User.attribute(heap_stack, &resolver);
+        let cpu_location = Attribution::User.attribute(cpu_stack, &resolver);
+        assert_eq!(heap_location.as_file_line(), "src/parse.rs:10");
+
+        let path =
+            std::env::temp_dir().join(format!("rsprof-shared-loc-{}.db", std::process::id()));
+        let mut storage = Storage::create(&path).unwrap();
+        let cpu_id = storage.record_cpu_sample_count(cpu_stack[0], &cpu_location, *samples);
+        let heap_id = storage.record_heap_sample(&heap_location, 64, 0, 64, 1, 0);
+        assert_eq!(cpu_id, heap_id);
+
+        drop(storage);
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", path.display(), suffix)).ok();
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Per-site allocation size distribution.
+//!
+//! rsprof-trace counts each callsite's allocations in power-of-two size
+//! buckets and keeps the exact smallest and largest size. That is enough to
+//! tell a site allocating uniform buffers (a pool or `with_capacity`
+//! candidate) from one whose sizes vary wildly.
+
+/// Number of size buckets (must match rsprof-trace)
+pub const SIZE_BUCKETS: usize = 32;
+
+/// Allocation sizes seen at one site: bucket `i` counts sizes in
+/// `[2^i, 2^(i+1))` (bucket 0 also counts size 0, the last bucket everything
+/// larger)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SizeDistribution {
+    pub min: u64,
+    pub max: u64,
+    pub buckets: [u64; SIZE_BUCKETS],
+}
+
+impl SizeDistribution {
+    /// Bucket an allocation of `size` bytes is counted in
+    pub fn bucket_of(size: u64) -> usize {
+        let log2 = (u64::BITS - size.leading_zeros()).saturating_sub(1) as usize;
+        log2.min(SIZE_BUCKETS - 1)
+    }
+
+    /// Count one allocation of `size` bytes
+    pub fn add(&mut self, size: u64) {
+        self.add_many(size, 1);
+    }
+
+    /// Count `count` allocations of `size` bytes (a sampled allocation's weight)
+    pub fn add_many(&mut self, size: u64, count: u64) {
+        if self.count() == 0 || size < self.min {
+            self.min = size;
+        }
+        self.max = self.max.max(size);
+        self.buckets[Self::bucket_of(size)] += count;
+    }
+
+    /// Fold in another site's sizes (several stacks resolving to one location)
+    pub fn merge(&mut self, other: &SizeDistribution) {
+        if other.count() == 0 {
+            return;
+        }
+        self.min = if self.count() == 0 {
+            other.min
+        } else {
+            self.min.min(other.min)
+        };
+        self.max = self.max.max(other.max);
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += count;
+        }
+    }
+
+    /// Number of allocations counted
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Sizes a bucket can hold here, narrowed by the known min and max
+    pub fn bucket_range(&self, bucket: usize) -> (u64, u64) {
+        let low = if bucket == 0 { 0 } else { 1u64 << bucket };
+        let high = if bucket == SIZE_BUCKETS - 1 {
+            u64::MAX
+        } else {
+            (1u64 << (bucket + 1)) - 1
+        };
+        (low.max(self.min), high.min(self.max))
+    }
+
+    /// Estimated median size, interpolated within its bucket
+    ///
+    /// Exact when every allocation in that bucket has the same size.
+    pub fn median(&self) -> Option<u64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let target = count.div_ceil(2);
+        let mut seen = 0;
+        for (bucket, &n) in self.buckets.iter().enumerate() {
+            if n == 0 {
+                continue;
+            }
+            if seen + n >= target {
+                let (low, high) = self.bucket_range(bucket);
+                let fraction = (target - seen) as f64 / n as f64;
+                return Some(low + ((high - low) as f64 * fraction).round() as u64);
+            }
+            seen += n;
+        }
+        Some(self.max)
+    }
+
+    /// Size range of the most common bucket, as (low, high)
+    pub fn mode(&self) -> Option<(u64, u64)> {
+        let (bucket, _) = self
+            .buckets
+            .iter()
+            .enumerate()
+            .filter(|&(_, &n)| n > 0)
+            .max_by_key(|&(bucket, &n)| (n, std::cmp::Reverse(bucket)))?;
+        Some(self.bucket_range(bucket))
+    }
+
+    /// Non-empty buckets as "bucket:count" pairs, for storage
+    pub fn histogram_string(&self) -> String {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|&(_, &n)| n > 0)
+            .map(|(bucket, n)| format!("{}:{}", bucket, n))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Rebuild a distribution from stored min/max and `histogram_string`
+    pub fn from_stored(min: u64, max: u64, histogram: &str) -> Self {
+        let mut sizes = SizeDistribution {
+            min,
+            max,
+            ..Default::default()
+        };
+        for pair in histogram.split(',') {
+            if let Some((bucket, n)) = pair.split_once(':')
+                && let (Ok(bucket), Ok(n)) = (bucket.parse::<usize>(), n.parse::<u64>())
+                && bucket < SIZE_BUCKETS
+            {
+                sizes.buckets[bucket] = n;
+            }
+        }
+        sizes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn distribution(sizes: &[u64]) -> SizeDistribution {
+        let mut dist = SizeDistribution::default();
+        for &size in sizes {
+            dist.add(size);
+        }
+        dist
+    }
+
+    #[test]
+    fn stats_match_a_known_set_of_sizes() {
+        let dist = distribution(&[24, 100, 4096, 4096, 4096]);
+        assert_eq!(dist.count(), 5);
+        assert_eq!(dist.min, 24);
+        assert_eq!(dist.max, 4096);
+        assert_eq!(dist.median(), Some(4096));
+        assert_eq!(dist.mode(), Some((4096, 4096)));
+
+        // Ten sizes sharing one bucket: the median is interpolated between min and max
+        let varied = distribution(&(16..26).collect::<Vec<_>>());
+        assert_eq!(varied.mode(), Some((16, 25)));
+        assert_eq!(varied.median(), Some(21));
+
+        assert_eq!(SizeDistribution::default().median(), None);
+        assert_eq!(SizeDistribution::default().mode(), None);
+    }
+
+    #[test]
+    fn stored_and_merged_distributions_keep_their_stats() {
+        let dist = distribution(&[24, 100, 4096, 4096, 4096]);
+        let stored = SizeDistribution::from_stored(dist.min, dist.max, &dist.histogram_string());
+        assert_eq!(stored.count(), 5);
+        assert_eq!(stored.median(), dist.median());
+        assert_eq!(stored.mode(), dist.mode());
+
+        let mut merged = distribution(&[8]);
+        merged.merge(&dist);
+        assert_eq!((merged.min, merged.max, merged.count()), (8, 4096, 6));
+    }
+}
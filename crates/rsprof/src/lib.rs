@@ -3,7 +3,9 @@ pub mod commands;
 pub mod cpu;
 pub mod error;
 pub mod heap;
+pub mod logging;
 pub mod process;
+pub mod serve;
 pub mod storage;
 pub mod symbols;
 pub mod tui;
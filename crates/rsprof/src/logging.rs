@@ -0,0 +1,173 @@
+//! Opt-in log of rsprof's own lifecycle, for debugging the profiler
+//!
+//! Off unless `RUST_LOG` is set. Attaching, sampler setup and teardown,
+//! symbol loading and lost samples log at `info`/`warn`; every checkpoint
+//! flush logs its row counts at `debug`. Lines go to stderr (redirect it to
+//! keep them out of the TUI) as `<time> <LEVEL> <target>: <event> key=value...`.
+
+use log::{LevelFilter, Log, Metadata, Record};
+use std::io::Write;
+
+/// `RUST_LOG` directives: a bare level, or `target=level` pairs
+/// (comma-separated, the longest matching target prefix wins)
+struct Logger {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Logger {
+    fn parse(spec: &str) -> Self {
+        let mut logger = Logger {
+            default: LevelFilter::Off,
+            targets: Vec::new(),
+        };
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    if let Ok(level) = level.trim().parse() {
+                        logger.targets.push((target.trim().to_string(), level));
+                    }
+                }
+                None => match directive.parse() {
+                    Ok(level) => logger.default = level,
+                    // A bare target enables everything for it
+                    Err(_) => logger
+                        .targets
+                        .push((directive.to_string(), LevelFilter::Trace)),
+                },
+            }
+        }
+        // Longest prefix first, so the most specific directive matches
+        logger
+            .targets
+            .sort_by_key(|(target, _)| std::cmp::Reverse(target.len()));
+        logger
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .find(|(prefix, _)| {
+                target == prefix
+                    || target
+                        .strip_prefix(prefix.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// The line logged for `record`, if its level is enabled
+    fn line(&self, record: &Record) -> Option<String> {
+        self.enabled(record.metadata()).then(|| {
+            format!(
+                "{} {:<5} {}: {}",
+                chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+                record.level(),
+                record.target(),
+                record.args()
+            )
+        })
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .chain([self.default])
+            .max()
+            .unwrap_or(LevelFilter::Off)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some(line) = self.line(record) {
+            let _ = writeln!(std::io::stderr().lock(), "{}", line);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install the logger if `RUST_LOG` is set (otherwise logging stays off)
+pub fn init() {
+    let Ok(spec) = std::env::var("RUST_LOG") else {
+        return;
+    };
+    let logger = Logger::parse(&spec);
+    let max_level = logger.max_level();
+    if log::set_logger(Box::leak(Box::new(logger))).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use crate::symbols::Location;
+    use std::sync::Mutex;
+
+    /// The logger, keeping its lines instead of writing them to stderr
+    struct Capture {
+        logger: Logger,
+        lines: Mutex<Vec<String>>,
+    }
+
+    impl Log for Capture {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            self.logger.enabled(metadata)
+        }
+
+        fn log(&self, record: &Record) {
+            if let Some(line) = self.logger.line(record) {
+                self.lines.lock().unwrap().push(line);
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn checkpoint_flushes_are_logged_with_their_row_counts() {
+        let capture: &'static Capture = Box::leak(Box::new(Capture {
+            logger: Logger::parse("warn,rsprof::storage=debug"),
+            lines: Mutex::new(Vec::new()),
+        }));
+        log::set_logger(capture).unwrap();
+        log::set_max_level(capture.logger.max_level());
+
+        let path = std::env::temp_dir().join(format!("rsprof-log-{}.db", std::process::id()));
+        let mut storage = Storage::create(&path).unwrap();
+        let location = |function: &str| Location {
+            file: "src/main.rs".to_string(),
+            line: 1,
+            column: 0,
+            function: function.to_string(),
+        };
+        storage.record_cpu_sample_count(0x1000, &location("parse"), 3);
+        storage.record_cpu_sample_count(0x2000, &location("render"), 1);
+        storage.record_heap_sample(&location("parse"), 4096, 0, 4096, 2, 0);
+        storage.flush_checkpoint_at(42_570).unwrap();
+        drop(storage);
+
+        let lines = capture.lines.lock().unwrap();
+        let checkpoint = lines
+            .iter()
+            .find(|line| line.contains("timestamp_ms=42570"))
+            .expect("checkpoint logged");
+        assert!(checkpoint.contains(" DEBUG rsprof::storage::writer: checkpoint id=1 "));
+        assert!(checkpoint.ends_with("cpu_rows=2 heap_rows=1 marker_rows=0 counter_rows=0"));
+        // Other targets stay at warn
+        assert!(!lines.iter().any(|line| line.contains(" INFO ")));
+        drop(lines);
+
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
@@ -1,9 +1,72 @@
 use anyhow::Context;
 use clap::Parser;
-use rsprof::cli::{Cli, Command};
+use rsprof::cli::{Cli, Command, RecordFormat};
 use rsprof::error::exit_code;
+use rsprof::process::ProcessInfo;
+use rsprof::storage::SampleSink;
+use rsprof::symbols::Attribution;
+use rsprof::symbols::attribution::is_internal_location;
 use std::path::PathBuf;
 use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Delete a profile along with SQLite's write-ahead log files
+fn remove_profile(path: &std::path::Path) -> std::io::Result<()> {
+    std::fs::remove_file(path)?;
+    for suffix in ["-wal", "-shm"] {
+        let mut sidecar = path.as_os_str().to_owned();
+        sidecar.push(suffix);
+        match std::fs::remove_file(&sidecar) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Replace the profile at `to` with the one recorded at `from`
+///
+/// SQLite's write-ahead log files move along if a crash left them behind.
+fn replace_profile(from: &std::path::Path, to: &std::path::Path) -> std::io::Result<()> {
+    remove_profile(to)?;
+    std::fs::rename(from, to)?;
+    for suffix in ["-wal", "-shm"] {
+        let (mut old, mut new) = (from.as_os_str().to_owned(), to.as_os_str().to_owned());
+        old.push(suffix);
+        new.push(suffix);
+        match std::fs::rename(&old, &new) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Path a recording is written to before it ends up at `output_path`
+///
+/// Never replaces an earlier profile by accident: an existing `output_path`
+/// is an error unless appending or `force` is set. With `force`, the
+/// recording goes next to the old profile and `replace_profile` moves it
+/// over once the recording ends, so a failed start leaves the old one in place.
+fn record_path(
+    output_path: &std::path::Path,
+    append_mode: bool,
+    force: bool,
+) -> rsprof::Result<PathBuf> {
+    if append_mode || !output_path.exists() {
+        return Ok(output_path.to_path_buf());
+    }
+    if !force {
+        return Err(rsprof::Error::InvalidArgument(format!(
+            "{} already exists; pass --force to overwrite it or --append to add to it",
+            output_path.display()
+        )));
+    }
+    let mut path = output_path.as_os_str().to_owned();
+    path.push(".partial");
+    Ok(PathBuf::from(path))
+}
 
 /// Find the most recent profile file for a process name
 fn find_latest_profile(proc_name: &str) -> Option<PathBuf> {
@@ -23,11 +86,13 @@ fn find_latest_profile(proc_name: &str) -> Option<PathBuf> {
         .collect();
 
     // Sort by modification time, most recent first
+    #[allow(clippy::unnecessary_sort_by)]
     candidates.sort_by(|a, b| b.1.cmp(&a.1));
     candidates.into_iter().next().map(|(path, _)| path)
 }
 
 fn main() -> ExitCode {
+    rsprof::logging::init();
     match run() {
         Ok(()) => ExitCode::from(exit_code::SUCCESS as u8),
         Err(e) => {
@@ -58,17 +123,140 @@ fn run() -> anyhow::Result<()> {
             since,
             until,
             json,
+            jsonl,
             csv,
             filter,
+            exclude,
+            regex,
+            cpu_weight,
+            heap_weight,
+            percent_of,
         }) => {
+            let filter = rsprof::commands::top::RowFilter::new(
+                filter.as_deref(),
+                exclude.as_deref(),
+                regex,
+            )?;
             rsprof::commands::top::run(
-                &file, metric, top, threshold, since, until, json, csv, filter,
+                &file,
+                metric,
+                top,
+                threshold,
+                since,
+                until,
+                json,
+                jsonl,
+                csv,
+                &filter,
+                cpu_weight,
+                heap_weight,
+                percent_of,
+                cli.names,
             )?;
         }
-        Some(Command::Query { file, sql }) => {
-            rsprof::commands::query::run(&file, &sql)?;
+        Some(Command::Annotate {
+            file,
+            source_root,
+            heap,
+            top,
+            context,
+        }) => {
+            rsprof::commands::annotate::run(&file, source_root.as_deref(), heap, top, context)?;
+        }
+        Some(Command::Diff {
+            base,
+            new,
+            metric,
+            lines,
+            top,
+            rename_map,
+            suggest_renames,
+            json,
+        }) => {
+            rsprof::commands::diff::run(
+                &base,
+                &new,
+                metric,
+                lines,
+                top,
+                rename_map.as_deref(),
+                suggest_renames,
+                json,
+                cli.names,
+            )?;
+        }
+        Some(Command::Assert {
+            profile,
+            golden,
+            max_regression,
+            min_share,
+            rename_map,
+        }) => {
+            rsprof::commands::assert::run(
+                &profile,
+                &golden,
+                max_regression,
+                min_share,
+                rename_map.as_deref(),
+                cli.names,
+            )?;
+        }
+        Some(Command::Peak { file, top, json }) => {
+            rsprof::commands::peak::run(&file, top, json, cli.names)?;
+        }
+        Some(Command::Retained {
+            file,
+            top,
+            window,
+            json,
+        }) => {
+            rsprof::commands::retained::run(&file, top, window as usize, json, cli.names)?;
+        }
+        Some(Command::Leaks { file, top, json }) => {
+            rsprof::commands::leaks::run(&file, top, json, cli.names)?;
+        }
+        Some(Command::Report {
+            file,
+            format,
+            output,
+            top,
+        }) => {
+            rsprof::commands::report::run(&file, format, output.as_deref(), top, cli.names)?;
+        }
+        Some(Command::Export {
+            file,
+            format,
+            metric,
+            output,
+        }) => {
+            rsprof::commands::export::run(&file, format, metric, output.as_deref())?;
+        }
+        Some(Command::Query {
+            file,
+            sql,
+            preset,
+            top,
+            json,
+        }) => match preset {
+            Some(preset) => rsprof::commands::query::run_preset(&file, preset, top, json)?,
+            None => rsprof::commands::query::run(&file, sql.as_deref().unwrap_or_default(), json)?,
+        },
+        Some(Command::Schema { file }) => {
+            rsprof::commands::schema::run(file.as_deref())?;
+        }
+        Some(Command::View {
+            playlist: Some(dir),
+            dwell,
+            ..
+        }) => {
+            rsprof::commands::view::run_playlist(
+                &dir,
+                dwell,
+                cli.names,
+                cli.columns.unwrap_or_default(),
+            )?;
         }
-        Some(Command::View { file }) => {
+        Some(Command::View { file, .. }) => {
             let profile_path = match file {
                 Some(f) => f,
                 None => {
@@ -81,11 +269,29 @@ fn run() -> anyhow::Result<()> {
                         })?
                 }
             };
-            rsprof::commands::view::run(&profile_path)?;
+            rsprof::commands::view::run(&profile_path, cli.names, cli.columns.unwrap_or_default())?;
         }
         Some(Command::List { dir }) => {
             rsprof::commands::list::run(dir.as_deref())?;
         }
+        Some(Command::Threads { target }) => {
+            rsprof::commands::threads::run(&target, cli.pid_ns)?;
+        }
+        Some(Command::Ingest { file }) => {
+            rsprof::commands::ingest::run(&file, cli.output.as_deref())?;
+        }
+        Some(Command::Symbolicate { file, aslr_offset }) => {
+            rsprof::commands::symbolicate::run(&file, aslr_offset)?;
+        }
+        Some(Command::ImportPerf { ref file, ref out }) => {
+            rsprof::commands::import_perf::run(
+                file,
+                out.as_deref().or(cli.output.as_deref()),
+                cli.pid,
+                cli.interval,
+                cli.attribution(),
+            )?;
+        }
         Some(Command::Completions { shell }) => {
             use clap::CommandFactory;
             let mut cmd = Cli::command();
@@ -101,21 +307,129 @@ fn run() -> anyhow::Result<()> {
 }
 
 fn run_profiler(cli: &Cli) -> anyhow::Result<()> {
+    // Install the Ctrl-C handler before waiting for the target and the
+    // (possibly slow) symbol load, so either can be abandoned cleanly
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+    ctrlc::set_handler(move || {
+        r.store(false, Ordering::SeqCst);
+        rsprof::symbols::cancel_loading();
+    })
+    .context("Failed to set Ctrl-C handler")?;
+
     // Resolve PID
     let pid = match (cli.pid, &cli.process) {
-        (Some(pid), _) => pid,
-        (_, Some(name)) => rsprof::process::find_process_by_name(name)?,
+        (Some(pid), _) => match cli.pid_ns {
+            Some(holder) => rsprof::process::host_pid(pid, holder)?,
+            None => pid,
+        },
+        (_, Some(name)) => match cli.wait {
+            Some(timeout) => wait_for_process(name, cli.pid_ns, timeout, &running)?,
+            None => rsprof::process::find_process_by_name(name, cli.pid_ns)?,
+        },
         _ => unreachable!("validated in cli"),
     };
 
     // Verify process exists and get info
-    let proc_info = rsprof::process::ProcessInfo::new(pid)?;
-    eprintln!(
-        "Attaching to {} (PID {})",
+    let proc_info = match ProcessInfo::new(pid) {
+        Err(e) if rsprof::process::procfs_hidden(pid) => {
+            let (proc_info, warnings) =
+                rsprof::heap::attach_without_procfs(pid, std::path::Path::new("/"), e)?;
+            for warning in warnings {
+                eprintln!("Warning: {}", warning);
+            }
+            proc_info
+        }
+        result => result?,
+    };
+    if proc_info.ns_pid() == proc_info.pid() {
+        eprintln!(
+            "Attaching to {} (PID {})",
+            proc_info.name(),
+            proc_info.pid()
+        );
+    } else {
+        eprintln!(
+            "Attaching to {} (PID {}, PID {} in its namespace)",
+            proc_info.name(),
+            proc_info.pid(),
+            proc_info.ns_pid()
+        );
+    }
+    log::info!(
+        "attach pid={} ns_pid={} name={} exe={} procfs={}",
+        proc_info.pid(),
+        proc_info.ns_pid(),
         proc_info.name(),
-        proc_info.pid()
+        proc_info.exe_path().display(),
+        proc_info.has_procfs()
     );
 
+    // Resolve thread filter up front so a bad pattern fails before any setup
+    // (without /proc there are no threads to list; warned about above)
+    // The filter is matched again as threads start and get renamed; only the
+    // threads it selects now are checked here
+    let thread_filter =
+        if (cli.threads.is_some() || !cli.exclude_thread.is_empty()) && proc_info.has_procfs() {
+            Some(rsprof::process::ThreadFilter {
+                pattern: cli.threads.clone(),
+                exclude: cli.exclude_thread.clone(),
+            })
+        } else {
+            None
+        };
+    let mut filtered_threads = None;
+    if let Some(pattern) = thread_filter.as_ref().and_then(|f| f.pattern.as_ref()) {
+        let threads = proc_info.threads_matching(pattern)?;
+        if threads.is_empty() {
+            return Err(rsprof::Error::InvalidArgument(format!(
+                "No threads of PID {} match '{}' (list them with `rsprof threads {}`)",
+                pid, pattern, pid
+            ))
+            .into());
+        }
+        eprintln!(
+            "Sampling {} thread(s) matching '{}'",
+            threads.len(),
+            pattern
+        );
+        filtered_threads = Some(threads);
+    }
+    if let Some(filter) = thread_filter.as_ref().filter(|f| !f.exclude.is_empty()) {
+        let mut threads = match filtered_threads {
+            Some(threads) => threads,
+            None => proc_info.threads()?,
+        };
+        let before = threads.len();
+        threads.retain(|t| filter.allows(t));
+        if threads.is_empty() {
+            return Err(rsprof::Error::InvalidArgument(format!(
+                "--exclude-thread leaves no threads of PID {} to sample (list them with `rsprof threads {}`)",
+                pid, pid
+            ))
+            .into());
+        }
+        eprintln!(
+            "Excluding {} thread(s); sampling {}",
+            before - threads.len(),
+            threads.len()
+        );
+        filtered_threads = Some(threads);
+    }
+
+    // Prefork workers that already exist; later forks are picked up as they happen
+    let forked_children = if cli.follow_forks {
+        let children = rsprof::process::forked_children(pid);
+        eprintln!(
+            "Following forks of PID {} ({} running child process(es))",
+            pid,
+            children.len()
+        );
+        children
+    } else {
+        Vec::new()
+    };
+
     // Determine output path
     let output_path = if let Some(ref path) = cli.output {
         path.clone()
@@ -127,46 +441,256 @@ fn run_profiler(cli: &Cli) -> anyhow::Result<()> {
         })
     } else {
         let timestamp = chrono::Local::now().format("%y%m%d%H%M%S");
-        std::path::PathBuf::from(format!("rsprof.{}.{}.db", proc_info.name(), timestamp))
+        let extension = match cli.format {
+            RecordFormat::Db => "db",
+            RecordFormat::Eventlog => "rsplog",
+        };
+        // Two recordings started in the same second get -2, -3, ... suffixes
+        let mut path = std::path::PathBuf::from(format!(
+            "rsprof.{}.{}.{}",
+            proc_info.name(),
+            timestamp,
+            extension
+        ));
+        let mut suffix = 2;
+        while path.exists() {
+            path = std::path::PathBuf::from(format!(
+                "rsprof.{}.{}-{}.{}",
+                proc_info.name(),
+                timestamp,
+                suffix,
+                extension
+            ));
+            suffix += 1;
+        }
+        path
     };
     let append_mode = cli.append && output_path.exists();
+
+    let record_path = record_path(&output_path, append_mode, cli.force)?;
+    let overwrite = record_path != output_path;
     if append_mode {
         eprintln!("Appending to: {}", output_path.display());
+    } else if overwrite {
+        if record_path.exists() {
+            remove_profile(&record_path)?;
+        }
+        eprintln!(
+            "Overwriting: {} (recording to {} until done)",
+            output_path.display(),
+            record_path.display()
+        );
     } else {
         eprintln!("Output: {}", output_path.display());
     }
 
-    // Load symbols
-    eprintln!("Loading debug symbols...");
-    let resolver = rsprof::symbols::SymbolResolver::new(&proc_info)?;
-    eprintln!(
-        "Loaded {} address ranges from DWARF",
-        resolver.range_count()
-    );
-    eprintln!("ASLR offset: 0x{:x}", resolver.aslr_offset());
+    // Load symbols (skipped with --no-symbols, resolved at view time instead;
+    // event logs hold the raw sampled addresses, resolved by `rsprof ingest`)
+    let eventlog = cli.format == RecordFormat::Eventlog;
+    let no_symbols = cli.no_symbols || eventlog;
+    let resolver = if eventlog {
+        eprintln!("Recording raw addresses; `rsprof ingest` will resolve them");
+        None
+    } else if cli.no_symbols {
+        eprintln!("Skipping debug symbols; addresses will be resolved when viewing");
+        None
+    } else {
+        eprintln!("Loading debug symbols...");
+        let resolver = match rsprof::symbols::SymbolResolver::new(&proc_info) {
+            Err(rsprof::Error::Interrupted) => {
+                eprintln!("Cancelled while loading debug symbols");
+                return Err(rsprof::Error::Interrupted.into());
+            }
+            result => result?,
+        };
+        let source = match resolver.debug_source() {
+            rsprof::symbols::DebugSource::Binary => String::new(),
+            rsprof::symbols::DebugSource::Local(path) => format!(" in {}", path.display()),
+            rsprof::symbols::DebugSource::Debuginfod(path) => {
+                format!(" fetched from debuginfod ({})", path.display())
+            }
+        };
+        eprintln!(
+            "Loaded {} address ranges from DWARF{}",
+            resolver.range_count(),
+            source
+        );
+        eprintln!("ASLR offset: 0x{:x}", resolver.aslr_offset());
+        Some(resolver)
+    };
+
+    if !running.load(Ordering::SeqCst) {
+        eprintln!("Cancelled before recording started");
+        return Err(rsprof::Error::Interrupted.into());
+    }
+
+    // --auto-freq starts low and tunes the rate once overhead can be measured
+    // (against the target's CPU time in /proc)
+    let mut auto_freq = cli
+        .auto_freq
+        .filter(|_| proc_info.has_procfs())
+        .map(|percent| rsprof::cpu::AutoFreq::new(pid, percent));
+    let cpu_freq = match &auto_freq {
+        Some(auto) => auto.freq_hz(),
+        None => cli.cpu_freq,
+    };
 
     // Initialize storage
-    let storage = if append_mode {
-        rsprof::storage::Storage::open_append(&output_path)?
+    let mut output = if eventlog {
+        Output::EventLog(rsprof::storage::EventLog::create(
+            &record_path,
+            &proc_info,
+            cpu_freq,
+        )?)
+    } else if append_mode {
+        Output::Database(rsprof::storage::Storage::open_append(&output_path)?)
     } else {
-        rsprof::storage::Storage::new(&output_path, &proc_info, cli.cpu_freq)?
+        Output::Database(rsprof::storage::Storage::new(
+            &record_path,
+            &proc_info,
+            cpu_freq,
+        )?)
     };
+    let storage = output.sink();
+
+    // Sampled addresses are stored as runtime addresses; keep the load offset
+    // to map them back to the binary (deferred symbols, disassembly)
+    // (best effort: /proc may be hidden, e.g. when attaching through shared memory)
+    match proc_info.aslr_offset() {
+        Ok(aslr_offset) => storage.set_meta("aslr_offset", &aslr_offset.to_string())?,
+        Err(e) => {
+            log::warn!("aslr_offset unavailable error={}", e);
+            if no_symbols || cli.defer_heap_symbols {
+                eprintln!(
+                    "Warning: can't read the target's load address ({}); \
+                     pass --aslr-offset to `rsprof symbolicate`",
+                    e
+                );
+            }
+        }
+    }
+    // Settings to repeat this recording with (`R` in `rsprof view`)
+    storage.set_meta("record_args", &cli.record_args().join(" "))?;
+
+    // Record what deferred symbolication needs to find the right binary later
+    if no_symbols || cli.defer_heap_symbols {
+        if let Some(build_id) = rsprof::symbols::read_build_id(proc_info.proc_exe_path()) {
+            storage.set_meta("build_id", &build_id)?;
+        }
+        storage.set_meta("attribution", cli.attribution().name())?;
+        storage.set_meta(rsprof::symbols::deferred::META_SYMBOLS, "deferred")?;
+    }
 
     // Try to initialize shared memory sampler (rsprof-trace) first
     // This provides both CPU and heap profiling from self-instrumented targets
-    let shm_sampler = match rsprof::heap::ShmHeapSampler::new(pid, proc_info.exe_path()) {
-        Ok(shm) => {
+    let shm_sampler = match rsprof::heap::ShmHeapSampler::new(
+        pid,
+        proc_info.ns_pid(),
+        proc_info.root_path(),
+    ) {
+        Ok(mut shm) => {
             eprintln!("Profiling enabled (rsprof-trace: CPU + heap via shared memory)");
+            if let Some(filter) = &thread_filter
+                && !shm.set_thread_filter(filter.clone())
+            {
+                eprintln!(
+                    "Warning: --threads/--exclude-thread need write access to the target's \
+                     shared memory; recording CPU and heap samples from every thread"
+                );
+            }
+            if !cli.counters.is_empty() {
+                eprintln!("Warning: --counters only applies to perf_event sampling; ignored");
+            }
+            // CPU samples come at the target's profiler!(cpu = ...) rate, not --freq
+            // --auto-freq needs CPU sampling in the target and write access to request rates
+            if auto_freq.is_some()
+                && !(shm.cpu_freq_hz().is_some() && shm.request_cpu_freq(cpu_freq))
+            {
+                eprintln!(
+                    "Warning: --auto-freq needs CPU sampling in profiler! and write access to its shared memory; ignored"
+                );
+                auto_freq = None;
+            }
+            if auto_freq.is_some() {
+                storage.set_meta("cpu_freq_hz", &cpu_freq.to_string())?;
+            } else if let Some(freq) = shm.cpu_freq_hz() {
+                if freq != cli.cpu_freq {
+                    eprintln!(
+                        "Target samples CPU at {} Hz (set in profiler!); --cpu-freq {} ignored",
+                        freq, cli.cpu_freq
+                    );
+                }
+                storage.set_meta("cpu_freq_hz", &freq.to_string())?;
+            }
+            if let Some(percent) = cli.warn_on_overhead {
+                shm.set_overhead_warning(percent);
+            }
+            if cli.follow_forks && !shm.enable_follow_forks() {
+                eprintln!(
+                    "Warning: --follow-forks needs write access to the target's shared memory; \
+                     forked children's CPU samples won't be recorded"
+                );
+            }
+            if cli.transfers && !shm.enable_free_sites() {
+                eprintln!(
+                    "Warning: --transfers needs write access to the target's shared memory; ignored"
+                );
+            }
+            if let Some(min_age) = cli.min_age {
+                eprintln!(
+                    "Recording only allocations live for at least {}",
+                    humantime::format_duration(min_age)
+                );
+                shm.set_min_age(min_age);
+                storage.set_meta("min_age_ms", &min_age.as_millis().to_string())?;
+            }
             Some(shm)
         }
+        // A segment left by another or older target: the target itself may
+        // not use rsprof-trace at all
+        Err(e @ rsprof::Error::IncompatibleShm(_)) => {
+            eprintln!("Warning: {}; falling back to perf_event", e);
+            None
+        }
         Err(_) => None,
     };
+    if cli.min_age.is_some() && shm_sampler.is_none() {
+        eprintln!("Warning: --min-age requires rsprof-trace heap profiling; ignored");
+    }
+    if cli.transfers && shm_sampler.is_none() {
+        eprintln!("Warning: --transfers requires rsprof-trace heap profiling; ignored");
+    }
 
     // Initialize perf-based CPU sampler as fallback
     let perf_sampler = if shm_sampler.is_none() {
-        match rsprof::cpu::CpuSampler::new(pid, cli.cpu_freq) {
+        let open_sampler = |counters: &[rsprof::cpu::PerfCounter]| match &thread_filter {
+            Some(filter) => {
+                rsprof::cpu::CpuSampler::for_threads(pid, filter.clone(), cpu_freq, counters)
+            }
+            // --follow-forks can't be combined with the thread filters
+            None if cli.follow_forks => {
+                let pids: Vec<u32> = std::iter::once(pid)
+                    .chain(forked_children.iter().copied())
+                    .collect();
+                rsprof::cpu::CpuSampler::following_forks(&pids, cpu_freq, counters)
+            }
+            None => rsprof::cpu::CpuSampler::new(pid, cpu_freq, counters),
+        };
+        // Hardware counters are often unavailable (VMs, containers): sample without them
+        let sampler = match open_sampler(&cli.counters) {
+            Err(e) if !cli.counters.is_empty() => {
+                eprintln!("Warning: {}; recording without --counters", e);
+                open_sampler(&[])
+            }
+            sampler => sampler,
+        };
+        match sampler {
             Ok(s) => {
                 eprintln!("CPU profiling enabled (perf_event)");
+                if !s.counters().is_empty() {
+                    let names: Vec<&str> = s.counters().iter().map(|c| c.name()).collect();
+                    storage.set_meta("cpu_counters", &names.join(","))?;
+                }
                 Some(s)
             }
             Err(e) => {
@@ -178,262 +702,159 @@ fn run_profiler(cli: &Cli) -> anyhow::Result<()> {
         None // Don't need perf when we have rsprof-trace
     };
 
-    // Run profiler
-    if cli.quiet {
-        run_headless(
-            perf_sampler,
-            shm_sampler,
-            resolver,
-            storage,
-            cli.interval,
-            cli.duration,
-            cli.include_internal,
-        )?;
+    // Off-CPU time comes from perf_event whichever sampler records CPU
+    let off_cpu_sampler = if cli.off_cpu {
+        let tids: Vec<u32> = match &filtered_threads {
+            Some(threads) => threads.iter().map(|t| t.tid).collect(),
+            None => proc_info.thread_ids().unwrap_or_else(|_| vec![pid]),
+        };
+        match rsprof::cpu::OffCpuSampler::for_threads(&tids) {
+            Ok(s) => {
+                eprintln!(
+                    "Off-CPU profiling enabled (sched_switch, {} thread(s))",
+                    tids.len()
+                );
+                Some(s)
+            }
+            Err(e) => {
+                eprintln!("Off-CPU profiling disabled: {}", e);
+                None
+            }
+        }
     } else {
-        rsprof::tui::run(
-            perf_sampler,
-            shm_sampler,
-            resolver,
-            storage,
-            cli.interval,
-            cli.duration,
-            cli.include_internal,
-        )?;
-    }
-
-    Ok(())
-}
+        None
+    };
 
-/// Patterns for internal/profiler/library functions to skip
-/// These functions should be attributed to the user code that calls them
-const SKIP_FUNCTION_PATTERNS: &[&str] = &[
-    // Rust allocator entry points
-    "__rust_alloc",
-    "__rust_dealloc",
-    "__rust_realloc",
-    "__rustc",
-    // Rust alloc crate internals
-    "alloc::alloc::",
-    "alloc::raw_vec::",
-    "alloc::vec::",
-    "alloc::string::",
-    "alloc::collections::",
-    "<alloc::",
-    "alloc::fmt::",
-    "alloc::ffi::", // format! and CString internals
-    // Hashmap/collections internals
-    "hashbrown::",
-    "std::collections::hash",
-    // Core library internals
-    "core::ptr::",
-    "core::slice::",
-    "core::iter::",
-    "core::sync::", // atomics, etc.
-    "core::option::",
-    "core::result::",
-    "<core::",
-    "core::ops::function::",
-    "core::ops::drop::",
-    "core::ffi::",
-    "core::fmt::",
-    "core::num::",
-    "core::str::",
-    "core::hash::",
-    "core::mem::",
-    // Std library internals
-    "std::io::",
-    "std::fmt::",
-    "std::sys::",
-    "std::thread::",
-    "std::sync::",
-    "<std::",
-    "fmt::num::",
-    "fmt::Write::",
-    // Trait implementations (raw DWARF names)
-    " as core::fmt::",  // <T as core::fmt::Display>::fmt
-    " as std::fmt::",   // <T as std::fmt::Write>::write
-    " as core::hash::", // <T as core::hash::Hash>::hash
-    " as alloc::",      // <T as alloc::*>::method
-    // Trait implementations on generic types
-    "<_>::", // any method on trait objects
-    // Libc functions
-    "malloc",
-    "calloc",
-    "realloc",
-    "free",
-    "memcpy",
-    "memmove",
-    "memset",
-    "memchr",
-    "_start",
-    "__libc_start_main",
-    // Exception/unwinding
-    "_Unwind_",
-    "__cxa_",
-    "_fini",
-    "_init",
-    "rust_eh_personality",
-    // Profiler internals (rsprof-trace)
-    "addr2line::",
-    "gimli::",
-    "object::",
-    "miniz_oxide::",
-    "rustc_demangle::", // demangling library
-    "rsprof_alloc::",
-    "rsprof_trace::", // profiling library
-    "profiling::",
-    "rsprof::",
-    // Sorting internals
-    "sort::shared::smallsort::",
-    // Generic patterns for generated code
-    "::{{closure}}", // closures attributed to parent
-];
-
-/// Check if a file path looks like internal/library code
-fn is_internal_file(file: &str) -> bool {
-    file.is_empty()
-        || file.starts_with('[')
-        || file.starts_with('<')  // <std>/, <hashbrown>/, etc
-        || file.contains("/rustc/")
-        || file.contains("/.cargo/registry/")
-        || file.contains("/rust/library/")
-        || file.contains("rsprof-alloc")  // profiler internals
-        || file.contains("rsprof-trace")  // profiler internals
-        || file.contains("profiling.rs")  // profiler internals
-        // Bare filenames without path context are usually library code
-        || file == "lib.rs"
-        || file == "time.rs"
-        || file == "unix.rs"
-        // Common library source files
-        || file.ends_with("memchr.rs")
-        || file.ends_with("maybe_uninit.rs")
-        || file.ends_with("methods.rs")
-        || (file.ends_with("mod.rs") && !file.contains("/src/")) // lib mod.rs, not user mod.rs
-}
+    // Live JSON snapshots for external dashboards (--serve)
+    let server = match &cli.serve {
+        Some(path) => {
+            let server = rsprof::serve::SnapshotServer::bind(path, pid, proc_info.name())?;
+            eprintln!("Serving snapshots on {}", path.display());
+            Some(server)
+        }
+        None => None,
+    };
 
-/// Check if a location is internal (profiler/library code)
-fn is_internal_location(loc: &rsprof::symbols::Location) -> bool {
-    if is_internal_file(&loc.file) {
-        return true;
+    // Run profiler
+    match output {
+        Output::Database(storage) if !cli.quiet => {
+            rsprof::tui::run(
+                perf_sampler,
+                shm_sampler,
+                resolver,
+                storage,
+                cli.interval,
+                cli.duration,
+                cli.attribution(),
+                cli.names,
+                cli.columns.unwrap_or_default(),
+                auto_freq,
+                cli.live_max_locations as usize,
+            )?;
+        }
+        Output::Database(storage) => {
+            run_headless(
+                perf_sampler,
+                shm_sampler,
+                off_cpu_sampler,
+                resolver,
+                storage,
+                server,
+                cli.interval,
+                cli.duration,
+                cli.attribution(),
+                cli.defer_heap_symbols,
+                auto_freq,
+                &running,
+            )?;
+        }
+        Output::EventLog(log) => {
+            run_headless(
+                perf_sampler,
+                shm_sampler,
+                off_cpu_sampler,
+                resolver,
+                log,
+                None,
+                cli.interval,
+                cli.duration,
+                cli.attribution(),
+                cli.defer_heap_symbols,
+                auto_freq,
+                &running,
+            )?;
+            eprintln!(
+                "Convert to a profile database with: rsprof ingest {}",
+                output_path.display()
+            );
+        }
+    }
+    if overwrite {
+        replace_profile(&record_path, &output_path)?;
     }
-    SKIP_FUNCTION_PATTERNS
-        .iter()
-        .any(|p| loc.function.contains(p))
-}
 
-/// Patterns for utility functions that should be attributed to their callers
-const UTILITY_PATTERNS: &[&str] = &[
-    // Derived trait methods - attribute to caller
-    ">::clone",       // Clone::clone on any type
-    ">::fmt",         // Debug/Display::fmt
-    ">::hash",        // Hash::hash
-    ">::eq",          // PartialEq::eq
-    ">::partial_cmp", // PartialOrd
-    ">::cmp",         // Ord
-    // Common utility functions
-    "::utils::",
-    "::to_string",
-    "::to_owned",
-    "::into",
-    "format_bytes",
-    "format_size",
-    "sanitize_",
-    "generate_trace_id",
-];
-
-/// Check if a function is a utility function (should attribute to caller)
-fn is_utility_function(func: &str) -> bool {
-    UTILITY_PATTERNS.iter().any(|p| func.contains(p))
+    Ok(())
 }
 
-/// Find the best "user" frame in a stack trace.
-/// If the first user frame is a utility function, return its caller instead.
-fn find_user_frame(
-    stack: &[u64],
-    resolver: &rsprof::symbols::SymbolResolver,
-) -> rsprof::symbols::Location {
-    let mut first_user_frame: Option<rsprof::symbols::Location> = None;
-    let mut first_user_idx: Option<usize> = None;
-
-    // FIRST PASS: Find the first user frame
-    for (i, &addr) in stack.iter().enumerate() {
-        let loc = resolver.resolve(addr);
-        // Skip internal files and functions
-        if is_internal_file(&loc.file) || is_internal_location(&loc) {
-            continue;
-        }
-        if !loc.function.is_empty() && loc.function != "[unknown]" {
-            first_user_frame = Some(loc);
-            first_user_idx = Some(i);
-            break;
-        }
-    }
-
-    // If first user frame is a utility function, look for its caller
-    if let (Some(first_loc), Some(first_idx)) = (&first_user_frame, first_user_idx) {
-        if is_utility_function(&first_loc.function) {
-            // Look for the next user frame (caller of the utility)
-            for &addr in stack.iter().skip(first_idx + 1) {
-                let loc = resolver.resolve(addr);
-                let has_internal_fn = SKIP_FUNCTION_PATTERNS
-                    .iter()
-                    .any(|p| loc.function.contains(p));
-                if !has_internal_fn && !loc.function.is_empty() && loc.function != "[unknown]" {
-                    // Found the caller - return it
-                    return loc;
-                }
-            }
-        }
-        // Return the first user frame if no better caller found
-        return first_user_frame.unwrap();
-    }
+/// Where a recording is written (see --format)
+#[allow(clippy::large_enum_variant)]
+enum Output {
+    Database(rsprof::storage::Storage),
+    EventLog(rsprof::storage::EventLog),
+}
 
-    // Fallback: look for frames with real source paths
-    for &addr in stack {
-        let loc = resolver.resolve(addr);
-        if !is_internal_file(&loc.file) && !is_internal_location(&loc) {
-            return loc;
+impl Output {
+    fn sink(&mut self) -> &mut dyn SampleSink {
+        match self {
+            Output::Database(storage) => storage,
+            Output::EventLog(log) => log,
         }
     }
-
-    // No user frame found - return a marker that will be filtered out
-    // by is_internal_location (empty function name or internal file)
-    rsprof::symbols::Location {
-        file: "[internal]".to_string(),
-        line: 0,
-        column: 0,
-        function: "[internal]".to_string(),
-    }
 }
 
 #[allow(clippy::too_many_arguments)]
 fn run_headless(
     mut perf_sampler: Option<rsprof::cpu::CpuSampler>,
     mut shm_sampler: Option<rsprof::heap::ShmHeapSampler>,
-    resolver: rsprof::symbols::SymbolResolver,
-    mut storage: rsprof::storage::Storage,
+    mut off_cpu_sampler: Option<rsprof::cpu::OffCpuSampler>,
+    resolver: Option<rsprof::symbols::SymbolResolver>,
+    mut storage: impl SampleSink,
+    mut server: Option<rsprof::serve::SnapshotServer>,
     checkpoint_interval: std::time::Duration,
     duration: Option<std::time::Duration>,
-    include_internal: bool,
+    attribution: Attribution,
+    defer_heap_symbols: bool,
+    mut auto_freq: Option<rsprof::cpu::AutoFreq>,
+    running: &AtomicBool,
 ) -> anyhow::Result<()> {
-    use std::sync::Arc;
-    use std::sync::atomic::{AtomicBool, Ordering};
-
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
-
-    ctrlc::set_handler(move || {
-        r.store(false, Ordering::SeqCst);
-    })
-    .context("Failed to set Ctrl-C handler")?;
-
     let start = std::time::Instant::now();
     let mut last_checkpoint = std::time::Instant::now();
     let mut total_cpu_samples = 0u64;
     let mut total_heap_events = 0u64;
+    let mut dropped_events = 0u64;
+    let mut warned_dropped = false;
 
     eprintln!("Recording (Ctrl-C to stop)...");
 
+    // Raw stacks can't be classified until symbolicated, so keep them all
+    let keep_all = attribution == Attribution::Leaf || resolver.is_none();
+    // --defer-heap-symbols: heap stacks are stored raw, like --no-symbols
+    let heap_resolver = resolver.as_ref().filter(|_| !defer_heap_symbols);
+    let keep_all_heap = keep_all || defer_heap_symbols;
+    // Resolved call stack per heap callsite (callsite stacks never change)
+    let mut heap_stacks: std::collections::HashMap<u64, Vec<rsprof::symbols::Location>> =
+        std::collections::HashMap::new();
+    // Resolved call stack per CPU callsite
+    let mut cpu_stacks: std::collections::HashMap<u64, Vec<rsprof::symbols::Location>> =
+        std::collections::HashMap::new();
+    // Location and resolved call stack per perf-sampled stack
+    let mut perf_stacks: std::collections::HashMap<Vec<u64>, PerfStack> =
+        std::collections::HashMap::new();
+    // Location per off-CPU stack (a blocked thread is credited at every read)
+    let mut off_cpu_locations: std::collections::HashMap<Vec<u64>, rsprof::symbols::Location> =
+        std::collections::HashMap::new();
+    let mut off_cpu_lost = 0u64;
+
     while running.load(Ordering::SeqCst) {
         // Check duration limit
         if let Some(max_duration) = duration
@@ -444,46 +865,109 @@ fn run_headless(
 
         // Read from shared memory sampler (rsprof-trace) - gets both CPU and heap events
         if let Some(ref mut shm) = shm_sampler {
-            let _events = shm.poll_events(std::time::Duration::from_millis(1));
+            let (_events, dropped) = shm.poll_events(std::time::Duration::from_millis(1));
+            dropped_events += dropped;
+
+            // Checkpoint labels (rsprof_trace::label) annotate the next checkpoint
+            if let Some(label) = shm.take_label() {
+                storage.set_checkpoint_label(&label);
+            }
 
             // Process CPU samples from rsprof-trace (aggregated stats)
             let cpu_stats = shm.read_cpu_stats();
-            for (_hash, (count, stack)) in cpu_stats {
+            let thread_samples = shm.read_thread_samples();
+            for samples in &thread_samples {
+                storage.record_cpu_thread(samples.tid);
+            }
+            let process_samples = if shm.follows_forks() {
+                rsprof::heap::samples_by_process(&thread_samples)
+            } else {
+                Default::default()
+            };
+            for (hash, (count, stack)) in cpu_stats {
                 total_cpu_samples += count;
-                let location = if include_internal {
-                    resolve_internal_stack(&stack, &resolver)
-                } else {
-                    // Walk the stack to find the first user frame (skip allocator/profiler internals)
-                    find_user_frame(&stack, &resolver)
-                };
-                if include_internal || !is_internal_location(&location) {
+                if let Some(auto) = auto_freq.as_mut() {
+                    auto.count_samples(count);
+                }
+                let location = attribute_stack(&stack, resolver.as_ref(), attribution);
+                if keep_all || !is_internal_location(&location) {
                     storage.record_cpu_sample_count(
                         stack.first().copied().unwrap_or(0),
                         &location,
                         count,
                     );
+                    for (&process_id, &samples) in process_samples.get(&hash).into_iter().flatten()
+                    {
+                        storage.record_process_samples(process_id, &location, samples);
+                    }
+                    if let Some(resolver) = &resolver
+                        && rsprof::symbols::in_allocator(&stack, resolver)
+                    {
+                        storage.record_allocator_samples(&location, count);
+                    }
+                    if let Some(resolver) = &resolver {
+                        let frames = cpu_stacks.entry(hash).or_insert_with(|| {
+                            rsprof::symbols::user_stack(&stack, resolver, &location)
+                        });
+                        storage.record_cpu_stack(frames, count);
+                    }
                 }
             }
-
-            // Just update the event count - heap stats are recorded at checkpoint time
-            total_heap_events = shm.read_stats().len() as u64;
         }
 
         // Fallback to perf-based CPU sampling if no SHM sampler
         if shm_sampler.is_none()
             && let Some(ref mut sampler) = perf_sampler
         {
+            let processing = std::time::Instant::now();
             let samples = sampler.read_samples()?;
             total_cpu_samples += samples.len() as u64;
+            if let Some(auto) = auto_freq.as_mut() {
+                auto.count_samples(samples.len() as u64);
+            }
 
-            for addr in samples {
-                let location = resolver.resolve(addr);
-                if include_internal || !is_internal_location(&location) {
-                    storage.record_cpu_sample(addr, &location);
+            for sample in samples {
+                storage.record_cpu_thread(sample.tid);
+                if !perf_stacks.contains_key(&sample.stack) {
+                    let stack = PerfStack::resolve(&sample.stack, resolver.as_ref(), attribution);
+                    perf_stacks.insert(sample.stack.clone(), stack);
                 }
+                let stack = &perf_stacks[&sample.stack];
+                let location = &stack.location;
+                if keep_all || !is_internal_location(location) {
+                    storage.record_cpu_sample_count(sample.ip, location, 1);
+                    if sampler.follows_forks() {
+                        storage.record_process_samples(sample.pid, location, 1);
+                    }
+                    if stack.in_allocator {
+                        storage.record_allocator_samples(location, 1);
+                    }
+                    storage.record_cpu_stack(&stack.frames, 1);
+                    if !sampler.counters().is_empty() {
+                        storage.record_cpu_counters(location, &sampler.named_counters(&sample));
+                    }
+                }
+            }
+            if let Some(auto) = auto_freq.as_mut() {
+                auto.add_processing(processing.elapsed());
             }
         }
 
+        // Time threads spent blocked (--off-cpu)
+        if let Some(ref mut sampler) = off_cpu_sampler {
+            for sample in sampler.read_samples() {
+                let location = off_cpu_locations
+                    .entry(sample.stack)
+                    .or_insert_with_key(|stack| {
+                        attribute_stack(stack, resolver.as_ref(), attribution)
+                    });
+                if keep_all || !is_internal_location(location) {
+                    storage.record_off_cpu(location, sample.count, sample.blocked_ns);
+                }
+            }
+            off_cpu_lost += sampler.take_lost();
+        }
+
         // Checkpoint - record heap stats and flush
         if last_checkpoint.elapsed() >= checkpoint_interval {
             // Record heap stats from SHM sampler (rsprof-trace)
@@ -494,17 +978,18 @@ fn run_headless(
 
                 for (key_addr, stats) in heap_stats {
                     let location = if let Some(stack) = inline_stacks.get(&key_addr) {
-                        if include_internal {
-                            resolve_internal_stack(stack, &resolver)
-                        } else {
-                            find_user_frame(stack, &resolver)
-                        }
-                    } else if include_internal {
-                        rsprof::symbols::Location::unknown()
-                    } else {
+                        attribute_stack(stack, heap_resolver, attribution)
+                    } else if key_addr == rsprof::heap::UNSTACKED_CALLSITE {
+                        rsprof::symbols::Location::unstacked()
+                    } else if let Some(resolver) = resolver
+                        .as_ref()
+                        .filter(|_| attribution == Attribution::User)
+                    {
                         resolver.resolve(key_addr)
+                    } else {
+                        rsprof::symbols::Location::unknown()
                     };
-                    if include_internal || !is_internal_location(&location) {
+                    if keep_all_heap || !is_internal_location(&location) {
                         storage.record_heap_sample(
                             &location,
                             stats.total_alloc_bytes as i64,
@@ -513,12 +998,92 @@ fn run_headless(
                             stats.total_allocs,
                             stats.total_frees,
                         );
+                        // Deferred stacks get their frames when symbolicated
+                        if let (Some(stack), Some(resolver)) =
+                            (inline_stacks.get(&key_addr), heap_resolver)
+                        {
+                            let frames = heap_stacks.entry(key_addr).or_insert_with(|| {
+                                rsprof::symbols::user_stack(stack, resolver, &location)
+                            });
+                            storage.record_heap_stack(
+                                frames,
+                                stats.total_alloc_bytes as i64,
+                                stats.total_free_bytes as i64,
+                                stats.live_bytes,
+                                stats.total_allocs,
+                                stats.total_frees,
+                            );
+                        }
+                        storage.record_heap_sizes(&location, &stats.sizes);
                     }
                 }
+
+                // Alloc site -> free site pairs (--transfers)
+                for transfer in shm.read_transfers() {
+                    let (Some(alloc_stack), Some(free_stack)) = (
+                        inline_stacks.get(&transfer.alloc_hash),
+                        inline_stacks.get(&transfer.free_hash),
+                    ) else {
+                        continue;
+                    };
+                    let alloc = attribute_stack(alloc_stack, heap_resolver, attribution);
+                    let free = attribute_stack(free_stack, heap_resolver, attribution);
+                    if keep_all_heap
+                        || !(is_internal_location(&alloc) || is_internal_location(&free))
+                    {
+                        storage.record_heap_transfer(&alloc, &free, transfer.count, transfer.bytes);
+                    }
+                }
+            }
+
+            // Record per-marker heap stats (rsprof_trace::mark)
+            if let Some(ref shm) = shm_sampler {
+                for marker in shm.read_markers() {
+                    storage.record_marker_sample(
+                        &marker.name,
+                        marker.total_alloc_bytes as i64,
+                        marker.total_free_bytes as i64,
+                        marker.live_bytes,
+                        marker.total_allocs,
+                        marker.total_frees,
+                    );
+                }
+            }
+
+            // Resident memory (incl. huge-page backing) to set the heap numbers against
+            if let Some(rollup) = shm_sampler
+                .as_ref()
+                .and_then(|shm| rsprof::process::MemoryRollup::for_pid(shm.target_pid()))
+            {
+                for (key, value) in rollup.meta() {
+                    storage.set_meta(key, &value.to_string())?;
+                }
             }
 
             storage.flush_checkpoint()?;
             last_checkpoint = std::time::Instant::now();
+            if let Some(server) = server.as_mut()
+                && let Some((cpu, heap)) = storage.query_top(rsprof::serve::SNAPSHOT_TOP)
+            {
+                server.publish(start.elapsed(), &cpu, &heap);
+            }
+            if let Some(warning) = shm_sampler.as_mut().and_then(|shm| shm.check_overhead()) {
+                eprintln!("\nWarning: {}", warning);
+            }
+            if dropped_events > 0 && !warned_dropped {
+                warned_dropped = true;
+                eprintln!(
+                    "\nWarning: the target is dropping samples because its profiler tables are full \
+                     (see `full` and `alloc_slots` in profiler!)"
+                );
+            }
+            if let Some(auto) = auto_freq.as_mut()
+                && let Some(freq) = auto.adjust(perf_sampler.as_mut(), shm_sampler.as_ref())?
+            {
+                eprintln!("\nCPU sampling frequency: {} Hz", freq);
+                storage.set_meta("cpu_freq_changes", &auto.changes_meta())?;
+                storage.set_meta("cpu_freq_hz", &format!("{:.2}", auto.effective_freq_hz()))?;
+            }
             eprint!(
                 "\rCPU samples: {} | Heap sites: {} | Elapsed: {:?}",
                 total_cpu_samples,
@@ -537,22 +1102,156 @@ fn run_headless(
         "\nRecording complete. CPU samples: {}, Heap sites: {}",
         total_cpu_samples, total_heap_events
     );
+    log::info!(
+        "recording_finished cpu_samples={} heap_sites={} elapsed_ms={}",
+        total_cpu_samples,
+        total_heap_events,
+        start.elapsed().as_millis()
+    );
+    if let Some(auto) = &auto_freq {
+        // Samples were taken at several rates; store the average so
+        // sample counts still convert to CPU time
+        storage.set_meta("cpu_freq_changes", &auto.changes_meta())?;
+        storage.set_meta("cpu_freq_hz", &format!("{:.2}", auto.effective_freq_hz()))?;
+    }
+    if let Some(percent) = shm_sampler.as_ref().and_then(|shm| shm.overhead_percent()) {
+        eprintln!("Profiler overhead: ~{:.1}% of target CPU time", percent);
+        storage.set_meta("overhead_pct", &format!("{:.2}", percent))?;
+    }
+    if dropped_events > 0 {
+        eprintln!("Lost samples: {} (profiler tables full)", dropped_events);
+        storage.set_meta("dropped_events", &dropped_events.to_string())?;
+    }
+    if off_cpu_lost > 0 {
+        eprintln!(
+            "Lost off-CPU switches: {} (perf ring buffer full)",
+            off_cpu_lost
+        );
+    }
 
     Ok(())
 }
 
-fn resolve_internal_stack(
-    stack: &[u64],
-    resolver: &rsprof::symbols::SymbolResolver,
-) -> rsprof::symbols::Location {
-    for &addr in stack {
-        if addr == 0 {
-            continue;
+/// A perf-sampled stack attributed like an rsprof-trace one
+struct PerfStack {
+    location: rsprof::symbols::Location,
+    /// Resolved user frames, `location` first (none without a resolver)
+    frames: Vec<rsprof::symbols::Location>,
+    /// The sample was inside the allocator on behalf of `location`
+    in_allocator: bool,
+}
+
+impl PerfStack {
+    fn resolve(
+        stack: &[u64],
+        resolver: Option<&rsprof::symbols::SymbolResolver>,
+        attribution: Attribution,
+    ) -> Self {
+        let location = attribute_stack(stack, resolver, attribution);
+        let (frames, in_allocator) = match resolver {
+            Some(resolver) => (
+                rsprof::symbols::user_stack(stack, resolver, &location),
+                rsprof::symbols::in_allocator(stack, resolver),
+            ),
+            None => (Vec::new(), false),
+        };
+        PerfStack {
+            location,
+            frames,
+            in_allocator,
         }
-        let loc = resolver.resolve(addr);
-        if loc.function != "_fini" && loc.function != "[unknown]" {
-            return loc;
+    }
+}
+
+/// Poll for a process matching `pattern` (--wait), until `timeout` if given
+fn wait_for_process(
+    pattern: &str,
+    pid_ns: Option<u32>,
+    timeout: Option<std::time::Duration>,
+    running: &AtomicBool,
+) -> rsprof::Result<u32> {
+    // Short enough to attach before most programs get past startup
+    const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
+
+    let start = std::time::Instant::now();
+    let mut shown_secs = None;
+    loop {
+        match rsprof::process::find_process_by_name(pattern, pid_ns) {
+            Err(rsprof::Error::ProcessNotFound(_)) => {}
+            result => {
+                if shown_secs.is_some() {
+                    eprintln!();
+                }
+                return result;
+            }
         }
+        let elapsed = start.elapsed();
+        if let Some(timeout) = timeout
+            && elapsed >= timeout
+        {
+            eprintln!();
+            return Err(rsprof::Error::ProcessNotFound(format!(
+                "No process matching '{}' started within {}",
+                pattern,
+                humantime::format_duration(timeout)
+            )));
+        }
+        if !running.load(Ordering::SeqCst) {
+            eprintln!();
+            eprintln!("Cancelled while waiting for the target");
+            return Err(rsprof::Error::Interrupted);
+        }
+        if shown_secs != Some(elapsed.as_secs()) {
+            shown_secs = Some(elapsed.as_secs());
+            eprint!(
+                "\rWaiting for a process matching '{}'... {}s",
+                pattern,
+                elapsed.as_secs()
+            );
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Attribute a sampled stack to a location
+///
+/// Without a resolver the raw stack is kept for deferred symbolication.
+fn attribute_stack(
+    stack: &[u64],
+    resolver: Option<&rsprof::symbols::SymbolResolver>,
+    attribution: Attribution,
+) -> rsprof::symbols::Location {
+    match resolver {
+        None => rsprof::symbols::deferred::raw_location(stack),
+        Some(resolver) => attribution.attribute(stack, resolver),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn existing_output_needs_force_to_be_overwritten() {
+        let output = std::env::temp_dir().join(format!("rsprof-force-{}.db", std::process::id()));
+        std::fs::write(&output, "old profile").unwrap();
+
+        let err = record_path(&output, false, false).unwrap_err();
+        assert!(err.to_string().contains("--force"));
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "old profile");
+        // Appending keeps adding to the same file
+        assert_eq!(record_path(&output, true, false).unwrap(), output);
+
+        let partial = record_path(&output, false, true).unwrap();
+        assert_ne!(partial, output);
+        // The old profile survives until the new recording is done
+        std::fs::write(&partial, "new profile").unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "old profile");
+        replace_profile(&partial, &output).unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "new profile");
+        assert!(!partial.exists());
+
+        remove_profile(&output).unwrap();
+        assert_eq!(record_path(&output, false, false).unwrap(), output);
     }
-    rsprof::symbols::Location::unknown()
 }
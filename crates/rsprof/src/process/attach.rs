@@ -1,14 +1,84 @@
+use super::MemoryMaps;
 use crate::error::{Error, Result};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+/// A thread of the target process
+#[derive(Debug, Clone)]
+pub struct ThreadInfo {
+    pub tid: u32,
+    /// Thread name from /proc/[pid]/task/[tid]/comm
+    pub name: String,
+    /// Scheduler state from /proc/[pid]/task/[tid]/stat (R, S, D, ...)
+    pub state: char,
+}
+
+impl ThreadInfo {
+    /// Human-readable scheduler state
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            'R' => "running",
+            'S' => "sleeping",
+            'D' => "disk sleep",
+            'T' => "stopped",
+            't' => "tracing stop",
+            'Z' => "zombie",
+            'X' => "dead",
+            'I' => "idle",
+            _ => "unknown",
+        }
+    }
+
+    /// Match a `--exclude-thread` spec: a TID, or a substring of the name
+    pub fn matches_spec(&self, spec: &str) -> bool {
+        match spec.parse::<u32>() {
+            Ok(tid) => tid == self.tid,
+            Err(_) => self.name.contains(spec),
+        }
+    }
+}
+
+/// Threads selected by `--threads` and `--exclude-thread`
+#[derive(Debug, Clone, Default)]
+pub struct ThreadFilter {
+    /// Name substring a thread must contain (`--threads`)
+    pub pattern: Option<String>,
+    /// Specs of threads left out, applied after `pattern` (`--exclude-thread`)
+    pub exclude: Vec<String>,
+}
+
+impl ThreadFilter {
+    /// Whether a thread is sampled under this filter
+    ///
+    /// Names can change, so the same thread may be allowed at one time and
+    /// not at another.
+    pub fn allows(&self, thread: &ThreadInfo) -> bool {
+        self.pattern
+            .as_ref()
+            .is_none_or(|pattern| thread.name.contains(pattern.as_str()))
+            && !self.exclude.iter().any(|spec| thread.matches_spec(spec))
+    }
+
+    /// The threads this filter allows
+    pub fn select(&self, threads: Vec<ThreadInfo>) -> Vec<ThreadInfo> {
+        threads.into_iter().filter(|t| self.allows(t)).collect()
+    }
+}
 
 /// Information about a target process
 pub struct ProcessInfo {
     pid: u32,
+    /// PID the target sees itself as, in its own PID namespace (containers)
+    ns_pid: u32,
     name: String,
     exe_path: PathBuf,
     /// Path to /proc/[pid]/exe - works even if binary was deleted/rebuilt
     proc_exe_path: PathBuf,
+    /// Path to /proc/[pid]/root - the target's view of the filesystem
+    root_path: PathBuf,
+    /// ASLR offset reported by the target itself, when /proc/[pid] is hidden
+    /// (None = read it from /proc/[pid]/maps)
+    known_aslr_offset: Option<u64>,
 }
 
 impl ProcessInfo {
@@ -18,6 +88,12 @@ impl ProcessInfo {
 
         // Check process exists
         if !std::path::Path::new(&proc_path).exists() {
+            if procfs_hidden(pid) {
+                return Err(Error::PermissionDenied(format!(
+                    "PID {} is running but /proc/{} is not visible (procfs unmounted or mounted with hidepid)",
+                    pid, pid
+                )));
+            }
             return Err(Error::ProcessNotFound(format!("PID {}", pid)));
         }
 
@@ -44,18 +120,75 @@ impl ProcessInfo {
             exe_path
         };
 
+        // Paths the target reports (exe, maps) are relative to its own root, which
+        // differs from ours when it runs in another mount namespace (containers).
+        // Fall back to our root if we aren't allowed to look through it.
+        let root_path = PathBuf::from(format!("{}/root", proc_path));
+        let root_path = if fs::read_dir(&root_path).is_ok() {
+            root_path
+        } else {
+            PathBuf::from("/")
+        };
+
+        // Kernels before 4.1 don't report NSpid; no namespaces to tell apart then
+        let ns_pid = ns_pids(pid)
+            .and_then(|pids| pids.last().copied())
+            .unwrap_or(pid);
+
         Ok(ProcessInfo {
             pid,
+            ns_pid,
             name,
             exe_path,
             proc_exe_path,
+            root_path,
+            known_aslr_offset: None,
         })
     }
 
+    /// Describe a process whose /proc entry can't be read, from what the
+    /// target reported about itself (rsprof-trace shared memory)
+    ///
+    /// `exe_path` is opened directly, so it must be valid here too.
+    pub fn without_procfs(pid: u32, exe_path: PathBuf, aslr_offset: u64) -> Self {
+        let name = exe_path
+            .file_name()
+            .map(|name| name.to_string_lossy().chars().take(15).collect())
+            .unwrap_or_else(|| format!("pid{}", pid));
+        ProcessInfo {
+            pid,
+            ns_pid: pid,
+            name,
+            proc_exe_path: exe_path.clone(),
+            exe_path,
+            root_path: PathBuf::from("/"),
+            known_aslr_offset: Some(aslr_offset),
+        }
+    }
+
+    /// Whether /proc/[pid] was readable (false for `without_procfs`)
+    pub fn has_procfs(&self) -> bool {
+        self.known_aslr_offset.is_none()
+    }
+
+    /// ASLR offset of the executable, from /proc/[pid]/maps or as reported
+    pub fn aslr_offset(&self) -> Result<u64> {
+        match self.known_aslr_offset {
+            Some(offset) => Ok(offset),
+            None => MemoryMaps::for_pid(self.pid)?.aslr_offset(&self.exe_path),
+        }
+    }
+
     pub fn pid(&self) -> u32 {
         self.pid
     }
 
+    /// PID in the target's own PID namespace: what it reports as its PID
+    /// (e.g. in rsprof-trace shared memory). Same as `pid` outside containers
+    pub fn ns_pid(&self) -> u32 {
+        self.ns_pid
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -70,37 +203,255 @@ impl ProcessInfo {
         &self.proc_exe_path
     }
 
+    /// Get /proc/[pid]/root, or `/` if it can't be read
+    /// Use with [`path_in_root`] to open files the target refers to by path
+    pub fn root_path(&self) -> &PathBuf {
+        &self.root_path
+    }
+
     /// Get all thread IDs for this process
     pub fn thread_ids(&self) -> Result<Vec<u32>> {
-        let task_path = format!("/proc/{}/task", self.pid);
-        let mut tids = Vec::new();
+        if !self.has_procfs() {
+            return Err(Error::PermissionDenied(format!(
+                "Cannot list threads of PID {}: /proc/{} is not visible",
+                self.pid, self.pid
+            )));
+        }
+        task_ids(self.pid).map_err(|e| {
+            Error::ProcessNotFound(format!("Cannot read tasks for PID {}: {}", self.pid, e))
+        })
+    }
 
-        for entry in fs::read_dir(&task_path).map_err(|e| {
+    /// Get all threads with their names
+    pub fn threads(&self) -> Result<Vec<ThreadInfo>> {
+        self.thread_ids()?;
+        threads_in(Path::new("/proc"), self.pid).map_err(|e| {
             Error::ProcessNotFound(format!("Cannot read tasks for PID {}: {}", self.pid, e))
-        })? {
-            if let Ok(entry) = entry
-                && let Some(name) = entry.file_name().to_str()
-                && let Ok(tid) = name.parse::<u32>()
-            {
-                tids.push(tid);
-            }
+        })
+    }
+
+    /// Get threads whose name matches a pattern (pgrep-style substring matching)
+    pub fn threads_matching(&self, pattern: &str) -> Result<Vec<ThreadInfo>> {
+        Ok(self
+            .threads()?
+            .into_iter()
+            .filter(|t| t.name.contains(pattern))
+            .collect())
+    }
+}
+
+/// Thread IDs of a process, from /proc/<pid>/task
+pub fn task_ids(pid: u32) -> std::io::Result<Vec<u32>> {
+    task_ids_in(Path::new("/proc"), pid)
+}
+
+/// Thread IDs of a process, from `<proc_root>/<pid>/task`
+fn task_ids_in(proc_root: &Path, pid: u32) -> std::io::Result<Vec<u32>> {
+    let mut tids = Vec::new();
+    for entry in fs::read_dir(proc_root.join(pid.to_string()).join("task"))? {
+        if let Ok(entry) = entry
+            && let Some(name) = entry.file_name().to_str()
+            && let Ok(tid) = name.parse::<u32>()
+        {
+            tids.push(tid);
         }
+    }
+    Ok(tids)
+}
 
-        Ok(tids)
+/// Threads of a process with their names and states, from `<proc_root>/<pid>/task`
+///
+/// Sorted by TID. Threads that exit between listing and reading are left out.
+pub fn threads_in(proc_root: &Path, pid: u32) -> std::io::Result<Vec<ThreadInfo>> {
+    let mut threads = Vec::new();
+
+    for tid in task_ids_in(proc_root, pid)? {
+        let task_path = proc_root
+            .join(pid.to_string())
+            .join("task")
+            .join(tid.to_string());
+        if let Ok(comm) = fs::read_to_string(task_path.join("comm")) {
+            let state = fs::read_to_string(task_path.join("stat"))
+                .ok()
+                .and_then(|stat| stat_state(&stat))
+                .unwrap_or('?');
+            threads.push(ThreadInfo {
+                tid,
+                name: comm.trim().to_string(),
+                state,
+            });
+        }
     }
+
+    threads.sort_by_key(|t| t.tid);
+    Ok(threads)
+}
+
+/// Whether a process exists but its /proc entry can't be seen
+pub fn procfs_hidden(pid: u32) -> bool {
+    !Path::new(&format!("/proc/{}", pid)).exists() && process_exists(pid)
 }
 
-/// Find a process by name (pgrep-style matching)
-pub fn find_process_by_name(pattern: &str) -> Result<u32> {
+/// Whether a process exists, with or without procfs
+///
+/// `kill(pid, 0)` checks existence without procfs; EPERM still means the
+/// process is there, just owned by someone else.
+pub fn process_exists(pid: u32) -> bool {
+    let alive = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0
+        || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+    pid != 0 && alive
+}
+
+/// Scheduler state (field 3) of a /proc stat line
+fn stat_state(stat: &str) -> Option<char> {
+    // comm may contain spaces and parentheses; the state follows the last ")"
+    stat[stat.rfind(')')? + 1..].trim_start().chars().next()
+}
+
+/// Total CPU time (user + system) consumed by a process, from /proc/[pid]/stat
+pub fn cpu_time(pid: u32) -> Option<std::time::Duration> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // comm may contain spaces; fields after ")" start at field 3 (state)
+    let rest = &stat[stat.rfind(')')? + 1..];
+    let mut fields = rest.split_whitespace().skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+
+    let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks_per_sec <= 0 {
+        return None;
+    }
+    Some(std::time::Duration::from_secs_f64(
+        (utime + stime) as f64 / ticks_per_sec as f64,
+    ))
+}
+
+/// Running processes forked from `pid` without exec: its descendants that
+/// still run the same executable (prefork workers)
+pub fn forked_children(pid: u32) -> Vec<u32> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    let processes: Vec<(u32, u32, Option<PathBuf>)> = entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok())
+        .filter_map(|pid| {
+            let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+            let exe = fs::read_link(format!("/proc/{}/exe", pid)).ok();
+            Some((pid, stat_parent(&stat)?, exe))
+        })
+        .collect();
+    children_sharing_exe(pid, &processes)
+}
+
+/// Descendants of `root` in a `(pid, parent pid, executable)` listing that
+/// run the same executable as `root`, parents before their children
+///
+/// A child that exec'd another program is skipped along with its subtree.
+fn children_sharing_exe(root: u32, processes: &[(u32, u32, Option<PathBuf>)]) -> Vec<u32> {
+    let Some(exe) = processes
+        .iter()
+        .find(|(pid, _, _)| *pid == root)
+        .and_then(|(_, _, exe)| exe.as_ref())
+    else {
+        return Vec::new();
+    };
+
+    let mut children = Vec::new();
+    let mut parents = vec![root];
+    while let Some(parent) = parents.pop() {
+        for (pid, _, _) in processes.iter().filter(|(pid, ppid, child_exe)| {
+            *ppid == parent && *pid != root && child_exe.as_ref() == Some(exe)
+        }) {
+            children.push(*pid);
+            parents.push(*pid);
+        }
+    }
+    children
+}
+
+/// Parent PID (field 4) of a /proc stat line
+fn stat_parent(stat: &str) -> Option<u32> {
+    stat[stat.rfind(')')? + 1..]
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+/// PIDs of a process in each PID namespace it is in, from the NSpid line of
+/// /proc/[pid]/status: ours first, its own (innermost) last
+pub fn ns_pids(pid: u32) -> Option<Vec<u32>> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("NSpid:"))?
+        .split_whitespace()
+        .map(|pid| pid.parse().ok())
+        .collect()
+}
+
+/// Our PID for the process that is `ns_pid` in the PID namespace of `holder`
+/// (any process in that namespace, e.g. a container's init)
+pub fn host_pid(ns_pid: u32, holder: u32) -> Result<u32> {
+    let namespace = holder_namespace(holder)?;
+    let entries = fs::read_dir("/proc")?;
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok())
+        .find(|&pid| {
+            pid_namespace(pid).as_ref() == Some(&namespace)
+                && ns_pids(pid).and_then(|pids| pids.last().copied()) == Some(ns_pid)
+        })
+        .ok_or_else(|| {
+            Error::ProcessNotFound(format!(
+                "PID {} in the PID namespace of PID {}",
+                ns_pid, holder
+            ))
+        })
+}
+
+/// Identity of a process's PID namespace (`pid:[inode]`)
+fn pid_namespace(pid: u32) -> Option<PathBuf> {
+    fs::read_link(format!("/proc/{}/ns/pid", pid)).ok()
+}
+
+/// PID namespace of a `--pid-ns` process
+fn holder_namespace(holder: u32) -> Result<PathBuf> {
+    pid_namespace(holder).ok_or_else(|| {
+        Error::ProcessNotFound(format!(
+            "Cannot read the PID namespace of PID {} (/proc/{}/ns/pid)",
+            holder, holder
+        ))
+    })
+}
+
+/// Map a path as seen by the target onto our filesystem via its root
+pub fn path_in_root(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Find a process by name (pgrep-style matching), optionally only among the
+/// processes in the PID namespace of `pid_ns`
+pub fn find_process_by_name(pattern: &str, pid_ns: Option<u32>) -> Result<u32> {
+    let namespace = pid_ns.map(holder_namespace).transpose()?;
     let mut matches: Vec<(u32, String)> = Vec::new();
 
-    for entry in fs::read_dir("/proc")? {
+    let entries = fs::read_dir("/proc").map_err(|e| {
+        Error::PermissionDenied(format!(
+            "Cannot list processes to match '{}': /proc is not readable ({}); pass --pid instead",
+            pattern, e
+        ))
+    })?;
+    for entry in entries {
         let entry = entry?;
         let name = entry.file_name();
         let name_str = name.to_string_lossy();
 
         // Check if it's a PID directory
-        if let Ok(pid) = name_str.parse::<u32>() {
+        if let Ok(pid) = name_str.parse::<u32>()
+            && (namespace.is_none() || pid_namespace(pid) == namespace)
+        {
             let comm_path = format!("/proc/{}/comm", pid);
             if let Ok(comm) = fs::read_to_string(&comm_path) {
                 let comm = comm.trim();
@@ -113,6 +464,11 @@ pub fn find_process_by_name(pattern: &str) -> Result<u32> {
     }
 
     match matches.len() {
+        // Not even our own entry: procfs is empty or hides other processes
+        0 if !Path::new("/proc/self").exists() => Err(Error::PermissionDenied(format!(
+            "Cannot match '{}': /proc lists no processes; pass --pid instead",
+            pattern
+        ))),
         0 => Err(Error::ProcessNotFound(format!(
             "No process matching '{}'",
             pattern
@@ -146,3 +502,121 @@ pub fn sanitize_name(name: &str) -> String {
         .take(32)
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forked_children_share_the_executable() {
+        let app = || Some(PathBuf::from("/usr/bin/app"));
+        let processes = [
+            (1, 0, Some(PathBuf::from("/sbin/init"))),
+            (100, 1, app()),
+            // Prefork workers, one of which forked again
+            (101, 100, app()),
+            (102, 100, app()),
+            (103, 101, app()),
+            // A child that exec'd a shell, and what it started
+            (104, 100, Some(PathBuf::from("/bin/sh"))),
+            (105, 104, app()),
+            // Same program, not forked from the target
+            (200, 1, app()),
+            // Exited before its executable could be read
+            (106, 100, None),
+        ];
+
+        let mut children = children_sharing_exe(100, &processes);
+        children.sort();
+        assert_eq!(children, vec![101, 102, 103]);
+        assert!(children_sharing_exe(300, &processes).is_empty());
+    }
+
+    #[test]
+    fn threads_are_read_from_the_task_directory() {
+        let root = std::env::temp_dir().join(format!("rsprof-procfs-{}", std::process::id()));
+        let task = root.join("42").join("task");
+        for (tid, comm, stat) in [
+            (42, "app\n", "42 (app) S 1 42"),
+            (44, "worker-1\n", "44 (worker-1) R 1 42"),
+            // comm with ") " in it: the state follows the last ")"
+            (43, "odd) R (x\n", "43 (odd) R (x) D 1 42"),
+        ] {
+            let dir = task.join(tid.to_string());
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("comm"), comm).unwrap();
+            fs::write(dir.join("stat"), stat).unwrap();
+        }
+        // Exited between listing and reading comm
+        fs::create_dir_all(task.join("45")).unwrap();
+        // Not a thread
+        fs::create_dir_all(task.join("self")).unwrap();
+
+        let threads = threads_in(&root, 42).unwrap();
+        let summary: Vec<(u32, &str, char)> = threads
+            .iter()
+            .map(|t| (t.tid, t.name.as_str(), t.state))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (42, "app", 'S'),
+                (43, "odd) R (x", 'D'),
+                (44, "worker-1", 'R')
+            ]
+        );
+        assert!(threads_in(&root, 7).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn thread_filters_match_names_then_exclusions() {
+        let thread = |tid, name: &str| ThreadInfo {
+            tid,
+            name: name.to_string(),
+            state: 'S',
+        };
+        let threads = vec![
+            thread(42, "app"),
+            thread(43, "worker-1"),
+            thread(44, "worker-2"),
+        ];
+        let tids = |filter: &ThreadFilter| -> Vec<u32> {
+            filter
+                .select(threads.clone())
+                .iter()
+                .map(|t| t.tid)
+                .collect()
+        };
+
+        assert_eq!(tids(&ThreadFilter::default()), vec![42, 43, 44]);
+        let workers = ThreadFilter {
+            pattern: Some("worker".to_string()),
+            exclude: Vec::new(),
+        };
+        assert_eq!(tids(&workers), vec![43, 44]);
+        let exclude = ThreadFilter {
+            pattern: Some("worker".to_string()),
+            exclude: vec!["44".to_string()],
+        };
+        assert_eq!(tids(&exclude), vec![43]);
+        // A thread renamed to match is picked up, one renamed away dropped
+        assert!(workers.allows(&thread(42, "worker-3")));
+        assert!(!workers.allows(&thread(43, "idle")));
+    }
+
+    #[test]
+    fn stat_state_follows_the_last_parenthesis() {
+        assert_eq!(stat_state("42 (app) S 1 42"), Some('S'));
+        assert_eq!(stat_state("42 (a) R (b) D 1 42"), Some('D'));
+        assert_eq!(stat_state("42 (app"), None);
+    }
+
+    #[test]
+    fn stat_parent_skips_the_command_name() {
+        assert_eq!(stat_parent("42 (app) S 7 42 42 0"), Some(7));
+        assert_eq!(stat_parent("42 (a) b (c) R 9 42 42 0"), Some(9));
+        assert_eq!(stat_parent("42 (app"), None);
+    }
+}
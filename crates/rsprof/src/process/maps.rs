@@ -93,6 +93,11 @@ impl MemoryMaps {
         Ok(0)
     }
 
+    /// All mappings, in address order
+    pub fn mappings(&self) -> &[MemoryMapping] {
+        &self.mappings
+    }
+
     /// Get all executable mappings
     pub fn executable_mappings(&self) -> impl Iterator<Item = &MemoryMapping> {
         self.mappings.iter().filter(|m| m.is_executable())
@@ -1,5 +1,10 @@
 mod attach;
 mod maps;
+mod smaps;
 
-pub use attach::{ProcessInfo, find_process_by_name};
+pub use attach::{
+    ProcessInfo, ThreadFilter, ThreadInfo, cpu_time, find_process_by_name, forked_children,
+    host_pid, ns_pids, path_in_root, process_exists, procfs_hidden, task_ids, threads_in,
+};
 pub use maps::MemoryMaps;
+pub use smaps::MemoryRollup;
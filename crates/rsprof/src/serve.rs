@@ -0,0 +1,218 @@
+//! Live snapshots over a Unix domain socket (`--serve`).
+//!
+//! At each checkpoint the recorder publishes one JSON object per line to every
+//! connected client: process metadata plus the current top CPU and heap
+//! locations. Clients can follow a recording without opening the database
+//! while it is being written.
+
+use crate::commands::query::json_string;
+use crate::error::{Error, Result};
+use crate::storage::{CpuEntry, HeapEntry};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Number of CPU and heap entries in each snapshot
+pub const SNAPSHOT_TOP: usize = 20;
+
+/// Unix socket publishing a JSON snapshot per checkpoint
+pub struct SnapshotServer {
+    listener: UnixListener,
+    path: PathBuf,
+    clients: Vec<UnixStream>,
+    pid: u32,
+    process_name: String,
+    /// Snapshots published so far
+    sequence: u64,
+}
+
+impl SnapshotServer {
+    /// Listen on `path`, replacing a stale socket left by a previous run
+    ///
+    /// Anything else already at `path` (a file, or a socket still in use) is
+    /// left alone and reported as an error.
+    pub fn bind(path: &Path, pid: u32, process_name: &str) -> Result<Self> {
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(Error::InvalidArgument(format!(
+                    "--serve: {} exists and is not a socket",
+                    path.display()
+                )));
+            }
+            if UnixStream::connect(path).is_ok() {
+                return Err(Error::InvalidArgument(format!(
+                    "--serve: {} is in use by another process",
+                    path.display()
+                )));
+            }
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        // Never let a connecting client stall the record loop
+        listener.set_nonblocking(true)?;
+
+        Ok(SnapshotServer {
+            listener,
+            path: path.to_path_buf(),
+            clients: Vec::new(),
+            pid,
+            process_name: process_name.to_string(),
+            sequence: 0,
+        })
+    }
+
+    /// Accept pending connections and send every client the current snapshot
+    ///
+    /// Clients that disconnected or stopped reading are dropped.
+    pub fn publish(&mut self, elapsed: Duration, cpu: &[CpuEntry], heap: &[HeapEntry]) {
+        self.accept_pending();
+        self.sequence += 1;
+        if self.clients.is_empty() {
+            return;
+        }
+
+        let line = self.snapshot_json(elapsed, cpu, heap);
+        self.clients
+            .retain_mut(|client| client.write_all(line.as_bytes()).is_ok());
+    }
+
+    fn accept_pending(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    // A client that can't keep up is disconnected, not waited for
+                    if stream
+                        .set_write_timeout(Some(Duration::from_millis(100)))
+                        .is_ok()
+                    {
+                        self.clients.push(stream);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => break, // WouldBlock: no more pending connections
+            }
+        }
+    }
+
+    fn snapshot_json(&self, elapsed: Duration, cpu: &[CpuEntry], heap: &[HeapEntry]) -> String {
+        let cpu = cpu
+            .iter()
+            .map(|e| {
+                format!(
+                    "{{\"function\":{},\"file\":{},\"line\":{},\"samples\":{},\"cpu_pct\":{:.2},\"instant_pct\":{:.2}}}",
+                    json_string(&e.function),
+                    json_string(&e.file),
+                    e.line,
+                    e.total_samples,
+                    e.total_percent,
+                    e.instant_percent
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let heap = heap
+            .iter()
+            .map(|e| {
+                format!(
+                    "{{\"function\":{},\"file\":{},\"line\":{},\"live_bytes\":{}}}",
+                    json_string(&e.function),
+                    json_string(&e.file),
+                    e.line,
+                    e.live_bytes
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        format!(
+            "{{\"seq\":{},\"pid\":{},\"process\":{},\"elapsed_secs\":{:.3},\"cpu\":[{}],\"heap\":[{}]}}\n",
+            self.sequence,
+            self.pid,
+            json_string(&self.process_name),
+            elapsed.as_secs_f64(),
+            cpu,
+            heap
+        )
+    }
+}
+
+impl Drop for SnapshotServer {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rsprof-{}-{}.sock", name, std::process::id()))
+    }
+
+    #[test]
+    fn clients_receive_a_snapshot_per_checkpoint() {
+        let path = socket_path("serve");
+        let mut server = SnapshotServer::bind(&path, 42, "app").unwrap();
+        let client = UnixStream::connect(&path).unwrap();
+        let mut lines = BufReader::new(client).lines();
+
+        let cpu = vec![CpuEntry {
+            location_id: 1,
+            file: "src/main.rs".to_string(),
+            line: 10,
+            function: "app::\"quoted\"".to_string(),
+            total_samples: 7,
+            total_percent: 70.0,
+            instant_percent: 50.0,
+        }];
+        let heap = vec![HeapEntry {
+            location_id: 2,
+            file: "src/cache.rs".to_string(),
+            line: 20,
+            function: "app::cache".to_string(),
+            live_bytes: 4096,
+            total_alloc_bytes: 8192,
+            total_free_bytes: 4096,
+            alloc_count: 2,
+            free_count: 1,
+        }];
+        server.publish(Duration::from_millis(1500), &cpu, &heap);
+        server.publish(Duration::from_secs(3), &cpu, &[]);
+
+        assert_eq!(
+            lines.next().unwrap().unwrap(),
+            "{\"seq\":1,\"pid\":42,\"process\":\"app\",\"elapsed_secs\":1.500,\
+             \"cpu\":[{\"function\":\"app::\\\"quoted\\\"\",\"file\":\"src/main.rs\",\"line\":10,\
+             \"samples\":7,\"cpu_pct\":70.00,\"instant_pct\":50.00}],\
+             \"heap\":[{\"function\":\"app::cache\",\"file\":\"src/cache.rs\",\"line\":20,\
+             \"live_bytes\":4096}]}"
+        );
+        let second = lines.next().unwrap().unwrap();
+        assert!(second.starts_with("{\"seq\":2,\"pid\":42,"), "{}", second);
+        assert!(second.ends_with("\"heap\":[]}"), "{}", second);
+
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn only_stale_sockets_are_replaced() {
+        let path = socket_path("serve-file");
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(SnapshotServer::bind(&path, 42, "app").is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+        std::fs::remove_file(&path).unwrap();
+
+        // Left behind by a run that didn't clean up
+        let stale = UnixListener::bind(&path).unwrap();
+        drop(stale);
+        let server = SnapshotServer::bind(&path, 42, "app").unwrap();
+        // Still served: a second recorder can't take it over
+        assert!(SnapshotServer::bind(&path, 43, "app").is_err());
+        drop(server);
+    }
+}
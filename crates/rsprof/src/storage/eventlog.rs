@@ -0,0 +1,1026 @@
+//! Append-only event log for recording with minimal overhead.
+//!
+//! `--format eventlog` appends samples to a file as compact records instead
+//! of updating SQLite at each checkpoint, and skips symbol resolution: each
+//! record holds the raw sampled stack (see `symbols::deferred`). perf_event
+//! samples are written one by one as they are read; rsprof-trace targets
+//! only publish per-callsite counts, which are written per checkpoint.
+//! `rsprof ingest` later replays the log through [`Storage`] and resolves
+//! the stacks to build a regular profile database.
+//!
+//! Layout: the magic `RSPLOG02`, then tagged records. Integers are LEB128
+//! varints (zigzag for signed values), strings are a varint length + UTF-8.
+//! The version in the magic changes whenever a record tag is added or a
+//! record's fields change, so older logs are rejected rather than misread.
+
+use super::{CpuEntry, HeapEntry, Storage};
+use crate::error::{Error, Result};
+use crate::heap::{SIZE_BUCKETS, SizeDistribution};
+use crate::process::ProcessInfo;
+use crate::symbols::Location;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::Instant;
+
+const MAGIC: &[u8; 8] = b"RSPLOG02";
+
+/// Magic without its version, to tell an event log of another version apart
+const MAGIC_PREFIX: &[u8] = b"RSPLOG";
+
+const TAG_META: u8 = b'M';
+const TAG_LOCATION: u8 = b'L';
+const TAG_CPU: u8 = b'C';
+const TAG_HEAP: u8 = b'H';
+const TAG_MARKER: u8 = b'K';
+const TAG_CHECKPOINT: u8 = b'P';
+const TAG_LABEL: u8 = b'B';
+const TAG_ADDRESS: u8 = b'A';
+const TAG_SIZES: u8 = b'Z';
+const TAG_COUNTERS: u8 = b'N';
+const TAG_ALLOCATOR_CPU: u8 = b'U';
+const TAG_TRANSFER: u8 = b'T';
+const TAG_PROCESS_CPU: u8 = b'F';
+const TAG_HEAP_STACK: u8 = b'S';
+const TAG_CPU_THREAD: u8 = b'R';
+const TAG_CPU_STACK: u8 = b'X';
+const TAG_OFF_CPU: u8 = b'O';
+
+/// Destination for recorded samples: a profile database or an event log
+pub trait SampleSink {
+    /// Set a metadata key on the recording
+    fn set_meta(&mut self, key: &str, value: &str) -> Result<()>;
+
+    /// Record CPU samples with a count
+    fn record_cpu_sample_count(&mut self, addr: u64, location: &Location, count: u64);
+
+    /// Record hardware counter deltas read with a CPU sample
+    fn record_cpu_counters(&mut self, location: &Location, counters: &[(&str, u64)]);
+
+    /// Mark recorded CPU samples as spent inside the allocator
+    fn record_allocator_samples(&mut self, location: &Location, count: u64);
+
+    /// Attribute recorded CPU samples to the process they were taken in
+    fn record_process_samples(&mut self, process_id: u32, location: &Location, count: u64);
+
+    /// Note a thread that took CPU samples since the last checkpoint
+    fn record_cpu_thread(&mut self, tid: u32);
+
+    /// Record CPU samples for a full call stack, credited location first
+    fn record_cpu_stack(&mut self, frames: &[Location], count: u64);
+
+    /// Record time threads spent off-CPU at a location (`--off-cpu`)
+    fn record_off_cpu(&mut self, location: &Location, count: u64, blocked_ns: u64);
+
+    /// Record cumulative heap stats for a location (once per checkpoint)
+    fn record_heap_sample(
+        &mut self,
+        location: &Location,
+        alloc_bytes: i64,
+        free_bytes: i64,
+        live_bytes: i64,
+        alloc_count: u64,
+        free_count: u64,
+    );
+
+    /// Record cumulative heap stats for a full call stack, credited location first
+    /// (once per checkpoint)
+    fn record_heap_stack(
+        &mut self,
+        frames: &[Location],
+        alloc_bytes: i64,
+        free_bytes: i64,
+        live_bytes: i64,
+        alloc_count: u64,
+        free_count: u64,
+    );
+
+    /// Record a location's cumulative allocation size distribution (once per checkpoint)
+    fn record_heap_sizes(&mut self, location: &Location, sizes: &SizeDistribution);
+
+    /// Record cumulative allocations made at `alloc` and freed at `free` (once per checkpoint)
+    fn record_heap_transfer(&mut self, alloc: &Location, free: &Location, count: u64, bytes: u64);
+
+    /// Record cumulative heap stats for a marker (once per checkpoint)
+    fn record_marker_sample(
+        &mut self,
+        marker: &str,
+        alloc_bytes: i64,
+        free_bytes: i64,
+        live_bytes: i64,
+        alloc_count: u64,
+        free_count: u64,
+    );
+
+    /// Label the next checkpoint
+    fn set_checkpoint_label(&mut self, label: &str);
+
+    /// Close the current checkpoint
+    fn flush_checkpoint(&mut self) -> Result<()>;
+
+    /// Top CPU and heap entries so far, if the sink can be queried while recording
+    fn query_top(&self, _limit: usize) -> Option<(Vec<CpuEntry>, Vec<HeapEntry>)> {
+        None
+    }
+}
+
+impl SampleSink for Storage {
+    fn set_meta(&mut self, key: &str, value: &str) -> Result<()> {
+        Storage::set_meta(self, key, value)
+    }
+
+    fn record_cpu_sample_count(&mut self, addr: u64, location: &Location, count: u64) {
+        Storage::record_cpu_sample_count(self, addr, location, count);
+    }
+
+    fn record_cpu_counters(&mut self, location: &Location, counters: &[(&str, u64)]) {
+        Storage::record_cpu_counters(self, location, counters);
+    }
+
+    fn record_allocator_samples(&mut self, location: &Location, count: u64) {
+        Storage::record_allocator_samples(self, location, count);
+    }
+
+    fn record_process_samples(&mut self, process_id: u32, location: &Location, count: u64) {
+        Storage::record_process_samples(self, process_id, location, count);
+    }
+
+    fn record_cpu_thread(&mut self, tid: u32) {
+        Storage::record_cpu_thread(self, tid);
+    }
+
+    fn record_cpu_stack(&mut self, frames: &[Location], count: u64) {
+        Storage::record_cpu_stack(self, frames, count);
+    }
+
+    fn record_off_cpu(&mut self, location: &Location, count: u64, blocked_ns: u64) {
+        Storage::record_off_cpu(self, location, count, blocked_ns);
+    }
+
+    fn record_heap_sample(
+        &mut self,
+        location: &Location,
+        alloc_bytes: i64,
+        free_bytes: i64,
+        live_bytes: i64,
+        alloc_count: u64,
+        free_count: u64,
+    ) {
+        Storage::record_heap_sample(
+            self,
+            location,
+            alloc_bytes,
+            free_bytes,
+            live_bytes,
+            alloc_count,
+            free_count,
+        );
+    }
+
+    fn record_heap_stack(
+        &mut self,
+        frames: &[Location],
+        alloc_bytes: i64,
+        free_bytes: i64,
+        live_bytes: i64,
+        alloc_count: u64,
+        free_count: u64,
+    ) {
+        Storage::record_heap_stack(
+            self,
+            frames,
+            alloc_bytes,
+            free_bytes,
+            live_bytes,
+            alloc_count,
+            free_count,
+        );
+    }
+
+    fn record_heap_sizes(&mut self, location: &Location, sizes: &SizeDistribution) {
+        Storage::record_heap_sizes(self, location, sizes);
+    }
+
+    fn record_heap_transfer(&mut self, alloc: &Location, free: &Location, count: u64, bytes: u64) {
+        Storage::record_heap_transfer(self, alloc, free, count, bytes);
+    }
+
+    fn record_marker_sample(
+        &mut self,
+        marker: &str,
+        alloc_bytes: i64,
+        free_bytes: i64,
+        live_bytes: i64,
+        alloc_count: u64,
+        free_count: u64,
+    ) {
+        Storage::record_marker_sample(
+            self,
+            marker,
+            alloc_bytes,
+            free_bytes,
+            live_bytes,
+            alloc_count,
+            free_count,
+        );
+    }
+
+    fn set_checkpoint_label(&mut self, label: &str) {
+        Storage::set_checkpoint_label(self, label);
+    }
+
+    fn flush_checkpoint(&mut self) -> Result<()> {
+        Storage::flush_checkpoint(self)
+    }
+
+    fn query_top(&self, limit: usize) -> Option<(Vec<CpuEntry>, Vec<HeapEntry>)> {
+        Some((
+            self.query_top_cpu_live(limit),
+            self.query_top_heap_live(limit),
+        ))
+    }
+}
+
+/// Append-only event log writer
+pub struct EventLog {
+    writer: BufWriter<File>,
+    start_time: Instant,
+    /// Locations already written: (file, line, function) -> log-local id
+    locations: HashMap<(String, u32, String), u64>,
+    /// Sampled addresses already written: (location id, addr)
+    addresses: HashSet<(u64, u64)>,
+    /// Records written since the last checkpoint
+    pending: u64,
+}
+
+impl EventLog {
+    /// Create a new event log, recording the same metadata as `Storage::new`
+    pub fn create(path: &Path, proc_info: &ProcessInfo, cpu_freq: u64) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+
+        let mut log = EventLog {
+            writer,
+            start_time: Instant::now(),
+            locations: HashMap::new(),
+            addresses: HashSet::new(),
+            pending: 0,
+        };
+        log.set_meta("pid", &proc_info.pid().to_string())?;
+        log.set_meta("process_name", proc_info.name())?;
+        log.set_meta("exe_path", &proc_info.exe_path().display().to_string())?;
+        log.set_meta("start_time", &chrono::Utc::now().to_rfc3339())?;
+        log.set_meta("cpu_freq_hz", &cpu_freq.to_string())?;
+        Ok(log)
+    }
+
+    /// Get the log-local id for a location, writing its definition on first use
+    fn location_id(&mut self, location: &Location) -> Result<u64> {
+        let key = (
+            location.file.clone(),
+            location.line,
+            location.function.clone(),
+        );
+        if let Some(&id) = self.locations.get(&key) {
+            return Ok(id);
+        }
+
+        let id = self.locations.len() as u64;
+        self.writer.write_all(&[TAG_LOCATION])?;
+        write_varint(&mut self.writer, id)?;
+        write_str(&mut self.writer, &location.file)?;
+        write_varint(&mut self.writer, location.line as u64)?;
+        write_str(&mut self.writer, &location.function)?;
+        self.locations.insert(key, id);
+        Ok(id)
+    }
+
+    fn write_heap_values(&mut self, values: (i64, i64, i64, u64, u64)) -> Result<()> {
+        let (alloc_bytes, free_bytes, live_bytes, alloc_count, free_count) = values;
+        write_signed(&mut self.writer, alloc_bytes)?;
+        write_signed(&mut self.writer, free_bytes)?;
+        write_signed(&mut self.writer, live_bytes)?;
+        write_varint(&mut self.writer, alloc_count)?;
+        write_varint(&mut self.writer, free_count)?;
+        Ok(())
+    }
+
+    fn try_record_cpu(&mut self, addr: u64, location: &Location, count: u64) -> Result<()> {
+        let id = self.location_id(location)?;
+        if addr != 0 && self.addresses.insert((id, addr)) {
+            self.writer.write_all(&[TAG_ADDRESS])?;
+            write_varint(&mut self.writer, id)?;
+            write_varint(&mut self.writer, addr)?;
+        }
+        self.writer.write_all(&[TAG_CPU])?;
+        write_varint(&mut self.writer, id)?;
+        write_varint(&mut self.writer, count)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Counters as a count, then (name, value) pairs
+    fn try_record_counters(&mut self, location: &Location, counters: &[(&str, u64)]) -> Result<()> {
+        let id = self.location_id(location)?;
+        self.writer.write_all(&[TAG_COUNTERS])?;
+        write_varint(&mut self.writer, id)?;
+        write_varint(&mut self.writer, counters.len() as u64)?;
+        for &(counter, value) in counters {
+            write_str(&mut self.writer, counter)?;
+            write_varint(&mut self.writer, value)?;
+        }
+        self.pending += 1;
+        Ok(())
+    }
+
+    fn try_record_allocator_samples(&mut self, location: &Location, count: u64) -> Result<()> {
+        let id = self.location_id(location)?;
+        self.writer.write_all(&[TAG_ALLOCATOR_CPU])?;
+        write_varint(&mut self.writer, id)?;
+        write_varint(&mut self.writer, count)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    fn try_record_process_samples(
+        &mut self,
+        process_id: u32,
+        location: &Location,
+        count: u64,
+    ) -> Result<()> {
+        let id = self.location_id(location)?;
+        self.writer.write_all(&[TAG_PROCESS_CPU])?;
+        write_varint(&mut self.writer, process_id as u64)?;
+        write_varint(&mut self.writer, id)?;
+        write_varint(&mut self.writer, count)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    fn try_record_cpu_thread(&mut self, tid: u32) -> Result<()> {
+        self.writer.write_all(&[TAG_CPU_THREAD])?;
+        write_varint(&mut self.writer, tid as u64)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Frame count, then each frame's location id, then the sample count
+    fn try_record_cpu_stack(&mut self, frames: &[Location], count: u64) -> Result<()> {
+        let ids = frames
+            .iter()
+            .map(|frame| self.location_id(frame))
+            .collect::<Result<Vec<_>>>()?;
+        self.writer.write_all(&[TAG_CPU_STACK])?;
+        write_varint(&mut self.writer, ids.len() as u64)?;
+        for id in ids {
+            write_varint(&mut self.writer, id)?;
+        }
+        write_varint(&mut self.writer, count)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    fn try_record_off_cpu(
+        &mut self,
+        location: &Location,
+        count: u64,
+        blocked_ns: u64,
+    ) -> Result<()> {
+        let id = self.location_id(location)?;
+        self.writer.write_all(&[TAG_OFF_CPU])?;
+        write_varint(&mut self.writer, id)?;
+        write_varint(&mut self.writer, count)?;
+        write_varint(&mut self.writer, blocked_ns)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    fn try_record_heap(
+        &mut self,
+        location: &Location,
+        values: (i64, i64, i64, u64, u64),
+    ) -> Result<()> {
+        let id = self.location_id(location)?;
+        self.writer.write_all(&[TAG_HEAP])?;
+        write_varint(&mut self.writer, id)?;
+        self.write_heap_values(values)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Frame count, then each frame's location id, then the heap values
+    fn try_record_heap_stack(
+        &mut self,
+        frames: &[Location],
+        values: (i64, i64, i64, u64, u64),
+    ) -> Result<()> {
+        let ids = frames
+            .iter()
+            .map(|frame| self.location_id(frame))
+            .collect::<Result<Vec<_>>>()?;
+        self.writer.write_all(&[TAG_HEAP_STACK])?;
+        write_varint(&mut self.writer, ids.len() as u64)?;
+        for id in ids {
+            write_varint(&mut self.writer, id)?;
+        }
+        self.write_heap_values(values)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Sizes as min, max and the non-empty buckets as (bucket, count) pairs
+    fn try_record_sizes(&mut self, location: &Location, sizes: &SizeDistribution) -> Result<()> {
+        let id = self.location_id(location)?;
+        self.writer.write_all(&[TAG_SIZES])?;
+        write_varint(&mut self.writer, id)?;
+        write_varint(&mut self.writer, sizes.min)?;
+        write_varint(&mut self.writer, sizes.max)?;
+        let used = sizes.buckets.iter().filter(|&&n| n > 0).count();
+        write_varint(&mut self.writer, used as u64)?;
+        for (bucket, &n) in sizes.buckets.iter().enumerate() {
+            if n > 0 {
+                write_varint(&mut self.writer, bucket as u64)?;
+                write_varint(&mut self.writer, n)?;
+            }
+        }
+        self.pending += 1;
+        Ok(())
+    }
+
+    fn try_record_transfer(
+        &mut self,
+        alloc: &Location,
+        free: &Location,
+        count: u64,
+        bytes: u64,
+    ) -> Result<()> {
+        let alloc_id = self.location_id(alloc)?;
+        let free_id = self.location_id(free)?;
+        self.writer.write_all(&[TAG_TRANSFER])?;
+        write_varint(&mut self.writer, alloc_id)?;
+        write_varint(&mut self.writer, free_id)?;
+        write_varint(&mut self.writer, count)?;
+        write_varint(&mut self.writer, bytes)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    fn try_record_marker(&mut self, marker: &str, values: (i64, i64, i64, u64, u64)) -> Result<()> {
+        self.writer.write_all(&[TAG_MARKER])?;
+        write_str(&mut self.writer, marker)?;
+        self.write_heap_values(values)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    fn try_set_label(&mut self, label: &str) -> Result<()> {
+        self.writer.write_all(&[TAG_LABEL])?;
+        write_str(&mut self.writer, label)?;
+        self.pending += 1;
+        Ok(())
+    }
+}
+
+impl SampleSink for EventLog {
+    fn set_meta(&mut self, key: &str, value: &str) -> Result<()> {
+        self.writer.write_all(&[TAG_META])?;
+        write_str(&mut self.writer, key)?;
+        write_str(&mut self.writer, value)?;
+        Ok(())
+    }
+
+    // Sample writes can't fail the recording loop; I/O errors surface at the
+    // next flush_checkpoint, since BufWriter keeps reporting them.
+    fn record_cpu_sample_count(&mut self, addr: u64, location: &Location, count: u64) {
+        self.try_record_cpu(addr, location, count).ok();
+    }
+
+    fn record_cpu_counters(&mut self, location: &Location, counters: &[(&str, u64)]) {
+        if !counters.is_empty() {
+            self.try_record_counters(location, counters).ok();
+        }
+    }
+
+    fn record_allocator_samples(&mut self, location: &Location, count: u64) {
+        self.try_record_allocator_samples(location, count).ok();
+    }
+
+    fn record_process_samples(&mut self, process_id: u32, location: &Location, count: u64) {
+        self.try_record_process_samples(process_id, location, count)
+            .ok();
+    }
+
+    fn record_cpu_thread(&mut self, tid: u32) {
+        self.try_record_cpu_thread(tid).ok();
+    }
+
+    fn record_cpu_stack(&mut self, frames: &[Location], count: u64) {
+        if !frames.is_empty() {
+            self.try_record_cpu_stack(frames, count).ok();
+        }
+    }
+
+    fn record_off_cpu(&mut self, location: &Location, count: u64, blocked_ns: u64) {
+        self.try_record_off_cpu(location, count, blocked_ns).ok();
+    }
+
+    fn record_heap_sample(
+        &mut self,
+        location: &Location,
+        alloc_bytes: i64,
+        free_bytes: i64,
+        live_bytes: i64,
+        alloc_count: u64,
+        free_count: u64,
+    ) {
+        self.try_record_heap(
+            location,
+            (alloc_bytes, free_bytes, live_bytes, alloc_count, free_count),
+        )
+        .ok();
+    }
+
+    fn record_heap_stack(
+        &mut self,
+        frames: &[Location],
+        alloc_bytes: i64,
+        free_bytes: i64,
+        live_bytes: i64,
+        alloc_count: u64,
+        free_count: u64,
+    ) {
+        if !frames.is_empty() {
+            self.try_record_heap_stack(
+                frames,
+                (alloc_bytes, free_bytes, live_bytes, alloc_count, free_count),
+            )
+            .ok();
+        }
+    }
+
+    fn record_heap_sizes(&mut self, location: &Location, sizes: &SizeDistribution) {
+        if sizes.count() > 0 {
+            self.try_record_sizes(location, sizes).ok();
+        }
+    }
+
+    fn record_heap_transfer(&mut self, alloc: &Location, free: &Location, count: u64, bytes: u64) {
+        self.try_record_transfer(alloc, free, count, bytes).ok();
+    }
+
+    fn record_marker_sample(
+        &mut self,
+        marker: &str,
+        alloc_bytes: i64,
+        free_bytes: i64,
+        live_bytes: i64,
+        alloc_count: u64,
+        free_count: u64,
+    ) {
+        self.try_record_marker(
+            marker,
+            (alloc_bytes, free_bytes, live_bytes, alloc_count, free_count),
+        )
+        .ok();
+    }
+
+    fn set_checkpoint_label(&mut self, label: &str) {
+        self.try_set_label(label).ok();
+    }
+
+    fn flush_checkpoint(&mut self) -> Result<()> {
+        if self.pending == 0 {
+            return Ok(());
+        }
+        let timestamp_ms = self.start_time.elapsed().as_millis() as u64;
+        self.writer.write_all(&[TAG_CHECKPOINT])?;
+        write_varint(&mut self.writer, timestamp_ms)?;
+        self.writer.flush()?;
+        log::debug!(
+            "checkpoint timestamp_ms={} records={}",
+            timestamp_ms,
+            self.pending
+        );
+        self.pending = 0;
+        Ok(())
+    }
+}
+
+/// Counts from converting an event log into a profile database
+#[derive(Debug, Default)]
+pub struct IngestSummary {
+    pub checkpoints: u64,
+    pub locations: u64,
+    pub records: u64,
+}
+
+/// Convert an event log into a new profile database at `db_path`
+///
+/// A truncated final record (e.g. the recorder was killed) is ignored, and
+/// samples after the last checkpoint marker are dropped.
+pub fn ingest(log_path: &Path, db_path: &Path) -> Result<IngestSummary> {
+    let mut reader = BufReader::new(File::open(log_path)?);
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        let reason = if magic.starts_with(MAGIC_PREFIX) {
+            format!(
+                "was written by another rsprof version (format {}, this rsprof reads {})",
+                String::from_utf8_lossy(&magic[MAGIC_PREFIX.len()..]),
+                String::from_utf8_lossy(&MAGIC[MAGIC_PREFIX.len()..])
+            )
+        } else {
+            "is not an rsprof event log".to_string()
+        };
+        return Err(Error::InvalidArgument(format!(
+            "{} {}",
+            log_path.display(),
+            reason
+        )));
+    }
+
+    let mut storage = Storage::create(db_path)?;
+    let mut locations: Vec<Location> = Vec::new();
+    let mut summary = IngestSummary::default();
+
+    loop {
+        let mut tag = [0u8; 1];
+        if reader.read_exact(&mut tag).is_err() {
+            break; // End of log
+        }
+        match ingest_record(&mut reader, tag[0], &mut storage, &mut locations) {
+            Ok(true) => summary.checkpoints += 1,
+            Ok(false) => {}
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        summary.records += 1;
+    }
+
+    summary.locations = locations.len() as u64;
+    Ok(summary)
+}
+
+/// Replay one record into `storage`; returns true for a checkpoint marker
+fn ingest_record(
+    reader: &mut impl Read,
+    tag: u8,
+    storage: &mut Storage,
+    locations: &mut Vec<Location>,
+) -> Result<bool> {
+    let location = |locations: &[Location], id: u64| {
+        locations.get(id as usize).cloned().ok_or_else(|| {
+            Error::InvalidArgument(format!("Event log references unknown location {}", id))
+        })
+    };
+
+    match tag {
+        TAG_META => {
+            let key = read_str(reader)?;
+            let value = read_str(reader)?;
+            storage.set_meta(&key, &value)?;
+        }
+        TAG_LOCATION => {
+            let _id = read_varint(reader)?; // Ids are assigned sequentially
+            let file = read_str(reader)?;
+            let line = read_varint(reader)? as u32;
+            let function = read_str(reader)?;
+            locations.push(Location {
+                file,
+                line,
+                column: 0,
+                function,
+            });
+        }
+        TAG_CPU => {
+            let location = location(locations, read_varint(reader)?)?;
+            let count = read_varint(reader)?;
+            storage.record_cpu_sample_count(0, &location, count);
+        }
+        TAG_COUNTERS => {
+            let location = location(locations, read_varint(reader)?)?;
+            let mut counters = Vec::new();
+            for _ in 0..read_varint(reader)? {
+                counters.push((read_str(reader)?, read_varint(reader)?));
+            }
+            let counters: Vec<(&str, u64)> = counters
+                .iter()
+                .map(|(counter, value)| (counter.as_str(), *value))
+                .collect();
+            storage.record_cpu_counters(&location, &counters);
+        }
+        TAG_ALLOCATOR_CPU => {
+            let location = location(locations, read_varint(reader)?)?;
+            storage.record_allocator_samples(&location, read_varint(reader)?);
+        }
+        TAG_PROCESS_CPU => {
+            let process_id = read_varint(reader)? as u32;
+            let location = location(locations, read_varint(reader)?)?;
+            storage.record_process_samples(process_id, &location, read_varint(reader)?);
+        }
+        TAG_CPU_THREAD => {
+            storage.record_cpu_thread(read_varint(reader)? as u32);
+        }
+        TAG_CPU_STACK => {
+            let depth = read_varint(reader)?;
+            let frames = (0..depth)
+                .map(|_| location(locations, read_varint(reader)?))
+                .collect::<Result<Vec<_>>>()?;
+            storage.record_cpu_stack(&frames, read_varint(reader)?);
+        }
+        TAG_OFF_CPU => {
+            let location = location(locations, read_varint(reader)?)?;
+            let count = read_varint(reader)?;
+            storage.record_off_cpu(&location, count, read_varint(reader)?);
+        }
+        TAG_ADDRESS => {
+            let location = location(locations, read_varint(reader)?)?;
+            let addr = read_varint(reader)?;
+            storage.record_location_address(&location, addr);
+        }
+        TAG_HEAP => {
+            let location = location(locations, read_varint(reader)?)?;
+            let (alloc, free, live, alloc_cnt, free_cnt) = read_heap_values(reader)?;
+            storage.record_heap_sample(&location, alloc, free, live, alloc_cnt, free_cnt);
+        }
+        TAG_HEAP_STACK => {
+            let depth = read_varint(reader)?;
+            let frames = (0..depth)
+                .map(|_| location(locations, read_varint(reader)?))
+                .collect::<Result<Vec<_>>>()?;
+            let (alloc, free, live, alloc_cnt, free_cnt) = read_heap_values(reader)?;
+            storage.record_heap_stack(&frames, alloc, free, live, alloc_cnt, free_cnt);
+        }
+        TAG_SIZES => {
+            let location = location(locations, read_varint(reader)?)?;
+            let sizes = read_sizes(reader)?;
+            storage.record_heap_sizes(&location, &sizes);
+        }
+        TAG_TRANSFER => {
+            let alloc = location(locations, read_varint(reader)?)?;
+            let free = location(locations, read_varint(reader)?)?;
+            let count = read_varint(reader)?;
+            let bytes = read_varint(reader)?;
+            storage.record_heap_transfer(&alloc, &free, count, bytes);
+        }
+        TAG_MARKER => {
+            let marker = read_str(reader)?;
+            let (alloc, free, live, alloc_cnt, free_cnt) = read_heap_values(reader)?;
+            storage.record_marker_sample(&marker, alloc, free, live, alloc_cnt, free_cnt);
+        }
+        TAG_LABEL => {
+            let label = read_str(reader)?;
+            storage.set_checkpoint_label(&label);
+        }
+        TAG_CHECKPOINT => {
+            let timestamp_ms = read_varint(reader)? as i64;
+            storage.flush_checkpoint_at(timestamp_ms)?;
+            return Ok(true);
+        }
+        other => {
+            return Err(Error::InvalidArgument(format!(
+                "Corrupt event log: unknown record tag 0x{:02x}",
+                other
+            )));
+        }
+    }
+
+    Ok(false)
+}
+
+fn read_heap_values(reader: &mut impl Read) -> Result<(i64, i64, i64, u64, u64)> {
+    Ok((
+        read_signed(reader)?,
+        read_signed(reader)?,
+        read_signed(reader)?,
+        read_varint(reader)?,
+        read_varint(reader)?,
+    ))
+}
+
+fn read_sizes(reader: &mut impl Read) -> Result<SizeDistribution> {
+    let mut sizes = SizeDistribution {
+        min: read_varint(reader)?,
+        max: read_varint(reader)?,
+        ..Default::default()
+    };
+    for _ in 0..read_varint(reader)? {
+        let bucket = read_varint(reader)? as usize;
+        let count = read_varint(reader)?;
+        if bucket >= SIZE_BUCKETS {
+            return Err(Error::InvalidArgument(format!(
+                "Corrupt event log: size bucket {} out of range",
+                bucket
+            )));
+        }
+        sizes.buckets[bucket] = count;
+    }
+    Ok(sizes)
+}
+
+fn write_varint(writer: &mut impl Write, mut value: u64) -> std::io::Result<()> {
+    let mut buf = [0u8; 10];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    writer.write_all(&buf[..len])
+}
+
+fn write_signed(writer: &mut impl Write, value: i64) -> std::io::Result<()> {
+    write_varint(writer, ((value << 1) ^ (value >> 63)) as u64)
+}
+
+fn write_str(writer: &mut impl Write, value: &str) -> std::io::Result<()> {
+    write_varint(writer, value.len() as u64)?;
+    writer.write_all(value.as_bytes())
+}
+
+fn read_varint(reader: &mut impl Read) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(Error::InvalidArgument(
+        "Corrupt event log: varint too long".to_string(),
+    ))
+}
+
+fn read_signed(reader: &mut impl Read) -> Result<i64> {
+    let value = read_varint(reader)?;
+    Ok(((value >> 1) as i64) ^ -((value & 1) as i64))
+}
+
+/// Read a string, growing the buffer as bytes arrive rather than trusting
+/// the length (a corrupt one would otherwise allocate up to 2^64 bytes)
+fn read_str(reader: &mut impl Read) -> Result<String> {
+    let len = read_varint(reader)?;
+    let mut buf = Vec::new();
+    reader.by_ref().take(len).read_to_end(&mut buf)?;
+    if (buf.len() as u64) < len {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    String::from_utf8(buf)
+        .map_err(|_| Error::InvalidArgument("Corrupt event log: invalid UTF-8".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{
+        query_checkpoint_labels, query_cpu_counter_totals, query_cpu_stacks, query_heap_stacks,
+        query_heap_transfers, query_top_allocator_cpu, query_top_cpu, query_top_heap_live,
+        query_top_markers, query_top_off_cpu, query_top_processes, query_top_sizes,
+    };
+    use rusqlite::Connection;
+
+    fn temp_path(name: &str, extension: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "rsprof-{}-{}.{}",
+            name,
+            std::process::id(),
+            extension
+        ))
+    }
+
+    fn remove_db(path: &Path) {
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", path.display(), suffix)).ok();
+        }
+    }
+
+    fn location(function: &str, line: u32) -> Location {
+        Location {
+            file: "src/main.rs".to_string(),
+            line,
+            column: 0,
+            function: function.to_string(),
+        }
+    }
+
+    /// Two checkpoints touching every kind of record
+    fn record(sink: &mut impl SampleSink) {
+        let (parse, hash, main) = (
+            location("parse", 10),
+            location("hash", 20),
+            location("main", 1),
+        );
+        let mut sizes = SizeDistribution {
+            min: 16,
+            max: 4096,
+            ..Default::default()
+        };
+        sizes.buckets[SizeDistribution::bucket_of(16)] = 3;
+        sizes.buckets[SizeDistribution::bucket_of(4096)] = 1;
+
+        sink.record_cpu_thread(42);
+        sink.record_cpu_sample_count(0x1010, &parse, 5);
+        sink.record_cpu_sample_count(0x2020, &hash, 2);
+        sink.record_cpu_stack(&[parse.clone(), main.clone()], 5);
+        sink.record_cpu_counters(&parse, &[("cycles", 1000), ("instructions", 800)]);
+        sink.record_allocator_samples(&hash, 1);
+        sink.record_process_samples(43, &hash, 2);
+        sink.record_off_cpu(&main, 3, 1_500_000);
+        sink.record_heap_sample(&parse, 4096, 1024, 3072, 4, 1);
+        sink.record_heap_stack(&[parse.clone(), main.clone()], 4096, 1024, 3072, 4, 1);
+        sink.record_heap_sizes(&parse, &sizes);
+        sink.record_heap_transfer(&parse, &hash, 1, 1024);
+        sink.record_marker_sample("request", 2048, 0, 2048, 2, 0);
+        sink.set_checkpoint_label("warm");
+        sink.flush_checkpoint().unwrap();
+
+        sink.record_cpu_thread(44);
+        sink.record_cpu_sample_count(0x1018, &parse, 1);
+        sink.record_cpu_stack(&[hash.clone(), main.clone()], 2);
+        sink.record_heap_sample(&parse, 8192, 8192, 0, 8, 8);
+        sink.record_heap_sample(&hash, 100, 0, 100, 1, 0);
+        sink.record_marker_sample("request", 4096, 4096, 0, 4, 4);
+        sink.flush_checkpoint().unwrap();
+    }
+
+    /// Everything a profile reports, apart from timestamps
+    fn aggregates(path: &Path) -> String {
+        let conn = Connection::open(path).unwrap();
+        let labels: Vec<String> = query_checkpoint_labels(&conn)
+            .into_iter()
+            .map(|(_, label)| label)
+            .collect();
+        format!(
+            "{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}",
+            query_top_cpu(&conn, 100, 0.0).unwrap(),
+            query_cpu_stacks(&conn).unwrap(),
+            query_cpu_counter_totals(&conn, "cycles"),
+            query_top_allocator_cpu(&conn, 100).unwrap(),
+            query_top_processes(&conn, 100).unwrap(),
+            query_top_off_cpu(&conn, 100).unwrap(),
+            query_top_heap_live(&conn, 100).unwrap(),
+            query_heap_stacks(&conn, 100).unwrap(),
+            query_top_sizes(&conn, 100).unwrap(),
+            query_heap_transfers(&conn, 100).unwrap(),
+            query_top_markers(&conn, 100).unwrap(),
+            labels,
+        )
+    }
+
+    #[test]
+    fn event_logs_ingest_to_the_same_profile() {
+        let proc_info = ProcessInfo::without_procfs(42, "/usr/bin/app".into(), 0);
+        let direct = temp_path("eventlog-direct", "db");
+        let log_path = temp_path("eventlog", "rsplog");
+        let ingested = temp_path("eventlog-ingested", "db");
+
+        let mut storage = Storage::new(&direct, &proc_info, 99).unwrap();
+        record(&mut storage);
+        drop(storage);
+        let mut log = EventLog::create(&log_path, &proc_info, 99).unwrap();
+        record(&mut log);
+        drop(log);
+
+        let summary = ingest(&log_path, &ingested).unwrap();
+        assert_eq!(summary.checkpoints, 2);
+        assert_eq!(summary.locations, 3);
+        let expected = aggregates(&direct);
+        assert!(expected.contains("parse"), "{}", expected);
+        assert_eq!(aggregates(&ingested), expected);
+
+        remove_db(&direct);
+        remove_db(&ingested);
+        std::fs::remove_file(&log_path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_string_lengths_are_not_allocated() {
+        let mut record = Vec::new();
+        write_varint(&mut record, u64::MAX >> 1).unwrap();
+        record.extend_from_slice(b"short");
+        match read_str(&mut record.as_slice()) {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof),
+            other => panic!("expected a truncated string, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn logs_of_other_versions_are_rejected() {
+        let log_path = temp_path("eventlog-old", "rsplog");
+        let db_path = temp_path("eventlog-old", "db");
+        std::fs::write(&log_path, b"RSPLOG01M").unwrap();
+        let error = ingest(&log_path, &db_path).unwrap_err().to_string();
+        assert!(error.contains("format 01"), "{}", error);
+        assert!(!db_path.exists());
+        std::fs::remove_file(&log_path).unwrap();
+    }
+}
@@ -1,9 +1,24 @@
+pub mod eventlog;
 mod schema;
 pub mod writer;
 
+pub use eventlog::{EventLog, SampleSink};
+pub use schema::{
+    SCHEMA_VERSION, dump_ddl, expected_ddl, get_meta, has_table, schema_differences, set_meta,
+    upgrade_tables,
+};
 pub use writer::{
-    CombinedEntry, CpuEntry, HeapEntry, Storage, TimeSeriesPoint, query_combined_live,
-    query_cpu_timeseries, query_cpu_timeseries_aggregated, query_heap_sparklines,
-    query_heap_sparklines_for_locations, query_heap_timeseries_aggregated, query_top_cpu,
-    query_top_heap_live,
+    AllocatorCpuEntry, BucketAggregate, CheckpointStats, CombinedEntry, CostEntry, CpuEntry,
+    CpuSeries, CpuStackEntry, GapEntry, HeapEntry, HeapPeak, HeapSeries, META_CLOCK_SKEW_STEPS,
+    MarkerEntry, OffCpuEntry, ProcessEntry, RUNNING_AVERAGE_CHECKPOINTS, SizeEntry, StackEntry,
+    Storage, TimeSeriesPoint, TransferEntry, query_checkpoint_labels, query_checkpoint_stats,
+    query_combined_live, query_cpu_counter_totals, query_cpu_freq_hz, query_cpu_sample_count,
+    query_cpu_stacks, query_cpu_timeseries, query_cpu_timeseries_aggregated,
+    query_heap_at_checkpoint, query_heap_count_sparklines_for_locations, query_heap_peak,
+    query_heap_series, query_heap_sizes, query_heap_sparklines,
+    query_heap_sparklines_for_locations, query_heap_stacks, query_heap_timeseries_aggregated,
+    query_heap_transfers, query_sample_gaps, query_top_allocator_cpu, query_top_cost,
+    query_top_cpu, query_top_cpu_range, query_top_heap_live, query_top_markers, query_top_off_cpu,
+    query_top_processes, query_top_sizes, repair_clock_skew, samples_to_secs, stack_hash,
+    write_heap_sizes,
 };
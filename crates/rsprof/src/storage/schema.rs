@@ -1,12 +1,22 @@
 use rusqlite::Connection;
 
-pub const SCHEMA_VERSION: i32 = 3;
+pub const SCHEMA_VERSION: i32 = 8;
 
 /// Create all tables (drops existing tables first to ensure clean state)
 pub fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
     conn.execute_batch(
         r#"
         -- Drop existing tables to ensure clean state for new session
+        DROP TABLE IF EXISTS off_cpu_samples;
+        DROP TABLE IF EXISTS cpu_stack_samples;
+        DROP TABLE IF EXISTS heap_stack_samples;
+        DROP TABLE IF EXISTS frames;
+        DROP TABLE IF EXISTS cpu_process_samples;
+        DROP TABLE IF EXISTS heap_transfers;
+        DROP TABLE IF EXISTS cpu_counters;
+        DROP TABLE IF EXISTS heap_sizes;
+        DROP TABLE IF EXISTS location_addresses;
+        DROP TABLE IF EXISTS marker_samples;
         DROP TABLE IF EXISTS heap_samples;
         DROP TABLE IF EXISTS cpu_samples;
         DROP TABLE IF EXISTS checkpoints;
@@ -19,12 +29,19 @@ pub fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
             value TEXT NOT NULL
         );
 
-        -- Checkpoints (one per interval)
+        -- Checkpoints (one per interval), with the CPU samples and the
+        -- threads that took them since the previous checkpoint
         CREATE TABLE checkpoints (
             id INTEGER PRIMARY KEY,
-            timestamp_ms INTEGER NOT NULL
+            timestamp_ms INTEGER NOT NULL,
+            label TEXT,
+            sample_count INTEGER NOT NULL DEFAULT 0,
+            thread_count INTEGER NOT NULL DEFAULT 0
         );
 
+        -- Index for "last N checkpoints" and time-range queries
+        CREATE INDEX idx_checkpoints_time ON checkpoints(timestamp_ms);
+
         -- Unique locations (file, line, function) - normalized
         CREATE TABLE locations (
             id INTEGER PRIMARY KEY,
@@ -39,6 +56,7 @@ pub fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
             checkpoint_id INTEGER NOT NULL,
             location_id INTEGER NOT NULL,
             count INTEGER NOT NULL,
+            allocator_count INTEGER NOT NULL DEFAULT 0,
             PRIMARY KEY (checkpoint_id, location_id),
             FOREIGN KEY (checkpoint_id) REFERENCES checkpoints(id),
             FOREIGN KEY (location_id) REFERENCES locations(id)
@@ -64,9 +82,329 @@ pub fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
         -- Index for timeseries queries by location
         CREATE INDEX idx_heap_location ON heap_samples(location_id);
         "#,
+    )?;
+
+    upgrade_tables(conn)
+}
+
+/// Create tables and indexes added after schema v3 (for appending to older profiles)
+pub fn upgrade_tables(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        r#"
+        -- Index on checkpoint time (v8 and earlier scan and sort checkpoints
+        -- on every sparkline refresh)
+        CREATE INDEX IF NOT EXISTS idx_checkpoints_time ON checkpoints(timestamp_ms);
+
+        -- Heap stats per marker per checkpoint (cumulative, like heap_samples)
+        CREATE TABLE IF NOT EXISTS marker_samples (
+            checkpoint_id INTEGER NOT NULL,
+            marker TEXT NOT NULL,
+            alloc_bytes INTEGER NOT NULL DEFAULT 0,
+            free_bytes INTEGER NOT NULL DEFAULT 0,
+            live_bytes INTEGER NOT NULL DEFAULT 0,
+            alloc_count INTEGER NOT NULL DEFAULT 0,
+            free_count INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (checkpoint_id, marker),
+            FOREIGN KEY (checkpoint_id) REFERENCES checkpoints(id)
+        );
+
+        -- Distinct sampled addresses per location (one source line spans many)
+        CREATE TABLE IF NOT EXISTS location_addresses (
+            location_id INTEGER NOT NULL,
+            addr INTEGER NOT NULL,
+            PRIMARY KEY (location_id, addr),
+            FOREIGN KEY (location_id) REFERENCES locations(id)
+        );
+
+        -- Allocation size distribution per location (cumulative, latest only)
+        -- histogram: "bucket:count" pairs, bucket i holding sizes in [2^i, 2^(i+1))
+        CREATE TABLE IF NOT EXISTS heap_sizes (
+            location_id INTEGER PRIMARY KEY,
+            min_size INTEGER NOT NULL,
+            max_size INTEGER NOT NULL,
+            histogram TEXT NOT NULL,
+            FOREIGN KEY (location_id) REFERENCES locations(id)
+        );
+
+        -- Hardware counter totals per location per checkpoint (--counters),
+        -- summed over the CPU samples counted in cpu_samples
+        CREATE TABLE IF NOT EXISTS cpu_counters (
+            checkpoint_id INTEGER NOT NULL,
+            location_id INTEGER NOT NULL,
+            counter TEXT NOT NULL,
+            value INTEGER NOT NULL,
+            PRIMARY KEY (checkpoint_id, location_id, counter),
+            FOREIGN KEY (checkpoint_id) REFERENCES checkpoints(id),
+            FOREIGN KEY (location_id) REFERENCES locations(id)
+        );
+
+        -- Allocations made at one location and freed at another (--transfers;
+        -- cumulative, latest only)
+        CREATE TABLE IF NOT EXISTS heap_transfers (
+            alloc_location_id INTEGER NOT NULL,
+            free_location_id INTEGER NOT NULL,
+            count INTEGER NOT NULL,
+            bytes INTEGER NOT NULL,
+            PRIMARY KEY (alloc_location_id, free_location_id),
+            FOREIGN KEY (alloc_location_id) REFERENCES locations(id),
+            FOREIGN KEY (free_location_id) REFERENCES locations(id)
+        );
+
+        -- CPU samples per process per checkpoint (--follow-forks); cpu_samples
+        -- holds the same samples summed over all processes
+        CREATE TABLE IF NOT EXISTS cpu_process_samples (
+            checkpoint_id INTEGER NOT NULL,
+            process_id INTEGER NOT NULL,
+            location_id INTEGER NOT NULL,
+            count INTEGER NOT NULL,
+            PRIMARY KEY (checkpoint_id, process_id, location_id),
+            FOREIGN KEY (checkpoint_id) REFERENCES checkpoints(id),
+            FOREIGN KEY (location_id) REFERENCES locations(id)
+        );
+
+        -- Call stacks of heap sites (v5) and CPU samples (v7), one row per
+        -- frame; depth 0 is the location heap_samples or cpu_samples credits,
+        -- higher depths are its callers
+        CREATE TABLE IF NOT EXISTS frames (
+            stack_hash INTEGER NOT NULL,
+            depth INTEGER NOT NULL,
+            location_id INTEGER NOT NULL,
+            PRIMARY KEY (stack_hash, depth),
+            FOREIGN KEY (location_id) REFERENCES locations(id)
+        );
+
+        -- Heap stats per call stack per checkpoint (cumulative, like heap_samples);
+        -- the stacks of a depth-0 location add up to its heap_samples row, less
+        -- allocations the target recorded without a stack
+        CREATE TABLE IF NOT EXISTS heap_stack_samples (
+            checkpoint_id INTEGER NOT NULL,
+            stack_hash INTEGER NOT NULL,
+            alloc_bytes INTEGER NOT NULL DEFAULT 0,
+            free_bytes INTEGER NOT NULL DEFAULT 0,
+            live_bytes INTEGER NOT NULL DEFAULT 0,
+            alloc_count INTEGER NOT NULL DEFAULT 0,
+            free_count INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (checkpoint_id, stack_hash),
+            FOREIGN KEY (checkpoint_id) REFERENCES checkpoints(id)
+        );
+
+        -- CPU samples per call stack per checkpoint (v7, rsprof-trace only);
+        -- the stacks of a depth-0 location add up to its cpu_samples count
+        CREATE TABLE IF NOT EXISTS cpu_stack_samples (
+            checkpoint_id INTEGER NOT NULL,
+            stack_hash INTEGER NOT NULL,
+            count INTEGER NOT NULL,
+            PRIMARY KEY (checkpoint_id, stack_hash),
+            FOREIGN KEY (checkpoint_id) REFERENCES checkpoints(id)
+        );
+
+        -- Time threads spent off-CPU per location per checkpoint (v8, --off-cpu):
+        -- how often they were switched out there and for how many ns in total
+        CREATE TABLE IF NOT EXISTS off_cpu_samples (
+            checkpoint_id INTEGER NOT NULL,
+            location_id INTEGER NOT NULL,
+            count INTEGER NOT NULL,
+            blocked_ns INTEGER NOT NULL,
+            PRIMARY KEY (checkpoint_id, location_id),
+            FOREIGN KEY (checkpoint_id) REFERENCES checkpoints(id),
+            FOREIGN KEY (location_id) REFERENCES locations(id)
+        );
+        "#,
+    )?;
+
+    // Columns added to create_tables since v4, for older profiles
+    // Optional text annotation per checkpoint (rsprof_trace::label)
+    add_column_if_missing(conn, "checkpoints", "label", "TEXT")?;
+    // CPU samples and sampling threads per interval (v6)
+    add_column_if_missing(
+        conn,
+        "checkpoints",
+        "sample_count",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    add_column_if_missing(
+        conn,
+        "checkpoints",
+        "thread_count",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    // Samples of `count` taken inside the allocator on behalf of the location
+    add_column_if_missing(
+        conn,
+        "cpu_samples",
+        "allocator_count",
+        "INTEGER NOT NULL DEFAULT 0",
     )
 }
 
+/// Add a column to an existing table unless it is already there
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    decl: &str,
+) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .filter_map(|name| name.ok())
+        .any(|name| name == column);
+    if !exists {
+        conn.execute_batch(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, decl
+        ))?;
+    }
+    Ok(())
+}
+
+/// Whether a table exists (profiles from older versions lack newer tables)
+pub fn has_table(conn: &Connection, table: &str) -> rusqlite::Result<bool> {
+    Ok(object_names(conn, "table")?
+        .iter()
+        .any(|name| name == table))
+}
+
+/// CREATE statements for every table and index, in creation order
+///
+/// Read back from `sqlite_master`, so columns added by `upgrade_tables`
+/// appear as SQLite appended them.
+pub fn dump_ddl(conn: &Connection) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT sql FROM sqlite_master
+         WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite_%'
+         ORDER BY rowid",
+    )?;
+    let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+    rows.map(|sql| sql.map(|sql| dedent(&sql))).collect()
+}
+
+/// DDL of a freshly created profile database
+pub fn expected_ddl() -> rusqlite::Result<Vec<String>> {
+    let conn = Connection::open_in_memory()?;
+    create_tables(&conn)?;
+    dump_ddl(&conn)
+}
+
+/// Differences between a database's schema and the one `create_tables` builds
+///
+/// Compares table and index names and each table's columns (type, NOT NULL,
+/// default, primary key). Returns one human-readable line per difference.
+pub fn schema_differences(conn: &Connection) -> rusqlite::Result<Vec<String>> {
+    let expected = Connection::open_in_memory()?;
+    create_tables(&expected)?;
+
+    let mut diffs = Vec::new();
+    for kind in ["table", "index"] {
+        let want = object_names(&expected, kind)?;
+        let have = object_names(conn, kind)?;
+        for name in want.iter().filter(|n| !have.contains(n)) {
+            diffs.push(format!("missing {} {}", kind, name));
+        }
+        for name in have.iter().filter(|n| !want.contains(n)) {
+            diffs.push(format!("unexpected {} {}", kind, name));
+        }
+    }
+
+    let have_tables = object_names(conn, "table")?;
+    for table in object_names(&expected, "table")? {
+        if !have_tables.contains(&table) {
+            continue;
+        }
+        let want = table_columns(&expected, &table)?;
+        let have = table_columns(conn, &table)?;
+        for column in &want {
+            match have.iter().find(|c| c.name == column.name) {
+                None => diffs.push(format!("{}: missing column {}", table, column.name)),
+                Some(actual) if actual != column => diffs.push(format!(
+                    "{}: column {} is {}, expected {}",
+                    table, column.name, actual, column
+                )),
+                Some(_) => {}
+            }
+        }
+        for column in have
+            .iter()
+            .filter(|c| !want.iter().any(|w| w.name == c.name))
+        {
+            diffs.push(format!("{}: unexpected column {}", table, column.name));
+        }
+    }
+    Ok(diffs)
+}
+
+/// Column as reported by `PRAGMA table_info`
+#[derive(PartialEq, Eq)]
+struct ColumnInfo {
+    name: String,
+    decl_type: String,
+    not_null: bool,
+    default: Option<String>,
+    pk: i64,
+}
+
+impl std::fmt::Display for ColumnInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.decl_type)?;
+        if self.not_null {
+            write!(f, " NOT NULL")?;
+        }
+        if let Some(default) = &self.default {
+            write!(f, " DEFAULT {}", default)?;
+        }
+        if self.pk > 0 {
+            write!(f, " (primary key)")?;
+        }
+        Ok(())
+    }
+}
+
+fn object_names(conn: &Connection, kind: &str) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT name FROM sqlite_master
+         WHERE type = ? AND name NOT LIKE 'sqlite_%'
+         ORDER BY name",
+    )?;
+    let rows = stmt.query_map([kind], |row| row.get(0))?;
+    rows.collect()
+}
+
+fn table_columns(conn: &Connection, table: &str) -> rusqlite::Result<Vec<ColumnInfo>> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let rows = stmt.query_map([], |row| {
+        Ok(ColumnInfo {
+            name: row.get(1)?,
+            decl_type: row.get(2)?,
+            not_null: row.get(3)?,
+            default: row.get(4)?,
+            pk: row.get(5)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Strip the indentation the statements have inside `create_tables`
+fn dedent(sql: &str) -> String {
+    let indent = sql
+        .lines()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    sql.lines()
+        .enumerate()
+        .map(|(i, line)| {
+            if i == 0 {
+                line
+            } else {
+                line.get(indent..).unwrap_or(line.trim_start())
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 /// Get the last checkpoint timestamp (for append mode)
 pub fn get_last_checkpoint_timestamp(conn: &Connection) -> rusqlite::Result<Option<i64>> {
     conn.query_row(
@@ -108,7 +446,6 @@ pub fn set_meta(conn: &Connection, key: &str, value: &str) -> rusqlite::Result<(
 }
 
 /// Get a metadata key
-#[allow(dead_code)]
 pub fn get_meta(conn: &Connection, key: &str) -> rusqlite::Result<Option<String>> {
     conn.query_row("SELECT value FROM meta WHERE key = ?", [key], |row| {
         row.get(0)
@@ -129,3 +466,44 @@ impl<T> OptionalExt<T> for rusqlite::Result<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn printed_ddl_matches_a_fresh_profile() {
+        let ddl = expected_ddl().unwrap();
+        assert!(ddl.iter().any(|sql| sql.starts_with("CREATE TABLE meta (")));
+        assert!(ddl.iter().all(|sql| !sql.contains("\n        ")));
+
+        let fresh = Connection::open_in_memory().unwrap();
+        create_tables(&fresh).unwrap();
+        assert_eq!(dump_ddl(&fresh).unwrap(), ddl);
+
+        // Running the printed statements rebuilds the same schema
+        let rebuilt = Connection::open_in_memory().unwrap();
+        for sql in &ddl {
+            rebuilt.execute_batch(sql).unwrap();
+        }
+        assert_eq!(dump_ddl(&rebuilt).unwrap(), ddl);
+        assert_eq!(schema_differences(&rebuilt).unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn differences_name_missing_and_unexpected_parts() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+        conn.execute_batch(
+            "DROP TABLE heap_transfers;
+             ALTER TABLE checkpoints DROP COLUMN label;
+             CREATE TABLE scratch (x INTEGER);",
+        )
+        .unwrap();
+
+        let diffs = schema_differences(&conn).unwrap();
+        assert!(diffs.contains(&"missing table heap_transfers".to_string()));
+        assert!(diffs.contains(&"unexpected table scratch".to_string()));
+        assert!(diffs.contains(&"checkpoints: missing column label".to_string()));
+    }
+}
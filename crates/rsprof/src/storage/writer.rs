@@ -1,15 +1,30 @@
 use super::schema::{self, SCHEMA_VERSION};
 use crate::error::Result;
+use crate::heap::SizeDistribution;
 use crate::process::ProcessInfo;
 use crate::symbols::Location;
-use rusqlite::Connection;
-use std::collections::HashMap;
+use rusqlite::{Connection, OptionalExtension};
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::time::Instant;
 
 /// Key for aggregating samples: (file, line, function)
 type LocationKey = (String, u32, String);
 
+/// Meta key: checkpoint with the highest total live heap bytes
+const META_HEAP_PEAK_CHECKPOINT: &str = "heap_peak_checkpoint";
+
+/// Meta key: total live heap bytes at that checkpoint
+const META_HEAP_PEAK_BYTES: &str = "heap_peak_bytes";
+
+/// Meta key: bytes freed without a recorded allocation, clamped out of live bytes
+const META_HEAP_ACCOUNTING_ERROR: &str = "heap_accounting_error_bytes";
+
+/// Host parameters bound per statement by batched inserts
+///
+/// SQLite's limit since 3.32 (the bundled build is newer).
+const MAX_BIND_PARAMS: usize = 32766;
+
 /// Pending heap sample data: (alloc_bytes, free_bytes, live_bytes, alloc_count, free_count)
 type HeapSampleData = (i64, i64, i64, u64, u64);
 
@@ -22,15 +37,74 @@ pub struct Storage {
     checkpoint_id: i64,
     /// Pending CPU samples: location_id -> count
     pending_cpu: HashMap<i64, u64>,
+    /// Pending CPU samples taken inside the allocator: location_id -> count
+    pending_allocator_cpu: HashMap<i64, u64>,
+    /// Pending hardware counter totals: counter name -> location_id -> value
+    pending_counters: HashMap<String, HashMap<i64, u64>>,
+    /// Pending CPU samples per process (--follow-forks): (pid, location_id) -> count
+    pending_process_cpu: HashMap<(u32, i64), u64>,
+    /// Threads seen taking CPU samples since the last checkpoint
+    pending_threads: HashSet<u32>,
     /// Pending heap samples: location_id -> (alloc_bytes, free_bytes, live_bytes)
     pending_heap: HashMap<i64, HeapSampleData>,
+    /// Pending heap samples per call stack: stack_hash -> heap stats
+    pending_heap_stacks: HashMap<i64, HeapSampleData>,
+    /// Pending CPU samples per call stack: stack_hash -> count
+    pending_cpu_stacks: HashMap<i64, u64>,
+    /// Pending off-CPU time (--off-cpu): location_id -> (switches, blocked ns)
+    pending_off_cpu: HashMap<i64, (u64, u64)>,
+    /// Call stacks already recorded (stack_hash)
+    stack_hashes: HashSet<i64>,
+    /// Newly seen call stacks to insert at the next checkpoint: (stack_hash, location_ids)
+    pending_frames: Vec<(i64, Vec<i64>)>,
+    /// Pending allocation size distributions: location_id -> sizes
+    pending_sizes: HashMap<i64, SizeDistribution>,
+    /// Pending alloc -> free site pairs: (alloc, free) location_id -> (count, bytes)
+    pending_transfers: HashMap<(i64, i64), (u64, u64)>,
+    /// Pending marker samples: marker name -> heap stats
+    pending_markers: HashMap<String, HeapSampleData>,
+    /// Label for the next checkpoint (rsprof_trace::label)
+    pending_label: Option<String>,
     /// Cache: (file, line, function) -> location_id
     location_cache: HashMap<LocationKey, i64>,
+    /// Sampled addresses already recorded: (location_id, addr)
+    location_addrs: HashSet<(i64, u64)>,
+    /// Newly seen addresses to insert at the next checkpoint
+    pending_addrs: Vec<(i64, u64)>,
+    /// Largest live bytes deficit clamped to zero per location (frees with no
+    /// seen alloc); cumulative counters, so a deficit is counted once
+    heap_deficits: HashMap<i64, i64>,
+    /// Total live bytes clamped to zero so far, across locations
+    heap_accounting_error: i64,
+    /// Highest total live heap bytes at any checkpoint so far
+    heap_peak_bytes: i64,
 }
 
 impl Storage {
     /// Create a new storage file
     pub fn new(path: &Path, proc_info: &ProcessInfo, cpu_freq: u64) -> Result<Self> {
+        let storage = Self::create(path)?;
+
+        // Set metadata
+        schema::set_meta(&storage.conn, "pid", &proc_info.pid().to_string())?;
+        schema::set_meta(&storage.conn, "process_name", proc_info.name())?;
+        schema::set_meta(
+            &storage.conn,
+            "exe_path",
+            &proc_info.exe_path().display().to_string(),
+        )?;
+        schema::set_meta(
+            &storage.conn,
+            "start_time",
+            &chrono::Utc::now().to_rfc3339(),
+        )?;
+        schema::set_meta(&storage.conn, "cpu_freq_hz", &cpu_freq.to_string())?;
+
+        Ok(storage)
+    }
+
+    /// Create a new storage file without process metadata (e.g. for `rsprof ingest`)
+    pub fn create(path: &Path) -> Result<Self> {
         let conn = Connection::open(path)?;
 
         // Enable WAL mode for concurrent reads during writes
@@ -45,17 +119,7 @@ impl Storage {
         // Create tables (drops existing tables first)
         schema::create_tables(&conn)?;
 
-        // Set metadata
         schema::set_meta(&conn, "version", &SCHEMA_VERSION.to_string())?;
-        schema::set_meta(&conn, "pid", &proc_info.pid().to_string())?;
-        schema::set_meta(&conn, "process_name", proc_info.name())?;
-        schema::set_meta(
-            &conn,
-            "exe_path",
-            &proc_info.exe_path().display().to_string(),
-        )?;
-        schema::set_meta(&conn, "start_time", &chrono::Utc::now().to_rfc3339())?;
-        schema::set_meta(&conn, "cpu_freq_hz", &cpu_freq.to_string())?;
 
         Ok(Storage {
             conn,
@@ -63,8 +127,26 @@ impl Storage {
             time_offset_ms: 0,
             checkpoint_id: 0,
             pending_cpu: HashMap::new(),
+            pending_allocator_cpu: HashMap::new(),
+            pending_counters: HashMap::new(),
+            pending_process_cpu: HashMap::new(),
+            pending_threads: HashSet::new(),
             pending_heap: HashMap::new(),
+            pending_heap_stacks: HashMap::new(),
+            pending_cpu_stacks: HashMap::new(),
+            pending_off_cpu: HashMap::new(),
+            stack_hashes: HashSet::new(),
+            pending_frames: Vec::new(),
+            pending_sizes: HashMap::new(),
+            pending_transfers: HashMap::new(),
+            pending_markers: HashMap::new(),
+            pending_label: None,
             location_cache: HashMap::new(),
+            location_addrs: HashSet::new(),
+            pending_addrs: Vec::new(),
+            heap_deficits: HashMap::new(),
+            heap_accounting_error: 0,
+            heap_peak_bytes: 0,
         })
     }
 
@@ -79,10 +161,23 @@ impl Storage {
              PRAGMA synchronous = NORMAL;",
         )?;
 
+        // Add any tables introduced since the profile was created
+        schema::upgrade_tables(&conn)?;
+
         // Load existing location cache
         let location_cache = schema::load_location_cache(&conn)?;
         eprintln!("Loaded {} existing locations", location_cache.len());
 
+        // Keep the earlier recording's heap peak unless this one exceeds it
+        let heap_peak_bytes = schema::get_meta(&conn, META_HEAP_PEAK_BYTES)?
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+
+        // Add to the earlier recording's accounting error
+        let heap_accounting_error = schema::get_meta(&conn, META_HEAP_ACCOUNTING_ERROR)?
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+
         // Get last checkpoint timestamp to calculate offset
         let last_timestamp_ms = schema::get_last_checkpoint_timestamp(&conn)?.unwrap_or(0);
         eprintln!("Continuing from timestamp {}ms", last_timestamp_ms);
@@ -93,11 +188,35 @@ impl Storage {
             time_offset_ms: last_timestamp_ms,
             checkpoint_id: 0,
             pending_cpu: HashMap::new(),
+            pending_allocator_cpu: HashMap::new(),
+            pending_counters: HashMap::new(),
+            pending_process_cpu: HashMap::new(),
+            pending_threads: HashSet::new(),
             pending_heap: HashMap::new(),
+            pending_heap_stacks: HashMap::new(),
+            pending_cpu_stacks: HashMap::new(),
+            pending_off_cpu: HashMap::new(),
+            stack_hashes: HashSet::new(),
+            pending_frames: Vec::new(),
+            pending_sizes: HashMap::new(),
+            pending_transfers: HashMap::new(),
+            pending_markers: HashMap::new(),
+            pending_label: None,
             location_cache,
+            location_addrs: HashSet::new(),
+            pending_addrs: Vec::new(),
+            heap_deficits: HashMap::new(),
+            heap_accounting_error,
+            heap_peak_bytes,
         })
     }
 
+    /// Set a metadata key on the profile
+    pub fn set_meta(&self, key: &str, value: &str) -> Result<()> {
+        schema::set_meta(&self.conn, key, value)?;
+        Ok(())
+    }
+
     /// Get or create location_id for a (file, line, function)
     fn get_location_id(&mut self, location: &Location) -> i64 {
         let key = (
@@ -132,19 +251,86 @@ impl Storage {
     }
 
     /// Record a CPU sample (aggregates by location_id)
-    pub fn record_cpu_sample(&mut self, _addr: u64, location: &Location) -> i64 {
-        let location_id = self.get_location_id(location);
-        *self.pending_cpu.entry(location_id).or_insert(0) += 1;
-        location_id
+    pub fn record_cpu_sample(&mut self, addr: u64, location: &Location) -> i64 {
+        self.record_cpu_sample_count(addr, location, 1)
     }
 
     /// Record CPU samples with a count (for aggregated stats from rsprof-trace)
-    pub fn record_cpu_sample_count(&mut self, _addr: u64, location: &Location, count: u64) -> i64 {
+    ///
+    /// Samples are keyed by location only; `addr` is kept in the location's
+    /// deduplicated address set.
+    pub fn record_cpu_sample_count(&mut self, addr: u64, location: &Location, count: u64) -> i64 {
         let location_id = self.get_location_id(location);
         *self.pending_cpu.entry(location_id).or_insert(0) += count;
+        self.record_address(location_id, addr);
         location_id
     }
 
+    /// Mark already-recorded CPU samples as spent inside the allocator
+    ///
+    /// `count` is a subset of the samples recorded for `location` this
+    /// checkpoint: time the location paid for its allocations.
+    pub fn record_allocator_samples(&mut self, location: &Location, count: u64) {
+        let location_id = self.get_location_id(location);
+        *self.pending_allocator_cpu.entry(location_id).or_insert(0) += count;
+    }
+
+    /// Attribute already-recorded CPU samples to the process they were taken in
+    ///
+    /// `count` is a subset of the samples recorded for `location` this
+    /// checkpoint (`--follow-forks`).
+    pub fn record_process_samples(&mut self, process_id: u32, location: &Location, count: u64) {
+        let location_id = self.get_location_id(location);
+        *self
+            .pending_process_cpu
+            .entry((process_id, location_id))
+            .or_insert(0) += count;
+    }
+
+    /// Note a thread that took CPU samples since the last checkpoint
+    pub fn record_cpu_thread(&mut self, tid: u32) {
+        self.pending_threads.insert(tid);
+    }
+
+    /// Record hardware counter deltas read with a CPU sample (`--counters`)
+    pub fn record_cpu_counters(&mut self, location: &Location, counters: &[(&str, u64)]) {
+        let location_id = self.get_location_id(location);
+        for &(counter, value) in counters {
+            let by_location = match self.pending_counters.get_mut(counter) {
+                Some(by_location) => by_location,
+                None => self
+                    .pending_counters
+                    .entry(counter.to_string())
+                    .or_default(),
+            };
+            *by_location.entry(location_id).or_insert(0) += value;
+        }
+    }
+
+    /// Record time a thread spent off-CPU at a location (`--off-cpu`)
+    ///
+    /// `count` is how many times it was switched out there; a long block
+    /// can be recorded over several checkpoints with a count of 0 after
+    /// the first.
+    pub fn record_off_cpu(&mut self, location: &Location, count: u64, blocked_ns: u64) {
+        let location_id = self.get_location_id(location);
+        let entry = self.pending_off_cpu.entry(location_id).or_insert((0, 0));
+        entry.0 += count;
+        entry.1 += blocked_ns;
+    }
+
+    /// Add a sampled address to a location's address set
+    pub fn record_location_address(&mut self, location: &Location, addr: u64) {
+        let location_id = self.get_location_id(location);
+        self.record_address(location_id, addr);
+    }
+
+    fn record_address(&mut self, location_id: i64, addr: u64) {
+        if addr != 0 && self.location_addrs.insert((location_id, addr)) {
+            self.pending_addrs.push((location_id, addr));
+        }
+    }
+
     /// Record a heap sample (aggregates by location_id)
     /// Called once per checkpoint with cumulative stats from sampler.
     /// Multiple stack keys that resolve to the same location are summed.
@@ -171,31 +357,212 @@ impl Storage {
         location_id
     }
 
+    /// Record heap stats for a site's full call stack (cumulative, once per checkpoint)
+    ///
+    /// `frames` starts at the location passed to `record_heap_sample`,
+    /// followed by its callers. Stack keys with the same frames are summed.
+    pub fn record_heap_stack(
+        &mut self,
+        frames: &[Location],
+        alloc_bytes: i64,
+        free_bytes: i64,
+        live_bytes: i64,
+        alloc_count: u64,
+        free_count: u64,
+    ) {
+        if frames.is_empty() {
+            return;
+        }
+        let hash = self.get_stack_hash(frames);
+        let entry = self
+            .pending_heap_stacks
+            .entry(hash)
+            .or_insert((0, 0, 0, 0, 0));
+        entry.0 += alloc_bytes;
+        entry.1 += free_bytes;
+        entry.2 += live_bytes;
+        entry.3 += alloc_count;
+        entry.4 += free_count;
+    }
+
+    /// Record CPU samples for a full call stack
+    ///
+    /// `frames` starts at the location passed to `record_cpu_sample_count`,
+    /// followed by its callers.
+    pub fn record_cpu_stack(&mut self, frames: &[Location], count: u64) {
+        if frames.is_empty() {
+            return;
+        }
+        let hash = self.get_stack_hash(frames);
+        *self.pending_cpu_stacks.entry(hash).or_insert(0) += count;
+    }
+
+    /// Hash of a call stack, queuing its frames the first time it is seen
+    fn get_stack_hash(&mut self, frames: &[Location]) -> i64 {
+        let location_ids: Vec<i64> = frames
+            .iter()
+            .map(|frame| self.get_location_id(frame))
+            .collect();
+        let hash = stack_hash(&location_ids);
+        if self.stack_hashes.insert(hash) {
+            self.pending_frames.push((hash, location_ids));
+        }
+        hash
+    }
+
+    /// Record a site's allocation size distribution (cumulative, once per checkpoint)
+    /// Multiple stack keys that resolve to the same location are merged.
+    pub fn record_heap_sizes(&mut self, location: &Location, sizes: &SizeDistribution) {
+        if sizes.count() == 0 {
+            return;
+        }
+        let location_id = self.get_location_id(location);
+        self.pending_sizes
+            .entry(location_id)
+            .or_default()
+            .merge(sizes);
+    }
+
+    /// Record allocations made at `alloc` and freed at `free` (cumulative, once per checkpoint)
+    /// Multiple stack pairs that resolve to the same locations are merged.
+    pub fn record_heap_transfer(
+        &mut self,
+        alloc: &Location,
+        free: &Location,
+        count: u64,
+        bytes: u64,
+    ) {
+        let alloc_id = self.get_location_id(alloc);
+        let free_id = self.get_location_id(free);
+        let entry = self
+            .pending_transfers
+            .entry((alloc_id, free_id))
+            .or_insert((0, 0));
+        entry.0 += count;
+        entry.1 += bytes;
+    }
+
+    /// Record heap stats attributed to a marker (cumulative, once per checkpoint)
+    pub fn record_marker_sample(
+        &mut self,
+        marker: &str,
+        alloc_bytes: i64,
+        free_bytes: i64,
+        live_bytes: i64,
+        alloc_count: u64,
+        free_count: u64,
+    ) {
+        self.pending_markers.insert(
+            marker.to_string(),
+            (alloc_bytes, free_bytes, live_bytes, alloc_count, free_count),
+        );
+    }
+
+    /// Label the next checkpoint; labels set within one interval are joined
+    pub fn set_checkpoint_label(&mut self, label: &str) {
+        match &mut self.pending_label {
+            Some(pending) => {
+                pending.push_str(" / ");
+                pending.push_str(label);
+            }
+            None => self.pending_label = Some(label.to_string()),
+        }
+    }
+
     /// Flush pending data to a new checkpoint
     pub fn flush_checkpoint(&mut self) -> Result<()> {
-        if self.pending_cpu.is_empty() && self.pending_heap.is_empty() {
+        // Add time offset for append mode
+        let timestamp_ms = self.start_time.elapsed().as_millis() as i64 + self.time_offset_ms;
+        self.flush_checkpoint_at(timestamp_ms)
+    }
+
+    /// Flush pending data to a new checkpoint with an explicit timestamp
+    pub fn flush_checkpoint_at(&mut self, timestamp_ms: i64) -> Result<()> {
+        if self.pending_cpu.is_empty()
+            && self.pending_heap.is_empty()
+            && self.pending_markers.is_empty()
+            && self.pending_off_cpu.is_empty()
+            && self.pending_label.is_none()
+        {
             return Ok(());
         }
 
+        let cpu_rows = self.pending_cpu.len();
+        let heap_rows = self.pending_heap.len();
+        let marker_rows = self.pending_markers.len();
+        let counter_rows: usize = self.pending_counters.values().map(HashMap::len).sum();
+        let sample_count: u64 = self.pending_cpu.values().sum();
+        let thread_count = self.pending_threads.len();
+        self.pending_threads.clear();
         let tx = self.conn.transaction()?;
 
-        // Create checkpoint (add time offset for append mode)
-        let timestamp_ms = self.start_time.elapsed().as_millis() as i64 + self.time_offset_ms;
+        // Create checkpoint
         tx.execute(
-            "INSERT INTO checkpoints (timestamp_ms) VALUES (?)",
-            [timestamp_ms],
+            "INSERT INTO checkpoints (timestamp_ms, label, sample_count, thread_count) VALUES (?, ?, ?, ?)",
+            rusqlite::params![
+                timestamp_ms,
+                self.pending_label.take(),
+                sample_count as i64,
+                thread_count as i64
+            ],
         )?;
         self.checkpoint_id = tx.last_insert_rowid();
 
-        // Insert CPU samples (just checkpoint_id, location_id, count)
+        // Insert CPU samples (checkpoint_id, location_id, count, allocator share)
+        {
+            let mut rows: Vec<[i64; 4]> = self
+                .pending_cpu
+                .drain()
+                .map(|(location_id, count)| {
+                    let allocator_count = self
+                        .pending_allocator_cpu
+                        .remove(&location_id)
+                        .unwrap_or(0)
+                        .min(count);
+                    [
+                        self.checkpoint_id,
+                        location_id,
+                        count as i64,
+                        allocator_count as i64,
+                    ]
+                })
+                .collect();
+            insert_batched(
+                &tx,
+                "INSERT INTO cpu_samples (checkpoint_id, location_id, count, allocator_count)",
+                &mut rows,
+            )?;
+            self.pending_allocator_cpu.clear();
+        }
+
+        // Insert hardware counter totals
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO cpu_counters (checkpoint_id, location_id, counter, value) VALUES (?, ?, ?, ?)",
+            )?;
+
+            for (counter, by_location) in self.pending_counters.drain() {
+                for (location_id, value) in by_location {
+                    stmt.execute(rusqlite::params![
+                        self.checkpoint_id,
+                        location_id,
+                        counter,
+                        value as i64
+                    ])?;
+                }
+            }
+        }
+
+        // Insert per-process CPU samples
         {
             let mut stmt = tx.prepare_cached(
-                "INSERT INTO cpu_samples (checkpoint_id, location_id, count) VALUES (?, ?, ?)",
+                "INSERT INTO cpu_process_samples (checkpoint_id, process_id, location_id, count) VALUES (?, ?, ?, ?)",
             )?;
 
-            for (location_id, count) in self.pending_cpu.drain() {
+            for ((process_id, location_id), count) in self.pending_process_cpu.drain() {
                 stmt.execute(rusqlite::params![
                     self.checkpoint_id,
+                    process_id,
                     location_id,
                     count as i64
                 ])?;
@@ -203,16 +570,168 @@ impl Storage {
         }
 
         // Insert heap samples
+        {
+            // A site can see frees for memory allocated before tracking started,
+            // driving live bytes negative: clamp and report the total instead
+            let had_heap = !self.pending_heap.is_empty();
+            let mut accounting_error = self.heap_accounting_error;
+            let mut live_total = 0;
+            let mut rows: Vec<[i64; 7]> = Vec::with_capacity(self.pending_heap.len());
+            for (location_id, (alloc, free, live, alloc_cnt, free_cnt)) in self.pending_heap.drain()
+            {
+                if live < 0 {
+                    // Only the part of the deficit not already counted
+                    let counted = self.heap_deficits.entry(location_id).or_default();
+                    accounting_error += (-live - *counted).max(0);
+                    *counted = (*counted).max(-live);
+                }
+                live_total += live.max(0);
+                rows.push([
+                    self.checkpoint_id,
+                    location_id,
+                    alloc,
+                    free,
+                    live.max(0),
+                    alloc_cnt as i64,
+                    free_cnt as i64,
+                ]);
+            }
+            insert_batched(
+                &tx,
+                "INSERT INTO heap_samples (checkpoint_id, location_id, alloc_bytes, free_bytes, live_bytes, alloc_count, free_count)",
+                &mut rows,
+            )?;
+
+            if accounting_error != self.heap_accounting_error {
+                schema::set_meta(
+                    &tx,
+                    META_HEAP_ACCOUNTING_ERROR,
+                    &accounting_error.to_string(),
+                )?;
+                self.heap_accounting_error = accounting_error;
+            }
+
+            // Remember when the whole heap peaked, for `rsprof peak`
+            if had_heap && live_total > self.heap_peak_bytes {
+                schema::set_meta(
+                    &tx,
+                    META_HEAP_PEAK_CHECKPOINT,
+                    &self.checkpoint_id.to_string(),
+                )?;
+                schema::set_meta(&tx, META_HEAP_PEAK_BYTES, &live_total.to_string())?;
+                self.heap_peak_bytes = live_total;
+            }
+        }
+
+        // Insert newly seen call stacks (already present when appending)
         {
             let mut stmt = tx.prepare_cached(
-                "INSERT INTO heap_samples (checkpoint_id, location_id, alloc_bytes, free_bytes, live_bytes, alloc_count, free_count) VALUES (?, ?, ?, ?, ?, ?, ?)",
+                "INSERT OR IGNORE INTO frames (stack_hash, depth, location_id) VALUES (?, ?, ?)",
             )?;
 
-            for (location_id, (alloc, free, live, alloc_cnt, free_cnt)) in self.pending_heap.drain()
-            {
+            for (hash, location_ids) in self.pending_frames.drain(..) {
+                for (depth, location_id) in location_ids.into_iter().enumerate() {
+                    stmt.execute(rusqlite::params![hash, depth as i64, location_id])?;
+                }
+            }
+        }
+
+        // Insert heap samples per call stack (live bytes clamped like heap_samples)
+        {
+            let mut rows: Vec<[i64; 7]> = self
+                .pending_heap_stacks
+                .drain()
+                .map(|(hash, (alloc, free, live, alloc_cnt, free_cnt))| {
+                    [
+                        self.checkpoint_id,
+                        hash,
+                        alloc,
+                        free,
+                        live.max(0),
+                        alloc_cnt as i64,
+                        free_cnt as i64,
+                    ]
+                })
+                .collect();
+            insert_batched(
+                &tx,
+                "INSERT INTO heap_stack_samples (checkpoint_id, stack_hash, alloc_bytes, free_bytes, live_bytes, alloc_count, free_count)",
+                &mut rows,
+            )?;
+        }
+
+        // Insert CPU samples per call stack
+        {
+            let mut rows: Vec<[i64; 3]> = self
+                .pending_cpu_stacks
+                .drain()
+                .map(|(hash, count)| [self.checkpoint_id, hash, count as i64])
+                .collect();
+            insert_batched(
+                &tx,
+                "INSERT INTO cpu_stack_samples (checkpoint_id, stack_hash, count)",
+                &mut rows,
+            )?;
+        }
+
+        // Insert off-CPU time
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO off_cpu_samples (checkpoint_id, location_id, count, blocked_ns) VALUES (?, ?, ?, ?)",
+            )?;
+
+            for (location_id, (count, blocked_ns)) in self.pending_off_cpu.drain() {
                 stmt.execute(rusqlite::params![
                     self.checkpoint_id,
                     location_id,
+                    count as i64,
+                    blocked_ns as i64
+                ])?;
+            }
+        }
+
+        // Replace size distributions (cumulative, so only the latest is kept)
+        for (location_id, sizes) in self.pending_sizes.drain() {
+            write_heap_sizes(&tx, location_id, &sizes)?;
+        }
+
+        // Replace alloc -> free pairs (cumulative, so only the latest is kept)
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT OR REPLACE INTO heap_transfers (alloc_location_id, free_location_id, count, bytes) VALUES (?, ?, ?, ?)",
+            )?;
+
+            for ((alloc_id, free_id), (count, bytes)) in self.pending_transfers.drain() {
+                stmt.execute(rusqlite::params![
+                    alloc_id,
+                    free_id,
+                    count as i64,
+                    bytes as i64
+                ])?;
+            }
+        }
+
+        // Insert newly seen addresses (already present when appending)
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT OR IGNORE INTO location_addresses (location_id, addr) VALUES (?, ?)",
+            )?;
+
+            for (location_id, addr) in self.pending_addrs.drain(..) {
+                stmt.execute(rusqlite::params![location_id, addr as i64])?;
+            }
+        }
+
+        // Insert marker samples
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO marker_samples (checkpoint_id, marker, alloc_bytes, free_bytes, live_bytes, alloc_count, free_count) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            )?;
+
+            for (marker, (alloc, free, live, alloc_cnt, free_cnt)) in self.pending_markers.drain() {
+                stmt.execute(rusqlite::params![
+                    self.checkpoint_id,
+                    marker,
                     alloc,
                     free,
                     live,
@@ -223,9 +742,28 @@ impl Storage {
         }
 
         tx.commit()?;
+        log::debug!(
+            "checkpoint id={} timestamp_ms={} cpu_rows={} heap_rows={} marker_rows={} counter_rows={}",
+            self.checkpoint_id,
+            timestamp_ms,
+            cpu_rows,
+            heap_rows,
+            marker_rows,
+            counter_rows
+        );
         Ok(())
     }
 
+    /// Labeled checkpoints as (seconds, label)
+    pub fn query_checkpoint_labels(&self) -> Vec<(f64, String)> {
+        query_checkpoint_labels(&self.conn)
+    }
+
+    /// CPU samples and threads per checkpoint
+    pub fn query_checkpoint_stats(&self) -> Vec<CheckpointStats> {
+        query_checkpoint_stats(&self.conn)
+    }
+
     /// Get total samples recorded
     pub fn total_samples(&self) -> Result<u64> {
         let count: i64 = self.conn.query_row(
@@ -236,6 +774,11 @@ impl Storage {
         Ok(count as u64)
     }
 
+    /// CPU sampling frequency this profile is recorded at
+    pub fn cpu_freq_hz(&self) -> Option<f64> {
+        query_cpu_freq_hz(&self.conn)
+    }
+
     /// Get number of checkpoints
     pub fn checkpoint_count(&self) -> Result<u64> {
         let count: i64 = self
@@ -265,6 +808,21 @@ impl Storage {
         query_top_heap_live(&self.conn, limit).unwrap_or_default()
     }
 
+    /// Heap usage per marker (and tag) at its latest checkpoint
+    pub fn query_top_markers(&self, limit: usize) -> Vec<MarkerEntry> {
+        query_top_markers(&self.conn, limit).unwrap_or_default()
+    }
+
+    /// CPU samples per call stack over the whole recording
+    pub fn query_cpu_stacks(&self) -> Vec<CpuStackEntry> {
+        query_cpu_stacks(&self.conn).unwrap_or_default()
+    }
+
+    /// Heap call stacks by live bytes at the last checkpoint
+    pub fn query_heap_stacks(&self, limit: usize) -> Vec<StackEntry> {
+        query_heap_stacks(&self.conn, limit).unwrap_or_default()
+    }
+
     /// Query combined CPU + Heap data for "Both" view
     pub fn query_combined_live(&self, limit: usize) -> Vec<CombinedEntry> {
         query_combined_live(&self.conn, limit).unwrap_or_default()
@@ -277,8 +835,29 @@ impl Storage {
         start_ms: i64,
         end_ms: i64,
         num_buckets: usize,
+        aggregate: BucketAggregate,
     ) -> Vec<(f64, f64)> {
-        query_heap_timeseries_aggregated(&self.conn, location_id, start_ms, end_ms, num_buckets)
+        query_heap_timeseries_aggregated(
+            &self.conn,
+            location_id,
+            start_ms,
+            end_ms,
+            num_buckets,
+            aggregate,
+        )
+    }
+
+    /// Query CPU samples per location within `(start_ms, end_ms]`, as
+    /// percentages of the samples in that range
+    pub fn query_top_cpu_range(&self, start_ms: i64, end_ms: i64) -> Vec<CpuEntry> {
+        let total = query_cpu_sample_count(&self.conn, start_ms, end_ms).unwrap_or(0);
+        query_top_cpu_range(&self.conn, usize::MAX, 0.0, (start_ms, end_ms), total)
+            .unwrap_or_default()
+    }
+
+    /// Allocation size distribution recorded for a location
+    pub fn query_heap_sizes(&self, location_id: i64) -> Option<SizeDistribution> {
+        query_heap_sizes(&self.conn, location_id)
     }
 
     /// Query sparkline data for all heap locations (recent N checkpoints)
@@ -331,70 +910,395 @@ impl Storage {
         start_ms: i64,
         end_ms: i64,
         num_buckets: usize,
+        series: CpuSeries,
+        aggregate: BucketAggregate,
     ) -> Vec<(f64, f64)> {
-        if num_buckets == 0 || start_ms >= end_ms {
-            return Vec::new();
-        }
-
-        let bucket_ms = (end_ms - start_ms) / num_buckets as i64;
-        if bucket_ms == 0 {
-            return Vec::new();
-        }
-
-        let query_result: rusqlite::Result<Vec<(f64, f64)>> = (|| {
-            // Aggregate by time bucket, taking MAX cpu% in each bucket
-            let mut stmt = self.conn.prepare(
-                r#"
-                WITH bucket_data AS (
-                    SELECT
-                        ((c.timestamp_ms - ?2) / ?4) as bucket_idx,
-                        CAST(cs.count AS REAL) * 100.0 / (
-                            SELECT SUM(count) FROM cpu_samples WHERE checkpoint_id = c.id
-                        ) as pct
-                    FROM checkpoints c
-                    JOIN cpu_samples cs ON cs.checkpoint_id = c.id AND cs.location_id = ?1
-                    WHERE c.timestamp_ms >= ?2 AND c.timestamp_ms < ?3
-                )
-                SELECT bucket_idx, MAX(pct) as max_pct
-                FROM bucket_data
-                GROUP BY bucket_idx
-                ORDER BY bucket_idx ASC
-                "#,
-            )?;
+        query_cpu_timeseries_aggregated(
+            &self.conn,
+            location_id,
+            start_ms,
+            end_ms,
+            num_buckets,
+            series,
+            aggregate,
+        )
+    }
+}
 
-            let rows = stmt.query_map(
-                rusqlite::params![location_id, start_ms, end_ms, bucket_ms],
-                |row| {
-                    let bucket_idx: i64 = row.get(0)?;
-                    let pct: f64 = row.get::<_, Option<f64>>(1)?.unwrap_or(0.0);
-                    // Convert bucket index back to time (center of bucket)
-                    let time_ms = start_ms + bucket_idx * bucket_ms + bucket_ms / 2;
-                    Ok((time_ms as f64 / 1000.0, pct))
-                },
-            )?;
+/// Rank locations by a combined CPU + heap cost score
+///
+/// Both inputs are normalized to a share of the whole profile (CPU samples and
+/// allocated bytes), so with equal weights 1% of CPU costs the same as 1% of
+/// allocations.
+pub fn query_top_cost(
+    conn: &Connection,
+    limit: usize,
+    cpu_weight: f64,
+    heap_weight: f64,
+) -> rusqlite::Result<Vec<CostEntry>> {
+    let combined = query_combined_live(conn, i64::MAX as usize)?;
+    let heap_grand_total: i64 = combined.iter().map(|e| e.heap_alloc_bytes.max(0)).sum();
+
+    let mut entries: Vec<CostEntry> = combined
+        .into_iter()
+        .map(|e| {
+            let alloc_bytes = e.heap_alloc_bytes.max(0);
+            let heap_pct = if heap_grand_total > 0 {
+                (alloc_bytes as f64 / heap_grand_total as f64) * 100.0
+            } else {
+                0.0
+            };
+            CostEntry {
+                location_id: e.location_id,
+                file: e.file,
+                line: e.line,
+                function: e.function,
+                cpu_pct: e.cpu_total_pct,
+                heap_pct,
+                alloc_bytes,
+                score: cpu_weight * e.cpu_total_pct + heap_weight * heap_pct,
+            }
+        })
+        .collect();
 
-            Ok(rows.filter_map(|r| r.ok()).collect())
-        })();
+    entries.sort_by(|a, b| b.score.total_cmp(&a.score));
+    entries.truncate(limit);
+    Ok(entries)
+}
 
-        query_result.unwrap_or_default()
+/// Query heap sites by CPU spent inside the allocator on their behalf
+///
+/// Only stack-sampled recordings (rsprof-trace) can tell allocator time
+/// apart; heap counters are cumulative, so the last checkpoint's are totals.
+pub fn query_top_allocator_cpu(
+    conn: &Connection,
+    limit: usize,
+) -> rusqlite::Result<Vec<AllocatorCpuEntry>> {
+    let grand_total: f64 = conn.query_row(
+        "SELECT COALESCE(SUM(count), 0.0) FROM cpu_samples",
+        [],
+        |row| row.get(0),
+    )?;
+    if grand_total == 0.0 {
+        return Ok(vec![]);
     }
-}
 
-/// Query results for top CPU consumers
-#[derive(Debug, Clone)]
-pub struct CpuEntry {
-    pub location_id: i64,
-    pub file: String,
-    pub line: u32,
-    pub function: String,
-    pub total_samples: u64,
-    pub total_percent: f64,
-    pub instant_percent: f64,
+    let mut stmt = conn.prepare(
+        r#"
+        WITH cpu AS (
+            SELECT location_id,
+                   SUM(allocator_count) AS allocator_samples,
+                   SUM(count) AS cpu_samples
+            FROM cpu_samples
+            GROUP BY location_id
+            HAVING SUM(allocator_count) > 0
+        ),
+        heap AS (
+            SELECT location_id,
+                   MAX(alloc_bytes) AS alloc_bytes,
+                   MAX(alloc_count) AS alloc_count
+            FROM heap_samples
+            GROUP BY location_id
+        )
+        SELECT l.id, l.file, l.line, l.function,
+               cpu.allocator_samples, cpu.cpu_samples,
+               COALESCE(heap.alloc_bytes, 0), COALESCE(heap.alloc_count, 0)
+        FROM cpu
+        JOIN locations l ON l.id = cpu.location_id
+        LEFT JOIN heap ON heap.location_id = cpu.location_id
+        ORDER BY cpu.allocator_samples DESC
+        LIMIT ?
+        "#,
+    )?;
+
+    let rows = stmt.query_map([limit as i64], |row| {
+        let allocator_samples = row.get::<_, i64>(4)? as u64;
+        Ok(AllocatorCpuEntry {
+            location_id: row.get(0)?,
+            file: row.get(1)?,
+            line: row.get(2)?,
+            function: row.get(3)?,
+            allocator_samples,
+            cpu_samples: row.get::<_, i64>(5)? as u64,
+            allocator_pct: allocator_samples as f64 * 100.0 / grand_total,
+            alloc_bytes: row.get(6)?,
+            alloc_count: row.get::<_, i64>(7)? as u64,
+        })
+    })?;
+    rows.collect()
 }
 
-/// Query results for top heap consumers
-#[derive(Debug, Clone)]
-pub struct HeapEntry {
+/// Query where memory allocated at one location is freed at another
+///
+/// Pairs within one function (memory dropped where it was allocated) are
+/// left out: only transfers of ownership remain, largest first.
+pub fn query_heap_transfers(
+    conn: &Connection,
+    limit: usize,
+) -> rusqlite::Result<Vec<TransferEntry>> {
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT a.file, a.line, a.function, f.file, f.line, f.function, t.count, t.bytes
+        FROM heap_transfers t
+        JOIN locations a ON a.id = t.alloc_location_id
+        JOIN locations f ON f.id = t.free_location_id
+        WHERE a.function != f.function
+        ORDER BY t.bytes DESC
+        LIMIT ?
+        "#,
+    )?;
+
+    let rows = stmt.query_map([limit as i64], |row| {
+        Ok(TransferEntry {
+            alloc_file: row.get(0)?,
+            alloc_line: row.get(1)?,
+            alloc_function: row.get(2)?,
+            free_file: row.get(3)?,
+            free_line: row.get(4)?,
+            free_function: row.get(5)?,
+            count: row.get::<_, i64>(6)? as u64,
+            bytes: row.get(7)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Query CPU samples per process, with each process's hottest location
+///
+/// Only profiles recorded with `--follow-forks` have per-process samples.
+pub fn query_top_processes(conn: &Connection, limit: usize) -> rusqlite::Result<Vec<ProcessEntry>> {
+    let grand_total: f64 = conn.query_row(
+        "SELECT COALESCE(SUM(count), 0.0) FROM cpu_samples",
+        [],
+        |row| row.get(0),
+    )?;
+
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT process_id, SUM(count) AS total
+        FROM cpu_process_samples
+        GROUP BY process_id
+        ORDER BY total DESC
+        LIMIT ?
+        "#,
+    )?;
+    let totals: Vec<(u32, i64)> = stmt
+        .query_map([limit as i64], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut hottest = conn.prepare(
+        r#"
+        SELECT l.file, l.line, l.function
+        FROM cpu_process_samples p
+        JOIN locations l ON l.id = p.location_id
+        WHERE p.process_id = ?
+        GROUP BY p.location_id
+        ORDER BY SUM(p.count) DESC
+        LIMIT 1
+        "#,
+    )?;
+    totals
+        .into_iter()
+        .map(|(process_id, samples)| {
+            let (file, line, function) = hottest.query_row([process_id], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?;
+            Ok(ProcessEntry {
+                process_id,
+                samples: samples as u64,
+                percent: if grand_total > 0.0 {
+                    samples as f64 / grand_total * 100.0
+                } else {
+                    0.0
+                },
+                file,
+                line,
+                function,
+            })
+        })
+        .collect()
+}
+
+/// Query the locations threads spent the most time off-CPU at
+///
+/// Only profiles recorded with `--off-cpu` have off-CPU time.
+pub fn query_top_off_cpu(conn: &Connection, limit: usize) -> rusqlite::Result<Vec<OffCpuEntry>> {
+    if !schema::has_table(conn, "off_cpu_samples")? {
+        return Ok(Vec::new());
+    }
+    let grand_total: f64 = conn.query_row(
+        "SELECT COALESCE(SUM(blocked_ns), 0.0) FROM off_cpu_samples",
+        [],
+        |row| row.get(0),
+    )?;
+
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT l.file, l.line, l.function, SUM(o.count), SUM(o.blocked_ns) AS total
+        FROM off_cpu_samples o
+        JOIN locations l ON l.id = o.location_id
+        GROUP BY o.location_id
+        ORDER BY total DESC
+        LIMIT ?
+        "#,
+    )?;
+    let rows = stmt.query_map([limit as i64], |row| {
+        let blocked_ns = row.get::<_, i64>(4)? as u64;
+        Ok(OffCpuEntry {
+            file: row.get(0)?,
+            line: row.get(1)?,
+            function: row.get(2)?,
+            switches: row.get::<_, i64>(3)? as u64,
+            blocked_ns,
+            percent: if grand_total > 0.0 {
+                blocked_ns as f64 / grand_total * 100.0
+            } else {
+                0.0
+            },
+        })
+    })?;
+    rows.collect()
+}
+
+/// Query the distribution of gaps between checkpoints with CPU samples per location
+///
+/// Samples are only stored as counts per checkpoint, with no time within it,
+/// so a gap is the stretch of checkpoints in which a location had no samples:
+/// from the end of one checkpoint that sampled it to the start of the next.
+/// Neighbouring checkpoints give a gap of 0. Long gaps mean the function went
+/// off-CPU (or elsewhere) for a while: a rough stall profile at checkpoint
+/// resolution. Locations below `min_cpu_pct` or sampled in fewer than two
+/// checkpoints are skipped; results are ordered by p90 gap.
+pub fn query_sample_gaps(
+    conn: &Connection,
+    limit: usize,
+    min_cpu_pct: f64,
+) -> rusqlite::Result<Vec<GapEntry>> {
+    let grand_total: f64 = conn.query_row(
+        "SELECT COALESCE(SUM(count), 0.0) FROM cpu_samples",
+        [],
+        |row| row.get(0),
+    )?;
+    if grand_total == 0.0 {
+        return Ok(vec![]);
+    }
+
+    // Interval covered by each checkpoint: (previous timestamp, timestamp]
+    let mut intervals: HashMap<i64, (f64, f64)> = HashMap::new();
+    {
+        let mut stmt =
+            conn.prepare("SELECT id, timestamp_ms FROM checkpoints ORDER BY timestamp_ms")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)))?;
+        let mut prev_ms = 0i64;
+        for row in rows {
+            let (id, ts) = row?;
+            intervals.insert(id, (prev_ms as f64, ts as f64));
+            prev_ms = ts;
+        }
+    }
+
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT cs.location_id, l.file, l.line, l.function, cs.count, cs.checkpoint_id
+        FROM cpu_samples cs
+        JOIN locations l ON l.id = cs.location_id
+        JOIN checkpoints c ON c.id = cs.checkpoint_id
+        ORDER BY cs.location_id, c.timestamp_ms
+        "#,
+    )?;
+    let mut rows = stmt.query([])?;
+
+    let mut entries = Vec::new();
+    // (entry, gaps so far, end of the last checkpoint that sampled it)
+    let mut current: Option<(GapEntry, Vec<f64>, Option<f64>)> = None;
+    while let Some(row) = rows.next()? {
+        let location_id: i64 = row.get(0)?;
+        if current.as_ref().map(|(e, _, _)| e.location_id) != Some(location_id) {
+            if let Some((entry, gaps, _)) = current.take() {
+                entries.extend(finish_gap_entry(entry, gaps, grand_total, min_cpu_pct));
+            }
+            current = Some((
+                GapEntry {
+                    location_id,
+                    file: row.get(1)?,
+                    line: row.get::<_, i64>(2)? as u32,
+                    function: row.get(3)?,
+                    samples: 0,
+                    cpu_pct: 0.0,
+                    p50_ms: 0.0,
+                    p90_ms: 0.0,
+                    p99_ms: 0.0,
+                },
+                Vec::new(),
+                None,
+            ));
+        }
+        let Some((entry, gaps, last_end_ms)) = current.as_mut() else {
+            continue;
+        };
+        let count = row.get::<_, i64>(4)?.max(0) as u64;
+        let Some(&(start_ms, end_ms)) = intervals.get(&row.get::<_, i64>(5)?) else {
+            continue;
+        };
+        if count == 0 {
+            continue;
+        }
+        // Empty checkpoints since the last one that sampled this location
+        if let Some(prev_end_ms) = *last_end_ms {
+            gaps.push(start_ms - prev_end_ms);
+        }
+        *last_end_ms = Some(end_ms);
+        entry.samples += count;
+    }
+    if let Some((entry, gaps, _)) = current.take() {
+        entries.extend(finish_gap_entry(entry, gaps, grand_total, min_cpu_pct));
+    }
+
+    entries.sort_by(|a, b| b.p90_ms.total_cmp(&a.p90_ms));
+    entries.truncate(limit);
+    Ok(entries)
+}
+
+/// Fill in a location's share and gap percentiles
+fn finish_gap_entry(
+    mut entry: GapEntry,
+    mut gaps: Vec<f64>,
+    grand_total: f64,
+    min_cpu_pct: f64,
+) -> Option<GapEntry> {
+    entry.cpu_pct = entry.samples as f64 / grand_total * 100.0;
+    if entry.cpu_pct < min_cpu_pct || gaps.is_empty() {
+        return None;
+    }
+
+    gaps.sort_by(f64::total_cmp);
+    entry.p50_ms = percentile(&gaps, 50.0);
+    entry.p90_ms = percentile(&gaps, 90.0);
+    entry.p99_ms = percentile(&gaps, 99.0);
+    Some(entry)
+}
+
+/// Nearest-rank percentile of sorted, non-empty values
+fn percentile(sorted: &[f64], pct: f64) -> f64 {
+    let rank = (pct / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Query results for top CPU consumers
+#[derive(Debug, Clone)]
+pub struct CpuEntry {
+    pub location_id: i64,
+    pub file: String,
+    pub line: u32,
+    pub function: String,
+    pub total_samples: u64,
+    pub total_percent: f64,
+    pub instant_percent: f64,
+}
+
+/// Query results for top heap consumers
+#[derive(Debug, Clone)]
+pub struct HeapEntry {
     pub location_id: i64,
     pub file: String,
     pub line: u32,
@@ -406,6 +1310,17 @@ pub struct HeapEntry {
     pub free_count: u64,
 }
 
+/// Heap stats for one marker (logical request/operation)
+#[derive(Debug, Clone)]
+pub struct MarkerEntry {
+    pub marker: String,
+    pub live_bytes: i64,
+    pub total_alloc_bytes: i64,
+    pub total_free_bytes: i64,
+    pub alloc_count: u64,
+    pub free_count: u64,
+}
+
 /// Combined CPU + Heap entry for "Both" view
 #[derive(Debug, Clone)]
 pub struct CombinedEntry {
@@ -417,10 +1332,160 @@ pub struct CombinedEntry {
     pub cpu_instant_pct: f64,
     /// Total heap allocations over all time (sum of alloc_bytes)
     pub heap_total: i64,
+    /// Bytes allocated at this location (alloc_bytes is cumulative, so its max)
+    pub heap_alloc_bytes: i64,
+    /// Number of allocations over all time (also cumulative)
+    pub heap_alloc_count: u64,
     /// Current slice heap usage (live_bytes at current checkpoint)
     pub heap_instant: i64,
 }
 
+/// Location ranked by combined CPU + allocation cost
+#[derive(Debug, Clone)]
+pub struct CostEntry {
+    pub location_id: i64,
+    pub file: String,
+    pub line: u32,
+    pub function: String,
+    /// Share of all CPU samples
+    pub cpu_pct: f64,
+    /// Share of all allocated bytes
+    pub heap_pct: f64,
+    /// Total bytes allocated at this location
+    pub alloc_bytes: i64,
+    /// `cpu_weight * cpu_pct + heap_weight * heap_pct`
+    pub score: f64,
+}
+
+/// Heap site ranked by CPU time spent in the allocator on its behalf
+#[derive(Debug, Clone)]
+pub struct AllocatorCpuEntry {
+    pub location_id: i64,
+    pub file: String,
+    pub line: u32,
+    pub function: String,
+    /// CPU samples inside malloc/realloc/free attributed to this site
+    pub allocator_samples: u64,
+    /// All CPU samples attributed to this site
+    pub cpu_samples: u64,
+    /// Share of all CPU samples spent in the allocator for this site
+    pub allocator_pct: f64,
+    /// Total bytes allocated at this location
+    pub alloc_bytes: i64,
+    /// Total allocation calls at this location
+    pub alloc_count: u64,
+}
+
+/// Allocations made at one location and freed at another
+#[derive(Debug, Clone)]
+pub struct TransferEntry {
+    pub alloc_file: String,
+    pub alloc_line: u32,
+    pub alloc_function: String,
+    pub free_file: String,
+    pub free_line: u32,
+    pub free_function: String,
+    /// Allocations freed
+    pub count: u64,
+    /// Bytes freed
+    pub bytes: i64,
+}
+
+/// Time threads spent off-CPU at one location (`--off-cpu`)
+#[derive(Debug, Clone)]
+pub struct OffCpuEntry {
+    pub file: String,
+    pub line: u32,
+    pub function: String,
+    /// Times a thread was switched out here
+    pub switches: u64,
+    /// Total time until those threads ran again
+    pub blocked_ns: u64,
+    /// Share of all off-CPU time in the recording
+    pub percent: f64,
+}
+
+/// Heap stats of one full call stack
+#[derive(Debug, Clone)]
+pub struct StackEntry {
+    pub stack_hash: i64,
+    pub live_bytes: i64,
+    pub total_alloc_bytes: i64,
+    pub alloc_count: u64,
+    /// Credited location first, then its callers
+    pub frames: Vec<Location>,
+}
+
+/// CPU samples of one full call stack
+#[derive(Debug, Clone)]
+pub struct CpuStackEntry {
+    pub stack_hash: i64,
+    pub samples: u64,
+    /// Credited location first, then its callers
+    pub frames: Vec<Location>,
+}
+
+/// CPU samples taken in one process of a `--follow-forks` recording
+#[derive(Debug, Clone)]
+pub struct ProcessEntry {
+    pub process_id: u32,
+    pub samples: u64,
+    /// Share of all CPU samples in the recording
+    pub percent: f64,
+    /// Location with the most samples in this process
+    pub file: String,
+    pub line: u32,
+    pub function: String,
+}
+
+impl TransferEntry {
+    /// Whether ownership moved to a different module (or crate)
+    pub fn crosses_module(&self) -> bool {
+        module_path(&self.alloc_function) != module_path(&self.free_function)
+    }
+}
+
+/// Module part of a function path: everything before the last `::` outside `<>`
+fn module_path(function: &str) -> &str {
+    let mut depth = 0i32;
+    let mut split = 0;
+    let bytes = function.as_bytes();
+    for i in 0..bytes.len() {
+        match bytes[i] {
+            b'<' => depth += 1,
+            b'>' => depth -= 1,
+            b':' if depth == 0 && i > 0 && bytes[i - 1] == b':' => split = i - 1,
+            _ => {}
+        }
+    }
+    &function[..split]
+}
+
+/// Gaps between consecutive CPU samples at one location
+#[derive(Debug, Clone)]
+pub struct GapEntry {
+    pub location_id: i64,
+    pub file: String,
+    pub line: u32,
+    pub function: String,
+    pub samples: u64,
+    /// Share of all CPU samples
+    pub cpu_pct: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+}
+
+/// Allocation size distribution at one location
+#[derive(Debug, Clone)]
+pub struct SizeEntry {
+    pub location_id: i64,
+    pub file: String,
+    pub line: u32,
+    pub function: String,
+    pub sizes: SizeDistribution,
+}
+
 /// Time-series data point for a function
 #[derive(Debug, Clone)]
 pub struct TimeSeriesPoint {
@@ -429,6 +1494,175 @@ pub struct TimeSeriesPoint {
 }
 
 /// Query CPU% over time for a specific location
+/// Meta key: number of backward clock steps repaired in the checkpoint timeline
+pub const META_CLOCK_SKEW_STEPS: &str = "clock_skew_steps";
+
+/// Repair checkpoint timestamps that go backwards
+///
+/// Checkpoint ids follow recording order, so a timestamp below the previous
+/// checkpoint's means the clock stepped back (e.g. wall-clock timestamps and
+/// an NTP step). The checkpoint is moved up to its predecessor's timestamp
+/// and every later one is shifted by the same amount, which keeps the chart's
+/// time axis and `MAX(timestamp_ms)` durations ordered while preserving the
+/// spacing between checkpoints. Prints a warning and adds the number of steps
+/// to meta `clock_skew_steps`; returns that number.
+pub fn repair_clock_skew(conn: &mut Connection) -> Result<usize> {
+    let checkpoints: Vec<(i64, i64)> = {
+        let mut stmt = conn.prepare("SELECT id, timestamp_ms FROM checkpoints ORDER BY id")?;
+        stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?
+    };
+
+    let mut steps = 0;
+    let mut largest_step_ms = 0;
+    let mut offset_ms = 0;
+    let mut previous_ms = i64::MIN;
+    let mut shifted = Vec::new();
+    for (id, timestamp_ms) in checkpoints {
+        let mut adjusted_ms = timestamp_ms + offset_ms;
+        if adjusted_ms < previous_ms {
+            steps += 1;
+            largest_step_ms = largest_step_ms.max(previous_ms - adjusted_ms);
+            offset_ms += previous_ms - adjusted_ms;
+            adjusted_ms = previous_ms;
+        }
+        if offset_ms != 0 {
+            shifted.push((id, adjusted_ms));
+        }
+        previous_ms = adjusted_ms;
+    }
+    if steps == 0 {
+        return Ok(0);
+    }
+
+    eprintln!(
+        "Warning: the clock went back {} time(s) during recording (up to {}ms); \
+         shifted {} checkpoint(s) to keep the timeline ordered",
+        steps,
+        largest_step_ms,
+        shifted.len()
+    );
+
+    let tx = conn.transaction()?;
+    for &(id, timestamp_ms) in &shifted {
+        tx.execute(
+            "UPDATE checkpoints SET timestamp_ms = ? WHERE id = ?",
+            [timestamp_ms, id],
+        )?;
+    }
+    let total_steps = schema::get_meta(&tx, META_CLOCK_SKEW_STEPS)?
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(0)
+        + steps;
+    schema::set_meta(&tx, META_CLOCK_SKEW_STEPS, &total_steps.to_string())?;
+    tx.commit()?;
+    Ok(steps)
+}
+
+/// Labeled checkpoints as (seconds, label), oldest first
+///
+/// Profiles recorded before checkpoint labels existed have none.
+pub fn query_checkpoint_labels(conn: &Connection) -> Vec<(f64, String)> {
+    let query_result: rusqlite::Result<Vec<(f64, String)>> = (|| {
+        let mut stmt = conn.prepare(
+            "SELECT timestamp_ms, label FROM checkpoints WHERE label IS NOT NULL ORDER BY timestamp_ms",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            let timestamp_ms: i64 = row.get(0)?;
+            let label: String = row.get(1)?;
+            Ok((timestamp_ms as f64 / 1000.0, label))
+        })?;
+        rows.collect()
+    })();
+    query_result.unwrap_or_default()
+}
+
+/// CPU samples and threads seen in one checkpoint's interval
+#[derive(Debug, Clone, Copy)]
+pub struct CheckpointStats {
+    pub timestamp_ms: i64,
+    /// Milliseconds since the previous checkpoint
+    pub interval_ms: i64,
+    pub sample_count: u64,
+    /// Threads that took CPU samples (0 where the profile predates the count,
+    /// or the samples came through the shared no-stack callsite)
+    pub thread_count: u32,
+}
+
+impl CheckpointStats {
+    /// CPU samples per second of wall time over the interval
+    pub fn sample_rate(&self) -> f64 {
+        if self.interval_ms > 0 {
+            self.sample_count as f64 * 1000.0 / self.interval_ms as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Per-checkpoint CPU sample and thread counts, oldest first
+///
+/// Profiles recorded before the counts were stored (schema v5 and older)
+/// have none.
+pub fn query_checkpoint_stats(conn: &Connection) -> Vec<CheckpointStats> {
+    let query_result: rusqlite::Result<Vec<(i64, i64, i64)>> = (|| {
+        let mut stmt = conn.prepare(
+            "SELECT timestamp_ms, sample_count, thread_count FROM checkpoints ORDER BY timestamp_ms",
+        )?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        rows.collect()
+    })();
+    let mut previous_ms = 0;
+    query_result
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(timestamp_ms, sample_count, thread_count)| {
+            let stats = CheckpointStats {
+                timestamp_ms,
+                interval_ms: timestamp_ms - previous_ms,
+                sample_count: sample_count.max(0) as u64,
+                thread_count: thread_count.max(0) as u32,
+            };
+            previous_ms = timestamp_ms;
+            stats
+        })
+        .collect()
+}
+
+/// CPU sampling frequency the profile was recorded at (meta `cpu_freq_hz`)
+pub fn query_cpu_freq_hz(conn: &Connection) -> Option<f64> {
+    schema::get_meta(conn, "cpu_freq_hz")
+        .ok()
+        .flatten()?
+        .parse::<f64>()
+        .ok()
+        .filter(|&freq| freq > 0.0)
+}
+
+/// Total of one hardware counter per location (`--counters`)
+///
+/// Empty when the profile didn't record that counter.
+pub fn query_cpu_counter_totals(conn: &Connection, counter: &str) -> HashMap<i64, u64> {
+    let query_result: rusqlite::Result<HashMap<i64, u64>> = (|| {
+        let mut stmt = conn.prepare(
+            "SELECT location_id, SUM(value) FROM cpu_counters WHERE counter = ? GROUP BY location_id",
+        )?;
+        let rows = stmt.query_map([counter], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)? as u64))
+        })?;
+        rows.collect()
+    })();
+    query_result.unwrap_or_default()
+}
+
+/// Estimated CPU time behind `samples` taken at `freq_hz`
+///
+/// Each sample stands for one sampling period of CPU time, so unlike CPU%
+/// this tells a busy function in an idle process from one in a pegged one.
+pub fn samples_to_secs(samples: u64, freq_hz: f64) -> f64 {
+    samples as f64 / freq_hz
+}
+
 pub fn query_cpu_timeseries(
     conn: &Connection,
     location_id: i64,
@@ -459,14 +1693,86 @@ pub fn query_cpu_timeseries(
     Ok(points)
 }
 
+/// Checkpoints averaged by `CpuSeries::RunningAverage`
+pub const RUNNING_AVERAGE_CHECKPOINTS: usize = 10;
+
+/// Which reading of a location's CPU share a chart plots
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CpuSeries {
+    /// Share of each checkpoint's samples
+    #[default]
+    Instant,
+    /// Share of all samples from the start of the profile up to each checkpoint
+    Cumulative,
+    /// Mean instant share over the last `RUNNING_AVERAGE_CHECKPOINTS` checkpoints
+    RunningAverage,
+}
+
+/// How a chart bucket collapses the checkpoints that fall into it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BucketAggregate {
+    /// Highest value: shows every spike
+    #[default]
+    Max,
+    /// Mean value: shows sustained load
+    Avg,
+    /// 95th percentile (nearest rank): spikes without one-off outliers
+    P95,
+}
+
+impl BucketAggregate {
+    /// Query collapsing `bucket_data(bucket_idx, value)` into one row per bucket
+    fn sql(self) -> &'static str {
+        match self {
+            BucketAggregate::Max => {
+                "SELECT bucket_idx, MAX(value) FROM bucket_data GROUP BY bucket_idx"
+            }
+            BucketAggregate::Avg => {
+                "SELECT bucket_idx, AVG(value) FROM bucket_data GROUP BY bucket_idx"
+            }
+            // Smallest value ranked at or above 95% of the bucket's checkpoints
+            BucketAggregate::P95 => {
+                r#"SELECT bucket_idx, MIN(value) FROM (
+                    SELECT bucket_idx, value,
+                           ROW_NUMBER() OVER (PARTITION BY bucket_idx ORDER BY value) AS row_num,
+                           COUNT(*) OVER (PARTITION BY bucket_idx) AS n
+                    FROM bucket_data
+                )
+                WHERE row_num * 20 >= n * 19
+                GROUP BY bucket_idx"#
+            }
+        }
+    }
+
+    /// Collapse one bucket's values, like `sql` does in the database
+    fn collapse(self, values: &mut [f64]) -> f64 {
+        if values.is_empty() {
+            return 0.0;
+        }
+        match self {
+            BucketAggregate::Max => values.iter().copied().fold(f64::MIN, f64::max),
+            BucketAggregate::Avg => values.iter().sum::<f64>() / values.len() as f64,
+            BucketAggregate::P95 => {
+                values.sort_by(f64::total_cmp);
+                values[(values.len() * 19).div_ceil(20) - 1]
+            }
+        }
+    }
+}
+
 /// Query CPU% over time aggregated into buckets (for chart rendering)
-/// Returns at most `num_buckets` points, each representing the MAX value in that time bucket
+/// Returns at most `num_buckets` points, each collapsing its time bucket by `aggregate`
+///
+/// Checkpoints where the location wasn't sampled count as 0%, so `Avg` and
+/// `P95` cover every checkpoint in the bucket, not only the busy ones.
 pub fn query_cpu_timeseries_aggregated(
     conn: &Connection,
     location_id: i64,
     start_ms: i64,
     end_ms: i64,
     num_buckets: usize,
+    series: CpuSeries,
+    aggregate: BucketAggregate,
 ) -> Vec<(f64, f64)> {
     if num_buckets == 0 || start_ms >= end_ms {
         return Vec::new();
@@ -476,26 +1782,21 @@ pub fn query_cpu_timeseries_aggregated(
     if bucket_ms == 0 {
         return Vec::new();
     }
+    if series != CpuSeries::Instant {
+        return query_cpu_series_derived(
+            conn,
+            location_id,
+            start_ms,
+            end_ms,
+            bucket_ms,
+            series,
+            aggregate,
+        )
+        .unwrap_or_default();
+    }
 
     let query_result: rusqlite::Result<Vec<(f64, f64)>> = (|| {
-        let mut stmt = conn.prepare(
-            r#"
-            WITH bucket_data AS (
-                SELECT
-                    ((c.timestamp_ms - ?2) / ?4) as bucket_idx,
-                    CAST(cs.count AS REAL) * 100.0 / (
-                        SELECT SUM(count) FROM cpu_samples WHERE checkpoint_id = c.id
-                    ) as pct
-                FROM checkpoints c
-                JOIN cpu_samples cs ON cs.checkpoint_id = c.id AND cs.location_id = ?1
-                WHERE c.timestamp_ms >= ?2 AND c.timestamp_ms < ?3
-            )
-            SELECT bucket_idx, MAX(pct) as max_pct
-            FROM bucket_data
-            GROUP BY bucket_idx
-            ORDER BY bucket_idx ASC
-            "#,
-        )?;
+        let mut stmt = conn.prepare(&cpu_timeseries_sql(aggregate))?;
 
         let rows = stmt.query_map(
             rusqlite::params![location_id, start_ms, end_ms, bucket_ms],
@@ -513,6 +1814,108 @@ pub fn query_cpu_timeseries_aggregated(
     query_result.unwrap_or_default()
 }
 
+/// Instant CPU% of location ?1 in buckets of ?4 ms over [?2, ?3)
+fn cpu_timeseries_sql(aggregate: BucketAggregate) -> String {
+    format!(
+        r#"
+        WITH bucket_data AS (
+            SELECT
+                ((c.timestamp_ms - ?2) / ?4) as bucket_idx,
+                CAST(COALESCE(cs.count, 0) AS REAL) * 100.0 / (
+                    SELECT SUM(count) FROM cpu_samples WHERE checkpoint_id = c.id
+                ) as value
+            FROM checkpoints c
+            LEFT JOIN cpu_samples cs ON cs.checkpoint_id = c.id AND cs.location_id = ?1
+            WHERE c.timestamp_ms >= ?2 AND c.timestamp_ms < ?3
+            -- Checkpoints without any samples carry no share
+            AND EXISTS (SELECT 1 FROM cpu_samples WHERE checkpoint_id = c.id)
+        )
+        {}
+        ORDER BY bucket_idx ASC
+        "#,
+        aggregate.sql()
+    )
+}
+
+/// Cumulative or running-average CPU% in buckets of `bucket_ms`
+///
+/// Both depend on checkpoints before the window, so every checkpoint up to
+/// `end_ms` is read. Checkpoints where the location wasn't sampled count as
+/// 0%, as in the instant series, before the bucket is collapsed.
+fn query_cpu_series_derived(
+    conn: &Connection,
+    location_id: i64,
+    start_ms: i64,
+    end_ms: i64,
+    bucket_ms: i64,
+    series: CpuSeries,
+    aggregate: BucketAggregate,
+) -> rusqlite::Result<Vec<(f64, f64)>> {
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT c.timestamp_ms,
+               COALESCE((SELECT count FROM cpu_samples
+                         WHERE checkpoint_id = c.id AND location_id = ?1), 0),
+               COALESCE((SELECT SUM(count) FROM cpu_samples WHERE checkpoint_id = c.id), 0)
+        FROM checkpoints c
+        WHERE c.timestamp_ms < ?2
+        ORDER BY c.timestamp_ms ASC
+        "#,
+    )?;
+    let rows = stmt
+        .query_map(rusqlite::params![location_id, end_ms], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, i64>(2)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut buckets: Vec<(i64, Vec<f64>)> = Vec::new();
+    let (mut location_sum, mut total_sum) = (0i64, 0i64);
+    let mut recent: std::collections::VecDeque<f64> = std::collections::VecDeque::new();
+    for (timestamp_ms, count, total) in rows {
+        // Checkpoints without any samples carry no share
+        if total == 0 {
+            continue;
+        }
+        let pct = match series {
+            CpuSeries::Instant => count as f64 * 100.0 / total as f64,
+            CpuSeries::Cumulative => {
+                location_sum += count;
+                total_sum += total;
+                location_sum as f64 * 100.0 / total_sum as f64
+            }
+            CpuSeries::RunningAverage => {
+                recent.push_back(count as f64 * 100.0 / total as f64);
+                if recent.len() > RUNNING_AVERAGE_CHECKPOINTS {
+                    recent.pop_front();
+                }
+                recent.iter().sum::<f64>() / recent.len() as f64
+            }
+        };
+        if timestamp_ms < start_ms {
+            continue;
+        }
+
+        let bucket_idx = (timestamp_ms - start_ms) / bucket_ms;
+        match buckets.last_mut() {
+            Some((last, values)) if *last == bucket_idx => values.push(pct),
+            _ => buckets.push((bucket_idx, vec![pct])),
+        }
+    }
+
+    // Collapsed like the instant series
+    Ok(buckets
+        .into_iter()
+        .map(|(bucket_idx, mut values)| {
+            let time_ms = start_ms + bucket_idx * bucket_ms + bucket_ms / 2;
+            (time_ms as f64 / 1000.0, aggregate.collapse(&mut values))
+        })
+        .collect())
+}
+
 /// Query top CPU consumers with both total and instant percentages (for live TUI)
 pub fn query_top_cpu_live(conn: &Connection, limit: usize) -> rusqlite::Result<Vec<CpuEntry>> {
     // Get totals
@@ -631,71 +2034,498 @@ pub fn query_top_cpu(
         })
     })?;
 
-    let mut entries = Vec::new();
-    for row in rows {
-        let entry = row?;
-        if entry.total_percent >= threshold {
-            entries.push(entry);
+    let mut entries = Vec::new();
+    for row in rows {
+        let entry = row?;
+        if entry.total_percent >= threshold {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Number of CPU samples in checkpoints within `(start_ms, end_ms]`
+///
+/// A checkpoint holds the samples taken since the previous one, so one
+/// stamped at `start_ms` falls before the range.
+pub fn query_cpu_sample_count(
+    conn: &Connection,
+    start_ms: i64,
+    end_ms: i64,
+) -> rusqlite::Result<u64> {
+    let count: i64 = conn.query_row(
+        r#"
+        SELECT COALESCE(SUM(cs.count), 0)
+        FROM cpu_samples cs
+        JOIN checkpoints c ON cs.checkpoint_id = c.id
+        WHERE c.timestamp_ms > ?1 AND c.timestamp_ms <= ?2
+        "#,
+        [start_ms, end_ms],
+        |row| row.get(0),
+    )?;
+    Ok(count as u64)
+}
+
+/// Query top CPU consumers in checkpoints within `(start_ms, end_ms]`
+///
+/// Percentages are relative to `total` samples, so the caller picks the
+/// denominator: the whole recording or just the range.
+pub fn query_top_cpu_range(
+    conn: &Connection,
+    limit: usize,
+    threshold: f64,
+    (start_ms, end_ms): (i64, i64),
+    total: u64,
+) -> rusqlite::Result<Vec<CpuEntry>> {
+    if total == 0 {
+        return Ok(vec![]);
+    }
+
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT l.id, l.file, l.line, l.function, SUM(cs.count) as samples
+        FROM cpu_samples cs
+        JOIN checkpoints c ON cs.checkpoint_id = c.id
+        JOIN locations l ON cs.location_id = l.id
+        WHERE c.timestamp_ms > ?1 AND c.timestamp_ms <= ?2
+        GROUP BY cs.location_id
+        ORDER BY samples DESC
+        LIMIT ?3
+        "#,
+    )?;
+
+    let rows = stmt.query_map(
+        rusqlite::params![start_ms, end_ms, limit.min(i64::MAX as usize) as i64],
+        |row| {
+            let samples: i64 = row.get(4)?;
+            Ok(CpuEntry {
+                location_id: row.get(0)?,
+                file: row.get(1)?,
+                line: row.get::<_, i64>(2)? as u32,
+                function: row.get(3)?,
+                total_samples: samples as u64,
+                total_percent: (samples as f64 / total as f64) * 100.0,
+                instant_percent: 0.0,
+            })
+        },
+    )?;
+
+    let mut entries = Vec::new();
+    for row in rows {
+        let entry = row?;
+        if entry.total_percent >= threshold {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Query top heap consumers with totals
+pub fn query_top_heap_live(conn: &Connection, limit: usize) -> rusqlite::Result<Vec<HeapEntry>> {
+    // Get the most recent checkpoint for live_bytes
+    let last_checkpoint: Option<i64> = conn
+        .query_row(
+            "SELECT id FROM checkpoints ORDER BY timestamp_ms DESC LIMIT 1",
+            [],
+            |row| row.get(0),
+        )
+        .ok();
+
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT
+            l.id, l.file, l.line, l.function,
+            COALESCE((
+                SELECT MAX(live_bytes, 0) FROM heap_samples
+                WHERE location_id = l.id AND checkpoint_id = ?1
+            ), 0) as live,
+            SUM(hs.alloc_bytes) as total_alloc,
+            SUM(hs.free_bytes) as total_free,
+            SUM(hs.alloc_count) as total_alloc_count,
+            SUM(hs.free_count) as total_free_count
+        FROM heap_samples hs
+        JOIN locations l ON hs.location_id = l.id
+        GROUP BY hs.location_id
+        ORDER BY live DESC, total_alloc DESC
+        LIMIT ?2
+        "#,
+    )?;
+
+    let cp_id = last_checkpoint.unwrap_or(0);
+    let rows = stmt.query_map(rusqlite::params![cp_id, limit as i64], |row| {
+        Ok(HeapEntry {
+            location_id: row.get(0)?,
+            file: row.get(1)?,
+            line: row.get::<_, i64>(2)? as u32,
+            function: row.get(3)?,
+            live_bytes: row.get(4)?,
+            total_alloc_bytes: row.get(5)?,
+            total_free_bytes: row.get(6)?,
+            alloc_count: row.get::<_, i64>(7)? as u64,
+            free_count: row.get::<_, i64>(8)? as u64,
+        })
+    })?;
+
+    let mut entries = Vec::new();
+    for row in rows {
+        entries.push(row?);
+    }
+
+    Ok(entries)
+}
+
+/// Checkpoint where total live heap bytes peaked
+#[derive(Debug, Clone)]
+pub struct HeapPeak {
+    pub checkpoint_id: i64,
+    pub timestamp_ms: i64,
+    pub live_bytes: i64,
+}
+
+/// Find the checkpoint with the most live heap bytes
+///
+/// Uses the peak tracked while recording, or scans `heap_samples` for
+/// profiles recorded before peaks were tracked.
+pub fn query_heap_peak(conn: &Connection) -> rusqlite::Result<Option<HeapPeak>> {
+    let recorded =
+        schema::get_meta(conn, META_HEAP_PEAK_CHECKPOINT)?.and_then(|v| v.parse::<i64>().ok());
+    let checkpoint_id = match recorded {
+        Some(id) => Some(id),
+        None => conn
+            .query_row(
+                r#"
+                SELECT checkpoint_id
+                FROM heap_samples
+                GROUP BY checkpoint_id
+                ORDER BY SUM(live_bytes) DESC, checkpoint_id
+                LIMIT 1
+                "#,
+                [],
+                |row| row.get(0),
+            )
+            .optional()?,
+    };
+    let Some(checkpoint_id) = checkpoint_id else {
+        return Ok(None);
+    };
+
+    conn.query_row(
+        r#"
+        SELECT c.timestamp_ms, COALESCE(SUM(hs.live_bytes), 0)
+        FROM checkpoints c
+        LEFT JOIN heap_samples hs ON hs.checkpoint_id = c.id
+        WHERE c.id = ?1
+        GROUP BY c.id
+        "#,
+        [checkpoint_id],
+        |row| {
+            Ok(HeapPeak {
+                checkpoint_id,
+                timestamp_ms: row.get(0)?,
+                live_bytes: row.get(1)?,
+            })
+        },
+    )
+    .optional()
+}
+
+/// Query live heap bytes per location as of one checkpoint
+///
+/// `heap_samples` is cumulative, so each row is the site's state at that
+/// moment rather than activity since the previous checkpoint.
+pub fn query_heap_at_checkpoint(
+    conn: &Connection,
+    checkpoint_id: i64,
+    limit: usize,
+) -> rusqlite::Result<Vec<HeapEntry>> {
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT l.id, l.file, l.line, l.function, hs.live_bytes, hs.alloc_bytes,
+               hs.free_bytes, hs.alloc_count, hs.free_count
+        FROM heap_samples hs
+        JOIN locations l ON hs.location_id = l.id
+        WHERE hs.checkpoint_id = ?1 AND hs.live_bytes > 0
+        ORDER BY hs.live_bytes DESC
+        LIMIT ?2
+        "#,
+    )?;
+
+    let rows = stmt.query_map(
+        rusqlite::params![checkpoint_id, limit.min(i64::MAX as usize) as i64],
+        |row| {
+            Ok(HeapEntry {
+                location_id: row.get(0)?,
+                file: row.get(1)?,
+                line: row.get::<_, i64>(2)? as u32,
+                function: row.get(3)?,
+                live_bytes: row.get(4)?,
+                total_alloc_bytes: row.get(5)?,
+                total_free_bytes: row.get(6)?,
+                alloc_count: row.get::<_, i64>(7)? as u64,
+                free_count: row.get::<_, i64>(8)? as u64,
+            })
+        },
+    )?;
+
+    rows.collect()
+}
+
+/// Query heap stats per marker, using each marker's latest checkpoint
+pub fn query_top_markers(conn: &Connection, limit: usize) -> rusqlite::Result<Vec<MarkerEntry>> {
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT ms.marker, ms.live_bytes, ms.alloc_bytes, ms.free_bytes,
+               ms.alloc_count, ms.free_count
+        FROM marker_samples ms
+        JOIN (
+            SELECT marker, MAX(checkpoint_id) AS checkpoint_id
+            FROM marker_samples
+            GROUP BY marker
+        ) latest ON latest.marker = ms.marker AND latest.checkpoint_id = ms.checkpoint_id
+        ORDER BY ms.alloc_bytes DESC
+        LIMIT ?1
+        "#,
+    )?;
+
+    let rows = stmt.query_map([limit as i64], |row| {
+        Ok(MarkerEntry {
+            marker: row.get(0)?,
+            live_bytes: row.get(1)?,
+            total_alloc_bytes: row.get(2)?,
+            total_free_bytes: row.get(3)?,
+            alloc_count: row.get::<_, i64>(4)? as u64,
+            free_count: row.get::<_, i64>(5)? as u64,
+        })
+    })?;
+
+    rows.collect()
+}
+
+/// Query allocation size distributions, busiest allocation sites first
+pub fn query_top_sizes(conn: &Connection, limit: usize) -> rusqlite::Result<Vec<SizeEntry>> {
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT l.id, l.file, l.line, l.function, hs.min_size, hs.max_size, hs.histogram
+        FROM heap_sizes hs
+        JOIN locations l ON l.id = hs.location_id
+        "#,
+    )?;
+
+    let rows = stmt.query_map([], |row| {
+        let histogram: String = row.get(6)?;
+        Ok(SizeEntry {
+            location_id: row.get(0)?,
+            file: row.get(1)?,
+            line: row.get::<_, i64>(2)? as u32,
+            function: row.get(3)?,
+            sizes: SizeDistribution::from_stored(
+                row.get::<_, i64>(4)? as u64,
+                row.get::<_, i64>(5)? as u64,
+                &histogram,
+            ),
+        })
+    })?;
+
+    // Counts live in the histogram text, so rank in Rust
+    let mut entries = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+    entries.sort_by(|a, b| {
+        b.sizes
+            .count()
+            .cmp(&a.sizes.count())
+            .then(a.location_id.cmp(&b.location_id))
+    });
+    entries.truncate(limit);
+    Ok(entries)
+}
+
+/// Allocation size distribution recorded for one location
+///
+/// Profiles recorded before size tracking existed have none.
+pub fn query_heap_sizes(conn: &Connection, location_id: i64) -> Option<SizeDistribution> {
+    conn.query_row(
+        "SELECT min_size, max_size, histogram FROM heap_sizes WHERE location_id = ?",
+        [location_id],
+        |row| {
+            let histogram: String = row.get(2)?;
+            Ok(SizeDistribution::from_stored(
+                row.get::<_, i64>(0)? as u64,
+                row.get::<_, i64>(1)? as u64,
+                &histogram,
+            ))
+        },
+    )
+    .ok()
+}
+
+/// Insert integer rows with multi-row `VALUES` statements
+///
+/// `insert` is the statement up to its column list. Rows are sorted first so
+/// index updates land in key order, then go in chunks that stay within
+/// `MAX_BIND_PARAMS`; full chunks reuse one cached statement.
+fn insert_batched<const N: usize>(
+    conn: &Connection,
+    insert: &str,
+    rows: &mut [[i64; N]],
+) -> rusqlite::Result<()> {
+    rows.sort_unstable();
+    let chunk_rows = MAX_BIND_PARAMS / N;
+    let placeholders = format!("({})", vec!["?"; N].join(", "));
+    let sql = |n: usize| {
+        format!(
+            "{insert} VALUES {}",
+            vec![placeholders.as_str(); n].join(", ")
+        )
+    };
+
+    for chunk in rows.chunks(chunk_rows) {
+        let params = rusqlite::params_from_iter(chunk.iter().flatten());
+        if chunk.len() == chunk_rows {
+            conn.prepare_cached(&sql(chunk_rows))?.execute(params)?;
+        } else {
+            conn.prepare(&sql(chunk.len()))?.execute(params)?;
         }
     }
+    Ok(())
+}
 
-    Ok(entries)
+/// Store a location's size distribution, replacing any earlier one
+pub fn write_heap_sizes(
+    conn: &Connection,
+    location_id: i64,
+    sizes: &SizeDistribution,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO heap_sizes (location_id, min_size, max_size, histogram) VALUES (?, ?, ?, ?)",
+        rusqlite::params![
+            location_id,
+            sizes.min as i64,
+            sizes.max as i64,
+            sizes.histogram_string()
+        ],
+    )?;
+    Ok(())
 }
 
-/// Query top heap consumers with totals
-pub fn query_top_heap_live(conn: &Connection, limit: usize) -> rusqlite::Result<Vec<HeapEntry>> {
-    // Get the most recent checkpoint for live_bytes
-    let last_checkpoint: Option<i64> = conn
+/// Identify a call stack by its frames' location ids (FNV-1a)
+pub fn stack_hash(location_ids: &[i64]) -> i64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for id in location_ids {
+        for byte in id.to_le_bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash as i64
+}
+
+/// Query heap call stacks by live bytes at the last checkpoint
+///
+/// Profiles from before schema v5 have no stacks and yield no entries.
+pub fn query_heap_stacks(conn: &Connection, limit: usize) -> rusqlite::Result<Vec<StackEntry>> {
+    if !schema::has_table(conn, "frames")? {
+        return Ok(Vec::new());
+    }
+
+    let last_checkpoint: i64 = conn
         .query_row(
             "SELECT id FROM checkpoints ORDER BY timestamp_ms DESC LIMIT 1",
             [],
             |row| row.get(0),
         )
-        .ok();
+        .optional()?
+        .unwrap_or(0);
 
+    // Stats are cumulative: the largest value is the latest
     let mut stmt = conn.prepare(
         r#"
-        SELECT
-            l.id, l.file, l.line, l.function,
-            COALESCE((
-                SELECT live_bytes FROM heap_samples
-                WHERE location_id = l.id AND checkpoint_id = ?1
-            ), 0) as live,
-            SUM(hs.alloc_bytes) as total_alloc,
-            SUM(hs.free_bytes) as total_free,
-            SUM(hs.alloc_count) as total_alloc_count,
-            SUM(hs.free_count) as total_free_count
-        FROM heap_samples hs
-        JOIN locations l ON hs.location_id = l.id
-        GROUP BY hs.location_id
-        ORDER BY live DESC, total_alloc DESC
-        LIMIT ?2
-        "#,
+    SELECT
+        stack_hash,
+        COALESCE(MAX(CASE WHEN checkpoint_id = ?1 THEN live_bytes END), 0) as live,
+        MAX(alloc_bytes) as total_alloc,
+        MAX(alloc_count) as total_alloc_count
+    FROM heap_stack_samples
+    GROUP BY stack_hash
+    ORDER BY live DESC, total_alloc DESC
+    LIMIT ?2
+    "#,
     )?;
-
-    let cp_id = last_checkpoint.unwrap_or(0);
-    let rows = stmt.query_map(rusqlite::params![cp_id, limit as i64], |row| {
-        Ok(HeapEntry {
-            location_id: row.get(0)?,
-            file: row.get(1)?,
-            line: row.get::<_, i64>(2)? as u32,
-            function: row.get(3)?,
-            live_bytes: row.get(4)?,
-            total_alloc_bytes: row.get(5)?,
-            total_free_bytes: row.get(6)?,
-            alloc_count: row.get::<_, i64>(7)? as u64,
-            free_count: row.get::<_, i64>(8)? as u64,
+    let rows = stmt.query_map(rusqlite::params![last_checkpoint, limit as i64], |row| {
+        Ok(StackEntry {
+            stack_hash: row.get(0)?,
+            live_bytes: row.get(1)?,
+            total_alloc_bytes: row.get(2)?,
+            alloc_count: row.get::<_, i64>(3)? as u64,
+            frames: Vec::new(),
         })
     })?;
+    let mut entries = rows.collect::<rusqlite::Result<Vec<_>>>()?;
 
-    let mut entries = Vec::new();
-    for row in rows {
-        entries.push(row?);
+    let mut frames = conn.prepare(STACK_FRAMES_SQL)?;
+    for entry in &mut entries {
+        entry.frames = stack_frames(&mut frames, entry.stack_hash)?;
+    }
+    Ok(entries)
+}
+
+/// Query CPU call stacks by samples over the whole recording
+///
+/// Only rsprof-trace recordings from schema v7 on have CPU stacks; others
+/// yield no entries.
+pub fn query_cpu_stacks(conn: &Connection) -> rusqlite::Result<Vec<CpuStackEntry>> {
+    if !schema::has_table(conn, "cpu_stack_samples")? {
+        return Ok(Vec::new());
     }
 
+    let mut stmt = conn.prepare(
+        r#"
+    SELECT stack_hash, SUM(count) as samples
+    FROM cpu_stack_samples
+    GROUP BY stack_hash
+    ORDER BY samples DESC
+    "#,
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok(CpuStackEntry {
+            stack_hash: row.get(0)?,
+            samples: row.get::<_, i64>(1)? as u64,
+            frames: Vec::new(),
+        })
+    })?;
+    let mut entries = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut frames = conn.prepare(STACK_FRAMES_SQL)?;
+    for entry in &mut entries {
+        entry.frames = stack_frames(&mut frames, entry.stack_hash)?;
+    }
     Ok(entries)
 }
 
+const STACK_FRAMES_SQL: &str = r#"
+    SELECT l.file, l.line, l.function
+    FROM frames f
+    JOIN locations l ON l.id = f.location_id
+    WHERE f.stack_hash = ?
+    ORDER BY f.depth
+    "#;
+
+/// Frames of one call stack, credited location first
+fn stack_frames(
+    stmt: &mut rusqlite::Statement<'_>,
+    stack_hash: i64,
+) -> rusqlite::Result<Vec<Location>> {
+    stmt.query_map([stack_hash], |row| {
+        Ok(Location {
+            file: row.get(0)?,
+            line: row.get::<_, i64>(1)? as u32,
+            column: 0,
+            function: row.get(2)?,
+        })
+    })?
+    .collect()
+}
+
 /// Query combined CPU + Heap data for "Both" view
 pub fn query_combined_live(
     conn: &Connection,
@@ -729,6 +2559,7 @@ pub fn query_combined_live(
 
     // Combined query joining CPU and Heap data
     // heap_total = sum of all allocations over time (alloc_bytes)
+    // heap_alloc_bytes = bytes allocated so far (alloc_bytes is cumulative)
     // heap_instant = current slice's live bytes (live_bytes at current checkpoint)
     let mut stmt = conn.prepare(
         r#"
@@ -737,7 +2568,9 @@ pub fn query_combined_live(
             COALESCE((SELECT SUM(count) FROM cpu_samples WHERE location_id = l.id), 0) as cpu_total,
             COALESCE((SELECT count FROM cpu_samples WHERE location_id = l.id AND checkpoint_id = ?1), 0) as cpu_instant,
             COALESCE((SELECT SUM(alloc_bytes) FROM heap_samples WHERE location_id = l.id), 0) as heap_total,
-            COALESCE((SELECT live_bytes FROM heap_samples WHERE location_id = l.id AND checkpoint_id = ?1), 0) as heap_instant
+            COALESCE((SELECT MAX(alloc_bytes) FROM heap_samples WHERE location_id = l.id), 0) as heap_alloc_bytes,
+            COALESCE((SELECT MAX(live_bytes, 0) FROM heap_samples WHERE location_id = l.id AND checkpoint_id = ?1), 0) as heap_instant,
+            COALESCE((SELECT MAX(alloc_count) FROM heap_samples WHERE location_id = l.id), 0) as heap_alloc_count
         FROM locations l
         WHERE l.id IN (
             SELECT DISTINCT location_id FROM cpu_samples
@@ -755,7 +2588,9 @@ pub fn query_combined_live(
         let cpu_total: i64 = row.get(4)?;
         let cpu_instant: i64 = row.get(5)?;
         let heap_total: i64 = row.get(6)?;
-        let heap_instant: i64 = row.get(7)?;
+        let heap_alloc_bytes: i64 = row.get(7)?;
+        let heap_instant: i64 = row.get(8)?;
+        let heap_alloc_count: i64 = row.get(9)?;
 
         Ok(CombinedEntry {
             location_id: row.get(0)?,
@@ -773,6 +2608,8 @@ pub fn query_combined_live(
                 0.0
             },
             heap_total,
+            heap_alloc_bytes,
+            heap_alloc_count: heap_alloc_count as u64,
             heap_instant,
         })
     })?;
@@ -785,6 +2622,25 @@ pub fn query_combined_live(
     Ok(entries)
 }
 
+/// Live bytes of location ?1 in buckets of ?4 ms over [?2, ?3)
+fn heap_timeseries_sql(aggregate: BucketAggregate) -> String {
+    format!(
+        r#"
+        WITH bucket_data AS (
+            SELECT
+                ((c.timestamp_ms - ?2) / ?4) as bucket_idx,
+                hs.live_bytes as value
+            FROM checkpoints c
+            JOIN heap_samples hs ON hs.checkpoint_id = c.id AND hs.location_id = ?1
+            WHERE c.timestamp_ms >= ?2 AND c.timestamp_ms < ?3
+        )
+        {}
+        ORDER BY bucket_idx ASC
+        "#,
+        aggregate.sql()
+    )
+}
+
 /// Query heap bytes over time aggregated into buckets (for chart rendering)
 pub fn query_heap_timeseries_aggregated(
     conn: &Connection,
@@ -792,6 +2648,7 @@ pub fn query_heap_timeseries_aggregated(
     start_ms: i64,
     end_ms: i64,
     num_buckets: usize,
+    aggregate: BucketAggregate,
 ) -> Vec<(f64, f64)> {
     if num_buckets == 0 || start_ms >= end_ms {
         return Vec::new();
@@ -803,30 +2660,16 @@ pub fn query_heap_timeseries_aggregated(
     }
 
     let query_result: rusqlite::Result<Vec<(f64, f64)>> = (|| {
-        let mut stmt = conn.prepare(
-            r#"
-            WITH bucket_data AS (
-                SELECT
-                    ((c.timestamp_ms - ?2) / ?4) as bucket_idx,
-                    hs.live_bytes
-                FROM checkpoints c
-                JOIN heap_samples hs ON hs.checkpoint_id = c.id AND hs.location_id = ?1
-                WHERE c.timestamp_ms >= ?2 AND c.timestamp_ms < ?3
-            )
-            SELECT bucket_idx, MAX(live_bytes) as max_bytes
-            FROM bucket_data
-            GROUP BY bucket_idx
-            ORDER BY bucket_idx ASC
-            "#,
-        )?;
+        let mut stmt = conn.prepare(&heap_timeseries_sql(aggregate))?;
 
         let rows = stmt.query_map(
             rusqlite::params![location_id, start_ms, end_ms, bucket_ms],
             |row| {
                 let bucket_idx: i64 = row.get(0)?;
-                let bytes: i64 = row.get::<_, Option<i64>>(1)?.unwrap_or(0);
+                // AVG makes it a real; MAX and P95 keep whole bytes
+                let bytes: f64 = row.get::<_, Option<f64>>(1)?.unwrap_or(0.0);
                 let time_ms = start_ms + bucket_idx * bucket_ms + bucket_ms / 2;
-                Ok((time_ms as f64 / 1000.0, bytes as f64))
+                Ok((time_ms as f64 / 1000.0, bytes))
             },
         )?;
 
@@ -836,6 +2679,51 @@ pub fn query_heap_timeseries_aggregated(
     query_result.unwrap_or_default()
 }
 
+/// Live bytes of one heap location at every checkpoint it has data for
+#[derive(Debug, Clone)]
+pub struct HeapSeries {
+    pub location_id: i64,
+    pub file: String,
+    pub line: u32,
+    pub function: String,
+    /// (timestamp_ms, live_bytes), oldest first
+    pub points: Vec<(i64, i64)>,
+}
+
+/// Query the live bytes of every heap location over the whole recording
+///
+/// Same checkpoint join as [`query_heap_timeseries_aggregated`], without
+/// bucketing: one point per checkpoint that has a row for the location.
+pub fn query_heap_series(conn: &Connection) -> rusqlite::Result<Vec<HeapSeries>> {
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT l.id, l.file, l.line, l.function, c.timestamp_ms, hs.live_bytes
+        FROM checkpoints c
+        JOIN heap_samples hs ON hs.checkpoint_id = c.id
+        JOIN locations l ON l.id = hs.location_id
+        ORDER BY l.id, c.timestamp_ms, c.id
+        "#,
+    )?;
+    let mut rows = stmt.query([])?;
+
+    let mut series: Vec<HeapSeries> = Vec::new();
+    while let Some(row) = rows.next()? {
+        let location_id: i64 = row.get(0)?;
+        let point = (row.get(4)?, row.get(5)?);
+        match series.last_mut() {
+            Some(last) if last.location_id == location_id => last.points.push(point),
+            _ => series.push(HeapSeries {
+                location_id,
+                file: row.get(1)?,
+                line: row.get::<_, i64>(2)? as u32,
+                function: row.get(3)?,
+                points: vec![point],
+            }),
+        }
+    }
+    Ok(series)
+}
+
 /// Query sparkline data for all heap locations (recent N checkpoints)
 /// Returns HashMap<location_id, Vec<live_bytes>> for sparkline rendering
 pub fn query_heap_sparklines(conn: &Connection, num_points: usize) -> HashMap<i64, Vec<i64>> {
@@ -849,11 +2737,81 @@ pub fn query_heap_sparklines_for_locations(
     conn: &Connection,
     num_points: usize,
     location_ids: &[i64],
+) -> HashMap<i64, Vec<i64>> {
+    query_heap_column_for_locations(conn, num_points, location_ids, "live_bytes")
+}
+
+/// Query allocation-count sparklines for specific locations (or all if empty)
+/// Returns HashMap<location_id, Vec<allocs per checkpoint>>
+pub fn query_heap_count_sparklines_for_locations(
+    conn: &Connection,
+    num_points: usize,
+    location_ids: &[i64],
+) -> HashMap<i64, Vec<i64>> {
+    // alloc_count is cumulative: fetch one extra checkpoint as the baseline
+    query_heap_column_for_locations(conn, num_points + 1, location_ids, "alloc_count")
+        .into_iter()
+        .map(|(loc_id, counts)| {
+            let baseline = counts.len() > num_points;
+            (loc_id, cumulative_to_deltas(&counts, baseline))
+        })
+        .collect()
+}
+
+/// Convert cumulative per-checkpoint counts into per-checkpoint deltas
+///
+/// Zeros (checkpoints where the location was not sampled) carry the previous
+/// total forward. With `baseline`, the first value only seeds the first delta.
+fn cumulative_to_deltas(counts: &[i64], baseline: bool) -> Vec<i64> {
+    let (mut prev, rest) = match counts.split_first() {
+        Some((&first, rest)) if baseline => (first, rest),
+        _ => (0, counts),
+    };
+    rest.iter()
+        .map(|&count| {
+            let count = if count == 0 { prev } else { count };
+            let delta = (count - prev).max(0);
+            prev = count;
+            delta
+        })
+        .collect()
+}
+
+/// The last N checkpoints, newest first (walks `idx_checkpoints_time`)
+const LAST_CHECKPOINTS_SQL: &str = "SELECT id FROM checkpoints ORDER BY timestamp_ms DESC LIMIT ?";
+
+/// Query for one heap_samples column at `num_checkpoints` checkpoint IDs,
+/// limited to `num_locations` location IDs unless that is 0
+fn heap_column_sql(column: &str, num_checkpoints: usize, num_locations: usize) -> String {
+    let placeholders = |n: usize| vec!["?"; n].join(",");
+    let mut query = format!(
+        r#"
+        SELECT hs.location_id, hs.checkpoint_id, hs.{}
+        FROM heap_samples hs
+        WHERE hs.checkpoint_id IN ({})
+        "#,
+        column,
+        placeholders(num_checkpoints)
+    );
+    if num_locations > 0 {
+        query.push_str(&format!(
+            "AND hs.location_id IN ({})",
+            placeholders(num_locations)
+        ));
+    }
+    query
+}
+
+/// Query one heap_samples column per checkpoint for sparkline rendering
+fn query_heap_column_for_locations(
+    conn: &Connection,
+    num_points: usize,
+    location_ids: &[i64],
+    column: &str,
 ) -> HashMap<i64, Vec<i64>> {
     let query_result: rusqlite::Result<HashMap<i64, Vec<i64>>> = (|| {
         // Get the last N checkpoints in chronological order
-        let mut cp_stmt =
-            conn.prepare("SELECT id FROM checkpoints ORDER BY timestamp_ms DESC LIMIT ?")?;
+        let mut cp_stmt = conn.prepare(LAST_CHECKPOINTS_SQL)?;
         let checkpoint_ids: Vec<i64> = cp_stmt
             .query_map([num_points as i64], |row| row.get(0))?
             .filter_map(|r| r.ok())
@@ -874,39 +2832,7 @@ pub fn query_heap_sparklines_for_locations(
             .map(|(i, &id)| (id, i))
             .collect();
 
-        // Build query based on whether we have specific location_ids
-        let cp_placeholders = checkpoint_ids
-            .iter()
-            .map(|_| "?")
-            .collect::<Vec<_>>()
-            .join(",");
-
-        let query = if location_ids.is_empty() {
-            format!(
-                r#"
-                SELECT hs.location_id, hs.checkpoint_id, hs.live_bytes
-                FROM heap_samples hs
-                WHERE hs.checkpoint_id IN ({})
-                "#,
-                cp_placeholders
-            )
-        } else {
-            let loc_placeholders = location_ids
-                .iter()
-                .map(|_| "?")
-                .collect::<Vec<_>>()
-                .join(",");
-            format!(
-                r#"
-                SELECT hs.location_id, hs.checkpoint_id, hs.live_bytes
-                FROM heap_samples hs
-                WHERE hs.checkpoint_id IN ({})
-                AND hs.location_id IN ({})
-                "#,
-                cp_placeholders, loc_placeholders
-            )
-        };
-
+        let query = heap_column_sql(column, num_checkpoints, location_ids.len());
         let mut stmt = conn.prepare(&query)?;
 
         // Build parameter list
@@ -928,15 +2854,15 @@ pub fn query_heap_sparklines_for_locations(
             Ok((
                 row.get::<_, i64>(0)?, // location_id
                 row.get::<_, i64>(1)?, // checkpoint_id
-                row.get::<_, i64>(2)?, // live_bytes
+                row.get::<_, i64>(2)?, // value
             ))
         })?;
 
         for row in rows {
-            if let Ok((loc_id, cp_id, live_bytes)) = row
+            if let Ok((loc_id, cp_id, value)) = row
                 && let Some(&idx) = cp_index.get(&cp_id)
             {
-                raw_data.entry(loc_id).or_default().push((idx, live_bytes));
+                raw_data.entry(loc_id).or_default().push((idx, value));
             }
         }
 
@@ -953,8 +2879,8 @@ pub fn query_heap_sparklines_for_locations(
             let values = result
                 .entry(loc_id)
                 .or_insert_with(|| vec![0i64; num_checkpoints]);
-            for (idx, live_bytes) in data_points {
-                values[idx] = live_bytes;
+            for (idx, value) in data_points {
+                values[idx] = value;
             }
         }
 
@@ -963,3 +2889,606 @@ pub fn query_heap_sparklines_for_locations(
 
     query_result.unwrap_or_default()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// In-memory profile with `location_id` 1 and 2 and one checkpoint per entry
+    /// of `counts`: (timestamp_ms, samples at location 1, samples at location 2)
+    fn cpu_profile(counts: &[(i64, i64, i64)]) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        schema::create_tables(&conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO locations (id, file, line, function) VALUES
+                 (1, 'a.rs', 1, 'a'), (2, 'b.rs', 1, 'b');",
+        )
+        .unwrap();
+        for (i, &(timestamp_ms, a, b)) in counts.iter().enumerate() {
+            let id = i as i64 + 1;
+            conn.execute(
+                "INSERT INTO checkpoints (id, timestamp_ms) VALUES (?, ?)",
+                [id, timestamp_ms],
+            )
+            .unwrap();
+            for (location_id, count) in [(1, a), (2, b)] {
+                if count > 0 {
+                    conn.execute(
+                        "INSERT INTO cpu_samples (checkpoint_id, location_id, count) VALUES (?, ?, ?)",
+                        [id, location_id, count],
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        conn
+    }
+
+    #[test]
+    fn cost_weights_reorder_cpu_and_heap_heavy_sites() {
+        // Location 1 takes most CPU samples, location 2 allocates most bytes
+        // (heap counters are cumulative: 2 ends at 9000 bytes, not 9000 + 3000)
+        let conn = cpu_profile(&[(100, 8, 2), (200, 8, 2)]);
+        conn.execute_batch(
+            "INSERT INTO heap_samples
+                 (checkpoint_id, location_id, alloc_bytes, free_bytes, live_bytes, alloc_count, free_count)
+             VALUES (1, 1, 500, 0, 500, 1, 0), (2, 1, 1000, 0, 1000, 2, 0),
+                    (1, 2, 3000, 0, 3000, 3, 0), (2, 2, 9000, 0, 9000, 9, 0);",
+        )
+        .unwrap();
+        let ranking = |cpu_weight, heap_weight| {
+            query_top_cost(&conn, 10, cpu_weight, heap_weight)
+                .unwrap()
+                .iter()
+                .map(|e| e.location_id)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(ranking(1.0, 0.0), vec![1, 2]);
+        assert_eq!(ranking(0.0, 1.0), vec![2, 1]);
+        // 80% CPU + 10% heap against 20% CPU + 90% heap
+        assert_eq!(ranking(1.0, 1.0), vec![2, 1]);
+        assert_eq!(ranking(5.0, 1.0), vec![1, 2]);
+
+        let entries = query_top_cost(&conn, 10, 1.0, 1.0).unwrap();
+        assert_eq!(entries[0].alloc_bytes, 9000);
+        assert!((entries[0].heap_pct - 90.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn negative_live_bytes_clamp_to_zero_and_count_as_errors() {
+        let path =
+            std::env::temp_dir().join(format!("rsprof-accounting-error-{}.db", std::process::id()));
+        let mut storage = Storage::create(&path).unwrap();
+        let location = |function: &str| Location {
+            file: "src/main.rs".to_string(),
+            line: 1,
+            column: 0,
+            function: function.to_string(),
+        };
+        let accounting_error = |storage: &Storage| {
+            schema::get_meta(&storage.conn, META_HEAP_ACCOUNTING_ERROR)
+                .unwrap()
+                .map(|v| v.parse::<i64>().unwrap())
+        };
+        let live = |storage: &Storage| {
+            let mut live: Vec<_> = storage
+                .query_top_heap_live(10)
+                .into_iter()
+                .map(|e| (e.function, e.live_bytes))
+                .collect();
+            live.sort();
+            live
+        };
+
+        // `freed` saw 300 bytes more freed than allocated
+        storage.record_heap_sample(&location("freed"), 100, 400, -300, 1, 2);
+        storage.record_heap_sample(&location("kept"), 500, 0, 500, 1, 0);
+        storage.flush_checkpoint_at(100).unwrap();
+        assert_eq!(live(&storage), [("freed".into(), 0), ("kept".into(), 500)]);
+        assert_eq!(accounting_error(&storage), Some(300));
+
+        // Counters are cumulative: the same deficit isn't counted again, a
+        // deeper one only adds the difference
+        storage.record_heap_sample(&location("freed"), 100, 400, -300, 1, 2);
+        storage.flush_checkpoint_at(200).unwrap();
+        assert_eq!(accounting_error(&storage), Some(300));
+        storage.record_heap_sample(&location("freed"), 100, 600, -500, 1, 3);
+        storage.record_heap_sample(&location("kept"), 500, 600, -100, 1, 1);
+        storage.flush_checkpoint_at(300).unwrap();
+        assert_eq!(live(&storage), [("freed".into(), 0), ("kept".into(), 0)]);
+        assert_eq!(accounting_error(&storage), Some(600));
+
+        drop(storage);
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", path.display(), suffix)).ok();
+        }
+    }
+
+    #[test]
+    fn heap_peak_is_the_mid_run_checkpoint_with_its_own_sites() {
+        let path = std::env::temp_dir().join(format!("rsprof-heap-peak-{}.db", std::process::id()));
+        let mut storage = Storage::create(&path).unwrap();
+        let location = |function: &str| Location {
+            file: "src/main.rs".to_string(),
+            line: 1,
+            column: 0,
+            function: function.to_string(),
+        };
+
+        // A buffer peaks at 200 ms and is freed; the cache grows until the end
+        for (timestamp_ms, buffer, cache) in [(100, 1000, 100), (200, 8000, 200), (300, 0, 3000)] {
+            storage.record_heap_sample(&location("buffer"), 8000, 8000 - buffer, buffer, 1, 0);
+            storage.record_heap_sample(&location("cache"), cache, 0, cache, 1, 0);
+            storage.flush_checkpoint_at(timestamp_ms).unwrap();
+        }
+        let sites_at_peak = |conn: &Connection| {
+            let peak = query_heap_peak(conn).unwrap().unwrap();
+            let sites: Vec<_> = query_heap_at_checkpoint(conn, peak.checkpoint_id, 10)
+                .unwrap()
+                .into_iter()
+                .map(|e| (e.function, e.live_bytes))
+                .collect();
+            (peak.timestamp_ms, peak.live_bytes, sites)
+        };
+        let expected = (
+            200,
+            8200,
+            vec![("buffer".to_string(), 8000), ("cache".to_string(), 200)],
+        );
+
+        assert_eq!(sites_at_peak(&storage.conn), expected);
+        assert_eq!(storage.query_top_heap_live(10)[0].function, "cache");
+
+        // Profiles recorded before the peak was tracked find it by scanning
+        storage
+            .conn
+            .execute("DELETE FROM meta WHERE key LIKE 'heap_peak_%'", [])
+            .unwrap();
+        assert_eq!(sites_at_peak(&storage.conn), expected);
+
+        drop(storage);
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", path.display(), suffix)).ok();
+        }
+    }
+
+    #[test]
+    fn allocator_cpu_is_charged_to_the_allocating_site() {
+        let path =
+            std::env::temp_dir().join(format!("rsprof-allocator-cpu-{}.db", std::process::id()));
+        let mut storage = Storage::create(&path).unwrap();
+        let location = |function: &str| Location {
+            file: "src/main.rs".to_string(),
+            line: 1,
+            column: 0,
+            function: function.to_string(),
+        };
+
+        // `parse` allocates heavily and 6 of its 10 samples are in malloc;
+        // `compute` only burns CPU
+        storage.record_cpu_sample_count(0x1000, &location("parse"), 10);
+        storage.record_allocator_samples(&location("parse"), 6);
+        storage.record_heap_sample(&location("parse"), 64_000, 0, 64_000, 1000, 0);
+        storage.record_cpu_sample_count(0x2000, &location("compute"), 20);
+        storage.flush_checkpoint_at(100).unwrap();
+
+        let entries = query_top_allocator_cpu(&storage.conn, 10).unwrap();
+        assert_eq!(entries.len(), 1);
+        let parse = &entries[0];
+        assert_eq!(parse.function, "parse");
+        assert_eq!((parse.allocator_samples, parse.cpu_samples), (6, 10));
+        assert_eq!((parse.alloc_bytes, parse.alloc_count), (64_000, 1000));
+        assert_eq!(parse.allocator_pct, 20.0);
+
+        drop(storage);
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", path.display(), suffix)).ok();
+        }
+    }
+
+    #[test]
+    fn transfers_pair_alloc_and_free_locations() {
+        let path = std::env::temp_dir().join(format!("rsprof-transfers-{}.db", std::process::id()));
+        let mut storage = Storage::create(&path).unwrap();
+        let location = |function: &str, line: u32| Location {
+            file: "src/main.rs".to_string(),
+            line,
+            column: 0,
+            function: function.to_string(),
+        };
+
+        // Two stacks allocate in the parser and free in the server; a third
+        // pair is a buffer dropped where it was allocated
+        let parse = location("app::parser::parse", 10);
+        let respond = location("app::server::respond", 20);
+        let local = location("app::parser::tokenize", 30);
+        storage.record_heap_sample(&parse, 4200, 4200, 0, 6, 6);
+        storage.record_heap_transfer(&parse, &respond, 3, 3000);
+        storage.record_heap_transfer(&parse, &respond, 1, 1000);
+        storage.record_heap_transfer(&local, &local, 9, 9000);
+        storage.record_heap_transfer(&parse, &location("app::parser::finish", 40), 2, 200);
+        storage.flush_checkpoint_at(100).unwrap();
+
+        let entries = query_heap_transfers(&storage.conn, 10).unwrap();
+        let pairs: Vec<_> = entries
+            .iter()
+            .map(|e| {
+                (
+                    e.alloc_function.as_str(),
+                    e.free_function.as_str(),
+                    e.count,
+                    e.bytes,
+                    e.crosses_module(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            pairs,
+            [
+                ("app::parser::parse", "app::server::respond", 4, 4000, true),
+                ("app::parser::parse", "app::parser::finish", 2, 200, false),
+            ]
+        );
+
+        drop(storage);
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", path.display(), suffix)).ok();
+        }
+    }
+
+    #[test]
+    fn heap_stacks_keep_their_callers_and_are_stored_once() {
+        let path =
+            std::env::temp_dir().join(format!("rsprof-heap-stacks-{}.db", std::process::id()));
+        let mut storage = Storage::create(&path).unwrap();
+        let location = |function: &str, line: u32| Location {
+            file: "src/main.rs".to_string(),
+            line,
+            column: 0,
+            function: function.to_string(),
+        };
+
+        // One allocating site reached from two callers; two stack keys
+        // resolve to the request path and are summed
+        let alloc = location("app::buf::grow", 10);
+        let request = [
+            alloc.clone(),
+            location("app::server::handle", 20),
+            location("app::main", 30),
+        ];
+        let startup = [alloc.clone(), location("app::config::load", 40)];
+        storage.record_heap_sample(&alloc, 1050, 100, 950, 11, 1);
+        storage.record_heap_stack(&request, 600, 100, 500, 6, 1);
+        storage.record_heap_stack(&request, 400, 0, 400, 4, 0);
+        storage.record_heap_stack(&startup, 50, 0, 50, 1, 0);
+        storage.record_heap_stack(&[], 999, 0, 999, 1, 0);
+        storage.flush_checkpoint_at(100).unwrap();
+        storage.record_heap_sample(&alloc, 2050, 1000, 1050, 21, 10);
+        storage.record_heap_stack(&request, 2000, 1000, 1000, 20, 10);
+        storage.record_heap_stack(&startup, 50, 0, 50, 1, 0);
+        storage.flush_checkpoint_at(200).unwrap();
+
+        let stacks = query_heap_stacks(&storage.conn, 10).unwrap();
+        let summary: Vec<_> = stacks
+            .iter()
+            .map(|s| {
+                let functions: Vec<_> = s.frames.iter().map(|f| f.function.as_str()).collect();
+                (functions, s.live_bytes, s.total_alloc_bytes, s.alloc_count)
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (
+                    vec!["app::buf::grow", "app::server::handle", "app::main"],
+                    1000,
+                    2000,
+                    20
+                ),
+                (vec!["app::buf::grow", "app::config::load"], 50, 50, 1),
+            ]
+        );
+
+        let frame_rows: i64 = storage
+            .conn
+            .query_row("SELECT COUNT(*) FROM frames", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(frame_rows, 5);
+
+        // Profiles from before the frames table have no stacks
+        storage.conn.execute_batch("DROP TABLE frames").unwrap();
+        assert!(query_heap_stacks(&storage.conn, 10).unwrap().is_empty());
+
+        drop(storage);
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", path.display(), suffix)).ok();
+        }
+    }
+
+    #[test]
+    fn addresses_on_one_line_share_a_location_and_are_all_kept() {
+        let path =
+            std::env::temp_dir().join(format!("rsprof-location-addrs-{}.db", std::process::id()));
+        let mut storage = Storage::create(&path).unwrap();
+        let location = |function: &str, line| Location {
+            file: "src/main.rs".to_string(),
+            line,
+            column: 0,
+            function: function.to_string(),
+        };
+
+        // Three instructions of one line (one sampled twice), and another line
+        let parse = storage.record_cpu_sample(0x1010, &location("parse", 7));
+        for addr in [0x1024, 0x1010, 0x1038] {
+            assert_eq!(
+                storage.record_cpu_sample(addr, &location("parse", 7)),
+                parse
+            );
+        }
+        let emit = storage.record_cpu_sample(0x2000, &location("emit", 9));
+        assert_ne!(emit, parse);
+        storage.flush_checkpoint_at(100).unwrap();
+        // Seen again later: still one row per address
+        storage.record_cpu_sample(0x1024, &location("parse", 7));
+        storage.record_cpu_sample(0x104c, &location("parse", 7));
+        storage.flush_checkpoint_at(200).unwrap();
+
+        let count =
+            |sql: &str| -> i64 { storage.conn.query_row(sql, [], |row| row.get(0)).unwrap() };
+        assert_eq!(count("SELECT COUNT(*) FROM locations"), 2);
+        assert_eq!(
+            count(&format!(
+                "SELECT SUM(count) FROM cpu_samples WHERE location_id = {}",
+                parse
+            )),
+            6
+        );
+        let mut stmt = storage
+            .conn
+            .prepare("SELECT location_id, addr FROM location_addresses ORDER BY location_id, addr")
+            .unwrap();
+        let addrs: Vec<(i64, i64)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert_eq!(
+            addrs,
+            [
+                (parse, 0x1010),
+                (parse, 0x1024),
+                (parse, 0x1038),
+                (parse, 0x104c),
+                (emit, 0x2000)
+            ]
+        );
+
+        drop(stmt);
+        drop(storage);
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", path.display(), suffix)).ok();
+        }
+    }
+
+    #[test]
+    fn cpu_seconds_are_estimated_from_samples_and_frequency() {
+        let conn = cpu_profile(&[(1000, 297, 99)]);
+        assert_eq!(query_cpu_freq_hz(&conn), None);
+        for bad in ["0", "-99", "fast"] {
+            schema::set_meta(&conn, "cpu_freq_hz", bad).unwrap();
+            assert_eq!(query_cpu_freq_hz(&conn), None);
+        }
+        schema::set_meta(&conn, "cpu_freq_hz", "99").unwrap();
+        let freq = query_cpu_freq_hz(&conn).unwrap();
+        assert_eq!(freq, 99.0);
+
+        // 75% and 25% of the samples, but 3s and 1s of CPU either way
+        let entries = query_top_cpu(&conn, 10, 0.0).unwrap();
+        let secs: Vec<f64> = entries
+            .iter()
+            .map(|e| samples_to_secs(e.total_samples, freq))
+            .collect();
+        assert_eq!(secs, [3.0, 1.0]);
+        assert_eq!(samples_to_secs(1, 1000.0), 0.001);
+    }
+
+    #[test]
+    fn sample_gaps_span_only_empty_checkpoints() {
+        // Location 1 skips the checkpoints ending at 200 and 300; location 2
+        // is sampled in every checkpoint, however many samples each holds
+        let conn = cpu_profile(&[(100, 5, 1), (200, 0, 50), (300, 0, 1), (400, 5, 50)]);
+        let entries = query_sample_gaps(&conn, 10, 0.0).unwrap();
+
+        let gap_of = |id| {
+            let entry = entries.iter().find(|e| e.location_id == id).unwrap();
+            (entry.p50_ms, entry.p99_ms)
+        };
+        assert_eq!(gap_of(1), (200.0, 200.0));
+        assert_eq!(gap_of(2), (0.0, 0.0));
+    }
+
+    #[test]
+    fn bucket_avg_counts_checkpoints_without_the_location() {
+        // Location 1 holds 100% of one checkpoint and none of the next three
+        let conn = cpu_profile(&[(0, 10, 0), (100, 0, 10), (200, 0, 10), (300, 0, 10)]);
+        let query = |aggregate| {
+            query_cpu_timeseries_aggregated(&conn, 1, 0, 400, 1, CpuSeries::Instant, aggregate)
+        };
+
+        assert_eq!(query(BucketAggregate::Max), vec![(0.2, 100.0)]);
+        assert_eq!(query(BucketAggregate::Avg), vec![(0.2, 25.0)]);
+        assert_eq!(query(BucketAggregate::P95), vec![(0.2, 100.0)]);
+    }
+
+    #[test]
+    fn bucket_skips_checkpoints_without_samples() {
+        // The empty checkpoint at 100ms has no share to average in
+        let conn = cpu_profile(&[(0, 5, 5), (100, 0, 0), (200, 5, 5)]);
+        let points = query_cpu_timeseries_aggregated(
+            &conn,
+            1,
+            0,
+            300,
+            1,
+            CpuSeries::Instant,
+            BucketAggregate::Avg,
+        );
+        assert_eq!(points, vec![(0.15, 50.0)]);
+    }
+
+    #[test]
+    fn derived_series_collapse_like_instant() {
+        let conn = cpu_profile(&[(0, 10, 0), (100, 0, 10), (200, 0, 10), (300, 0, 10)]);
+        let points = query_cpu_timeseries_aggregated(
+            &conn,
+            1,
+            0,
+            400,
+            1,
+            CpuSeries::Cumulative,
+            BucketAggregate::Avg,
+        );
+        // Cumulative shares 100, 50, 33.3 and 25
+        let expected = (100.0 + 50.0 + 100.0 / 3.0 + 25.0) / 4.0;
+        assert_eq!(points.len(), 1);
+        assert!((points[0].1 - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn cumulative_and_running_average_series_of_a_known_function() {
+        // Location 1 takes every sample of the first checkpoint, none of the
+        // next eleven
+        let mut checkpoints = vec![(0, 10, 0)];
+        checkpoints.extend((1..12).map(|i| (i * 100, 0, 10)));
+        let conn = cpu_profile(&checkpoints);
+        // One checkpoint per bucket from 500ms; earlier ones still count
+        let values = |series| -> Vec<f64> {
+            query_cpu_timeseries_aggregated(&conn, 1, 500, 1200, 7, series, BucketAggregate::Max)
+                .iter()
+                .map(|&(_, pct)| pct)
+                .collect()
+        };
+
+        assert_eq!(values(CpuSeries::Instant), [0.0; 7]);
+        let cumulative: Vec<f64> = (5..12).map(|i| 100.0 / (i + 1) as f64).collect();
+        assert_eq!(values(CpuSeries::Cumulative), cumulative);
+        // The 100% checkpoint leaves the 10-checkpoint window at 1000ms
+        let average = [
+            100.0 / 6.0,
+            100.0 / 7.0,
+            100.0 / 8.0,
+            100.0 / 9.0,
+            10.0,
+            0.0,
+            0.0,
+        ];
+        assert_eq!(values(CpuSeries::RunningAverage), average);
+    }
+
+    #[test]
+    fn backward_clock_steps_are_repaired_once() {
+        // The clock went back 100ms before the third checkpoint
+        let mut conn = cpu_profile(&[(100, 1, 1), (300, 3, 1), (200, 1, 3), (400, 1, 1)]);
+        let timestamps = |conn: &Connection| -> Vec<i64> {
+            query_cpu_timeseries(conn, 1)
+                .unwrap()
+                .iter()
+                .map(|p| p.timestamp_ms)
+                .collect()
+        };
+        assert_eq!(timestamps(&conn), [100, 200, 300, 400]);
+
+        assert_eq!(repair_clock_skew(&mut conn).unwrap(), 1);
+        assert_eq!(timestamps(&conn), [100, 300, 300, 500]);
+        assert_eq!(
+            schema::get_meta(&conn, META_CLOCK_SKEW_STEPS).unwrap(),
+            Some("1".to_string())
+        );
+        assert_eq!(repair_clock_skew(&mut conn).unwrap(), 0);
+
+        // Both checkpoints now at 300ms (75% and 25%) share a chart bucket
+        let points = query_cpu_timeseries_aggregated(
+            &conn,
+            1,
+            0,
+            500,
+            5,
+            CpuSeries::Instant,
+            BucketAggregate::Avg,
+        );
+        assert_eq!(points, vec![(0.15, 50.0), (0.35, 50.0)]);
+    }
+
+    /// `EXPLAIN QUERY PLAN` detail lines for a query on a fresh profile
+    fn query_plan(sql: &str) -> Vec<String> {
+        let conn = Connection::open_in_memory().unwrap();
+        schema::create_tables(&conn).unwrap();
+        let mut stmt = conn.prepare(&format!("EXPLAIN QUERY PLAN {sql}")).unwrap();
+        let nulls = vec![rusqlite::types::Null; stmt.parameter_count()];
+        stmt.query_map(rusqlite::params_from_iter(nulls), |row| {
+            row.get::<_, String>(3)
+        })
+        .unwrap()
+        .map(|detail| detail.unwrap())
+        .collect()
+    }
+
+    fn assert_plan(sql: &str, uses: &[&str]) {
+        let plan = query_plan(sql);
+        // Walking a whole index in order, or a subquery's rows, is fine;
+        // reading a whole table is not
+        let table_scan = |step: &String| {
+            step.starts_with("SCAN ") && !step.starts_with("SCAN (") && !step.contains(" INDEX ")
+        };
+        assert!(!plan.iter().any(table_scan), "table scan in {plan:?}");
+        for index in uses {
+            assert!(
+                plan.iter().any(|step| step.contains(index)),
+                "{index} unused in {plan:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn sparkline_queries_use_indexes() {
+        assert_plan(LAST_CHECKPOINTS_SQL, &["idx_checkpoints_time"]);
+        assert_plan(
+            &heap_column_sql("live_bytes", 3, 0),
+            &["sqlite_autoindex_heap_samples_1 (checkpoint_id=?)"],
+        );
+        assert_plan(
+            &heap_column_sql("live_bytes", 3, 2),
+            &["sqlite_autoindex_heap_samples_1 (checkpoint_id=? AND location_id=?)"],
+        );
+    }
+
+    #[test]
+    fn timeseries_queries_use_indexes() {
+        for aggregate in [
+            BucketAggregate::Max,
+            BucketAggregate::Avg,
+            BucketAggregate::P95,
+        ] {
+            assert_plan(
+                &cpu_timeseries_sql(aggregate),
+                &[
+                    "idx_checkpoints_time (timestamp_ms>? AND timestamp_ms<?)",
+                    "sqlite_autoindex_cpu_samples_1 (checkpoint_id=? AND location_id=?)",
+                ],
+            );
+            assert_plan(
+                &heap_timeseries_sql(aggregate),
+                &["SEARCH hs", "SEARCH c USING INTEGER PRIMARY KEY"],
+            );
+        }
+    }
+
+    #[test]
+    fn p95_takes_nearest_rank() {
+        let mut values: Vec<f64> = (1..=20).map(f64::from).rev().collect();
+        assert_eq!(BucketAggregate::P95.collapse(&mut values), 19.0);
+        assert_eq!(BucketAggregate::P95.collapse(&mut [3.0]), 3.0);
+        assert_eq!(BucketAggregate::Avg.collapse(&mut []), 0.0);
+    }
+}
@@ -0,0 +1,401 @@
+//! Attribution heuristics: map a sampled stack to the user code responsible.
+//!
+//! Allocator, std and profiler frames are skipped so samples land on the
+//! first frame the user wrote (or its caller, for small utility functions).
+
+use super::{Location, SymbolResolver};
+
+/// Which frame of a sampled stack gets the sample (`--attribute`)
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Attribution {
+    /// The frame that was executing, even inside std or the allocator (e.g. a sort loop)
+    Leaf,
+    /// The nearest user frame, skipping allocator, std and profiler frames
+    #[default]
+    User,
+}
+
+impl Attribution {
+    /// Name as stored in the profile meta
+    pub fn name(self) -> &'static str {
+        match self {
+            Attribution::Leaf => "leaf",
+            Attribution::User => "user",
+        }
+    }
+
+    /// Attribute a stack to the frame this policy credits
+    pub fn attribute(self, stack: &[u64], resolver: &SymbolResolver) -> Location {
+        match self {
+            Attribution::Leaf => resolve_internal_stack(stack, resolver),
+            Attribution::User => find_user_frame(stack, resolver),
+        }
+    }
+}
+
+/// Patterns for internal/profiler functions to skip
+const SKIP_FUNCTION_PATTERNS: &[&str] = &[
+    // Rust allocator entry points
+    "__rust_alloc",
+    "__rust_dealloc",
+    "__rust_realloc",
+    "__rustc",
+    // Rust alloc crate internals
+    "alloc::alloc::",
+    "alloc::raw_vec::",
+    "alloc::vec::",
+    "alloc::string::",
+    "alloc::collections::",
+    "<alloc::",
+    "alloc::fmt::",
+    "alloc::ffi::", // format! and CString internals
+    // Hashmap/collections internals
+    "hashbrown::",
+    "std::collections::hash",
+    // Core library internals
+    "core::ptr::",
+    "core::slice::",
+    "core::iter::",
+    "core::sync::", // atomics, etc.
+    "core::option::",
+    "core::result::",
+    "<core::",
+    "core::ops::function::",
+    "core::ops::drop::",
+    "core::ffi::",
+    "core::fmt::",
+    "core::num::",
+    "core::str::",
+    "core::hash::",
+    "core::mem::",
+    // Std library internals
+    "std::io::",
+    "std::fmt::",
+    "std::sys::",
+    "std::thread::",
+    "std::sync::",
+    "<std::",
+    "fmt::num::",
+    "fmt::Write::",
+    // Trait implementations (raw DWARF names)
+    " as core::fmt::",  // <T as core::fmt::Display>::fmt
+    " as std::fmt::",   // <T as std::fmt::Write>::write
+    " as core::hash::", // <T as core::hash::Hash>::hash
+    " as alloc::",      // <T as alloc::*>::method
+    // Trait implementations on generic types
+    "<_>::", // any method on trait objects
+    // Libc functions
+    "malloc",
+    "calloc",
+    "realloc",
+    "free",
+    "memcpy",
+    "memmove",
+    "memset",
+    "memchr",
+    "_start",
+    "__libc_start_main",
+    // Exception/unwinding
+    "_Unwind_",
+    "__cxa_",
+    "_fini",
+    "_init",
+    "rust_eh_personality",
+    // Profiler internals (rsprof-trace)
+    "addr2line::",
+    "gimli::",
+    "object::",
+    "miniz_oxide::",
+    "rustc_demangle::",
+    "rsprof_alloc::",
+    "rsprof_trace::",
+    "profiling::",
+    "rsprof::",
+    // Sorting internals
+    "sort::shared::smallsort::",
+    // Generic patterns for generated code
+    "::{{closure}}", // closures attributed to parent
+];
+
+/// Allocator entry points and internals (substring match)
+const ALLOCATOR_FUNCTION_PATTERNS: &[&str] = &[
+    "__rust_alloc",
+    "__rust_dealloc",
+    "__rust_realloc",
+    "__rdl_",
+    "alloc::alloc::",
+    "alloc::raw_vec::finish_grow",
+    "core::alloc::global::GlobalAlloc", // <A as GlobalAlloc>, impl GlobalAlloc for A
+];
+
+/// libc allocator functions (exact match: names like "free" are too short
+/// to search for)
+const LIBC_ALLOCATOR_FUNCTIONS: &[&str] = &[
+    "malloc",
+    "calloc",
+    "realloc",
+    "free",
+    "cfree",
+    "posix_memalign",
+    "aligned_alloc",
+    "__libc_malloc",
+    "__libc_calloc",
+    "__libc_realloc",
+    "__libc_free",
+    "_int_malloc",
+    "_int_free",
+    "_int_realloc",
+    "malloc_consolidate",
+];
+
+/// Check if a function belongs to the allocator
+fn is_allocator_function(func: &str) -> bool {
+    LIBC_ALLOCATOR_FUNCTIONS.contains(&func)
+        || ALLOCATOR_FUNCTION_PATTERNS.iter().any(|p| func.contains(p))
+}
+
+/// Check if a file path looks like internal/library code
+fn is_internal_file(file: &str) -> bool {
+    file.is_empty()
+        || file.starts_with('[')
+        || file.starts_with('<')
+        || file.contains("/rustc/")
+        || file.contains("/.cargo/registry/")
+        || file.contains("/rust/library/")
+        || file.contains("rsprof-alloc")
+        || file.contains("rsprof-trace")
+        || file.contains("profiling.rs")
+        || file == "lib.rs"
+        || file == "time.rs"
+        || file == "unix.rs"
+        || file.ends_with("memchr.rs")
+        || file.ends_with("maybe_uninit.rs")
+        || file.ends_with("methods.rs")
+        || (file.ends_with("mod.rs") && !file.contains("/src/"))
+}
+
+/// Check if a location is internal (profiler/library code)
+pub fn is_internal_location(loc: &Location) -> bool {
+    if is_internal_file(&loc.file) {
+        return true;
+    }
+    SKIP_FUNCTION_PATTERNS
+        .iter()
+        .any(|p| loc.function.contains(p))
+}
+
+/// Patterns for utility functions that should be attributed to their callers
+const UTILITY_PATTERNS: &[&str] = &[
+    // Derived trait methods - attribute to caller
+    ">::clone",       // Clone::clone on any type
+    ">::fmt",         // Debug/Display::fmt
+    ">::hash",        // Hash::hash
+    ">::eq",          // PartialEq::eq
+    ">::partial_cmp", // PartialOrd
+    ">::cmp",         // Ord
+    // Common utility functions
+    "::utils::",
+    "::to_string",
+    "::to_owned",
+    "::into",
+    "format_bytes",
+    "format_size",
+    "sanitize_",
+    "generate_trace_id",
+];
+
+/// Check if a function is a utility function (should attribute to caller)
+fn is_utility_function(func: &str) -> bool {
+    UTILITY_PATTERNS.iter().any(|p| func.contains(p))
+}
+
+/// Find the first "user" frame in a stack trace (not allocator internals)
+/// If the first user frame is a utility function, return its caller instead.
+pub fn find_user_frame(stack: &[u64], resolver: &SymbolResolver) -> Location {
+    let mut first_user_frame: Option<Location> = None;
+    let mut first_user_idx: Option<usize> = None;
+
+    // FIRST PASS: Find the first user frame
+    for (i, &addr) in stack.iter().enumerate() {
+        let loc = resolver.resolve(addr);
+        // Skip internal functions based on name patterns
+        let has_internal_fn = SKIP_FUNCTION_PATTERNS
+            .iter()
+            .any(|p| loc.function.contains(p));
+        if !has_internal_fn
+            && !is_internal_file(&loc.file)
+            && !loc.function.is_empty()
+            && loc.function != "[unknown]"
+        {
+            first_user_frame = Some(loc);
+            first_user_idx = Some(i);
+            break;
+        }
+    }
+
+    // SECOND PASS: If first user frame is a utility function, find its caller
+    if let (Some(first), Some(idx)) = (&first_user_frame, first_user_idx) {
+        if is_utility_function(&first.function) {
+            // Look for the caller (next frame that's not internal)
+            for &addr in stack.iter().skip(idx + 1) {
+                let loc = resolver.resolve(addr);
+                let has_internal_fn = SKIP_FUNCTION_PATTERNS
+                    .iter()
+                    .any(|p| loc.function.contains(p));
+                if !has_internal_fn && !loc.function.is_empty() && loc.function != "[unknown]" {
+                    return loc;
+                }
+            }
+        }
+        return first_user_frame.unwrap();
+    }
+
+    // Fallback: look for frames with real source paths
+    for &addr in stack {
+        let loc = resolver.resolve(addr);
+        if !is_internal_file(&loc.file) && !is_internal_location(&loc) {
+            return loc;
+        }
+    }
+
+    // No user frame found - return a marker that will be filtered out
+    // by is_internal_location (internal file)
+    Location {
+        file: "[internal]".to_string(),
+        line: 0,
+        column: 0,
+        function: "[internal]".to_string(),
+    }
+}
+
+/// Frames of a sampled stack from the credited `location` outward (callers last)
+///
+/// Starts at the first frame resolving to `location` and keeps the callers
+/// a user would recognise, skipping internal and unresolved frames. Empty
+/// when `location` is not on the stack.
+pub fn user_stack(stack: &[u64], resolver: &SymbolResolver, location: &Location) -> Vec<Location> {
+    let mut frames = stack
+        .iter()
+        .filter(|&&addr| addr != 0)
+        .map(|&addr| resolver.resolve(addr))
+        .skip_while(|loc| {
+            loc.file != location.file
+                || loc.line != location.line
+                || loc.function != location.function
+        });
+    let Some(first) = frames.next() else {
+        return Vec::new();
+    };
+
+    let mut result = vec![first];
+    result.extend(frames.filter(|loc| {
+        !is_internal_location(loc) && !loc.function.is_empty() && loc.function != "[unknown]"
+    }));
+    result
+}
+
+/// Check if a sampled stack was inside the allocator on behalf of its user frame
+///
+/// Walks from the leaf until the first user frame; any allocator frame on
+/// the way means the sample is allocation cost of that frame. Frames are
+/// matched by symbol name since `resolve` hides std and allocator code.
+pub fn in_allocator(stack: &[u64], resolver: &SymbolResolver) -> bool {
+    for &addr in stack {
+        if resolver
+            .function_name(addr)
+            .is_some_and(|name| is_allocator_function(&name))
+        {
+            return true;
+        }
+        let loc = resolver.resolve(addr);
+        if !is_internal_location(&loc) && !loc.function.is_empty() && loc.function != "[unknown]" {
+            return false;
+        }
+    }
+    false
+}
+
+/// Resolve the first meaningful frame without skipping internals (--include-internal)
+pub fn resolve_internal_stack(stack: &[u64], resolver: &SymbolResolver) -> Location {
+    for &addr in stack {
+        if addr == 0 {
+            continue;
+        }
+        let loc = resolver.resolve(addr);
+        if loc.function != "_fini" && loc.function != "[unknown]" {
+            return loc;
+        }
+    }
+    Location::unknown()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(file: &str, function: &str) -> Location {
+        Location {
+            file: file.to_string(),
+            line: 1,
+            column: 0,
+            function: function.to_string(),
+        }
+    }
+
+    #[test]
+    fn option_result_and_atomic_combinators_are_internal() {
+        for function in [
+            "core::option::Option<T>::map",
+            "core::result::Result<T,E>::and_then",
+            "core::sync::atomic::AtomicUsize::fetch_add",
+        ] {
+            assert!(
+                is_internal_location(&location("/home/me/app/src/main.rs", function)),
+                "{function}"
+            );
+        }
+        assert!(!is_internal_location(&location(
+            "/home/me/app/src/main.rs",
+            "app::handle_request"
+        )));
+    }
+
+    #[test]
+    fn allocator_frames_are_recognized_by_name() {
+        for function in [
+            "malloc",
+            "_int_free",
+            "__rust_alloc",
+            "alloc::raw_vec::finish_grow",
+            "<rsprof_trace::ProfilingAllocator as core::alloc::global::GlobalAlloc>::alloc",
+        ] {
+            assert!(is_allocator_function(function), "{function}");
+        }
+        // libc names match exactly, not as substrings of user functions
+        assert!(!is_allocator_function("app::free_list::push"));
+        assert!(!is_allocator_function("app::parse"));
+    }
+
+    #[test]
+    fn leaf_credits_memcpy_and_user_credits_its_caller() {
+        let resolver = SymbolResolver::from_symbols(
+            &[
+                (0x1000, "memcpy"),
+                (0x2000, "app::copy_frame"),
+                (0x3000, "app::main"),
+            ],
+            &[
+                (0x1000, 0x1100, "[libc]", 1),
+                (0x2000, 0x2100, "/home/me/app/src/frame.rs", 1),
+                (0x3000, 0x3100, "/home/me/app/src/main.rs", 1),
+            ],
+        );
+        let stack = [0x1010, 0x2010, 0x3010];
+
+        let leaf = Attribution::Leaf.attribute(&stack, &resolver);
+        assert_eq!(leaf.function, "memcpy");
+        let user = Attribution::User.attribute(&stack, &resolver);
+        assert_eq!(user.function, "app::copy_frame");
+        assert_eq!(user.file, "src/frame.rs");
+    }
+}
@@ -0,0 +1,79 @@
+//! Separate debug info from debuginfod servers
+//!
+//! Servers are taken from `DEBUGINFOD_URLS` (space separated) and files are
+//! kept in the same cache as elfutils' client (`DEBUGINFOD_CACHE_PATH`, else
+//! `$XDG_CACHE_HOME/debuginfod_client`), so a file fetched by gdb or perf is
+//! not downloaded again.
+
+use super::dwarf::read_build_id;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Path of the debug file for `build_id`, downloading it if not cached
+///
+/// `None` if no server is configured or none of them has the file.
+pub(crate) fn fetch_debug_file(build_id: &str) -> Option<PathBuf> {
+    let urls = std::env::var("DEBUGINFOD_URLS").ok()?;
+    let dir = cache_dir()?.join(build_id);
+    let path = dir.join("debuginfo");
+    if read_build_id(&path).as_deref() == Some(build_id) {
+        log::info!("debuginfod_cached path={}", path.display());
+        return Some(path);
+    }
+
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout_read(READ_TIMEOUT)
+        .build();
+    for server in urls.split_whitespace() {
+        let url = format!(
+            "{}/buildid/{}/debuginfo",
+            server.trim_end_matches('/'),
+            build_id
+        );
+        eprintln!("Fetching debug info from {}", server);
+        match download(&agent, &url, build_id, &dir, &path) {
+            Ok(()) => {
+                log::info!("debuginfod_fetched url={} path={}", url, path.display());
+                return Some(path);
+            }
+            Err(e) => log::warn!("debuginfod_failed server={} error={}", server, e),
+        }
+    }
+    None
+}
+
+/// Download `url` to `path`, keeping it only if its build-id matches
+fn download(
+    agent: &ureq::Agent,
+    url: &str,
+    build_id: &str,
+    dir: &Path,
+    path: &Path,
+) -> io::Result<()> {
+    let response = agent.get(url).call().map_err(io::Error::other)?;
+    fs::create_dir_all(dir)?;
+    let partial = dir.join(".debuginfo.partial");
+    io::copy(&mut response.into_reader(), &mut File::create(&partial)?)?;
+    if read_build_id(&partial).as_deref() != Some(build_id) {
+        let _ = fs::remove_file(&partial);
+        return Err(io::Error::other("build-id mismatch"));
+    }
+    fs::rename(&partial, path)
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("DEBUGINFOD_CACHE_PATH") {
+        return Some(PathBuf::from(path));
+    }
+    let cache = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(cache.join("debuginfod_client"))
+}
@@ -0,0 +1,375 @@
+//! Deferred symbolication for recordings made with `--no-symbols` or
+//! `--defer-heap-symbols`.
+//!
+//! The recorder skips DWARF loading (or, for heap stacks only, skips
+//! resolving them) and stores each sampled stack as a placeholder location
+//! (file `[raw]`, function `0x..;0x..`), one per distinct stack. When the
+//! profile is opened by `view`, `top` and the other reading commands, the
+//! placeholders are resolved against the binary and merged into regular
+//! locations in an in-memory copy; `rsprof symbolicate` does the same to
+//! the file itself.
+
+use super::attribution::{Attribution, in_allocator, is_internal_location, user_stack};
+use super::{Location, SymbolResolver, read_build_id};
+use crate::error::{Error, Result};
+use crate::storage::{
+    get_meta, query_heap_sizes, set_meta, stack_hash, upgrade_tables, write_heap_sizes,
+};
+use rusqlite::Connection;
+use rusqlite::DatabaseName;
+use rusqlite::backup::Progress;
+use std::path::Path;
+
+/// File name used for placeholder locations holding raw addresses
+pub const RAW_FILE: &str = "[raw]";
+
+/// Meta key recording whether symbolication is still pending
+pub const META_SYMBOLS: &str = "symbols";
+
+/// Build a placeholder location for an unresolved stack (innermost frame first)
+pub fn raw_location(stack: &[u64]) -> Location {
+    let function = stack
+        .iter()
+        .filter(|&&addr| addr != 0)
+        .map(|addr| format!("0x{:x}", addr))
+        .collect::<Vec<_>>()
+        .join(";");
+
+    Location {
+        file: RAW_FILE.to_string(),
+        line: 0,
+        column: 0,
+        function,
+    }
+}
+
+/// Parse the stack stored in a placeholder location's function name
+pub fn parse_raw_stack(function: &str) -> Vec<u64> {
+    function
+        .split(';')
+        .filter_map(|s| u64::from_str_radix(s.trim_start_matches("0x"), 16).ok())
+        .collect()
+}
+
+/// Check if the profile still holds placeholder locations
+pub fn is_deferred(conn: &Connection) -> Result<bool> {
+    Ok(get_meta(conn, META_SYMBOLS)?.as_deref() == Some("deferred"))
+}
+
+/// Open a profile for reading, resolving placeholder locations in memory
+///
+/// A deferred profile is copied into an in-memory database and resolved
+/// there, so reading it never rewrites the file (`rsprof symbolicate` does
+/// that once, to skip the work on later opens). Other profiles are opened
+/// as they are.
+pub fn open_resolved(file: &Path) -> Result<Connection> {
+    let conn = Connection::open(file)?;
+    if !is_deferred(&conn)? {
+        return Ok(conn);
+    }
+    drop(conn);
+
+    let mut memory = Connection::open_in_memory()?;
+    memory.restore(DatabaseName::Main, file, None::<fn(Progress)>)?;
+    symbolicate_if_deferred(&mut memory, None)?;
+    Ok(memory)
+}
+
+/// Resolve placeholder locations if the profile was recorded with `--no-symbols`
+/// or `--defer-heap-symbols`
+///
+/// `aslr_offset` overrides the load address recorded with the profile,
+/// which is missing when the target's memory maps couldn't be read.
+/// Returns the number of placeholder locations that were resolved.
+pub fn symbolicate_if_deferred(conn: &mut Connection, aslr_offset: Option<u64>) -> Result<usize> {
+    if !is_deferred(conn)? {
+        return Ok(0);
+    }
+
+    let exe_path = get_meta(conn, "exe_path")?
+        .ok_or_else(|| Error::SymbolResolution("Profile has no exe_path".to_string()))?;
+    let exe_path = Path::new(&exe_path);
+    let aslr_offset = match aslr_offset {
+        Some(offset) => offset,
+        None => get_meta(conn, "aslr_offset")?
+            .and_then(|v| v.parse::<u64>().ok())
+            .ok_or_else(|| {
+                Error::SymbolResolution(
+                    "Profile has no aslr_offset (the target's memory maps weren't readable); \
+                     pass --aslr-offset"
+                        .to_string(),
+                )
+            })?,
+    };
+    // Older profiles stored include_internal=1 for leaf attribution
+    let attribution = match get_meta(conn, "attribution")?.as_deref() {
+        Some("leaf") => Attribution::Leaf,
+        Some(_) => Attribution::User,
+        None if get_meta(conn, "include_internal")?.as_deref() == Some("1") => Attribution::Leaf,
+        None => Attribution::User,
+    };
+
+    // Refuse to resolve against a different build than the one recorded
+    if let Some(recorded) = get_meta(conn, "build_id")?
+        && read_build_id(exe_path).as_deref() != Some(recorded.as_str())
+    {
+        return Err(Error::SymbolResolution(format!(
+            "{} has changed since recording (build-id {} no longer matches)",
+            exe_path.display(),
+            recorded
+        )));
+    }
+
+    let resolver = SymbolResolver::for_binary(exe_path, exe_path, Path::new("/"), aslr_offset)?;
+
+    let raw: Vec<(i64, String)> = {
+        let mut stmt = conn.prepare("SELECT id, function FROM locations WHERE file = ?")?;
+        stmt.query_map([RAW_FILE], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?
+    };
+
+    // Profiles from older versions lack location_addresses, heap_sizes, cpu_counters,
+    // heap_transfers, cpu_process_samples, frames, heap_stack_samples, off_cpu_samples
+    // and allocator_count
+    upgrade_tables(conn)?;
+
+    let tx = conn.transaction()?;
+    for (raw_id, function) in &raw {
+        let stack = parse_raw_stack(function);
+        let location = attribution.attribute(&stack, &resolver);
+
+        if attribution == Attribution::Leaf || !is_internal_location(&location) {
+            let id = location_id(&tx, &location)?;
+
+            // Several raw stacks usually resolve to the same location: sum them
+            let allocator = in_allocator(&stack, &resolver);
+            tx.execute(
+                "INSERT INTO cpu_samples (checkpoint_id, location_id, count, allocator_count)
+                 SELECT checkpoint_id, ?1, count, CASE WHEN ?3 THEN count ELSE 0 END
+                 FROM cpu_samples WHERE location_id = ?2
+                 ON CONFLICT(checkpoint_id, location_id) DO UPDATE SET
+                    count = count + excluded.count,
+                    allocator_count = allocator_count + excluded.allocator_count",
+                rusqlite::params![id, *raw_id, allocator],
+            )?;
+            tx.execute(
+                "INSERT INTO heap_samples
+                    (checkpoint_id, location_id, alloc_bytes, free_bytes, live_bytes,
+                     alloc_count, free_count)
+                 SELECT checkpoint_id, ?1, alloc_bytes, free_bytes, live_bytes,
+                        alloc_count, free_count
+                 FROM heap_samples WHERE location_id = ?2
+                 ON CONFLICT(checkpoint_id, location_id) DO UPDATE SET
+                    alloc_bytes = alloc_bytes + excluded.alloc_bytes,
+                    free_bytes = free_bytes + excluded.free_bytes,
+                    live_bytes = live_bytes + excluded.live_bytes,
+                    alloc_count = alloc_count + excluded.alloc_count,
+                    free_count = free_count + excluded.free_count",
+                [id, *raw_id],
+            )?;
+            // The raw stack holds the callers heap_samples collapsed away
+            let frames = user_stack(&stack, &resolver, &location)
+                .iter()
+                .map(|frame| location_id(&tx, frame))
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            if !frames.is_empty() {
+                let hash = stack_hash(&frames);
+                for (depth, frame_id) in frames.iter().enumerate() {
+                    tx.execute(
+                        "INSERT OR IGNORE INTO frames (stack_hash, depth, location_id)
+                         VALUES (?, ?, ?)",
+                        rusqlite::params![hash, depth as i64, frame_id],
+                    )?;
+                }
+                tx.execute(
+                    "INSERT INTO heap_stack_samples
+                        (checkpoint_id, stack_hash, alloc_bytes, free_bytes, live_bytes,
+                         alloc_count, free_count)
+                     SELECT checkpoint_id, ?1, alloc_bytes, free_bytes, live_bytes,
+                            alloc_count, free_count
+                     FROM heap_samples WHERE location_id = ?2
+                     ON CONFLICT(checkpoint_id, stack_hash) DO UPDATE SET
+                        alloc_bytes = alloc_bytes + excluded.alloc_bytes,
+                        free_bytes = free_bytes + excluded.free_bytes,
+                        live_bytes = live_bytes + excluded.live_bytes,
+                        alloc_count = alloc_count + excluded.alloc_count,
+                        free_count = free_count + excluded.free_count",
+                    rusqlite::params![hash, *raw_id],
+                )?;
+            }
+            tx.execute(
+                "INSERT INTO cpu_counters (checkpoint_id, location_id, counter, value)
+                 SELECT checkpoint_id, ?1, counter, value FROM cpu_counters WHERE location_id = ?2
+                 ON CONFLICT(checkpoint_id, location_id, counter)
+                 DO UPDATE SET value = value + excluded.value",
+                [id, *raw_id],
+            )?;
+            tx.execute(
+                "INSERT INTO cpu_process_samples (checkpoint_id, process_id, location_id, count)
+                 SELECT checkpoint_id, process_id, ?1, count
+                 FROM cpu_process_samples WHERE location_id = ?2
+                 ON CONFLICT(checkpoint_id, process_id, location_id)
+                 DO UPDATE SET count = count + excluded.count",
+                [id, *raw_id],
+            )?;
+            tx.execute(
+                "INSERT INTO off_cpu_samples (checkpoint_id, location_id, count, blocked_ns)
+                 SELECT checkpoint_id, ?1, count, blocked_ns
+                 FROM off_cpu_samples WHERE location_id = ?2
+                 ON CONFLICT(checkpoint_id, location_id) DO UPDATE SET
+                    count = count + excluded.count,
+                    blocked_ns = blocked_ns + excluded.blocked_ns",
+                [id, *raw_id],
+            )?;
+            tx.execute(
+                "INSERT OR IGNORE INTO location_addresses (location_id, addr)
+                 SELECT ?1, addr FROM location_addresses WHERE location_id = ?2",
+                [id, *raw_id],
+            )?;
+            // Either end of a transfer may be this stack (the other end is
+            // remapped on its own turn)
+            tx.execute(
+                "INSERT INTO heap_transfers (alloc_location_id, free_location_id, count, bytes)
+                 SELECT ?1, free_location_id, count, bytes
+                 FROM heap_transfers WHERE alloc_location_id = ?2
+                 ON CONFLICT(alloc_location_id, free_location_id) DO UPDATE SET
+                    count = count + excluded.count,
+                    bytes = bytes + excluded.bytes",
+                [id, *raw_id],
+            )?;
+            tx.execute(
+                "INSERT INTO heap_transfers (alloc_location_id, free_location_id, count, bytes)
+                 SELECT alloc_location_id, ?1, count, bytes
+                 FROM heap_transfers WHERE free_location_id = ?2
+                 ON CONFLICT(alloc_location_id, free_location_id) DO UPDATE SET
+                    count = count + excluded.count,
+                    bytes = bytes + excluded.bytes",
+                [id, *raw_id],
+            )?;
+            if let Some(raw_sizes) = query_heap_sizes(&tx, *raw_id) {
+                let mut sizes = query_heap_sizes(&tx, id).unwrap_or_default();
+                sizes.merge(&raw_sizes);
+                write_heap_sizes(&tx, id, &sizes)?;
+            }
+        }
+
+        tx.execute(
+            "DELETE FROM location_addresses WHERE location_id = ?",
+            [raw_id],
+        )?;
+        tx.execute("DELETE FROM heap_sizes WHERE location_id = ?", [raw_id])?;
+        tx.execute(
+            "DELETE FROM heap_transfers WHERE alloc_location_id = ?1 OR free_location_id = ?1",
+            [raw_id],
+        )?;
+        tx.execute("DELETE FROM cpu_samples WHERE location_id = ?", [raw_id])?;
+        tx.execute("DELETE FROM cpu_counters WHERE location_id = ?", [raw_id])?;
+        tx.execute(
+            "DELETE FROM cpu_process_samples WHERE location_id = ?",
+            [raw_id],
+        )?;
+        tx.execute("DELETE FROM heap_samples WHERE location_id = ?", [raw_id])?;
+        tx.execute(
+            "DELETE FROM off_cpu_samples WHERE location_id = ?",
+            [raw_id],
+        )?;
+        tx.execute("DELETE FROM locations WHERE id = ?", [raw_id])?;
+    }
+    set_meta(&tx, META_SYMBOLS, "resolved")?;
+    tx.commit()?;
+
+    Ok(raw.len())
+}
+
+/// Id of a resolved location, adding it if new
+fn location_id(conn: &Connection, location: &Location) -> rusqlite::Result<i64> {
+    conn.execute(
+        "INSERT OR IGNORE INTO locations (file, line, function) VALUES (?, ?, ?)",
+        rusqlite::params![&location.file, location.line as i64, &location.function],
+    )?;
+    conn.query_row(
+        "SELECT id FROM locations WHERE file = ? AND line = ? AND function = ?",
+        rusqlite::params![&location.file, location.line as i64, &location.function],
+        |row| row.get(0),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Storage, query_top_heap_live};
+
+    #[inline(never)]
+    fn allocating_leaf() -> u32 {
+        std::hint::black_box(11)
+    }
+
+    #[inline(never)]
+    fn allocating_caller() -> u32 {
+        std::hint::black_box(13)
+    }
+
+    #[test]
+    fn deferred_heap_stacks_resolve_like_eager_ones() {
+        let exe = std::env::current_exe().unwrap();
+        let aslr_offset = crate::process::MemoryMaps::for_pid(std::process::id())
+            .unwrap()
+            .aslr_offset(&exe)
+            .unwrap();
+        let leaf = allocating_leaf as *const () as u64;
+        let caller = allocating_caller as *const () as u64;
+        let stacks = [[leaf, caller], [leaf, caller + 4]];
+
+        // A --defer-heap-symbols recording: one raw location per distinct stack
+        let path =
+            std::env::temp_dir().join(format!("rsprof-defer-heap-{}.db", std::process::id()));
+        let mut storage = Storage::create(&path).unwrap();
+        storage
+            .set_meta("exe_path", &exe.to_string_lossy())
+            .unwrap();
+        storage
+            .set_meta("aslr_offset", &aslr_offset.to_string())
+            .unwrap();
+        storage.set_meta("attribution", "leaf").unwrap();
+        storage.set_meta(META_SYMBOLS, "deferred").unwrap();
+        for (stack, live) in stacks.iter().zip([300, 200]) {
+            storage.record_heap_sample(&raw_location(stack), live, 0, live, 3, 0);
+        }
+        storage.flush_checkpoint_at(1000).unwrap();
+        drop(storage);
+
+        let raw: i64 = Connection::open(&path)
+            .unwrap()
+            .query_row(
+                "SELECT COUNT(*) FROM locations WHERE file = ?",
+                [RAW_FILE],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(raw, 2);
+
+        // What the recorder would have stored resolving each stack eagerly
+        let resolver = SymbolResolver::for_binary(&exe, &exe, Path::new("/"), aslr_offset).unwrap();
+        let eager: Vec<Location> = stacks
+            .iter()
+            .map(|stack| Attribution::Leaf.attribute(stack, &resolver))
+            .collect();
+        assert_eq!(eager[0].as_file_line(), eager[1].as_file_line());
+        assert!(eager[0].function.contains("allocating_leaf"));
+
+        let conn = open_resolved(&path).unwrap();
+        let entries = query_top_heap_live(&conn, 10).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            (&entries[0].file, entries[0].line, &entries[0].function),
+            (&eager[0].file, eager[0].line, &eager[0].function)
+        );
+        assert_eq!(entries[0].live_bytes, 500);
+        assert_eq!(entries[0].alloc_count, 6);
+
+        drop(conn);
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", path.display(), suffix)).ok();
+        }
+    }
+}
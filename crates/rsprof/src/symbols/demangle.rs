@@ -0,0 +1,28 @@
+//! Symbol name demangling
+
+/// Demangle a linker symbol name
+///
+/// Rust names (legacy and v0) first, then, with the `cpp-demangle` feature,
+/// Itanium C++ names of mixed Rust/C++ binaries (without their parameter
+/// lists, like Rust names). Anything else is returned as is.
+pub(crate) fn demangle(name: &str) -> String {
+    if let Ok(demangled) = rustc_demangle::try_demangle(name) {
+        return demangled.to_string();
+    }
+    #[cfg(feature = "cpp-demangle")]
+    if let Some(demangled) = demangle_cpp(name) {
+        return demangled;
+    }
+    name.to_string()
+}
+
+#[cfg(feature = "cpp-demangle")]
+fn demangle_cpp(name: &str) -> Option<String> {
+    if !name.starts_with("_Z") {
+        return None;
+    }
+    let symbol = cpp_demangle::Symbol::new(name).ok()?;
+    symbol
+        .demangle(&cpp_demangle::DemangleOptions::new().no_params())
+        .ok()
+}
@@ -1,10 +1,12 @@
 use crate::error::{Error, Result};
+use crate::process::path_in_root;
 use gimli::{EndianSlice, RunTimeEndian};
 use object::{Object, ObjectSection};
 use std::collections::HashMap;
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 /// Parsed DWARF debug information
 pub struct DwarfInfo {
@@ -26,9 +28,30 @@ pub struct AddressRange {
     pub column: u32,
 }
 
+/// Set from a Ctrl-C handler to abandon a slow DWARF load
+static CANCEL_LOADING: AtomicBool = AtomicBool::new(false);
+
+/// Make any DWARF parse in progress (and later ones) fail with
+/// `Error::Interrupted` at the next symbol or compilation unit
+pub fn cancel_loading() {
+    CANCEL_LOADING.store(true, Ordering::Relaxed);
+}
+
+fn check_cancelled(cancel: &AtomicBool) -> Result<()> {
+    if cancel.load(Ordering::Relaxed) {
+        return Err(Error::Interrupted);
+    }
+    Ok(())
+}
+
 impl DwarfInfo {
     /// Parse DWARF info from an ELF file
     pub fn parse(path: &Path) -> Result<Self> {
+        Self::parse_cancellable(path, &CANCEL_LOADING)
+    }
+
+    /// `parse`, giving up with `Error::Interrupted` once `cancel` is set
+    fn parse_cancellable(path: &Path, cancel: &AtomicBool) -> Result<Self> {
         let file = File::open(path).map_err(Error::Io)?;
 
         let mmap = unsafe { memmap2::Mmap::map(&file) }.map_err(Error::Io)?;
@@ -51,13 +74,13 @@ impl DwarfInfo {
         };
 
         // Parse function names from symbol table first (doesn't need DWARF)
-        let functions = Self::parse_functions(&object);
+        let functions = Self::parse_functions(&object, cancel)?;
 
         // Parse line info using a helper that owns the data
-        let ranges = Self::parse_line_info_from_object(&object, endian)?;
+        let ranges = Self::parse_line_info_from_object(&object, endian, cancel)?;
 
         // Parse function declarations from DWARF DIEs
-        let function_decls = Self::parse_function_decls_from_object(&object, endian)?;
+        let function_decls = Self::parse_function_decls_from_object(&object, endian, cancel)?;
 
         Ok(DwarfInfo {
             ranges,
@@ -69,6 +92,7 @@ impl DwarfInfo {
     fn parse_line_info_from_object(
         object: &object::File<'_>,
         endian: RunTimeEndian,
+        cancel: &AtomicBool,
     ) -> Result<Vec<AddressRange>> {
         // Helper to load a section's data
         let load_section = |name: &str| -> &[u8] {
@@ -95,16 +119,18 @@ impl DwarfInfo {
             ..Default::default()
         };
 
-        Self::parse_line_info(&dwarf)
+        Self::parse_line_info(&dwarf, cancel)
     }
 
     fn parse_line_info(
         dwarf: &gimli::Dwarf<EndianSlice<'_, RunTimeEndian>>,
+        cancel: &AtomicBool,
     ) -> Result<Vec<AddressRange>> {
         let mut ranges = Vec::new();
         let mut units = dwarf.units();
 
         while let Ok(Some(header)) = units.next() {
+            check_cancelled(cancel)?;
             let unit = dwarf
                 .unit(header)
                 .map_err(|e| Error::SymbolResolution(format!("Failed to parse unit: {}", e)))?;
@@ -114,6 +140,7 @@ impl DwarfInfo {
                 let mut prev_row: Option<(u64, String, u32, u32)> = None;
 
                 while let Ok(Some((header, row))) = rows.next_row() {
+                    check_cancelled(cancel)?;
                     let addr = row.address();
 
                     // Get file path
@@ -172,26 +199,31 @@ impl DwarfInfo {
         Ok(ranges)
     }
 
-    fn parse_functions(object: &object::File<'_>) -> HashMap<u64, String> {
+    fn parse_functions(
+        object: &object::File<'_>,
+        cancel: &AtomicBool,
+    ) -> Result<HashMap<u64, String>> {
         use object::ObjectSymbol;
 
         let mut functions = HashMap::new();
 
         for symbol in object.symbols() {
+            check_cancelled(cancel)?;
             if symbol.kind() == object::SymbolKind::Text
                 && let Ok(name) = symbol.name()
             {
-                let demangled = rustc_demangle::demangle(name).to_string();
+                let demangled = super::demangle(name);
                 functions.insert(symbol.address(), demangled);
             }
         }
 
-        functions
+        Ok(functions)
     }
 
     fn parse_function_decls_from_object(
         object: &object::File<'_>,
         endian: RunTimeEndian,
+        cancel: &AtomicBool,
     ) -> Result<HashMap<String, (String, u32)>> {
         // Helper to load a section's data
         let load_section = |name: &str| -> &[u8] {
@@ -218,16 +250,18 @@ impl DwarfInfo {
             ..Default::default()
         };
 
-        Self::parse_function_decls(&dwarf)
+        Self::parse_function_decls(&dwarf, cancel)
     }
 
     fn parse_function_decls(
         dwarf: &gimli::Dwarf<EndianSlice<'_, RunTimeEndian>>,
+        cancel: &AtomicBool,
     ) -> Result<HashMap<String, (String, u32)>> {
         let mut function_decls: HashMap<String, (String, u32)> = HashMap::new();
         let mut units = dwarf.units();
 
         while let Ok(Some(header)) = units.next() {
+            check_cancelled(cancel)?;
             let unit = match dwarf.unit(header) {
                 Ok(u) => u,
                 Err(_) => continue,
@@ -268,7 +302,7 @@ impl DwarfInfo {
                 };
 
                 let func_name = match func_name {
-                    Some(n) => rustc_demangle::demangle(n).to_string(),
+                    Some(n) => super::demangle(n),
                     None => continue,
                 };
 
@@ -352,3 +386,192 @@ impl DwarfInfo {
             || path.starts_with("<")
     }
 }
+
+/// Read the GNU build-id of an ELF file as a hex string
+pub fn read_build_id(path: &Path) -> Option<String> {
+    let file = File::open(path).ok()?;
+    let mmap = unsafe { memmap2::Mmap::map(&file) }.ok()?;
+    let object = object::File::parse(&*mmap).ok()?;
+    let id = object.build_id().ok()??;
+    Some(id.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Read the file name stored in an ELF file's `.gnu_debuglink` section
+fn read_debuglink(path: &Path) -> Option<String> {
+    let file = File::open(path).ok()?;
+    let mmap = unsafe { memmap2::Mmap::map(&file) }.ok()?;
+    let object = object::File::parse(&*mmap).ok()?;
+    let (name, _crc) = object.gnu_debuglink().ok()??;
+    Some(String::from_utf8_lossy(name).into_owned())
+}
+
+/// Locate split debug info for a stripped binary
+///
+/// Checks the `.gnu_debuglink` name next to the executable and in its
+/// `.debug/` directory, then `<exe>.debug`, then the `/usr/lib/debug`
+/// build-id and path mirrors. Candidates with a different build-id are skipped.
+/// All candidates are looked up under `root`, the target's filesystem root.
+pub fn find_debug_file(binary: &Path, exe_path: &Path, root: &Path) -> Option<PathBuf> {
+    const DEBUG_ROOT: &str = "/usr/lib/debug";
+
+    let build_id = read_build_id(binary);
+    let dir = exe_path.parent().unwrap_or(Path::new("/"));
+    let mut candidates = Vec::new();
+
+    if let Some(link) = read_debuglink(binary) {
+        candidates.push(dir.join(&link));
+        candidates.push(dir.join(".debug").join(&link));
+    }
+    let mut sibling = exe_path.as_os_str().to_owned();
+    sibling.push(".debug");
+    candidates.push(PathBuf::from(sibling));
+    if let Some(id) = build_id.as_deref().filter(|id| id.len() > 2) {
+        candidates.push(
+            Path::new(DEBUG_ROOT)
+                .join(".build-id")
+                .join(&id[..2])
+                .join(format!("{}.debug", &id[2..])),
+        );
+    }
+    if let Ok(relative) = exe_path.strip_prefix("/") {
+        let mut mirrored = Path::new(DEBUG_ROOT).join(relative).into_os_string();
+        mirrored.push(".debug");
+        candidates.push(PathBuf::from(mirrored));
+    }
+
+    candidates
+        .into_iter()
+        .map(|candidate| path_in_root(root, &candidate))
+        .find(|candidate| {
+            candidate.is_file()
+                && match (&build_id, read_build_id(candidate)) {
+                    (Some(expected), Some(found)) => *expected == found,
+                    _ => true,
+                }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write a minimal ELF file with an optional build-id note and `.gnu_debuglink`
+    fn write_elf(path: &Path, build_id: Option<&[u8]>, debuglink: Option<&str>) {
+        const SHSTRTAB: &[u8] = b"\0.shstrtab\0.note.gnu.build-id\0.gnu_debuglink\0";
+        let mut sections: Vec<(u32, u32, Vec<u8>)> = vec![(1, 3, SHSTRTAB.to_vec())];
+        if let Some(id) = build_id {
+            let mut note = Vec::new();
+            for word in [4, id.len() as u32, 3] {
+                note.extend(word.to_le_bytes());
+            }
+            note.extend(b"GNU\0");
+            note.extend(id);
+            note.resize(note.len().next_multiple_of(4), 0);
+            sections.push((11, 7, note));
+        }
+        if let Some(name) = debuglink {
+            let mut link = name.as_bytes().to_vec();
+            link.push(0);
+            link.resize(link.len().next_multiple_of(4), 0);
+            link.extend(0u32.to_le_bytes()); // CRC, not checked
+            sections.push((30, 1, link));
+        }
+
+        // Header, section contents, then the section headers
+        let mut data = vec![0u8; 64];
+        let mut headers = vec![0u8; 64]; // Null section
+        for (name, kind, contents) in &sections {
+            let offset = data.len() as u64;
+            data.extend(contents);
+            data.resize(data.len().next_multiple_of(8), 0);
+            headers.extend(name.to_le_bytes());
+            headers.extend(kind.to_le_bytes());
+            for field in [0, 0, offset, contents.len() as u64] {
+                headers.extend(field.to_le_bytes());
+            }
+            headers.extend([0u8; 8]); // sh_link, sh_info
+            headers.extend(4u64.to_le_bytes());
+            headers.extend(0u64.to_le_bytes());
+        }
+        let shoff = data.len() as u64;
+        data.extend(headers);
+
+        data[..16].copy_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+        data[16..18].copy_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+        data[18..20].copy_from_slice(&62u16.to_le_bytes()); // x86-64
+        data[20..24].copy_from_slice(&1u32.to_le_bytes());
+        data[40..48].copy_from_slice(&shoff.to_le_bytes());
+        data[52..54].copy_from_slice(&64u16.to_le_bytes()); // e_ehsize
+        data[58..60].copy_from_slice(&64u16.to_le_bytes()); // e_shentsize
+        data[60..62].copy_from_slice(&(sections.len() as u16 + 1).to_le_bytes());
+        data[62..64].copy_from_slice(&1u16.to_le_bytes()); // e_shstrndx
+
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn cancelling_stops_loading_promptly() {
+        let binary = std::env::current_exe().unwrap();
+        let cancel = AtomicBool::new(true);
+        assert!(matches!(
+            DwarfInfo::parse_cancellable(&binary, &cancel),
+            Err(Error::Interrupted)
+        ));
+
+        // Part way through this test binary's DWARF (seconds of work)
+        let cancel = Arc::new(AtomicBool::new(false));
+        let canceller = {
+            let cancel = Arc::clone(&cancel);
+            std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                cancel.store(true, Ordering::Relaxed);
+                std::time::Instant::now()
+            })
+        };
+        let result = DwarfInfo::parse_cancellable(&binary, &cancel);
+        let stopped = std::time::Instant::now();
+        let cancelled = canceller.join().unwrap();
+        assert!(matches!(result, Err(Error::Interrupted)));
+        assert!(stopped.duration_since(cancelled) < std::time::Duration::from_millis(250));
+    }
+
+    #[test]
+    fn debug_files_are_found_by_debuglink_name_and_build_id() {
+        let root = std::env::temp_dir().join(format!("rsprof-debug-files-{}", std::process::id()));
+        let exe_path = Path::new("/app/bin/server");
+        let binary = path_in_root(&root, exe_path);
+        let id: &[u8] = &[0xab, 0xcd, 0xef, 0x01];
+        let other: &[u8] = &[0x12, 0x34, 0x56, 0x78];
+        let found = || find_debug_file(&binary, exe_path, &root);
+        write_elf(&binary, Some(id), Some("server.dbg"));
+        assert_eq!(read_build_id(&binary).as_deref(), Some("abcdef01"));
+        assert_eq!(found(), None);
+
+        // Build-id path, the last resort for a stripped binary
+        let by_id = root.join("usr/lib/debug/.build-id/ab/cdef01.debug");
+        write_elf(&by_id, Some(id), None);
+        assert_eq!(found(), Some(by_id.clone()));
+
+        // `<exe>.debug` before that, unless it is another build's
+        let sibling = root.join("app/bin/server.debug");
+        write_elf(&sibling, Some(other), None);
+        assert_eq!(found(), Some(by_id.clone()));
+        write_elf(&sibling, Some(id), None);
+        assert_eq!(found(), Some(sibling.clone()));
+
+        // The debuglink name, in the `.debug/` directory or next to the exe
+        let linked_in_debug_dir = root.join("app/bin/.debug/server.dbg");
+        write_elf(&linked_in_debug_dir, Some(id), None);
+        assert_eq!(found(), Some(linked_in_debug_dir));
+        let linked = root.join("app/bin/server.dbg");
+        write_elf(&linked, Some(id), None);
+        assert_eq!(found(), Some(linked));
+
+        // Nothing matching the build-id is used at all
+        write_elf(&binary, Some(&[0x99, 0x99, 0x99, 0x99]), Some("server.dbg"));
+        assert_eq!(found(), None);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
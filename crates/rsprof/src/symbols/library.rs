@@ -0,0 +1,259 @@
+//! Shared objects mapped into the target (libc, C dependencies, dlopen'd
+//! plugins), symbolicated on first use.
+
+use super::dwarf::find_debug_file;
+use super::{Location, SymbolResolver};
+use crate::process::{MemoryMaps, path_in_root};
+use object::{Object, ObjectSymbol, ObjectSymbolTable, SymbolKind};
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How often `/proc/<pid>/maps` is re-read for addresses outside every known
+/// object (libraries dlopen'd after attaching)
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A shared object's executable mappings
+struct Library {
+    start: u64,
+    end: u64,
+    /// Path as the target sees it
+    path: String,
+    /// Runtime address minus file virtual address
+    bias: u64,
+    symbols: OnceCell<Symbols>,
+}
+
+/// How a library's addresses are symbolicated
+enum Symbols {
+    /// Debug info of a library built from the user's sources (a Rust plugin)
+    Dwarf(Box<SymbolResolver>),
+    /// Symbol table only: function names
+    Table(SymbolTable),
+    /// File missing or unreadable
+    None,
+}
+
+/// The target's shared objects, sorted by address
+pub(crate) struct Libraries {
+    pid: u32,
+    /// The executable, which the resolver itself covers
+    exe_path: PathBuf,
+    /// `/proc/<pid>/root`, for opening the paths the target reports
+    root: PathBuf,
+    libraries: Vec<Library>,
+    refreshed: Instant,
+}
+
+impl Libraries {
+    /// Read the objects currently mapped into `pid`
+    pub(crate) fn for_pid(pid: u32, exe_path: &Path, root: &Path) -> Option<Self> {
+        let maps = MemoryMaps::for_pid(pid).ok()?;
+        let mut libraries = Libraries {
+            pid,
+            exe_path: exe_path.to_path_buf(),
+            root: root.to_path_buf(),
+            libraries: Vec::new(),
+            refreshed: Instant::now(),
+        };
+        libraries.update(&maps);
+        log::info!(
+            "libraries_mapped pid={} count={}",
+            pid,
+            libraries.libraries.len()
+        );
+        Some(libraries)
+    }
+
+    /// Location of `addr` if a shared object maps it
+    ///
+    /// Re-reads the maps (at most every `REFRESH_INTERVAL`) when no object
+    /// does, to pick up libraries loaded since.
+    pub(crate) fn resolve(&mut self, addr: u64) -> Option<Location> {
+        if self.find(addr).is_none() && self.refreshed.elapsed() >= REFRESH_INTERVAL {
+            self.refreshed = Instant::now();
+            if let Ok(maps) = MemoryMaps::for_pid(self.pid) {
+                self.update(&maps);
+            }
+        }
+        let library = &self.libraries[self.find(addr)?];
+        Some(library.resolve(addr, &self.root))
+    }
+
+    /// Name of the function containing `addr`, if a known object maps it
+    pub(crate) fn function_name(&self, addr: u64) -> Option<String> {
+        self.libraries[self.find(addr)?].function_name(addr, &self.root)
+    }
+
+    fn find(&self, addr: u64) -> Option<usize> {
+        let index = self
+            .libraries
+            .partition_point(|library| library.start <= addr)
+            .checked_sub(1)?;
+        (addr < self.libraries[index].end).then_some(index)
+    }
+
+    /// Take the current mappings, keeping the symbols of objects still mapped
+    fn update(&mut self, maps: &MemoryMaps) {
+        let exe = self.exe_path.to_string_lossy();
+        // Bias from the first mapping of each file, like the executable's
+        let mut biases: HashMap<&str, u64> = HashMap::new();
+        for mapping in maps.mappings() {
+            if let Some(path) = &mapping.pathname {
+                biases
+                    .entry(path)
+                    .or_insert(mapping.start.wrapping_sub(mapping.offset));
+            }
+        }
+
+        let mut previous = std::mem::take(&mut self.libraries);
+        for mapping in maps.executable_mappings() {
+            let Some(path) = mapping.pathname.as_deref() else {
+                continue;
+            };
+            if !path.starts_with('/') || path == exe || path.ends_with(" (deleted)") {
+                continue;
+            }
+            let library = match previous
+                .iter()
+                .position(|l| l.start == mapping.start && l.path == path)
+            {
+                Some(index) => previous.swap_remove(index),
+                None => Library {
+                    start: mapping.start,
+                    end: mapping.end,
+                    path: path.to_string(),
+                    bias: biases[path],
+                    symbols: OnceCell::new(),
+                },
+            };
+            self.libraries.push(library);
+        }
+        self.libraries.sort_by_key(|library| library.start);
+    }
+}
+
+impl Library {
+    fn symbols(&self, root: &Path) -> &Symbols {
+        self.symbols
+            .get_or_init(|| load_symbols(Path::new(&self.path), root, self.bias))
+    }
+
+    fn resolve(&self, addr: u64, root: &Path) -> Location {
+        let name = format!("[{}]", self.path.rsplit('/').next().unwrap_or(&self.path));
+        match self.symbols(root) {
+            Symbols::Dwarf(resolver) => resolver.resolve(addr),
+            Symbols::Table(symbols) => {
+                let function = symbols
+                    .lookup(addr.wrapping_sub(self.bias))
+                    .map_or_else(|| name.clone(), str::to_string);
+                Location {
+                    file: name,
+                    line: 0,
+                    column: 0,
+                    function,
+                }
+            }
+            Symbols::None => Location {
+                file: name.clone(),
+                line: 0,
+                column: 0,
+                function: name,
+            },
+        }
+    }
+
+    fn function_name(&self, addr: u64, root: &Path) -> Option<String> {
+        match self.symbols(root) {
+            Symbols::Dwarf(resolver) => resolver.function_name(addr),
+            Symbols::Table(symbols) => symbols
+                .lookup(addr.wrapping_sub(self.bias))
+                .map(str::to_string),
+            Symbols::None => None,
+        }
+    }
+}
+
+/// Debug info for a library built from the target's own sources, else its
+/// symbol table
+///
+/// Only debug info inside the file is used: system libraries' separate debug
+/// files would make their C sources look like user code, but their full
+/// symbol table replaces a stripped library's exported symbols. `path` is in
+/// the target's terms, opened under `root`.
+fn load_symbols(path: &Path, root: &Path, bias: u64) -> Symbols {
+    let file = path_in_root(root, path);
+    if has_debug_info(&file)
+        && let Ok(resolver) = SymbolResolver::for_binary(&file, path, root, bias)
+        && resolver.target_root().is_some()
+    {
+        return Symbols::Dwarf(Box::new(resolver));
+    }
+    let table = SymbolTable::load(&file);
+    if table.as_ref().is_none_or(|table| table.dynamic)
+        && let Some(debug_file) = find_debug_file(&file, path, root)
+        && let Some(full) = SymbolTable::load(&debug_file)
+    {
+        return Symbols::Table(full);
+    }
+    table.map_or(Symbols::None, Symbols::Table)
+}
+
+fn has_debug_info(path: &Path) -> bool {
+    let Ok(file) = File::open(path) else {
+        return false;
+    };
+    let Ok(mmap) = (unsafe { memmap2::Mmap::map(&file) }) else {
+        return false;
+    };
+    object::File::parse(&*mmap).is_ok_and(|object| object.section_by_name(".debug_info").is_some())
+}
+
+/// Function symbols of an ELF file, sorted by address
+pub(crate) struct SymbolTable {
+    /// (start, size, demangled name); size 0 when the table doesn't say
+    symbols: Vec<(u64, u64, String)>,
+    /// Read from `.dynsym`: exported functions only
+    dynamic: bool,
+}
+
+impl SymbolTable {
+    /// Read `.symtab`, else `.dynsym` (all a stripped library keeps)
+    pub(crate) fn load(path: &Path) -> Option<Self> {
+        let file = File::open(path).ok()?;
+        let mmap = unsafe { memmap2::Mmap::map(&file) }.ok()?;
+        let object = object::File::parse(&*mmap).ok()?;
+        let (table, dynamic) = match object.symbol_table() {
+            Some(table) => (table, false),
+            None => (object.dynamic_symbol_table()?, true),
+        };
+        let mut symbols: Vec<(u64, u64, String)> = table
+            .symbols()
+            .filter(|s| s.is_definition() && s.kind() == SymbolKind::Text && s.address() != 0)
+            .filter_map(|s| {
+                let name = s.name().ok().filter(|name| !name.is_empty())?;
+                let name = super::demangle(name);
+                Some((s.address(), s.size(), name))
+            })
+            .collect();
+        // Aliases share an address; keep one name
+        symbols.sort_by_key(|&(start, _, _)| start);
+        symbols.dedup_by_key(|&mut (start, _, _)| start);
+        Some(SymbolTable { symbols, dynamic })
+    }
+
+    /// Name of the function containing a file address
+    ///
+    /// `None` past the end of the preceding symbol, which in a stripped
+    /// library is usually an unexported function rather than that symbol.
+    pub(crate) fn lookup(&self, file_addr: u64) -> Option<&str> {
+        let index = self
+            .symbols
+            .partition_point(|&(start, _, _)| start <= file_addr)
+            .checked_sub(1)?;
+        let (start, size, name) = &self.symbols[index];
+        (*size == 0 || file_addr < start + size).then_some(name.as_str())
+    }
+}
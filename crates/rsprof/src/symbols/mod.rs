@@ -1,4 +1,18 @@
+pub mod attribution;
+#[cfg(feature = "debuginfod")]
+mod debuginfod;
+pub mod deferred;
+mod demangle;
 mod dwarf;
+pub(crate) mod library;
 mod resolver;
 
-pub use resolver::{Location, SymbolResolver, shorten_function_name};
+pub(crate) use demangle::demangle;
+
+pub use attribution::{Attribution, in_allocator, user_stack};
+
+pub use dwarf::{cancel_loading, read_build_id};
+pub use resolver::{
+    DebugSource, Location, SymbolResolver, shorten_function_name, split_symbol_hash,
+    strip_hash_suffix,
+};
@@ -1,6 +1,8 @@
-use super::dwarf::{AddressRange, DwarfInfo};
-use crate::error::Result;
-use crate::process::{MemoryMaps, ProcessInfo};
+use super::dwarf::{AddressRange, DwarfInfo, find_debug_file};
+use super::library::Libraries;
+use crate::error::{Error, Result};
+use crate::process::{ProcessInfo, path_in_root};
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
@@ -23,6 +25,18 @@ impl Location {
         }
     }
 
+    /// Placeholder for allocations the target recorded without a stack
+    /// (outside the `profiler!` stack size range, or counted with
+    /// `full = count_only` after its callsite table filled up)
+    pub fn unstacked() -> Self {
+        Location {
+            file: "(no stack)".to_string(),
+            line: 0,
+            column: 0,
+            function: "(allocations recorded without a stack)".to_string(),
+        }
+    }
+
     /// Format as file:line
     pub fn as_file_line(&self) -> String {
         if self.line > 0 {
@@ -49,6 +63,9 @@ impl Location {
     }
 }
 
+/// Most addresses `SymbolResolver::resolve` keeps before starting over
+const CACHE_CAPACITY: usize = 1 << 16;
+
 /// Symbol resolver using DWARF debug info
 pub struct SymbolResolver {
     /// DWARF address ranges (sorted by start address)
@@ -59,34 +76,144 @@ pub struct SymbolResolver {
     function_decls: HashMap<String, (String, u32)>,
     /// ASLR offset to subtract from runtime addresses
     aslr_offset: u64,
-    /// LRU cache for recent lookups
-    cache: HashMap<u64, Location>,
+    /// Resolved locations by runtime address (the offset never changes for a
+    /// resolver, so runtime addresses key it unambiguously)
+    cache: RefCell<HashMap<u64, Location>>,
     /// Root directory for the target app's source (used to filter dependencies)
     target_root: Option<PathBuf>,
+    /// Where the debug info was loaded from
+    debug_source: DebugSource,
+    /// Shared objects of the live target, for addresses outside the executable
+    libraries: Option<RefCell<Libraries>>,
+}
+
+/// Origin of the DWARF a resolver was built from
+#[derive(Debug, Clone)]
+pub enum DebugSource {
+    /// The executable itself
+    Binary,
+    /// A separate debug file found on disk
+    Local(PathBuf),
+    /// A separate debug file downloaded from a debuginfod server
+    Debuginfod(PathBuf),
+}
+
+impl DebugSource {
+    /// The separate debug file, if any
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            DebugSource::Binary => None,
+            DebugSource::Local(path) | DebugSource::Debuginfod(path) => Some(path),
+        }
+    }
 }
 
 impl SymbolResolver {
     /// Create a new symbol resolver for a process
     pub fn new(proc_info: &ProcessInfo) -> Result<Self> {
-        // Parse DWARF info from executable
+        // Get ASLR offset from memory maps (or the target's own report)
+        let aslr_offset = proc_info.aslr_offset()?;
+
         // Use proc_exe_path which works even if binary was deleted/rebuilt
-        let dwarf = DwarfInfo::parse(proc_info.proc_exe_path())?;
-        let target_root = detect_target_root(&dwarf, proc_info.exe_path());
+        let mut resolver = Self::for_binary(
+            proc_info.proc_exe_path(),
+            proc_info.exe_path(),
+            proc_info.root_path(),
+            aslr_offset,
+        )?;
+        if proc_info.has_procfs() {
+            resolver.libraries =
+                Libraries::for_pid(proc_info.pid(), proc_info.exe_path(), proc_info.root_path())
+                    .map(RefCell::new);
+        }
+        Ok(resolver)
+    }
 
-        // Get ASLR offset from memory maps
-        let maps = MemoryMaps::for_pid(proc_info.pid())?;
-        let aslr_offset = maps.aslr_offset(proc_info.exe_path())?;
+    /// Create a resolver from a binary on disk and a known ASLR offset
+    ///
+    /// Used when symbolicating a recording after the process has exited.
+    /// `exe_path` is the original executable path (for locating the source root
+    /// and split debug files of a stripped binary), as seen from `root`
+    /// (`/proc/<pid>/root` for a target in another mount namespace).
+    pub fn for_binary(
+        binary: &Path,
+        exe_path: &Path,
+        root: &Path,
+        aslr_offset: u64,
+    ) -> Result<Self> {
+        let started = std::time::Instant::now();
+        let (dwarf, debug_source) = match DwarfInfo::parse(binary) {
+            Err(Error::MissingDebugInfo { path }) => {
+                let source = match find_debug_file(binary, exe_path, root) {
+                    Some(debug_file) => DebugSource::Local(debug_file),
+                    None => fetch_debug_file(binary).ok_or(Error::MissingDebugInfo { path })?,
+                };
+                let debug_file = source.path().unwrap_or(binary);
+                log::info!("debug_file path={}", debug_file.display());
+                (DwarfInfo::parse(debug_file)?, source)
+            }
+            result => (result?, DebugSource::Binary),
+        };
+        let target_root = detect_target_root(&dwarf, exe_path, root);
+        log::info!(
+            "symbols_loaded binary={} ranges={} functions={} aslr_offset=0x{:x} elapsed_ms={}",
+            binary.display(),
+            dwarf.ranges.len(),
+            dwarf.functions.len(),
+            aslr_offset,
+            started.elapsed().as_millis()
+        );
 
         Ok(SymbolResolver {
             ranges: dwarf.ranges,
             functions: dwarf.functions,
             function_decls: dwarf.function_decls,
             aslr_offset,
-            cache: HashMap::new(),
+            cache: RefCell::new(HashMap::new()),
             target_root,
+            debug_source,
+            libraries: None,
         })
     }
 
+    /// Resolver over synthetic debug info: functions by start address and
+    /// `(start, end, file, line)` line table rows
+    #[cfg(test)]
+    pub(crate) fn from_symbols(functions: &[(u64, &str)], lines: &[(u64, u64, &str, u32)]) -> Self {
+        SymbolResolver {
+            ranges: lines
+                .iter()
+                .map(|&(start, end, file, line)| AddressRange {
+                    start,
+                    end,
+                    file: file.to_string(),
+                    line,
+                    column: 0,
+                })
+                .collect(),
+            functions: functions
+                .iter()
+                .map(|&(start, function)| (start, function.to_string()))
+                .collect(),
+            function_decls: HashMap::new(),
+            aslr_offset: 0,
+            cache: RefCell::new(HashMap::new()),
+            target_root: None,
+            debug_source: DebugSource::Binary,
+            libraries: None,
+        }
+    }
+
+    /// Root directory of the target's sources, if it could be determined
+    pub(crate) fn target_root(&self) -> Option<&Path> {
+        self.target_root.as_deref()
+    }
+
+    /// Where the debug info was loaded from
+    pub fn debug_source(&self) -> &DebugSource {
+        &self.debug_source
+    }
+
     /// Number of address ranges loaded
     pub fn range_count(&self) -> usize {
         self.ranges.len()
@@ -98,12 +225,31 @@ impl SymbolResolver {
     }
 
     /// Resolve a runtime address to a source location
+    ///
+    /// Samples cluster on a few hot addresses, so results are cached; the
+    /// cache is dropped whole once it holds `CACHE_CAPACITY` addresses.
     pub fn resolve(&self, addr: u64) -> Location {
-        // Check cache first
-        if let Some(loc) = self.cache.get(&addr) {
+        if let Some(loc) = self.cache.borrow().get(&addr) {
             return loc.clone();
         }
 
+        let location = self.lookup(addr);
+        let mut cache = self.cache.borrow_mut();
+        if cache.len() >= CACHE_CAPACITY {
+            cache.clear();
+        }
+        cache.insert(addr, location.clone());
+        location
+    }
+
+    /// Resolve a runtime address without the cache
+    fn lookup(&self, addr: u64) -> Location {
+        if let Some(libraries) = &self.libraries
+            && let Some(location) = libraries.borrow_mut().resolve(addr)
+        {
+            return location;
+        }
+
         // Adjust for ASLR
         let debug_addr = addr.saturating_sub(self.aslr_offset);
 
@@ -188,15 +334,23 @@ impl SymbolResolver {
         }
     }
 
-    /// Resolve and cache (mutable version)
-    pub fn resolve_cached(&mut self, addr: u64) -> Location {
-        if let Some(loc) = self.cache.get(&addr) {
-            return loc.clone();
+    /// Name of the function containing a runtime address, in any crate
+    ///
+    /// Unlike `resolve`, frames outside the target's source (std, allocator,
+    /// dependencies) keep their name. `None` for addresses outside the binary
+    /// and its shared libraries.
+    pub fn function_name(&self, addr: u64) -> Option<String> {
+        if let Some(libraries) = &self.libraries
+            && let Some(name) = libraries.borrow().function_name(addr)
+        {
+            return Some(name);
         }
-
-        let location = self.resolve(addr);
-        self.cache.insert(addr, location.clone());
-        location
+        let debug_addr = addr.saturating_sub(self.aslr_offset);
+        let (first, last) = (self.ranges.first()?, self.ranges.last()?);
+        if debug_addr < first.start || debug_addr >= last.end {
+            return None;
+        }
+        Some(self.find_function(debug_addr)).filter(|name| name != "[unknown]")
     }
 
     fn find_function(&self, addr: u64) -> String {
@@ -237,11 +391,23 @@ impl SymbolResolver {
     }
 }
 
-fn detect_target_root(dwarf: &DwarfInfo, exe_path: &Path) -> Option<PathBuf> {
+/// Debug file for `binary` from the servers in `DEBUGINFOD_URLS`, by build-id
+#[cfg(feature = "debuginfod")]
+fn fetch_debug_file(binary: &Path) -> Option<DebugSource> {
+    let build_id = super::read_build_id(binary)?;
+    super::debuginfod::fetch_debug_file(&build_id).map(DebugSource::Debuginfod)
+}
+
+#[cfg(not(feature = "debuginfod"))]
+fn fetch_debug_file(_binary: &Path) -> Option<DebugSource> {
+    None
+}
+
+fn detect_target_root(dwarf: &DwarfInfo, exe_path: &Path, fs_root: &Path) -> Option<PathBuf> {
     if let Some(root) = root_from_main_decl(dwarf) {
         return Some(root);
     }
-    cargo_root_from_exe(exe_path)
+    cargo_root_from_exe(exe_path, fs_root)
 }
 
 fn root_from_main_decl(dwarf: &DwarfInfo) -> Option<PathBuf> {
@@ -264,13 +430,17 @@ fn root_from_source_path(path: &str) -> Option<PathBuf> {
     Some(PathBuf::from(root))
 }
 
-fn cargo_root_from_exe(exe_path: &Path) -> Option<PathBuf> {
+/// Walk up from the executable looking for its Cargo.toml
+///
+/// Files are read under `fs_root`, but the returned directory is in the
+/// target's terms, matching the source paths recorded in its DWARF.
+fn cargo_root_from_exe(exe_path: &Path, fs_root: &Path) -> Option<PathBuf> {
     let exe_name = exe_path.file_stem()?.to_string_lossy();
     let mut current = exe_path.parent();
     let mut fallback_root: Option<PathBuf> = None;
 
     while let Some(dir) = current {
-        let cargo_path = dir.join("Cargo.toml");
+        let cargo_path = path_in_root(fs_root, &dir.join("Cargo.toml"));
         if cargo_path.exists() {
             if fallback_root.is_none() {
                 fallback_root = Some(dir.to_path_buf());
@@ -357,6 +527,24 @@ fn simplify_path(path: &str) -> String {
     result
 }
 
+/// Split a symbol into its name and the `::h<16 hex digits>` hash suffix
+///
+/// The suffix is empty when the symbol has no hash.
+pub fn split_symbol_hash(name: &str) -> (&str, &str) {
+    if let Some(idx) = name.rfind("::h") {
+        let suffix = &name[idx + 3..];
+        if suffix.len() == 16 && suffix.chars().all(|c| c.is_ascii_hexdigit()) {
+            return name.split_at(idx);
+        }
+    }
+    (name, "")
+}
+
+/// Strip the hash suffix from Rust function names (e.g., "foo::h1234abcd" -> "foo")
+pub fn strip_hash_suffix(name: &str) -> &str {
+    split_symbol_hash(name).0
+}
+
 /// Shorten a fully-qualified function name for display.
 ///
 /// Returns just the function name, unless it's a method on a type,
@@ -433,3 +621,55 @@ pub fn shorten_function_name(name: &str) -> &str {
     // Otherwise just return the function name
     last_segment
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(never)]
+    fn resolved_through_the_target_root() -> u32 {
+        std::hint::black_box(3)
+    }
+
+    #[test]
+    fn split_debug_files_are_opened_through_the_target_root() {
+        let pid = std::process::id();
+        let proc_info = ProcessInfo::new(pid).unwrap();
+        let root = proc_info.root_path();
+        assert_eq!(*root, PathBuf::from(format!("/proc/{}/root", pid)));
+
+        // A stripped binary (an ELF header, nothing else) next to the debug
+        // info of this test binary, as the target sees them
+        let dir = std::env::temp_dir().join(format!("rsprof-target-root-{}", pid));
+        std::fs::create_dir_all(&dir).unwrap();
+        let exe_path = dir.join("server");
+        let mut header = vec![0u8; 64];
+        header[..8].copy_from_slice(b"\x7fELF\x02\x01\x01\0");
+        header[16..18].copy_from_slice(&2u16.to_le_bytes());
+        header[18..20].copy_from_slice(&62u16.to_le_bytes());
+        header[20..24].copy_from_slice(&1u32.to_le_bytes());
+        header[52..54].copy_from_slice(&64u16.to_le_bytes());
+        std::fs::write(&exe_path, header).unwrap();
+        let test_binary = std::env::current_exe().unwrap();
+        std::os::unix::fs::symlink(&test_binary, dir.join("server.debug")).unwrap();
+
+        let aslr_offset = crate::process::MemoryMaps::for_pid(pid)
+            .unwrap()
+            .aslr_offset(&test_binary)
+            .unwrap();
+        let resolver = SymbolResolver::for_binary(&exe_path, &exe_path, root, aslr_offset);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let resolver = resolver.unwrap();
+
+        let debug_file = resolver.debug_source().path().unwrap();
+        assert_eq!(debug_file, path_in_root(root, &dir.join("server.debug")));
+        assert!(debug_file.starts_with(root));
+        let addr = resolved_through_the_target_root as *const () as u64;
+        assert!(
+            resolver
+                .resolve(addr)
+                .function
+                .contains("resolved_through_the_target_root")
+        );
+    }
+}
@@ -1,8 +1,18 @@
-use crate::cpu::CpuSampler;
+use crate::cli::{ColumnWidths, NameOptions, PercentBase};
+use crate::cpu::{AutoFreq, CpuSampler, PerfCounter};
 use crate::error::Result;
-use crate::heap::ShmHeapSampler;
-use crate::storage::{CpuEntry, HeapEntry, Storage, query_cpu_timeseries_aggregated};
-use crate::symbols::SymbolResolver;
+use crate::heap::{ShmHeapSampler, SizeDistribution, samples_by_process};
+use crate::process::MemoryRollup;
+use crate::storage::{
+    BucketAggregate, CheckpointStats, CpuEntry, CpuSeries, HeapEntry, META_CLOCK_SKEW_STEPS,
+    MarkerEntry, Storage, get_meta, query_cpu_stacks, query_cpu_timeseries_aggregated,
+    query_heap_stacks,
+};
+use crate::symbols::attribution::{in_allocator, is_internal_location};
+use crate::symbols::{
+    Attribution, Location, SymbolResolver, deferred, shorten_function_name, split_symbol_hash,
+    user_stack,
+};
 use crossterm::{
     event::{
         self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind, KeyModifiers,
@@ -15,9 +25,31 @@ use ratatui::{Terminal, prelude::*};
 use rusqlite::Connection;
 use std::collections::{HashMap, VecDeque};
 use std::io::{self, stdout};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 
+pub(super) type Tui = Terminal<CrosstermBackend<io::Stdout>>;
+
+/// Switch the terminal to the TUI (raw mode, alternate screen, mouse capture)
+pub(super) fn enter_terminal() -> Result<Tui> {
+    enable_raw_mode()?;
+    let mut stdout = stdout();
+    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+    Ok(Terminal::new(CrosstermBackend::new(stdout))?)
+}
+
+/// Restore the terminal after `enter_terminal`
+pub(super) fn leave_terminal(terminal: &mut Tui) -> Result<()> {
+    disable_raw_mode()?;
+    execute!(
+        terminal.backend_mut(),
+        LeaveAlternateScreen,
+        DisableMouseCapture
+    )?;
+    terminal.show_cursor()?;
+    Ok(())
+}
+
 /// Cache for chart data with prefetch window
 #[derive(Default)]
 struct ChartDataCache {
@@ -32,6 +64,17 @@ struct ChartDataCache {
     checkpoint_seq: u64,
 }
 
+/// CPU samples per location within the visible chart range, for
+/// percentages of the window
+#[derive(Default)]
+struct CpuWindowCache {
+    /// Checkpoint time range queried, ms since start (None = not queried)
+    range_ms: Option<(i64, i64)>,
+    checkpoint_seq: u64,
+    /// location_id -> (samples, percent of the window's samples)
+    shares: HashMap<i64, (u64, f64)>,
+}
+
 /// Cache for heap chart data
 #[derive(Default)]
 struct HeapChartCache {
@@ -40,6 +83,8 @@ struct HeapChartCache {
     cache_end_secs: f64,
     points_per_sec: f64,
     data: Vec<(f64, f64)>,
+    /// Allocation size distribution of the cached location
+    sizes: Option<SizeDistribution>,
     checkpoint_seq: u64,
 }
 
@@ -49,204 +94,35 @@ struct LocationInfo {
     function: String,
 }
 
+use super::calltree::CallTree;
+use super::churn::{ChurnAlert, ChurnDetector};
+use super::flame::{FlameGraph, FlameView};
+use super::source::{SourceSnippet, source_roots};
 use super::ui;
 
-/// Patterns for internal/profiler functions to skip
-const SKIP_FUNCTION_PATTERNS: &[&str] = &[
-    // Rust allocator entry points
-    "__rust_alloc",
-    "__rust_dealloc",
-    "__rust_realloc",
-    "__rustc",
-    // Rust alloc crate internals
-    "alloc::alloc::",
-    "alloc::raw_vec::",
-    "alloc::vec::",
-    "alloc::string::",
-    "alloc::collections::",
-    "<alloc::",
-    "alloc::fmt::",
-    "alloc::ffi::", // format! and CString internals
-    // Hashmap/collections internals
-    "hashbrown::",
-    "std::collections::hash",
-    // Core library internals
-    "core::ptr::",
-    "core::slice::",
-    "core::iter::",
-    "<core::",
-    "core::ops::function::",
-    "core::ops::drop::",
-    "core::ffi::",
-    "core::fmt::",
-    "core::num::",
-    "core::str::",
-    "core::hash::",
-    "core::mem::",
-    // Std library internals
-    "std::io::",
-    "std::fmt::",
-    "std::sys::",
-    "std::thread::",
-    "std::sync::",
-    "<std::",
-    "fmt::num::",
-    "fmt::Write::",
-    // Trait implementations (raw DWARF names)
-    " as core::fmt::",  // <T as core::fmt::Display>::fmt
-    " as std::fmt::",   // <T as std::fmt::Write>::write
-    " as core::hash::", // <T as core::hash::Hash>::hash
-    " as alloc::",      // <T as alloc::*>::method
-    // Trait implementations on generic types
-    "<_>::", // any method on trait objects
-    // Libc functions
-    "malloc",
-    "calloc",
-    "realloc",
-    "free",
-    "memcpy",
-    "memmove",
-    "memset",
-    "memchr",
-    "_start",
-    "__libc_start_main",
-    // Exception/unwinding
-    "_Unwind_",
-    "__cxa_",
-    "_fini",
-    "_init",
-    "rust_eh_personality",
-    // Profiler internals (rsprof-trace)
-    "addr2line::",
-    "gimli::",
-    "object::",
-    "miniz_oxide::",
-    "rustc_demangle::",
-    "rsprof_alloc::",
-    "rsprof_trace::",
-    "profiling::",
-    "rsprof::",
-    // Sorting internals
-    "sort::shared::smallsort::",
-    // Generic patterns for generated code
-    "::{{closure}}", // closures attributed to parent
-];
-
 const SPARKLINE_WIDTH: u64 = 12;
 
-/// Check if a file path looks like internal/library code
-fn is_internal_file(file: &str) -> bool {
-    file.is_empty()
-        || file.starts_with('[')
-        || file.starts_with('<')
-        || file.contains("/rustc/")
-        || file.contains("/.cargo/registry/")
-        || file.contains("/rust/library/")
-        || file.contains("rsprof-alloc")
-        || file.contains("rsprof-trace")
-        || file.contains("profiling.rs")
-        || file == "lib.rs"
-        || file == "time.rs"
-        || file == "unix.rs"
-        || file.ends_with("memchr.rs")
-        || file.ends_with("maybe_uninit.rs")
-        || file.ends_with("methods.rs")
-        || (file.ends_with("mod.rs") && !file.contains("/src/"))
-}
-
-/// Check if a location is internal (profiler/library code)
-fn is_internal_location(loc: &crate::symbols::Location) -> bool {
-    if is_internal_file(&loc.file) {
-        return true;
-    }
-    SKIP_FUNCTION_PATTERNS
-        .iter()
-        .any(|p| loc.function.contains(p))
-}
-
-/// Patterns for utility functions that should be attributed to their callers
-const UTILITY_PATTERNS: &[&str] = &[
-    // Derived trait methods - attribute to caller
-    ">::clone",       // Clone::clone on any type
-    ">::fmt",         // Debug/Display::fmt
-    ">::hash",        // Hash::hash
-    ">::eq",          // PartialEq::eq
-    ">::partial_cmp", // PartialOrd
-    ">::cmp",         // Ord
-    // Common utility functions
-    "::utils::",
-    "::to_string",
-    "::to_owned",
-    "::into",
+/// Display thresholds '>' steps through: minimum CPU% of a shown row
+const CPU_THRESHOLD_STEPS: &[f64] = &[0.0, 0.1, 0.5, 1.0, 2.0, 5.0, 10.0];
+
+/// Display thresholds '>' steps through: minimum live bytes of a shown row
+const HEAP_THRESHOLD_STEPS: &[i64] = &[
+    0,
+    1 << 10,
+    10 << 10,
+    100 << 10,
+    1 << 20,
+    10 << 20,
+    100 << 20,
 ];
 
-/// Check if a function is a utility function (should attribute to caller)
-fn is_utility_function(func: &str) -> bool {
-    UTILITY_PATTERNS.iter().any(|p| func.contains(p))
-}
-
-/// Find the first "user" frame in a stack trace (not allocator internals)
-/// If the first user frame is a utility function, return its caller instead.
-fn find_user_frame(stack: &[u64], resolver: &SymbolResolver) -> crate::symbols::Location {
-    let mut first_user_frame: Option<crate::symbols::Location> = None;
-    let mut first_user_idx: Option<usize> = None;
-
-    // FIRST PASS: Find the first user frame
-    for (i, &addr) in stack.iter().enumerate() {
-        let loc = resolver.resolve(addr);
-        // Skip internal functions based on name patterns
-        let has_internal_fn = SKIP_FUNCTION_PATTERNS
-            .iter()
-            .any(|p| loc.function.contains(p));
-        if !has_internal_fn
-            && !is_internal_file(&loc.file)
-            && !loc.function.is_empty()
-            && loc.function != "[unknown]"
-        {
-            first_user_frame = Some(loc);
-            first_user_idx = Some(i);
-            break;
-        }
-    }
-
-    // SECOND PASS: If first user frame is a utility function, find its caller
-    if let (Some(first), Some(idx)) = (&first_user_frame, first_user_idx) {
-        if is_utility_function(&first.function) {
-            // Look for the caller (next frame that's not internal)
-            for &addr in stack.iter().skip(idx + 1) {
-                let loc = resolver.resolve(addr);
-                let has_internal_fn = SKIP_FUNCTION_PATTERNS
-                    .iter()
-                    .any(|p| loc.function.contains(p));
-                if !has_internal_fn && !loc.function.is_empty() && loc.function != "[unknown]" {
-                    return loc;
-                }
-            }
-        }
-        return first_user_frame.unwrap();
-    }
-
-    // Fallback: look for frames with real source paths
-    for &addr in stack {
-        let loc = resolver.resolve(addr);
-        if !is_internal_file(&loc.file) && !is_internal_location(&loc) {
-            return loc;
-        }
-    }
-
-    // Last resort: first address
-    if !stack.is_empty() {
-        return resolver.resolve(stack[0]);
-    }
-
-    resolver.resolve(0)
-}
-
 /// Focus state for keyboard navigation
 #[derive(Clone, Copy, PartialEq)]
 pub enum Focus {
     Table,
     Chart,
+    /// Callers/callees pane under the table
+    CallTree,
 }
 
 /// Chart visualization type
@@ -296,6 +172,48 @@ pub enum ViewMode {
     Memory,
 }
 
+/// How CPU percentages are mapped to hotness colors
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum HeatMode {
+    /// Fixed thresholds (20/10/5%)
+    #[default]
+    Absolute,
+    /// Relative to the current entries (top decile is hottest)
+    Relative,
+}
+
+/// What the memory view sparklines plot
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum SparklineSource {
+    /// Live bytes at each checkpoint
+    #[default]
+    LiveBytes,
+    /// Allocations made during each checkpoint
+    AllocCount,
+}
+
+/// What the memory view's rows are
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum HeapGrouping {
+    /// One row per allocation site
+    #[default]
+    Site,
+    /// One row per marker or `rsprof_trace::tag`, whatever the stack
+    Tag,
+}
+
+/// How function names are displayed in the tables
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum DemangleMode {
+    /// Function or Type::method, with long generics and the hash elided
+    #[default]
+    Short,
+    /// Full demangled path without the hash suffix
+    Full,
+    /// Symbol exactly as recorded, hash included
+    Raw,
+}
+
 /// Fixed zoom levels with corresponding aggregation bucket sizes
 /// (window_secs, bucket_secs) - bucket is None if no aggregation needed
 const ZOOM_LEVELS: &[(f64, Option<f64>)] = &[
@@ -326,6 +244,8 @@ pub struct ChartState {
     pub chart_type: ChartType,
     /// Whether Y-axis starts from zero (false = auto-scale)
     pub y_axis_from_zero: bool,
+    /// How each bucket collapses the checkpoints in it
+    pub aggregate: BucketAggregate,
 }
 
 impl Default for ChartState {
@@ -336,6 +256,7 @@ impl Default for ChartState {
             total_duration_secs: 0.0,
             chart_type: ChartType::Line,
             y_axis_from_zero: false, // Auto-scale by default
+            aggregate: BucketAggregate::Max,
         }
     }
 }
@@ -356,6 +277,7 @@ impl ChartState {
             total_duration_secs: duration_secs,
             chart_type: ChartType::Line,
             y_axis_from_zero: false,
+            aggregate: BucketAggregate::Max,
         }
     }
 
@@ -371,6 +293,15 @@ impl ChartState {
     pub fn toggle_y_axis_zero(&mut self) {
         self.y_axis_from_zero = !self.y_axis_from_zero;
     }
+
+    /// Cycle bucket aggregation: max -> mean -> p95
+    pub fn cycle_aggregate(&mut self) {
+        self.aggregate = match self.aggregate {
+            BucketAggregate::Max => BucketAggregate::Avg,
+            BucketAggregate::Avg => BucketAggregate::P95,
+            BucketAggregate::P95 => BucketAggregate::Max,
+        };
+    }
 }
 
 impl ChartState {
@@ -488,7 +419,7 @@ pub struct App {
     paused_elapsed: Option<Duration>,
     last_draw: Instant,
     last_click: Option<(Instant, u16, u16)>,
-    include_internal: bool,
+    attribution: Attribution,
 
     // Selection state
     selected_row: usize,
@@ -507,10 +438,51 @@ pub struct App {
     heap_live_entries: HashMap<i64, HeapEntry>,
     heap_last_seen: HashMap<i64, u64>,
     chart_checkpoint_seq: u64,
+    // Every CPU/heap entry, and the rows shown: those at or above the display threshold
+    all_entries: Vec<CpuEntry>,
+    all_heap_entries: Vec<HeapEntry>,
     cached_entries: Vec<CpuEntry>,
     cached_heap_entries: Vec<HeapEntry>,
+    // View-only floor per table ('<'/'>'): index into CPU/HEAP_THRESHOLD_STEPS
+    cpu_threshold_step: usize,
+    heap_threshold_step: usize,
+    // Row filter typed after '/': rows whose function or location contains it
+    filter: String,
+    // Keystrokes go to the filter until Enter/Esc
+    filter_editing: bool,
     cached_cpu_sparklines: HashMap<i64, VecDeque<i64>>,
     cached_heap_sparklines: HashMap<i64, VecDeque<i64>>,
+    cached_heap_count_sparklines: HashMap<i64, VecDeque<i64>>,
+    // Cumulative alloc counts at the previous checkpoint (for count deltas)
+    prev_heap_alloc_counts: HashMap<i64, u64>,
+    // Allocation rate baselines per heap site, flagging sudden jumps (live only)
+    churn: ChurnDetector,
+    // Labeled checkpoints as (seconds, label) for the chart timeline
+    checkpoint_labels: Vec<(f64, String)>,
+    // CPU samples and sampling threads per checkpoint, for the header's rate
+    checkpoint_stats: Vec<CheckpointStats>,
+    // (bytes, allocations) made per location, for the CPU view's Alloc column
+    cpu_allocs: HashMap<i64, (i64, u64)>,
+    // (cycles, instructions) per location, for the CPU view's IPC column
+    cpu_ipc_counts: HashMap<i64, (u64, u64)>,
+    // Sampling frequency, to show samples as CPU time
+    cpu_freq_hz: Option<f64>,
+    // Retunes the sampling frequency to an overhead budget (--auto-freq)
+    pub auto_freq: Option<AutoFreq>,
+    // Most locations kept per view in live mode (--live-max-locations)
+    pub live_max_locations: usize,
+    // Checkpoint each live CPU location last got samples at, for evicting
+    cpu_last_sampled: HashMap<i64, u64>,
+    // Events the target dropped (tables full): in total, and since the last checkpoint
+    dropped_events: u64,
+    new_dropped_events: u64,
+    // Resolved call stack per heap callsite (callsite stacks never change)
+    heap_stacks: HashMap<u64, Vec<Location>>,
+    // Resolved call stack per CPU callsite
+    cpu_stacks: HashMap<u64, Vec<Location>>,
+    // Per perf-sampled stack: its location, resolved call stack, and
+    // whether it was inside the allocator
+    perf_stacks: HashMap<Vec<u64>, (Location, Vec<Location>, bool)>,
     table_area: Rect,
     chart_area: Rect,
     chart_data_cache: ChartDataCache,
@@ -526,10 +498,74 @@ pub struct App {
     file_name: Option<String>,
     // View mode (CPU or Memory)
     pub view_mode: ViewMode,
+    // CPU hotness coloring (fixed thresholds or relative to the distribution)
+    pub heat_mode: HeatMode,
+    // CPU% denominator: the whole run or the visible chart window
+    pub percent_base: PercentBase,
+    cpu_window: CpuWindowCache,
+    // Memory view sparklines: live bytes or allocations per checkpoint
+    pub sparkline_source: SparklineSource,
+    // Memory view rows: allocation sites, or markers/tags
+    pub heap_grouping: HeapGrouping,
+    // Heap usage per marker/tag, latest checkpoint
+    tag_entries: Vec<MarkerEntry>,
+    // CPU chart series: instant, cumulative or running-average share
+    pub cpu_series: CpuSeries,
+    // Function name display: short, full path, or raw symbol
+    pub demangle_mode: DemangleMode,
+    // Name simplifications turned off from the command line
+    pub name_options: NameOptions,
+    // Fixed table column widths from --columns
+    pub column_widths: ColumnWidths,
     // Chart visibility (false = full-width table with sparklines)
     pub chart_visible: bool,
+    // Source pane under the table (shown while the chart is hidden)
+    pub source_visible: bool,
+    // Directories source files are looked up under
+    source_roots: Vec<PathBuf>,
+    // Source around the selected row, reloaded when the selection moves
+    source_snippet: Option<SourceSnippet>,
+    // Flamegraph of the recorded stacks in place of the table and chart
+    pub flame_visible: bool,
+    // Built when first shown, rebuilt when the stacks are re-read
+    flame: FlameView,
+    // Callers/callees pane under the table, in place of the source pane
+    pub call_tree_visible: bool,
+    // Neighbours of the selected row's function (or of one navigated to)
+    call_tree: Option<CallTree>,
+    call_tree_generation: u64,
+    // Function navigated to in the pane that has no row: (row's function, target)
+    call_tree_target: Option<(String, String)>,
+    // Pane cursor over the callers, then the callees
+    call_tree_row: usize,
+    flame_generation: u64,
+    // Call stacks of the current view with their weights, for the flamegraph
+    // and call tree; re-read when the view, memory weight or checkpoint changes
+    stacks: Vec<(Vec<Location>, u64)>,
+    stacks_source: Option<(ViewMode, SparklineSource, u64)>,
+    stacks_generation: u64,
     // Time offset for append mode (seconds from previous recording)
     time_offset_secs: f64,
+    // Dump the next rendered frame to a text file
+    screenshot_requested: bool,
+    // Transient footer message (e.g. where a screenshot was saved)
+    pub status_message: Option<(String, Instant)>,
+    // Position in a `view --playlist` slideshow
+    playlist: Option<Playlist>,
+    // Command that repeats the recording of the viewed profile (`R`)
+    pub rerecord_command: Option<String>,
+    rerecord_requested: bool,
+}
+
+/// Position of the open profile in a `view --playlist` slideshow
+pub struct Playlist {
+    pub position: usize,
+    pub len: usize,
+    /// Advance to the next profile after this long
+    dwell: Option<Duration>,
+    shown_at: Instant,
+    /// Where to go once this profile closes: -1/+1, or None to quit
+    step: Option<isize>,
 }
 
 impl App {
@@ -538,13 +574,14 @@ impl App {
     pub fn new(
         perf_sampler: Option<CpuSampler>,
         shm_sampler: Option<ShmHeapSampler>,
-        resolver: SymbolResolver,
+        resolver: Option<SymbolResolver>,
         storage: Storage,
         checkpoint_interval: Duration,
         max_duration: Option<Duration>,
-        include_internal: bool,
+        attribution: Attribution,
         time_offset_secs: f64,
     ) -> Self {
+        let source_roots = source_roots(resolver.as_ref().and_then(|r| r.target_root()));
         let mut chart_state = ChartState::default();
         // If appending, set initial duration to the offset so chart shows historical range
         if time_offset_secs > 0.0 {
@@ -552,7 +589,7 @@ impl App {
         }
 
         // Pre-load existing data when appending
-        let (cached_entries, cached_heap_entries, total_samples) = if time_offset_secs > 0.0 {
+        let (all_entries, all_heap_entries, total_samples) = if time_offset_secs > 0.0 {
             let cpu_entries = storage.query_top_cpu_live(1000);
             let heap_entries = storage.query_top_heap_live(100);
             let samples = storage.total_samples().unwrap_or(0);
@@ -564,7 +601,7 @@ impl App {
         // Build location_info and live_cpu_totals from pre-loaded entries
         let mut location_info = HashMap::new();
         let mut live_cpu_totals = HashMap::new();
-        for entry in &cached_entries {
+        for entry in &all_entries {
             location_info.insert(
                 entry.location_id,
                 LocationInfo {
@@ -578,14 +615,15 @@ impl App {
 
         // Build heap_live_entries from pre-loaded entries
         let mut heap_live_entries = HashMap::new();
-        for entry in &cached_heap_entries {
+        for entry in &all_heap_entries {
             heap_live_entries.insert(entry.location_id, entry.clone());
         }
 
         App {
             sampler: perf_sampler,
             shm_heap_sampler: shm_sampler,
-            resolver: Some(resolver),
+            resolver,
+            cpu_freq_hz: storage.cpu_freq_hz(),
             storage: Some(storage),
             conn: None,
             checkpoint_interval,
@@ -598,7 +636,7 @@ impl App {
             paused_elapsed: None,
             last_draw: Instant::now(),
             last_click: None,
-            include_internal,
+            attribution,
             selected_row: 0,
             scroll_offset: 0,
             selected_location_id: None,
@@ -615,10 +653,23 @@ impl App {
             heap_live_entries,
             heap_last_seen: HashMap::new(),
             chart_checkpoint_seq: 0,
-            cached_entries,
-            cached_heap_entries,
+            cached_entries: all_entries.clone(),
+            cached_heap_entries: all_heap_entries.clone(),
+            all_entries,
+            all_heap_entries,
+            cpu_threshold_step: 0,
+            heap_threshold_step: 0,
+            filter: String::new(),
+            filter_editing: false,
             cached_cpu_sparklines: HashMap::new(),
             cached_heap_sparklines: HashMap::new(),
+            cached_heap_count_sparklines: HashMap::new(),
+            prev_heap_alloc_counts: HashMap::new(),
+            churn: ChurnDetector::default(),
+            checkpoint_labels: Vec::new(),
+            checkpoint_stats: Vec::new(),
+            cpu_allocs: HashMap::new(),
+            cpu_ipc_counts: HashMap::new(),
             table_area: Rect::default(),
             chart_area: Rect::default(),
             chart_data_cache: ChartDataCache::default(),
@@ -628,14 +679,52 @@ impl App {
             static_duration_secs: 0.0,
             file_name: None,
             view_mode: ViewMode::default(),
+            heat_mode: HeatMode::default(),
+            percent_base: PercentBase::default(),
+            cpu_window: CpuWindowCache::default(),
+            sparkline_source: SparklineSource::default(),
+            heap_grouping: HeapGrouping::default(),
+            tag_entries: Vec::new(),
+            cpu_series: CpuSeries::default(),
+            demangle_mode: DemangleMode::default(),
+            name_options: NameOptions::default(),
+            column_widths: ColumnWidths::default(),
+            auto_freq: None,
+            live_max_locations: usize::MAX,
+            cpu_last_sampled: HashMap::new(),
+            dropped_events: 0,
+            new_dropped_events: 0,
+            heap_stacks: HashMap::new(),
+            cpu_stacks: HashMap::new(),
+            perf_stacks: HashMap::new(),
             chart_visible: false, // Hidden by default, sparklines show in table
+            source_visible: false,
+            source_roots,
+            source_snippet: None,
+            flame_visible: false,
+            flame: FlameView::default(),
+            flame_generation: 0,
+            call_tree_visible: false,
+            call_tree: None,
+            call_tree_generation: 0,
+            call_tree_target: None,
+            call_tree_row: 0,
+            stacks: Vec::new(),
+            stacks_source: None,
+            stacks_generation: 0,
             time_offset_secs,
+            screenshot_requested: false,
+            status_message: None,
+            playlist: None,
+            rerecord_command: None,
+            rerecord_requested: false,
         }
     }
 
     /// Create a static viewer app from a profile database
     pub fn from_file(path: &Path) -> Result<Self> {
-        let conn = Connection::open(path)?;
+        // A --no-symbols recording is resolved in memory; the file stays as recorded
+        let conn = crate::symbols::deferred::open_resolved(path)?;
 
         // Load metadata
         let total_samples: i64 = conn
@@ -667,6 +756,35 @@ impl App {
             .into_iter()
             .map(|(k, v)| (k, VecDeque::from(v)))
             .collect();
+        let heap_count_sparklines: HashMap<i64, VecDeque<i64>> =
+            crate::storage::query_heap_count_sparklines_for_locations(
+                &conn,
+                12,
+                &heap_location_ids,
+            )
+            .into_iter()
+            .map(|(k, v)| (k, VecDeque::from(v)))
+            .collect();
+
+        let checkpoint_labels = crate::storage::query_checkpoint_labels(&conn);
+        let checkpoint_stats = crate::storage::query_checkpoint_stats(&conn);
+        let tag_entries = crate::storage::query_top_markers(&conn, usize::MAX).unwrap_or_default();
+        let cpu_allocs: HashMap<i64, (i64, u64)> =
+            crate::storage::query_combined_live(&conn, i64::MAX as usize)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|e| e.heap_alloc_bytes > 0)
+                .map(|e| (e.location_id, (e.heap_alloc_bytes, e.heap_alloc_count)))
+                .collect();
+
+        let instructions = crate::storage::query_cpu_counter_totals(&conn, "instructions");
+        let cpu_ipc_counts: HashMap<i64, (u64, u64)> =
+            crate::storage::query_cpu_counter_totals(&conn, "cycles")
+                .into_iter()
+                .filter_map(|(id, cycles)| Some((id, (cycles, *instructions.get(&id)?))))
+                .collect();
+
+        let cpu_freq_hz = crate::storage::query_cpu_freq_hz(&conn);
 
         let file_name = path.file_name().map(|n| n.to_string_lossy().to_string());
 
@@ -686,7 +804,7 @@ impl App {
             paused_elapsed: None,
             last_draw: Instant::now(),
             last_click: None,
-            include_internal: false,
+            attribution: Attribution::default(),
             selected_row: 0,
             scroll_offset: 0,
             selected_location_id: None,
@@ -703,10 +821,24 @@ impl App {
             heap_live_entries: HashMap::new(),
             heap_last_seen: HashMap::new(),
             chart_checkpoint_seq: 0,
-            cached_entries: entries,
-            cached_heap_entries: heap_entries,
+            all_entries: entries,
+            all_heap_entries: heap_entries,
+            cached_entries: Vec::new(),
+            cached_heap_entries: Vec::new(),
+            cpu_threshold_step: 0,
+            heap_threshold_step: 0,
+            filter: String::new(),
+            filter_editing: false,
             cached_cpu_sparklines: HashMap::new(),
             cached_heap_sparklines: heap_sparklines,
+            cached_heap_count_sparklines: heap_count_sparklines,
+            prev_heap_alloc_counts: HashMap::new(),
+            churn: ChurnDetector::default(),
+            checkpoint_labels,
+            checkpoint_stats,
+            cpu_allocs,
+            cpu_ipc_counts,
+            cpu_freq_hz,
             table_area: Rect::default(),
             chart_area: Rect::default(),
             chart_data_cache: ChartDataCache::default(),
@@ -716,8 +848,45 @@ impl App {
             static_duration_secs: duration_secs,
             file_name,
             view_mode: ViewMode::default(),
-            chart_visible: false,  // Hidden by default
+            heat_mode: HeatMode::default(),
+            percent_base: PercentBase::default(),
+            cpu_window: CpuWindowCache::default(),
+            sparkline_source: SparklineSource::default(),
+            heap_grouping: HeapGrouping::default(),
+            tag_entries,
+            cpu_series: CpuSeries::default(),
+            demangle_mode: DemangleMode::default(),
+            name_options: NameOptions::default(),
+            column_widths: ColumnWidths::default(),
+            auto_freq: None,
+            live_max_locations: usize::MAX,
+            cpu_last_sampled: HashMap::new(),
+            dropped_events: 0,
+            new_dropped_events: 0,
+            heap_stacks: HashMap::new(),
+            cpu_stacks: HashMap::new(),
+            perf_stacks: HashMap::new(),
+            chart_visible: false, // Hidden by default
+            source_visible: false,
+            source_roots: source_roots(None),
+            source_snippet: None,
+            flame_visible: false,
+            flame: FlameView::default(),
+            flame_generation: 0,
+            call_tree_visible: false,
+            call_tree: None,
+            call_tree_generation: 0,
+            call_tree_target: None,
+            call_tree_row: 0,
+            stacks: Vec::new(),
+            stacks_source: None,
+            stacks_generation: 0,
             time_offset_secs: 0.0, // Static mode has no offset
+            screenshot_requested: false,
+            status_message: None,
+            playlist: None,
+            rerecord_command: None,
+            rerecord_requested: false,
         };
 
         app.sort_all_entries();
@@ -729,6 +898,21 @@ impl App {
             app.load_timeseries_static(loc_id, &func_name);
         }
 
+        // The time axis was repaired on open (see storage::repair_clock_skew)
+        if let Some(steps) = app
+            .conn
+            .as_ref()
+            .and_then(|conn| get_meta(conn, META_CLOCK_SKEW_STEPS).ok().flatten())
+        {
+            app.status_message = Some((
+                format!(
+                    "Clock skew: the clock went back {} time(s); later checkpoints shifted",
+                    steps
+                ),
+                Instant::now(),
+            ));
+        }
+
         Ok(app)
     }
 
@@ -748,30 +932,50 @@ impl App {
     }
 
     pub fn run(&mut self) -> Result<()> {
-        // Setup terminal
-        enable_raw_mode()?;
-        let mut stdout = stdout();
-        execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-        let backend = CrosstermBackend::new(stdout);
-        let mut terminal = Terminal::new(backend)?;
-
-        // Main loop
+        let mut terminal = enter_terminal()?;
         let result = self.main_loop(&mut terminal);
+        leave_terminal(&mut terminal)?;
+        result
+    }
 
-        // Restore terminal
-        disable_raw_mode()?;
-        execute!(
-            terminal.backend_mut(),
-            LeaveAlternateScreen,
-            DisableMouseCapture
-        )?;
-        terminal.show_cursor()?;
+    /// Show this profile as entry `position` of `len` in a playlist
+    pub fn set_playlist(&mut self, position: usize, len: usize, dwell: Option<Duration>) {
+        self.playlist = Some(Playlist {
+            position,
+            len,
+            dwell,
+            shown_at: Instant::now(),
+            step: None,
+        });
+    }
 
-        result
+    /// Playlist position, if viewing as part of a playlist
+    pub fn playlist(&self) -> Option<&Playlist> {
+        self.playlist.as_ref()
+    }
+
+    /// Whether the viewer was closed with `R` to record the target again
+    pub fn rerecord_requested(&self) -> bool {
+        self.rerecord_requested
+    }
+
+    /// Move requested when the profile was closed (-1/+1), None to quit
+    pub fn playlist_step(&self) -> Option<isize> {
+        self.playlist.as_ref().and_then(|p| p.step)
     }
 
-    fn main_loop(&mut self, terminal: &mut Terminal<CrosstermBackend<io::Stdout>>) -> Result<()> {
+    pub(super) fn main_loop(&mut self, terminal: &mut Tui) -> Result<()> {
         while self.running {
+            // Slideshow: advance once the dwell time is up
+            if let Some(playlist) = self.playlist.as_mut()
+                && playlist
+                    .dwell
+                    .is_some_and(|dwell| playlist.shown_at.elapsed() >= dwell)
+            {
+                playlist.step = Some(1);
+                break;
+            }
+
             // Check duration limit (live mode only)
             if !self.is_static()
                 && let Some(max) = self.max_duration
@@ -792,7 +996,9 @@ impl App {
 
             if event::poll(poll_duration)? {
                 match event::read()? {
-                    Event::Key(key) => {
+                    Event::Key(key) =>
+                    {
+                        #[allow(clippy::collapsible_match)]
                         if key.kind == KeyEventKind::Press {
                             self.handle_key(key.code, key.modifiers);
                             needs_redraw = true;
@@ -847,30 +1053,62 @@ impl App {
 
                 // Prefer rsprof-trace SHM sampler (provides both CPU and heap)
                 if let Some(shm) = self.shm_heap_sampler.as_mut() {
-                    if let (Some(resolver), Some(storage)) =
-                        (self.resolver.as_ref(), self.storage.as_mut())
-                    {
-                        let _events = shm.poll_events(std::time::Duration::from_millis(1));
+                    let resolver = self.resolver.as_ref();
+                    // Raw stacks can't be classified until symbolicated, so keep them all
+                    let keep_all = self.attribution == Attribution::Leaf || resolver.is_none();
+                    if let Some(storage) = self.storage.as_mut() {
+                        let (_events, dropped) =
+                            shm.poll_events(std::time::Duration::from_millis(1));
+                        self.new_dropped_events += dropped;
+
+                        // Checkpoint labels (rsprof_trace::label) annotate the next checkpoint
+                        if let Some(label) = shm.take_label() {
+                            storage.set_checkpoint_label(&label);
+                        }
 
                         // Process CPU samples from rsprof-trace (aggregated stats)
                         let cpu_stats = shm.read_cpu_stats();
+                        let thread_samples = shm.read_thread_samples();
+                        for samples in &thread_samples {
+                            storage.record_cpu_thread(samples.tid);
+                        }
+                        let process_samples = if shm.follows_forks() {
+                            samples_by_process(&thread_samples)
+                        } else {
+                            Default::default()
+                        };
                         let live_cpu_totals = &mut self.live_cpu_totals;
                         let live_cpu_instant = &mut self.live_cpu_instant;
                         let location_info = &mut self.location_info;
-                        for (_hash, (count, stack)) in cpu_stats {
+                        let cpu_stacks = &mut self.cpu_stacks;
+                        for (hash, (count, stack)) in cpu_stats {
                             self.total_samples += count;
-                            let location = if self.include_internal {
-                                resolve_internal_stack(&stack, resolver)
-                            } else {
-                                // Walk the stack to find the first user frame (skip allocator/profiler internals)
-                                find_user_frame(&stack, resolver)
-                            };
-                            if self.include_internal || !is_internal_location(&location) {
+                            if let Some(auto) = self.auto_freq.as_mut() {
+                                auto.count_samples(count);
+                            }
+                            let location = attribute_stack(&stack, resolver, self.attribution);
+                            if keep_all || !is_internal_location(&location) {
                                 let location_id = storage.record_cpu_sample_count(
                                     stack.first().copied().unwrap_or(0),
                                     &location,
                                     count,
                                 );
+                                for (&process_id, &samples) in
+                                    process_samples.get(&hash).into_iter().flatten()
+                                {
+                                    storage.record_process_samples(process_id, &location, samples);
+                                }
+                                if let Some(resolver) = resolver
+                                    && in_allocator(&stack, resolver)
+                                {
+                                    storage.record_allocator_samples(&location, count);
+                                }
+                                if let Some(resolver) = resolver {
+                                    let frames = cpu_stacks
+                                        .entry(hash)
+                                        .or_insert_with(|| user_stack(&stack, resolver, &location));
+                                    storage.record_cpu_stack(frames, count);
+                                }
                                 *live_cpu_totals.entry(location_id).or_insert(0) += count;
                                 *live_cpu_instant.entry(location_id).or_insert(0) += count;
                                 location_info
@@ -890,17 +1128,17 @@ impl App {
                             let inline_stacks = shm.read_inline_stacks();
                             for (key_addr, stats) in heap_stats {
                                 let location = if let Some(stack) = inline_stacks.get(&key_addr) {
-                                    if self.include_internal {
-                                        resolve_internal_stack(stack, resolver)
-                                    } else {
-                                        find_user_frame(stack, resolver)
-                                    }
-                                } else if self.include_internal {
-                                    crate::symbols::Location::unknown()
-                                } else {
+                                    attribute_stack(stack, resolver, self.attribution)
+                                } else if key_addr == crate::heap::UNSTACKED_CALLSITE {
+                                    Location::unstacked()
+                                } else if let Some(resolver) =
+                                    resolver.filter(|_| self.attribution == Attribution::User)
+                                {
                                     resolver.resolve(key_addr)
+                                } else {
+                                    Location::unknown()
                                 };
-                                if self.include_internal || !is_internal_location(&location) {
+                                if keep_all || !is_internal_location(&location) {
                                     let location_id = storage.record_heap_sample(
                                         &location,
                                         stats.total_alloc_bytes as i64,
@@ -909,6 +1147,24 @@ impl App {
                                         stats.total_allocs,
                                         stats.total_frees,
                                     );
+                                    // Deferred stacks get their frames when symbolicated
+                                    if let (Some(stack), Some(resolver)) =
+                                        (inline_stacks.get(&key_addr), resolver)
+                                    {
+                                        let frames =
+                                            self.heap_stacks.entry(key_addr).or_insert_with(|| {
+                                                user_stack(stack, resolver, &location)
+                                            });
+                                        storage.record_heap_stack(
+                                            frames,
+                                            stats.total_alloc_bytes as i64,
+                                            stats.total_free_bytes as i64,
+                                            stats.live_bytes,
+                                            stats.total_allocs,
+                                            stats.total_frees,
+                                        );
+                                    }
+                                    storage.record_heap_sizes(&location, &stats.sizes);
                                     let entry =
                                         heap_entries_map.entry(location_id).or_insert_with(|| {
                                             HeapEntry {
@@ -931,39 +1187,125 @@ impl App {
                                 }
                             }
 
+                            // Alloc site -> free site pairs (--transfers)
+                            for transfer in shm.read_transfers() {
+                                let (Some(alloc_stack), Some(free_stack)) = (
+                                    inline_stacks.get(&transfer.alloc_hash),
+                                    inline_stacks.get(&transfer.free_hash),
+                                ) else {
+                                    continue;
+                                };
+                                let alloc =
+                                    attribute_stack(alloc_stack, resolver, self.attribution);
+                                let free = attribute_stack(free_stack, resolver, self.attribution);
+                                if keep_all
+                                    || !(is_internal_location(&alloc)
+                                        || is_internal_location(&free))
+                                {
+                                    storage.record_heap_transfer(
+                                        &alloc,
+                                        &free,
+                                        transfer.count,
+                                        transfer.bytes,
+                                    );
+                                }
+                            }
+
+                            for marker in shm.read_markers() {
+                                storage.record_marker_sample(
+                                    &marker.name,
+                                    marker.total_alloc_bytes as i64,
+                                    marker.total_free_bytes as i64,
+                                    marker.live_bytes,
+                                    marker.total_allocs,
+                                    marker.total_frees,
+                                );
+                            }
+
+                            // Resident memory (incl. huge-page backing) to set the heap numbers against
+                            if let Some(rollup) = MemoryRollup::for_pid(shm.target_pid()) {
+                                for (key, value) in rollup.meta() {
+                                    storage.set_meta(key, &value.to_string())?;
+                                }
+                            }
+
                             storage.flush_checkpoint()?;
                             did_checkpoint = true;
                         }
                     }
                 }
                 // Fallback: Use perf-based CPU sampling
-                else if let (Some(sampler), Some(resolver), Some(storage)) = (
-                    self.sampler.as_mut(),
-                    self.resolver.as_ref(),
-                    self.storage.as_mut(),
-                ) {
+                else if let (Some(sampler), Some(storage)) =
+                    (self.sampler.as_mut(), self.storage.as_mut())
+                {
+                    let resolver = self.resolver.as_ref();
+                    let processing = Instant::now();
                     let samples = sampler.read_samples()?;
                     self.total_samples += samples.len() as u64;
+                    if let Some(auto) = self.auto_freq.as_mut() {
+                        auto.count_samples(samples.len() as u64);
+                    }
 
                     let live_cpu_totals = &mut self.live_cpu_totals;
                     let live_cpu_instant = &mut self.live_cpu_instant;
                     let location_info = &mut self.location_info;
-                    for addr in samples {
-                        let location = resolver.resolve(addr);
-                        if self.include_internal || !is_internal_location(&location) {
-                            let location_id = storage.record_cpu_sample(addr, &location);
+                    let cpu_ipc_counts = &mut self.cpu_ipc_counts;
+                    let keep_all = self.attribution == Attribution::Leaf || resolver.is_none();
+                    for sample in samples {
+                        storage.record_cpu_thread(sample.tid);
+                        if !self.perf_stacks.contains_key(&sample.stack) {
+                            let location =
+                                attribute_stack(&sample.stack, resolver, self.attribution);
+                            let (frames, allocator) = match resolver {
+                                Some(resolver) => (
+                                    user_stack(&sample.stack, resolver, &location),
+                                    in_allocator(&sample.stack, resolver),
+                                ),
+                                None => (Vec::new(), false),
+                            };
+                            self.perf_stacks
+                                .insert(sample.stack.clone(), (location, frames, allocator));
+                        }
+                        let (location, frames, allocator) = &self.perf_stacks[&sample.stack];
+                        if keep_all || !is_internal_location(location) {
+                            let location_id = storage.record_cpu_sample(sample.ip, location);
+                            if sampler.follows_forks() {
+                                storage.record_process_samples(sample.pid, location, 1);
+                            }
+                            if *allocator {
+                                storage.record_allocator_samples(location, 1);
+                            }
+                            storage.record_cpu_stack(frames, 1);
+                            if !sampler.counters().is_empty() {
+                                storage.record_cpu_counters(
+                                    location,
+                                    &sampler.named_counters(&sample),
+                                );
+                            }
+                            if let (Some(cycles), Some(instructions)) = (
+                                sampler.counter_value(&sample, PerfCounter::Cycles),
+                                sampler.counter_value(&sample, PerfCounter::Instructions),
+                            ) {
+                                let counts = cpu_ipc_counts.entry(location_id).or_insert((0, 0));
+                                counts.0 += cycles;
+                                counts.1 += instructions;
+                            }
                             *live_cpu_totals.entry(location_id).or_insert(0) += 1;
                             *live_cpu_instant.entry(location_id).or_insert(0) += 1;
                             location_info
                                 .entry(location_id)
                                 .or_insert_with(|| LocationInfo {
-                                    file: location.file,
+                                    file: location.file.clone(),
                                     line: location.line,
-                                    function: location.function,
+                                    function: location.function.clone(),
                                 });
                         }
                     }
 
+                    if let Some(auto) = self.auto_freq.as_mut() {
+                        auto.add_processing(processing.elapsed());
+                    }
+
                     if self.last_checkpoint.elapsed() >= self.checkpoint_interval {
                         storage.flush_checkpoint()?;
                         did_checkpoint = true;
@@ -971,16 +1313,63 @@ impl App {
                 }
 
                 if did_checkpoint {
+                    if let Some(warning) = self
+                        .shm_heap_sampler
+                        .as_mut()
+                        .and_then(|shm| shm.check_overhead())
+                    {
+                        self.status_message = Some((warning, Instant::now()));
+                    }
+                    if self.new_dropped_events > 0 {
+                        self.dropped_events += std::mem::take(&mut self.new_dropped_events);
+                        self.status_message = Some((
+                            format!(
+                                "Lost {} samples (profiler tables full)",
+                                self.dropped_events
+                            ),
+                            Instant::now(),
+                        ));
+                    }
+                    if let Some(auto) = self.auto_freq.as_mut()
+                        && let Some(freq) =
+                            auto.adjust(self.sampler.as_mut(), self.shm_heap_sampler.as_ref())?
+                    {
+                        self.status_message = Some((
+                            format!("CPU sampling frequency: {} Hz", freq),
+                            Instant::now(),
+                        ));
+                        // Samples are now at mixed rates; convert them at the average
+                        let effective = auto.effective_freq_hz();
+                        self.cpu_freq_hz = Some(effective);
+                        if let Some(storage) = &self.storage {
+                            storage.set_meta("cpu_freq_changes", &auto.changes_meta())?;
+                            storage.set_meta("cpu_freq_hz", &format!("{:.2}", effective))?;
+                        }
+                    }
                     self.chart_checkpoint_seq = self.chart_checkpoint_seq.wrapping_add(1);
-                    for (location_id, entry) in heap_entries_map {
+                    for (location_id, mut entry) in heap_entries_map {
+                        // Same clamping as the stored samples (see Storage::flush_checkpoint)
+                        entry.live_bytes = entry.live_bytes.max(0);
                         self.heap_live_entries.insert(location_id, entry);
                     }
+                    self.evict_heap_locations();
+                    self.cpu_allocs = self
+                        .heap_live_entries
+                        .iter()
+                        .filter(|(_, e)| e.total_alloc_bytes > 0)
+                        .map(|(id, e)| (*id, (e.total_alloc_bytes, e.alloc_count)))
+                        .collect();
                     self.last_checkpoint = Instant::now();
                     self.refresh_cpu_entries();
                     let heap_entries: Vec<HeapEntry> =
                         self.heap_live_entries.values().cloned().collect();
                     self.update_heap_entries(heap_entries);
                     self.update_sparklines();
+                    if let Some(storage) = &self.storage {
+                        self.checkpoint_labels = storage.query_checkpoint_labels();
+                        self.checkpoint_stats = storage.query_checkpoint_stats();
+                        self.tag_entries = storage.query_top_markers(usize::MAX);
+                    }
                     // New data available; refresh chart data next time it's rendered.
                     self.chart_data_cache.location_id = None;
                     self.heap_chart_cache.location_id = None;
@@ -1070,10 +1459,19 @@ impl App {
             } else {
                 Duration::from_millis(33)
             };
-            if needs_redraw || checkpointed || self.last_draw.elapsed() >= frame_interval {
-                terminal.draw(|frame| {
+            if needs_redraw
+                || checkpointed
+                || self.screenshot_requested
+                || self.last_draw.elapsed() >= frame_interval
+            {
+                let completed = terminal.draw(|frame| {
                     ui::render(frame, self);
                 })?;
+                if self.screenshot_requested {
+                    self.screenshot_requested = false;
+                    let text = ui::buffer_to_text(completed.buffer);
+                    self.save_screenshot(&text);
+                }
                 self.last_draw = Instant::now();
             }
         }
@@ -1081,6 +1479,10 @@ impl App {
         // Final flush (live mode only)
         if let Some(storage) = self.storage.as_mut() {
             storage.flush_checkpoint()?;
+            if let Some(auto) = &self.auto_freq {
+                storage.set_meta("cpu_freq_changes", &auto.changes_meta())?;
+                storage.set_meta("cpu_freq_hz", &format!("{:.2}", auto.effective_freq_hz()))?;
+            }
         }
 
         Ok(())
@@ -1089,16 +1491,42 @@ impl App {
     fn handle_key(&mut self, key: KeyCode, modifiers: KeyModifiers) {
         let ctrl = modifiers.contains(KeyModifiers::CONTROL);
 
+        if self.filter_editing && !(ctrl && key == KeyCode::Char('c')) {
+            self.handle_filter_key(key);
+            return;
+        }
+        if self.flame_visible && self.handle_flame_key(key, ctrl) {
+            return;
+        }
+        if self.focus == Focus::CallTree && self.call_tree_visible && self.handle_call_tree_key(key)
+        {
+            return;
+        }
+
         match key {
             // Global controls
             KeyCode::Char('c') if ctrl => self.running = false,
             KeyCode::Char('q') => self.running = false,
+            // ] / [ - next/previous profile in a playlist
+            KeyCode::Char(c @ (']' | '[')) if self.playlist.is_some() => {
+                if let Some(playlist) = self.playlist.as_mut() {
+                    playlist.step = Some(if c == ']' { 1 } else { -1 });
+                }
+                self.running = false;
+            }
             KeyCode::Esc => {
-                // ESC hides the chart if visible, otherwise does nothing
+                // ESC hides the chart if visible, else clears the row filter
                 if self.chart_visible {
                     self.chart_visible = false;
+                } else if !self.filter.is_empty() {
+                    self.filter.clear();
+                    self.apply_filter();
                 }
             }
+            // / - type a row filter
+            KeyCode::Char('/') => self.filter_editing = true,
+            // F - flamegraph of the current view's call stacks
+            KeyCode::Char('F') => self.flame_visible = !self.flame_visible,
             KeyCode::Char('p') if !self.is_static() => {
                 self.paused = !self.paused;
                 if self.paused {
@@ -1109,8 +1537,11 @@ impl App {
             }
             KeyCode::Tab => {
                 self.focus = match self.focus {
+                    Focus::Table if self.call_tree_visible && !self.chart_visible => {
+                        Focus::CallTree
+                    }
                     Focus::Table => Focus::Chart,
-                    Focus::Chart => Focus::Table,
+                    Focus::Chart | Focus::CallTree => Focus::Table,
                 };
             }
 
@@ -1129,9 +1560,83 @@ impl App {
                     ViewMode::Memory => ViewMode::Cpu,
                 };
             }
+            // r - toggle absolute/relative CPU hotness coloring
+            KeyCode::Char('r') => {
+                self.heat_mode = match self.heat_mode {
+                    HeatMode::Absolute => HeatMode::Relative,
+                    HeatMode::Relative => HeatMode::Absolute,
+                };
+            }
+            // w - CPU% of the whole run or of the visible chart window
+            KeyCode::Char('w') if self.view_mode == ViewMode::Cpu => {
+                self.percent_base = match self.percent_base {
+                    PercentBase::Total => PercentBase::Window,
+                    PercentBase::Window => PercentBase::Total,
+                };
+                self.cpu_window.range_ms = None;
+                self.sort_cpu_entries();
+            }
+            // a - toggle memory sparklines between live bytes and alloc counts
+            KeyCode::Char('a') if self.view_mode == ViewMode::Memory => {
+                self.sparkline_source = match self.sparkline_source {
+                    SparklineSource::LiveBytes => SparklineSource::AllocCount,
+                    SparklineSource::AllocCount => SparklineSource::LiveBytes,
+                };
+            }
+            // T - memory rows per allocation site or per marker/tag
+            KeyCode::Char('T') if self.view_mode == ViewMode::Memory => {
+                self.heap_grouping = match self.heap_grouping {
+                    HeapGrouping::Site => HeapGrouping::Tag,
+                    HeapGrouping::Tag => HeapGrouping::Site,
+                };
+                self.selected_row = 0;
+                self.scroll_offset = 0;
+                self.update_selection_from_row();
+            }
+            // > / < - raise/lower the current table's display threshold
+            KeyCode::Char('>') => self.step_display_threshold(1),
+            KeyCode::Char('<') => self.step_display_threshold(-1),
+            // D - cycle function names: short / full / raw
+            KeyCode::Char('D') => {
+                self.demangle_mode = match self.demangle_mode {
+                    DemangleMode::Short => DemangleMode::Full,
+                    DemangleMode::Full => DemangleMode::Raw,
+                    DemangleMode::Raw => DemangleMode::Short,
+                };
+            }
+            // R - quit and print the command that records this target again
+            KeyCode::Char('R') if self.rerecord_command.is_some() => {
+                self.rerecord_requested = true;
+                self.running = false;
+            }
+            // s - save the current screen as plain text
+            KeyCode::Char('s') => {
+                self.screenshot_requested = true;
+            }
             // c or Enter - toggle chart visibility
             KeyCode::Char('c') | KeyCode::Enter => {
                 self.chart_visible = !self.chart_visible;
+                if self.focus == Focus::CallTree {
+                    self.focus = Focus::Table;
+                }
+            }
+            // Source pane takes the chart's place
+            KeyCode::Char('o') => {
+                self.source_visible = !self.source_visible;
+                if self.source_visible {
+                    self.chart_visible = false;
+                    self.call_tree_visible = false;
+                }
+            }
+            // t - callers/callees pane, in the same place
+            KeyCode::Char('t') => {
+                self.call_tree_visible = !self.call_tree_visible;
+                if self.call_tree_visible {
+                    self.chart_visible = false;
+                    self.source_visible = false;
+                } else if self.focus == Focus::CallTree {
+                    self.focus = Focus::Table;
+                }
             }
 
             // === TABLE CONTROLS (vim-style) ===
@@ -1228,11 +1733,37 @@ impl App {
             KeyCode::Char('z') if self.focus == Focus::Chart => {
                 self.chart_state.toggle_y_axis_zero();
             }
+            // A - cycle how chart buckets collapse: max / mean / p95
+            KeyCode::Char('A') if self.focus == Focus::Chart => {
+                self.chart_state.cycle_aggregate();
+                self.chart_data_cache.location_id = None;
+                self.heap_chart_cache.location_id = None;
+            }
+            // v - cycle the CPU chart series: instant / cumulative / running average
+            KeyCode::Char('v') if self.focus == Focus::Chart && self.view_mode == ViewMode::Cpu => {
+                self.cpu_series = match self.cpu_series {
+                    CpuSeries::Instant => CpuSeries::Cumulative,
+                    CpuSeries::Cumulative => CpuSeries::RunningAverage,
+                    CpuSeries::RunningAverage => CpuSeries::Instant,
+                };
+                self.chart_data_cache.location_id = None;
+            }
 
             _ => {}
         }
     }
 
+    /// Write a screenshot to `rsprof-screen.<timestamp>.txt` in the current directory
+    fn save_screenshot(&mut self, text: &str) {
+        let timestamp = chrono::Local::now().format("%y%m%d%H%M%S");
+        let path = format!("rsprof-screen.{}.txt", timestamp);
+        let message = match std::fs::write(&path, text) {
+            Ok(()) => format!("Saved {}", path),
+            Err(e) => format!("Screenshot failed: {}", e),
+        };
+        self.status_message = Some((message, Instant::now()));
+    }
+
     /// Move table selection by delta rows (positive = down, negative = up)
     fn move_selection(&mut self, delta: i32) {
         let entry_count = self.active_entry_count();
@@ -1249,10 +1780,234 @@ impl App {
     fn active_entry_count(&self) -> usize {
         match self.view_mode {
             ViewMode::Cpu => self.cached_entries.len(),
+            ViewMode::Memory if self.heap_grouping == HeapGrouping::Tag => self.tag_entries.len(),
             ViewMode::Memory => self.cached_heap_entries.len(),
         }
     }
 
+    /// Navigate the flamegraph; false for keys it leaves to `handle_key`
+    fn handle_flame_key(&mut self, key: KeyCode, ctrl: bool) -> bool {
+        let view = &mut self.flame;
+        match key {
+            KeyCode::Esc => self.flame_visible = false,
+            // h/l - frames under the same caller, like panning the chart
+            KeyCode::Char('h') | KeyCode::Left if !ctrl => view.select_sibling(-1),
+            KeyCode::Char('l') | KeyCode::Right if !ctrl => view.select_sibling(1),
+            // k/j - caller / heaviest callee
+            KeyCode::Char('k') | KeyCode::Up => view.select_parent(),
+            KeyCode::Char('j') | KeyCode::Down => view.select_child(),
+            // Enter or +/= zooms into the selected frame, - back out
+            KeyCode::Enter | KeyCode::Char('+') | KeyCode::Char('=') => view.zoom_in(),
+            KeyCode::Char('-') => view.zoom_out(),
+            KeyCode::Char('0') | KeyCode::Char('^') | KeyCode::Home => view.reset_zoom(),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Flamegraph of the current view, rebuilt when its weights change
+    pub fn flame_view(&mut self) -> &FlameView {
+        self.refresh_stacks();
+        if self.flame_generation != self.stacks_generation {
+            let graph = FlameGraph::from_stacks(
+                self.stacks
+                    .iter()
+                    .map(|(frames, weight)| (frames.as_slice(), *weight)),
+            );
+            self.flame.replace_graph(graph);
+            self.flame_generation = self.stacks_generation;
+        }
+        &self.flame
+    }
+
+    /// Re-read the current view's call stacks when their weights change
+    fn refresh_stacks(&mut self) {
+        let source = (
+            self.view_mode,
+            self.sparkline_source,
+            self.chart_checkpoint_seq,
+        );
+        if self.stacks_source != Some(source) {
+            self.stacks = self.query_stacks();
+            self.stacks_source = Some(source);
+            self.stacks_generation += 1;
+        }
+    }
+
+    /// Recorded stacks weighted by CPU samples, or by live bytes /
+    /// allocations as the memory sparklines show
+    fn query_stacks(&self) -> Vec<(Vec<Location>, u64)> {
+        match self.view_mode {
+            ViewMode::Cpu => {
+                let stacks = if let Some(storage) = &self.storage {
+                    storage.query_cpu_stacks()
+                } else if let Some(conn) = &self.conn {
+                    query_cpu_stacks(conn).unwrap_or_default()
+                } else {
+                    Vec::new()
+                };
+                stacks
+                    .into_iter()
+                    .map(|stack| (stack.frames, stack.samples))
+                    .collect()
+            }
+            ViewMode::Memory => {
+                let stacks = if let Some(storage) = &self.storage {
+                    storage.query_heap_stacks(usize::MAX)
+                } else if let Some(conn) = &self.conn {
+                    query_heap_stacks(conn, usize::MAX).unwrap_or_default()
+                } else {
+                    Vec::new()
+                };
+                stacks
+                    .into_iter()
+                    .map(|stack| {
+                        let weight = match self.sparkline_source {
+                            SparklineSource::LiveBytes => stack.live_bytes.max(0) as u64,
+                            SparklineSource::AllocCount => stack.alloc_count,
+                        };
+                        (stack.frames, weight)
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Move through the call tree pane; Enter opens the function under the
+    /// cursor. False for keys it leaves to `handle_key`
+    fn handle_call_tree_key(&mut self, key: KeyCode) -> bool {
+        match key {
+            KeyCode::Char('j') | KeyCode::Down => self.call_tree_row += 1,
+            KeyCode::Char('k') | KeyCode::Up => {
+                self.call_tree_row = self.call_tree_row.saturating_sub(1);
+            }
+            KeyCode::Enter | KeyCode::Char('l') | KeyCode::Right => self.open_call_tree_row(),
+            KeyCode::Esc => self.focus = Focus::Table,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Center the call tree on the caller/callee under the cursor, selecting
+    /// its table row if it has one
+    fn open_call_tree_row(&mut self) {
+        let Some(function) = self
+            .call_tree
+            .as_ref()
+            .and_then(|tree| tree.neighbours().nth(self.call_tree_row))
+            .map(|(function, _)| function.clone())
+        else {
+            return;
+        };
+        let row = match self.view_mode {
+            ViewMode::Cpu => self
+                .cached_entries
+                .iter()
+                .position(|e| e.function == function),
+            ViewMode::Memory if self.heap_grouping == HeapGrouping::Tag => None,
+            ViewMode::Memory => self
+                .cached_heap_entries
+                .iter()
+                .position(|e| e.function == function),
+        };
+        match row {
+            Some(row) => {
+                self.call_tree_target = None;
+                self.selected_row = row;
+                self.update_selection_from_row();
+                self.ensure_selection_visible();
+            }
+            // Only its callees were sampled: keep the row, follow the function
+            None => {
+                if let Some((_, _, row_function)) = self.selected_location() {
+                    self.call_tree_target = Some((row_function.to_string(), function));
+                }
+            }
+        }
+        self.call_tree_row = 0;
+    }
+
+    /// Edit the row filter: typing narrows the rows as you go, Enter keeps
+    /// the filter, Esc drops it
+    fn handle_filter_key(&mut self, key: KeyCode) {
+        match key {
+            KeyCode::Char(c) => self.filter.push(c),
+            KeyCode::Backspace => {
+                self.filter.pop();
+            }
+            KeyCode::Enter => {
+                self.filter_editing = false;
+                return;
+            }
+            KeyCode::Esc => {
+                self.filter_editing = false;
+                self.filter.clear();
+            }
+            _ => return,
+        }
+        self.apply_filter();
+    }
+
+    /// Rebuild both tables for a changed filter, keeping the selected
+    /// location selected while it still matches (else the row in its place),
+    /// so clearing the filter leaves the last match selected
+    fn apply_filter(&mut self) {
+        self.ensure_selection_anchor();
+        self.sort_all_entries();
+        self.reselect_anchor();
+        self.selected_row = self
+            .selected_row
+            .min(self.active_entry_count().saturating_sub(1));
+        self.update_selection_from_row();
+        self.ensure_selection_visible();
+    }
+
+    /// Row filter, and whether it is being typed
+    pub fn filter(&self) -> (&str, bool) {
+        (&self.filter, self.filter_editing)
+    }
+
+    /// Move the current table's display threshold up or down one step
+    ///
+    /// View-only: rows under the threshold are hidden from the cached
+    /// entries, and come back when it is lowered, without a new query.
+    fn step_display_threshold(&mut self, delta: isize) {
+        let step = |current: usize, len: usize| current.saturating_add_signed(delta).min(len - 1);
+        match self.view_mode {
+            ViewMode::Cpu => {
+                self.cpu_threshold_step = step(self.cpu_threshold_step, CPU_THRESHOLD_STEPS.len());
+                self.sort_cpu_entries();
+            }
+            ViewMode::Memory => {
+                self.heap_threshold_step =
+                    step(self.heap_threshold_step, HEAP_THRESHOLD_STEPS.len());
+                self.sort_heap_entries();
+            }
+        }
+        self.selected_row = self
+            .selected_row
+            .min(self.active_entry_count().saturating_sub(1));
+        self.ensure_selection_visible();
+    }
+
+    /// Minimum CPU% of a shown CPU row (0 when every row is shown)
+    pub fn cpu_threshold(&self) -> f64 {
+        CPU_THRESHOLD_STEPS[self.cpu_threshold_step]
+    }
+
+    /// Minimum live bytes of a shown heap row (0 when every row is shown)
+    pub fn heap_threshold(&self) -> i64 {
+        HEAP_THRESHOLD_STEPS[self.heap_threshold_step]
+    }
+
+    /// Rows of the current table hidden by its display threshold or filter
+    pub fn hidden_row_count(&self) -> usize {
+        match self.view_mode {
+            ViewMode::Cpu => self.all_entries.len() - self.cached_entries.len(),
+            ViewMode::Memory => self.all_heap_entries.len() - self.cached_heap_entries.len(),
+        }
+    }
+
     /// Get half page size for Ctrl+d/u
     fn half_page(&self) -> usize {
         let visible = self.table_area.height.saturating_sub(3) as usize;
@@ -1369,7 +2124,45 @@ impl App {
     }
 
     pub fn heap_sparklines(&self) -> &HashMap<i64, VecDeque<i64>> {
-        &self.cached_heap_sparklines
+        match self.sparkline_source {
+            SparklineSource::LiveBytes => &self.cached_heap_sparklines,
+            SparklineSource::AllocCount => &self.cached_heap_count_sparklines,
+        }
+    }
+
+    /// Heap usage per marker/tag, most allocated first
+    pub fn tag_entries(&self) -> &[MarkerEntry] {
+        &self.tag_entries
+    }
+
+    /// Heap sites whose allocation rate just jumped (live mode)
+    pub fn churn_alerts(&self) -> &HashMap<i64, ChurnAlert> {
+        self.churn.alerts()
+    }
+
+    /// (bytes, allocations) made per location (None without heap data)
+    pub fn cpu_allocs(&self) -> Option<&HashMap<i64, (i64, u64)>> {
+        (!self.cpu_allocs.is_empty()).then_some(&self.cpu_allocs)
+    }
+
+    /// (cycles, instructions) per location (None without those `--counters`)
+    pub fn cpu_ipc_counts(&self) -> Option<&HashMap<i64, (u64, u64)>> {
+        (!self.cpu_ipc_counts.is_empty()).then_some(&self.cpu_ipc_counts)
+    }
+
+    /// CPU sampling frequency in Hz (None if the profile doesn't record it)
+    pub fn cpu_freq_hz(&self) -> Option<f64> {
+        self.cpu_freq_hz
+    }
+
+    /// Labeled checkpoints as (seconds, label)
+    pub fn checkpoint_labels(&self) -> &[(f64, String)] {
+        &self.checkpoint_labels
+    }
+
+    /// CPU samples and sampling threads per checkpoint, oldest first
+    pub fn checkpoint_stats(&self) -> &[CheckpointStats] {
+        &self.checkpoint_stats
     }
 
     pub fn func_history(&self) -> &[(f64, f64)] {
@@ -1380,6 +2173,84 @@ impl App {
         self.selected_func_name.as_deref()
     }
 
+    /// File, line and function of the selected row (None for marker/tag rows)
+    fn selected_location(&self) -> Option<(&str, u32, &str)> {
+        match self.view_mode {
+            ViewMode::Cpu => {
+                let entry = match self.selected_location_id {
+                    Some(id) => self.cached_entries.iter().find(|e| e.location_id == id),
+                    None => self.cached_entries.get(self.selected_row),
+                }?;
+                Some((entry.file.as_str(), entry.line, entry.function.as_str()))
+            }
+            ViewMode::Memory if self.heap_grouping == HeapGrouping::Tag => None,
+            ViewMode::Memory => {
+                let id = self.selected_heap_location_id()?;
+                let entry = self
+                    .cached_heap_entries
+                    .iter()
+                    .find(|e| e.location_id == id)?;
+                Some((entry.file.as_str(), entry.line, entry.function.as_str()))
+            }
+        }
+    }
+
+    /// Source around the selected row, read when the selection changes
+    pub fn selected_source(&mut self) -> Option<&SourceSnippet> {
+        let Some((file, line, _)) = self.selected_location() else {
+            self.source_snippet = None;
+            return None;
+        };
+        if !self
+            .source_snippet
+            .as_ref()
+            .is_some_and(|snippet| snippet.is_for(file, line))
+        {
+            self.source_snippet = Some(SourceSnippet::load(&self.source_roots, file, line));
+        }
+        self.source_snippet.as_ref()
+    }
+
+    /// Callers and callees of the selected row's function, rebuilt when the
+    /// selection or the stacks change
+    pub fn selected_call_tree(&mut self) -> Option<&CallTree> {
+        let Some((_, _, row_function)) = self.selected_location() else {
+            self.call_tree = None;
+            return None;
+        };
+        let function = match &self.call_tree_target {
+            Some((row, target)) if row == row_function => target.clone(),
+            _ => row_function.to_string(),
+        };
+        self.refresh_stacks();
+        let moved = self
+            .call_tree
+            .as_ref()
+            .is_none_or(|tree| tree.function != function);
+        if moved || self.call_tree_generation != self.stacks_generation {
+            if moved {
+                self.call_tree_row = 0;
+            }
+            self.call_tree = Some(CallTree::build(
+                self.stacks
+                    .iter()
+                    .map(|(frames, weight)| (frames.as_slice(), *weight)),
+                &function,
+            ));
+            self.call_tree_generation = self.stacks_generation;
+        }
+        let tree = self.call_tree.as_ref()?;
+        self.call_tree_row = self
+            .call_tree_row
+            .min(tree.neighbour_count().saturating_sub(1));
+        Some(tree)
+    }
+
+    /// Pane cursor: index into the call tree's callers, then callees
+    pub fn call_tree_row(&self) -> usize {
+        self.call_tree_row
+    }
+
     pub fn active_sort(&self) -> TableSort {
         match self.view_mode {
             ViewMode::Cpu => self.cpu_sort,
@@ -1468,6 +2339,7 @@ impl App {
                     self.update_selected_cpu(location_id, &func_name);
                 }
             }
+            ViewMode::Memory if self.heap_grouping == HeapGrouping::Tag => {}
             ViewMode::Memory => {
                 let entry = self
                     .cached_heap_entries
@@ -1487,7 +2359,7 @@ impl App {
 
         // Update CPU sparklines
         let cpu_current: HashMap<i64, i64> = self
-            .cached_entries
+            .all_entries
             .iter()
             .map(|e| (e.location_id, (e.instant_percent * 1000.0) as i64))
             .collect();
@@ -1503,7 +2375,7 @@ impl App {
             sparkline.push_back(cpu_current.get(loc_id).copied().unwrap_or(0));
         }
 
-        for entry in &self.cached_entries {
+        for entry in &self.all_entries {
             self.cached_cpu_sparklines
                 .entry(entry.location_id)
                 .or_insert_with(|| {
@@ -1518,7 +2390,7 @@ impl App {
 
         // Update heap sparklines
         let heap_current: HashMap<i64, i64> = self
-            .cached_heap_entries
+            .all_heap_entries
             .iter()
             .map(|e| (e.location_id, e.live_bytes))
             .collect();
@@ -1534,7 +2406,7 @@ impl App {
             sparkline.push_back(heap_current.get(loc_id).copied().unwrap_or(0));
         }
 
-        for entry in &self.cached_heap_entries {
+        for entry in &self.all_heap_entries {
             self.cached_heap_sparklines
                 .entry(entry.location_id)
                 .or_insert_with(|| {
@@ -1544,6 +2416,59 @@ impl App {
                 });
         }
 
+        // Update allocation-count sparklines (allocs since the previous checkpoint)
+        let count_current: HashMap<i64, i64> = self
+            .all_heap_entries
+            .iter()
+            .map(|e| {
+                let prev = self
+                    .prev_heap_alloc_counts
+                    .insert(e.location_id, e.alloc_count)
+                    .unwrap_or(0);
+                (e.location_id, e.alloc_count.saturating_sub(prev) as i64)
+            })
+            .collect();
+        self.prev_heap_alloc_counts
+            .retain(|loc_id, _| count_current.contains_key(loc_id));
+
+        // Flag sites whose allocation rate jumped above their recent baseline
+        let flagged = self
+            .churn
+            .observe(self.chart_checkpoint_seq, &count_current);
+        if let Some(alert) = flagged
+            .iter()
+            .filter_map(|id| Some((id, self.churn.alerts().get(id)?)))
+            .max_by(|a, b| a.1.z_score.total_cmp(&b.1.z_score))
+            .and_then(|(id, alert)| {
+                let entry = self
+                    .all_heap_entries
+                    .iter()
+                    .find(|e| e.location_id == *id)?;
+                Some(format!(
+                    "Allocation rate jump: {} at {}:{} ({} allocs/checkpoint, baseline {:.0})",
+                    shorten_function_name(split_symbol_hash(&entry.function).0),
+                    entry.file,
+                    entry.line,
+                    alert.allocs,
+                    alert.baseline
+                ))
+            })
+        {
+            self.status_message = Some((alert, Instant::now()));
+        }
+        self.cached_heap_count_sparklines
+            .retain(|loc_id, _| count_current.contains_key(loc_id));
+        for (&loc_id, &count) in &count_current {
+            let sparkline = self
+                .cached_heap_count_sparklines
+                .entry(loc_id)
+                .or_insert_with(|| VecDeque::with_capacity(SPARKLINE_WIDTH));
+            if sparkline.len() >= SPARKLINE_WIDTH {
+                sparkline.pop_front();
+            }
+            sparkline.push_back(count);
+        }
+
         // Drop stale heap entries once they fall off the sparkline window.
         self.prune_heap_entries();
 
@@ -1554,11 +2479,20 @@ impl App {
     fn refresh_cpu_entries(&mut self) {
         let total_samples = self.total_samples as f64;
         if total_samples <= 0.0 {
+            self.all_entries.clear();
             self.cached_entries.clear();
             self.live_cpu_instant.clear();
             return;
         }
 
+        for (&location_id, &count) in &self.live_cpu_instant {
+            if count > 0 {
+                self.cpu_last_sampled
+                    .insert(location_id, self.chart_checkpoint_seq);
+            }
+        }
+        self.evict_cpu_locations();
+
         let instant_total: u64 = self.live_cpu_instant.values().sum();
         let mut entries = Vec::new();
         for (&location_id, &total) in &self.live_cpu_totals {
@@ -1594,8 +2528,8 @@ impl App {
                 .cmp(&a.total_samples)
                 .then(a.location_id.cmp(&b.location_id))
         });
-        self.cached_entries = entries;
-        for entry in &self.cached_entries {
+        self.all_entries = entries;
+        for entry in &self.all_entries {
             self.cpu_last_seen
                 .insert(entry.location_id, self.chart_checkpoint_seq);
         }
@@ -1604,10 +2538,63 @@ impl App {
         self.sort_cpu_entries();
     }
 
+    /// Past `live_max_locations`, forget the least recently sampled CPU
+    /// locations (smallest totals first among equals)
+    ///
+    /// They stay in the profile; if one is sampled again its live total
+    /// starts over.
+    fn evict_cpu_locations(&mut self) {
+        if self.live_cpu_totals.len() <= self.live_max_locations {
+            return;
+        }
+        let mut ranked: Vec<(u64, u64, i64)> = self
+            .live_cpu_totals
+            .iter()
+            .map(|(&id, &total)| {
+                let last = self.cpu_last_sampled.get(&id).copied().unwrap_or(0);
+                (last, total, id)
+            })
+            .collect();
+        ranked.sort_unstable_by(|a, b| b.cmp(a));
+        let keep: std::collections::HashSet<i64> = ranked
+            .iter()
+            .take(self.live_max_locations)
+            .map(|&(_, _, id)| id)
+            .collect();
+        self.live_cpu_totals.retain(|id, _| keep.contains(id));
+        self.live_cpu_instant.retain(|id, _| keep.contains(id));
+        self.location_info.retain(|id, _| keep.contains(id));
+        self.cpu_last_seen.retain(|id, _| keep.contains(id));
+        self.cpu_last_sampled.retain(|id, _| keep.contains(id));
+        self.cpu_ipc_counts.retain(|id, _| keep.contains(id));
+    }
+
+    /// Past `live_max_locations`, keep only the heap locations holding the
+    /// most live bytes (then the most allocated)
+    ///
+    /// Their counters are cumulative, so a location that comes back is exact.
+    fn evict_heap_locations(&mut self) {
+        if self.heap_live_entries.len() <= self.live_max_locations {
+            return;
+        }
+        let mut ranked: Vec<(i64, i64, i64)> = self
+            .heap_live_entries
+            .values()
+            .map(|e| (e.live_bytes, e.total_alloc_bytes, e.location_id))
+            .collect();
+        ranked.sort_unstable_by(|a, b| b.cmp(a));
+        let keep: std::collections::HashSet<i64> = ranked
+            .iter()
+            .take(self.live_max_locations)
+            .map(|&(_, _, id)| id)
+            .collect();
+        self.heap_live_entries.retain(|id, _| keep.contains(id));
+    }
+
     fn update_heap_entries(&mut self, entries: Vec<HeapEntry>) {
-        self.cached_heap_entries = entries;
+        self.all_heap_entries = entries;
         self.sort_heap_entries();
-        for entry in &self.cached_heap_entries {
+        for entry in &self.all_heap_entries {
             self.heap_last_seen
                 .insert(entry.location_id, self.chart_checkpoint_seq);
         }
@@ -1615,7 +2602,7 @@ impl App {
 
     fn prune_cpu_entries(&mut self) {
         let cutoff = self.chart_checkpoint_seq.saturating_sub(SPARKLINE_WIDTH);
-        self.cached_entries.retain(|entry| {
+        self.all_entries.retain(|entry| {
             self.cpu_last_seen
                 .get(&entry.location_id)
                 .copied()
@@ -1623,15 +2610,16 @@ impl App {
                 > cutoff
         });
         let keep: std::collections::HashSet<i64> =
-            self.cached_entries.iter().map(|e| e.location_id).collect();
+            self.all_entries.iter().map(|e| e.location_id).collect();
         self.live_cpu_totals.retain(|id, _| keep.contains(id));
         self.location_info.retain(|id, _| keep.contains(id));
         self.cpu_last_seen.retain(|id, _| keep.contains(id));
+        self.sort_cpu_entries();
     }
 
     fn prune_heap_entries(&mut self) {
         let cutoff = self.chart_checkpoint_seq.saturating_sub(SPARKLINE_WIDTH);
-        self.cached_heap_entries.retain(|entry| {
+        self.all_heap_entries.retain(|entry| {
             self.heap_last_seen
                 .get(&entry.location_id)
                 .copied()
@@ -1639,12 +2627,13 @@ impl App {
                 > cutoff
         });
         let keep: std::collections::HashSet<i64> = self
-            .cached_heap_entries
+            .all_heap_entries
             .iter()
             .map(|e| e.location_id)
             .collect();
         self.heap_live_entries.retain(|id, _| keep.contains(id));
         self.heap_last_seen.retain(|id, _| keep.contains(id));
+        self.sort_heap_entries();
     }
 
     fn sort_all_entries(&mut self) {
@@ -1652,11 +2641,26 @@ impl App {
         self.sort_heap_entries();
     }
 
+    /// Rebuild the CPU rows: entries at or above the display threshold and
+    /// matching the filter, sorted
     fn sort_cpu_entries(&mut self) {
         let sort = self.cpu_sort;
+        let base = self.percent_base;
+        let window = &self.cpu_window;
+        let floor = CPU_THRESHOLD_STEPS[self.cpu_threshold_step];
+        let filter = self.filter.to_lowercase();
+        self.cached_entries = self
+            .all_entries
+            .iter()
+            .filter(|e| cpu_share(base, window, e).1 >= floor)
+            .filter(|e| matches_filter(&filter, &e.function, &e.file, e.line))
+            .cloned()
+            .collect();
         self.cached_entries.sort_by(|a, b| {
             let ordering = match sort.column {
-                SortColumn::Total => cmp_f64(a.total_percent, b.total_percent),
+                SortColumn::Total => {
+                    cmp_f64(cpu_share(base, window, a).1, cpu_share(base, window, b).1)
+                }
                 SortColumn::Live | SortColumn::Trend => {
                     cmp_f64(a.instant_percent, b.instant_percent)
                 }
@@ -1672,8 +2676,19 @@ impl App {
         });
     }
 
+    /// Rebuild the heap rows: entries at or above the display threshold and
+    /// matching the filter, sorted
     fn sort_heap_entries(&mut self) {
         let sort = self.heap_sort;
+        let floor = HEAP_THRESHOLD_STEPS[self.heap_threshold_step];
+        let filter = self.filter.to_lowercase();
+        self.cached_heap_entries = self
+            .all_heap_entries
+            .iter()
+            .filter(|e| floor == 0 || e.live_bytes >= floor)
+            .filter(|e| matches_filter(&filter, &e.function, &e.file, e.line))
+            .cloned()
+            .collect();
         self.cached_heap_entries.sort_by(|a, b| {
             let ordering = match sort.column {
                 SortColumn::Total => a.total_alloc_bytes.cmp(&b.total_alloc_bytes),
@@ -1771,10 +2786,35 @@ impl App {
             return None;
         }
 
-        let fixed_width = 8 + 8 + 14;
+        // The CPU view has extra (unsortable) Alloc and IPC columns when heap or
+        // counter data exists
+        let mut alloc_width = 0;
+        if self.view_mode == ViewMode::Cpu {
+            if self.cpu_allocs().is_some() {
+                alloc_width += 10;
+            }
+            if self.cpu_ipc_counts().is_some() {
+                alloc_width += 6;
+            }
+        }
+        let [function, location, trend] =
+            ui::variable_column_widths(self.column_widths, self.table_area.width);
+        let fixed = |c: Constraint| match c {
+            Constraint::Length(width) => width,
+            _ => 0,
+        };
+        let fill = |c: Constraint| match c {
+            Constraint::Fill(weight) => weight,
+            _ => 0,
+        };
+        let trend_width = fixed(trend);
+        let fixed_width = 8 + 8 + alloc_width + fixed(function) + fixed(location) + trend_width;
         let remaining = inner_width.saturating_sub(fixed_width);
-        let func_width = remaining / 2;
-        let loc_width = remaining - func_width;
+        // Fill columns split what the fixed ones leave, by weight
+        let fill_total = fill(function) + fill(location);
+        let share = |c: Constraint| (remaining * fill(c)).checked_div(fill_total).unwrap_or(0);
+        let func_width = fixed(function) + share(function);
+        let loc_width = fixed(location) + share(location);
 
         let mut offset = 0u16;
         let pos = x.saturating_sub(inner_x);
@@ -1787,6 +2827,10 @@ impl App {
             return Some(SortColumn::Live);
         }
         offset += 8;
+        if pos < offset + alloc_width {
+            return None;
+        }
+        offset += alloc_width;
         if pos < offset + func_width {
             return Some(SortColumn::Function);
         }
@@ -1795,7 +2839,7 @@ impl App {
             return Some(SortColumn::Location);
         }
         offset += loc_width;
-        if pos < offset + 14 {
+        if pos < offset + trend_width {
             return Some(SortColumn::Trend);
         }
         None
@@ -1847,9 +2891,19 @@ impl App {
                     start_ms,
                     end_ms,
                     num_buckets,
+                    self.cpu_series,
+                    self.chart_state.aggregate,
                 )
             } else if let Some(conn) = &self.conn {
-                query_cpu_timeseries_aggregated(conn, location_id, start_ms, end_ms, num_buckets)
+                query_cpu_timeseries_aggregated(
+                    conn,
+                    location_id,
+                    start_ms,
+                    end_ms,
+                    num_buckets,
+                    self.cpu_series,
+                    self.chart_state.aggregate,
+                )
             } else {
                 Vec::new()
             };
@@ -1964,7 +3018,13 @@ impl App {
 
             // Query from DB with aggregation
             let data = if let Some(storage) = &self.storage {
-                storage.query_heap_timeseries_aggregated(location_id, start_ms, end_ms, num_buckets)
+                storage.query_heap_timeseries_aggregated(
+                    location_id,
+                    start_ms,
+                    end_ms,
+                    num_buckets,
+                    self.chart_state.aggregate,
+                )
             } else if let Some(conn) = &self.conn {
                 crate::storage::query_heap_timeseries_aggregated(
                     conn,
@@ -1972,13 +3032,22 @@ impl App {
                     start_ms,
                     end_ms,
                     num_buckets,
+                    self.chart_state.aggregate,
                 )
             } else {
                 Vec::new()
             };
+            let sizes = if let Some(storage) = &self.storage {
+                storage.query_heap_sizes(location_id)
+            } else if let Some(conn) = &self.conn {
+                crate::storage::query_heap_sizes(conn, location_id)
+            } else {
+                None
+            };
 
             // Update cache
             self.heap_chart_cache.location_id = Some(location_id);
+            self.heap_chart_cache.sizes = sizes;
             self.heap_chart_cache.cache_start_secs = prefetch_start;
             self.heap_chart_cache.cache_end_secs = prefetch_end;
             self.heap_chart_cache.points_per_sec = points_per_sec;
@@ -1989,6 +3058,56 @@ impl App {
         &self.heap_chart_cache.data
     }
 
+    /// Recount CPU samples for the visible chart range (seconds)
+    ///
+    /// Only needed for `PercentBase::Window`; re-queries when the range or the
+    /// data changed and re-sorts the table by the new shares.
+    pub fn update_cpu_window(&mut self, start_secs: f64, end_secs: f64) {
+        if self.percent_base != PercentBase::Window {
+            return;
+        }
+
+        // Quarter-second steps, so a live window doesn't re-query every frame
+        let to_ms = |secs: f64| ((secs.max(0.0) * 4.0).floor() as i64) * 250;
+        let range_ms = (to_ms(start_secs), to_ms(end_secs) + 250);
+        if self.cpu_window.range_ms == Some(range_ms)
+            && self.cpu_window.checkpoint_seq == self.chart_checkpoint_seq
+        {
+            return;
+        }
+
+        let entries = if let Some(storage) = &self.storage {
+            storage.query_top_cpu_range(range_ms.0, range_ms.1)
+        } else if let Some(conn) = &self.conn {
+            let total =
+                crate::storage::query_cpu_sample_count(conn, range_ms.0, range_ms.1).unwrap_or(0);
+            crate::storage::query_top_cpu_range(conn, usize::MAX, 0.0, range_ms, total)
+                .unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+
+        self.cpu_window.range_ms = Some(range_ms);
+        self.cpu_window.checkpoint_seq = self.chart_checkpoint_seq;
+        self.cpu_window.shares = entries
+            .into_iter()
+            .map(|e| (e.location_id, (e.total_samples, e.total_percent)))
+            .collect();
+        self.sort_cpu_entries();
+    }
+
+    /// Samples and CPU% of an entry against the selected denominator
+    pub fn cpu_share(&self, entry: &CpuEntry) -> (u64, f64) {
+        cpu_share(self.percent_base, &self.cpu_window, entry)
+    }
+
+    /// Allocation size distribution of the charted heap location
+    ///
+    /// Refreshed with the memory chart data by `query_heap_chart_data`.
+    pub fn selected_heap_sizes(&self) -> Option<&SizeDistribution> {
+        self.heap_chart_cache.sizes.as_ref()
+    }
+
     fn chart_bucket_params(
         &self,
         visible_start: f64,
@@ -2051,22 +3170,380 @@ impl App {
     }
 }
 
+/// Samples and CPU% of `entry`: run totals, or its share of the window
+fn cpu_share(base: PercentBase, window: &CpuWindowCache, entry: &CpuEntry) -> (u64, f64) {
+    match base {
+        PercentBase::Total => (entry.total_samples, entry.total_percent),
+        PercentBase::Window => window
+            .shares
+            .get(&entry.location_id)
+            .copied()
+            .unwrap_or((0, 0.0)),
+    }
+}
+
+/// Whether a row's function (without its hash) or `file:line` contains the
+/// lowercased `filter`
+fn matches_filter(filter: &str, function: &str, file: &str, line: u32) -> bool {
+    filter.is_empty()
+        || split_symbol_hash(function)
+            .0
+            .to_lowercase()
+            .contains(filter)
+        || format!("{}:{}", file, line).to_lowercase().contains(filter)
+}
+
 fn cmp_f64(a: f64, b: f64) -> std::cmp::Ordering {
     a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal)
 }
 
-fn resolve_internal_stack(
+/// Attribute a sampled stack to a location
+///
+/// Without a resolver the raw stack is kept for deferred symbolication.
+fn attribute_stack(
     stack: &[u64],
-    resolver: &crate::symbols::SymbolResolver,
-) -> crate::symbols::Location {
-    for &addr in stack {
-        if addr == 0 {
-            continue;
+    resolver: Option<&SymbolResolver>,
+    attribution: Attribution,
+) -> Location {
+    match resolver {
+        None => deferred::raw_location(stack),
+        Some(resolver) => attribution.attribute(stack, resolver),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    /// Counts the allocations each thread makes, to catch per-frame copies
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+    }
+
+    fn count_allocation() {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count_allocation();
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe { System.dealloc(ptr, layout) }
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            count_allocation();
+            unsafe { System.realloc(ptr, layout, new_size) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Open a viewer on a profile with one checkpoint written by `record`
+    fn viewer(name: &str, record: impl FnOnce(&mut Storage)) -> App {
+        let path = std::env::temp_dir().join(format!("rsprof-{}-{}.db", name, std::process::id()));
+        let mut storage = Storage::create(&path).unwrap();
+        record(&mut storage);
+        storage.flush_checkpoint_at(1000).unwrap();
+        drop(storage);
+        let app = App::from_file(&path).unwrap();
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", path.display(), suffix)).ok();
+        }
+        app
+    }
+
+    #[inline(never)]
+    fn sampled_in_the_recording() -> u32 {
+        std::hint::black_box(7)
+    }
+
+    #[test]
+    fn deferred_profiles_are_resolved_without_rewriting_the_file() {
+        use crate::symbols::deferred;
+
+        // A --no-symbols recording of this test binary
+        let exe = std::env::current_exe().unwrap();
+        let aslr_offset = crate::process::MemoryMaps::for_pid(std::process::id())
+            .unwrap()
+            .aslr_offset(&exe)
+            .unwrap();
+        let addr = sampled_in_the_recording as *const () as u64;
+        let path = std::env::temp_dir().join(format!("rsprof-deferred-{}.db", std::process::id()));
+        let mut storage = Storage::create(&path).unwrap();
+        storage
+            .set_meta("exe_path", &exe.to_string_lossy())
+            .unwrap();
+        storage
+            .set_meta("aslr_offset", &aslr_offset.to_string())
+            .unwrap();
+        storage.set_meta("attribution", "leaf").unwrap();
+        storage
+            .set_meta(deferred::META_SYMBOLS, "deferred")
+            .unwrap();
+        storage.record_cpu_sample_count(addr, &deferred::raw_location(&[addr]), 5);
+        storage.flush_checkpoint_at(1000).unwrap();
+        drop(storage);
+
+        let app = App::from_file(&path).unwrap();
+        assert!(
+            app.cached_entries
+                .iter()
+                .any(|e| e.function.contains("sampled_in_the_recording") && e.total_samples == 5)
+        );
+        assert!(
+            app.cached_entries
+                .iter()
+                .all(|e| e.file != deferred::RAW_FILE)
+        );
+
+        let conn = Connection::open(&path).unwrap();
+        assert!(deferred::is_deferred(&conn).unwrap());
+        let raw: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM locations WHERE file = ?",
+                [deferred::RAW_FILE],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(raw, 1);
+
+        drop((app, conn));
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", path.display(), suffix)).ok();
+        }
+    }
+
+    fn location(function: &str) -> Location {
+        Location {
+            file: format!("src/{}.rs", function),
+            line: 1,
+            column: 0,
+            function: function.to_string(),
         }
-        let loc = resolver.resolve(addr);
-        if loc.function != "_fini" && loc.function != "[unknown]" {
-            return loc;
+    }
+
+    #[test]
+    fn display_threshold_hides_and_restores_cpu_rows() {
+        let mut app = viewer("cpu-threshold", |storage| {
+            for (function, count) in [("hot", 700), ("warm", 297), ("cold", 3)] {
+                storage.record_cpu_sample_count(0, &location(function), count);
+            }
+        });
+        assert_eq!(app.cached_entries.len(), 3);
+
+        // 0.1% keeps the 0.3% row, 0.5% hides it
+        app.step_display_threshold(1);
+        assert_eq!(app.cached_entries.len(), 3);
+        app.step_display_threshold(1);
+        assert_eq!(app.cpu_threshold(), 0.5);
+        assert_eq!(app.cached_entries.len(), 2);
+        assert_eq!(app.hidden_row_count(), 1);
+        assert!(app.cached_entries.iter().all(|e| e.function != "cold"));
+
+        app.step_display_threshold(-1);
+        app.step_display_threshold(-1);
+        assert_eq!(app.cpu_threshold(), 0.0);
+        assert_eq!(app.cached_entries.len(), 3);
+        assert_eq!(app.hidden_row_count(), 0);
+    }
+
+    #[test]
+    fn display_threshold_hides_and_restores_heap_rows() {
+        let mut app = viewer("heap-threshold", |storage| {
+            for (function, live) in [("big", 2 << 20), ("medium", 50 << 10), ("small", 500)] {
+                storage.record_heap_sample(&location(function), live, 0, live, 1, 0);
+            }
+        });
+        app.view_mode = ViewMode::Memory;
+        assert_eq!(app.cached_heap_entries.len(), 3);
+
+        // Up to 1 MB: only the 2 MB row is left
+        for _ in 0..4 {
+            app.step_display_threshold(1);
         }
+        assert_eq!(app.heap_threshold(), 1 << 20);
+        let shown: Vec<&str> = app
+            .cached_heap_entries
+            .iter()
+            .map(|e| e.function.as_str())
+            .collect();
+        assert_eq!(shown, ["big"]);
+        assert_eq!(app.hidden_row_count(), 2);
+
+        for _ in 0..4 {
+            app.step_display_threshold(-1);
+        }
+        assert_eq!(app.cached_heap_entries.len(), 3);
+        // Never below zero
+        app.step_display_threshold(-1);
+        assert_eq!(app.heap_threshold(), 0);
+    }
+    #[test]
+    fn rendering_borrows_the_sparklines() {
+        let mut app = viewer("render-allocs", |storage| {
+            storage.record_cpu_sample_count(0x1000, &location("parse"), 10);
+        });
+        let mut terminal = Terminal::new(backend::TestBackend::new(120, 30)).unwrap();
+        let mut frame_allocations = |app: &mut App| {
+            let before = ALLOCATIONS.with(Cell::get);
+            terminal.draw(|frame| ui::render(frame, app)).unwrap();
+            ALLOCATIONS.with(Cell::get) - before
+        };
+        frame_allocations(&mut app);
+        let small = frame_allocations(&mut app);
+
+        // Sparklines of 10000 more locations: a copy per frame would allocate
+        // at least once for each
+        for location_id in 1000..11000 {
+            app.cached_cpu_sparklines
+                .insert(location_id, VecDeque::from([1; 12]));
+        }
+        let large = frame_allocations(&mut app);
+        assert!(large < small + 1000, "{small} -> {large} allocations");
+    }
+
+    #[test]
+    fn demangle_key_cycles_the_displayed_function_names() {
+        let symbol = "app::Cache<app::Record>::insert::h0123456789abcdef";
+        let mut app = viewer("demangle-key", |storage| {
+            let location = Location {
+                function: symbol.to_string(),
+                ..location("cache")
+            };
+            storage.record_cpu_sample_count(0x1000, &location, 5);
+        });
+        // The function cell, as drawn
+        let mut terminal = Terminal::new(backend::TestBackend::new(160, 20)).unwrap();
+        let mut shown = |app: &mut App| -> String {
+            let completed = terminal.draw(|frame| ui::render(frame, app)).unwrap();
+            let text = ui::buffer_to_text(completed.buffer);
+            let row = text.lines().find(|line| line.contains("insert")).unwrap();
+            let start = row.find(|c: char| c.is_alphabetic()).unwrap();
+            row[start..].split("  ").next().unwrap().to_string()
+        };
+
+        assert_eq!(shown(&mut app), "Cache<_>::insert");
+        app.handle_key(KeyCode::Char('D'), KeyModifiers::NONE);
+        assert_eq!(shown(&mut app), "app::Cache<app::Record>::insert");
+        app.handle_key(KeyCode::Char('D'), KeyModifiers::NONE);
+        assert_eq!(shown(&mut app), symbol);
+        app.handle_key(KeyCode::Char('D'), KeyModifiers::NONE);
+        assert_eq!(shown(&mut app), "Cache<_>::insert");
+    }
+
+    #[test]
+    fn playlist_keys_open_the_adjacent_profile() {
+        use super::super::{open_playlist_entry, playlist_index};
+
+        let dir = std::env::temp_dir().join(format!("rsprof-playlist-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a profile").unwrap();
+        // Created out of order: played in file name order
+        for (name, samples) in [("night-2.db", 2), ("night-3.db", 3), ("night-1.db", 1)] {
+            let mut storage = Storage::create(&dir.join(name)).unwrap();
+            storage.record_cpu_sample_count(0x1000, &location("work"), samples);
+            storage.flush_checkpoint_at(1000).unwrap();
+        }
+        let files = crate::commands::view::playlist_files(&dir).unwrap();
+        assert_eq!(files.len(), 3);
+
+        // Press `key` on entry `index`, then open the entry it asks for
+        let press = |index: usize, key: char| {
+            let mut app = open_playlist_entry(&files, index, None).unwrap();
+            app.handle_key(KeyCode::Char(key), KeyModifiers::NONE);
+            let step = app.playlist_step().unwrap();
+            let next = open_playlist_entry(&files, playlist_index(index, step, files.len()), None)
+                .unwrap();
+            let position = next.playlist().unwrap().position;
+            (
+                position,
+                next.file_name().unwrap().to_string(),
+                next.total_samples(),
+            )
+        };
+
+        assert_eq!(press(0, ']'), (1, "night-2.db".to_string(), 2));
+        assert_eq!(press(1, ']'), (2, "night-3.db".to_string(), 3));
+        assert_eq!(press(1, '['), (0, "night-1.db".to_string(), 1));
+        // Wrapping around both ends
+        assert_eq!(press(2, ']'), (0, "night-1.db".to_string(), 1));
+        assert_eq!(press(0, '['), (2, "night-3.db".to_string(), 3));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn trend_source_switches_to_allocations_per_checkpoint() {
+        // Cumulative (alloc count, live bytes) at 250, 500 and 1000 ms
+        let mut app = viewer("sparkline-source", |storage| {
+            for (timestamp_ms, allocs, live) in [(250, 10, 1000), (500, 25, 1500)] {
+                storage.record_heap_sample(&location("cache"), live, 0, live, allocs, 0);
+                storage.flush_checkpoint_at(timestamp_ms).unwrap();
+            }
+            storage.record_heap_sample(&location("cache"), 1200, 0, 1200, 27, 0);
+        });
+        app.view_mode = ViewMode::Memory;
+        let id = app.cached_heap_entries[0].location_id;
+        let trend = |app: &App| -> Vec<i64> {
+            let values = &app.heap_sparklines()[&id];
+            values.iter().skip(values.len() - 3).copied().collect()
+        };
+
+        assert!(app.sparkline_source == SparklineSource::LiveBytes);
+        assert_eq!(trend(&app), [1000, 1500, 1200]);
+        app.handle_key(KeyCode::Char('a'), KeyModifiers::NONE);
+        assert!(app.sparkline_source == SparklineSource::AllocCount);
+        assert_eq!(trend(&app), [10, 15, 2]);
+        app.handle_key(KeyCode::Char('a'), KeyModifiers::NONE);
+        assert_eq!(trend(&app), [1000, 1500, 1200]);
+    }
+
+    #[test]
+    fn window_denominator_reproportions_cpu_percentages() {
+        // cache: 300 samples by 500 ms; parse: 100 before and 100 after
+        let mut app = viewer("percent-of", |storage| {
+            storage.record_cpu_sample_count(0x1000, &location("cache"), 300);
+            storage.record_cpu_sample_count(0x2000, &location("parse"), 100);
+            storage.flush_checkpoint_at(500).unwrap();
+            storage.record_cpu_sample_count(0x2000, &location("parse"), 100);
+        });
+        let shares = |app: &App| -> Vec<(String, u64, f64)> {
+            app.cached_entries
+                .iter()
+                .map(|e| {
+                    let (samples, percent) = app.cpu_share(e);
+                    (e.function.clone(), samples, percent)
+                })
+                .collect()
+        };
+
+        assert_eq!(
+            shares(&app),
+            [("cache".into(), 300, 60.0), ("parse".into(), 200, 40.0)]
+        );
+
+        // Only the second checkpoint is visible: parse is all of it
+        app.handle_key(KeyCode::Char('w'), KeyModifiers::NONE);
+        app.update_cpu_window(0.6, 1.0);
+        assert_eq!(
+            shares(&app),
+            [("parse".into(), 100, 100.0), ("cache".into(), 0, 0.0)]
+        );
+
+        app.handle_key(KeyCode::Char('w'), KeyModifiers::NONE);
+        assert_eq!(
+            shares(&app),
+            [("cache".into(), 300, 60.0), ("parse".into(), 200, 40.0)]
+        );
     }
-    crate::symbols::Location::unknown()
 }
@@ -0,0 +1,93 @@
+//! Callers and callees of one function (`t`)
+//!
+//! Built from the same weighted call stacks as the flamegraph. A stack
+//! counts once towards the function however often it recurses, and once
+//! towards each distinct caller and callee next to it.
+
+use crate::symbols::Location;
+
+/// One function's neighbours in the recorded stacks
+pub struct CallTree {
+    pub function: String,
+    /// Weight of the stacks through the function
+    pub total: u64,
+    /// Weight of the stacks credited to the function itself
+    pub self_weight: u64,
+    /// (function, weight of stacks it calls this one in), heaviest first
+    pub callers: Vec<(String, u64)>,
+    /// (function, weight of stacks this one calls it in), heaviest first
+    pub callees: Vec<(String, u64)>,
+}
+
+impl CallTree {
+    /// Neighbours of `function` in stacks given credited location first
+    pub fn build<'a>(
+        stacks: impl IntoIterator<Item = (&'a [Location], u64)>,
+        function: &str,
+    ) -> Self {
+        let mut tree = CallTree {
+            function: function.to_string(),
+            total: 0,
+            self_weight: 0,
+            callers: Vec::new(),
+            callees: Vec::new(),
+        };
+        for (frames, weight) in stacks {
+            let depths: Vec<usize> = frames
+                .iter()
+                .enumerate()
+                .filter(|(_, frame)| frame.function == function)
+                .map(|(depth, _)| depth)
+                .collect();
+            if depths.is_empty() || weight == 0 {
+                continue;
+            }
+            tree.total += weight;
+            if depths[0] == 0 {
+                tree.self_weight += weight;
+            }
+
+            let mut callers: Vec<&str> = depths
+                .iter()
+                .filter_map(|&depth| frames.get(depth + 1))
+                .map(|frame| frame.function.as_str())
+                .collect();
+            let mut callees: Vec<&str> = depths
+                .iter()
+                .filter(|&&depth| depth > 0)
+                .map(|&depth| frames[depth - 1].function.as_str())
+                .collect();
+            callers.sort_unstable();
+            callers.dedup();
+            callees.sort_unstable();
+            callees.dedup();
+            for caller in callers {
+                add_weight(&mut tree.callers, caller, weight);
+            }
+            for callee in callees {
+                add_weight(&mut tree.callees, callee, weight);
+            }
+        }
+        tree.callers
+            .sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        tree.callees
+            .sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        tree
+    }
+
+    /// Callers then callees, the order the pane's cursor moves through
+    pub fn neighbours(&self) -> impl Iterator<Item = &(String, u64)> {
+        self.callers.iter().chain(&self.callees)
+    }
+
+    pub fn neighbour_count(&self) -> usize {
+        self.callers.len() + self.callees.len()
+    }
+}
+
+fn add_weight(entries: &mut Vec<(String, u64)>, function: &str, weight: u64) {
+    match entries.iter_mut().find(|(name, _)| name == function) {
+        Some((_, total)) => *total += weight,
+        None => entries.push((function.to_string(), weight)),
+    }
+}
@@ -0,0 +1,161 @@
+//! Allocation churn alerts for the live memory view
+//!
+//! Each heap site keeps a baseline of its allocations per checkpoint over the
+//! last `BASELINE_CHECKPOINTS`. A checkpoint whose count sits far above that
+//! baseline (z-score) flags the site for a few checkpoints, so a phase change
+//! that starts hammering the allocator stands out while it happens.
+
+use std::collections::{HashMap, VecDeque};
+
+/// Checkpoints a site's baseline rate is taken over
+const BASELINE_CHECKPOINTS: usize = 10;
+
+/// Checkpoints of history needed before a site can be flagged
+const MIN_BASELINE_CHECKPOINTS: usize = 5;
+
+/// Standard deviations above the baseline that count as a jump
+const Z_THRESHOLD: f64 = 4.0;
+
+/// Fewest allocations in a checkpoint worth flagging (keeps quiet sites quiet)
+const MIN_ALLOCS: f64 = 100.0;
+
+/// Checkpoints a flagged site stays highlighted
+const ALERT_CHECKPOINTS: u64 = 3;
+
+/// A site whose allocation rate jumped
+#[derive(Debug, Clone, Copy)]
+pub struct ChurnAlert {
+    /// Standard deviations above the baseline at the jump
+    pub z_score: f64,
+    /// Allocations in the checkpoint that jumped
+    pub allocs: i64,
+    /// Baseline allocations per checkpoint before the jump
+    pub baseline: f64,
+    /// Checkpoint the alert expires at
+    until: u64,
+}
+
+/// Rolling allocations-per-checkpoint window of one site
+///
+/// Sum and sum of squares are updated as counts enter and leave, so each
+/// checkpoint costs O(1) per site.
+#[derive(Debug, Default)]
+struct Baseline {
+    counts: VecDeque<i64>,
+    sum: f64,
+    sum_sq: f64,
+}
+
+impl Baseline {
+    fn push(&mut self, count: i64) {
+        if self.counts.len() >= BASELINE_CHECKPOINTS
+            && let Some(old) = self.counts.pop_front()
+        {
+            self.sum -= old as f64;
+            self.sum_sq -= (old as f64) * (old as f64);
+        }
+        self.counts.push_back(count);
+        self.sum += count as f64;
+        self.sum_sq += (count as f64) * (count as f64);
+    }
+
+    fn mean(&self) -> f64 {
+        self.sum / self.counts.len() as f64
+    }
+
+    /// z-score of `count` against the window, or None while it's too short
+    ///
+    /// The deviation is floored at the Poisson spread (√mean, at least 1) so
+    /// a perfectly steady site doesn't flag on its first small wobble.
+    fn z_score(&self, count: i64) -> Option<f64> {
+        if self.counts.len() < MIN_BASELINE_CHECKPOINTS {
+            return None;
+        }
+        let mean = self.mean();
+        let variance = (self.sum_sq / self.counts.len() as f64 - mean * mean).max(0.0);
+        let deviation = variance.sqrt().max(mean.sqrt()).max(1.0);
+        Some((count as f64 - mean) / deviation)
+    }
+}
+
+/// Per-site allocation rate baselines and the sites currently flagged
+#[derive(Debug, Default)]
+pub struct ChurnDetector {
+    baselines: HashMap<i64, Baseline>,
+    alerts: HashMap<i64, ChurnAlert>,
+}
+
+impl ChurnDetector {
+    /// Feed one checkpoint's allocation counts per location
+    ///
+    /// Returns the locations newly flagged at this checkpoint. Sites missing
+    /// from `counts` are forgotten.
+    pub fn observe(&mut self, checkpoint: u64, counts: &HashMap<i64, i64>) -> Vec<i64> {
+        self.baselines.retain(|id, _| counts.contains_key(id));
+        self.alerts
+            .retain(|id, alert| alert.until > checkpoint && counts.contains_key(id));
+
+        let mut flagged = Vec::new();
+        for (&location_id, &count) in counts {
+            // A new site's first count covers everything before it was seen
+            let Some(baseline) = self.baselines.get_mut(&location_id) else {
+                self.baselines.insert(location_id, Baseline::default());
+                continue;
+            };
+            if let Some(z_score) = baseline.z_score(count)
+                && z_score >= Z_THRESHOLD
+                && count as f64 >= MIN_ALLOCS
+            {
+                if !self.alerts.contains_key(&location_id) {
+                    flagged.push(location_id);
+                }
+                self.alerts.insert(
+                    location_id,
+                    ChurnAlert {
+                        z_score,
+                        allocs: count,
+                        baseline: baseline.mean(),
+                        until: checkpoint + ALERT_CHECKPOINTS,
+                    },
+                );
+            }
+            baseline.push(count);
+        }
+        flagged
+    }
+
+    /// Sites whose allocation rate jumped in the last few checkpoints
+    pub fn alerts(&self) -> &HashMap<i64, ChurnAlert> {
+        &self.alerts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spiking_site_is_flagged_while_steady_sites_are_not() {
+        let mut detector = ChurnDetector::default();
+        // Site 1 wobbles around 200, site 2 allocates 50 until it spikes
+        let steady = [190, 210, 205, 195, 200, 198, 202, 207, 193, 200];
+        for (checkpoint, &count) in steady.iter().enumerate() {
+            let counts = HashMap::from([(1, count), (2, 50)]);
+            assert!(detector.observe(checkpoint as u64, &counts).is_empty());
+        }
+
+        let counts = HashMap::from([(1, 204), (2, 2000)]);
+        assert_eq!(detector.observe(10, &counts), [2]);
+        let alert = detector.alerts()[&2];
+        assert_eq!(alert.allocs, 2000);
+        assert_eq!(alert.baseline, 50.0);
+        assert!(!detector.alerts().contains_key(&1));
+
+        // Still highlighted for a few checkpoints, without being re-announced
+        let counts = HashMap::from([(1, 196), (2, 50)]);
+        assert!(detector.observe(11, &counts).is_empty());
+        assert!(detector.alerts().contains_key(&2));
+        detector.observe(10 + ALERT_CHECKPOINTS, &counts);
+        assert!(detector.alerts().is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+//! Flamegraph of the recorded call stacks (`F`)
+//!
+//! Stacks are merged by function from the outermost caller down, drawn as an
+//! icicle: callers on top, each frame as wide as the samples (or bytes, or
+//! allocations) of the stacks passing through it. CPU stacks come from any
+//! recording from schema v7 on; heap stacks from any recording with heap
+//! profiling.
+
+use crate::symbols::Location;
+
+/// One merged frame
+pub struct FlameNode {
+    pub function: String,
+    /// Weight of every stack through this frame
+    pub total: u64,
+    /// Weight of the stacks credited to this frame (it is their leaf)
+    pub self_weight: u64,
+    pub parent: Option<usize>,
+    /// Heaviest first
+    pub children: Vec<usize>,
+}
+
+/// Stacks merged into a tree; node 0 is the root holding every stack
+pub struct FlameGraph {
+    nodes: Vec<FlameNode>,
+}
+
+impl FlameGraph {
+    /// Merge weighted stacks, each credited location first
+    pub fn from_stacks<'a>(stacks: impl IntoIterator<Item = (&'a [Location], u64)>) -> Self {
+        let mut graph = FlameGraph {
+            nodes: vec![FlameNode {
+                function: "all".to_string(),
+                total: 0,
+                self_weight: 0,
+                parent: None,
+                children: Vec::new(),
+            }],
+        };
+        for (frames, weight) in stacks {
+            if frames.is_empty() || weight == 0 {
+                continue;
+            }
+            let mut node = 0;
+            graph.nodes[0].total += weight;
+            for frame in frames.iter().rev() {
+                node = graph.child(node, &frame.function);
+                graph.nodes[node].total += weight;
+            }
+            graph.nodes[node].self_weight += weight;
+        }
+        for index in 0..graph.nodes.len() {
+            let mut children = std::mem::take(&mut graph.nodes[index].children);
+            children.sort_by_key(|&child| std::cmp::Reverse(graph.nodes[child].total));
+            graph.nodes[index].children = children;
+        }
+        graph
+    }
+
+    /// Child of `parent` for `function`, added if missing
+    fn child(&mut self, parent: usize, function: &str) -> usize {
+        if let Some(&child) = self.nodes[parent]
+            .children
+            .iter()
+            .find(|&&child| self.nodes[child].function == function)
+        {
+            return child;
+        }
+        let child = self.nodes.len();
+        self.nodes.push(FlameNode {
+            function: function.to_string(),
+            total: 0,
+            self_weight: 0,
+            parent: Some(parent),
+            children: Vec::new(),
+        });
+        self.nodes[parent].children.push(child);
+        child
+    }
+
+    pub fn node(&self, index: usize) -> &FlameNode {
+        &self.nodes[index]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes[0].total == 0
+    }
+
+    /// Functions from the root's child down to `index`
+    fn path(&self, mut index: usize) -> Vec<String> {
+        let mut path = Vec::new();
+        while let Some(parent) = self.nodes[index].parent {
+            path.push(self.nodes[index].function.clone());
+            index = parent;
+        }
+        path.reverse();
+        path
+    }
+
+    /// Deepest node along a path from `path`
+    fn find(&self, path: &[String]) -> usize {
+        let mut index = 0;
+        for function in path {
+            match self.nodes[index]
+                .children
+                .iter()
+                .find(|&&child| self.nodes[child].function == *function)
+            {
+                Some(&child) => index = child,
+                None => break,
+            }
+        }
+        index
+    }
+}
+
+impl Default for FlameGraph {
+    fn default() -> Self {
+        FlameGraph::from_stacks(std::iter::empty())
+    }
+}
+
+/// A flamegraph with the zoomed-in and selected frames
+#[derive(Default)]
+pub struct FlameView {
+    pub graph: FlameGraph,
+    /// Frame drawn full width on the top row
+    pub zoom: usize,
+    pub selected: usize,
+}
+
+impl FlameView {
+    /// Swap in a rebuilt graph, keeping the zoom and selection where the
+    /// same call paths still exist
+    pub fn replace_graph(&mut self, graph: FlameGraph) {
+        let zoom = self.graph.path(self.zoom);
+        let selected = self.graph.path(self.selected);
+        self.graph = graph;
+        self.zoom = self.graph.find(&zoom);
+        self.selected = self.graph.find(&selected);
+        if !self.is_shown(self.selected) {
+            self.selected = self.zoom;
+        }
+    }
+
+    /// Whether `index` is the zoomed frame or below it
+    fn is_shown(&self, mut index: usize) -> bool {
+        loop {
+            if index == self.zoom {
+                return true;
+            }
+            match self.graph.nodes[index].parent {
+                Some(parent) => index = parent,
+                None => return false,
+            }
+        }
+    }
+
+    /// Select the caller, up to the zoomed frame
+    pub fn select_parent(&mut self) {
+        if self.selected != self.zoom
+            && let Some(parent) = self.graph.nodes[self.selected].parent
+        {
+            self.selected = parent;
+        }
+    }
+
+    /// Select the heaviest callee
+    pub fn select_child(&mut self) {
+        if let Some(&child) = self.graph.nodes[self.selected].children.first() {
+            self.selected = child;
+        }
+    }
+
+    /// Select the frame `delta` places left or right under the same caller
+    pub fn select_sibling(&mut self, delta: isize) {
+        if self.selected == self.zoom {
+            return;
+        }
+        let Some(parent) = self.graph.nodes[self.selected].parent else {
+            return;
+        };
+        let siblings = &self.graph.nodes[parent].children;
+        if let Some(position) = siblings.iter().position(|&s| s == self.selected) {
+            let position = position
+                .saturating_add_signed(delta)
+                .min(siblings.len() - 1);
+            self.selected = siblings[position];
+        }
+    }
+
+    /// Draw the selected frame full width
+    pub fn zoom_in(&mut self) {
+        self.zoom = self.selected;
+    }
+
+    /// Widen the view to the zoomed frame's caller
+    pub fn zoom_out(&mut self) {
+        if let Some(parent) = self.graph.nodes[self.zoom].parent {
+            self.zoom = parent;
+        }
+    }
+
+    /// Show every stack again
+    pub fn reset_zoom(&mut self) {
+        self.zoom = 0;
+    }
+}
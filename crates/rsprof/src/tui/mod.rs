@@ -1,25 +1,37 @@
 mod app;
+mod calltree;
+mod churn;
+mod flame;
+mod source;
 mod ui;
 
-use crate::cpu::CpuSampler;
+use crate::cli::{ColumnWidths, NameOptions};
+use crate::cpu::{AutoFreq, CpuSampler};
 use crate::error::Result;
 use crate::heap::ShmHeapSampler;
 use crate::storage::Storage;
-use crate::symbols::SymbolResolver;
+use crate::symbols::{Attribution, SymbolResolver};
+use std::path::PathBuf;
 use std::time::Duration;
 
 pub use app::App;
 
 /// Run the TUI profiler
+///
+/// Without a resolver (`--no-symbols`) raw stacks are recorded for later symbolication.
 #[allow(clippy::too_many_arguments)]
 pub fn run(
     perf_sampler: Option<CpuSampler>,
     shm_sampler: Option<ShmHeapSampler>,
-    resolver: SymbolResolver,
+    resolver: Option<SymbolResolver>,
     storage: Storage,
     checkpoint_interval: Duration,
     max_duration: Option<Duration>,
-    include_internal: bool,
+    attribution: Attribution,
+    names: NameOptions,
+    columns: ColumnWidths,
+    auto_freq: Option<AutoFreq>,
+    live_max_locations: usize,
 ) -> Result<()> {
     let time_offset_secs = storage.time_offset_secs();
     let mut app = App::new(
@@ -29,8 +41,55 @@ pub fn run(
         storage,
         checkpoint_interval,
         max_duration,
-        include_internal,
+        attribution,
         time_offset_secs,
     );
+    app.name_options = names;
+    app.column_widths = columns;
+    app.auto_freq = auto_freq;
+    app.live_max_locations = live_max_locations;
     app.run()
 }
+
+/// View profiles one after another (`view --playlist`)
+///
+/// `]`/`[` open the next/previous profile, wrapping around; with `dwell` the
+/// next one opens automatically after that long. `q` quits.
+pub fn run_playlist(
+    files: &[PathBuf],
+    dwell: Option<Duration>,
+    names: NameOptions,
+    columns: ColumnWidths,
+) -> Result<()> {
+    let mut terminal = app::enter_terminal()?;
+    let mut index = 0;
+    let result = loop {
+        let mut app = match open_playlist_entry(files, index, dwell) {
+            Ok(app) => app,
+            Err(e) => break Err(e),
+        };
+        app.name_options = names;
+        app.column_widths = columns;
+        if let Err(e) = app.main_loop(&mut terminal) {
+            break Err(e);
+        }
+        match app.playlist_step() {
+            Some(step) => index = playlist_index(index, step, files.len()),
+            None => break Ok(()),
+        }
+    };
+    app::leave_terminal(&mut terminal)?;
+    result
+}
+
+/// Open entry `index` of a playlist
+fn open_playlist_entry(files: &[PathBuf], index: usize, dwell: Option<Duration>) -> Result<App> {
+    let mut app = App::from_file(&files[index])?;
+    app.set_playlist(index, files.len(), dwell);
+    Ok(app)
+}
+
+/// Entry `step` away from `index` in a playlist of `len`, wrapping around
+fn playlist_index(index: usize, step: isize, len: usize) -> usize {
+    (index as isize + step).rem_euclid(len as isize) as usize
+}
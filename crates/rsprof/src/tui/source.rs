@@ -0,0 +1,79 @@
+//! Source lines around the selected row's location (`o`)
+//!
+//! Recorded paths are relative to the target's crate (`src/foo.rs`) or
+//! absolute paths from the machine that built it; they are looked up like
+//! `rsprof annotate` does, under the target's source root (live mode) and the
+//! current directory.
+
+use crate::commands::annotate::find_source;
+use std::path::{Path, PathBuf};
+
+/// Lines read on each side of the location (the pane shows what fits)
+const CONTEXT_LINES: u32 = 15;
+
+/// Source around one location
+pub struct SourceSnippet {
+    pub file: String,
+    pub line: u32,
+    /// (line number, text), or why the source can't be shown
+    pub lines: Result<Vec<(u32, String)>, String>,
+}
+
+impl SourceSnippet {
+    /// Read the lines around `file:line`, trying each root in order
+    pub fn load(roots: &[PathBuf], file: &str, line: u32) -> Self {
+        let lines = if line == 0 || file.starts_with('[') || file.starts_with('(') {
+            Err("no line information".to_string())
+        } else {
+            read_lines(roots, file, line)
+        };
+        SourceSnippet {
+            file: file.to_string(),
+            line,
+            lines,
+        }
+    }
+
+    pub fn is_for(&self, file: &str, line: u32) -> bool {
+        self.file == file && self.line == line
+    }
+}
+
+fn read_lines(roots: &[PathBuf], file: &str, line: u32) -> Result<Vec<(u32, String)>, String> {
+    let Some(path) = roots.iter().find_map(|root| find_source(root, file)) else {
+        let roots: Vec<String> = roots
+            .iter()
+            .map(|root| root.display().to_string())
+            .collect();
+        return Err(format!(
+            "source unavailable: {} not found under {}",
+            file,
+            roots.join(", ")
+        ));
+    };
+    let source = std::fs::read_to_string(&path)
+        .map_err(|e| format!("source unavailable: {}: {}", path.display(), e))?;
+    let first = line.saturating_sub(CONTEXT_LINES).max(1);
+    let lines: Vec<(u32, String)> = (first..)
+        .zip(source.lines().skip(first as usize - 1))
+        .take_while(|&(number, _)| number <= line + CONTEXT_LINES)
+        .map(|(number, text)| (number, text.replace('\t', "    ")))
+        .collect();
+    if lines.iter().all(|&(number, _)| number != line) {
+        return Err(format!(
+            "source unavailable: {} has no line {} (changed since profiling?)",
+            path.display(),
+            line
+        ));
+    }
+    Ok(lines)
+}
+
+/// Roots to look sources up under: the target's crate, then the current directory
+pub fn source_roots(target_root: Option<&Path>) -> Vec<PathBuf> {
+    target_root
+        .map(Path::to_path_buf)
+        .into_iter()
+        .chain(std::iter::once(PathBuf::from(".")))
+        .collect()
+}
@@ -1,7 +1,19 @@
-use super::app::{App, ChartType, Focus, SortColumn, TableSort, ViewMode};
-use crate::storage::{CpuEntry, HeapEntry};
+use super::app::{
+    App, ChartType, DemangleMode, Focus, HeapGrouping, HeatMode, SortColumn, SparklineSource,
+    TableSort, ViewMode,
+};
+use super::churn::ChurnAlert;
+use super::flame::FlameGraph;
+use crate::cli::{ColumnWidths, NameOptions, PercentBase};
+use crate::heap::SizeDistribution;
+use crate::storage::{
+    BucketAggregate, CpuEntry, CpuSeries, HeapEntry, MarkerEntry, RUNNING_AVERAGE_CHECKPOINTS,
+    samples_to_secs,
+};
+use crate::symbols::strip_hash_suffix;
 use ratatui::{
     Frame,
+    buffer::Buffer,
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     symbols,
@@ -12,6 +24,16 @@ use ratatui::{
     },
 };
 use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+/// Allocations per CPU sample at which a CPU row's Alloc cell gets a badge
+const ALLOC_HEAVY_PER_SAMPLE: f64 = 100.0;
+
+/// Badge after the bytes of allocation-heavy CPU rows
+const ALLOC_BADGE: &str = "▲";
+
+/// Badge before the function of heap rows whose allocation rate just jumped
+const CHURN_BADGE: &str = "↯";
 
 /// Unified table row data - used by all table views
 struct TableRow {
@@ -19,6 +41,12 @@ struct TableRow {
     total: String,
     /// Secondary/live metric value
     live: String,
+    /// Estimated CPU time (CPU view with a known sampling frequency only)
+    time: Option<String>,
+    /// Total bytes allocated (CPU view with heap data only)
+    alloc: Option<String>,
+    /// Instructions per cycle (CPU view with cycles and instructions counters only)
+    ipc: Option<String>,
     /// Function name (already formatted)
     function: String,
     /// Location string (file:line)
@@ -29,16 +57,41 @@ struct TableRow {
     total_color: Color,
     /// Color for the live column
     live_color: Color,
+    /// Color for the alloc column
+    alloc_color: Color,
+    /// Flashed: allocation rate jumped above its baseline (memory view only)
+    alert: bool,
 }
 
 /// Convert CPU entries to unified table rows
+///
+/// `share` gives each entry's samples and CPU% against the selected
+/// denominator (the whole run or the visible window).
+#[allow(clippy::too_many_arguments)]
 fn cpu_to_table_rows(
     entries: &[CpuEntry],
+    share: impl Fn(&CpuEntry) -> (u64, f64),
     sparklines: &HashMap<i64, VecDeque<i64>>,
+    allocs: Option<&HashMap<i64, (i64, u64)>>,
+    ipc_counts: Option<&HashMap<i64, (u64, u64)>>,
+    cpu_freq_hz: Option<f64>,
+    heat_mode: HeatMode,
+    demangle_mode: DemangleMode,
+    names: NameOptions,
 ) -> Vec<TableRow> {
+    let (total_heat, live_heat) = match heat_mode {
+        HeatMode::Absolute => (HeatThresholds::ABSOLUTE, HeatThresholds::ABSOLUTE),
+        HeatMode::Relative => (
+            HeatThresholds::relative(entries.iter().map(|e| share(e).1)),
+            HeatThresholds::relative(entries.iter().map(|e| e.instant_percent)),
+        ),
+    };
+
     entries
         .iter()
         .map(|e| {
+            let (samples, percent) = share(e);
+
             // Use location sparkline if available, otherwise generate from current values
             let sparkline_data: Vec<i64> = sparklines
                 .get(&e.location_id)
@@ -46,19 +99,50 @@ fn cpu_to_table_rows(
                 .unwrap_or_else(|| {
                     // Generate simple sparkline from total/instant as percentages * 1000
                     vec![
-                        (e.total_percent * 1000.0) as i64,
+                        (percent * 1000.0) as i64,
                         (e.instant_percent * 1000.0) as i64,
                     ]
                 });
 
+            // Bytes allocated at the same location (hot because it allocates?)
+            let alloc = allocs.map(|allocs| allocs.get(&e.location_id).copied());
+            // Badge sites that allocate heavily for the CPU time they take
+            let alloc_heavy = alloc.flatten().is_some_and(|(_, count)| {
+                e.total_samples > 0
+                    && count as f64 / e.total_samples as f64 >= ALLOC_HEAVY_PER_SAMPLE
+            });
+
+            // Low IPC: stalled on memory or mispredictions rather than computing
+            let ipc = ipc_counts.map(|counts| match counts.get(&e.location_id) {
+                Some(&(cycles, instructions)) if cycles > 0 => {
+                    format!("{:.2}", instructions as f64 / cycles as f64)
+                }
+                _ => "-".to_string(),
+            });
+
             TableRow {
-                total: format!("{:5.1}%", e.total_percent),
+                total: format!("{:5.1}%", percent),
                 live: format!("{:5.1}%", e.instant_percent),
-                function: format_function(&e.function),
+                time: cpu_freq_hz.map(|freq| format_cpu_time(samples_to_secs(samples, freq))),
+                alloc: alloc.map(|alloc| match alloc {
+                    Some((bytes, _)) if alloc_heavy => {
+                        format!("{} {}", format_bytes(bytes), ALLOC_BADGE)
+                    }
+                    Some((bytes, _)) => format_bytes(bytes),
+                    None => "-".to_string(),
+                }),
+                ipc,
+                function: format_function(&e.function, demangle_mode, names),
                 location: format_location(&e.file, e.line),
                 sparkline_data,
-                total_color: color_for_percent(e.total_percent),
-                live_color: color_for_percent(e.instant_percent),
+                total_color: color_for_percent(percent, &total_heat),
+                live_color: color_for_percent(e.instant_percent, &live_heat),
+                alloc_color: match alloc.flatten() {
+                    _ if alloc_heavy => Color::Magenta,
+                    Some((bytes, _)) => color_for_bytes(bytes),
+                    None => Color::DarkGray,
+                },
+                alert: false,
             }
         })
         .collect()
@@ -68,6 +152,9 @@ fn cpu_to_table_rows(
 fn heap_to_table_rows(
     entries: &[HeapEntry],
     sparklines: &HashMap<i64, VecDeque<i64>>,
+    churn_alerts: &HashMap<i64, ChurnAlert>,
+    demangle_mode: DemangleMode,
+    names: NameOptions,
 ) -> Vec<TableRow> {
     entries
         .iter()
@@ -76,26 +163,84 @@ fn heap_to_table_rows(
                 .get(&e.location_id)
                 .map(|v| v.iter().copied().collect())
                 .unwrap_or_else(|| vec![e.total_alloc_bytes, e.live_bytes]);
+            let alert = churn_alerts.contains_key(&e.location_id);
+            let function = format_function(&e.function, demangle_mode, names);
 
             TableRow {
                 total: format_bytes(e.total_alloc_bytes),
                 live: format_bytes(e.live_bytes),
-                function: format_function(&e.function),
+                time: None,
+                alloc: None,
+                ipc: None,
+                function: if alert {
+                    format!("{} {}", CHURN_BADGE, function)
+                } else {
+                    function
+                },
                 location: format_location(&e.file, e.line),
                 sparkline_data,
                 total_color: color_for_bytes(e.total_alloc_bytes),
                 live_color: color_for_bytes(e.live_bytes),
+                alloc_color: Color::Reset,
+                alert,
             }
         })
         .collect()
 }
 
+/// Convert marker/tag entries to unified table rows
+fn tag_to_table_rows(entries: &[MarkerEntry]) -> Vec<TableRow> {
+    entries
+        .iter()
+        .map(|e| TableRow {
+            total: format_bytes(e.total_alloc_bytes),
+            live: format_bytes(e.live_bytes),
+            time: None,
+            alloc: None,
+            ipc: None,
+            function: e.marker.clone(),
+            location: format!("{} allocs", e.alloc_count),
+            sparkline_data: Vec::new(),
+            total_color: color_for_bytes(e.total_alloc_bytes),
+            live_color: color_for_bytes(e.live_bytes),
+            alloc_color: Color::Reset,
+            alert: false,
+        })
+        .collect()
+}
+
 struct TableRenderState {
     selected: usize,
     scroll_offset: usize,
     focus: Focus,
     sort: TableSort,
     area: Rect,
+    columns: ColumnWidths,
+}
+
+/// Tables narrower than this drop the Trend sparkline and give Function more room
+const NARROW_TABLE_WIDTH: u16 = 80;
+/// Trend column width: a 12-character sparkline plus padding
+const TREND_WIDTH: u16 = 14;
+
+/// Constraints for the Function, Location and Trend columns of a table
+/// `table_width` wide
+///
+/// Widths fixed with `--columns` win; the rest adapt to the width. A zero
+/// width hides the column.
+pub(super) fn variable_column_widths(columns: ColumnWidths, table_width: u16) -> [Constraint; 3] {
+    let narrow = table_width < NARROW_TABLE_WIDTH;
+    let function = columns.function.map_or(
+        Constraint::Fill(if narrow { 2 } else { 1 }),
+        Constraint::Length,
+    );
+    let location = columns
+        .location
+        .map_or(Constraint::Fill(1), Constraint::Length);
+    let trend = columns
+        .trend
+        .unwrap_or(if narrow { 0 } else { TREND_WIDTH });
+    [function, location, Constraint::Length(trend)]
 }
 
 /// Render a unified table with the standard layout
@@ -129,20 +274,63 @@ fn render_unified_table(
         return;
     }
 
-    let header_labels = [
+    let show_time = rows.iter().any(|r| r.time.is_some());
+    let show_alloc = rows.iter().any(|r| r.alloc.is_some());
+    let show_ipc = rows.iter().any(|r| r.ipc.is_some());
+    let [function_width, location_width, trend_width] =
+        variable_column_widths(state.columns, state.area.width);
+    let sparkline_width = match trend_width {
+        Constraint::Length(width) => width.saturating_sub(2) as usize,
+        _ => 0,
+    };
+
+    let mut widths = vec![
+        Constraint::Length(8), // Total (fixed)
+        Constraint::Length(8), // Live (fixed)
+        function_width,
+        location_width,
+        trend_width,
+    ];
+    if show_ipc {
+        widths.insert(2, Constraint::Length(6)); // IPC (fixed)
+    }
+    if show_alloc {
+        widths.insert(2, Constraint::Length(10)); // Alloc (fixed, room for the badge)
+    }
+    if show_time {
+        widths.insert(2, Constraint::Length(8)); // Time (fixed)
+    }
+    // Zero-width columns are hidden rather than rendered empty
+    let keep: Vec<bool> = widths.iter().map(|w| *w != Constraint::Length(0)).collect();
+    widths.retain(|w| *w != Constraint::Length(0));
+
+    let mut header_labels = vec![
         header_label("Total", SortColumn::Total, state.sort),
         header_label("Live", SortColumn::Live, state.sort),
         header_label("Function", SortColumn::Function, state.sort),
         header_label("Location", SortColumn::Location, state.sort),
         header_label("Trend", SortColumn::Trend, state.sort),
     ];
-    let header_cells = header_labels.iter().map(|h| {
-        Cell::from(h.as_str()).style(
-            Style::default()
-                .fg(Color::Cyan)
-                .add_modifier(Modifier::BOLD),
-        )
-    });
+    if show_ipc {
+        header_labels.insert(2, "IPC".to_string());
+    }
+    if show_alloc {
+        header_labels.insert(2, "Alloc".to_string());
+    }
+    if show_time {
+        header_labels.insert(2, "Time".to_string());
+    }
+    let header_cells = header_labels
+        .iter()
+        .zip(&keep)
+        .filter(|(_, k)| **k)
+        .map(|(h, _)| {
+            Cell::from(h.as_str()).style(
+                Style::default()
+                    .fg(Color::Cyan)
+                    .add_modifier(Modifier::BOLD),
+            )
+        });
     let header = Row::new(header_cells).height(1);
 
     let visible_height = state.area.height.saturating_sub(3) as usize;
@@ -166,33 +354,54 @@ fn render_unified_table(
         .take(visible_height.max(1))
         .map(|(i, row)| {
             // Sparkline with per-character coloring
-            let sparkline_line = render_sparkline(&row.sparkline_data, 12, global_max);
+            let sparkline_line = render_sparkline(&row.sparkline_data, sparkline_width, global_max);
 
             let style = if i == selected {
                 Style::default().bg(Color::DarkGray)
             } else {
                 Style::default()
             };
+            let style = if row.alert {
+                style
+                    .fg(Color::LightRed)
+                    .add_modifier(Modifier::BOLD | Modifier::SLOW_BLINK)
+            } else {
+                style
+            };
 
-            Row::new(vec![
+            let mut cells = vec![
                 Cell::from(row.total.clone()).style(Style::default().fg(row.total_color)),
                 Cell::from(row.live.clone()).style(Style::default().fg(row.live_color)),
                 Cell::from(row.function.clone()),
                 Cell::from(row.location.clone()),
                 Cell::from(sparkline_line),
-            ])
-            .style(style)
+            ];
+            if show_ipc {
+                cells.insert(2, Cell::from(row.ipc.clone().unwrap_or_default()));
+            }
+            if show_alloc {
+                let alloc = row.alloc.clone().unwrap_or_default();
+                cells.insert(
+                    2,
+                    Cell::from(alloc).style(Style::default().fg(row.alloc_color)),
+                );
+            }
+            if show_time {
+                let time = row.time.clone().unwrap_or_default();
+                cells.insert(
+                    2,
+                    Cell::from(time).style(Style::default().fg(row.total_color)),
+                );
+            }
+            let cells = cells
+                .into_iter()
+                .zip(&keep)
+                .filter(|(_, k)| **k)
+                .map(|(c, _)| c);
+            Row::new(cells).style(style)
         })
         .collect();
 
-    let widths = [
-        Constraint::Length(8),  // Total (fixed)
-        Constraint::Length(8),  // Live (fixed)
-        Constraint::Fill(1),    // Function (expand)
-        Constraint::Fill(1),    // Location (expand)
-        Constraint::Length(14), // Trend (fixed, 12 chars + padding)
-    ];
-
     let table = Table::new(table_rows, widths).header(header).block(block);
 
     frame.render_widget(table, state.area);
@@ -345,9 +554,12 @@ fn render_header_status(frame: &mut Frame, app: &App, area: Rect) {
     let minutes = (elapsed.as_secs() % 3600) / 60;
     let seconds = elapsed.as_secs() % 60;
 
-    let header = if app.is_static() {
+    let mut header = if app.is_static() {
         // Static/view mode header
-        let file_name = app.file_name().unwrap_or("profile");
+        let mut file_name = app.file_name().unwrap_or("profile").to_string();
+        if let Some(playlist) = app.playlist() {
+            file_name = format!("[{}/{}] {}", playlist.position + 1, playlist.len, file_name);
+        }
         Line::from(vec![
             Span::styled(
                 "rsprof",
@@ -399,10 +611,84 @@ fn render_header_status(frame: &mut Frame, app: &App, area: Rect) {
         ])
     };
 
+    if let Some(rate) = sampling_summary(app) {
+        header.spans.push(Span::raw(format!(" │ {}", rate)));
+    }
+
     let paragraph = Paragraph::new(header);
     frame.render_widget(paragraph, area);
 }
 
+/// CPU samples per second over time, with the threads taking them
+///
+/// Live: the current rate, a sparkline of the last checkpoints and the
+/// threads seen in the latest one. Viewing: the average rate, a sparkline
+/// of the whole run and the most threads in any checkpoint. A flat low line
+/// is an idle target; dips mark off-CPU stretches.
+fn sampling_summary(app: &App) -> Option<String> {
+    const WIDTH: usize = 12;
+    let stats = app.checkpoint_stats();
+    // Profiles from before the counts were stored have zeros throughout
+    if stats
+        .iter()
+        .all(|s| s.sample_count == 0 && s.thread_count == 0)
+    {
+        return None;
+    }
+    let (shown, rate, threads) = if app.is_static() {
+        let samples: u64 = stats.iter().map(|s| s.sample_count).sum();
+        let ms: i64 = stats.iter().map(|s| s.interval_ms).sum();
+        let rate = if ms > 0 {
+            samples as f64 * 1000.0 / ms as f64
+        } else {
+            0.0
+        };
+        let threads = stats.iter().map(|s| s.thread_count).max().unwrap_or(0);
+        (stats, rate, threads)
+    } else {
+        let last = stats.last()?;
+        let shown = &stats[stats.len().saturating_sub(WIDTH)..];
+        (shown, last.sample_rate(), last.thread_count)
+    };
+
+    // Rate per bucket of checkpoints (one checkpoint each when they fit)
+    let buckets = shown.len().min(WIDTH);
+    let rates: Vec<f64> = (0..buckets)
+        .map(|i| {
+            let bucket = &shown[i * shown.len() / buckets..(i + 1) * shown.len() / buckets];
+            let samples: u64 = bucket.iter().map(|s| s.sample_count).sum();
+            let ms: i64 = bucket.iter().map(|s| s.interval_ms).sum();
+            if ms > 0 {
+                samples as f64 * 1000.0 / ms as f64
+            } else {
+                0.0
+            }
+        })
+        .collect();
+    let max = rates.iter().copied().fold(0.0, f64::max);
+    let sparkline: String = rates
+        .iter()
+        .map(|&r| {
+            let level = if max > 0.0 {
+                (r / max * (SPARKLINE_CHARS.len() - 1) as f64).round() as usize
+            } else {
+                0
+            };
+            SPARKLINE_CHARS[level]
+        })
+        .collect();
+
+    let mut summary = format!("{:.0}/s {}", rate, sparkline);
+    if threads > 0 {
+        summary.push_str(&format!(
+            " │ {} thread{}",
+            threads,
+            if threads == 1 { "" } else { "s" }
+        ));
+    }
+    Some(summary)
+}
+
 fn render_header_tabs(frame: &mut Frame, app: &App, area: Rect) {
     let active_style = Style::default().bg(Color::Cyan).fg(Color::Black);
     let inactive_style = Style::default().fg(Color::DarkGray);
@@ -429,6 +715,11 @@ fn render_header_tabs(frame: &mut Frame, app: &App, area: Rect) {
 }
 
 fn render_main_content(frame: &mut Frame, app: &mut App, area: Rect) {
+    if app.flame_visible {
+        render_flamegraph(frame, app, area);
+        return;
+    }
+
     let elapsed_secs = app.elapsed_secs();
     let view_mode = app.view_mode;
     let chart_visible = app.chart_visible;
@@ -440,16 +731,54 @@ fn render_main_content(frame: &mut Frame, app: &mut App, area: Rect) {
     // Prepare table data based on view mode (use appropriate sparklines)
     let (title, rows) = match view_mode {
         ViewMode::Cpu => {
+            let (x_start, x_end) = app.chart_state.visible_range(elapsed_secs);
+            app.update_cpu_window(x_start, x_end);
             let entries = app.entries();
-            let sparklines = app.cpu_sparklines().clone();
-            ("Top CPU", cpu_to_table_rows(entries, &sparklines))
+            (
+                match app.percent_base {
+                    PercentBase::Total => "Top CPU",
+                    PercentBase::Window => "Top CPU (% of window)",
+                },
+                cpu_to_table_rows(
+                    entries,
+                    |e| app.cpu_share(e),
+                    app.cpu_sparklines(),
+                    app.cpu_allocs(),
+                    app.cpu_ipc_counts(),
+                    app.cpu_freq_hz(),
+                    app.heat_mode,
+                    app.demangle_mode,
+                    app.name_options,
+                ),
+            )
+        }
+        ViewMode::Memory if app.heap_grouping == HeapGrouping::Tag => {
+            ("Memory by Tag", tag_to_table_rows(app.tag_entries()))
         }
         ViewMode::Memory => {
             let entries = app.heap_entries();
-            let sparklines = app.heap_sparklines().clone();
-            ("Top Memory", heap_to_table_rows(entries, &sparklines))
+            (
+                "Top Memory",
+                heap_to_table_rows(
+                    entries,
+                    app.heap_sparklines(),
+                    app.churn_alerts(),
+                    app.demangle_mode,
+                    app.name_options,
+                ),
+            )
+        }
+    };
+
+    // Marker/tag rows have no location to filter or chart
+    let tag_rows = view_mode == ViewMode::Memory && app.heap_grouping == HeapGrouping::Tag;
+    let title = match app.filter() {
+        (filter, _) if !filter.is_empty() && !tag_rows => {
+            format!("{} matching \"{}\"", title, filter)
         }
+        _ => title.to_string(),
     };
+    let chart_visible = chart_visible && !tag_rows;
 
     if chart_visible {
         // Split: left table (60%) | right chart (40%)
@@ -465,7 +794,7 @@ fn render_main_content(frame: &mut Frame, app: &mut App, area: Rect) {
         // Render unified table
         render_unified_table(
             frame,
-            title,
+            &title,
             &rows,
             TableRenderState {
                 selected,
@@ -473,6 +802,7 @@ fn render_main_content(frame: &mut Frame, app: &mut App, area: Rect) {
                 focus,
                 sort,
                 area: chunks[0],
+                columns: app.column_widths,
             },
         );
 
@@ -482,22 +812,329 @@ fn render_main_content(frame: &mut Frame, app: &mut App, area: Rect) {
             ViewMode::Memory => render_memory_chart(frame, app, elapsed_secs, chunks[1]),
         }
     } else {
-        // Full-width table with sparklines (no chart)
-        app.set_table_area(area);
+        // Full-width table with sparklines (no chart), source or call tree pane below it
+        let pane_visible = app.source_visible || app.call_tree_visible;
+        let (table_area, pane_area) =
+            if pane_visible && !tag_rows && area.height >= 2 * SOURCE_PANE_HEIGHT {
+                let chunks = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([Constraint::Min(0), Constraint::Length(SOURCE_PANE_HEIGHT)])
+                    .split(area);
+                (chunks[0], Some(chunks[1]))
+            } else {
+                (area, None)
+            };
+        app.set_table_area(table_area);
         app.set_chart_area(Rect::default());
 
         render_unified_table(
             frame,
-            title,
+            &title,
             &rows,
             TableRenderState {
                 selected,
                 scroll_offset,
                 focus,
                 sort,
-                area,
+                area: table_area,
+                columns: app.column_widths,
             },
         );
+        match pane_area {
+            Some(pane_area) if app.call_tree_visible => {
+                render_call_tree_pane(frame, app, pane_area)
+            }
+            Some(pane_area) => render_source_pane(frame, app, pane_area),
+            None => {}
+        }
+    }
+}
+
+/// Rows of the source pane, borders included
+const SOURCE_PANE_HEIGHT: u16 = 13;
+
+/// Source lines around the selected row's location, centred on it
+fn render_source_pane(frame: &mut Frame, app: &mut App, area: Rect) {
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .border_style(Style::default().fg(Color::DarkGray));
+    let Some(snippet) = app.selected_source() else {
+        let message = Line::styled("no source location", Style::default().fg(Color::DarkGray));
+        frame.render_widget(Paragraph::new(message).block(block.title(" Source ")), area);
+        return;
+    };
+
+    let block = block.title(format!(
+        " {} ",
+        format_location(&snippet.file, snippet.line)
+    ));
+    let lines: Vec<Line> = match &snippet.lines {
+        Err(reason) => vec![Line::styled(
+            reason.clone(),
+            Style::default().fg(Color::DarkGray),
+        )],
+        Ok(lines) => {
+            let height = area.height.saturating_sub(2) as usize;
+            let hot = lines
+                .iter()
+                .position(|&(number, _)| number == snippet.line)
+                .unwrap_or(0);
+            let start = hot
+                .saturating_sub(height / 2)
+                .min(lines.len().saturating_sub(height));
+            let width = lines
+                .last()
+                .map_or(1, |(number, _)| number.to_string().len());
+            lines[start..]
+                .iter()
+                .take(height)
+                .map(|(number, text)| {
+                    if *number == snippet.line {
+                        Line::styled(
+                            format!("{:>width$} > {}", number, text),
+                            Style::default()
+                                .fg(Color::Yellow)
+                                .add_modifier(Modifier::BOLD),
+                        )
+                    } else {
+                        Line::from(vec![
+                            Span::styled(
+                                format!("{:>width$}   ", number),
+                                Style::default().fg(Color::DarkGray),
+                            ),
+                            Span::raw(text.clone()),
+                        ])
+                    }
+                })
+                .collect()
+        }
+    };
+    frame.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+/// Flamegraph of the current view's call stacks, in place of the table
+///
+/// Frames are colored by their self weight as a share of the zoomed frame,
+/// with the table's heat thresholds; the bottom row describes the selection.
+fn render_flamegraph(frame: &mut Frame, app: &mut App, area: Rect) {
+    app.set_table_area(Rect::default());
+    app.set_chart_area(Rect::default());
+    let (demangle_mode, names, heat_mode) = (app.demangle_mode, app.name_options, app.heat_mode);
+    let (weight_label, format_weight) = stack_weight(app);
+    let view = app.flame_view();
+    let graph = &view.graph;
+
+    let mut title = format!(" Flamegraph: {} ", weight_label);
+    if view.zoom != 0 {
+        title.push_str(&format!(
+            "- zoomed to {} ",
+            format_function(&graph.node(view.zoom).function, demangle_mode, names)
+        ));
+    }
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .border_style(Style::default().fg(Color::Cyan))
+        .title(title);
+    if graph.is_empty() {
+        let message = match app.view_mode {
+            ViewMode::Cpu => "no CPU call stacks (recorded by rsprof-trace targets only)",
+            ViewMode::Memory => "no heap call stacks",
+        };
+        let message = Line::styled(message, Style::default().fg(Color::DarkGray));
+        frame.render_widget(Paragraph::new(message).block(block), area);
+        return;
+    }
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+    if inner.height < 2 || inner.width == 0 {
+        return;
+    }
+
+    // One row per stack depth from the zoomed frame down, the last row for
+    // the selected frame's weights
+    let mut boxes = Vec::new();
+    flame_layout(
+        graph,
+        view.zoom,
+        (0.0, inner.width as f64),
+        0,
+        inner.height - 1,
+        &mut boxes,
+    );
+    let zoom_total = graph.node(view.zoom).total.max(1) as f64;
+    let self_pct = |index: usize| graph.node(index).self_weight as f64 * 100.0 / zoom_total;
+    let heat = match heat_mode {
+        HeatMode::Absolute => HeatThresholds::ABSOLUTE,
+        HeatMode::Relative => HeatThresholds::relative(boxes.iter().map(|b| self_pct(b.0))),
+    };
+
+    let buffer = frame.buffer_mut();
+    for &(index, row, start, end) in &boxes {
+        let style = if index == view.selected {
+            Style::default()
+                .bg(Color::Cyan)
+                .fg(Color::Black)
+                .add_modifier(Modifier::BOLD)
+        } else {
+            match color_for_percent(self_pct(index), &heat) {
+                Color::White => Style::default().bg(Color::DarkGray).fg(Color::White),
+                color => Style::default().bg(color).fg(Color::Black),
+            }
+        };
+        // Leave a gap between neighbours unless the frame is a single column
+        let width = if end - start > 1 { end - start - 1 } else { 1 } as usize;
+        let name = format_function(&graph.node(index).function, demangle_mode, names);
+        let label: String = name.chars().take(width).collect();
+        buffer.set_stringn(
+            inner.x + start,
+            inner.y + row,
+            format!("{:<width$}", label),
+            width,
+            style,
+        );
+    }
+
+    let selected = graph.node(view.selected);
+    let root_total = graph.node(0).total.max(1) as f64;
+    let detail = Line::from(vec![
+        Span::styled(
+            format_function(&selected.function, demangle_mode, names),
+            Style::default().add_modifier(Modifier::BOLD),
+        ),
+        Span::raw(format!(
+            "  total {} ({:.1}%)  self {} ({:.1}%)",
+            format_weight(selected.total),
+            selected.total as f64 * 100.0 / root_total,
+            format_weight(selected.self_weight),
+            selected.self_weight as f64 * 100.0 / root_total,
+        )),
+    ]);
+    let detail_area = Rect {
+        y: inner.bottom() - 1,
+        height: 1,
+        ..inner
+    };
+    frame.render_widget(Paragraph::new(detail), detail_area);
+}
+
+/// What the call stacks are weighed by, and how to show a weight
+fn stack_weight(app: &App) -> (&'static str, fn(u64) -> String) {
+    match app.view_mode {
+        ViewMode::Cpu => ("CPU samples", |w| format!("{} samples", w)),
+        ViewMode::Memory => match app.sparkline_source {
+            SparklineSource::LiveBytes => ("live bytes", |w| format_bytes(w as i64)),
+            SparklineSource::AllocCount => ("allocations", |w| format!("{} allocs", w)),
+        },
+    }
+}
+
+/// Callers (left) and callees (right) of the selected row's function, as
+/// shares of the stacks through it
+fn render_call_tree_pane(frame: &mut Frame, app: &mut App, area: Rect) {
+    let (demangle_mode, names) = (app.demangle_mode, app.name_options);
+    let focused = app.focus == Focus::CallTree;
+    let (_, format_weight) = stack_weight(app);
+    let cursor = app.call_tree_row();
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .border_style(Style::default().fg(if focused {
+            Color::Cyan
+        } else {
+            Color::DarkGray
+        }));
+    let Some(tree) = app.selected_call_tree() else {
+        let message = Line::styled("no function selected", Style::default().fg(Color::DarkGray));
+        frame.render_widget(
+            Paragraph::new(message).block(block.title(" Call tree ")),
+            area,
+        );
+        return;
+    };
+
+    let total = tree.total.max(1) as f64;
+    let block = block.title(format!(
+        " {}: total {}, self {} ({:.1}%) ",
+        format_function(&tree.function, demangle_mode, names),
+        format_weight(tree.total),
+        format_weight(tree.self_weight),
+        tree.self_weight as f64 * 100.0 / total,
+    ));
+    if tree.total == 0 {
+        let message = Line::styled(
+            "no call stacks through this function",
+            Style::default().fg(Color::DarkGray),
+        );
+        frame.render_widget(Paragraph::new(message).block(block), area);
+        return;
+    }
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+    let columns =
+        Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).split(inner);
+
+    // Heading row, then as many entries as fit, scrolled to the cursor
+    let rows = inner.height.saturating_sub(1) as usize;
+    for (column, heading, entries, first) in [
+        (columns[0], "Callers", &tree.callers, 0),
+        (columns[1], "Callees", &tree.callees, tree.callers.len()),
+    ] {
+        let mut lines = vec![Line::styled(
+            heading,
+            Style::default()
+                .fg(Color::Cyan)
+                .add_modifier(Modifier::BOLD),
+        )];
+        if entries.is_empty() {
+            lines.push(Line::styled(
+                "  (none)",
+                Style::default().fg(Color::DarkGray),
+            ));
+        }
+        let start = match cursor.checked_sub(first) {
+            Some(position) if position < entries.len() => (position + 1).saturating_sub(rows),
+            _ => 0,
+        };
+        for (index, (name, weight)) in entries.iter().enumerate().skip(start).take(rows) {
+            let text = format!(
+                "{:>5.1}% {:>12}  {}",
+                *weight as f64 * 100.0 / total,
+                format_weight(*weight),
+                format_function(name, demangle_mode, names)
+            );
+            let style = if focused && first + index == cursor {
+                Style::default().bg(Color::Cyan).fg(Color::Black)
+            } else {
+                Style::default()
+            };
+            lines.push(Line::styled(text, style));
+        }
+        frame.render_widget(Paragraph::new(lines), column);
+    }
+}
+
+/// Place `index` and its callees: (node, row, start column, end column)
+///
+/// Children split their parent's span by weight, heaviest on the left;
+/// frames narrower than a column or deeper than `rows` are left out.
+fn flame_layout(
+    graph: &FlameGraph,
+    index: usize,
+    (x, width): (f64, f64),
+    row: u16,
+    rows: u16,
+    boxes: &mut Vec<(usize, u16, u16, u16)>,
+) {
+    let (start, end) = (x.round() as u16, (x + width).round() as u16);
+    if row >= rows || end <= start {
+        return;
+    }
+    boxes.push((index, row, start, end));
+    let node = graph.node(index);
+    let mut child_x = x;
+    for &child in &node.children {
+        let child_width = width * graph.node(child).total as f64 / node.total as f64;
+        flame_layout(graph, child, (child_x, child_width), row + 1, rows, boxes);
+        child_x += child_width;
     }
 }
 
@@ -511,7 +1148,7 @@ fn render_memory_chart(frame: &mut Frame, app: &mut App, elapsed_secs: f64, area
     // Get selected function name for title
     let base_title = if let Some(func) = app.selected_heap_func() {
         let clean = strip_hash_suffix(func);
-        let short = clean.split("::").last().unwrap_or(&clean);
+        let short = clean.split("::").last().unwrap_or(clean);
         short.to_string()
     } else {
         "Memory".to_string()
@@ -532,10 +1169,6 @@ fn render_memory_chart(frame: &mut Frame, app: &mut App, elapsed_secs: f64, area
     } else {
         ""
     };
-    let title = format!(
-        " {} [{}] ({}){} ",
-        base_title, zoom_label, chart_type_label, y_axis_label
-    );
 
     // Calculate chart inner width for aggregation
     let chart_inner_width = area.width.saturating_sub(12).max(1) as usize;
@@ -545,6 +1178,18 @@ fn render_memory_chart(frame: &mut Frame, app: &mut App, elapsed_secs: f64, area
         .query_heap_chart_data(x_start, x_end, chart_inner_width)
         .to_vec();
 
+    // Size distribution of the selected site's individual allocations
+    let sizes_label = app
+        .selected_heap_sizes()
+        .filter(|sizes| sizes.count() > 0)
+        .map(format_size_distribution)
+        .unwrap_or_default();
+    let aggregate_label = aggregate_label(app.chart_state.aggregate);
+    let title = format!(
+        " {} [{}] ({}){}{}{} ",
+        base_title, zoom_label, chart_type_label, y_axis_label, aggregate_label, sizes_label
+    );
+
     let block = Block::default()
         .title(title.clone())
         .borders(Borders::ALL)
@@ -559,45 +1204,30 @@ fn render_memory_chart(frame: &mut Frame, app: &mut App, elapsed_secs: f64, area
     }
 
     // Filter data to visible range
-    let visible_data: Vec<(f64, f64)> = chart_data
-        .iter()
-        .filter(|(t, _)| *t >= x_start && *t <= x_end)
-        .copied()
-        .collect();
-
-    // Calculate y bounds from visible data (bytes)
-    let (y_min, y_max) = if visible_data.is_empty() {
-        (0.0, 1000000.0) // Default to 1MB
-    } else {
-        let max_y = visible_data.iter().map(|(_, y)| *y).fold(0.0f64, f64::max);
-        if app.chart_state.y_axis_from_zero {
-            // Start from zero
-            let padding = max_y * 0.1;
-            (0.0, max_y + padding)
-        } else {
-            // Auto-scale
-            let min_y = visible_data
-                .iter()
-                .map(|(_, y)| *y)
-                .fold(f64::MAX, f64::min);
-            let range = (max_y - min_y).max(1.0);
-            let padding = range * 0.1;
-            ((min_y - padding).max(0.0), max_y + padding)
-        }
-    };
+    let visible_data = visible_points(&chart_data, x_start, x_end);
+    let (y_min, y_max) = memory_y_bounds(&visible_data, app.chart_state.y_axis_from_zero);
+    let (x_start, x_end) = finite_bounds(x_start, x_end, (0.0, 1.0));
 
     let (marker, graph_type) = match chart_type {
         ChartType::Line => (symbols::Marker::Braille, GraphType::Line),
         ChartType::Bar => (symbols::Marker::HalfBlock, GraphType::Bar),
     };
 
-    let datasets = vec![
+    // Checkpoint labels in view, drawn as vertical lines under the data
+    let labels = visible_labels(app.checkpoint_labels(), x_start, x_end);
+    let label_lines: Vec<[(f64, f64); 2]> = labels
+        .iter()
+        .map(|(t, _)| [(*t, y_min), (*t, y_max)])
+        .collect();
+
+    let mut datasets = label_datasets(&label_lines);
+    datasets.push(
         Dataset::default()
             .marker(marker)
             .graph_type(graph_type)
             .style(Style::default().fg(Color::Magenta))
             .data(&visible_data),
-    ];
+    );
 
     // Generate x-axis labels
     let x_labels = generate_time_labels(x_start, x_end);
@@ -609,6 +1239,9 @@ fn render_memory_chart(frame: &mut Frame, app: &mut App, elapsed_secs: f64, area
         Span::raw(format_bytes_short(y_max as i64)),
     ];
 
+    let (area, strip_area) = split_label_strip(area, !labels.is_empty());
+    let graph_span = chart_graph_span(area, &y_labels, &x_labels);
+
     let chart = Chart::new(datasets)
         .block(block)
         .x_axis(
@@ -626,6 +1259,41 @@ fn render_memory_chart(frame: &mut Frame, app: &mut App, elapsed_secs: f64, area
         );
 
     frame.render_widget(chart, area);
+    if let Some(strip_area) = strip_area {
+        render_label_strip(frame, &labels, (x_start, x_end), graph_span, strip_area);
+    }
+}
+
+/// Chart title suffix for how buckets collapse (max is the default, unlabeled)
+fn aggregate_label(aggregate: BucketAggregate) -> &'static str {
+    match aggregate {
+        BucketAggregate::Max => "",
+        BucketAggregate::Avg => " mean",
+        BucketAggregate::P95 => " p95",
+    }
+}
+
+/// Summarize allocation sizes for the memory chart title
+///
+/// Sizes are bucketed by power of two, so median and mode are estimates
+/// unless the site only ever allocates one size.
+fn format_size_distribution(sizes: &SizeDistribution) -> String {
+    if sizes.min == sizes.max {
+        return format!(" | size {} (uniform)", format_bytes(sizes.min as i64));
+    }
+    let median = sizes.median().unwrap_or(0);
+    let mode = match sizes.mode() {
+        Some((low, high)) if low == high => format_bytes(low as i64),
+        Some((low, high)) => format!("{}-{}", format_bytes(low as i64), format_bytes(high as i64)),
+        None => "-".to_string(),
+    };
+    format!(
+        " | size {}-{} med ~{} mode {}",
+        format_bytes(sizes.min as i64),
+        format_bytes(sizes.max as i64),
+        format_bytes(median as i64),
+        mode
+    )
 }
 
 /// Format bytes for y-axis labels (short form)
@@ -652,7 +1320,7 @@ fn render_line_chart(frame: &mut Frame, app: &mut App, elapsed_secs: f64, area:
     // Get selected function name for title (strip hash suffix and simplify)
     let base_title = if let Some(func) = app.selected_func() {
         let clean = strip_hash_suffix(func);
-        let short = clean.split("::").last().unwrap_or(&clean);
+        let short = clean.split("::").last().unwrap_or(clean);
         short.to_string()
     } else {
         "CPU%".to_string()
@@ -673,9 +1341,15 @@ fn render_line_chart(frame: &mut Frame, app: &mut App, elapsed_secs: f64, area:
     } else {
         ""
     };
+    let series_label = match app.cpu_series {
+        CpuSeries::Instant => String::new(),
+        CpuSeries::Cumulative => " cumulative".to_string(),
+        CpuSeries::RunningAverage => format!(" avg{}", RUNNING_AVERAGE_CHECKPOINTS),
+    };
+    let aggregate_label = aggregate_label(app.chart_state.aggregate);
     let title = format!(
-        " {} [{}] ({}){} ",
-        base_title, zoom_label, chart_type_label, y_axis_label
+        " {} [{}] ({}){}{}{} ",
+        base_title, zoom_label, chart_type_label, y_axis_label, series_label, aggregate_label
     );
 
     // Calculate chart inner width for aggregation
@@ -702,52 +1376,43 @@ fn render_line_chart(frame: &mut Frame, app: &mut App, elapsed_secs: f64, area:
     }
 
     // Filter data to visible range (cache may have prefetched extra data)
-    let visible_data: Vec<(f64, f64)> = chart_data
-        .iter()
-        .filter(|(t, _)| *t >= x_start && *t <= x_end)
-        .copied()
-        .collect();
-
-    // Calculate y bounds from visible data
-    let (y_min, y_max) = if visible_data.is_empty() {
-        (0.0, 100.0)
-    } else {
-        let max_y = visible_data.iter().map(|(_, y)| *y).fold(0.0f64, f64::max);
-        if app.chart_state.y_axis_from_zero {
-            // Start from zero, round max to nice number
-            let padding = max_y * 0.1;
-            (0.0, ((max_y + padding) / 5.0).ceil() * 5.0)
-        } else {
-            // Auto-scale
-            let min_y = visible_data
-                .iter()
-                .map(|(_, y)| *y)
-                .fold(f64::MAX, f64::min);
-            let range = (max_y - min_y).max(1.0);
-            let padding = range * 0.1;
-            (
-                ((min_y - padding).max(0.0) / 5.0).floor() * 5.0,
-                ((max_y + padding) / 5.0).ceil() * 5.0,
-            )
-        }
-    };
+    let visible_data = visible_points(&chart_data, x_start, x_end);
+    let (y_min, y_max) = cpu_y_bounds(&visible_data, app.chart_state.y_axis_from_zero);
+    let (x_start, x_end) = finite_bounds(x_start, x_end, (0.0, 1.0));
 
     let (marker, graph_type) = match chart_type {
         ChartType::Line => (symbols::Marker::Braille, GraphType::Line),
         ChartType::Bar => (symbols::Marker::HalfBlock, GraphType::Bar),
     };
 
-    let datasets = vec![
+    // Checkpoint labels in view, drawn as vertical lines under the data
+    let labels = visible_labels(app.checkpoint_labels(), x_start, x_end);
+    let label_lines: Vec<[(f64, f64); 2]> = labels
+        .iter()
+        .map(|(t, _)| [(*t, y_min), (*t, y_max)])
+        .collect();
+
+    let mut datasets = label_datasets(&label_lines);
+    datasets.push(
         Dataset::default()
             .marker(marker)
             .graph_type(graph_type)
             .style(Style::default().fg(Color::Green))
             .data(&visible_data),
-    ];
+    );
 
     // Generate x-axis labels based on visible range
     let x_labels = generate_time_labels(x_start, x_end);
 
+    let y_labels = vec![
+        Span::raw(format!("{:.0}%", y_min)),
+        Span::raw(format!("{:.0}%", (y_min + y_max) / 2.0)),
+        Span::raw(format!("{:.0}%", y_max)),
+    ];
+
+    let (area, strip_area) = split_label_strip(area, !labels.is_empty());
+    let graph_span = chart_graph_span(area, &y_labels, &x_labels);
+
     let chart = Chart::new(datasets)
         .block(block)
         .x_axis(
@@ -761,14 +1426,184 @@ fn render_line_chart(frame: &mut Frame, app: &mut App, elapsed_secs: f64, area:
                 .title("%")
                 .style(Style::default().fg(Color::DarkGray))
                 .bounds([y_min, y_max])
-                .labels(vec![
-                    Span::raw(format!("{:.0}%", y_min)),
-                    Span::raw(format!("{:.0}%", (y_min + y_max) / 2.0)),
-                    Span::raw(format!("{:.0}%", y_max)),
-                ]),
+                .labels(y_labels),
         );
 
     frame.render_widget(chart, area);
+    if let Some(strip_area) = strip_area {
+        render_label_strip(frame, &labels, (x_start, x_end), graph_span, strip_area);
+    }
+}
+
+/// Points of `data` inside `[x_start, x_end]`, without non-finite values
+fn visible_points(data: &[(f64, f64)], x_start: f64, x_end: f64) -> Vec<(f64, f64)> {
+    data.iter()
+        .filter(|(t, y)| *t >= x_start && *t <= x_end && y.is_finite())
+        .copied()
+        .collect()
+}
+
+/// Y-axis bounds of the CPU chart for its visible points, in multiples of 5%
+fn cpu_y_bounds(points: &[(f64, f64)], from_zero: bool) -> (f64, f64) {
+    let (y_min, y_max) = if points.is_empty() {
+        (0.0, 100.0)
+    } else {
+        let max_y = points.iter().map(|(_, y)| *y).fold(0.0f64, f64::max);
+        if from_zero {
+            // Start from zero, round max to nice number
+            let padding = max_y * 0.1;
+            (0.0, ((max_y + padding) / 5.0).ceil() * 5.0)
+        } else {
+            // Auto-scale
+            let min_y = points.iter().map(|(_, y)| *y).fold(f64::MAX, f64::min);
+            let range = (max_y - min_y).max(1.0);
+            let padding = range * 0.1;
+            (
+                ((min_y - padding).max(0.0) / 5.0).floor() * 5.0,
+                ((max_y + padding) / 5.0).ceil() * 5.0,
+            )
+        }
+    };
+    finite_bounds(y_min, y_max, (0.0, 100.0))
+}
+
+/// Y-axis bounds of the memory chart for its visible points (bytes)
+fn memory_y_bounds(points: &[(f64, f64)], from_zero: bool) -> (f64, f64) {
+    let (y_min, y_max) = if points.is_empty() {
+        (0.0, 1000000.0) // Default to 1MB
+    } else {
+        let max_y = points.iter().map(|(_, y)| *y).fold(0.0f64, f64::max);
+        if from_zero {
+            // Start from zero
+            let padding = max_y * 0.1;
+            (0.0, max_y + padding)
+        } else {
+            // Auto-scale
+            let min_y = points.iter().map(|(_, y)| *y).fold(f64::MAX, f64::min);
+            let range = (max_y - min_y).max(1.0);
+            let padding = range * 0.1;
+            ((min_y - padding).max(0.0), max_y + padding)
+        }
+    };
+    finite_bounds(y_min, y_max, (0.0, 1000000.0))
+}
+
+/// Make axis bounds safe to hand to ratatui
+///
+/// Non-finite or inverted bounds fall back to `default`; an empty range is
+/// widened so the chart still has something to scale against.
+fn finite_bounds(min: f64, max: f64, default: (f64, f64)) -> (f64, f64) {
+    if !min.is_finite() || !max.is_finite() || min > max {
+        return default;
+    }
+    if min == max {
+        return (min, max + 1.0);
+    }
+    (min, max)
+}
+
+/// Checkpoint labels inside the visible time range
+fn visible_labels(labels: &[(f64, String)], x_start: f64, x_end: f64) -> Vec<(f64, String)> {
+    labels
+        .iter()
+        .filter(|(t, _)| *t >= x_start && *t <= x_end)
+        .cloned()
+        .collect()
+}
+
+/// Vertical guide lines marking labeled checkpoints
+fn label_datasets(lines: &[[(f64, f64); 2]]) -> Vec<Dataset<'_>> {
+    lines
+        .iter()
+        .map(|line| {
+            Dataset::default()
+                .marker(symbols::Marker::Braille)
+                .graph_type(GraphType::Line)
+                .style(Style::default().fg(Color::Yellow))
+                .data(line)
+        })
+        .collect()
+}
+
+/// Reserve the bottom row of a chart area for checkpoint labels, if any
+fn split_label_strip(area: Rect, has_labels: bool) -> (Rect, Option<Rect>) {
+    if !has_labels || area.height < 6 {
+        return (area, None);
+    }
+    let chart = Rect {
+        height: area.height - 1,
+        ..area
+    };
+    let strip = Rect {
+        y: area.y + area.height - 1,
+        height: 1,
+        ..area
+    };
+    (chart, Some(strip))
+}
+
+/// Columns (x, width) of a chart's plot area
+///
+/// Mirrors ratatui's Chart layout: inside the border, right of the y-axis
+/// labels (or the overhang of the first x label) and the axis line.
+fn chart_graph_span(area: Rect, y_labels: &[Span], x_labels: &[Span]) -> (u16, u16) {
+    let inner_x = area.x + 1;
+    let inner_width = area.width.saturating_sub(2);
+    let y_label_width = y_labels.iter().map(|l| l.width()).max().unwrap_or(0) as u16;
+    let first_x_width = x_labels.first().map_or(0, |l| l.width()) as u16;
+    let left = y_label_width
+        .max(first_x_width.saturating_sub(1))
+        .min(inner_width / 3);
+    let x = inner_x + left + 1;
+    (x, (inner_x + inner_width).saturating_sub(x))
+}
+
+/// Render checkpoint labels under the chart, aligned with the plot area
+fn render_label_strip(
+    frame: &mut Frame,
+    labels: &[(f64, String)],
+    (x_start, x_end): (f64, f64),
+    (x, width): (u16, u16),
+    area: Rect,
+) {
+    if width == 0 {
+        return;
+    }
+    let text = label_strip(labels, x_start, x_end, width as usize);
+    let strip = Rect { x, width, ..area };
+    frame.render_widget(
+        Paragraph::new(text).style(Style::default().fg(Color::Yellow)),
+        strip,
+    );
+}
+
+/// Lay out checkpoint labels on a line `width` columns wide
+///
+/// Each label starts with a "▲" in its timestamp's column; a label that would
+/// overlap the previous one is skipped.
+fn label_strip(labels: &[(f64, String)], x_start: f64, x_end: f64, width: usize) -> String {
+    let mut line = vec![' '; width];
+    let span = x_end - x_start;
+    if width == 0 || span <= 0.0 {
+        return String::new();
+    }
+
+    let mut next_free = 0;
+    for (t, text) in labels {
+        // Same mapping as the braille canvas (two dots per column) the guide lines use
+        let col = ((t - x_start) / span * (width * 2 - 1) as f64) as usize / 2;
+        if col < next_free || col >= width {
+            continue;
+        }
+        let chars =
+            std::iter::once('▲').chain(text.chars().map(|c| if c.is_control() { ' ' } else { c }));
+        for (i, ch) in chars.take(width - col).enumerate() {
+            line[col + i] = ch;
+            next_free = col + i + 2; // Keep a gap before the next label
+        }
+    }
+
+    line.into_iter().collect::<String>().trim_end().to_string()
 }
 
 /// Generate x-axis time labels: start, middle, end
@@ -806,18 +1641,19 @@ fn format_time(secs: f64) -> String {
     }
 }
 
-/// Strip the hash suffix from Rust function names (e.g., "foo::h1234abcd" -> "foo")
-fn strip_hash_suffix(name: &str) -> String {
-    if let Some(idx) = name.rfind("::h") {
-        let suffix = &name[idx + 3..];
-        if suffix.len() == 16 && suffix.chars().all(|c| c.is_ascii_hexdigit()) {
-            return name[..idx].to_string();
-        }
+/// Format bytes into human-readable units (B, KB, MB, GB, TB)
+/// Format estimated CPU time: 850ms, 12.3s, 4m05s
+fn format_cpu_time(secs: f64) -> String {
+    if secs < 1.0 {
+        format!("{:.0}ms", secs * 1000.0)
+    } else if secs < 60.0 {
+        format!("{:.1}s", secs)
+    } else {
+        let secs = secs.round() as u64;
+        format!("{}m{:02}s", secs / 60, secs % 60)
     }
-    name.to_string()
 }
 
-/// Format bytes into human-readable units (B, KB, MB, GB, TB)
 fn format_bytes(bytes: i64) -> String {
     let abs_bytes = bytes.abs() as f64;
     let sign = if bytes < 0 { "-" } else { "" };
@@ -867,53 +1703,269 @@ fn render_footer(frame: &mut Frame, app: &App, area: Rect) {
     spans.push(Span::styled(" m ", Style::default().bg(Color::DarkGray)));
     spans.push(Span::raw(" mode "));
 
-    // Chart toggle - show/hide
-    let chart_label = if app.chart_visible {
-        "hide chart"
-    } else {
-        "show chart"
+    // Row filter: being typed, applied, or the key to start one
+    match app.filter() {
+        (filter, true) => {
+            spans.push(Span::styled(
+                " / ",
+                Style::default().bg(Color::Yellow).fg(Color::Black),
+            ));
+            spans.push(Span::styled(
+                format!(" {}█ ", filter),
+                Style::default().fg(Color::Yellow),
+            ));
+            spans.push(Span::raw(" Enter keep  Esc clear "));
+        }
+        ("", false) => {
+            spans.push(Span::styled(" / ", Style::default().bg(Color::DarkGray)));
+            spans.push(Span::raw(" filter "));
+        }
+        (filter, false) => {
+            spans.push(Span::styled(" / ", Style::default().bg(Color::DarkGray)));
+            spans.push(Span::styled(
+                format!(" filter:{} ", filter),
+                Style::default().fg(Color::Yellow),
+            ));
+        }
+    }
+
+    // Playlist navigation
+    if app.playlist().is_some() {
+        spans.push(Span::styled(" [ ] ", Style::default().bg(Color::DarkGray)));
+        spans.push(Span::raw(" prev/next "));
+    }
+
+    // Repeat the recording of a viewed profile
+    if app.rerecord_command.is_some() {
+        spans.push(Span::styled(" R ", Style::default().bg(Color::DarkGray)));
+        spans.push(Span::raw(" re-record "));
+    }
+
+    // Hotness coloring toggle (CPU view only)
+    if app.view_mode == ViewMode::Cpu {
+        let heat_label = match app.heat_mode {
+            HeatMode::Absolute => "heat:abs",
+            HeatMode::Relative => "heat:rel",
+        };
+        spans.push(Span::styled(" r ", Style::default().bg(Color::DarkGray)));
+        spans.push(Span::raw(format!(" {} ", heat_label)));
+
+        let percent_label = match app.percent_base {
+            PercentBase::Total => "%:run",
+            PercentBase::Window => "%:window",
+        };
+        spans.push(Span::styled(" w ", Style::default().bg(Color::DarkGray)));
+        spans.push(Span::raw(format!(" {} ", percent_label)));
+    }
+
+    // Function name display
+    let names_label = match app.demangle_mode {
+        DemangleMode::Short => "names:short",
+        DemangleMode::Full => "names:full",
+        DemangleMode::Raw => "names:raw",
     };
-    spans.push(Span::styled(" c ", Style::default().bg(Color::DarkGray)));
-    spans.push(Span::raw(format!(" {} ", chart_label)));
-
-    // Context-sensitive help based on chart visibility and focus
-    if app.chart_visible {
-        spans.push(Span::styled(" Esc ", Style::default().bg(Color::DarkGray)));
-        spans.push(Span::raw(" hide "));
-        spans.push(Span::styled(" Tab ", Style::default().bg(Color::DarkGray)));
-        spans.push(Span::raw(" focus "));
-
-        if app.focus == Focus::Table {
-            spans.push(Span::styled(" j/k ", Style::default().bg(Color::DarkGray)));
-            spans.push(Span::raw(" nav "));
+    spans.push(Span::styled(" D ", Style::default().bg(Color::DarkGray)));
+    spans.push(Span::raw(format!(" {} ", names_label)));
+
+    // Sparkline source toggle (memory view only)
+    if app.view_mode == ViewMode::Memory {
+        let spark_label = match app.sparkline_source {
+            SparklineSource::LiveBytes => "trend:bytes",
+            SparklineSource::AllocCount => "trend:allocs",
+        };
+        spans.push(Span::styled(" a ", Style::default().bg(Color::DarkGray)));
+        spans.push(Span::raw(format!(" {} ", spark_label)));
+    }
+
+    // Memory rows per site or per marker/tag (only once there are tags)
+    if app.view_mode == ViewMode::Memory && !app.tag_entries().is_empty() {
+        let group_label = match app.heap_grouping {
+            HeapGrouping::Site => "group:site",
+            HeapGrouping::Tag => "group:tag",
+        };
+        spans.push(Span::styled(" T ", Style::default().bg(Color::DarkGray)));
+        spans.push(Span::raw(format!(" {} ", group_label)));
+    }
+
+    // Display threshold: rows under a CPU% or live-bytes floor are hidden
+    if !(app.view_mode == ViewMode::Memory && app.heap_grouping == HeapGrouping::Tag) {
+        let floor = match app.view_mode {
+            ViewMode::Cpu if app.cpu_threshold() > 0.0 => Some(format!("{}%", app.cpu_threshold())),
+            ViewMode::Memory if app.heap_threshold() > 0 => {
+                Some(format_bytes_short(app.heap_threshold()))
+            }
+            _ => None,
+        };
+        let threshold_label = match floor {
+            Some(floor) => format!("min:{} ({} hidden)", floor, app.hidden_row_count()),
+            None => "min:off".to_string(),
+        };
+        spans.push(Span::styled(" </> ", Style::default().bg(Color::DarkGray)));
+        spans.push(Span::raw(format!(" {} ", threshold_label)));
+    }
+
+    if app.flame_visible {
+        // Flamegraph navigation
+        spans.push(Span::styled(" F ", Style::default().bg(Color::DarkGray)));
+        spans.push(Span::raw(" hide flame "));
+        spans.push(Span::styled(" h/l ", Style::default().bg(Color::DarkGray)));
+        spans.push(Span::raw(" sibling "));
+        spans.push(Span::styled(" j/k ", Style::default().bg(Color::DarkGray)));
+        spans.push(Span::raw(" callee/caller "));
+        spans.push(Span::styled(" +/- ", Style::default().bg(Color::DarkGray)));
+        spans.push(Span::raw(" zoom "));
+        spans.push(Span::styled(" 0 ", Style::default().bg(Color::DarkGray)));
+        spans.push(Span::raw(" all "));
+    } else {
+        // Chart toggle - show/hide
+        let chart_label = if app.chart_visible {
+            "hide chart"
+        } else {
+            "show chart"
+        };
+        spans.push(Span::styled(" c ", Style::default().bg(Color::DarkGray)));
+        spans.push(Span::raw(format!(" {} ", chart_label)));
+
+        // Context-sensitive help based on chart visibility and focus
+        if app.chart_visible {
+            spans.push(Span::styled(" Esc ", Style::default().bg(Color::DarkGray)));
+            spans.push(Span::raw(" hide "));
+            spans.push(Span::styled(" Tab ", Style::default().bg(Color::DarkGray)));
+            spans.push(Span::raw(" focus "));
+
+            if app.focus == Focus::Table {
+                spans.push(Span::styled(" j/k ", Style::default().bg(Color::DarkGray)));
+                spans.push(Span::raw(" nav "));
+            } else {
+                spans.push(Span::styled(" h/l ", Style::default().bg(Color::DarkGray)));
+                spans.push(Span::raw(" pan "));
+                spans.push(Span::styled(" +/- ", Style::default().bg(Color::DarkGray)));
+                spans.push(Span::raw(" zoom "));
+                spans.push(Span::styled(" b ", Style::default().bg(Color::DarkGray)));
+                spans.push(Span::raw(" bar/line "));
+                spans.push(Span::styled(" z ", Style::default().bg(Color::DarkGray)));
+                spans.push(Span::raw(" y:0 "));
+                spans.push(Span::styled(" A ", Style::default().bg(Color::DarkGray)));
+                spans.push(Span::raw(" max/mean/p95 "));
+                if app.view_mode == ViewMode::Cpu {
+                    spans.push(Span::styled(" v ", Style::default().bg(Color::DarkGray)));
+                    spans.push(Span::raw(" series "));
+                }
+            }
         } else {
-            spans.push(Span::styled(" h/l ", Style::default().bg(Color::DarkGray)));
-            spans.push(Span::raw(" pan "));
-            spans.push(Span::styled(" +/- ", Style::default().bg(Color::DarkGray)));
-            spans.push(Span::raw(" zoom "));
-            spans.push(Span::styled(" b ", Style::default().bg(Color::DarkGray)));
-            spans.push(Span::raw(" bar/line "));
-            spans.push(Span::styled(" z ", Style::default().bg(Color::DarkGray)));
-            spans.push(Span::raw(" y:0 "));
+            // Table-only mode
+            if app.focus == Focus::CallTree && app.call_tree_visible {
+                spans.push(Span::styled(" j/k ", Style::default().bg(Color::DarkGray)));
+                spans.push(Span::raw(" caller/callee "));
+                spans.push(Span::styled(
+                    " Enter ",
+                    Style::default().bg(Color::DarkGray),
+                ));
+                spans.push(Span::raw(" open "));
+                spans.push(Span::styled(" Tab ", Style::default().bg(Color::DarkGray)));
+                spans.push(Span::raw(" table "));
+            } else {
+                spans.push(Span::styled(" j/k ", Style::default().bg(Color::DarkGray)));
+                spans.push(Span::raw(" nav "));
+                spans.push(Span::styled(" ^d/u ", Style::default().bg(Color::DarkGray)));
+                spans.push(Span::raw(" page "));
+            }
+            spans.push(Span::styled(" o ", Style::default().bg(Color::DarkGray)));
+            spans.push(Span::raw(if app.source_visible {
+                " hide source "
+            } else {
+                " source "
+            }));
+            spans.push(Span::styled(" t ", Style::default().bg(Color::DarkGray)));
+            spans.push(Span::raw(if app.call_tree_visible {
+                " hide calls "
+            } else {
+                " calls "
+            }));
+            spans.push(Span::styled(" F ", Style::default().bg(Color::DarkGray)));
+            spans.push(Span::raw(" flame "));
         }
-    } else {
-        // Table-only mode
-        spans.push(Span::styled(" j/k ", Style::default().bg(Color::DarkGray)));
-        spans.push(Span::raw(" nav "));
-        spans.push(Span::styled(" ^d/u ", Style::default().bg(Color::DarkGray)));
-        spans.push(Span::raw(" page "));
+    }
+
+    spans.push(Span::styled(" s ", Style::default().bg(Color::DarkGray)));
+    spans.push(Span::raw(" screenshot "));
+
+    // Recent status (shown for a few seconds)
+    if let Some((message, at)) = &app.status_message
+        && at.elapsed() < Duration::from_secs(3)
+    {
+        spans.push(Span::styled(
+            format!(" {} ", message),
+            Style::default().fg(Color::Yellow),
+        ));
     }
 
     let paragraph = Paragraph::new(Line::from(spans));
     frame.render_widget(paragraph, area);
 }
 
-fn color_for_percent(pct: f64) -> Color {
-    if pct >= 20.0 {
+/// Serialize a rendered frame as monochrome text, one line per row
+pub fn buffer_to_text(buffer: &Buffer) -> String {
+    let area = buffer.area;
+    let mut text = String::with_capacity((area.width as usize + 1) * area.height as usize);
+
+    for y in area.top()..area.bottom() {
+        let mut line = String::new();
+        for x in area.left()..area.right() {
+            line.push_str(buffer[(x, y)].symbol());
+        }
+        text.push_str(line.trim_end());
+        text.push('\n');
+    }
+
+    text
+}
+
+/// Percent thresholds for red / yellow / green
+struct HeatThresholds {
+    hot: f64,
+    warm: f64,
+    mild: f64,
+}
+
+impl HeatThresholds {
+    const ABSOLUTE: HeatThresholds = HeatThresholds {
+        hot: 20.0,
+        warm: 10.0,
+        mild: 5.0,
+    };
+
+    /// Thresholds from the distribution of the current values:
+    /// top decile is hot, next 20% warm, next 20% mild.
+    fn relative(values: impl Iterator<Item = f64>) -> Self {
+        let mut sorted: Vec<f64> = values.filter(|v| *v > 0.0).collect();
+        if sorted.is_empty() {
+            return Self::ABSOLUTE;
+        }
+        sorted.sort_by(|a, b| b.total_cmp(a));
+
+        // Value at the given top fraction (0.1 = top 10%)
+        let at = |frac: f64| {
+            let idx = ((sorted.len() as f64 * frac).ceil() as usize).saturating_sub(1);
+            sorted[idx.min(sorted.len() - 1)]
+        };
+
+        HeatThresholds {
+            hot: at(0.1),
+            warm: at(0.3),
+            mild: at(0.5),
+        }
+    }
+}
+
+fn color_for_percent(pct: f64, heat: &HeatThresholds) -> Color {
+    if pct <= 0.0 {
+        Color::White
+    } else if pct >= heat.hot {
         Color::Red
-    } else if pct >= 10.0 {
+    } else if pct >= heat.warm {
         Color::Yellow
-    } else if pct >= 5.0 {
+    } else if pct >= heat.mild {
         Color::Green
     } else {
         Color::White
@@ -957,16 +2009,18 @@ fn simplify_path(path: &str) -> String {
     path.rsplit('/').next().unwrap_or(path).to_string()
 }
 
-fn format_function(func: &str) -> String {
-    let mut result = func.to_string();
-
-    // Remove hash suffix FIRST (before shortening)
-    if let Some(idx) = result.rfind("::h") {
-        let suffix = &result[idx + 3..];
-        if suffix.len() == 16 && suffix.chars().all(|c| c.is_ascii_hexdigit()) {
-            result = result[..idx].to_string();
-        }
-    }
+/// Format a function name for the tables
+///
+/// `names` keeps the hash (short and full modes) or full generics (short mode).
+fn format_function(func: &str, mode: DemangleMode, names: NameOptions) -> String {
+    // Remove hash suffix FIRST (before shortening); it's re-added at the end
+    let (name, hash) = crate::symbols::split_symbol_hash(func);
+    let hash = if names.keep_hash { hash } else { "" };
+    let mut result = match mode {
+        DemangleMode::Raw => return func.to_string(),
+        DemangleMode::Full => return format!("{}{}", name, hash),
+        DemangleMode::Short => name.to_string(),
+    };
 
     // Now shorten to function name or Type::method
     let shortened = crate::symbols::shorten_function_name(&result);
@@ -1007,7 +2061,9 @@ fn format_function(func: &str) -> String {
     }
 
     // Remove complex generic parameters
-    while let (Some(start), Some(end)) = (result.find('<'), result.rfind('>')) {
+    while !names.keep_generics
+        && let (Some(start), Some(end)) = (result.find('<'), result.rfind('>'))
+    {
         if start < end {
             let generic = &result[start..=end];
             if generic.len() > 20 || generic.contains("::") {
@@ -1020,6 +2076,7 @@ fn format_function(func: &str) -> String {
         }
     }
 
+    result.push_str(hash);
     result
 }
 
@@ -1035,3 +2092,352 @@ fn simplify_type_path(path: &str) -> String {
 
 /// Unicode block characters for sparklines (8 levels from empty to full)
 const SPARKLINE_CHARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use crate::symbols::Location;
+    use ratatui::Terminal;
+    use ratatui::backend::TestBackend;
+
+    #[test]
+    fn non_finite_chart_data_renders_with_sane_bounds() {
+        let data = [
+            (0.0, f64::NAN),
+            (1.0, f64::INFINITY),
+            (2.0, 5.0),
+            (3.0, f64::NEG_INFINITY),
+            (f64::NAN, 50.0),
+            (4.0, 7.5),
+        ];
+        let points = visible_points(&data, 0.0, 10.0);
+        assert_eq!(points, [(2.0, 5.0), (4.0, 7.5)]);
+
+        let all_bad = visible_points(&[(0.0, f64::NAN), (1.0, f64::INFINITY)], 0.0, 10.0);
+        assert!(all_bad.is_empty());
+
+        for from_zero in [false, true] {
+            for (y_min, y_max) in [
+                cpu_y_bounds(&points, from_zero),
+                cpu_y_bounds(&all_bad, from_zero),
+                memory_y_bounds(&points, from_zero),
+                memory_y_bounds(&all_bad, from_zero),
+            ] {
+                assert!(y_min.is_finite() && y_max.is_finite());
+                assert!(y_min < y_max);
+            }
+        }
+        assert_eq!(cpu_y_bounds(&points, true), (0.0, 10.0));
+        assert_eq!(finite_bounds(f64::NAN, 10.0, (0.0, 1.0)), (0.0, 1.0));
+        assert_eq!(finite_bounds(3.0, 3.0, (0.0, 1.0)), (3.0, 4.0));
+
+        // Straight to ratatui, as the charts hand it over
+        let (x_start, x_end) = finite_bounds(f64::NAN, f64::INFINITY, (0.0, 1.0));
+        let (y_min, y_max) = cpu_y_bounds(&points, false);
+        let mut terminal = Terminal::new(TestBackend::new(40, 12)).unwrap();
+        for data in [&points, &all_bad] {
+            terminal
+                .draw(|frame| {
+                    let chart = Chart::new(vec![Dataset::default().data(data)])
+                        .x_axis(Axis::default().bounds([x_start, x_end]))
+                        .y_axis(Axis::default().bounds([y_min, y_max]));
+                    frame.render_widget(chart, frame.area());
+                })
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn checkpoint_labels_render_under_their_guide_lines() {
+        let checkpoints = [
+            (-1.0, "before".to_string()),
+            (2.5, "v2 deployed".to_string()),
+            (7.5, "warm".to_string()),
+            (12.0, "after".to_string()),
+        ];
+        let (x_start, x_end) = (0.0, 10.0);
+        let labels = visible_labels(&checkpoints, x_start, x_end);
+        assert_eq!(labels, checkpoints[1..3]);
+
+        let label_lines: Vec<[(f64, f64); 2]> =
+            labels.iter().map(|(t, _)| [(*t, 0.0), (*t, 1.0)]).collect();
+        let x_labels = generate_time_labels(x_start, x_end);
+        let y_labels = vec![Span::raw("0%"), Span::raw("100%")];
+        let (width, height) = (60, 14);
+        let mut terminal = Terminal::new(TestBackend::new(width, height)).unwrap();
+        terminal
+            .draw(|frame| {
+                let (area, strip) = split_label_strip(frame.area(), true);
+                let span = chart_graph_span(area, &y_labels, &x_labels);
+                let chart = Chart::new(label_datasets(&label_lines))
+                    .block(Block::default().borders(Borders::ALL))
+                    .x_axis(
+                        Axis::default()
+                            .bounds([x_start, x_end])
+                            .labels(x_labels.clone()),
+                    )
+                    .y_axis(Axis::default().bounds([0.0, 1.0]).labels(y_labels.clone()));
+                frame.render_widget(chart, area);
+                render_label_strip(frame, &labels, (x_start, x_end), span, strip.unwrap());
+            })
+            .unwrap();
+
+        let buffer = terminal.backend().buffer();
+        let columns_with = |y: u16, wanted: &dyn Fn(&str) -> bool| -> Vec<u16> {
+            (0..width)
+                .filter(|&x| wanted(buffer[(x, y)].symbol()))
+                .collect()
+        };
+        // Guide lines are braille dots, on a row in the middle of the plot
+        let braille = |s: &str| s.chars().all(|c| ('\u{2801}'..='\u{28ff}').contains(&c));
+        let guides = columns_with(height / 2, &braille);
+        let markers = columns_with(height - 1, &|s| s == "▲");
+        assert_eq!(guides.len(), 2);
+        assert_eq!(markers, guides);
+
+        let strip: String = (0..width)
+            .map(|x| buffer[(x, height - 1)].symbol())
+            .collect();
+        let at = |col: u16| strip.chars().skip(col as usize).collect::<String>();
+        assert!(at(markers[0]).starts_with("▲v2 deployed"));
+        assert!(at(markers[1]).starts_with("▲warm"));
+    }
+
+    /// Open a viewer on a profile with one checkpoint written by `record`
+    fn viewer(name: &str, record: impl FnOnce(&mut Storage)) -> App {
+        let path = std::env::temp_dir().join(format!("rsprof-{}-{}.db", name, std::process::id()));
+        let mut storage = Storage::create(&path).unwrap();
+        record(&mut storage);
+        storage.flush_checkpoint_at(1000).unwrap();
+        drop(storage);
+        let app = App::from_file(&path).unwrap();
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", path.display(), suffix)).ok();
+        }
+        app
+    }
+
+    fn location(function: &str, line: u32) -> Location {
+        Location {
+            file: format!("src/{}.rs", function),
+            line,
+            column: 0,
+            function: function.to_string(),
+        }
+    }
+
+    /// Render the whole UI and dump it as text
+    fn screen(app: &mut App, width: u16, height: u16) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(width, height)).unwrap();
+        let completed = terminal.draw(|frame| render(frame, app)).unwrap();
+        buffer_to_text(completed.buffer)
+    }
+
+    #[test]
+    fn screenshots_contain_the_header_table_and_footer() {
+        let mut app = viewer("screenshot", |storage| {
+            storage.record_cpu_sample_count(0x1000, &location("parse_record", 12), 40);
+        });
+        let height = 30;
+        let text = screen(&mut app, 120, height);
+
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), height as usize);
+        assert!(lines.iter().all(|line| line == &line.trim_end()));
+        let header = lines[0];
+        assert!(
+            header.starts_with("rsprof  VIEW  rsprof-screenshot-"),
+            "{header}"
+        );
+        assert!(header.contains("40 samples"), "{header}");
+        assert!(header.ends_with("[CPU] [Memory]"), "{header}");
+        assert!(text.contains("parse_record"));
+        let footer = lines[height as usize - 1];
+        assert!(
+            footer.starts_with(" q  quit  m  mode  /  filter"),
+            "{footer}"
+        );
+    }
+
+    #[test]
+    fn cpu_rows_show_the_bytes_their_location_allocated() {
+        let mut app = viewer("alloc-column", |storage| {
+            // Hot because it allocates: 500 allocations per sample
+            storage.record_cpu_sample_count(0x1000, &location("fill_buffer", 3), 10);
+            storage.record_heap_sample(&location("fill_buffer", 3), 4 << 20, 0, 4 << 20, 5000, 0);
+            storage.record_cpu_sample_count(0x2000, &location("hash_block", 7), 30);
+            storage.record_cpu_sample_count(0x3000, &location("grow_table", 9), 20);
+            storage.record_heap_sample(&location("grow_table", 9), 64 << 10, 0, 0, 2, 2);
+        });
+        let text = screen(&mut app, 140, 24);
+        let row = |function: &str| {
+            let line = text.lines().find(|line| line.contains(function)).unwrap();
+            line.split_whitespace().collect::<Vec<_>>().join(" ")
+        };
+
+        assert!(text.lines().any(|line| line.contains("Alloc")));
+        let expected = |alloc: &str, function: &str| format!("{} {}", alloc, function);
+        assert!(row("fill_buffer").contains(&expected(
+            &format!("{} {}", format_bytes(4 << 20), ALLOC_BADGE),
+            "fill_buffer"
+        )));
+        // Freed memory still counts: it is what was allocated
+        assert!(row("grow_table").contains(&expected(&format_bytes(64 << 10), "grow_table")));
+        assert!(row("hash_block").contains(&expected("-", "hash_block")));
+    }
+
+    #[test]
+    fn narrow_tables_drop_the_trend_column_and_widen_function() {
+        let row = TableRow {
+            total: "42.0%".to_string(),
+            live: "1.0%".to_string(),
+            time: None,
+            alloc: None,
+            ipc: None,
+            function: "parser::expression::parse_binary_operator".to_string(),
+            location: "src/parser/expression.rs:120".to_string(),
+            sparkline_data: vec![1, 2, 3],
+            total_color: Color::Red,
+            live_color: Color::Red,
+            alloc_color: Color::DarkGray,
+            alert: false,
+        };
+        // Whether Trend is shown, and the Function and Location column widths
+        // (with their spacing) of a table `width` wide
+        let render = |width: u16, columns: ColumnWidths| {
+            let mut terminal = Terminal::new(TestBackend::new(width, 6)).unwrap();
+            terminal
+                .draw(|frame| {
+                    let state = TableRenderState {
+                        selected: 0,
+                        scroll_offset: 0,
+                        focus: Focus::Table,
+                        sort: TableSort {
+                            column: SortColumn::Total,
+                            descending: true,
+                        },
+                        area: frame.area(),
+                        columns,
+                    };
+                    render_unified_table(frame, "CPU", std::slice::from_ref(&row), state);
+                })
+                .unwrap();
+            let buffer = terminal.backend().buffer();
+            let header: String = (0..width).map(|x| buffer[(x, 1)].symbol()).collect();
+            // Column of a label (the sort arrow takes more than one byte)
+            let column = |label: &str| header.find(label).map(|i| header[..i].chars().count());
+            let (function, location) = (column("Function").unwrap(), column("Location").unwrap());
+            let trend = column("Trend");
+            let end = trend.unwrap_or(width as usize - 1);
+            (trend.is_some(), location - function, end - location)
+        };
+
+        let (trend, function, location) = render(120, ColumnWidths::default());
+        assert!(trend);
+        assert!(function.abs_diff(location) <= 1);
+
+        let (trend, narrow_function, location) = render(70, ColumnWidths::default());
+        assert!(!trend);
+        assert!(narrow_function.abs_diff(2 * location) <= 2);
+        assert!(narrow_function > function * 70 / 120);
+
+        // Fixed widths win over the adaptive layout
+        let fixed = ColumnWidths {
+            function: Some(30),
+            trend: Some(0),
+            ..ColumnWidths::default()
+        };
+        let (trend, function, _) = render(120, fixed);
+        assert!(!trend);
+        assert_eq!(function, 31);
+    }
+
+    #[test]
+    fn allocation_heavy_cpu_rows_get_a_badge() {
+        let entry = |location_id, function: &str, total_samples| CpuEntry {
+            location_id,
+            file: format!("src/{}.rs", function),
+            line: 1,
+            function: function.to_string(),
+            total_samples,
+            total_percent: 0.0,
+            instant_percent: 0.0,
+        };
+        let entries = [
+            entry(1, "fill_buffer", 10),
+            entry(2, "checksum", 200),
+            entry(3, "hash_block", 50),
+        ];
+        // fill_buffer: 100 allocations per sample; checksum: one per 100 samples
+        let allocs = HashMap::from([(1, (4 << 20, 1000)), (2, (64, 2))]);
+        let rows = cpu_to_table_rows(
+            &entries,
+            |e| (e.total_samples, e.total_percent),
+            &HashMap::new(),
+            Some(&allocs),
+            None,
+            None,
+            HeatMode::Absolute,
+            DemangleMode::Short,
+            NameOptions::default(),
+        );
+        let alloc = |i: usize| (rows[i].alloc.clone().unwrap(), rows[i].alloc_color);
+
+        assert_eq!(
+            alloc(0),
+            (
+                format!("{} {}", format_bytes(4 << 20), ALLOC_BADGE),
+                Color::Magenta
+            )
+        );
+        assert_eq!(alloc(1), (format_bytes(64), color_for_bytes(64)));
+        // Pure compute: nothing allocated, no badge
+        assert_eq!(alloc(2), ("-".to_string(), Color::DarkGray));
+    }
+
+    #[test]
+    fn name_options_toggle_generics_and_hash_independently() {
+        let symbol = "myapp::cache::Cache<myapp::model::Record>::insert::h0123456789abcdef";
+        let format = |keep_generics, keep_hash| {
+            let names = NameOptions {
+                keep_generics,
+                keep_hash,
+            };
+            format_function(symbol, DemangleMode::Short, names)
+        };
+
+        assert_eq!(format(false, false), "Cache<_>::insert");
+        assert_eq!(format(true, false), "Cache<myapp::model::Record>::insert");
+        assert_eq!(format(false, true), "Cache<_>::insert::h0123456789abcdef");
+        assert_eq!(
+            format(true, true),
+            "Cache<myapp::model::Record>::insert::h0123456789abcdef"
+        );
+    }
+
+    #[test]
+    fn relative_heat_colors_a_flat_profile() {
+        // 40 functions between 3.0% and 1.05%: none reaches the absolute 5%
+        let percents: Vec<f64> = (0..40).map(|i| 3.0 - i as f64 * 0.05).collect();
+        let colors = |heat: &HeatThresholds| -> Vec<Color> {
+            percents
+                .iter()
+                .map(|&pct| color_for_percent(pct, heat))
+                .collect()
+        };
+
+        assert!(
+            colors(&HeatThresholds::ABSOLUTE)
+                .iter()
+                .all(|&c| c == Color::White)
+        );
+
+        let colors = colors(&HeatThresholds::relative(percents.iter().copied()));
+        // Top decile hot, then 20% warm, 20% mild, the rest uncolored
+        assert_eq!(colors[..4], [Color::Red; 4]);
+        assert_eq!(colors[4..12], [Color::Yellow; 8]);
+        assert_eq!(colors[12..20], [Color::Green; 8]);
+        assert!(colors[20..].iter().all(|&c| c == Color::White));
+    }
+}